@@ -0,0 +1,619 @@
+//! Archive of the kernel images pushed, telling which exact binary was on a
+//! board at any time.
+//!
+//! When an archive directory is set in the settings, each image pushed is
+//! stored in it once, named by its SHA-256 digest, and each push is recorded
+//! with its transfer report, the board it went to, how the boot went, and the
+//! console output which followed until the next push or the end of the
+//! session, along with the captures of the instruments run meanwhile and the
+//! notes typed during the session (`Ctrl+A n` in terminal mode). The
+//! command line archives to `bootcom/archive` in the user state
+//! directory with `--archive`, and `bootcom history` lists the boots recorded
+//! or compares two of them:
+//!
+//! ```text
+//! archive/
+//! ├── boots/
+//! │   ├── 000041.log
+//! │   ├── 000041.toml
+//! │   ├── 000042.log
+//! │   └── 000042.toml
+//! └── images/
+//!     └── 1a2b3c...
+//! ```
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::archive;
+//!
+//! let dir = archive::default_path().unwrap();
+//! for boot in archive::list(&dir).unwrap() {
+//!     println!("{}", boot);
+//! }
+//! ```
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use sha2::{Digest, Sha256};
+use toml::{value::Table, Value};
+
+use crate::boards::{self, BootResult};
+use crate::progress::{InstrumentCapture, TransferReport};
+use crate::resume;
+use crate::settings::Settings;
+use crate::utils::{Crc32, HumanDate, HumanDuration, HumanSize};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A push of a kernel image, as recorded in the archive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Boot {
+    /// The number of the push, increasing from one push to the next.
+    pub id: u64,
+    /// When the transfer ended.
+    pub at: SystemTime,
+    /// The port the image was pushed on.
+    pub port: String,
+    /// The board of the inventory on the port, if any.
+    pub board: Option<String>,
+    /// The path the image was pushed from.
+    pub image: String,
+    /// The SHA-256 digest of the image, naming it in the archive.
+    pub sha256: String,
+    pub protocol: String,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub crc: u32,
+    /// How the boot went, unknown while the session is checking it or when
+    /// it ended before.
+    pub result: Option<BootResult>,
+    /// The captures of the instruments run for the boot.
+    pub captures: Vec<InstrumentCapture>,
+    /// The notes typed while the boot was followed.
+    pub notes: Vec<Note>,
+}
+
+impl Boot {
+    /// The path of the archived image in the archive `dir`.
+    pub fn image_path(&self, dir: &Path) -> PathBuf {
+        dir.join("images").join(&self.sha256)
+    }
+
+    /// The path of the console log of the boot in the archive `dir`.
+    pub fn log_path(&self, dir: &Path) -> PathBuf {
+        dir.join("boots").join(format!("{:06}.log", self.id))
+    }
+
+    /// The humanized details of the boot, by name, in the order they are best
+    /// shown, with the length of its console log in the archive `dir`.
+    pub fn details(&self, dir: &Path) -> Vec<(&'static str, String)> {
+        let log = fs::read(self.log_path(dir)).unwrap_or_default();
+        let lines = log.split(|byte| *byte == b'\n').count() - 1;
+        vec![
+            ("date", HumanDate(self.at).to_string()),
+            ("board", self.board.clone().unwrap_or_else(|| "-".into())),
+            ("port", self.port.clone()),
+            ("image", self.image.clone()),
+            ("sha256", self.sha256.clone()),
+            ("size", HumanSize(self.bytes).to_string()),
+            ("crc32", format!("{:08x}", self.crc)),
+            ("protocol", self.protocol.clone()),
+            ("transfer", HumanDuration(self.duration).to_string()),
+            (
+                "result",
+                self.result
+                    .as_ref()
+                    .map_or_else(|| "unknown".into(), ToString::to_string),
+            ),
+            (
+                "console",
+                format!("{} lines ({})", lines, HumanSize(log.len() as u64)),
+            ),
+            (
+                "captures",
+                match self.captures.as_slice() {
+                    [] => "-".into(),
+                    captures => captures
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                },
+            ),
+            (
+                "notes",
+                match self.notes.as_slice() {
+                    [] => "-".into(),
+                    notes => notes
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                },
+            ),
+        ]
+    }
+}
+
+/// A note typed during a session, to keep an observation along with the
+/// console output.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Note {
+    /// When the note was typed.
+    pub at: SystemTime,
+    pub text: String,
+}
+impl fmt::Display for Note {
+    /// The note and when it was typed, e.g. `UART stuck after the reset
+    /// (2023-11-14 22:13:20 UTC)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.text, HumanDate(self.at))
+    }
+}
+
+impl fmt::Display for Boot {
+    /// A line summing up the boot, e.g. `#42  2023-11-14 22:13:20 UTC  rpi4-a
+    /// kernel8.img  1a2b3c4d5e6f  2.9 MiB  booted`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Path::new(&self.image)
+            .file_name()
+            .map_or_else(|| self.image.clone(), |name| name.to_string_lossy().into());
+        write!(
+            f,
+            "#{}  {}  {}  {}  {}  {}  {}",
+            self.id,
+            HumanDate(self.at),
+            self.board.as_deref().unwrap_or(&self.port),
+            name,
+            &self.sha256[..12.min(self.sha256.len())],
+            HumanSize(self.bytes),
+            self.result
+                .as_ref()
+                .map_or_else(|| "unknown".into(), ToString::to_string)
+        )
+    }
+}
+
+/// The path of the default archive directory, if the user state directory can
+/// be found.
+pub fn default_path() -> Option<PathBuf> {
+    resume::default_path().map(|path| path.with_file_name("archive"))
+}
+
+/// The boots recorded in the archive `dir`, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<Boot>, String> {
+    let boots = dir.join("boots");
+    let entries = match fs::read_dir(&boots) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("{}: {}", boots.display(), e)),
+    };
+    let mut ids: Vec<u64> = entries
+        .flatten()
+        .filter_map(|entry| record_id(&entry.file_name().to_string_lossy()))
+        .collect();
+    ids.sort_unstable();
+    ids.into_iter().map(|id| load(dir, id)).collect()
+}
+
+/// Read the boot `id` from the archive `dir`.
+pub fn load(dir: &Path, id: u64) -> Result<Boot, String> {
+    let path = record_path(dir, id);
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(id, &text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The last boot archived in `dir` before `boot` which reached all its boot
+/// stages, of the same board, or on the same port for the boots of no board.
+pub fn last_known_good(dir: &Path, boot: &Boot) -> Result<Option<Boot>, String> {
+    Ok(list(dir)?.into_iter().rev().find(|earlier| {
+        earlier.id < boot.id
+            && earlier.result == Some(BootResult::Booted)
+            && match &boot.board {
+                Some(board) => earlier.board.as_ref() == Some(board),
+                None => earlier.board.is_none() && earlier.port == boot.port,
+            }
+    }))
+}
+
+/// Parse the `text` of the record of the boot `id`.
+pub fn parse(id: u64, text: &str) -> Result<Boot, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let string = |key: &str| match root.get(key) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        _ => Err(format!("`{}` needs to be a string", key)),
+    };
+    let number = |key: &str| match root.get(key) {
+        Some(Value::Integer(value)) if *value >= 0 => Ok(*value as u64),
+        _ => Err(format!("`{}` needs to be a positive number", key)),
+    };
+    Ok(Boot {
+        id,
+        at: UNIX_EPOCH + Duration::from_secs(number("at")?),
+        port: string("port")?,
+        board: string("board").ok(),
+        image: string("image")?,
+        sha256: string("sha256")?,
+        protocol: string("protocol")?,
+        bytes: number("bytes")?,
+        duration: Duration::from_millis(number("duration_ms")?),
+        crc: number("crc")? as u32,
+        result: boards::boot_result(&root),
+        captures: captures(&root)?,
+        notes: notes(&root)?,
+    })
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// A push being archived: its record, completed with the result of the boot,
+/// and its console log.
+#[derive(Debug)]
+pub(crate) struct Archived {
+    dir: PathBuf,
+    boot: Boot,
+    /// The console log, closed after a failed write.
+    log: Option<fs::File>,
+    /// Whether the console log ends in the middle of a line.
+    mid_line: bool,
+}
+impl Archived {
+    /// Archive the push of the `report` on the port of the `settings`, if they
+    /// have an archive directory.
+    pub(crate) fn start(settings: &Settings, report: &TransferReport) -> io::Result<Option<Self>> {
+        let dir = match &settings.archive {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(None),
+        };
+        let data = fs::read(&report.image)?;
+        let mut crc = Crc32::new();
+        crc.update(&data);
+        if crc.finalize() != report.crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`{}` changed since it was pushed", report.image),
+            ));
+        }
+        let sha256: String = Sha256::digest(&data)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        fs::create_dir_all(dir.join("images"))?;
+        let image = dir.join("images").join(&sha256);
+        if !image.exists() {
+            // Renamed into place, a partly written image is never taken for
+            // a complete one.
+            let partial = image.with_extension(format!("{}.part", std::process::id()));
+            fs::write(&partial, &data)?;
+            fs::rename(&partial, &image)?;
+        }
+
+        let mut boot = Boot {
+            id: 0,
+            at: SystemTime::now(),
+            port: settings.path.clone().unwrap_or_default(),
+            board: boards::board_of(settings).map(|board| board.name.clone()),
+            image: fs::canonicalize(&report.image)
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|_| report.image.clone()),
+            sha256,
+            protocol: report.protocol.to_string(),
+            bytes: report.bytes,
+            duration: report.duration,
+            crc: report.crc,
+            result: None,
+            captures: vec![],
+            notes: vec![],
+        };
+        boot.id = create_record(&dir, &boot)?;
+        let log = fs::File::create(boot.log_path(&dir))?;
+        Ok(Some(Archived {
+            dir,
+            boot,
+            log: Some(log),
+            mid_line: false,
+        }))
+    }
+
+    /// Append the console output `data` to the log.
+    pub(crate) fn output(&mut self, data: &[u8]) {
+        if let Some(&last) = data.last() {
+            self.mid_line = last != b'\n';
+        }
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.write_all(data) {
+                info!("could not log the console of boot #{}: {}", self.boot.id, e);
+                self.log = None;
+            }
+        }
+    }
+
+    /// Record the `result` of the boot.
+    pub(crate) fn result(&mut self, result: &BootResult) {
+        self.boot.result = Some(result.clone());
+        self.save();
+    }
+
+    /// Record the `capture` of an instrument.
+    pub(crate) fn capture(&mut self, capture: &InstrumentCapture) {
+        self.boot.captures.push(capture.clone());
+        self.save();
+    }
+
+    /// Record the `note`, also written to the log as its `line`.
+    pub(crate) fn note(&mut self, note: &Note, line: &str) {
+        let newline = if self.mid_line { "\n" } else { "" };
+        self.output(format!("{}{}\n", newline, line).as_bytes());
+        self.boot.notes.push(note.clone());
+        self.save();
+    }
+
+    fn save(&self) {
+        let path = record_path(&self.dir, self.boot.id);
+        if let Err(e) = fs::write(&path, to_toml(&self.boot)) {
+            info!(
+                "could not update the record of boot #{}: {}",
+                self.boot.id, e
+            );
+        }
+    }
+
+    /// The number of the push.
+    pub(crate) fn id(&self) -> u64 {
+        self.boot.id
+    }
+
+    /// The path of the console log.
+    pub(crate) fn log_path(&self) -> PathBuf {
+        self.boot.log_path(&self.dir)
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+fn record_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join("boots").join(format!("{:06}.toml", id))
+}
+
+/// The id of the boot recorded in the file `name`, if it is a record.
+fn record_id(name: &str) -> Option<u64> {
+    name.strip_suffix(".toml")?.parse().ok()
+}
+
+/// Save the record of the `boot` under the next free id, returned.
+fn create_record(dir: &Path, boot: &Boot) -> io::Result<u64> {
+    let boots = dir.join("boots");
+    fs::create_dir_all(&boots)?;
+    let mut id = fs::read_dir(&boots)?
+        .flatten()
+        .filter_map(|entry| record_id(&entry.file_name().to_string_lossy()))
+        .max()
+        .unwrap_or(0);
+    // Another process may take the same id meanwhile, the next one is tried.
+    loop {
+        id += 1;
+        let created = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(record_path(dir, id));
+        match created {
+            Ok(mut file) => {
+                let boot = Boot { id, ..boot.clone() };
+                file.write_all(to_toml(&boot).as_bytes())?;
+                return Ok(id);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn captures(root: &Table) -> Result<Vec<InstrumentCapture>, String> {
+    let captures = match root.get("capture") {
+        None => return Ok(vec![]),
+        Some(Value::Array(captures)) => captures,
+        Some(_) => return Err("`capture` needs to be an array of sections".into()),
+    };
+    captures
+        .iter()
+        .map(|capture| {
+            let table = capture
+                .as_table()
+                .ok_or("`capture` needs to be an array of sections")?;
+            let string = |key: &str| match table.get(key) {
+                Some(Value::String(value)) => Ok(value.clone()),
+                _ => Err(format!("`capture.{}` needs to be a string", key)),
+            };
+            let time = |key: &str| match table.get(key) {
+                Some(Value::Integer(millis)) if *millis >= 0 => {
+                    Ok(UNIX_EPOCH + Duration::from_millis(*millis as u64))
+                }
+                _ => Err(format!("`capture.{}` needs to be a positive number", key)),
+            };
+            Ok(InstrumentCapture {
+                name: string("name")?,
+                file: string("file")?,
+                started: time("started_ms")?,
+                stopped: time("stopped_ms")?,
+            })
+        })
+        .collect()
+}
+
+fn notes(root: &Table) -> Result<Vec<Note>, String> {
+    let notes = match root.get("note") {
+        None => return Ok(vec![]),
+        Some(Value::Array(notes)) => notes,
+        Some(_) => return Err("`note` needs to be an array of sections".into()),
+    };
+    notes
+        .iter()
+        .map(|note| {
+            let table = note
+                .as_table()
+                .ok_or("`note` needs to be an array of sections")?;
+            let at = match table.get("at_ms") {
+                Some(Value::Integer(millis)) if *millis >= 0 => {
+                    UNIX_EPOCH + Duration::from_millis(*millis as u64)
+                }
+                _ => return Err("`note.at_ms` needs to be a positive number".into()),
+            };
+            match table.get("text") {
+                Some(Value::String(text)) => Ok(Note {
+                    at,
+                    text: text.clone(),
+                }),
+                _ => Err("`note.text` needs to be a string".into()),
+            }
+        })
+        .collect()
+}
+
+fn to_toml(boot: &Boot) -> String {
+    let mut root = Table::new();
+    let at = boot
+        .at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    root.insert("at".into(), Value::Integer(at as i64));
+    root.insert("port".into(), Value::String(boot.port.clone()));
+    if let Some(board) = &boot.board {
+        root.insert("board".into(), Value::String(board.clone()));
+    }
+    root.insert("image".into(), Value::String(boot.image.clone()));
+    root.insert("sha256".into(), Value::String(boot.sha256.clone()));
+    root.insert("protocol".into(), Value::String(boot.protocol.clone()));
+    root.insert("bytes".into(), Value::Integer(boot.bytes as i64));
+    root.insert(
+        "duration_ms".into(),
+        Value::Integer(boot.duration.as_millis() as i64),
+    );
+    root.insert("crc".into(), Value::Integer(boot.crc.into()));
+    if let Some(result) = &boot.result {
+        boards::insert_boot_result(&mut root, result);
+    }
+    if !boot.captures.is_empty() {
+        let millis = |time: SystemTime| {
+            let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            Value::Integer(millis.as_millis() as i64)
+        };
+        let captures = boot.captures.iter().map(|capture| {
+            let mut table = Table::new();
+            table.insert("name".into(), Value::String(capture.name.clone()));
+            table.insert("file".into(), Value::String(capture.file.clone()));
+            table.insert("started_ms".into(), millis(capture.started));
+            table.insert("stopped_ms".into(), millis(capture.stopped));
+            Value::Table(table)
+        });
+        root.insert("capture".into(), Value::Array(captures.collect()));
+    }
+    if !boot.notes.is_empty() {
+        let notes = boot.notes.iter().map(|note| {
+            let millis = note.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut table = Table::new();
+            table.insert("at_ms".into(), Value::Integer(millis.as_millis() as i64));
+            table.insert("text".into(), Value::String(note.text.clone()));
+            Value::Table(table)
+        });
+        root.insert("note".into(), Value::Array(notes.collect()));
+    }
+    format!(
+        "# Saved by bootcom for `bootcom history`.\n{}",
+        Value::Table(root)
+    )
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn pushes_are_archived() {
+    use crate::settings::{SettingsBuilder, TransferProtocol};
+
+    let dir = std::env::temp_dir().join(format!("bootcom-archive-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("kernel8.img");
+    fs::write(&image, vec![0x5a; 3000]).unwrap();
+    let mut crc = Crc32::new();
+    crc.update(&[0x5a; 3000]);
+    let report = TransferReport {
+        image: image.display().to_string(),
+        protocol: TransferProtocol::Raspbootin,
+        bytes: 3000,
+        duration: Duration::from_millis(1250),
+        retries: 0,
+        crc: crc.finalize(),
+        resumed_from: 0,
+        output: vec![],
+    };
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyUSB0")
+        .archive(dir.join("archive").to_string_lossy())
+        .finalize();
+
+    let capture = InstrumentCapture {
+        name: "power".into(),
+        file: "captures/power-1700000000.csv".into(),
+        started: UNIX_EPOCH + Duration::from_millis(1_700_000_000_125),
+        stopped: UNIX_EPOCH + Duration::from_millis(1_700_000_003_342),
+    };
+    let note = Note {
+        at: UNIX_EPOCH + Duration::from_millis(1_700_000_001_500),
+        text: "no \"login\" prompt yet".into(),
+    };
+    for _ in 0..2 {
+        let mut archived = Archived::start(&settings, &report).unwrap().unwrap();
+        archived.output(b"Booting...\r\nlogin: ");
+        archived.capture(&capture);
+        archived.result(&BootResult::Booted);
+    }
+    let mut archived = Archived::start(&settings, &report).unwrap().unwrap();
+    archived.output(b"Booting...");
+    archived.note(&note, "[BC] note");
+    archived.output(b"login: ");
+
+    let boots = list(&dir.join("archive")).unwrap();
+    assert_eq!(boots.len(), 3);
+    assert_eq!(boots[2].notes, vec![note]);
+    assert_eq!(
+        fs::read(boots[2].log_path(&dir.join("archive"))).unwrap(),
+        b"Booting...\n[BC] note\nlogin: "
+    );
+    let boot = &boots[1];
+    assert_eq!(boot.id, 2);
+    assert_eq!(boot.port, "/dev/ttyUSB0");
+    assert_eq!(boot.duration, Duration::from_millis(1250));
+    assert_eq!(boot.result, Some(BootResult::Booted));
+    assert_eq!(boot.captures, vec![capture]);
+    assert_eq!(boot.sha256, boots[0].sha256);
+    let archived = fs::read(boot.image_path(&dir.join("archive"))).unwrap();
+    assert_eq!(archived, vec![0x5a; 3000]);
+    let details = boot.details(&dir.join("archive"));
+    assert!(details.contains(&("console", "1 lines (19 B)".into())));
+    let good = last_known_good(&dir.join("archive"), boot).unwrap();
+    assert_eq!(good.map(|good| good.id), Some(1));
+    assert_eq!(
+        last_known_good(&dir.join("archive"), &boots[0]).unwrap(),
+        None
+    );
+
+    // An image rebuilt since it was pushed is not taken for the one pushed.
+    fs::write(&image, vec![0xa5; 3000]).unwrap();
+    assert!(Archived::start(&settings, &report).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
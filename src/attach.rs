@@ -0,0 +1,276 @@
+//! Attaching to the console of a board supervised by a remote `bootcom`
+//! daemon, as if the board was plugged in locally.
+//!
+//! The daemon serves the console on the sockets passed by systemd (see
+//! `--daemon`). Once attached, the console output of the board is printed as it
+//! comes, and the keys pressed (`Ctrl+C` included) and the text pasted are
+//! typed on the board console, as in terminal mode. The kernel image of the
+//! options, typically the one just built on this host, is uploaded to the
+//! daemon when attached and on `F6`, read again each time, to be pushed the
+//! next time the bootloader asks for an image. `F10` detaches.
+//!
+//! Nothing is encrypted over TCP, so a token is only sent to the Unix socket of
+//! the daemon, which the hosts without access to it reach through an SSH
+//! tunnel (e.g. `ssh -L /tmp/board.sock:/run/bootcom/ttyUSB0.sock labhost`).
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::attach::{attach, AttachOptions};
+//!
+//! let options = AttachOptions {
+//!     token: Some("team-a-3f9c1e".into()),
+//!     image: Some("target/kernel8.img".into()),
+//!     ..AttachOptions::default()
+//! };
+//! if let Err(e) = attach("/tmp/board.sock", &options) {
+//!     eprintln!("could not attach: {}", e);
+//! }
+//! ```
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use console::style;
+use crossterm::event::{KeyCode, KeyEvent};
+
+use crate::messages::Catalog;
+use crate::utils::{key_bytes, render, subscribe_typing, HumanSize, Input};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The TCP port of the daemon when the address does not give one.
+pub const DEFAULT_PORT: u16 = 7000;
+
+/// Options for attaching to a daemon.
+#[derive(Debug, Clone, Default)]
+pub struct AttachOptions {
+    /// The token authenticating with the daemon, when it has access rules. Only
+    /// sent to a Unix socket.
+    pub token: Option<String>,
+    /// The kernel image uploaded for the next push.
+    pub image: Option<String>,
+    /// The catalog translating the messages, all in English by default.
+    pub messages: Catalog,
+}
+
+/// Attach to the daemon at `address`, a `host` (served on [`DEFAULT_PORT`]),
+/// a `host:port`, or the path of a Unix socket, until the user detaches or the
+/// daemon goes away.
+pub fn attach(address: &str, options: &AttachOptions) -> io::Result<()> {
+    let messages = &options.messages;
+    if options.token.is_some() && !is_unix_socket(address) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            messages.text("attach.token_in_cleartext"),
+        ));
+    }
+    let (mut reader, writer) = connect(address)?;
+    let mut daemon = Daemon {
+        writer,
+        at_line_start: true,
+        messages: messages.clone(),
+    };
+    println!(
+        "{}",
+        messages.text_with(
+            "attach.attached",
+            &[
+                ("address", &style(address).cyan()),
+                ("push", &style("F6").cyan()),
+                ("detach", &style("F10").cyan()),
+            ]
+        )
+    );
+    if let Some(token) = &options.token {
+        daemon.command(&format!("auth {}", token))?;
+    }
+    if let Some(image) = &options.image {
+        daemon.upload(image)?;
+    }
+
+    let closed = Arc::new(AtomicBool::new(false));
+    {
+        let closed = closed.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    // The lines are ended for the raw mode of the terminal too.
+                    Ok(read) => {
+                        let text = String::from_utf8_lossy(&buf[..read]).replace('\n', "\r\n");
+                        let _ = render::write(text.as_bytes());
+                    }
+                }
+            }
+            closed.store(true, Ordering::SeqCst);
+        });
+    }
+
+    // `Ctrl+C` and the pasted text are typed on the board console too.
+    let keys = subscribe_typing();
+    while !closed.load(Ordering::SeqCst) {
+        let key = match keys.next_input(Duration::from_millis(100)) {
+            Some(Input::Key(key)) => key,
+            Some(Input::Paste(text)) => {
+                daemon.type_bytes(text.as_bytes())?;
+                continue;
+            }
+            None => continue,
+        };
+        match key.code {
+            KeyCode::F(10) => break,
+            KeyCode::F(6) => match &options.image {
+                Some(image) => daemon.upload(image)?,
+                None => println!("{}", style(messages.text("attach.no_image")).yellow()),
+            },
+            _ => daemon.type_key(key)?,
+        }
+    }
+    if closed.load(Ordering::SeqCst) {
+        println!("{}", messages.text("attach.closed"));
+    } else {
+        println!("{}", messages.text("attach.detached"));
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+type Reader = Box<dyn Read + Send>;
+type Writer = Box<dyn Write + Send>;
+
+/// Returns `true` if the `address` of the daemon is the path of a Unix socket.
+fn is_unix_socket(address: &str) -> bool {
+    cfg!(unix) && address.contains('/')
+}
+
+fn connect(address: &str) -> io::Result<(Reader, Writer)> {
+    #[cfg(unix)]
+    if is_unix_socket(address) {
+        let stream = std::os::unix::net::UnixStream::connect(address)?;
+        return Ok((Box::new(stream.try_clone()?), Box::new(stream)));
+    }
+    let stream = if address.contains(':') {
+        TcpStream::connect(address)?
+    } else {
+        TcpStream::connect((address, DEFAULT_PORT))?
+    };
+    // Keys are sent one at a time.
+    stream.set_nodelay(true)?;
+    Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+}
+
+/// The connection to the daemon, to which what is typed on the console and
+/// the commands are written.
+struct Daemon {
+    writer: Writer,
+    /// Whether the console is at the start of a line, where the commands go.
+    at_line_start: bool,
+    messages: Catalog,
+}
+impl Daemon {
+    fn type_key(&mut self, key: KeyEvent) -> io::Result<()> {
+        match key_bytes(&key) {
+            Some(data) => self.type_bytes(&data),
+            None => Ok(()),
+        }
+    }
+
+    /// Type the `data` on the board console.
+    fn type_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = escape_commands(&mut self.at_line_start, data);
+        self.writer.write_all(&data)
+    }
+
+    fn command(&mut self, command: &str) -> io::Result<()> {
+        writeln!(self.writer, "~{}", command)
+    }
+
+    /// Upload the kernel `image`, the daemon answers when it is saved.
+    fn upload(&mut self, image: &str) -> io::Result<()> {
+        if !self.at_line_start {
+            println!("{}", style(self.messages.text("attach.mid_line")).yellow());
+            return Ok(());
+        }
+        let data = match fs::read(image) {
+            Ok(data) => data,
+            Err(e) => {
+                println!(
+                    "{}",
+                    style(
+                        self.messages
+                            .text_with("attach.not_read", &[("image", &image), ("error", &e)])
+                    )
+                    .yellow()
+                );
+                return Ok(());
+            }
+        };
+        let name = Path::new(image)
+            .file_name()
+            .map_or("kernel.img".into(), |name| name.to_string_lossy());
+        println!(
+            "{}",
+            self.messages.text_with(
+                "attach.uploading",
+                &[
+                    ("name", &style(&name).cyan()),
+                    ("size", &HumanSize(data.len() as u64))
+                ]
+            )
+        );
+        self.command(&format!("upload {} {}", data.len(), name))?;
+        self.writer.write_all(&data)?;
+        self.writer.flush()
+    }
+}
+
+/// The `data` with the `~` typed at the start of a line doubled, as it
+/// starts a command otherwise, given whether the console is `at_line_start`,
+/// which is kept up to date.
+fn escape_commands(at_line_start: &mut bool, data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if *at_line_start && *byte == b'~' {
+            escaped.push(b'~');
+        }
+        escaped.push(*byte);
+        *at_line_start = *byte == b'\r' || *byte == b'\n';
+    }
+    escaped
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn commands_are_escaped_at_line_start() {
+    let mut at_line_start = true;
+    assert_eq!(escape_commands(&mut at_line_start, b"~"), b"~~");
+    assert!(!at_line_start);
+    assert_eq!(escape_commands(&mut at_line_start, b"~/x\r"), b"~/x\r");
+    assert!(at_line_start);
+    // Pasted lines, each escaped.
+    assert_eq!(
+        escape_commands(&mut at_line_start, b"~a\n~b\r~"),
+        b"~~a\n~~b\r~~"
+    );
+    assert_eq!(escape_commands(&mut at_line_start, b""), b"");
+    assert!(!at_line_start);
+}
@@ -1,26 +1,30 @@
 //! Bootcom command line interface.
 
-use std::process;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
 
 use clap::{
     crate_authors, crate_description, crate_name, crate_version, value_t, App, AppSettings::*, Arg,
+    ArgMatches, SubCommand,
 };
 use console::style;
 use log::{debug, trace, LevelFilter};
 use serialport::{DataBits, FlowControl, Parity, StopBits};
 use simplelog::*;
 
-use bootcom::{self as bc, DeviceManager};
+use bootcom::{
+    self as bc, archive, boards, config, debug_bundle, diff, fastboot,
+    messages::{self, Catalog},
+    progress::{JsonProgress, ObserverHandle},
+    protocol, resume, severity, usage, DeviceManager,
+};
 
 fn main() {
-    println!("[BC] bootcom v{}", crate_version!());
-
-    ctrlc::set_handler(move || {
-        println!("🛑 received Ctrl+C!");
-        process::exit(0);
-    })
-    .expect("Failed to install my Ctrl-C handler!");
-
     let matches = App::new(crate_name!())
         .version(format!("v{}", crate_version!()).as_str())
         .author(crate_authors!())
@@ -41,11 +45,26 @@ fn main() {
                \t* waits for 'OK' \n\
                \t* sends the kernel image \n\
             \n\
+            Other trigger patterns can be registered with `--trigger`, each \
+            mapped to a transfer protocol (e.g. a burst of 'C' for XMODEM-CRC \
+            receivers).\n\
+            \n\
             After that it goes back into terminal mode.\n\
             \n\
             Bootcom can be started before or after the bootloader is running. \
             It can also properly manage unplugging and re-plugging of the USB \
-            cable.\
+            cable.\n\
+            \n\
+            Press F4 in terminal mode to mark the console log, F5 to start or \
+            stop a stopwatch, Ctrl+A n to type a note, Ctrl+A a to send \
+            Ctrl+A to the board, F7 to change the baud rate, parity or flow \
+            control of the open port, and F10 or Ctrl+A q to quit. The other \
+            keys, Ctrl+C included, and the text pasted are sent to the \
+            board.\n\
+            \n\
+            With `--watch`, the kernel image is sent again whenever it is \
+            rebuilt, after resetting the board with `--reset-command` if \
+            given.\
         ",
         )
         .max_term_width(80)
@@ -65,6 +84,46 @@ fn main() {
                 .takes_value(true)
                 .require_equals(true),
         )
+        .arg(
+            Arg::with_name("VID")
+                .help("the vendor ID of the USB serial controller to use")
+                .long_help(
+                    "the vendor ID of the USB serial controller to use, in \
+                     hexadecimal; the port is the first one matching all of \
+                     `--vid`, `--pid` and `--serial-number` given, looked \
+                     for again when the board is re-plugged, whatever its \
+                     device path.",
+                )
+                .long("--vid")
+                .takes_value(true)
+                .conflicts_with("DEVICE_TTY")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PID")
+                .help("the product ID of the USB serial controller to use")
+                .long_help(
+                    "the product ID of the USB serial controller to use, in \
+                     hexadecimal; see `--vid`.",
+                )
+                .long("--pid")
+                .takes_value(true)
+                .conflicts_with("DEVICE_TTY")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("SERIAL_NUMBER")
+                .help("the serial number of the USB serial controller to use")
+                .long_help(
+                    "the serial number of the USB serial controller to use, \
+                     telling apart the adapters of the same model; see \
+                     `--vid`.",
+                )
+                .long("--serial-number")
+                .takes_value(true)
+                .conflicts_with("DEVICE_TTY")
+                .require_equals(true),
+        )
         .arg(
             Arg::with_name("BAUD_RATE")
                 .help("serial port baud rate")
@@ -108,6 +167,12 @@ fn main() {
         .arg(
             Arg::with_name("FLOW_CONTROL")
                 .help("flow control mode")
+                .long_help(
+                    "flow control mode; with `soft`, XON/XOFF from the \
+                     device pause the writing, and XON, XOFF and DLE bytes in \
+                     the kernel image are sent as DLE followed by the byte \
+                     XOR 0x20, to be unescaped by the bootloader.",
+                )
                 .short("-f")
                 .long("--flow-control")
                 .takes_value(true)
@@ -116,115 +181,2019 @@ fn main() {
                 .require_equals(true),
         )
         .arg(
-            Arg::with_name("KERNEL_IMAGE")
-                .help("path to the kernel image to be pushed")
+            Arg::with_name("BAUD_RESCAN")
+                .help("what to do when the terminal receives only garbage")
                 .long_help(
-                    "path to the kernel image to be pushed; when not \
-                     set, `bootcom` will look for `kernel8.img` in the current \
-                     working directory.",
+                    "what to do when the terminal suddenly receives mostly \
+                     garbage, which usually means the board switched its \
+                     UART configuration; `prompt` asks before rescanning \
+                     the baud rate, `auto` rescans without asking and \
+                     `off` only prints a warning.",
                 )
-                .index(1),
+                .long("--baud-rescan")
+                .takes_value(true)
+                .possible_values(&["off", "prompt", "auto"])
+                .default_value("prompt")
+                .require_equals(true),
         )
-        .arg(Arg::with_name("v").short("v").multiple(true).help(
-            "Sets the logging level of verbosity, repeat several times for \
-                higher verbosity",
-        ))
-        .get_matches();
-
-    // Vary the output based on how many times the user used the "verbose" flag
-    // (i.e. 'bootcom -v -v -v' or 'bootcom -vvv' vs 'bootcom -v'
-    let log_level: LevelFilter;
-    match matches.occurrences_of("v") {
-        0 => log_level = LevelFilter::Warn,
-        1 => log_level = LevelFilter::Info,
-        2 => log_level = LevelFilter::Debug,
-        _ => log_level = LevelFilter::Trace,
-    }
-
-    TermLogger::init(
-        log_level,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
-    .unwrap();
-
-    trace!("{:#?}", matches);
-
-    // Arguments with default values ===========================================
-
-    // It's safe to call unwrap on all command line arguments with default
-    // values, because the value with either be what the user input at runtime
-    // or the default value
-
-    let baud_rate = value_t!(matches.value_of("BAUD_RATE"), u32).unwrap_or_else(|_| {
-        println!(
-            "{}: `{}` needs to be a numeric value",
-            style("error").red(),
-            style("baud-rate").cyan()
-        );
-        println!(
-            "   {} `{}` is not a valid value",
-            style("-->").cyan(),
-            style(matches.value_of("BAUD_RATE").unwrap()).on_red()
-        );
-        process::exit(-1);
-    });
-
-    let data_bits = match matches.value_of("DATA_BITS").unwrap() {
-        "5" => DataBits::Five,
-        "6" => DataBits::Six,
-        "7" => DataBits::Seven,
-        "8" => DataBits::Eight,
-        _ => unreachable!(),
-    };
-
-    let stop_bits = match matches.value_of("STOP_BITS").unwrap() {
-        "1" => StopBits::One,
-        "2" => StopBits::Two,
-        _ => unreachable!(),
-    };
-
-    let parity = match matches.value_of("PARITY").unwrap() {
-        "none" => Parity::None,
-        "even" => Parity::Even,
-        "odd" => Parity::Odd,
-        _ => unreachable!(),
-    };
-
-    let flow_control = match matches.value_of("FLOW_CONTROL").unwrap() {
-        "none" => FlowControl::None,
-        "soft" => FlowControl::Software,
-        "hard" => FlowControl::Hardware,
-        _ => unreachable!(),
-    };
-
-    // END - Arguments with default values =====================================
-
-    let mut settings = bc::SettingsBuilder::default()
-        .baud_rate(baud_rate)
-        .data_bits(data_bits)
-        .stop_bits(stop_bits)
-        .parity(parity)
-        .flow_control(flow_control)
-        .finalize();
-
-    // START - Arguments with NO default values ================================
-
-    if matches.is_present("DEVICE_TTY") {
-        settings.path = Some(matches.value_of("DEVICE_TTY").unwrap().into());
-    }
-
-    if matches.is_present("KERNEL_IMAGE") {
-        settings.kernel_image = Some(matches.value_of("KERNEL_IMAGE").unwrap().into());
-    }
-
-    // END - Arguments =========================================================
-
-    // Run the state machine ===================================================
-
-    let mut sdm = bc::singleton(settings);
-    let exit_code = sdm.run();
-    debug!("exit code: {}", exit_code);
-    std::process::exit(exit_code.into());
+        .arg(
+            Arg::with_name("TRIGGER")
+                .help("trigger pattern, its transfer protocol and kernel image")
+                .long_help(
+                    "a trigger pattern, as hex bytes, and the transfer \
+                     protocol to use when the device sends it, separated by \
+                     `:` (e.g. `030303:raspbootin`, `030304:chunked` or \
+                     `434343:xmodem-crc` or `434343:ymodem`), optionally \
+                     followed by the kernel \
+                     image to send for this trigger (e.g. \
+                     `03030301:raspbootin:debug.img`); can be repeated to \
+                     register several triggers, replacing the default \
+                     `030303:raspbootin`. F6 in terminal mode cycles through \
+                     the images to force for the next transfer.",
+                )
+                .long("--trigger")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("HOST_DIR")
+                .help("directory exposed to the booted kernel via host services")
+                .long_help(
+                    "enables the host services, which the booted kernel can \
+                     use to open, read and write files in this directory \
+                     (and get the host time) over the serial line; paths \
+                     outside of the directory are refused.",
+                )
+                .long("--host-dir")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("DUMP_DIR")
+                .help("directory in which the memory dumps of the device are saved")
+                .long_help(
+                    "enables the reception of memory dumps: the device can \
+                     stream a memory region (address, length and CRC-32) \
+                     after sending 0x04 three times, which is saved in this \
+                     directory along with its metadata.",
+                )
+                .long("--dump-dir")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("TIME_SYNC")
+                .help("send the host time to the device, for boards without an RTC")
+                .long_help(
+                    "send the host time to the device when the port is \
+                     opened, and whenever the device asks for it by sending \
+                     0x16 three times, as 0x16 0x16 0x16 followed by the unix \
+                     time in milliseconds (u64, little endian).",
+                )
+                .long("--time-sync"),
+        )
+        .arg(
+            Arg::with_name("SEND_SCRIPT")
+                .help("script of console input lines to send after connection")
+                .long_help(
+                    "a script of console input lines to send to the device \
+                     after connection, to reproduce a fixed sequence of \
+                     commands; lines starting with `@` are directives: \
+                     `@delay <ms>`, `@line-delay <ms>`, `@wait <text>` and \
+                     `@timeout <s>` (for the following waits, 30s by \
+                     default). Use `@@` to send a line starting with `@`.",
+                )
+                .long("--send-script")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PASTE_BYTE_DELAY")
+                .help("pause per byte of pasted text, in microseconds")
+                .long_help(
+                    "pause per byte of text pasted to the device (and of \
+                     script lines), in microseconds; the text is written in \
+                     chunks of 16 bytes so that boards with small UART \
+                     FIFOs don't drop characters.",
+                )
+                .long("--paste-byte-delay")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PASTE_LINE_DELAY")
+                .help("pause after each line of pasted text, in milliseconds")
+                .long("--paste-line-delay")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("RECORD")
+                .help("file to record the console session to, for asciinema")
+                .long_help(
+                    "file to record the console session to, in the asciicast \
+                     v2 format; the recording can be replayed with \
+                     `asciinema play` or shared.",
+                )
+                .long("--record")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("LOG_FILE")
+                .help("file to append the console session to")
+                .long_help(
+                    "file to append the console session to, to keep the boot \
+                     logs of the successive runs and compare them between \
+                     kernel builds; see --log-format and --log-sent.",
+                )
+                .long("--log-file")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("LOG_FORMAT")
+                .help("how the console session is written to the log file")
+                .long_help(
+                    "how the console session is written to the log file: \
+                     `raw` writes the bytes as shown on the terminal, \
+                     `lines` writes the console lines without their escape \
+                     sequences, each prefixed with the time it started.",
+                )
+                .long("--log-format")
+                .takes_value(true)
+                .possible_values(&["raw", "lines"])
+                .default_value("raw")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("LOG_SENT")
+                .help("also log the data sent to the device")
+                .long_help(
+                    "also log the data sent to the device to the log file: \
+                     the keys typed, the pasted text and the scripted input; \
+                     in the `lines` format, the lines sent start with `> `.",
+                )
+                .long("--log-sent")
+                .requires("LOG_FILE"),
+        )
+        .arg(
+            Arg::with_name("REPORT")
+                .help("file to write a report of the run to on exit, in Markdown or HTML")
+                .long_help(
+                    "file to write a report of the run to on exit: settings, \
+                     transfers, timings, errors, first and last console lines \
+                     and links to the files written, for attaching to an \
+                     issue. In HTML when the name ends with `.html`, in \
+                     Markdown otherwise.",
+                )
+                .long("--report")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("DEBUG_BUNDLE")
+                .help("file to save a debug bundle to when bootcom exits with an error")
+                .long_help(
+                    "file to save a debug bundle to when bootcom exits with an \
+                     error or crashes, for reporting a bug in bootcom: a tar \
+                     archive of the log, the end of the console output, the \
+                     state transitions, the effective settings and the \
+                     environment.",
+                )
+                .long("--save-debug-bundle")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("CHAOS")
+                .help("faults to inject in the transfers, e.g. `corrupt=0.01%,drop=1%,delay=5%`")
+                .long_help(
+                    "faults to inject on purpose in the transfers of the kernel \
+                     image, to validate the robustness of a bootloader: the \
+                     rates of the bytes sent corrupted (`corrupt`), of the \
+                     writes dropped (`drop`) and of the reads delayed \
+                     (`delay`), e.g. `corrupt=0.01%,drop=1%,delay=5%`. The \
+                     delay is 1000 ms unless given with `delay-ms`, and the \
+                     seed shown after each transfer can be given with `seed` \
+                     to replay its faults.",
+                )
+                .long("--chaos")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("TRAILER")
+                .help("version of the kernel, appended to the image in a trailer of metadata")
+                .long_help(
+                    "version of the kernel, appended to the image when it is \
+                     sent in a trailer of metadata, along with the git hash, \
+                     the build time and the CRC-32 of the image, for the \
+                     kernel to read at runtime. `bootcom protocol describe` \
+                     shows its layout.",
+                )
+                .long("--trailer")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("GIT_HASH")
+                .help("git hash of the kernel, in the trailer of the image")
+                .long("--git-hash")
+                .takes_value(true)
+                .env("BOOTCOM_GIT_HASH")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("SIGN")
+                .help("key signing the kernel image, a key file or `agent`")
+                .long_help(
+                    "key signing the kernel image with Ed25519: a key file made \
+                     by `bootcom keygen`, or `agent` for the first Ed25519 key \
+                     of the SSH agent. The signature is sent in a header before \
+                     the image, for a secure bootloader to verify it before \
+                     jumping to it; `bootcom protocol describe` shows its layout.",
+                )
+                .long("--sign")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PCAP")
+                .help("file to capture the traffic on the port to, for Wireshark")
+                .long_help(
+                    "file to capture the traffic on the port to, in the pcapng \
+                     format; `bootcom protocol dissector` generates the Lua \
+                     dissector decoding it in Wireshark.",
+                )
+                .long("--pcap")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("STATUS_FILE")
+                .help("file to periodically write the health status to")
+                .long_help(
+                    "file to periodically write the health status to (state, \
+                     time of the last console output, number of boots and \
+                     errors), in the Prometheus text format, for monitoring \
+                     long running tests.",
+                )
+                .long("--status-file")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("STATUS_INTERVAL")
+                .help("interval between health status updates, in seconds")
+                .long("--status-interval")
+                .takes_value(true)
+                .default_value("10")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("SILENCE_ALERT")
+                .help("alert when the console is silent for that many seconds")
+                .long("--silence-alert")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("SILENCE_HOOK")
+                .help("shell command to run on the silence alert")
+                .long_help(
+                    "shell command to run on the silence alert; the silence \
+                     duration in seconds is given in the \
+                     `BOOTCOM_SILENT_SECS` environment variable.",
+                )
+                .long("--silence-hook")
+                .takes_value(true)
+                .requires("SILENCE_ALERT")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("SHOW_BLUETOOTH")
+                .help("offer Bluetooth serial ports in the port selection")
+                .long_help(
+                    "offer Bluetooth virtual serial ports in the port \
+                     selection; they are hidden by default as they clutter \
+                     the list and may hang when opened.",
+                )
+                .long("--show-bluetooth"),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .help("path to the configuration file")
+                .long_help(
+                    "path to the configuration file; by default \
+                     `bootcom/config.toml` in the user configuration \
+                     directory is used if it exists.",
+                )
+                .long("--config")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .help("the connection profile of the configuration file to use")
+                .long_help(
+                    "the connection profile of the configuration file to use, \
+                     giving the port, baud rate, kernel image and reset \
+                     command of a board; the arguments given on the command \
+                     line take precedence.",
+                )
+                .long("--profile")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("RESUME")
+                .help("resume the previous run")
+                .long_help(
+                    "reconnect to the port of the previous run, found again by \
+                     the identity of its USB serial controller, with the same \
+                     line parameters and kernel image; the command line \
+                     arguments given take precedence. The session is saved to \
+                     `bootcom/session.toml` in the user state directory.",
+                )
+                .long("--resume"),
+        )
+        .arg(
+            Arg::with_name("ARCHIVE")
+                .help("archive the kernel images pushed, see `bootcom history`")
+                .long_help(
+                    "archive each kernel image pushed, named by its SHA-256 \
+                     digest, with the report of the transfer, the board it \
+                     went to and the console output of the boot, to \
+                     `bootcom/archive` in the user state directory. `bootcom \
+                     history` lists them.",
+                )
+                .long("--archive"),
+        )
+        .arg(
+            Arg::with_name("FASTBOOT")
+                .help("stage the kernel image through fastboot before attaching the console")
+                .long_help(
+                    "stage the kernel image through the fastboot bootloader \
+                     of the board before attaching its serial console, as \
+                     `fastboot boot` does: `usb` (the only device, with the \
+                     `fastboot` host tool), `usb:<serial>` or \
+                     `tcp:<host>[:<port>]`.",
+                )
+                .long("--fastboot")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("DAEMON")
+                .help("run as a systemd service")
+                .long_help(
+                    "run as a systemd service supervising the board: the \
+                     keyboard is not read, the service manager is notified of \
+                     the readiness, the state and the liveness of bootcom \
+                     (`Type=notify`, `WatchdogSec=`), and the console is \
+                     served on the sockets passed by socket activation.",
+                )
+                .long("--daemon"),
+        )
+        .arg(
+            Arg::with_name("ADVERTISE")
+                .help("advertise the console served on the network with mDNS")
+                .long_help(
+                    "advertise the console served on the TCP sockets passed by \
+                     socket activation on the LAN with multicast DNS, as a \
+                     `_bootcom._tcp` service with the port and the line \
+                     parameters of the board, for the clients to discover it. \
+                     Only with `--daemon`.",
+                )
+                .long("--advertise")
+                .requires("DAEMON"),
+        )
+        .arg(
+            Arg::with_name("PROGRESS")
+                .help("how the progress of the transfers is reported")
+                .long_help(
+                    "how the progress of the transfers is reported: with a \
+                     progress bar, or with JSON objects written one per line \
+                     for tools driving `bootcom`.",
+                )
+                .long("--progress")
+                .takes_value(true)
+                .possible_values(&["bar", "json"])
+                .default_value("bar")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("PERSIST")
+                .help("ask the device to persist the kernel image to its storage")
+                .long_help(
+                    "ask the device to persist the kernel image to its \
+                     storage (eMMC, SD card...) after the transfer, before \
+                     booting it; only supported by the `chunked` transfer \
+                     protocol.",
+                )
+                .long("--persist"),
+        )
+        .arg(
+            Arg::with_name("WATCH")
+                .help("send the kernel image again whenever it is rebuilt")
+                .long_help(
+                    "watch the kernel image in terminal mode and send it \
+                     again once rebuilt: the board is reset with the reset \
+                     command, if any, for the bootloader to ask for the \
+                     image, or the image is sent right away to a bootloader \
+                     still waiting for it.",
+                )
+                .long("--watch"),
+        )
+        .arg(
+            Arg::with_name("RESET_COMMAND")
+                .help("command resetting the board before a rebuilt kernel image is sent again")
+                .long_help(
+                    "command resetting the board before the kernel image \
+                     watched with `--watch` is sent again once rebuilt, run \
+                     with the shell, e.g. a relay or a power switch.",
+                )
+                .long("--reset-command")
+                .takes_value(true)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("MODEM_LINES")
+                .help("show the modem lines (CTS, DSR, CD, RI) when they change")
+                .long_help(
+                    "show the state of the modem lines (CTS, DSR, CD, RI, \
+                     RTS and DTR) in terminal mode whenever it changes; DTR \
+                     and RTS can always be toggled with F2 and F3.",
+                )
+                .long("--modem-lines"),
+        )
+        .arg(
+            Arg::with_name("SETTLE_DELAY")
+                .help("wait after the port appears before opening it, in milliseconds")
+                .long_help(
+                    "wait after the port appears before opening it, in \
+                     milliseconds; gives programs probing new serial devices, \
+                     like ModemManager on Linux, the time to let go of it.",
+                )
+                .long("--settle-delay")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("RESET_GRACE")
+                .help("wait for the device to come back after a reset, in milliseconds")
+                .long_help(
+                    "wait for the device to come back after the connection is \
+                     lost, in milliseconds; boards dropping DTR or \
+                     re-enumerating when they reset are then silently \
+                     reopened and the console session goes on.",
+                )
+                .long("--reset-grace")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("FLUSH_WINDOW")
+                .help("quiet time after a kernel transfer, in milliseconds")
+                .long_help(
+                    "how long the device must stay quiet after the last byte \
+                     of the kernel image, in milliseconds; what the \
+                     bootloader prints in the meantime is shown before the \
+                     transfer summary instead of being interleaved with it.",
+                )
+                .long("--flush-window")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("MAX_SEND_ATTEMPTS")
+                .help("consecutive failed kernel transfers before giving up")
+                .long_help(
+                    "how many consecutive kernel transfers may fail, each \
+                     time the device requests the image again, before the \
+                     session is declared failed; 0 for no limit.",
+                )
+                .long("--max-send-attempts")
+                .takes_value(true)
+                .default_value("5")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("MAX_SELECTION_ATTEMPTS")
+                .help("canceled port or image selections before giving up")
+                .long_help(
+                    "how many times in a row the interactive selection of a \
+                     port or of a kernel image may be canceled, or fail, \
+                     before giving up; 0 for no limit.",
+                )
+                .long("--max-selection-attempts")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("MAX_PORT_WAIT_ATTEMPTS")
+                .help("times the ports are looked for before giving up")
+                .long_help(
+                    "how many times the serial ports are looked for, every \
+                     one or two seconds, while waiting for a port to appear, \
+                     before giving up; 0 for no limit.",
+                )
+                .long("--max-port-wait-attempts")
+                .takes_value(true)
+                .default_value("0")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("MAX_READ")
+                .help("maximum number of bytes read at once in terminal mode")
+                .long_help(
+                    "maximum number of bytes read at once from the serial port \
+                     in terminal mode; raise it if the console falls behind \
+                     the output of the device at high baud rates.",
+                )
+                .long("--max-read")
+                .takes_value(true)
+                .default_value("65536")
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("KERNEL_IMAGE")
+                .help("path to the kernel image to be pushed")
+                .long_help(
+                    "path to the kernel image to be pushed; when not \
+                     set, `bootcom` will look for `kernel8.img` in the current \
+                     working directory.",
+                )
+                .index(1),
+        )
+        .arg(Arg::with_name("v").short("v").multiple(true).help(
+            "Sets the logging level of verbosity, repeat several times for \
+                higher verbosity",
+        ))
+        .subcommand(
+            SubCommand::with_name("attach")
+                .about("Attaches to the console of a board served by a remote bootcom daemon")
+                .arg(
+                    Arg::with_name("ADDRESS")
+                        .help("the daemon: `host`, `host:port` or the path of a Unix socket")
+                        .long_help(
+                            "the daemon serving the console of the board: `host` \
+                             (on port 7000), `host:port`, or the path of a Unix \
+                             socket.",
+                        )
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("TOKEN")
+                        .help("the token authenticating with the daemon, only sent to a Unix socket")
+                        .long("--token")
+                        .takes_value(true)
+                        .env("BOOTCOM_TOKEN")
+                        .hide_env_values(true)
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .help("kernel image uploaded for the next push, again on F6")
+                        .long("--image")
+                        .takes_value(true)
+                        .require_equals(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("boards")
+                .about("Lists the boards of the inventory with their status"),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compares the console logs of two boots, listing the new errors")
+                .arg(
+                    Arg::with_name("BOOTS")
+                        .help("the number of an archived boot or the path of a console log, twice to compare them")
+                        .long_help(
+                            "the number of an archived boot, e.g. `#42`, or the path of a \
+                             console log. Given twice, the first one is compared with the \
+                             second one. Given once, the last boot of the same board which \
+                             reached all its boot stages is compared with this boot. By \
+                             default, the last boot archived.",
+                        )
+                        .multiple(true)
+                        .max_values(2)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("IGNORE")
+                        .help("leave out the lines containing this text, in addition to `[diff]`")
+                        .long("--ignore")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .require_equals(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Lists the boots archived with --archive, shows or compares them")
+                .arg(
+                    Arg::with_name("BOOTS")
+                        .help("the number of a boot to show, or of two boots to compare")
+                        .multiple(true)
+                        .max_values(2)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("BOARD")
+                        .help("only list the boots of this board")
+                        .long("--board")
+                        .takes_value(true)
+                        .require_equals(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Creates an Ed25519 key to sign the kernel images with --sign")
+                .arg(
+                    Arg::with_name("KEY_FILE")
+                        .help("file to write the key to, its public key going to the same file with `.pub` added")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("messages")
+                .about("Prints the messages in English, as a catalog file to translate them in"),
+        )
+        .subcommand(
+            SubCommand::with_name("pool")
+                .about("Pushes a kernel image to the first idle board of a profile and checks it boots")
+                .arg(
+                    Arg::with_name("PROFILE")
+                        .help("the profile of the boards of the inventory to pick from")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("IMAGE")
+                        .help("kernel image to push (default: the one of the board)")
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("EXPECT")
+                        .help("text expected on the console once booted (default: the last `[[expect]]` stage)")
+                        .long("--expect")
+                        .takes_value(true)
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("BOOT_TIMEOUT")
+                        .help("seconds to wait for the expected text (default: the `[[expect]]` timeouts)")
+                        .long("--boot-timeout")
+                        .takes_value(true)
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("WAIT")
+                        .help("seconds to wait for a board of the profile to be idle")
+                        .long("--wait")
+                        .takes_value(true)
+                        .default_value("600")
+                        .require_equals(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("protocol")
+                .about("Shows the wire protocol spoken with the bootloader")
+                .setting(SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("describe").about(
+                    "Prints the triggers, frames, handshakes and checksums as configured",
+                ))
+                .subcommand(
+                    SubCommand::with_name("dissector")
+                        .about("Generates the Wireshark dissector of the `--pcap` captures")
+                        .arg(
+                            Arg::with_name("OUTPUT")
+                                .help("file to write the Lua dissector to (default: stdout)")
+                                .short("-o")
+                                .long("--output")
+                                .takes_value(true)
+                                .require_equals(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("raw")
+                .about("Bridges stdin and stdout to the device, 8-bit clean, for external tools")
+                .long_about(
+                    "Bridges stdin and stdout to the device, 8-bit clean: the bytes are \
+                     copied as they are, with no triggers, quirks or codecs. The port is \
+                     selected and reopened after a reset as in the interactive session, \
+                     and the messages go to stderr. Ends when stdin is closed.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Shows the usage statistics kept with `stats = true` in `[usage]`")
+                .long_about(
+                    "Shows the usage statistics kept in a local file once enabled with \
+                     `stats = true` in the `[usage]` section of the configuration file: \
+                     the kernel images pushed in total and by board, and day by day over \
+                     the last days. Nothing is ever sent anywhere.",
+                )
+                .arg(
+                    Arg::with_name("DAYS")
+                        .help("number of days to show the pushes of")
+                        .long("--days")
+                        .takes_value(true)
+                        .default_value("14")
+                        .require_equals(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stub")
+                .about("Generates a bootloader receiver stub matching bootcom's protocol")
+                .arg(
+                    Arg::with_name("TARGET")
+                        .help("target architecture of the stub")
+                        .long("--target")
+                        .takes_value(true)
+                        .possible_values(&["aarch64", "arm", "riscv64"])
+                        .default_value("aarch64")
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("LANGUAGE")
+                        .help("language of the generated stub")
+                        .long("--lang")
+                        .takes_value(true)
+                        .possible_values(&["rust", "c"])
+                        .default_value("rust")
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("TRIGGER")
+                        .help("trigger pattern sent by the stub, as hex bytes")
+                        .long("--trigger")
+                        .takes_value(true)
+                        .default_value("030303")
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("NO_CRC")
+                        .help("do not send the CRC-32 of the received image back")
+                        .long("--no-crc"),
+                )
+                .arg(
+                    Arg::with_name("PROTOCOL")
+                        .help("protocol the stub receives the kernel image with")
+                        .long("--protocol")
+                        .takes_value(true)
+                        .possible_values(&["raspbootin", "chunked", "chunked-v2"])
+                        .default_value("raspbootin")
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("BUFFER")
+                        .help("receive buffer advertised with the chunked protocol, in bytes")
+                        .long("--buffer")
+                        .takes_value(true)
+                        .default_value("256")
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("LOAD_ADDRESS")
+                        .help("load address of the kernel, in hex (default depends on target)")
+                        .long("--load-address")
+                        .takes_value(true)
+                        .require_equals(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("file to write the stub to (default: stdout)")
+                        .short("-o")
+                        .long("--output")
+                        .takes_value(true)
+                        .require_equals(true),
+                ),
+        )
+        .get_matches();
+
+    if matches.subcommand_matches("messages").is_some() {
+        print!("{}", messages::template());
+        return;
+    }
+
+    let raw = matches.subcommand_matches("raw").is_some();
+    // Vary the output based on how many times the user used the "verbose" flag
+    // (i.e. 'bootcom -v -v -v' or 'bootcom -vvv' vs 'bootcom -v'
+    let log_level = match matches.occurrences_of("v") {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    let terminal_mode = if raw {
+        TerminalMode::Stderr
+    } else {
+        TerminalMode::Mixed
+    };
+    // The debug bundle needs the log, even when it is not shown.
+    let debug_log = matches
+        .value_of("DEBUG_BUNDLE")
+        .map(|_| debug_bundle::LogCapture::default());
+    match &debug_log {
+        Some(log) => CombinedLogger::init(vec![
+            TermLogger::new(
+                log_level,
+                Config::default(),
+                terminal_mode,
+                ColorChoice::Auto,
+            ),
+            WriteLogger::new(
+                log_level.max(LevelFilter::Info),
+                Config::default(),
+                log.clone(),
+            ),
+        ]),
+        None => TermLogger::init(
+            log_level,
+            Config::default(),
+            terminal_mode,
+            ColorChoice::Auto,
+        ),
+    }
+    .unwrap();
+
+    let (config, config_file, catalog) = load_config(&matches);
+
+    if let Some(stub_matches) = matches.subcommand_matches("stub") {
+        generate_stub(&catalog, stub_matches);
+        return;
+    }
+
+    if let Some(attach_matches) = matches.subcommand_matches("attach") {
+        attach_daemon(&catalog, attach_matches);
+        return;
+    }
+
+    if matches.subcommand_matches("boards").is_some() {
+        list_boards(&catalog, &config);
+        return;
+    }
+
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        diff_logs(&catalog, &config, diff_matches);
+        return;
+    }
+
+    if let Some(keygen_matches) = matches.subcommand_matches("keygen") {
+        generate_key(&catalog, keygen_matches);
+        return;
+    }
+
+    if let Some(history_matches) = matches.subcommand_matches("history") {
+        show_history(&catalog, history_matches);
+        return;
+    }
+
+    if let Some(stats_matches) = matches.subcommand_matches("stats") {
+        show_usage(&catalog, stats_matches);
+        return;
+    }
+
+    // The protocol descriptions and the dissector are redirected to files, and
+    // the standard output of the raw bridge only carries the device output.
+    if matches.subcommand_matches("protocol").is_none() && !raw {
+        println!(
+            "{}",
+            catalog.text_with("cli.version", &[("version", &crate_version!())])
+        );
+    }
+
+    trace!("{:#?}", matches);
+
+    // Arguments with default values ===========================================
+
+    // It's safe to call unwrap on all command line arguments with default
+    // values, because the value with either be what the user input at runtime
+    // or the default value
+
+    let baud_rate = value_t!(matches.value_of("BAUD_RATE"), u32).unwrap_or_else(|_| {
+        error(
+            &catalog,
+            catalog.text_with("cli.numeric", &[("arg", &style("baud-rate").cyan())]),
+        );
+        detail(catalog.text_with(
+            "cli.invalid_value",
+            &[(
+                "value",
+                &style(matches.value_of("BAUD_RATE").unwrap()).on_red(),
+            )],
+        ));
+        process::exit(-1);
+    });
+
+    let data_bits = match matches.value_of("DATA_BITS").unwrap() {
+        "5" => DataBits::Five,
+        "6" => DataBits::Six,
+        "7" => DataBits::Seven,
+        "8" => DataBits::Eight,
+        _ => unreachable!(),
+    };
+
+    let stop_bits = match matches.value_of("STOP_BITS").unwrap() {
+        "1" => StopBits::One,
+        "2" => StopBits::Two,
+        _ => unreachable!(),
+    };
+
+    let parity = match matches.value_of("PARITY").unwrap() {
+        "none" => Parity::None,
+        "even" => Parity::Even,
+        "odd" => Parity::Odd,
+        _ => unreachable!(),
+    };
+
+    let flow_control = match matches.value_of("FLOW_CONTROL").unwrap() {
+        "none" => FlowControl::None,
+        "soft" => FlowControl::Software,
+        "hard" => FlowControl::Hardware,
+        _ => unreachable!(),
+    };
+
+    let baud_rescan = match matches.value_of("BAUD_RESCAN").unwrap() {
+        "off" => bc::BaudRescan::Off,
+        "prompt" => bc::BaudRescan::Prompt,
+        "auto" => bc::BaudRescan::Auto,
+        _ => unreachable!(),
+    };
+
+    let paste_pacing = bc::PastePacing {
+        byte_delay: Duration::from_micros(numeric_arg(&catalog, &matches, "PASTE_BYTE_DELAY")),
+        line_delay: Duration::from_millis(numeric_arg(&catalog, &matches, "PASTE_LINE_DELAY")),
+    };
+
+    let mut health = bc::HealthReporting {
+        interval: Duration::from_secs(numeric_arg(&catalog, &matches, "STATUS_INTERVAL")),
+        ..bc::HealthReporting::default()
+    };
+
+    let retry = bc::RetryPolicy {
+        send_attempts: numeric_arg(&catalog, &matches, "MAX_SEND_ATTEMPTS") as usize,
+        selection_attempts: numeric_arg(&catalog, &matches, "MAX_SELECTION_ATTEMPTS") as usize,
+        port_wait_attempts: numeric_arg(&catalog, &matches, "MAX_PORT_WAIT_ATTEMPTS") as usize,
+    };
+
+    // END - Arguments with default values =====================================
+
+    let mut settings = bc::SettingsBuilder::default()
+        .baud_rate(baud_rate)
+        .data_bits(data_bits)
+        .stop_bits(stop_bits)
+        .parity(parity)
+        .flow_control(flow_control)
+        .baud_rescan(baud_rescan)
+        .paste_pacing(paste_pacing)
+        .bluetooth_ports(matches.is_present("SHOW_BLUETOOTH"))
+        .advertise(matches.is_present("ADVERTISE"))
+        .modem_lines(matches.is_present("MODEM_LINES"))
+        .persist(matches.is_present("PERSIST"))
+        .watch(matches.is_present("WATCH"))
+        .time_sync(matches.is_present("TIME_SYNC"))
+        .retry(retry)
+        .settle_delay(Duration::from_millis(numeric_arg(
+            &catalog,
+            &matches,
+            "SETTLE_DELAY",
+        )))
+        .reset_grace(Duration::from_millis(numeric_arg(
+            &catalog,
+            &matches,
+            "RESET_GRACE",
+        )))
+        .flush_window(Duration::from_millis(numeric_arg(
+            &catalog,
+            &matches,
+            "FLUSH_WINDOW",
+        )))
+        .max_read_size(numeric_arg(&catalog, &matches, "MAX_READ") as usize)
+        .progress_theme(config.progress)
+        .expectations(config.expectations)
+        .quirks(config.quirks)
+        .codecs(config.codecs)
+        .captures(config.captures)
+        .severities(config.severities)
+        .instruments(config.instruments)
+        .straps(config.straps)
+        .flashers(config.flashers)
+        .transfer_keys(config.encryption)
+        .streams(config.streams)
+        .access(config.access)
+        .boards(config.boards)
+        .messages(catalog)
+        .finalize();
+
+    if let Some(path) = config_file {
+        settings.config_file = Some(path.display().to_string());
+    }
+    settings.uboot = config.uboot;
+
+    if let Some(path) = resume::default_path() {
+        if matches.is_present("RESUME") {
+            resume_session(&mut settings, &matches, &path);
+        }
+        settings.resume_file = Some(path.display().to_string());
+    }
+
+    if let Some(name) = matches.value_of("PROFILE") {
+        use_profile(&mut settings, &matches, &config.profiles, name);
+    }
+
+    if let Some(path) = boards::default_path() {
+        settings.boards_file = Some(path.display().to_string());
+    }
+
+    if config.usage_stats {
+        settings.usage_file = usage::default_path().map(|path| path.display().to_string());
+    }
+
+    if matches.is_present("ARCHIVE") {
+        settings.archive = archive::default_path().map(|path| path.display().to_string());
+    }
+
+    if matches.is_present("DAEMON") {
+        settings.systemd = true;
+        // Nobody at the keyboard, the prompts take their default answer.
+        settings.keyboard = false;
+    }
+
+    if matches.value_of("PROGRESS") == Some("json") {
+        settings.progress_observer = Some(ObserverHandle::new(JsonProgress));
+    }
+
+    // START - Arguments with NO default values ================================
+
+    if matches.is_present("DEVICE_TTY") {
+        settings.path = Some(matches.value_of("DEVICE_TTY").unwrap().into());
+    }
+
+    let messages = settings.messages.clone();
+    let rule = bc::profiles::PortRule {
+        path: None,
+        vid: usb_id_arg(&messages, &matches, "VID"),
+        pid: usb_id_arg(&messages, &matches, "PID"),
+        serial_number: matches.value_of("SERIAL_NUMBER").map(String::from),
+    };
+    if rule != bc::profiles::PortRule::default() {
+        // Found once waited for.
+        settings.path = None;
+        settings.port_match = Some(rule);
+    }
+
+    if let Some(values) = matches.values_of("TRIGGER") {
+        settings.triggers = values
+            .map(|value| {
+                parse_trigger(value).unwrap_or_else(|| {
+                    error(
+                        &messages,
+                        messages.text_with("cli.trigger", &[("arg", &style("trigger").cyan())]),
+                    );
+                    detail(
+                        messages
+                            .text_with("cli.invalid_value", &[("value", &style(value).on_red())]),
+                    );
+                    process::exit(-1);
+                })
+            })
+            .collect();
+    }
+
+    if matches.is_present("HOST_DIR") {
+        settings.host_dir = Some(matches.value_of("HOST_DIR").unwrap().into());
+    }
+
+    if matches.is_present("DUMP_DIR") {
+        settings.dump_dir = Some(matches.value_of("DUMP_DIR").unwrap().into());
+    }
+
+    if matches.is_present("SEND_SCRIPT") {
+        settings.send_script = Some(matches.value_of("SEND_SCRIPT").unwrap().into());
+    }
+
+    if matches.is_present("RECORD") {
+        settings.record = Some(matches.value_of("RECORD").unwrap().into());
+    }
+
+    if let Some(path) = matches.value_of("LOG_FILE") {
+        settings.log_file = Some(bc::SessionLog {
+            path: path.into(),
+            format: match matches.value_of("LOG_FORMAT").unwrap() {
+                "raw" => bc::LogFormat::Raw,
+                "lines" => bc::LogFormat::Lines,
+                _ => unreachable!(),
+            },
+            sent: matches.is_present("LOG_SENT"),
+        });
+    }
+
+    if matches.is_present("RESET_COMMAND") {
+        settings.reset_command = Some(matches.value_of("RESET_COMMAND").unwrap().into());
+    }
+
+    if let Some(path) = matches.value_of("DEBUG_BUNDLE") {
+        settings.debug_bundle = Some(path.into());
+        settings.debug_log = debug_log;
+    }
+
+    if matches.is_present("REPORT") {
+        settings.report = Some(matches.value_of("REPORT").unwrap().into());
+    }
+
+    if let Some(chaos) = matches.value_of("CHAOS") {
+        settings.chaos = Some(chaos.parse().unwrap_or_else(|e| {
+            error(
+                &messages,
+                messages.text_with(
+                    "cli.invalid_arg",
+                    &[("arg", &style("chaos").cyan()), ("error", &e)],
+                ),
+            );
+            process::exit(-1);
+        }));
+    }
+
+    if let Some(version) = matches.value_of("TRAILER") {
+        settings.trailer = Some(bc::Trailer {
+            version: version.into(),
+            git_hash: matches.value_of("GIT_HASH").map(Into::into),
+        });
+    }
+
+    if let Some(key) = matches.value_of("SIGN") {
+        settings.signing_key = Some(key.parse().unwrap_or_else(|e| {
+            error(
+                &messages,
+                messages.text_with(
+                    "cli.invalid_arg",
+                    &[("arg", &style("sign").cyan()), ("error", &e)],
+                ),
+            );
+            process::exit(-1);
+        }));
+    }
+
+    if let Some(path) = matches.value_of("PCAP") {
+        settings.pcap = Some(bc::pcap::PcapCapture::create(path).unwrap_or_else(|e| {
+            error(
+                &messages,
+                messages.text_with("cli.not_created", &[("path", &path), ("error", &e)]),
+            );
+            process::exit(-1);
+        }));
+    }
+
+    if matches.is_present("STATUS_FILE") {
+        health.status_file = Some(matches.value_of("STATUS_FILE").unwrap().into());
+    }
+
+    if matches.is_present("SILENCE_ALERT") {
+        health.silence_threshold = Some(Duration::from_secs(numeric_arg(
+            &messages,
+            &matches,
+            "SILENCE_ALERT",
+        )));
+    }
+
+    if matches.is_present("SILENCE_HOOK") {
+        health.silence_hook = Some(matches.value_of("SILENCE_HOOK").unwrap().into());
+    }
+
+    settings.health = health;
+
+    if matches.is_present("KERNEL_IMAGE") {
+        settings.kernel_image = Some(matches.value_of("KERNEL_IMAGE").unwrap().into());
+    }
+
+    // END - Arguments =========================================================
+
+    if let Some(protocol_matches) = matches.subcommand_matches("protocol") {
+        if protocol_matches.subcommand_matches("describe").is_some() {
+            for section in protocol::describe(&settings) {
+                print!("\n{}", section);
+            }
+        }
+        if let Some(dissector_matches) = protocol_matches.subcommand_matches("dissector") {
+            write_output(
+                &settings.messages,
+                dissector_matches.value_of("OUTPUT"),
+                &bc::pcap::dissector(&settings),
+            );
+        }
+        return;
+    }
+
+    if raw {
+        bridge_raw(&settings);
+    }
+
+    if let Some(pool_matches) = matches.subcommand_matches("pool") {
+        run_on_pool(settings, pool_matches);
+    }
+
+    if let Some(target) = matches.value_of("FASTBOOT") {
+        stage_with_fastboot(&settings, target);
+    }
+
+    // Run the state machine ===================================================
+
+    let mut sdm = bc::BootServer::new(settings);
+
+    let interrupted = sdm.clone();
+    let interrupted_messages = messages.clone();
+    ctrlc::set_handler(move || {
+        let messages = &interrupted_messages;
+        println!("{}", messages.text("cli.interrupted"));
+        println!("{}", interrupted.stats().text(messages));
+        interrupted.write_report();
+        process::exit(0);
+    })
+    .expect("Failed to install my Ctrl-C handler!");
+
+    let exit_code = sdm.run();
+    println!("{}", sdm.stats().text(&messages));
+    debug!("exit code: {}", exit_code);
+    std::process::exit(exit_code.into());
+}
+
+/// Get the value of a numeric argument, exiting with an error if it is not a
+/// number. The argument must be present or have a default value.
+fn numeric_arg(messages: &Catalog, matches: &ArgMatches, name: &str) -> u64 {
+    value_t!(matches.value_of(name), u64).unwrap_or_else(|_| {
+        let arg = name.to_lowercase().replace('_', "-");
+        error(
+            messages,
+            messages.text_with("cli.numeric", &[("arg", &style(arg).cyan())]),
+        );
+        detail(messages.text_with(
+            "cli.invalid_value",
+            &[("value", &style(matches.value_of(name).unwrap()).on_red())],
+        ));
+        process::exit(-1);
+    })
+}
+
+/// Parse the USB ID given in hexadecimal to the argument `name`, if any, exiting
+/// with an error if it is not valid.
+fn usb_id_arg(messages: &Catalog, matches: &ArgMatches, name: &str) -> Option<u16> {
+    let value = matches.value_of(name)?;
+    let digits = value.trim_start_matches("0x");
+    Some(u16::from_str_radix(digits, 16).unwrap_or_else(|_| {
+        error(
+            messages,
+            messages.text_with("cli.usb_id", &[("arg", &style(name.to_lowercase()).cyan())]),
+        );
+        detail(messages.text_with("cli.invalid_value", &[("value", &style(value).on_red())]));
+        process::exit(-1);
+    }))
+}
+
+/// Stage the kernel image of the `settings` through the fastboot bootloader of
+/// the `target`, exiting with an error if it fails.
+fn stage_with_fastboot(settings: &bc::Settings, target: &str) {
+    let messages = &settings.messages;
+    let target: fastboot::FastbootTarget = target.parse().unwrap_or_else(|e| {
+        error(messages, e);
+        process::exit(-1);
+    });
+    let image = settings.kernel_image.as_deref().unwrap_or("kernel8.img");
+    println!(
+        "{}",
+        messages.text_with(
+            "cli.staging",
+            &[("image", &style(image).cyan()), ("target", &target)],
+        )
+    );
+    match fastboot::boot(settings, &target, image) {
+        Ok(report) => println!(
+            "{}",
+            messages.text_with("cli.staged", &[("report", &report)])
+        ),
+        Err(e) => {
+            error(messages, messages.text("cli.not_staged"));
+            detail(e);
+            process::exit(-1);
+        }
+    }
+}
+
+/// Load the configuration file given on the command line, or the default one
+/// if it exists, returning it along with its path and its message catalog.
+/// The errors loading them are in English, the catalog being unknown yet.
+fn load_config(matches: &ArgMatches) -> (config::Config, Option<PathBuf>, Catalog) {
+    let english = Catalog::default();
+    let path = match matches.value_of("CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => match config::default_path() {
+            Some(path) if path.exists() => path,
+            _ => return (config::Config::default(), None, english),
+        },
+    };
+    debug!("Loading configuration from {}", path.display());
+    let config = config::load(&path).unwrap_or_else(|e| {
+        error(&english, english.text("cli.invalid_config"));
+        detail(e);
+        process::exit(-1);
+    });
+    let catalog = match &config.message_catalog {
+        Some(catalog) => messages::load(&path.with_file_name(catalog)).unwrap_or_else(|e| {
+            error(&english, english.text("cli.invalid_catalog"));
+            detail(e);
+            process::exit(-1);
+        }),
+        None => english,
+    };
+    (config, Some(path), catalog)
+}
+
+/// Apply the connection profile `name` of the configuration file to the
+/// `settings`, except for the baud rate given on the command line, exiting with
+/// an error if there is no such profile. The port, the kernel image and the
+/// reset command given on the command line are applied later.
+fn use_profile(
+    settings: &mut bc::Settings,
+    matches: &ArgMatches,
+    profiles: &BTreeMap<String, bc::profiles::Profile>,
+    name: &str,
+) {
+    let messages = &settings.messages;
+    let profile = profiles.get(name).unwrap_or_else(|| {
+        error(
+            messages,
+            messages.text_with("cli.no_profile", &[("name", &style(name).cyan())]),
+        );
+        if !profiles.is_empty() {
+            let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            detail(messages.text_with("cli.known_profiles", &[("names", &names.join(", "))]));
+        }
+        process::exit(-1);
+    });
+    let given = settings.baud_rate;
+    profile.apply(settings);
+    if matches.occurrences_of("BAUD_RATE") > 0 {
+        settings.baud_rate = given;
+    }
+}
+
+/// Apply the state saved by the previous run from the resume file at `path` to
+/// the `settings`, except for the line parameters given on the command line.
+/// The port and the kernel image given on the command line are applied later.
+fn resume_session(settings: &mut bc::Settings, matches: &ArgMatches, path: &Path) {
+    let state = match resume::load(path) {
+        Ok(state) => state,
+        Err(e) => {
+            println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("cli.nothing_to_resume", &[("error", &e)])
+                )
+                .yellow()
+            );
+            return;
+        }
+    };
+    let given = settings.clone();
+    state.apply(settings);
+    if matches.occurrences_of("BAUD_RATE") > 0 {
+        settings.baud_rate = given.baud_rate;
+    }
+    if matches.occurrences_of("DATA_BITS") > 0 {
+        settings.data_bits = given.data_bits;
+    }
+    if matches.occurrences_of("STOP_BITS") > 0 {
+        settings.stop_bits = given.stop_bits;
+    }
+    if matches.occurrences_of("PARITY") > 0 {
+        settings.parity = given.parity;
+    }
+    if matches.occurrences_of("FLOW_CONTROL") > 0 {
+        settings.flow_control = given.flow_control;
+    }
+    if let Some(port) = &settings.path {
+        println!(
+            "{}",
+            settings
+                .messages
+                .text_with("cli.resuming", &[("port", &style(port).cyan())])
+        );
+    }
+}
+
+/// Parse a trigger specification of the form `<hex bytes>:<protocol>`,
+/// optionally followed by `:<kernel image>`.
+fn parse_trigger(value: &str) -> Option<bc::Trigger> {
+    let mut parts = value.splitn(3, ':');
+    let pattern = parse_hex(parts.next()?)?;
+    let protocol = match parts.next()? {
+        "raspbootin" => bc::TransferProtocol::Raspbootin,
+        "chunked" => bc::TransferProtocol::Chunked,
+        "xmodem-crc" => bc::TransferProtocol::XmodemCrc,
+        "ymodem" => bc::TransferProtocol::Ymodem,
+        _ => return None,
+    };
+    let image = match parts.next() {
+        Some("") => return None,
+        image => image.map(String::from),
+    };
+    Some(bc::Trigger {
+        pattern,
+        protocol,
+        image,
+    })
+}
+
+/// Parse a non-empty sequence of bytes written in hex (e.g. `030303`).
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Handle the `raw` subcommand: bridge the standard streams to the device
+/// until the standard input is closed.
+fn bridge_raw(settings: &bc::Settings) -> ! {
+    match bc::raw::bridge(settings) {
+        Ok(()) => process::exit(0),
+        Err(e) => {
+            let messages = &settings.messages;
+            eprintln!(
+                "{}: {}",
+                style(messages.text("cli.error")).red(),
+                messages.text_with("cli.raw_stopped", &[("error", &e)])
+            );
+            process::exit(-1);
+        }
+    }
+}
+
+/// Handle the `attach` subcommand: attach to the console of the daemon until the
+/// user detaches.
+fn attach_daemon(messages: &Catalog, matches: &ArgMatches) {
+    use bc::attach::{attach, AttachOptions};
+
+    let address = matches.value_of("ADDRESS").unwrap();
+    let options = AttachOptions {
+        token: matches.value_of("TOKEN").map(str::to_owned),
+        image: matches.value_of("IMAGE").map(str::to_owned),
+        messages: messages.clone(),
+    };
+    if let Err(e) = attach(address, &options) {
+        error(
+            messages,
+            messages.text_with("cli.not_attached", &[("address", &address), ("error", &e)]),
+        );
+        process::exit(-1);
+    }
+}
+
+/// Handle the `boards` subcommand: list the boards of the inventory declared
+/// in the configuration file with their status.
+fn list_boards(messages: &Catalog, config: &config::Config) {
+    if config.boards.is_empty() {
+        println!("{}", messages.text("cli.no_boards"));
+        return;
+    }
+    let file = boards::default_path();
+    for status in boards::inventory(&config.boards, file.as_deref()) {
+        let port = match &status.port {
+            boards::PortStatus::Idle => style(status.port.to_string()).green(),
+            boards::PortStatus::InUse(_) => style(status.port.to_string()).yellow(),
+            boards::PortStatus::Missing => style(status.port.to_string()).red(),
+        };
+        println!(
+            "{}",
+            messages.text_with(
+                "cli.board",
+                &[
+                    ("health", &if status.is_healthy() { "✅" } else { "❌" }),
+                    ("name", &style(&status.board.name).cyan()),
+                    ("path", &status.path),
+                    ("port", &port),
+                ],
+            )
+        );
+        if let Some(profile) = &status.board.profile {
+            println!(
+                "{}",
+                messages.text_with("cli.board_profile", &[("profile", profile)])
+            );
+        }
+        if let Some(image) = &status.board.image {
+            println!(
+                "{}",
+                messages.text_with("cli.board_image", &[("image", image)])
+            );
+        }
+        let boot = match &status.last_boot {
+            Some(boot) => boot.to_string(),
+            None => style(messages.text("cli.no_boot_recorded"))
+                .dim()
+                .to_string(),
+        };
+        println!(
+            "{}",
+            messages.text_with("cli.board_last_boot", &[("boot", &boot)])
+        );
+    }
+}
+
+/// Handle the `history` subcommand: list the boots of the archive, show one,
+/// or compare two of them.
+fn show_history(messages: &Catalog, matches: &ArgMatches) {
+    let exit_on_error = |e: String| -> ! {
+        error(messages, e);
+        process::exit(-1);
+    };
+    let dir =
+        archive::default_path().unwrap_or_else(|| exit_on_error(messages.text("cli.no_state_dir")));
+    let ids: Vec<u64> = matches
+        .values_of("BOOTS")
+        .map(|values| {
+            values
+                .map(|id| {
+                    id.trim_start_matches('#').parse().unwrap_or_else(|_| {
+                        exit_on_error(messages.text_with("cli.not_boot_number", &[("id", &id)]))
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let boots: Vec<_> = ids
+        .iter()
+        .map(|id| archive::load(&dir, *id).unwrap_or_else(|e| exit_on_error(e)))
+        .collect();
+    match boots.as_slice() {
+        [] => {
+            let boots = archive::list(&dir).unwrap_or_else(|e| exit_on_error(e));
+            let board = matches.value_of("BOARD");
+            let boots: Vec<_> = boots
+                .iter()
+                .filter(|boot| board.is_none() || boot.board.as_deref() == board)
+                .collect();
+            match board {
+                _ if !boots.is_empty() => (),
+                Some(board) => println!(
+                    "{}",
+                    messages.text_with("cli.no_boots_of", &[("board", &board)])
+                ),
+                None => println!("{}", messages.text("cli.no_boots")),
+            }
+            for boot in boots {
+                println!("{}", boot);
+            }
+        }
+        [boot] => {
+            for (name, value) in boot.details(&dir) {
+                println!("{:>10}  {}", style(name).dim(), value);
+            }
+            println!(
+                "{:>10}  {}",
+                style(messages.text("cli.boot_file")).dim(),
+                boot.image_path(&dir).display()
+            );
+            println!(
+                "{:>10}  {}",
+                style(messages.text("cli.boot_log")).dim(),
+                boot.log_path(&dir).display()
+            );
+        }
+        [a, b] => {
+            println!("{:>10}  #{} → #{}", "", a.id, b.id);
+            for ((name, before), (_, after)) in a.details(&dir).into_iter().zip(b.details(&dir)) {
+                if before == after {
+                    println!("{:>10}  {}", style(name).dim(), before);
+                } else {
+                    println!(
+                        "{:>10}  {} → {}",
+                        style(name).cyan(),
+                        style(before).red(),
+                        style(after).green()
+                    );
+                }
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Handle the `stats` subcommand: show the usage statistics, in total, by board
+/// and day by day over the last days.
+fn show_usage(messages: &Catalog, matches: &ArgMatches) {
+    let exit_on_error = |e: String| -> ! {
+        error(messages, e);
+        process::exit(-1);
+    };
+    let path =
+        usage::default_path().unwrap_or_else(|| exit_on_error(messages.text("cli.no_state_dir")));
+    if !path.exists() {
+        println!("{}", messages.text("cli.no_usage"));
+        return;
+    }
+    let usage = usage::load(&path).unwrap_or_else(|e| exit_on_error(e));
+    let days = numeric_arg(messages, matches, "DAYS");
+
+    print!("📊 {}", usage.total);
+    match usage.first_day() {
+        Some(day) => println!(
+            "{}",
+            messages.text_with("cli.usage_since", &[("day", &style(day).dim())])
+        ),
+        None => println!(),
+    }
+    for (board, tally) in &usage.boards {
+        println!("   {:<16}  {}", style(board).cyan(), tally);
+    }
+
+    println!();
+    let now = std::time::SystemTime::now();
+    let last_days = usage.last_days(now, days);
+    let most = last_days.iter().map(|(_, tally)| tally.boots).max();
+    for (day, tally) in &last_days {
+        // The bars are scaled to the busiest day.
+        let bar = match most {
+            Some(most) if most > 0 => "█".repeat((tally.boots * 20).div_ceil(most) as usize),
+            _ => String::new(),
+        };
+        println!(
+            "   {}  {:<20}  {}",
+            style(day).dim(),
+            style(bar).green(),
+            tally
+        );
+    }
+
+    // The last week against the one before, to tell where the transfer times
+    // are going.
+    let weeks = usage.last_days(now, 14);
+    let (before, last) = weeks.split_at(7);
+    let sum = |days: &[(String, usage::Tally)]| {
+        days.iter()
+            .fold(usage::Tally::default(), |mut sum, (_, tally)| {
+                sum.add(tally);
+                sum
+            })
+    };
+    println!();
+    println!(
+        "   {:<16}  {}",
+        style(messages.text("cli.last_week")).dim(),
+        sum(last)
+    );
+    println!(
+        "   {:<16}  {}",
+        style(messages.text("cli.week_before")).dim(),
+        sum(before)
+    );
+}
+
+/// Handle the `diff` subcommand: compare the console logs of two boots, by
+/// default the last one archived with the last known-good one, and list the
+/// errors which are new.
+fn diff_logs(messages: &Catalog, config: &config::Config, diff_matches: &ArgMatches) {
+    let exit_on_error = |e: String| -> ! {
+        error(messages, e);
+        process::exit(-1);
+    };
+    let mut options = config.diff.clone();
+    if let Some(ignore) = diff_matches.values_of("IGNORE") {
+        options.ignore.extend(ignore.map(str::to_owned));
+    }
+    let dir = archive::default_path();
+    let archive_dir = || {
+        dir.as_deref()
+            .unwrap_or_else(|| exit_on_error(messages.text("cli.no_state_dir")))
+    };
+
+    let load_boot = |id: &str| {
+        id.trim_start_matches('#')
+            .parse()
+            .map_err(|_| messages.text_with("cli.not_archived_boot", &[("id", &id)]))
+            .and_then(|number| archive::load(archive_dir(), number))
+            .unwrap_or_else(|e| exit_on_error(e))
+    };
+    // The name and the content of the console log of a boot or of a file.
+    let read_log = |boot: &str| {
+        let is_number = boot
+            .trim_start_matches('#')
+            .chars()
+            .all(|c| c.is_ascii_digit());
+        if is_number && !Path::new(boot).exists() {
+            return archived_log(archive_dir(), &load_boot(boot));
+        }
+        match std::fs::read(boot) {
+            Ok(log) => (boot.to_owned(), log),
+            Err(e) => exit_on_error(format!("{}: {}", boot, e)),
+        }
+    };
+    let known_good = |boot: &archive::Boot| match archive::last_known_good(archive_dir(), boot)
+        .unwrap_or_else(|e| exit_on_error(e))
+    {
+        Some(good) => archived_log(archive_dir(), &good),
+        None => exit_on_error(messages.text_with(
+            "cli.no_known_good",
+            &[
+                ("board", &boot.board.as_deref().unwrap_or(&boot.port)),
+                ("id", &boot.id),
+            ],
+        )),
+    };
+
+    let boots: Vec<&str> = diff_matches
+        .values_of("BOOTS")
+        .map(Iterator::collect)
+        .unwrap_or_default();
+    let ((before_name, before), (after_name, after)) = match boots.as_slice() {
+        [] => {
+            let last = archive::list(archive_dir())
+                .unwrap_or_else(|e| exit_on_error(e))
+                .pop()
+                .unwrap_or_else(|| exit_on_error(messages.text("cli.nothing_archived")));
+            (known_good(&last), archived_log(archive_dir(), &last))
+        }
+        [boot] => {
+            let boot = load_boot(boot);
+            (known_good(&boot), archived_log(archive_dir(), &boot))
+        }
+        [a, b] => (read_log(a), read_log(b)),
+        _ => unreachable!(),
+    };
+
+    let lines = diff::diff(&before, &after, &options);
+    println!("{}", style(format!("--- {}", before_name)).red());
+    println!("{}", style(format!("+++ {}", after_name)).green());
+    for line in diff::hunks(&lines, options.context) {
+        match line {
+            None => println!("{}", style("  ⋯").dim()),
+            Some(line @ diff::DiffLine::Same(_)) => println!("{}", line),
+            Some(line @ diff::DiffLine::Removed(_)) => println!("{}", style(line).red()),
+            Some(line @ diff::DiffLine::Added(_)) => println!("{}", style(line).green()),
+        }
+    }
+    let rules = match &config.severities {
+        rules if rules.is_empty() => severity::default_rules(),
+        rules => rules.clone(),
+    };
+    let errors = diff::new_errors(&lines, &rules);
+    if errors.is_empty() {
+        println!(
+            "{}",
+            messages.text_with("cli.no_new_errors", &[("before", &before_name)])
+        );
+    } else {
+        println!(
+            "{}",
+            messages.text_with(
+                "cli.new_errors",
+                &[("count", &errors.len()), ("before", &before_name)],
+            )
+        );
+        for error in errors {
+            println!("   {}", style(error).red());
+        }
+    }
+}
+
+/// The name and the content of the console log of the archived `boot`.
+fn archived_log(dir: &Path, boot: &archive::Boot) -> (String, Vec<u8>) {
+    let log = std::fs::read(boot.log_path(dir)).unwrap_or_default();
+    let name = format!(
+        "#{} ({})",
+        boot.id,
+        boot.board.as_deref().unwrap_or(&boot.port)
+    );
+    (name, log)
+}
+
+/// Handle the `pool` subcommand: claim the first idle board of the profile,
+/// waiting for one if they are all in use, push the kernel image to it and
+/// check that it boots, then release it. Exits with the result.
+fn run_on_pool(mut settings: bc::Settings, matches: &ArgMatches) -> ! {
+    use bc::push::PushOptions;
+
+    let messages = &settings.messages.clone();
+    let profile = matches.value_of("PROFILE").unwrap();
+    if !settings
+        .boards
+        .iter()
+        .any(|board| board.profile.as_deref() == Some(profile))
+    {
+        error(
+            messages,
+            messages.text_with("cli.no_pool_board", &[("profile", &profile)]),
+        );
+        process::exit(-1);
+    }
+
+    let waiting = Instant::now();
+    let wait = Duration::from_secs(numeric_arg(messages, matches, "WAIT"));
+    let claim = loop {
+        match boards::claim(&settings, profile) {
+            Ok(Some(claim)) => break claim,
+            Ok(None) if waiting.elapsed() < wait => thread::sleep(Duration::from_secs(1)),
+            Ok(None) => {
+                error(
+                    messages,
+                    messages.text_with(
+                        "cli.no_idle_board",
+                        &[("profile", &profile), ("seconds", &wait.as_secs())],
+                    ),
+                );
+                process::exit(-1);
+            }
+            Err(e) => {
+                error(
+                    messages,
+                    messages.text_with("cli.not_claimed", &[("error", &e)]),
+                );
+                process::exit(-1);
+            }
+        }
+    };
+    let board = &claim.status().board;
+    println!(
+        "{}",
+        messages.text_with(
+            "cli.claimed",
+            &[
+                ("board", &style(&board.name).cyan()),
+                ("path", &claim.status().path),
+            ],
+        )
+    );
+
+    let image = match matches.value_of("IMAGE").or(board.image.as_deref()) {
+        Some(image) => image.to_string(),
+        None => {
+            error(
+                messages,
+                messages.text_with("cli.no_board_image", &[("board", &board.name)]),
+            );
+            process::exit(-1);
+        }
+    };
+    settings.path = Some(claim.status().path.clone());
+    let last_stage = settings.expectations.last();
+    let options = PushOptions {
+        expect: matches
+            .value_of("EXPECT")
+            .map(str::to_owned)
+            .or_else(|| last_stage.map(|stage| stage.pattern.clone())),
+        verify_timeout: match matches.value_of("BOOT_TIMEOUT") {
+            Some(_) => Duration::from_secs(numeric_arg(messages, matches, "BOOT_TIMEOUT")),
+            None if last_stage.is_some() => settings.expectations.iter().map(|s| s.timeout).sum(),
+            None => PushOptions::default().verify_timeout,
+        },
+        ..PushOptions::default()
+    };
+    let pushed = bc::push_image(&settings, &image, &options);
+    println!(
+        "{}",
+        messages.text_with("cli.released", &[("board", &style(&board.name).cyan())])
+    );
+    drop(claim);
+    match pushed {
+        Ok(report) => {
+            println!(
+                "{}",
+                messages.text_with("cli.pushed", &[("report", &report)])
+            );
+            process::exit(0);
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                style(messages.text_with("cli.not_pushed", &[("error", &e)])).red()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Handle the `stub` subcommand: render the receiver stub and write it to the
+/// requested output.
+fn generate_stub(messages: &Catalog, matches: &ArgMatches) {
+    use bc::stub::{self, StubOptions};
+
+    // Values with defaults or restricted to possible values are safe to
+    // unwrap and parse.
+    let target = matches.value_of("TARGET").unwrap().parse().unwrap();
+    let language = matches.value_of("LANGUAGE").unwrap().parse().unwrap();
+
+    let mut options = StubOptions::new(target, language);
+    let trigger = matches.value_of("TRIGGER").unwrap();
+    options.trigger = parse_hex(trigger).unwrap_or_else(|| {
+        error(
+            messages,
+            messages.text_with("cli.hex_bytes", &[("arg", &style("trigger").cyan())]),
+        );
+        process::exit(-1);
+    });
+    options.crc = !matches.is_present("NO_CRC");
+    options.protocol = matches.value_of("PROTOCOL").unwrap().parse().unwrap();
+    let smallest = options.protocol.smallest_buffer();
+    let buffer = matches.value_of("BUFFER").unwrap();
+    options.receive_buffer = match buffer.parse::<u16>() {
+        Ok(buffer) if buffer >= smallest => buffer,
+        _ => {
+            let e = format!("needs to be from {} to {} bytes", smallest, u16::MAX);
+            error(
+                messages,
+                messages.text_with(
+                    "cli.invalid_arg",
+                    &[("arg", &style("buffer").cyan()), ("error", &e)],
+                ),
+            );
+            process::exit(-1);
+        }
+    };
+    if let Some(address) = matches.value_of("LOAD_ADDRESS") {
+        let address = address.trim_start_matches("0x");
+        options.load_address = Some(u64::from_str_radix(address, 16).unwrap_or_else(|_| {
+            error(
+                messages,
+                messages.text_with("cli.hex_address", &[("arg", &style("load-address").cyan())]),
+            );
+            process::exit(-1);
+        }));
+    }
+
+    write_output(
+        messages,
+        matches.value_of("OUTPUT"),
+        &stub::render(&options),
+    );
+}
+
+/// Handle the `keygen` subcommand: create a signing key and its public key.
+fn generate_key(messages: &Catalog, matches: &ArgMatches) {
+    let path = matches.value_of("KEY_FILE").unwrap();
+    match bc::signing::keygen(Path::new(path)) {
+        Ok(public_key) => {
+            let hex: String = public_key.iter().map(|b| format!("{:02x}", b)).collect();
+            println!(
+                "{}",
+                messages.text_with("cli.key_written", &[("path", &path)])
+            );
+            println!("{}", messages.text_with("cli.public_key", &[("key", &hex)]));
+        }
+        Err(e) => {
+            error(
+                messages,
+                messages.text_with("cli.key_not_created", &[("path", &path), ("error", &e)]),
+            );
+            process::exit(-1);
+        }
+    }
+}
+
+/// Write the generated `source` to the file at `path`, or to the standard
+/// output.
+fn write_output(messages: &Catalog, path: Option<&str>, source: &str) {
+    match path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, source) {
+                error(
+                    messages,
+                    messages.text_with("cli.not_written", &[("path", &path), ("error", &e)]),
+                );
+                process::exit(-1);
+            }
+        }
+        None => print!("{}", source),
+    }
+}
+
+/// Print the error `text`, in red after the translated `error`.
+fn error(messages: &Catalog, text: impl fmt::Display) {
+    println!("{}: {}", style(messages.text("cli.error")).red(), text);
+}
+
+/// Print the `detail` of the error printed before.
+fn detail(detail: impl fmt::Display) {
+    println!("   {} {}", style("-->").cyan(), detail);
 }
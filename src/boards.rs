@@ -0,0 +1,540 @@
+//! Inventory of the boards of a multi-board setup.
+//!
+//! The boards are declared in the configuration file (see
+//! [`config`](crate::config)), each with a unique name, the identity of its
+//! port (found again like the one of a resumed session when a USB device gets
+//! another path), and optionally the profile of the kernel images it runs and
+//! the image to push to it:
+//!
+//! ```toml
+//! [[board]]
+//! name = "rpi4-a"
+//! profile = "rpi4"
+//! image = "target/kernel8.img"
+//! [board.port]
+//! path = "/dev/ttyUSB0"
+//! vid = 1027
+//! pid = 24577
+//! serial_number = "A10KZP3V"
+//! ```
+//!
+//! The sessions on the port of a board record how its last boot went to the
+//! boards file, by default `bootcom/boards.toml` next to the resume file in the
+//! user state directory. `bootcom boards` lists the boards with their status:
+//! whether their port is there and free, and the result of their last boot.
+//!
+//! Several jobs can share a pool of identical boards: each [`claim`]s the
+//! first idle board of a profile, which nobody else can claim until it is
+//! released. The claims are advisory locks (`flock`) on lock files next to the
+//! boards file, which the system releases when the processes holding them go
+//! away, however they do.
+//!
+//! **Example**
+//! ```
+//! use bootcom::boards::{self, BootResult};
+//!
+//! let records = boards::parse_records(
+//!     "[rpi4-a]\nresult = \"failed\"\nreason = \"no login\"\nat = 1700000000",
+//! )
+//! .unwrap();
+//! assert_eq!(records["rpi4-a"].result, BootResult::Failed("no login".into()));
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+use toml::{value::Table, Value};
+
+use crate::resume::{self, PortIdentity};
+use crate::settings::Settings;
+use crate::utils::{is_port_present, port_holders, HumanDuration};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A board of the inventory.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Board {
+    /// The name of the board, unique in the inventory.
+    pub name: String,
+    /// The port of the console of the board.
+    pub port: PortIdentity,
+    /// The profile of the kernel images the board runs, if any.
+    pub profile: Option<String>,
+    /// The kernel image to push to the board, if any.
+    pub image: Option<String>,
+}
+
+/// The result of a boot of a board.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BootResult {
+    /// The kernel image was pushed, there were no boot stages to check.
+    Pushed,
+    /// All the boot stages were reached.
+    Booted,
+    /// The push or a boot stage failed, for the given reason.
+    Failed(String),
+}
+
+/// The last boot of a board, as recorded to the boards file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BootRecord {
+    pub result: BootResult,
+    /// When the boot ended.
+    pub at: SystemTime,
+}
+
+/// Whether the port of a board can be used.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PortStatus {
+    /// The port is there and nobody has it open.
+    Idle,
+    /// The board is claimed, or its port is open by the given processes (only
+    /// known on Linux).
+    InUse(Vec<String>),
+    /// The port is not there, the board is unplugged or off.
+    Missing,
+}
+
+/// The status of a board of the inventory.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BoardStatus {
+    pub board: Board,
+    /// The path of the port of the board now.
+    pub path: String,
+    pub port: PortStatus,
+    /// The last boot of the board, if any was recorded.
+    pub last_boot: Option<BootRecord>,
+}
+
+impl BoardStatus {
+    /// Returns `true` if the board is idle and did not fail its last boot.
+    pub fn is_healthy(&self) -> bool {
+        let failed = matches!(
+            self.last_boot,
+            Some(BootRecord {
+                result: BootResult::Failed(_),
+                ..
+            })
+        );
+        self.port == PortStatus::Idle && !failed
+    }
+}
+
+/// A board of the inventory claimed by this process, released when dropped.
+#[derive(Debug)]
+pub struct Claim {
+    status: BoardStatus,
+    /// The lock file, locked for as long as it is open.
+    lock: File,
+}
+
+impl Claim {
+    /// The status of the board when it was claimed.
+    pub fn status(&self) -> &BoardStatus {
+        &self.status
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Err(e) = self.lock.unlock() {
+            info!("could not release {}: {}", self.status.board.name, e);
+        }
+    }
+}
+
+impl fmt::Display for BootResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootResult::Pushed => write!(f, "pushed"),
+            BootResult::Booted => write!(f, "booted"),
+            BootResult::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+impl fmt::Display for BootRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ago = SystemTime::now()
+            .duration_since(self.at)
+            .unwrap_or_default();
+        write!(f, "{} ({} ago)", self.result, HumanDuration(ago))
+    }
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortStatus::Idle => write!(f, "idle"),
+            PortStatus::InUse(holders) if holders.is_empty() => write!(f, "in use"),
+            PortStatus::InUse(holders) => write!(f, "in use by {}", holders.join(", ")),
+            PortStatus::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+/// The path of the default boards file, if the user state directory can be
+/// found.
+pub fn default_path() -> Option<PathBuf> {
+    resume::default_path().map(|path| path.with_file_name("boards.toml"))
+}
+
+/// The status of the `boards`, with the last boots recorded to the boards
+/// `file`, if any.
+pub fn inventory(boards: &[Board], file: Option<&Path>) -> Vec<BoardStatus> {
+    let records = file
+        .and_then(|file| load_records(file).ok())
+        .unwrap_or_default();
+    boards
+        .iter()
+        .map(|board| {
+            let path = board.port.locate();
+            let claimant = claimant(&lock_path(file, &board.name));
+            let port = if !is_port_present(&path) {
+                PortStatus::Missing
+            } else {
+                match (port_holders(&path).as_slice(), claimant) {
+                    ([], None) => PortStatus::Idle,
+                    ([], Some(claim)) => PortStatus::InUse(vec![claim]),
+                    (holders, _) => PortStatus::InUse(
+                        holders
+                            .iter()
+                            .map(|(pid, name)| format!("{} ({})", name, pid))
+                            .collect(),
+                    ),
+                }
+            };
+            BoardStatus {
+                board: board.clone(),
+                path,
+                port,
+                last_boot: records.get(&board.name).cloned(),
+            }
+        })
+        .collect()
+}
+
+/// Claim the first idle board of the `profile` in the inventory of the
+/// `settings`, preferring the boards which did not fail their last boot.
+/// Returns `None` when they are all in use or missing.
+pub fn claim(settings: &Settings, profile: &str) -> io::Result<Option<Claim>> {
+    let file = settings.boards_file.as_deref().map(Path::new);
+    let mut idle: Vec<_> = inventory(&settings.boards, file)
+        .into_iter()
+        .filter(|status| {
+            status.board.profile.as_deref() == Some(profile) && status.port == PortStatus::Idle
+        })
+        .collect();
+    // The sort is stable, the boards keep the order of the inventory.
+    idle.sort_by_key(|status| !status.is_healthy());
+    for status in idle {
+        let lock = lock_path(file, &status.board.name);
+        if let Some(dir) = lock.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Never removed, another process may be about to lock it.
+        let mut lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock)?;
+        if try_claim(&lock)? {
+            // Only shown in the inventory, the lock is what claims the board.
+            lock.set_len(0)?;
+            lock.write_all(process::id().to_string().as_bytes())?;
+            return Ok(Some(Claim { status, lock }));
+        }
+    }
+    Ok(None)
+}
+
+/// Read and parse the boards file at `path`.
+pub fn load_records(path: &Path) -> Result<BTreeMap<String, BootRecord>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_records(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the `text` of a boards file, the last boots by board name.
+pub fn parse_records(text: &str) -> Result<BTreeMap<String, BootRecord>, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    root.iter()
+        .map(|(name, record)| {
+            let table = record
+                .as_table()
+                .ok_or_else(|| format!("`{}` needs to be a section", name))?;
+            let result = boot_result(table)
+                .ok_or_else(|| format!("`{}.result` is missing or invalid", name))?;
+            let at = match table.get("at") {
+                Some(Value::Integer(secs)) if *secs >= 0 => {
+                    UNIX_EPOCH + Duration::from_secs(*secs as u64)
+                }
+                _ => return Err(format!("`{}.at` is missing or invalid", name)),
+            };
+            Ok((name.clone(), BootRecord { result, at }))
+        })
+        .collect()
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Record the `result` of the boot which just ended to the boards file of the
+/// `settings`, if their port is the one of a board of the inventory. Failures
+/// are only logged, the inventory is informative.
+pub(crate) fn record(settings: &Settings, result: BootResult) {
+    let (file, board) = match (&settings.boards_file, board_of(settings)) {
+        (Some(file), Some(board)) => (Path::new(file), board),
+        _ => return,
+    };
+    let mut records = load_records(file).unwrap_or_default();
+    records.insert(
+        board.name.clone(),
+        BootRecord {
+            result,
+            at: SystemTime::now(),
+        },
+    );
+    let saved = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| fs::write(file, to_toml(&records)));
+    if let Err(e) = saved {
+        info!("could not record the boot of {}: {}", board.name, e);
+    }
+}
+
+/// The board of the inventory whose port is the one of the `settings`, if
+/// any.
+pub(crate) fn board_of(settings: &Settings) -> Option<&Board> {
+    let path = settings.path.as_ref()?;
+    settings.boards.iter().find(|b| b.port.locate() == *path)
+}
+
+/// Insert the `result` key of a boot `result` in a `table`, with the
+/// `reason` of a failure.
+pub(crate) fn insert_boot_result(table: &mut Table, result: &BootResult) {
+    let name = match result {
+        BootResult::Pushed => "pushed",
+        BootResult::Booted => "booted",
+        BootResult::Failed(reason) => {
+            table.insert("reason".into(), Value::String(reason.clone()));
+            "failed"
+        }
+    };
+    table.insert("result".into(), Value::String(name.into()));
+}
+
+/// The boot result in a `table`, if it has a valid one.
+pub(crate) fn boot_result(table: &Table) -> Option<BootResult> {
+    match table.get("result")?.as_str()? {
+        "pushed" => Some(BootResult::Pushed),
+        "booted" => Some(BootResult::Booted),
+        "failed" => Some(BootResult::Failed(
+            table
+                .get("reason")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        )),
+        _ => None,
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// How many times the lock file of a board is tried when claiming it.
+const LOCK_ATTEMPTS: u32 = 3;
+
+/// How long to wait before trying the lock file of a board again.
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The lock file claiming the board `name`, next to the boards `file`.
+fn lock_path(file: Option<&Path>, name: &str) -> PathBuf {
+    let dir = match file {
+        Some(file) => file.with_file_name("claims"),
+        None => std::env::temp_dir().join("bootcom-claims"),
+    };
+    dir.join(format!("{}.lock", name))
+}
+
+/// Lock the `lock` file of a board, unless another process claimed it in the
+/// meantime. It is tried again shortly when it is locked, in case the other
+/// process only checks the claim for its inventory.
+fn try_claim(lock: &File) -> io::Result<bool> {
+    for _ in 0..LOCK_ATTEMPTS {
+        match lock.try_lock() {
+            Ok(()) => return Ok(true),
+            Err(TryLockError::WouldBlock) => thread::sleep(LOCK_RETRY_DELAY),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// The claim held on the `lock` file of a board, with the process holding it
+/// when it already wrote its pid, if any.
+fn claimant(lock: &Path) -> Option<String> {
+    let file = File::open(lock).ok()?;
+    match file.try_lock_shared() {
+        // Unlocked when the file is closed.
+        Ok(()) => None,
+        Err(TryLockError::WouldBlock) => match fs::read_to_string(lock) {
+            Ok(pid) if !pid.trim().is_empty() => Some(format!("a claim ({})", pid.trim())),
+            _ => Some("a claim".into()),
+        },
+        Err(TryLockError::Error(e)) => {
+            debug!("could not check the claim {}: {}", lock.display(), e);
+            None
+        }
+    }
+}
+
+fn to_toml(records: &BTreeMap<String, BootRecord>) -> String {
+    let mut root = Table::new();
+    for (name, record) in records {
+        let mut table = Table::new();
+        insert_boot_result(&mut table, &record.result);
+        let at = record
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        table.insert("at".into(), Value::Integer(at as i64));
+        root.insert(name.clone(), Value::Table(table));
+    }
+    format!(
+        "# Saved by bootcom for `bootcom boards`.\n{}",
+        Value::Table(root)
+    )
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn records_round_trip() {
+    let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut records = BTreeMap::new();
+    records.insert(
+        "rpi4-a".to_string(),
+        BootRecord {
+            result: BootResult::Booted,
+            at,
+        },
+    );
+    records.insert(
+        "rpi4-b".to_string(),
+        BootRecord {
+            result: BootResult::Failed("boot stage `login:` not reached within 1m00s".into()),
+            at,
+        },
+    );
+    assert_eq!(parse_records(&to_toml(&records)).unwrap(), records);
+    assert!(parse_records("[rpi4-a]\nresult = \"lost\"\nat = 0")
+        .unwrap_err()
+        .contains("rpi4-a.result"));
+}
+
+#[test]
+fn failed_boards_are_not_healthy() {
+    let mut status = BoardStatus {
+        board: Board {
+            name: "rpi4-a".into(),
+            port: PortIdentity {
+                path: "/dev/ttyUSB0".into(),
+                usb: None,
+            },
+            profile: None,
+            image: None,
+        },
+        path: "/dev/ttyUSB0".into(),
+        port: PortStatus::Idle,
+        last_boot: None,
+    };
+    assert!(status.is_healthy());
+    status.last_boot = Some(BootRecord {
+        result: BootResult::Failed("no login".into()),
+        at: SystemTime::now(),
+    });
+    assert!(!status.is_healthy());
+    status.last_boot = None;
+    status.port = PortStatus::Missing;
+    assert!(!status.is_healthy());
+}
+
+#[test]
+fn boards_are_claimed_once() {
+    use crate::settings::SettingsBuilder;
+
+    let dir = std::env::temp_dir().join(format!("bootcom-claims-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let board = |name: &str| {
+        // Any file nobody has open stands for an idle port.
+        let port = dir.join(name);
+        fs::write(&port, b"").unwrap();
+        Board {
+            name: name.into(),
+            port: PortIdentity {
+                path: port.display().to_string(),
+                usb: None,
+            },
+            profile: Some("rpi4".into()),
+            image: None,
+        }
+    };
+    let settings = SettingsBuilder::default()
+        .boards(vec![board("a"), board("b")])
+        .boards_file(dir.join("boards.toml").to_string_lossy())
+        .finalize();
+
+    let a = claim(&settings, "rpi4").unwrap().unwrap();
+    assert_eq!(a.status().board.name, "a");
+    let b = claim(&settings, "rpi4").unwrap().unwrap();
+    assert_eq!(b.status().board.name, "b");
+    assert!(claim(&settings, "rpi4").unwrap().is_none());
+    assert!(claim(&settings, "rpi3").unwrap().is_none());
+    drop(a);
+    let again = claim(&settings, "rpi4").unwrap().unwrap();
+    assert_eq!(again.status().board.name, "a");
+
+    // The lock file left by a process which is gone claims nothing.
+    drop(again);
+    fs::write(lock_path(Some(&dir.join("boards.toml")), "a"), "4194305").unwrap();
+    assert!(claim(&settings, "rpi4").unwrap().is_some());
+    drop(b);
+
+    // Only one of the jobs racing for the boards gets each of them.
+    let claims: Vec<_> = (0..8)
+        .map(|_| {
+            let settings = settings.clone();
+            std::thread::spawn(move || claim(&settings, "rpi4").unwrap())
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|job| job.join().unwrap())
+        .collect();
+    let mut names: Vec<_> = claims
+        .iter()
+        .map(|c| c.status().board.name.clone())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["a", "b"]);
+    drop(claims);
+    fs::remove_dir_all(dir).unwrap();
+}
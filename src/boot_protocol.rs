@@ -22,7 +22,8 @@
 mod macros;
 
 mod events;
+mod session;
 mod state_machine;
 mod states;
 
-pub use state_machine::{factory, SerialBootProtocol};
+pub(crate) use state_machine::{factory, Outcome};
@@ -0,0 +1,112 @@
+//! Data shared by all the states of the boot protocol state machine.
+//!
+//! Unlike the data attached to the states and events, the session lives as
+//! long as the state machine and is carried over from one state to the next
+//! on every transition.
+
+use std::time::Instant;
+
+use console::style;
+
+use crate::archive::Archived;
+use crate::codec::CodecChain;
+use crate::context::Context;
+use crate::fsm::Shared;
+use crate::settings::Settings;
+use crate::severity::LineClassifier;
+use crate::stats::SessionStats;
+use crate::utils::{
+    rom_loaders::RomLoaderCheck, Attempts, BlobCapture, BootCheck, Instruments, ScriptPlayer,
+    Straps, StreamDemux, UbootHandoff,
+};
+
+/// Per-session data, shared by all states of the boot protocol state machine.
+#[derive(Debug, Default)]
+pub(crate) struct Session {
+    /// The context shared with the device manager, where the console output
+    /// goes.
+    pub context: Context,
+    /// The console input script being played back, if any. Cleared once the
+    /// playback is finished.
+    pub script: Option<ScriptPlayer>,
+    /// The verification of the boot after the last kernel push, if the
+    /// settings expect some console output. Cleared once it is over.
+    pub boot_check: Option<BootCheck>,
+    /// The archiving of the last kernel push, if the settings have an
+    /// archive, where the console output goes until the next push.
+    pub archived: Option<Archived>,
+    /// The codecs transforming the console streams of this session.
+    pub codecs: CodecChain,
+    /// The streams tagged by the device on the console, split from it.
+    pub streams: StreamDemux,
+    /// The blobs being extracted from the console output.
+    pub captures: BlobCapture,
+    /// The external capture tools run in sync with the boot phases.
+    pub instruments: Instruments,
+    /// The console lines counted by severity.
+    pub severities: LineClassifier,
+    /// The detection of the boards waiting in a ROM loader.
+    pub rom_loaders: RomLoaderCheck,
+    /// The scripted handoff to U-Boot, if the settings have one.
+    pub uboot: UbootHandoff,
+    /// The boot mode strapping pins driven, released with the session.
+    pub straps: Straps,
+    /// The kernel transfers which failed since the last successful one.
+    pub sends: Attempts,
+    /// The statistics of this session, added to the context ones when it
+    /// ends.
+    pub stats: SessionStats,
+    /// When the session started, unset for the placeholder sessions.
+    started: Option<Instant>,
+}
+impl Session {
+    /// Start a new session within the `context`, loading the console input
+    /// script from the `settings` if one was given.
+    pub(crate) fn new(settings: &Settings, context: Context) -> Self {
+        let script = settings.send_script.as_ref().and_then(|path| {
+            ScriptPlayer::load(path)
+                .map_err(|e| {
+                    let failed = settings
+                        .messages
+                        .text_with("boot.script_not_loaded", &[("path", path), ("error", &e)]);
+                    println!("{}", style(failed).red())
+                })
+                .ok()
+        });
+        Session {
+            context,
+            script,
+            boot_check: None,
+            archived: None,
+            codecs: CodecChain::new(&settings.codecs),
+            streams: StreamDemux::new(&settings.streams, &settings.messages),
+            captures: BlobCapture::new(&settings.captures),
+            instruments: Instruments::new(&settings.instruments, &settings.messages),
+            severities: LineClassifier::new(&settings.severities),
+            rom_loaders: RomLoaderCheck::new(settings.path.as_deref()),
+            uboot: UbootHandoff::new(settings.uboot.as_ref()),
+            straps: Straps::default(),
+            sends: Attempts::new("sending the kernel image", settings.retry.send_attempts),
+            stats: SessionStats {
+                sessions: 1,
+                ..SessionStats::default()
+            },
+            started: Some(Instant::now()),
+        }
+    }
+
+    /// End the session, adding its statistics to the context ones.
+    pub(crate) fn finish(&mut self) {
+        if let Some(started) = self.started.take() {
+            self.stats.connected_time = started.elapsed();
+        }
+        self.context.stats.lock().unwrap().add(&self.stats);
+    }
+}
+impl Shared for Session {
+    const MACHINE: &'static str = "protocol";
+
+    fn context(&self) -> &Context {
+        &self.context
+    }
+}
@@ -1,199 +1,184 @@
-//! `bootcom` serial boot protocol state machine.
-//!
-//! The boot session using `bootcom` has two modes: terminal mode and
-//! kernel-send mode. During terminal mode, `bootcom` operates similarly to a
-//! simple terminal, printing whatever data it reads on the serial port
-//! (stripping out special commands) and eventually taking commands from the
-//! booting device and the user.
-//!
-//! The following state diagram summarizes the different states and transitions
-//! `bootcom` device management goes through:
-//!
-//! ```text
-//! TODO: add the state diagram
-//! ```
-
-use super::events::*;
-use super::states::*;
-use crate::settings::Settings;
-
-// =============================================================================
-// Public Interface
-// =============================================================================
-
-/// Represents the `bootcom` serial boot protocol state machine. Use the
-/// `factory()` function to get an instance then run it by calling its `run()`
-/// method.
-pub struct SerialBootProtocol {
-    sm: ProtocolStates,
-}
-impl SerialBootProtocol {
-    /// The boot protocol state machine event loop runs until the `Done` state
-    /// is reached and its `should_exit` flag is set. At such point, the event
-    /// loop terminates and returns an exit code indicating no errors when equal
-    /// to **`0`**; otherwise a termination with error.
-    pub fn run(&mut self) -> i8 {
-        loop {
-            self.sm = self.sm.step();
-            if let ProtocolStates::Done(sm) = &self.sm {
-                if sm.state.should_exit {
-                    return if sm.state.with_error { 1 } else { 0 };
-                }
-            }
-        }
-    }
-}
-
-/// Factory function for the `bootcom` serial boot protocol state machine. Use
-/// it to get an instance of the state machine, which you can run by invoking
-/// its `run()` method.
-pub fn factory(settings: Settings) -> SerialBootProtocol {
-    SerialBootProtocol {
-        // The same machine naturally starts in the `Init` state.
-        sm: ProtocolStates::Init(ProtocolStateMachine::new(settings)),
-    }
-}
-
-// =============================================================================
-// Private stuff
-// =============================================================================
-
-/// The raw state machine implementing `bootcom`'s serial boot protocol.
-///
-/// This is a private interface, abstracted for a simpler and more intuitive use
-/// in the public `SerialBootProtocol` interface.
-///
-/// Note that using a generic type that holds the current state serves two
-/// purposes. It allows for also having shared data by all states that is not
-/// really part of state data (e.g. state machine parameters, statistics,
-/// etc...). Additionally, it's nicer when debugging to see the state machine
-/// and the current state it is holding at any time.
-#[derive(Debug)]
-struct ProtocolStateMachine<S: Runnable> {
-    settings: Settings,
-    state: S,
-}
-impl<S: Runnable> ProtocolStateMachine<S> {
-    fn run(&mut self) -> Event {
-        self.state.run(&self.settings)
-    }
-}
-
-/// The state machine starts in the `InitState`.
-impl ProtocolStateMachine<InitState> {
-    fn new(settings: Settings) -> Self {
-        ProtocolStateMachine {
-            settings,
-            state: InitState {},
-        }
-    }
-}
-
-/// An enum wrapper around the states of the boot protocol state machine. It
-/// provides a simpler and more intuitive model for manipulating states and
-/// their transitions.
-enum ProtocolStates {
-    Init(ProtocolStateMachine<InitState>),
-    TerminalMode(ProtocolStateMachine<TerminalModeState>),
-    KernelSendMode(ProtocolStateMachine<KernelSendModeState>),
-    Done(ProtocolStateMachine<DoneState>),
-}
-impl ProtocolStates {
-    /// The unit of work in the state machine event loop. It checks the current
-    /// state and the current event and decides the next transition. State
-    /// transitions from events are implemented using the rust `From`/`Into`
-    /// pattern. Most of the potential errors of state/event/transition
-    /// mismatches can be caught at compile time.
-    fn step(&mut self) -> Self {
-        match self {
-            ProtocolStates::Init(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::SwitchToTerminalMode(ev) => ProtocolStates::TerminalMode(ev.into()),
-                    Event::Done(ev) => ProtocolStates::Done(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            ProtocolStates::TerminalMode(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::SwitchToKernelSendMode(ev) => ProtocolStates::KernelSendMode(ev.into()),
-                    Event::Done(ev) => ProtocolStates::Done(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            ProtocolStates::Done(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::Exit(ev) => ProtocolStates::Done(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            ProtocolStates::KernelSendMode(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::SwitchToTerminalMode(ev) => ProtocolStates::TerminalMode(ev.into()),
-                    Event::Done(ev) => ProtocolStates::Done(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-        }
-    }
-}
-
-// -----------------------------------------------------------------------------
-// State from Event transitions
-// -----------------------------------------------------------------------------
-
-impl From<SwitchToTerminalModeEvent> for ProtocolStateMachine<TerminalModeState> {
-    fn from(event: SwitchToTerminalModeEvent) -> ProtocolStateMachine<TerminalModeState> {
-        // ... Logic prior to transition
-        ProtocolStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: TerminalModeState {
-                port: Some(event.port),
-            },
-        }
-    }
-}
-
-impl From<SwitchToKernelSendModeEvent> for ProtocolStateMachine<KernelSendModeState> {
-    fn from(event: SwitchToKernelSendModeEvent) -> ProtocolStateMachine<KernelSendModeState> {
-        // ... Logic prior to transition
-        ProtocolStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: KernelSendModeState {
-                port: Some(event.port),
-            },
-        }
-    }
-}
-
-impl From<DoneEvent> for ProtocolStateMachine<DoneState> {
-    fn from(event: DoneEvent) -> ProtocolStateMachine<DoneState> {
-        // ... Logic prior to transition
-        ProtocolStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: DoneState {
-                with_error: event.with_errors,
-                should_exit: false,
-            },
-        }
-    }
-}
-impl From<ExitEvent> for ProtocolStateMachine<DoneState> {
-    fn from(event: ExitEvent) -> ProtocolStateMachine<DoneState> {
-        // ... Logic prior to transition
-        ProtocolStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: DoneState {
-                with_error: event.with_error,
-                should_exit: true,
-            },
-        }
-    }
-}
+//! `bootcom` serial boot protocol state machine.
+//!
+//! The boot session using `bootcom` has two main modes: terminal mode and
+//! kernel-send mode. During terminal mode, `bootcom` operates similarly to a
+//! simple terminal, printing whatever data it reads on the serial port
+//! (stripping out special commands) and eventually taking commands from the
+//! booting device and the user. When host services are enabled, the booted
+//! kernel can also switch `bootcom` into service mode to access files in a
+//! sandboxed host directory, and when a dump directory is set, the device can
+//! switch it into dump mode to stream a memory region to the host.
+//!
+//! The following state diagram summarizes the different states and transitions
+//! `bootcom` device management goes through:
+//!
+//! ```text
+//! TODO: add the state diagram
+//! ```
+
+use std::fmt;
+
+use super::events::*;
+use super::session::Session;
+use super::states::*;
+use crate::context::Context;
+use crate::fsm::{FaultEvent, Machine, StateMachine};
+use crate::settings::Settings;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// How a boot protocol session ended, returned by its event loop so that the
+/// device manager can decide what to do next.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Outcome {
+    /// The user asked to quit `bootcom`.
+    UserQuit,
+    /// The communication with the device failed, usually because it was
+    /// disconnected.
+    PortError { source: String },
+    /// The port is used by another program and the user chose not to retry.
+    PortBusy { source: String },
+    /// The kernel image could not be read or sent with the requested protocol.
+    ImageError { source: String },
+    /// The kernel transfers kept failing although the device was still there,
+    /// up to the maximum number of attempts.
+    TransferFailed { attempts: usize, source: String },
+    /// An illegal transition was attempted, which is a bug in `bootcom`.
+    Fault { anomaly: String },
+}
+impl Outcome {
+    /// Whether the session ended because of an error.
+    pub(crate) fn is_error(&self) -> bool {
+        *self != Outcome::UserQuit
+    }
+}
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::UserQuit => write!(f, "user quit"),
+            Outcome::PortError { source } => write!(f, "port error: {}", source),
+            Outcome::PortBusy { source } => write!(f, "port busy: {}", source),
+            Outcome::ImageError { source } => write!(f, "image error: {}", source),
+            Outcome::TransferFailed { attempts, source } => {
+                write!(f, "transfer failed {} time(s), last: {}", attempts, source)
+            }
+            Outcome::Fault { anomaly } => write!(f, "fault: {}", anomaly),
+        }
+    }
+}
+
+/// Represents the `bootcom` serial boot protocol state machine. Use the
+/// `factory()` function to get an instance then run it by calling its `run()`
+/// method.
+pub(crate) struct SerialBootProtocol {
+    sm: ProtocolStates,
+}
+impl SerialBootProtocol {
+    /// The boot protocol state machine event loop runs until the `Done` state
+    /// is reached and its `should_exit` flag is set. At such point, the event
+    /// loop terminates and returns the outcome of the session.
+    pub(crate) fn run(&mut self) -> Outcome {
+        self.sm.run_to_exit()
+    }
+}
+
+/// Factory function for the `bootcom` serial boot protocol state machine. Use
+/// it to get an instance of the state machine, which you can run by invoking
+/// its `run()` method.
+///
+/// The `context` is shared with the device manager and the other sessions.
+pub(crate) fn factory(settings: Settings, context: Context) -> SerialBootProtocol {
+    SerialBootProtocol {
+        // The same machine naturally starts in the `Init` state.
+        sm: ProtocolStates::Init(StateMachine::new(settings, context)),
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The boot protocol state machine starts in the `InitState`.
+impl StateMachine<InitState> {
+    fn new(settings: Settings, context: Context) -> Self {
+        StateMachine {
+            shared: Session::new(&settings, context),
+            settings,
+            state: InitState {},
+        }
+    }
+}
+
+transitions! {
+    /// An enum wrapper around the states of the boot protocol state machine. It
+    /// provides a simpler and more intuitive model for manipulating states and
+    /// their transitions.
+    enum ProtocolStates, shared Session, exit Outcome;
+    states {
+        Init(InitState) {
+            SwitchToTerminalMode => TerminalMode,
+            Done => Done,
+        },
+        TerminalMode(TerminalModeState) {
+            SwitchToKernelSendMode => KernelSendMode,
+            SwitchToServiceMode => ServiceMode,
+            SwitchToDumpMode => DumpMode,
+            SwitchToTerminalMode => TerminalMode,
+            UserQuit => Done,
+            Done => Done,
+        },
+        KernelSendMode(KernelSendModeState) {
+            SwitchToTerminalMode => TerminalMode,
+            Done => Done,
+        },
+        ServiceMode(ServiceModeState) {
+            SwitchToTerminalMode => TerminalMode,
+            Done => Done,
+        },
+        DumpMode(DumpModeState) {
+            SwitchToTerminalMode => TerminalMode,
+            Done => Done,
+        },
+        Fault(FaultState) {
+            Done => Done,
+        },
+        Done(DoneState) {
+            Exit => Done,
+        },
+    }
+    // The session is not part of the events, it is moved over to the new state
+    // machine by `step()`.
+    from {
+        |event: SwitchToTerminalModeEvent| TerminalModeState {
+            port: Some(event.port),
+        }
+        |event: SwitchToKernelSendModeEvent| KernelSendModeState {
+            port: Some(event.port),
+            protocol: event.protocol,
+            image: event.image,
+        }
+        |event: SwitchToServiceModeEvent| ServiceModeState {
+            port: Some(event.port),
+        }
+        |event: SwitchToDumpModeEvent| DumpModeState {
+            port: Some(event.port),
+        }
+        |event: FaultEvent| FaultState {
+            anomaly: event.anomaly,
+        }
+        |event: UserQuitEvent| DoneState {
+            outcome: Outcome::UserQuit,
+            should_exit: false,
+        }
+        |event: DoneEvent| DoneState {
+            outcome: event.outcome,
+            should_exit: false,
+        }
+        |event: ExitEvent| DoneState {
+            outcome: event.outcome,
+            should_exit: true,
+        }
+    }
+}
@@ -1,314 +1,2124 @@
-//! States for the `bootcom` serial boot protocol state machine.
-//!
-//! This modules is private and restricted to the
-//! [`boot_protocol`](crate::boot_protocol) scope. The public interface of the
-//! serial boot protocol state machine is provided by
-//! [`boot_protocol`](crate::boot_protocol).
-//!
-//! ```ignore
-//! use super::states::*;
-//! ```
-//!
-//! Refer to the [`state_machine`](super::state_machine) module for an overview
-//! of states, events and transitions.
-
-use std::{fmt, thread, time::Duration};
-
-use console::style;
-use log::{info, log_enabled, trace, Level::Debug};
-use serialport::SerialPort;
-
-use super::events::*;
-
-use crate::utils::open_and_setup_port;
-use crate::{settings::Settings, utils::send_kernel};
-
-// =============================================================================
-// Crate-Public Interface
-// =============================================================================
-
-/// Trait adding the ability for a state to be `run` after a transition into it.
-pub(crate) trait Runnable {
-    /// A state implements this method so it can be `run` after the state
-    /// machine transitions into it.
-    ///
-    /// During this call, the state can do any work that needs to be done and
-    /// when finished, requests a transition to a `new state` by returning the
-    /// appropriate `event`. The `state` and the `event` are consumed to create
-    /// the `new state` using the corresponding [`From`] trait implementation
-    /// (provided such implementation exists).
-    fn run(&mut self, settings: &Settings) -> Event;
-}
-
-// Init State ==================================================================
-
-/// The initial state of the boot protocol state machine.
-///
-/// From the `InitState`, the state machine can evolve via the following
-/// transitions:
-///
-///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** which happens
-///    after the serial port is initialized and connected,
-///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
-///    finished due to the user action or to any other interruption caused by
-///    unrecoverable errors, disconnection, etc.
-#[derive(Debug)]
-pub(crate) struct InitState {}
-impl Runnable for InitState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!("=> Init");
-        assert_ne!(settings.path, None);
-
-        match open_and_setup_port(&settings) {
-            Ok(port) => Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
-                settings: settings.clone(),
-                port,
-            }),
-            Err(_) => {
-                // This is fatal for the protocol state machine, but not for
-                // `bootcom`. Terminate with error so that `bootcom` device
-                // manager can go back into waiting for the device to be ready
-                // or select a new one.
-                Event::Done(DoneEvent {
-                    settings: settings.clone(),
-                    with_errors: true,
-                })
-            }
-        }
-    }
-}
-
-// TerminalMode State ==========================================================
-
-/// A `state` of the boot protocol state machine where `bootcom` reads data from
-/// the booting device, displays it on the terminal and can take commands from
-/// the user or the booting device.
-///
-/// The currenlty implemented commands are:
-/// * **`send_kernel`**: initiated by the boot device consecutively sending
-///   **`0x03`** **three(3)** times.
-///
-/// The booting device is not allowed to send a command before a response to the
-/// previous one was received.
-///
-/// This state can tranisition to another state as following:
-///
-///  * **[`SwitchToKernelSendModeEvent`] => [`KernelSendModeState`]** upon
-///    reception of the `send_kernel` command from the booting device,
-///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
-///    finished due to the user action or to any other interruption caused by
-///    errors, disconnection, etc.
-pub(crate) struct TerminalModeState {
-    /// The serial port to be used, already configured and open.
-    ///
-    /// Consumed and moved upon the transition to [`KernelSendModeState`].
-    pub port: Option<Box<dyn SerialPort>>,
-}
-impl Runnable for TerminalModeState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        use hexplay::HexViewBuilder;
-        use std::io::{self, Write};
-
-        info!("=> Terminal Mode");
-        let mut got_errors = false;
-        let mut send_kernel = false;
-
-        if let Some(mut port) = self.port.take() {
-            loop {
-                // To handle the unreliable behavior of blocking/non-blocking of
-                // reads over the serial port, we'll first check the available
-                // data in the port's input buffer, and we only read the exact
-                // number of available bytes (up to a certain maximum amount).
-                // That way we can always know that read will return
-                // immediately.
-                match port.bytes_to_read() {
-                    Ok(available) => {
-                        trace!("Bytes available to read: {}", available);
-                        if available > 0 {
-                            // We'll read 4K maximum each time
-                            let mut serial_buf: Vec<u8> =
-                                vec![0; std::cmp::min(available, 4096) as usize];
-                            match port.read(serial_buf.as_mut_slice()) {
-                                Ok(mut t) => {
-                                    // The data may contain a command at the end
-                                    // and only at the end.
-                                    let command: Vec<u8> = serial_buf[..t]
-                                        .iter()
-                                        .rev()
-                                        .take_while(|b| **b == 3)
-                                        .cloned()
-                                        .collect();
-
-                                    if command == [3, 3, 3] {
-                                        // We got a `send_kernel` command
-                                        t -= 3;
-                                        send_kernel = true;
-                                    }
-
-                                    io::stdout().write_all(&serial_buf[..t]).unwrap();
-                                    println!();
-
-                                    // Dump the received data in a hex table for
-                                    // debugging
-                                    if log_enabled!(Debug) {
-                                        let view = HexViewBuilder::new(&serial_buf[..t])
-                                            .address_offset(0)
-                                            .row_width(16)
-                                            .finish();
-                                        println!("{}", view);
-                                    }
-
-                                    if send_kernel {
-                                        break;
-                                    };
-                                }
-                                Err(ref e) => {
-                                    info!("error: {:?}", e.to_string());
-                                    got_errors = true;
-                                    break;
-                                }
-                            }
-                        }
-
-                        thread::sleep(Duration::from_millis(100));
-                    }
-                    Err(ref e) => {
-                        info!("error: {:?}", e.to_string());
-                        got_errors = true;
-                        break;
-                    }
-                }
-            }
-            // Check commands
-            if send_kernel {
-                return Event::SwitchToKernelSendMode(SwitchToKernelSendModeEvent {
-                    settings: settings.clone(),
-                    port,
-                });
-            }
-
-            return Event::Done(DoneEvent {
-                settings: settings.clone(),
-                with_errors: got_errors,
-            });
-        }
-
-        // We should never reach here!
-        unreachable!()
-    }
-}
-impl fmt::Debug for TerminalModeState {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.port {
-            Some(port) => debug_fmt_serialport!(port, f).finish(),
-            None => f.debug_tuple("TerminalModeState").finish(),
-        }
-    }
-}
-
-// KernelSendMode State ========================================================
-
-/// A `state` of the boot protocol state machine where `bootcom` reads the
-/// content of the kernel image and send it to the boot device.
-///
-/// The kernel image size is limited to a maximum of 0xFFFFFFFF (i.e. can fit in
-/// a 32 bit unsigned integer). The size is sent first, in **[`little
-/// endian`](https://en.wikipedia.org/wiki/Endianness)** format, then `bootcom`
-/// expects a response from the boot device with the bytes `'O'` `'K'`, before
-/// finally pushing the entire content of the kernel image.
-///
-///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** upon
-///    completion of the kernel image push,
-///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
-///    interrupted due to unrecoverable errors, disconnection, etc.
-pub(crate) struct KernelSendModeState {
-    /// The serial port to be used, already configured and open.
-    ///
-    /// Consumed and moved upon the transition to [`TerminalModeState`].
-    pub port: Option<Box<dyn SerialPort>>,
-}
-impl Runnable for KernelSendModeState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!("=> Kernel Send Mode");
-
-        if let Some(mut port) = self.port.take() {
-            // Try to send the kernel data. If the operation fails, we'll go
-            // back to terminal mode just waiting for the bootloader to notice
-            // the failure and eventually restart the request to send the
-            // kernel.
-            // TODO: Implement this error recovery in the bootloader
-
-            loop {
-                match send_kernel(&mut port, settings) {
-                    Ok(_) => {
-                        break;
-                    }
-                    Err(ref e) => {
-                        info!("error: {:?}", e.to_string());
-                        println!("{}", style("[BC] 💥 Failed to send kernel image!").red());
-                    }
-                }
-            }
-
-            // Go back to terminal mode.
-            return Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
-                settings: settings.clone(),
-                port,
-            });
-        }
-
-        // We should never reach here!
-        unreachable!()
-    }
-}
-impl fmt::Debug for KernelSendModeState {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.port {
-            Some(port) => debug_fmt_serialport!(port, f).finish(),
-            None => f.debug_tuple("TerminalModeState").finish(),
-        }
-    }
-}
-
-// Done State ==================================================================
-
-/// Reached when the boot protocol state machine completes its execution and is
-/// about to terminate (normally or abnormally).
-///
-/// This state goes into a 2-phase execution. During the initial phase, it runs
-/// like any other state to do its own things like printing some information,
-/// cleaning up etc. It then triggers the [`ExitEvent`] to cause the boot
-/// protocol state machine to terminate and exit.
-///
-/// Termination due to errors is indicated with the `with_error` field in the
-/// state. This condition can be used to set the return value from the boot
-/// protocol state machine event loop.
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct DoneState {
-    /// When `true`, indicates an abnormal completion caused by an error.
-    pub with_error: bool,
-    /// When `true` instructs the boot protocol state machine to exit its event
-    /// loop.
-    pub should_exit: bool,
-}
-impl Runnable for DoneState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!(
-            "=> Done with{}errors",
-            if self.with_error { " " } else { " no " }
-        );
-        // Report errors
-        if self.with_error {
-            println!(
-                "{}",
-                style("[BC] 💥 Unrecoverable error on the serial port!").red()
-            );
-            println!("[BC] 🔌 Disconnect and reconnect the device!");
-        }
-
-        Event::Exit(ExitEvent {
-            settings: settings.clone(),
-            with_error: self.with_error,
-        })
-    }
-}
+//! States for the `bootcom` serial boot protocol state machine.
+//!
+//! This modules is private and restricted to the
+//! [`boot_protocol`](crate::boot_protocol) scope. The public interface of the
+//! serial boot protocol state machine is provided by
+//! [`boot_protocol`](crate::boot_protocol).
+//!
+//! ```ignore
+//! use super::states::*;
+//! ```
+//!
+//! Refer to the [`state_machine`](super::state_machine) module for an overview
+//! of states, events and transitions.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use console::{style, Term};
+use crossterm::event::{KeyCode, KeyModifiers};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use log::{info, log_enabled, trace, Level::Debug};
+use serialport::SerialPort;
+
+use super::events::*;
+use super::session::Session;
+use super::state_machine::Outcome;
+
+use crate::archive::{Archived, Note};
+use crate::boards::{self, BootResult};
+use crate::clock::clock;
+use crate::codec::CodecChain;
+use crate::context::Context;
+use crate::fsm::Runnable;
+use crate::pcap::{self, Traffic};
+use crate::progress::InstrumentCapture;
+use crate::resume;
+use crate::settings::{BaudRescan, Flasher, Phase, Settings, TransferProtocol};
+use crate::transport::Transport;
+use crate::usage;
+use crate::utils::{
+    apply_config, apply_straps, configure_port, describe_changes, is_port_busy, is_port_present,
+    is_transient, key_bytes, map_output, modem_manager, noise_hint, note_line, open_and_setup_port,
+    prompt_busy_retry, prompt_line_settings, prompt_note, receive_dump, render,
+    rom_loaders::{self, Detection},
+    scan_baud_rate, send_kernel, send_time, shell, show_banner, static_warnings, subscribe_typing,
+    suspend, write_paced, BlobCapture, BootCheck, Handoff, HostServices, HumanDuration, HumanSize,
+    Input, Keys, LineCheck, ModemLines, NoiseDetector, Playback, SendError, SoftFlow, Stage,
+    StreamDemux, TriggerMatcher, DUMP_TRIGGER, SERVICE_TRIGGER, TIME_TRIGGER,
+};
+
+/// How often the presence of the device is checked in terminal mode.
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a key press is waited for on an idle console.
+const KEY_WAIT: Duration = Duration::from_millis(100);
+
+/// How long the key following `Ctrl+A` is waited for.
+const PALETTE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+// Init State ==================================================================
+
+/// The initial state of the boot protocol state machine.
+///
+/// From the `InitState`, the state machine can evolve via the following
+/// transitions:
+///
+///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** which happens
+///    after the serial port is initialized and connected,
+///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
+///    finished due to the user action or to any other interruption caused by
+///    unrecoverable errors, disconnection, etc.
+#[derive(Debug)]
+pub(crate) struct InitState {}
+impl Runnable for InitState {
+    type Shared = Session;
+    type Event = Event;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event {
+        info!("=> Init");
+        assert_ne!(settings.path, None);
+
+        match apply_straps(settings) {
+            Ok(straps) => session.straps = straps,
+            Err(e) => println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("boot.straps_failed", &[("error", &e)])
+                )
+                .yellow()
+            ),
+        }
+        loop {
+            return match open_and_setup_port(settings) {
+                Ok(mut port) => {
+                    session.rom_loaders.connected(clock(settings).now());
+                    show_banner(settings);
+                    resume::save(settings, None);
+                    for warning in static_warnings(settings) {
+                        let warning = settings
+                            .messages
+                            .text_with("boot.warning", &[("warning", &warning)]);
+                        println!("{}", style(warning).yellow());
+                    }
+                    if settings.time_sync {
+                        if let Err(e) = sync_time(settings, &mut port) {
+                            let warning = settings
+                                .messages
+                                .text_with("boot.time_not_sent", &[("error", &e)]);
+                            println!("{}", style(warning).yellow());
+                        }
+                    }
+                    Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                        settings: settings.clone(),
+                        port,
+                    })
+                }
+                Err(ref e)
+                    if is_port_busy(e)
+                        && settings.keyboard
+                        && prompt_busy_retry(settings, settings.path.as_ref().unwrap()) =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    session.stats.error(&e);
+                    // This is fatal for the protocol state machine, but not for
+                    // `bootcom`. Terminate with error so that `bootcom` device
+                    // manager can go back into waiting for the device to be
+                    // ready or select a new one.
+                    let source = e.to_string();
+                    let outcome = if is_port_busy(&e) {
+                        Outcome::PortBusy { source }
+                    } else {
+                        Outcome::PortError { source }
+                    };
+                    Event::Done(DoneEvent {
+                        settings: settings.clone(),
+                        outcome,
+                    })
+                }
+            };
+        }
+    }
+}
+
+// TerminalMode State ==========================================================
+
+/// A `state` of the boot protocol state machine where `bootcom` reads data from
+/// the booting device, displays it on the terminal and can take commands from
+/// the user or the booting device.
+///
+/// The currenlty implemented commands are:
+/// * **`send_kernel`**: initiated by the boot device sending one of the trigger
+///   patterns registered in the settings (by default **`0x03`** consecutively
+///   sent **three(3)** times). The protocol used to send the kernel is the one
+///   associated with the received trigger.
+/// * **`host_services`**: initiated by the booted kernel sending **`0x05`**
+///   consecutively **three(3)** times, only when host services are enabled.
+/// * **`dump`**: initiated by the device sending **`0x04`** consecutively
+///   **three(3)** times, only when a dump directory is set.
+/// * **`time`**: initiated by the device sending **`0x16`** consecutively
+///   **three(3)** times, only when time synchronization is enabled. The host
+///   time is sent back right away, without leaving terminal mode.
+///
+/// The booting device is not allowed to send a command before a response to the
+/// previous one was received.
+///
+/// The modem lines can be controlled from the keyboard: `F2` toggles DTR and
+/// `F3` toggles RTS. The state of the lines is shown when it changes, if
+/// enabled in the settings. `F7` changes the baud rate, parity or flow control
+/// of the open port, for the rest of the session. `F4` inserts a timestamped
+/// marker in the console log, and `F5` starts or stops a stopwatch. `Ctrl+A`
+/// followed by `n` prompts for a note, recorded with the console output and in
+/// the archive, followed by `a` sends `Ctrl+A` itself and followed by `q` quits
+/// `bootcom`, as `F10` does. The other keys typed, `Ctrl+C` included, are sent
+/// to the device as a terminal would send them, along with the input queued
+/// through the session handle. The text pasted is sent as a whole, paced as
+/// configured.
+///
+/// When a console input script was given in the settings, its lines are sent
+/// to the device as the playback progresses, following its delays and waiting
+/// for the expected patterns in the received data.
+///
+/// The received data is continuously checked for line noise. A sudden storm of
+/// invalid bytes usually means the board switched its UART configuration, in
+/// which case a baud rate rescan is offered (or automatically performed)
+/// according to the [`BaudRescan`] policy in the settings.
+///
+/// The presence of the device is checked every second, so that its
+/// disconnection ends the session even when the console is idle. When a reset
+/// grace period is set, a lost connection is first given that long to come
+/// back, in which case the session goes on with the reopened port.
+///
+/// When watched in the settings, the kernel image is sent again once rebuilt:
+/// the board is reset with the reset command of the settings, if any, for its
+/// bootloader to ask for the image, or the image is sent right away otherwise.
+///
+/// The state works over any [`Transport`], the modem lines, the line
+/// parameters, the baud rate rescans and the presence checks only applying to
+/// a serial port.
+///
+/// This state can tranisition to another state as following:
+///
+///  * **[`SwitchToKernelSendModeEvent`] => [`KernelSendModeState`]** upon
+///    reception of the `send_kernel` command from the booting device, or when
+///    the watched kernel image was rebuilt and there is no reset command,
+///  * **[`SwitchToServiceModeEvent`] => [`ServiceModeState`]** upon reception
+///    of the `host_services` command from the booted kernel,
+///  * **[`SwitchToDumpModeEvent`] => [`DumpModeState`]** upon reception of the
+///    `dump` command from the device,
+///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** after the
+///    port has been reopened with a new baud rate following a rescan, or
+///    reconfigured with the line parameters chosen by the user,
+///  * **[`UserQuitEvent`] => [`DoneState`]** when the user presses the quit
+///    key,
+///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
+///    interrupted by errors, disconnection, etc.
+pub(crate) struct TerminalModeState<T = Box<dyn SerialPort>> {
+    /// The link to the device to be used, already configured and open.
+    ///
+    /// Consumed and moved upon the transition to [`KernelSendModeState`].
+    pub port: Option<T>,
+}
+impl<T: Transport> Runnable for TerminalModeState<T> {
+    type Shared = Session;
+    type Event = Event<T>;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event<T> {
+        use hexplay::HexViewBuilder;
+
+        info!("=> Terminal Mode");
+        let mut error = None;
+        let mut quit = false;
+        let mut presence_checked = clock(settings).now();
+        let mut command = None;
+        let mut rescan = false;
+        let mut reconfigure = None;
+        let mut reload = None;
+        let mut commands = command_matcher(settings);
+        let mut noise = NoiseDetector::new();
+        let mut noise_reported = false;
+        let mut flash = None;
+        let mut flow = SoftFlow::new(settings.flow_control);
+        let mut lines = ModemLines::new();
+        // The keyboard shortcuts, unless the keyboard belongs to someone else.
+        let keys = settings.keyboard.then(subscribe_typing);
+        let mut line_check = LineCheck::new(clock(settings).now());
+        // Reused by all the reads, sized for the largest one.
+        let mut read_buf: Vec<u8> = vec![0; settings.max_read_size];
+
+        if let Some(mut port) = self.port.take() {
+            let mut link = port.describe();
+            loop {
+                // A board reset may drop the connection for a moment, reopen
+                // the same device and go on with the session if it comes back
+                // within the grace period.
+                if let Some(e) = error.take() {
+                    let path = serial_path(settings, &mut port).map(str::to_owned);
+                    drop(port);
+                    port = match reconnect::<T>(settings, &link, path.as_deref()) {
+                        Some(port) => port,
+                        None => {
+                            session.stats.error(&e);
+                            return Event::Done(DoneEvent {
+                                settings: settings.clone(),
+                                outcome: Outcome::PortError { source: e },
+                            });
+                        }
+                    };
+                    link = port.describe();
+                    flow = SoftFlow::new(settings.flow_control);
+                    presence_checked = clock(settings).now();
+                    line_check = LineCheck::new(presence_checked);
+                }
+
+                // To handle the unreliable behavior of blocking/non-blocking of
+                // reads over the serial port, we'll first check the available
+                // data in the port's input buffer, and we only read the exact
+                // number of available bytes (up to a certain maximum amount).
+                // That way we can always know that read will return
+                // immediately.
+                match port.bytes_to_read() {
+                    Ok(available) => {
+                        trace!("Bytes available to read: {}", available);
+                        if available > 0 || commands.is_holding() {
+                            let received = match available {
+                                // The start of a trigger held back is shown
+                                // once the device went quiet.
+                                0 => Ok((commands.release(clock(settings).now()), None)),
+                                _ => {
+                                    let wanted = std::cmp::min(available as usize, read_buf.len());
+                                    let read = port.read(&mut read_buf[..wanted]);
+                                    read.map(|t| {
+                                        session.stats.bytes_received += t as u64;
+                                        // Flow control characters are not part
+                                        // of the console output, and neither
+                                        // are the triggers.
+                                        commands.feed(&flow.receive(&read_buf[..t]))
+                                    })
+                                }
+                            };
+                            match received {
+                                // Nothing to show after all, which is not an
+                                // error.
+                                Ok((serial_buf, None)) if serial_buf.is_empty() => {}
+                                Ok((serial_buf, found)) => {
+                                    // The data may contain a command at the end
+                                    // and only at the end.
+                                    if found.is_some() {
+                                        command = found;
+                                    }
+
+                                    // Render the data followed by a new line,
+                                    // the console apart from the streams.
+                                    if !serial_buf.is_empty() {
+                                        let mut rendered = vec![];
+                                        for (stream, run) in session.streams.feed(&serial_buf) {
+                                            match stream {
+                                                Some(stream) => rendered.extend(
+                                                    session
+                                                        .streams
+                                                        .write(stream, &run)
+                                                        .unwrap_or_default(),
+                                                ),
+                                                None => {
+                                                    let mapped = map_output(&settings.quirks, &run);
+                                                    rendered.extend(session.codecs.decode(&mapped));
+                                                }
+                                            }
+                                        }
+                                        rendered.push(b'\n');
+                                        session.context.output(&rendered);
+                                    }
+
+                                    if let Some(script) = &mut session.script {
+                                        script.output(&serial_buf);
+                                    }
+                                    check_boot(settings, session, &serial_buf);
+                                    session.severities.output(&serial_buf);
+                                    if let Some(archived) = &mut session.archived {
+                                        archived.output(&serial_buf);
+                                    }
+                                    capture_blobs(settings, session, &serial_buf);
+                                    if let Some(detection) = session.rom_loaders.output(&serial_buf)
+                                    {
+                                        let path = serial_path(settings, &mut port);
+                                        flash = rom_loader_found(settings, detection, &link, path);
+                                        if flash.is_some() {
+                                            break;
+                                        }
+                                    }
+                                    let steps =
+                                        session.uboot.output(&serial_buf, clock(settings).now());
+                                    if let Err(ref e) = follow_uboot(
+                                        settings,
+                                        session,
+                                        &mut port,
+                                        steps,
+                                        &mut command,
+                                    ) {
+                                        info!("error: {:?}", e.to_string());
+                                        error = Some(e.to_string());
+                                        continue;
+                                    }
+
+                                    // AT commands echoed back right after
+                                    // the device is plugged in are a sure
+                                    // sign of ModemManager probing it.
+                                    if modem_manager::looks_like_probe(&serial_buf) {
+                                        if let Some(path) = serial_path(settings, &mut port) {
+                                            modem_manager::warn(settings, path);
+                                        }
+                                    }
+
+                                    // Dump the received data in a hex table for
+                                    // debugging
+                                    if log_enabled!(Debug) {
+                                        let view = HexViewBuilder::new(&serial_buf)
+                                            .address_offset(0)
+                                            .row_width(16)
+                                            .finish();
+                                        println!("{}", view);
+                                    }
+
+                                    // The time is sent back without leaving
+                                    // terminal mode.
+                                    if let Some(Command::Time) = command {
+                                        command = None;
+                                        if let Err(ref e) = sync_time(settings, &mut port) {
+                                            info!("error: {:?}", e.to_string());
+                                            error = Some(e.to_string());
+                                            continue;
+                                        }
+                                    }
+
+                                    if command.is_some() {
+                                        break;
+                                    };
+
+                                    if !noise_reported && noise.feed(&serial_buf) {
+                                        noise_reported = true;
+                                        if port.serial_port().is_some()
+                                            && should_rescan(settings, noise.noise_percent())
+                                        {
+                                            rescan = true;
+                                            break;
+                                        }
+                                    }
+                                }
+                                // Try again on the next round.
+                                Err(ref e) if is_transient(e) => {
+                                    trace!("transient read error: {}", e);
+                                }
+                                Err(ref e) => {
+                                    info!("error: {:?}", e.to_string());
+                                    error = Some(e.to_string());
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // Nothing is written while the device asked for a
+                        // pause.
+                        if !flow.is_paused() {
+                            if let Err(ref e) = play_script(settings, session, &mut port) {
+                                info!("error: {:?}", e.to_string());
+                                error = Some(e.to_string());
+                                continue;
+                            }
+                            if let Err(ref e) = write_input(settings, session, &mut port) {
+                                info!("error: {:?}", e.to_string());
+                                error = Some(e.to_string());
+                                continue;
+                            }
+                        }
+
+                        // The line parameters changed remotely.
+                        let changes = session.context.session.take_line_changes();
+                        if !changes.is_empty() {
+                            let new_settings = changes
+                                .iter()
+                                .fold(settings.clone(), |new, change| change.apply(&new));
+                            reconfigure = Some(Box::new(new_settings));
+                            break;
+                        }
+
+                        // Don't wait for a read error to notice the device is
+                        // gone, it won't come on an idle console.
+                        if clock(settings).now().duration_since(presence_checked)
+                            >= PRESENCE_CHECK_INTERVAL
+                        {
+                            presence_checked = clock(settings).now();
+                            let path = serial_path(settings, &mut port);
+                            if path.is_some_and(|path| !is_port_present(path)) {
+                                let e = format!("{} was disconnected", link);
+                                info!("error: {:?}", e);
+                                error = Some(e);
+                                continue;
+                            }
+                        }
+
+                        if let Some(reloaded) = reload_config(settings, session) {
+                            reload = Some(reloaded);
+                            break;
+                        }
+
+                        if let Some(load) = watch_image(settings, session) {
+                            command = Some(load);
+                            break;
+                        }
+
+                        // A stage of the boot may time out on a silent
+                        // console.
+                        check_boot(settings, session, &[]);
+
+                        if let Some(detection) = session.rom_loaders.poll(clock(settings).now()) {
+                            let path = serial_path(settings, &mut port);
+                            flash = rom_loader_found(settings, detection, &link, path);
+                            if flash.is_some() {
+                                break;
+                            }
+                        }
+
+                        if let Some(step) = session.uboot.poll(clock(settings).now()) {
+                            report_handoff(settings, &step);
+                        }
+
+                        if let Some(serial) = port.serial_port() {
+                            if let Some(warning) = line_check.poll_cts(settings, serial) {
+                                let warning = settings
+                                    .messages
+                                    .text_with("boot.warning", &[("warning", &warning)]);
+                                println!("{}", style(warning).yellow());
+                            }
+                        }
+
+                        // Wait for more data, handling the keyboard shortcuts
+                        // in the meantime. While data flows, only the keys
+                        // already typed are handled.
+                        let wait = match available {
+                            0 => KEY_WAIT,
+                            _ => Duration::ZERO,
+                        };
+                        match handle_keys(
+                            settings,
+                            &session.context,
+                            keys.as_ref(),
+                            wait,
+                            &mut port,
+                            &mut lines,
+                        ) {
+                            KeyAction::None => (),
+                            KeyAction::Quit => {
+                                quit = true;
+                                break;
+                            }
+                            KeyAction::Reconfigure(new_settings) => {
+                                reconfigure = Some(new_settings);
+                                break;
+                            }
+                            KeyAction::Note(text) => annotate(session, text),
+                        }
+                    }
+                    Err(ref e) => {
+                        info!("error: {:?}", e.to_string());
+                        error = Some(e.to_string());
+                        continue;
+                    }
+                }
+            }
+            if rescan {
+                // Close the port before scanning, we'll reopen it afterwards.
+                drop(port);
+                session.stats.baud_rescans += 1;
+                return rescan_baud_rate::<T>(settings);
+            }
+
+            // Only found for a serial port, which the flasher needs.
+            if let (Some(flasher), Some(path)) = (flash, settings.path.as_deref()) {
+                drop(port);
+                return flash_rom_loader::<T>(settings, session, &flasher, path);
+            }
+
+            if let Some(new_settings) = reload {
+                return Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                    settings: new_settings,
+                    port,
+                });
+            }
+
+            if let Some(new_settings) = reconfigure {
+                return reconfigure_port(settings, *new_settings, port);
+            }
+
+            if quit {
+                return Event::UserQuit(UserQuitEvent {
+                    settings: settings.clone(),
+                });
+            }
+
+            // Check commands
+            match command {
+                Some(Command::SendKernel(index)) => {
+                    let trigger = &settings.triggers[index];
+                    return switch_to_kernel_send_mode(
+                        settings,
+                        session,
+                        port,
+                        trigger.protocol,
+                        trigger.image.clone(),
+                    );
+                }
+                Some(Command::Load(protocol)) => {
+                    return switch_to_kernel_send_mode(settings, session, port, protocol, None);
+                }
+                Some(Command::HostServices) => {
+                    return Event::SwitchToServiceMode(SwitchToServiceModeEvent {
+                        settings: settings.clone(),
+                        port,
+                    });
+                }
+                // Handled without leaving terminal mode.
+                Some(Command::Time) => (),
+                Some(Command::Dump) => {
+                    return Event::SwitchToDumpMode(SwitchToDumpModeEvent {
+                        settings: settings.clone(),
+                        port,
+                    });
+                }
+                None => (),
+            }
+        }
+
+        // We should never reach here!
+        unreachable!()
+    }
+}
+impl<T: Transport> fmt::Debug for TerminalModeState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port {
+            Some(port) => f.debug_tuple("").field(&port.describe()).finish(),
+            None => f.debug_tuple("TerminalModeState").finish(),
+        }
+    }
+}
+
+/// Commands the booting device can issue while in terminal mode, by sending the
+/// corresponding trigger pattern.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    /// Send the kernel for the trigger at this index in the settings.
+    SendKernel(usize),
+    /// Send the kernel for the load command of the U-Boot handoff.
+    Load(TransferProtocol),
+    HostServices,
+    Dump,
+    Time,
+}
+
+/// Build the matcher for the trigger patterns of all the commands enabled in
+/// the `settings`.
+fn command_matcher(settings: &Settings) -> TriggerMatcher<Command> {
+    let mut triggers: Vec<(Vec<u8>, Command)> = settings
+        .triggers
+        .iter()
+        .enumerate()
+        .map(|(index, t)| (t.pattern.clone(), Command::SendKernel(index)))
+        .collect();
+    if settings.host_dir.is_some() {
+        triggers.push((SERVICE_TRIGGER.to_vec(), Command::HostServices));
+    }
+    if settings.dump_dir.is_some() {
+        triggers.push((DUMP_TRIGGER.to_vec(), Command::Dump));
+    }
+    if settings.time_sync {
+        triggers.push((TIME_TRIGGER.to_vec(), Command::Time));
+    }
+    TriggerMatcher::new(triggers)
+}
+
+/// Cycle the kernel image forced for the next transfers through the images
+/// known from the `settings`, and back to the ones bound to the triggers.
+fn select_next_image(settings: &Settings, context: &Context) {
+    let mut images: Vec<&str> = vec![];
+    for image in settings
+        .triggers
+        .iter()
+        .filter_map(|t| t.image.as_deref())
+        .chain(settings.kernel_image.as_deref())
+    {
+        if !images.contains(&image) {
+            images.push(image);
+        }
+    }
+    let mut selected = context.selected_image.lock().unwrap();
+    let next = match selected.as_deref() {
+        None => images.first(),
+        Some(current) => images
+            .iter()
+            .position(|image| *image == current)
+            .and_then(|i| images.get(i + 1)),
+    };
+    *selected = next.map(|image| image.to_string());
+    match &*selected {
+        Some(image) => println!(
+            "{}",
+            settings
+                .messages
+                .text_with("boot.next_image", &[("image", &style(image).cyan())])
+        ),
+        None => println!("{}", settings.messages.text("boot.next_image_of_trigger")),
+    }
+}
+
+/// Advance the verification of the boot with the `data` received from the
+/// device, if any, and report the stages reached or failed.
+fn check_boot(settings: &Settings, session: &mut Session, data: &[u8]) {
+    let check = match &mut session.boot_check {
+        Some(check) => check,
+        None => return,
+    };
+    let mut failure = None;
+    for stage in check.advance(data, clock(settings).now()) {
+        match stage {
+            Stage::Reached(pattern, after) => println!(
+                "{}",
+                settings.messages.text_with(
+                    "boot.stage_reached",
+                    &[
+                        ("stage", &style(pattern).cyan()),
+                        ("after", &HumanDuration(after))
+                    ]
+                )
+            ),
+            Stage::TimedOut(pattern, timeout) => {
+                let e = format!(
+                    "boot stage `{}` not reached within {}",
+                    pattern,
+                    HumanDuration(timeout)
+                );
+                let failed = settings.messages.text_with("boot.failed", &[("error", &e)]);
+                println!("{}", style(failed).red());
+                session.stats.error(&e);
+                session.context.health.error();
+                failure = Some(e);
+            }
+        }
+    }
+    if check.is_over() {
+        session.boot_check = None;
+        let captures = session.instruments.phase_ended(Phase::Boot);
+        report_captures(settings, session, captures);
+        let result = failure.map_or(BootResult::Booted, BootResult::Failed);
+        record_boot(settings, session, result);
+    }
+}
+
+/// Record the `result` of the last boot to the inventory of the boards and to
+/// the archive.
+fn record_boot(settings: &Settings, session: &mut Session, result: BootResult) {
+    if let Some(archived) = &mut session.archived {
+        archived.result(&result);
+    }
+    boards::record(settings, result);
+}
+
+/// Report the `captures` of the instruments, and record them to the archive
+/// of the last push, if any.
+fn report_captures(settings: &Settings, session: &mut Session, captures: Vec<InstrumentCapture>) {
+    for capture in captures {
+        match &settings.progress_observer {
+            Some(handle) => handle.observer().instrument(&capture),
+            None => println!(
+                "{}",
+                settings
+                    .messages
+                    .text_with("boot.captured", &[("capture", &capture)])
+            ),
+        }
+        if let Some(archived) = &mut session.archived {
+            archived.capture(&capture);
+        }
+        session
+            .context
+            .report
+            .file(format!("capture of `{}`", capture.name), &capture.file);
+    }
+}
+
+/// Extract the blobs framed in the console output `data` and save them to
+/// files.
+fn capture_blobs(settings: &Settings, session: &mut Session, data: &[u8]) {
+    for blob in session.captures.feed(data) {
+        let rule = blob.rule;
+        let saved = blob.data.and_then(|data| {
+            let len = data.len();
+            session
+                .captures
+                .save(rule, &data)
+                .map(|path| (len, path))
+                .map_err(|e| e.to_string())
+        });
+        match saved {
+            Ok((len, path)) => {
+                let captured = settings.messages.text_with(
+                    "boot.blob_captured",
+                    &[("size", &HumanSize(len as u64)), ("path", &path.display())],
+                );
+                println!("{}", style(captured).yellow());
+                session.context.report.file("blob", path);
+            }
+            Err(e) => {
+                let failed = settings
+                    .messages
+                    .text_with("boot.blob_not_captured", &[("error", &e)]);
+                println!("{}", style(failed).red());
+                session
+                    .stats
+                    .error(format!("could not capture a blob: {}", e));
+            }
+        }
+    }
+}
+
+/// Advance the playback of the console input script, if any, and report its
+/// completion.
+fn play_script(
+    settings: &Settings,
+    session: &mut Session,
+    port: &mut dyn Transport,
+) -> std::io::Result<()> {
+    if let Some(script) = &mut session.script {
+        let mut port = session.context.outputs.sending(port);
+        let mut device = session.codecs.encoder(&mut port);
+        match script.poll(clock(settings).now(), &mut device, &settings.paste_pacing)? {
+            Playback::Running => return Ok(()),
+            Playback::Finished => println!("{}", settings.messages.text("boot.script_completed")),
+            Playback::TimedOut(pattern) => println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("boot.script_timed_out", &[("pattern", &pattern)])
+                )
+                .yellow()
+            ),
+        }
+        session.script = None;
+    }
+    Ok(())
+}
+
+/// Write the data queued by library code through the session handle, if any.
+fn write_input(
+    settings: &Settings,
+    session: &mut Session,
+    port: &mut dyn Transport,
+) -> std::io::Result<()> {
+    let data = session.context.session.take_input();
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut port = session.context.outputs.sending(port);
+    let mut device = session.codecs.encoder(&mut port);
+    write_paced(&mut device, &data, &settings.paste_pacing)
+}
+
+/// What the user asked for from the keyboard, beyond what is handled right
+/// away.
+enum KeyAction {
+    None,
+    /// The quit key (`F10` or `Ctrl+A q`) was pressed.
+    Quit,
+    /// New line parameters were chosen (`F7`).
+    Reconfigure(Box<Settings>),
+    /// A note was typed (`Ctrl+A n`).
+    Note(String),
+}
+
+/// Wait up to `wait` for a key press, toggling DTR on `F2` and RTS on `F3`,
+/// marking the console log on `F4`, starting or stopping the stopwatch on
+/// `F5`, choosing the kernel image on `F6`, prompting for new line parameters
+/// on `F7`, handling the commands of `Ctrl+A`, and show the modem lines when
+/// they were toggled or, if enabled in the settings, when they changed. The
+/// other keys and the text pasted are queued for the device as the input of
+/// the session.
+///
+/// Failing to access the modem lines is not fatal, some ports (like virtual
+/// ones) don't have them, and the links other than a serial port neither.
+fn handle_keys(
+    settings: &Settings,
+    context: &Context,
+    keys: Option<&Keys>,
+    wait: Duration,
+    port: &mut dyn Transport,
+    lines: &mut ModemLines,
+) -> KeyAction {
+    let previous = *lines;
+    let input = match keys {
+        Some(keys) => keys.next_input(wait),
+        None => {
+            clock(settings).sleep(wait);
+            None
+        }
+    };
+    let key = match input {
+        Some(Input::Key(key)) => Some(key),
+        // Paced by `write_input` like the rest of the input.
+        Some(Input::Paste(text)) => {
+            context.session.write(text.as_bytes());
+            None
+        }
+        None => None,
+    };
+    if let (Some(keys), Some(key)) = (keys, key) {
+        if key.code == KeyCode::Char('a') && key.modifiers == KeyModifiers::CONTROL {
+            return palette(settings, keys, context);
+        }
+    }
+    let result = match (key.map(|key| key.code), port.serial_port()) {
+        (Some(KeyCode::F(2)), Some(serial)) => lines.toggle_dtr(serial),
+        (Some(KeyCode::F(3)), Some(serial)) => lines.toggle_rts(serial),
+        (Some(KeyCode::F(4)), _) => {
+            let now = clock(settings).now();
+            let line = context
+                .stopwatch
+                .lock()
+                .unwrap()
+                .mark(now, SystemTime::now());
+            context.outputs.write_line(&style(line).cyan().to_string());
+            Ok(())
+        }
+        (Some(KeyCode::F(5)), _) => {
+            let now = clock(settings).now();
+            let line = context
+                .stopwatch
+                .lock()
+                .unwrap()
+                .toggle(now, SystemTime::now());
+            context.outputs.write_line(&style(line).cyan().to_string());
+            Ok(())
+        }
+        (Some(KeyCode::F(6)), _) => {
+            select_next_image(settings, context);
+            Ok(())
+        }
+        (Some(KeyCode::F(7)), _) => {
+            return match prompt_line_settings(settings) {
+                Some(new_settings) => KeyAction::Reconfigure(Box::new(new_settings)),
+                None => KeyAction::None,
+            }
+        }
+        (Some(KeyCode::F(10)), _) => return KeyAction::Quit,
+        _ => {
+            if let Some(bytes) = key.as_ref().and_then(key_bytes) {
+                context.session.write(&bytes);
+            }
+            Ok(())
+        }
+    };
+    let result = result.and_then(|_| match port.serial_port() {
+        Some(serial) if settings.modem_lines => lines.refresh(serial),
+        _ => Ok(()),
+    });
+    match result {
+        Ok(_) if *lines != previous => println!(
+            "{}",
+            settings
+                .messages
+                .text_with("boot.modem_lines", &[("lines", &style(&*lines).cyan())])
+        ),
+        Ok(_) => (),
+        Err(e) => info!("modem lines error: {}", e),
+    }
+    KeyAction::None
+}
+
+/// Wait a little for the key of a command after `Ctrl+A`: `n` to type a note,
+/// `a` to send `Ctrl+A` to the device, `q` to quit.
+fn palette(settings: &Settings, keys: &Keys, context: &Context) -> KeyAction {
+    let messages = &settings.messages;
+    render::message(&style(messages.text("prompt.palette")).dim().to_string());
+    match keys.next(PALETTE_TIMEOUT).map(|key| key.code) {
+        Some(KeyCode::Char('n')) => prompt_note(settings).map_or(KeyAction::None, KeyAction::Note),
+        Some(KeyCode::Char('a')) => {
+            context.session.write(&[0x01]);
+            KeyAction::None
+        }
+        Some(KeyCode::Char('q')) => KeyAction::Quit,
+        _ => KeyAction::None,
+    }
+}
+
+/// Record the note `text` in the console log, the log and the archived push,
+/// if any.
+fn annotate(session: &mut Session, text: String) {
+    let note = Note {
+        at: SystemTime::now(),
+        text,
+    };
+    let line = note_line(note.at, &note.text);
+    info!("note: {}", note.text);
+    session
+        .context
+        .outputs
+        .note(&style(&line).cyan().to_string(), &note.text);
+    if let Some(archived) = &mut session.archived {
+        archived.note(&note, &line);
+    }
+}
+
+/// Check the configuration file for modifications and apply the changes safe
+/// in the middle of the session, returning the new settings if there are any.
+fn reload_config(settings: &Settings, session: &mut Session) -> Option<Settings> {
+    let config = match session.context.config.poll()? {
+        Ok(config) => config,
+        Err(e) => {
+            println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("boot.config_not_reloaded", &[("error", &e)])
+                )
+                .yellow()
+            );
+            return None;
+        }
+    };
+    let reloaded = apply_config(settings, &config, true);
+    if !reloaded.queued.is_empty() {
+        println!(
+            "{}",
+            style(settings.messages.text_with(
+                "boot.config_queued",
+                &[("changes", &reloaded.queued.join(", "))]
+            ))
+            .dim()
+        );
+    }
+    if reloaded.applied.is_empty() {
+        return None;
+    }
+    println!(
+        "{}",
+        settings.messages.text_with(
+            "boot.config_reloaded",
+            &[("changes", &style(reloaded.applied.join(", ")).green())]
+        )
+    );
+    let new_settings = reloaded.settings;
+    session.codecs = CodecChain::new(&new_settings.codecs);
+    session.captures = BlobCapture::new(&new_settings.captures);
+    session.streams = StreamDemux::new(&new_settings.streams, &new_settings.messages);
+    show_banner(&new_settings);
+    Some(new_settings)
+}
+
+/// Check the watched kernel image for a rebuild, resetting the board with the
+/// reset command of the `settings` if there is one, returning the command
+/// sending the image right away otherwise.
+fn watch_image(settings: &Settings, session: &mut Session) -> Option<Command> {
+    if !settings.watch {
+        return None;
+    }
+    let selected = session.context.selected_image.lock().unwrap().clone();
+    let image = selected
+        .or_else(|| settings.kernel_image.clone())
+        .unwrap_or_else(|| "kernel8.img".into());
+    if !session
+        .context
+        .image_watch
+        .poll(&image, clock(settings).now())
+    {
+        return None;
+    }
+    println!(
+        "{}",
+        settings
+            .messages
+            .text_with("boot.image_rebuilt", &[("image", &style(&image).cyan())])
+    );
+    match &settings.reset_command {
+        // The bootloader asks for the image once the board is reset.
+        Some(reset) => {
+            info!("resetting the board with `{}`", reset);
+            match shell(reset).status() {
+                Ok(status) if status.success() => (),
+                Ok(status) => println!(
+                    "{}",
+                    style(
+                        settings
+                            .messages
+                            .text_with("boot.reset_failed", &[("status", &status)])
+                    )
+                    .yellow()
+                ),
+                Err(e) => println!(
+                    "{}",
+                    style(
+                        settings
+                            .messages
+                            .text_with("boot.reset_not_run", &[("error", &e)])
+                    )
+                    .yellow()
+                ),
+            }
+            None
+        }
+        None => Some(Command::Load(
+            settings
+                .triggers
+                .first()
+                .map_or(TransferProtocol::Raspbootin, |t| t.protocol),
+        )),
+    }
+}
+
+/// Apply the line parameters of the `new_settings` to the open `port`, going
+/// on with the session with them, or with the current `settings` if the port
+/// can't be reconfigured.
+fn reconfigure_port<T: Transport>(
+    settings: &Settings,
+    new_settings: Settings,
+    mut port: T,
+) -> Event<T> {
+    let changes = describe_changes(settings, &new_settings);
+    let serial = match port.serial_port() {
+        Some(serial) => serial,
+        None => {
+            println!(
+                "{}",
+                style(settings.messages.text("boot.not_a_serial_port")).yellow()
+            );
+            return Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                settings: settings.clone(),
+                port,
+            });
+        }
+    };
+    let settings = match configure_port(serial, &new_settings) {
+        Ok(_) => {
+            println!(
+                "{}",
+                settings.messages.text_with(
+                    "boot.line_switched",
+                    &[("changes", &style(changes.join(", ")).green())]
+                )
+            );
+            show_banner(&new_settings);
+            resume::save(&new_settings, None);
+            new_settings
+        }
+        Err(e) => {
+            println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("boot.line_not_changed", &[("error", &e)])
+                )
+                .yellow()
+            );
+            if let Err(e) = configure_port(serial, settings) {
+                return Event::Done(DoneEvent {
+                    settings: settings.clone(),
+                    outcome: Outcome::PortError {
+                        source: e.to_string(),
+                    },
+                });
+            }
+            settings.clone()
+        }
+    };
+    Event::SwitchToTerminalMode(SwitchToTerminalModeEvent { settings, port })
+}
+
+/// The device path of the `port`, when it is the serial port of the
+/// settings. The other transports have no path to check or hand over.
+fn serial_path<'a>(settings: &'a Settings, port: &mut dyn Transport) -> Option<&'a str> {
+    port.serial_port()?;
+    settings.path.as_deref()
+}
+
+/// Reopen the `link` to the device after the connection was lost, provided it
+/// comes back within the reset grace period of the settings, as it does when a
+/// board reset makes its USB serial controller re-enumerate. The serial port
+/// at `path`, if any, is only opened again once it is back.
+fn reconnect<T: Transport>(settings: &Settings, link: &str, path: Option<&str>) -> Option<T> {
+    if settings.reset_grace == Duration::from_millis(0) {
+        return None;
+    }
+    info!(
+        "Waiting {:?} for {} to come back",
+        settings.reset_grace, link
+    );
+    let deadline = clock(settings).now() + settings.reset_grace;
+    while clock(settings).now() < deadline {
+        if path.is_none_or(is_port_present) {
+            match T::open(settings) {
+                Ok(mut port) => {
+                    info!("Reopened {} after a reset", link);
+                    // The board most likely lost its time with the reset.
+                    if settings.time_sync && sync_time(settings, &mut port).is_err() {
+                        continue;
+                    }
+                    return Some(port);
+                }
+                // The link can't come back.
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return None,
+                Err(_) => (),
+            }
+        }
+        clock(settings).sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+/// Send the host time to the device and tell the user.
+fn sync_time(settings: &Settings, port: &mut dyn Transport) -> std::io::Result<()> {
+    let now = send_time(port)?;
+    println!(
+        "{}",
+        style(
+            settings
+                .messages
+                .text_with("boot.time_sent", &[("now", &now)])
+        )
+        .dim()
+    );
+    Ok(())
+}
+
+/// Warn the user about the noise storm and decide, according to the
+/// [`BaudRescan`] policy, whether a baud rate rescan should be done.
+fn should_rescan(settings: &Settings, noise_percent: usize) -> bool {
+    println!(
+        "{}",
+        style(
+            settings
+                .messages
+                .text_with("boot.noise", &[("percent", &noise_percent)])
+        )
+        .yellow()
+    );
+    if let Some(hint) = noise_hint(settings) {
+        let hint = settings
+            .messages
+            .text_with("boot.warning", &[("warning", &hint)]);
+        println!("{}", style(hint).yellow());
+    }
+    match settings.baud_rescan {
+        BaudRescan::Off => false,
+        BaudRescan::Auto => true,
+        // Nobody to ask.
+        BaudRescan::Prompt if !settings.keyboard => false,
+        BaudRescan::Prompt => {
+            let _paused = render::pause();
+            let _keyboard = suspend();
+            Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(settings.messages.text("prompt.rescan"))
+                .default(true)
+                .interact_on_opt(&Term::stdout())
+                .unwrap_or(None)
+                .unwrap_or(false)
+        }
+    }
+}
+
+/// Start sending the kernel with the `protocol`, the image selected by the
+/// user coming before the `image` of the request.
+fn switch_to_kernel_send_mode<T: Transport>(
+    settings: &Settings,
+    session: &mut Session,
+    port: T,
+    protocol: TransferProtocol,
+    image: Option<String>,
+) -> Event<T> {
+    // The previous boot is over for the instruments too.
+    let captures = session.instruments.stop_all();
+    report_captures(settings, session, captures);
+    session.instruments.phase_started(Phase::Trigger);
+    let selected = session.context.selected_image.lock().unwrap().clone();
+    Event::SwitchToKernelSendMode(SwitchToKernelSendModeEvent {
+        settings: settings.clone(),
+        port,
+        protocol,
+        image: selected.or(image),
+    })
+}
+
+/// Carry out the `steps` of the U-Boot handoff, setting the `command` when
+/// the kernel is to be sent.
+fn follow_uboot(
+    settings: &Settings,
+    session: &mut Session,
+    port: &mut dyn Transport,
+    steps: Vec<Handoff>,
+    command: &mut Option<Command>,
+) -> std::io::Result<()> {
+    for step in steps {
+        match step {
+            Handoff::Type(ref text) => {
+                let mut port = session.context.outputs.sending(port);
+                let mut device = session.codecs.encoder(&mut port);
+                write_paced(&mut device, text, &settings.paste_pacing)?;
+            }
+            Handoff::Transfer(protocol) => *command = Some(Command::Load(protocol)),
+            _ => (),
+        }
+        report_handoff(settings, &step);
+    }
+    Ok(())
+}
+
+/// Tell the user how the U-Boot handoff goes.
+fn report_handoff(settings: &Settings, step: &Handoff) {
+    let messages = &settings.messages;
+    match step {
+        Handoff::Started => println!("{}", messages.text("boot.uboot_started")),
+        Handoff::Finished => println!("{}", messages.text("boot.uboot_finished")),
+        Handoff::TimedOut => println!("{}", style(messages.text("boot.uboot_timed_out")).yellow()),
+        Handoff::Type(_) | Handoff::Transfer(_) => (),
+    }
+}
+
+/// Tell the user about the board found waiting in a ROM loader on the `link`,
+/// and return the flasher to run for it, if one is configured and the link is
+/// the serial port at `path`.
+fn rom_loader_found(
+    settings: &Settings,
+    detection: Detection,
+    link: &str,
+    path: Option<&str>,
+) -> Option<Flasher> {
+    let loader = match detection {
+        Detection::Banner(loader) => {
+            println!(
+                "{}",
+                style(settings.messages.text_with(
+                    "boot.rom_loader",
+                    &[("loader", &rom_loaders::title(loader))]
+                ))
+                .yellow()
+            );
+            loader
+        }
+        Detection::Silent(loader) => {
+            println!(
+                "{}",
+                style(settings.messages.text_with(
+                    "boot.rom_loader_silent",
+                    &[("link", &link), ("loader", &rom_loaders::title(loader))]
+                ))
+                .yellow()
+            );
+            loader
+        }
+    };
+    let flasher = path.and_then(|_| {
+        settings
+            .flashers
+            .iter()
+            .find(|flasher| flasher.loader == loader)
+    });
+    if flasher.is_none() {
+        for line in rom_loaders::guidance(loader, path.unwrap_or(link)) {
+            println!(
+                "{}",
+                settings
+                    .messages
+                    .text_with("boot.detail", &[("line", &line)])
+            );
+        }
+    }
+    flasher.cloned()
+}
+
+/// Run the `flasher` on the closed serial port at `path`, and go back into
+/// terminal mode with the port reopened.
+fn flash_rom_loader<T: Transport>(
+    settings: &Settings,
+    session: &Session,
+    flasher: &Flasher,
+    path: &str,
+) -> Event<T> {
+    let selected = session.context.selected_image.lock().unwrap().clone();
+    let image = selected
+        .or_else(|| settings.kernel_image.clone())
+        .unwrap_or_else(|| "kernel8.img".into());
+    let messages = &settings.messages;
+    println!(
+        "{}",
+        messages.text_with(
+            "boot.flashing",
+            &[
+                ("image", &style(&image).cyan()),
+                ("loader", &flasher.loader)
+            ]
+        )
+    );
+    match rom_loaders::run_flasher(flasher, path, &image) {
+        Ok(output) if output.status.success() => println!("{}", messages.text("boot.flashed")),
+        Ok(output) => {
+            println!(
+                "{}",
+                style(messages.text_with("boot.flasher_failed", &[("status", &output.status)]))
+                    .red()
+            );
+            let errors = String::from_utf8_lossy(&output.stderr);
+            let lines: Vec<&str> = errors.lines().collect();
+            for line in &lines[lines.len().saturating_sub(5)..] {
+                println!("{}", messages.text_with("boot.detail", &[("line", line)]));
+            }
+        }
+        Err(e) => println!(
+            "{}",
+            style(messages.text_with("boot.flasher_not_run", &[("error", &e)])).red()
+        ),
+    }
+
+    match T::open(settings) {
+        Ok(port) => Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+            settings: settings.clone(),
+            port,
+        }),
+        Err(e) => Event::Done(DoneEvent {
+            settings: settings.clone(),
+            outcome: Outcome::PortError {
+                source: e.to_string(),
+            },
+        }),
+    }
+}
+
+/// Scan for a working baud rate and go back into terminal mode with the port
+/// reopened at that baud rate.
+///
+/// If no working baud rate could be found, the port is reopened with the
+/// original settings and rescanning is disabled for the rest of the session to
+/// avoid an endless scanning loop.
+fn rescan_baud_rate<T: Transport>(settings: &Settings) -> Event<T> {
+    let mut new_settings = settings.clone();
+    match scan_baud_rate(settings) {
+        Some(baud_rate) => {
+            println!(
+                "{}",
+                settings.messages.text_with(
+                    "boot.baud_switching",
+                    &[
+                        ("from", &settings.baud_rate),
+                        ("to", &style(baud_rate).green())
+                    ]
+                )
+            );
+            new_settings.baud_rate = baud_rate;
+        }
+        None => {
+            println!(
+                "{}",
+                style(settings.messages.text("boot.baud_not_found")).yellow()
+            );
+            new_settings.baud_rescan = BaudRescan::Off;
+        }
+    }
+
+    match T::open(&new_settings) {
+        Ok(port) => {
+            if new_settings.baud_rate != settings.baud_rate {
+                show_banner(&new_settings);
+                resume::save(&new_settings, None);
+            }
+            Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                settings: new_settings,
+                port,
+            })
+        }
+        Err(e) => Event::Done(DoneEvent {
+            settings: new_settings,
+            outcome: Outcome::PortError {
+                source: e.to_string(),
+            },
+        }),
+    }
+}
+
+// KernelSendMode State ========================================================
+
+/// A `state` of the boot protocol state machine where `bootcom` reads the
+/// content of the kernel image and send it to the boot device, using the
+/// transfer protocol associated with the trigger that was received.
+///
+/// With the default `raspbootin` style protocol, the kernel image size is
+/// limited to a maximum of 0xFFFFFFFF (i.e. can fit in a 32 bit unsigned
+/// integer). The size is sent first, in **[`little
+/// endian`](https://en.wikipedia.org/wiki/Endianness)** format, then `bootcom`
+/// expects a response from the boot device with the bytes `'O'` `'K'`, before
+/// finally pushing the entire content of the kernel image. With XMODEM-CRC, the
+/// image is sent in acknowledged blocks of 128 bytes.
+///
+/// When a transfer fails while the device is still there, `bootcom` goes back
+/// to terminal mode and waits for the bootloader to notice the failure and
+/// request the kernel image again. After as many consecutive failures as the
+/// [`RetryPolicy`](crate::settings::RetryPolicy) allows, the session is
+/// declared failed.
+///
+/// This state can tranisition to another state as following:
+///
+///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** upon
+///    completion of the kernel image push,
+///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
+///    interrupted due to unrecoverable errors, disconnection, etc.
+pub(crate) struct KernelSendModeState<T = Box<dyn SerialPort>> {
+    /// The link to the device to be used, already configured and open.
+    ///
+    /// Consumed and moved upon the transition to [`TerminalModeState`].
+    pub port: Option<T>,
+    /// The protocol to use for the transfer.
+    pub protocol: TransferProtocol,
+    /// The kernel image to send, if not the one from the settings.
+    pub image: Option<String>,
+}
+impl<T: Transport> Runnable for KernelSendModeState<T> {
+    type Shared = Session;
+    type Event = Event<T>;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event<T> {
+        info!("=> Kernel Send Mode");
+
+        if let Some(mut port) = self.port.take() {
+            // Try to send the kernel data. If the transfer fails while the
+            // device is still there, we'll go back to terminal mode just
+            // waiting for the bootloader to notice the failure and eventually
+            // restart the request to send the kernel.
+
+            // The image selection and the progress bar are not to be
+            // disturbed by other output.
+            let _paused = render::pause();
+            let mut captures = session.instruments.phase_ended(Phase::Trigger);
+            session.instruments.phase_started(Phase::Transfer);
+            let sent = send_kernel(
+                &mut port,
+                settings,
+                self.protocol,
+                self.image.as_deref(),
+                &mut session.context.interrupted.lock().unwrap(),
+            );
+            match sent {
+                Ok(report) => {
+                    if let Some(step) = session.uboot.transferred(true, clock(settings).now()) {
+                        report_handoff(settings, &step);
+                    }
+                    session.sends.reset();
+                    session.context.health.boot();
+                    captures.extend(match report {
+                        Some(_) => session.instruments.phase_ended(Phase::Transfer),
+                        None => session.instruments.stop_all(),
+                    });
+                    if let Some(report) = report {
+                        resume::save(settings, Some(&report.image));
+                        session.archived = match Archived::start(settings, &report) {
+                            Ok(archived) => archived,
+                            Err(e) => {
+                                println!(
+                                    "{}",
+                                    style(
+                                        settings
+                                            .messages
+                                            .text_with("boot.not_archived", &[("error", &e)])
+                                    )
+                                    .yellow()
+                                );
+                                None
+                            }
+                        };
+                        if let Some(archived) = &session.archived {
+                            println!(
+                                "{}",
+                                settings
+                                    .messages
+                                    .text_with("boot.archived", &[("id", &archived.id())])
+                            );
+                        }
+                        report_captures(settings, session, std::mem::take(&mut captures));
+                        session.instruments.phase_started(Phase::Boot);
+                        session.boot_check =
+                            BootCheck::start(&settings.expectations, clock(settings).now());
+                        if session.boot_check.is_none() {
+                            record_boot(settings, session, BootResult::Pushed);
+                        }
+                        session
+                            .context
+                            .report
+                            .transfer(&report, session.archived.as_ref().map(Archived::id));
+                        if let Some(archived) = &session.archived {
+                            session.context.report.file(
+                                format!("console log of push #{}", archived.id()),
+                                archived.log_path(),
+                            );
+                        }
+                        usage::record(settings, Some(&report));
+                        session.stats.transfer(report);
+                    }
+                    report_captures(settings, session, captures);
+                }
+                Err(e) => {
+                    info!("error: {:?}", e.to_string());
+                    session.stats.error(&e);
+                    println!(
+                        "{}",
+                        style(settings.messages.text("boot.send_failed")).red()
+                    );
+                    if session
+                        .uboot
+                        .transferred(false, clock(settings).now())
+                        .is_some()
+                    {
+                        println!("{}", settings.messages.text("boot.uboot_abandoned"));
+                    }
+                    let source = e.to_string();
+                    // The console output which follows is not the one of the
+                    // archived push anymore.
+                    session.archived = None;
+                    captures.extend(session.instruments.stop_all());
+                    report_captures(settings, session, captures);
+                    boards::record(settings, BootResult::Failed(source.clone()));
+                    usage::record(settings, None);
+                    let outcome = match e {
+                        SendError::Image(e) => Some(Outcome::ImageError {
+                            source: e.to_string(),
+                        }),
+                        // The device is gone if the port can't even be queried.
+                        SendError::Port(e) if port.bytes_to_read().is_err() => {
+                            Some(Outcome::PortError {
+                                source: e.to_string(),
+                            })
+                        }
+                        SendError::Port(_) => None,
+                        // The new build goes out when the bootloader asks for
+                        // the kernel again.
+                        // The device is still there, booting the image or
+                        // waiting for another one.
+                        SendError::Persist(ref e) => {
+                            println!(
+                                "{}",
+                                style(
+                                    settings
+                                        .messages
+                                        .text_with("boot.not_persisted", &[("error", e)])
+                                )
+                                .red()
+                            );
+                            None
+                        }
+                        SendError::ImageChanged => {
+                            println!(
+                                "{}",
+                                style(settings.messages.text("boot.image_changed")).yellow()
+                            );
+                            None
+                        }
+                    };
+                    if let Some(outcome) = outcome {
+                        return Event::Done(DoneEvent {
+                            settings: settings.clone(),
+                            outcome,
+                        });
+                    }
+                    session.context.health.error();
+                    if let Err(e) = session.sends.failed() {
+                        return Event::Done(DoneEvent {
+                            settings: settings.clone(),
+                            outcome: Outcome::TransferFailed {
+                                attempts: e.attempts,
+                                source,
+                            },
+                        });
+                    }
+                    println!(
+                        "{}",
+                        style(
+                            settings
+                                .messages
+                                .text_with("boot.waiting_request", &[("attempts", &session.sends)])
+                        )
+                        .dim()
+                    );
+                }
+            }
+
+            // Go back to terminal mode.
+            return Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                settings: settings.clone(),
+                port,
+            });
+        }
+
+        // We should never reach here!
+        unreachable!()
+    }
+}
+impl<T: Transport> fmt::Debug for KernelSendModeState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port {
+            Some(port) => f.debug_tuple("").field(&port.describe()).finish(),
+            None => f.debug_tuple("TerminalModeState").finish(),
+        }
+    }
+}
+
+// ServiceMode State ===========================================================
+
+/// A `state` of the boot protocol state machine where `bootcom` serves host
+/// service requests (file access in the sandboxed host directory, time...)
+/// from the booted kernel. See the `host_services` module for the details of
+/// the request and response frames.
+///
+/// This state can tranisition to another state as following:
+///
+///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** when the
+///    kernel ends the service session, or stops sending requests,
+///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
+///    interrupted due to unrecoverable errors, disconnection, etc.
+pub(crate) struct ServiceModeState {
+    /// The serial port to be used, already configured and open.
+    ///
+    /// Consumed and moved upon the transition to [`TerminalModeState`].
+    pub port: Option<Box<dyn SerialPort>>,
+}
+impl Runnable for ServiceModeState {
+    type Shared = Session;
+    type Event = Event;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event {
+        info!("=> Service Mode");
+
+        if let Some(mut port) = self.port.take() {
+            // The command is only recognized when host services are enabled.
+            let root = settings.host_dir.as_ref().unwrap();
+            let mut services = HostServices::new(root);
+            session.stats.host_service_sessions += 1;
+            let _traffic = pcap::mark(settings, Traffic::HostServices);
+            return match services.serve(&mut port) {
+                Ok(_) => Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                    settings: settings.clone(),
+                    port,
+                }),
+                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    // The kernel stopped talking to us in the middle of a
+                    // request. Go back to terminal mode and show what it does.
+                    println!(
+                        "{}",
+                        style(settings.messages.text("boot.services_timed_out")).yellow()
+                    );
+                    Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                        settings: settings.clone(),
+                        port,
+                    })
+                }
+                Err(ref e) => {
+                    info!("error: {:?}", e.to_string());
+                    session.stats.error(e);
+                    Event::Done(DoneEvent {
+                        settings: settings.clone(),
+                        outcome: Outcome::PortError {
+                            source: e.to_string(),
+                        },
+                    })
+                }
+            };
+        }
+
+        // We should never reach here!
+        unreachable!()
+    }
+}
+impl fmt::Debug for ServiceModeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port {
+            Some(port) => debug_fmt_serialport!(port, f).finish(),
+            None => f.debug_tuple("ServiceModeState").finish(),
+        }
+    }
+}
+
+// DumpMode State ==============================================================
+
+/// A `state` of the boot protocol state machine where `bootcom` receives a
+/// memory dump streamed by the device and saves it, with its metadata, in the
+/// dump directory. See the `dump` module for the details of the frames.
+///
+/// This state can tranisition to another state as following:
+///
+///  * **[`SwitchToTerminalModeEvent`] => [`TerminalModeState`]** once the dump
+///    is received, or when the device stops sending it,
+///  * **[`DoneEvent`] => [`DoneState`]** when the serial boot session is
+///    interrupted due to unrecoverable errors, disconnection, etc.
+pub(crate) struct DumpModeState {
+    /// The serial port to be used, already configured and open.
+    ///
+    /// Consumed and moved upon the transition to [`TerminalModeState`].
+    pub port: Option<Box<dyn SerialPort>>,
+}
+impl Runnable for DumpModeState {
+    type Shared = Session;
+    type Event = Event;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event {
+        info!("=> Dump Mode");
+
+        if let Some(mut port) = self.port.take() {
+            // The command is only recognized when a dump directory is set.
+            let directory = settings.dump_dir.as_ref().unwrap();
+            println!("{}", settings.messages.text("boot.dump_receiving"));
+            let _traffic = pcap::mark(settings, Traffic::Dump);
+            match receive_dump(&mut port, directory) {
+                Ok(dump) => {
+                    session.stats.dumps_received += 1;
+                    let values: &[(&str, &dyn fmt::Display)] = &[
+                        ("size", &HumanSize(dump.header.length.into())),
+                        ("address", &format!("{:#x}", dump.header.address)),
+                        ("path", &dump.path.display()),
+                    ];
+                    if dump.intact {
+                        let saved = settings.messages.text_with("boot.dump_saved", values);
+                        println!("{}", style(saved).green());
+                    } else {
+                        let saved = settings.messages.text_with("boot.dump_bad_crc", values);
+                        println!("{}", style(saved).yellow());
+                        session.stats.error("memory dump received with a bad CRC");
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == std::io::ErrorKind::TimedOut
+                        || e.kind() == std::io::ErrorKind::InvalidData =>
+                {
+                    // Back to terminal mode to show what the device does.
+                    println!(
+                        "{}",
+                        style(
+                            settings
+                                .messages
+                                .text_with("boot.dump_aborted", &[("error", e)])
+                        )
+                        .yellow()
+                    );
+                    session.stats.error(e);
+                }
+                Err(ref e) => {
+                    info!("error: {:?}", e.to_string());
+                    session.stats.error(e);
+                    return Event::Done(DoneEvent {
+                        settings: settings.clone(),
+                        outcome: Outcome::PortError {
+                            source: e.to_string(),
+                        },
+                    });
+                }
+            }
+            return Event::SwitchToTerminalMode(SwitchToTerminalModeEvent {
+                settings: settings.clone(),
+                port,
+            });
+        }
+
+        // We should never reach here!
+        unreachable!()
+    }
+}
+impl fmt::Debug for DumpModeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.port {
+            Some(port) => debug_fmt_serialport!(port, f).finish(),
+            None => f.debug_tuple("DumpModeState").finish(),
+        }
+    }
+}
+
+// Fault State =================================================================
+
+/// Reached when a state returned an event for which no transition exists,
+/// which is a bug in `bootcom` rather than a problem with the device.
+///
+/// Instead of bringing `bootcom` down, the anomaly is reported and the session
+/// is terminated:
+///
+///  * **[`DoneEvent`] => [`DoneState`]** with errors, so that the device
+///    manager goes back into waiting for the device to be ready.
+#[derive(Debug)]
+pub(crate) struct FaultState {
+    pub anomaly: String,
+}
+impl Runnable for FaultState {
+    type Shared = Session;
+    type Event = Event;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event {
+        info!("=> Fault");
+        println!(
+            "{}",
+            style(
+                settings
+                    .messages
+                    .text_with("boot.fault", &[("anomaly", &self.anomaly)])
+            )
+            .red()
+        );
+        session.stats.error(&self.anomaly);
+        Event::Done(DoneEvent {
+            settings: settings.clone(),
+            outcome: Outcome::Fault {
+                anomaly: self.anomaly.clone(),
+            },
+        })
+    }
+}
+
+// Done State ==================================================================
+
+/// Reached when the boot protocol state machine completes its execution and is
+/// about to terminate (normally or abnormally).
+///
+/// This state goes into a 2-phase execution. During the initial phase, it runs
+/// like any other state to do its own things like printing some information,
+/// cleaning up etc. It then triggers the [`ExitEvent`] to cause the boot
+/// protocol state machine to terminate and exit.
+///
+/// How the session ended is indicated with the `outcome` field in the state,
+/// which is returned from the boot protocol state machine event loop.
+#[derive(Debug, Clone)]
+pub(crate) struct DoneState {
+    /// How the session ended, with the cause of the errors.
+    pub outcome: Outcome,
+    /// When `true` instructs the boot protocol state machine to exit its event
+    /// loop.
+    pub should_exit: bool,
+}
+impl Runnable for DoneState {
+    type Shared = Session;
+    type Event = Event;
+    type Exit = Outcome;
+
+    fn run(&mut self, settings: &Settings, session: &mut Session) -> Event {
+        info!("=> Done ({})", self.outcome);
+        // Report errors
+        if self.outcome.is_error() {
+            session.context.health.error();
+        }
+        let messages = &settings.messages;
+        match &self.outcome {
+            Outcome::PortError { source } => {
+                println!(
+                    "{}",
+                    style(messages.text_with("boot.port_error", &[("error", source)])).red()
+                );
+                println!("{}", messages.text("boot.reconnect"));
+            }
+            Outcome::ImageError { source } => println!(
+                "{}",
+                style(messages.text_with("boot.image_error", &[("error", source)])).red()
+            ),
+            Outcome::TransferFailed { attempts, source } => println!(
+                "{}",
+                style(messages.text_with(
+                    "boot.transfer_failed",
+                    &[("attempts", attempts), ("error", source)]
+                ))
+                .red()
+            ),
+            // Already reported.
+            Outcome::PortBusy { .. } | Outcome::Fault { .. } | Outcome::UserQuit => (),
+        }
+        if let Some(report) = &session.stats.last_transfer {
+            println!(
+                "{}",
+                style(messages.text_with(
+                    "boot.last_image",
+                    &[
+                        ("report", report),
+                        ("retries", &report.retries),
+                        ("crc", &format!("{:#010x}", report.crc))
+                    ]
+                ))
+                .dim()
+            );
+        }
+        let captures = session.instruments.stop_all();
+        report_captures(settings, session, captures);
+        let summary = session.severities.summary();
+        if summary.lines > 0 {
+            match &settings.progress_observer {
+                Some(handle) => handle.observer().console_summary(summary),
+                None => println!("{}", summary.text(&settings.messages)),
+            }
+        }
+        session.finish();
+
+        Event::Exit(ExitEvent {
+            settings: settings.clone(),
+            outcome: self.outcome.clone(),
+        })
+    }
+
+    fn exit(&self) -> Option<Outcome> {
+        if self.should_exit {
+            Some(self.outcome.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn reset_grace_in_simulated_time() {
+    use crate::clock::SimulatedClock;
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+    use std::sync::Arc;
+
+    let clock = Arc::new(SimulatedClock::new());
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyBOOTCOM-unplugged")
+        .reset_grace(Duration::from_secs(10))
+        .clock(clock.clone())
+        .finalize();
+    let path = settings.path.as_deref();
+    assert!(reconnect::<Box<dyn SerialPort>>(&settings, "-", path).is_none());
+    assert_eq!(clock.elapsed(), Duration::from_secs(10));
+
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyBOOTCOM-unplugged")
+        .reset_grace(Duration::from_millis(0))
+        .clock(clock.clone())
+        .finalize();
+    let path = settings.path.as_deref();
+    assert!(reconnect::<Box<dyn SerialPort>>(&settings, "-", path).is_none());
+    assert_eq!(clock.elapsed(), Duration::from_secs(10));
+
+    // A link which can't be opened again isn't waited for.
+    let settings = SettingsBuilder::default()
+        .reset_grace(Duration::from_secs(10))
+        .clock(clock.clone())
+        .finalize();
+    assert!(reconnect::<MemoryTransport>(&settings, "memory", None).is_none());
+    assert_eq!(clock.elapsed(), Duration::from_secs(10));
+}
+
+#[test]
+fn trigger_over_a_memory_transport() {
+    use crate::fsm::Summarize;
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+
+    let mut device = MemoryTransport::new(|_: &[u8]| vec![]);
+    device.queue_input(b"Hello\r\n\x03\x03\x03");
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    let mut state = TerminalModeState { port: Some(device) };
+    match state.run(&settings, &mut Session::default()) {
+        Event::SwitchToKernelSendMode(event) => {
+            assert_eq!(event.protocol, TransferProtocol::Raspbootin);
+            assert_eq!(event.port.describe(), "memory");
+        }
+        event => panic!("unexpected {}", event.summary()),
+    }
+}
+
+/// The throughput of the console reads of terminal mode from a device sending
+/// at 921600 baud over a pseudo terminal, with each of the two changes made to
+/// them measured on its own: not waiting for the keyboard while data flows,
+/// and reading into a buffer reused by all the reads, as large as
+/// `max_read_size`, instead of one of 4 KiB at most allocated for each. Run
+/// with `cargo test --release read_throughput -- --ignored --nocapture`.
+#[cfg(unix)]
+#[test]
+#[ignore]
+fn read_throughput() {
+    use crate::settings::SettingsBuilder;
+    use serialport::TTYPort;
+    use std::{io::Write, thread, time::Instant};
+
+    const BAUD_921600: usize = 92160;
+    const SECONDS: usize = 3;
+    /// The output of 10 ms.
+    const BURST: usize = BAUD_921600 / 100;
+
+    fn measure(read: &mut dyn FnMut(&mut dyn Transport, usize) -> usize, wait: Duration) -> f64 {
+        let (mut device, slave) = TTYPort::pair().unwrap();
+        let mut port: Box<dyn SerialPort> = Box::new(slave);
+        // Bursts of 10 ms of output, as a UART at 921600 baud sends them.
+        // Waiting for the reads once the pseudo terminal is full.
+        device.set_timeout(Duration::from_secs(60)).unwrap();
+        let writer = thread::spawn(move || {
+            let burst = vec![b'x'; BURST];
+            let started = Instant::now();
+            for i in 1..=SECONDS * 100 {
+                device.write_all(&burst).unwrap();
+                let due = started + Duration::from_millis(10 * i as u64);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+            device
+        });
+        let started = Instant::now();
+        let mut received = 0;
+        while received < BURST * 100 * SECONDS {
+            match port.bytes_to_read().unwrap() {
+                0 => thread::sleep(Duration::from_millis(1)),
+                available => {
+                    received += read(&mut port, available as usize);
+                    thread::sleep(wait);
+                }
+            }
+        }
+        let rate = received as f64 / started.elapsed().as_secs_f64();
+        drop(writer.join().unwrap());
+        rate
+    }
+
+    let settings = SettingsBuilder::default().finalize();
+    let mut reused = vec![0; settings.max_read_size];
+    let mut allocated = |port: &mut dyn Transport, available: usize| {
+        let mut buf = vec![0; available.min(4096)];
+        port.read(&mut buf).unwrap()
+    };
+    let results = [
+        ("before", measure(&mut allocated, KEY_WAIT)),
+        ("no wait", measure(&mut allocated, Duration::ZERO)),
+        (
+            "reused",
+            measure(
+                &mut |port, available| {
+                    let wanted = available.min(reused.len());
+                    port.read(&mut reused[..wanted]).unwrap()
+                },
+                KEY_WAIT,
+            ),
+        ),
+    ];
+    for (name, rate) in results {
+        println!(
+            "{:>7}: {:>6.0} B/s, {:>3.0}% of 921600 baud",
+            name,
+            rate,
+            rate * 100.0 / BAUD_921600 as f64
+        );
+    }
+}
@@ -5,7 +5,7 @@
 //! use bootcom::{self as bc, DeviceManager};
 //!
 //! let settings = bc::SettingsBuilder::default().finalize();
-//! let mut sdm = bc::singleton(settings);
+//! let mut sdm = bc::BootServer::new(settings);
 //! let status = sdm.run(); // status code returned after the `Exit` event
 //! println!("status: {}", status);
 //! std::process::exit(0);
@@ -15,4 +15,4 @@ mod events;
 mod state_machine;
 mod states;
 
-pub use state_machine::{singleton, DeviceManager};
+pub use state_machine::{BootServer, DeviceManager};
@@ -41,86 +41,127 @@
 //!                               v
 //!                              END
 //! ```
+//!
+//! An event for which no transition exists from the current state leads to the
+//! `Fault` state, which reports the anomaly and goes back to `WaitForPort` (or
+//! to `Done` with an error when no device path is known).
 
-use std::sync::{Arc, Mutex, Once};
+use std::sync::{Arc, Mutex};
 
 use super::events::*;
 use super::states::*;
+use crate::console_lines::ConsoleLines;
+use crate::context::Context;
+use crate::fsm::{FaultEvent, Machine, StateMachine};
+use crate::session_handle::SessionHandle;
 use crate::settings::Settings;
+use crate::stats::SessionStats;
+use crate::utils::prepare_terminal;
 
 // =============================================================================
 // Public Interface
 // =============================================================================
 
 // -----------------------------------------------------------------------------
-// Device Manager Singleton
+// Device Manager
 // -----------------------------------------------------------------------------
 
 pub trait DeviceManager {
     fn run(&mut self) -> i8;
+    /// The statistics of the boot sessions that ended so far.
+    fn stats(&self) -> SessionStats;
 }
 
 /// Encapsulate the state machine creation and event loop to provide a concise
 /// and simple public interface to the module users.
 ///
-/// Only one instance of this struct exists, using the `singleton` pattern, and
-/// which can accessed by calling the `singleton()` function.
+/// Each instance manages its own device with its own settings, statistics and
+/// output sinks, so that several devices can be managed from the same process,
+/// each one from its own thread. Cloning it gives another handle to the same
+/// device manager, e.g. to look at its statistics while it runs.
+///
+/// **Example**
+/// ```no_run
+/// use bootcom::{BootServer, DeviceManager, SettingsBuilder};
+///
+/// let boards = ["/dev/ttyUSB0", "/dev/ttyUSB1"];
+/// let threads: Vec<_> = boards
+///     .iter()
+///     .map(|path| {
+///         // Only one of them can read the keyboard.
+///         let settings = SettingsBuilder::default()
+///             .path(*path)
+///             .keyboard(false)
+///             .finalize();
+///         let mut server = BootServer::new(settings);
+///         std::thread::spawn(move || server.run())
+///     })
+///     .collect();
+/// for thread in threads {
+///     println!("status: {}", thread.join().unwrap());
+/// }
+/// ```
 #[derive(Clone)]
-pub struct SingletonReader {
+pub struct BootServer {
     // Since this can be used in many threads, we need to protect concurrent
     // access
     inner: Arc<Mutex<DeviceManagerStates>>,
+    // Another handle to the context of the state machine, so that it can be
+    // looked at while the state machine runs.
+    context: Context,
+}
+impl BootServer {
+    /// Create a device manager for the device of the `settings`.
+    pub fn new(settings: Settings) -> Self {
+        let sm = StateMachine::new(settings);
+        BootServer {
+            context: sm.shared.clone(),
+            inner: Arc::new(Mutex::new(DeviceManagerStates::Init(sm))),
+        }
+    }
+
+    /// The lines printed by the device from now on, until the device manager
+    /// stops running.
+    pub fn console_lines(&self) -> ConsoleLines {
+        self.context.outputs.subscribe_lines()
+    }
+
+    /// A handle to write to the device while the sessions run.
+    pub fn session_handle(&self) -> SessionHandle {
+        self.context.session.clone()
+    }
+
+    /// Write the report of the run to the file of the settings, if any, with
+    /// the statistics of the sessions that ended so far. Done when the device
+    /// manager stops running, and to be done when interrupting it.
+    pub fn write_report(&self) {
+        self.context.report.write(&self.stats());
+    }
 }
-impl DeviceManager for SingletonReader {
+impl DeviceManager for BootServer {
     /// The device manager event loop runs until the `Done` state is reached and
     /// its `should_exit` flag is set. At such point, the event loop terminates
     /// and returns an exit code indicating no errors when equal to **`0`**;
-    /// otherwise a termination with error.
+    /// otherwise a termination with error, after dumping the history of the
+    /// state machines events to the standard error.
     ///
     /// The returned status code could be used as an exit code from `bootcom`.
     fn run(&mut self) -> i8 {
-        loop {
-            let mut data = self.inner.lock().unwrap();
-            *data = data.step();
-            if let DeviceManagerStates::Done(sm) = &*data {
-                if sm.state.should_exit {
-                    return if sm.state.with_error { 1 } else { 0 };
-                }
-            }
+        let code = self.inner.lock().unwrap().run_to_exit();
+        self.context.health.stopping();
+        self.context.outputs.close_lines();
+        self.write_report();
+        if code != 0 {
+            eprintln!("{}", self.context.history);
+            self.context
+                .debug_bundle
+                .save(&format!("exit code {}", code), Some(&self.stats()));
         }
+        code
     }
-}
 
-/// Returns the single instance of the device manager.
-///
-/// In order to use the singleton instance, proper locking needs to be observed.
-/// The example below demonstrates an example usage scenario:
-///
-/// ```ignore
-///     let settings = SettingsBuilder::new().finalize();
-///     let mut s = singleton(settings);
-///     s.run();
-/// ```
-pub fn singleton(settings: Settings) -> SingletonReader {
-    // Initialize it to a null value
-    static mut DM_SINGLETON: *const SingletonReader = 0 as *const SingletonReader;
-    static DM_ONCE: Once = Once::new();
-
-    unsafe {
-        DM_ONCE.call_once(|| {
-            // Make it
-            let singleton = SingletonReader {
-                inner: Arc::new(Mutex::new(DeviceManagerStates::Init(
-                    DeviceManagerStateMachine::new(settings),
-                ))),
-            };
-
-            // Put it in the heap so it can outlive this call
-            DM_SINGLETON = std::mem::transmute(Box::new(singleton));
-        });
-
-        // Now we give out a copy of the data that is safe to use concurrently.
-        (*DM_SINGLETON).clone()
+    fn stats(&self) -> SessionStats {
+        self.context.stats.lock().unwrap().clone()
     }
 }
 
@@ -128,164 +169,144 @@ pub fn singleton(settings: Settings) -> SingletonReader {
 // Private stuff
 // =============================================================================
 
-// -----------------------------------------------------------------------------
-// The State Machine
-// -----------------------------------------------------------------------------
-
-/// The state machine implementing `bootcom`'s management of serial port devices
-/// lifecycle.
-///
-/// Note that using a generic type that holds the current state serves two
-/// purposes. It allows for also having shared data by all states that is not
-/// really part of state data (e.g. state machine parameters, statistics,
-/// etc...). Additionally, it's nicer when debugging to see the state machine
-/// and the current state it is holding at any time.
-#[derive(Debug)]
-struct DeviceManagerStateMachine<S: Runnable> {
-    settings: Settings,
-    state: S,
-}
-impl<S: Runnable> DeviceManagerStateMachine<S> {
-    fn run(&mut self) -> Event {
-        self.state.run(&self.settings)
-    }
-}
-
 /// The device management state machine starts in the `InitState`.
-impl DeviceManagerStateMachine<InitState> {
+impl StateMachine<InitState> {
     fn new(settings: Settings) -> Self {
-        DeviceManagerStateMachine {
+        let context = Context::new(&settings);
+        context.history.dump_on_panic();
+        context.debug_bundle.save_on_panic();
+        prepare_terminal();
+        StateMachine {
+            shared: context,
             settings,
             state: InitState {},
         }
     }
 }
 
-/// Wraps the state machine and its various states into a simple enum, which can
-/// also be used for pattern matching during state transitions.
-enum DeviceManagerStates {
-    Init(DeviceManagerStateMachine<InitState>),
-    WaitForPort(DeviceManagerStateMachine<WaitForPortState>),
-    SelectPort(DeviceManagerStateMachine<SelectPortState>),
-    Service(DeviceManagerStateMachine<ServiceState>),
-    Done(DeviceManagerStateMachine<DoneState>),
-}
-impl DeviceManagerStates {
-    fn step(&mut self) -> Self {
-        match self {
-            DeviceManagerStates::Init(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::WaitForPort(ev) => DeviceManagerStates::WaitForPort(ev.into()),
-                    Event::SelectPort(ev) => DeviceManagerStates::SelectPort(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            DeviceManagerStates::WaitForPort(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::PortReady(ev) => DeviceManagerStates::Service(ev.into()),
-                    Event::SelectPort(ev) => DeviceManagerStates::SelectPort(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            DeviceManagerStates::SelectPort(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::SelectPort(ev) => DeviceManagerStates::SelectPort(ev.into()),
-                    Event::PortReady(ev) => DeviceManagerStates::Service(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            DeviceManagerStates::Service(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::Done(ev) => DeviceManagerStates::Done(ev.into()),
-                    Event::PortError(ev) => DeviceManagerStates::WaitForPort(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-            DeviceManagerStates::Done(sm) => {
-                let event = sm.run();
-                match event {
-                    Event::Exit(ev) => DeviceManagerStates::Done(ev.into()),
-                    _ => unreachable!("illegal event {:#?} at current state {:#?}", event, sm),
-                }
-            }
-        }
+transitions! {
+    /// Wraps the state machine and its various states into a simple enum, which
+    /// can also be used for pattern matching during state transitions.
+    enum DeviceManagerStates, shared Context, exit i8;
+    states {
+        Init(InitState) {
+            WaitForPort => WaitForPort,
+            SelectPort => SelectPort,
+        },
+        WaitForPort(WaitForPortState) {
+            PortReady => Service,
+            SelectPort => SelectPort,
+            Done => Done,
+        },
+        SelectPort(SelectPortState) {
+            SelectPort => SelectPort,
+            PortReady => Service,
+            Done => Done,
+        },
+        Service(ServiceState) {
+            Done => Done,
+            PortError => WaitForPort,
+            SelectPort => SelectPort,
+        },
+        Fault(FaultState) {
+            WaitForPort => WaitForPort,
+            Done => Done,
+        },
+        Done(DoneState) {
+            Exit => Done,
+        },
     }
-}
-
-// -----------------------------------------------------------------------------
-// State from Event transitions
-// -----------------------------------------------------------------------------
-
-impl From<WaitForPortEvent> for DeviceManagerStateMachine<WaitForPortState> {
-    fn from(event: WaitForPortEvent) -> DeviceManagerStateMachine<WaitForPortState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: WaitForPortState {},
+    // The context is not part of the events, it is moved over to the new state
+    // machine by `step()`.
+    from {
+        |event: WaitForPortEvent| WaitForPortState {}
+        |event: PortErrorEvent| WaitForPortState {}
+        |event: SelectPortEvent| SelectPortState {
+            selections: event.selections,
         }
-    }
-}
-impl From<PortErrorEvent> for DeviceManagerStateMachine<WaitForPortState> {
-    fn from(event: PortErrorEvent) -> DeviceManagerStateMachine<WaitForPortState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: WaitForPortState {},
+        |event: PortReadyEvent| ServiceState {}
+        |event: FaultEvent| FaultState {
+            anomaly: event.anomaly,
         }
-    }
-}
-
-impl From<SelectPortEvent> for DeviceManagerStateMachine<SelectPortState> {
-    fn from(event: SelectPortEvent) -> DeviceManagerStateMachine<SelectPortState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: SelectPortState {},
+        |event: DoneEvent| DoneState {
+            with_error: event.with_errors,
+            should_exit: false,
         }
-    }
-}
-
-impl From<PortReadyEvent> for DeviceManagerStateMachine<ServiceState> {
-    fn from(event: PortReadyEvent) -> DeviceManagerStateMachine<ServiceState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: ServiceState {},
+        |event: ExitEvent| DoneState {
+            with_error: event.with_error,
+            should_exit: true,
         }
     }
 }
 
-impl From<DoneEvent> for DeviceManagerStateMachine<DoneState> {
-    fn from(event: DoneEvent) -> DeviceManagerStateMachine<DoneState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: DoneState {
-                with_error: event.with_errors,
-                should_exit: false,
-            },
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(unix)]
+#[test]
+fn independent_device_managers() {
+    use std::{
+        io::Write,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serialport::{SerialPort, TTYPort};
+
+    use crate::settings::{RetryPolicy, SettingsBuilder};
+
+    // Each board is simulated by the master side of a pseudo terminal.
+    let dir = std::env::temp_dir().join(format!("bootcom-managers-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let boards: Vec<_> = ["board A", "board B"]
+        .iter()
+        .enumerate()
+        .map(|(i, banner)| {
+            let (master, slave) = TTYPort::pair().unwrap();
+            let record = dir.join(format!("{}.cast", i));
+            let settings = SettingsBuilder::default()
+                .path(slave.name().unwrap())
+                .keyboard(false)
+                .record(record.to_string_lossy())
+                .retry(RetryPolicy {
+                    port_wait_attempts: 1,
+                    ..RetryPolicy::default()
+                })
+                .finalize();
+            let server = BootServer::new(settings);
+            let mut running = server.clone();
+            let thread = thread::spawn(move || running.run());
+            (banner, master, slave, record, server, thread)
+        })
+        .collect();
+
+    for (banner, master, _, record, _, _) in &boards {
+        let mut master = master.try_clone_native().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        let mut recorded = String::new();
+        while !recorded.contains(*banner) && Instant::now() < deadline {
+            master.write_all(banner.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            recorded = std::fs::read_to_string(record).unwrap_or_default();
         }
+        assert!(recorded.contains(*banner));
     }
-}
-impl From<ExitEvent> for DeviceManagerStateMachine<DoneState> {
-    fn from(event: ExitEvent) -> DeviceManagerStateMachine<DoneState> {
-        // ... Logic prior to transition
-        DeviceManagerStateMachine {
-            // ... attr: val.attr
-            settings: event.settings,
-            state: DoneState {
-                with_error: event.with_error,
-                should_exit: true,
-            },
-        }
+
+    // Unplug both boards, both managers give up waiting for them.
+    for (banner, master, slave, record, server, thread) in boards {
+        drop(master);
+        drop(slave);
+        assert_ne!(thread.join().unwrap(), 0);
+        let stats = server.stats();
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(stats.bytes_received % banner.len() as u64, 0);
+        let other = if banner.ends_with('A') {
+            "board B"
+        } else {
+            "board A"
+        };
+        assert!(!std::fs::read_to_string(record).unwrap().contains(other));
     }
+    std::fs::remove_dir_all(dir).unwrap();
 }
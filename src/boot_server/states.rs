@@ -1,162 +1,270 @@
-//! States for the `bootcom` boot server state machine.
-//!
-//! This modules is private and restricted to the
-//! [`boot_server`](crate::boot_server) scope. The public interface of the state
-//! machine is provided by [`boot_server`](crate::boot_server).
-//!
-//! ```ignore
-//! use super::events::*;
-//! ```
-//!
-//! Refer to the [`state_machine`](super::state_machine) module for an overview
-//! of states, events and transitions.
-
-use log::info;
-
-use crate::utils;
-use crate::{
-    boot_protocol::{self as bpsm},
-    settings::Settings,
-};
-
-use super::events::*;
-
-// =============================================================================
-// Crate-Public Interface
-// =============================================================================
-
-/// Trait adding the ability for a state to be `run` after a transition into it.
-pub(crate) trait Runnable {
-    /// A state implements this method so it can be `run` after the state
-    /// machine transitions into it.
-    ///
-    /// During this call, the state can do any work that needs to be done and
-    /// when finished, requests transition to a new state by returning the
-    /// appropriate `event`. The `event` is then consumed to create the new
-    /// `state` using the corresponding `From` trait implementation if avaiable.
-    fn run(&mut self, settings: &Settings) -> Event;
-}
-
-// Init State ==================================================================
-
-/// Represents the initial state of the device manager state machine.
-///
-/// From the `InitState`, the state machine can evolve via the following
-/// transitions:
-///
-///  * **`WaitForPortEvent` => `WaitForPortState`** when a specific device path
-///    was provided in the settings,
-///  * **`SelectPortEvent` => `SelectPortState`** when no device path was
-///    provided in the settings.
-#[derive(Debug)]
-pub(crate) struct InitState {}
-impl Runnable for InitState {
-    /// At the `Init` state, check if the provided `settings` have a device
-    /// path, and if yes, transition to the `WaitForPort` state; otherwise
-    /// transition to the `SelectPort` state.
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!("=> Init");
-        match settings.path {
-            Some(_) => Event::WaitForPort(WaitForPortEvent {
-                settings: settings.clone(),
-            }),
-            None => Event::SelectPort(SelectPortEvent {
-                settings: settings.clone(),
-            }),
-        }
-    }
-}
-
-// WaitForPortState ============================================================
-
-#[derive(Debug)]
-pub(crate) struct WaitForPortState {}
-impl Runnable for WaitForPortState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        let path = settings.path.as_ref().unwrap();
-        info!("=> WaitForPort");
-        let canceled = utils::wait_for_port(path);
-        if canceled {
-            Event::SelectPort(SelectPortEvent {
-                settings: settings.clone(),
-            })
-        } else {
-            // The wait for port to be ready completed without cancellation. Fire
-            // the `PortReady` event to trigger the transition to the next state.
-            Event::PortReady(PortReadyEvent {
-                settings: settings.clone(),
-            })
-        }
-    }
-}
-
-// SelectPortState =============================================================
-
-#[derive(Debug)]
-pub(crate) struct SelectPortState {}
-impl Runnable for SelectPortState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!("=> SelectPort");
-        let selection = crate::utils::select_port();
-        match selection {
-            // We have a serial port device path that we now need to update in
-            // the settings and then trigger the transition via the `PortReady`
-            // event.
-            Some(path) => {
-                let mut cloned_settings = settings.clone();
-                cloned_settings.path = Some(path);
-                Event::PortReady(PortReadyEvent {
-                    settings: cloned_settings,
-                })
-            }
-            None => Event::SelectPort(SelectPortEvent {
-                settings: settings.clone(),
-            }),
-        }
-    }
-}
-
-// ServiceState ================================================================
-
-#[derive(Debug)]
-pub(crate) struct ServiceState {}
-impl Runnable for ServiceState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!("=> Service");
-
-        let mut bpsm = bpsm::factory(settings.clone());
-        match bpsm.run() {
-            // Normal termination -> we're done.
-            0 => Event::Done(DoneEvent {
-                settings: settings.clone(),
-                with_errors: false,
-            }),
-            // A port error inside the boot protocol state machine -> wait for
-            // the device to be ready again
-            _ => Event::PortError(PortErrorEvent {
-                settings: settings.clone(),
-            }),
-        }
-    }
-}
-
-// Done State ==================================================================
-
-// State B goes and breaks up that String into words.
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct DoneState {
-    pub with_error: bool,
-    pub should_exit: bool,
-}
-impl Runnable for DoneState {
-    fn run(&mut self, settings: &Settings) -> Event {
-        info!(
-            "=> Done with{}errors",
-            if self.with_error { " " } else { " no " }
-        );
-        Event::Exit(ExitEvent {
-            settings: settings.clone(),
-            with_error: self.with_error,
-        })
-    }
-}
+//! States for the `bootcom` boot server state machine.
+//!
+//! This modules is private and restricted to the
+//! [`boot_server`](crate::boot_server) scope. The public interface of the state
+//! machine is provided by [`boot_server`](crate::boot_server).
+//!
+//! ```ignore
+//! use super::events::*;
+//! ```
+//!
+//! Refer to the [`state_machine`](super::state_machine) module for an overview
+//! of states, events and transitions.
+
+use console::style;
+use log::info;
+
+use crate::utils::{self, Attempts, RetriesExhausted};
+use crate::{
+    boot_protocol::{self as bpsm, Outcome},
+    context::Context,
+    fsm::Runnable,
+    settings::Settings,
+};
+
+use super::events::*;
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+// Init State ==================================================================
+
+/// Represents the initial state of the device manager state machine.
+///
+/// From the `InitState`, the state machine can evolve via the following
+/// transitions:
+///
+///  * **`WaitForPortEvent` => `WaitForPortState`** when a specific device path
+///    or a port rule was provided in the settings,
+///  * **`SelectPortEvent` => `SelectPortState`** when neither was provided in
+///    the settings.
+#[derive(Debug)]
+pub(crate) struct InitState {}
+impl Runnable for InitState {
+    /// At the `Init` state, check if the provided `settings` have a device
+    /// path or a port rule, and if yes, transition to the `WaitForPort` state;
+    /// otherwise transition to the `SelectPort` state.
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, _context: &mut Context) -> Event {
+        info!("=> Init");
+        if settings.path.is_some() || settings.port_match.is_some() {
+            Event::WaitForPort(WaitForPortEvent {
+                settings: settings.clone(),
+            })
+        } else {
+            Event::SelectPort(SelectPortEvent::new(settings.clone()))
+        }
+    }
+}
+
+// WaitForPortState ============================================================
+
+#[derive(Debug)]
+pub(crate) struct WaitForPortState {}
+impl Runnable for WaitForPortState {
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, context: &mut Context) -> Event {
+        info!("=> WaitForPort");
+        match utils::wait_for_port(settings) {
+            Ok(None) => Event::SelectPort(SelectPortEvent::new(settings.clone())),
+            // The wait for port to be ready completed without cancellation. Fire
+            // the `PortReady` event to trigger the transition to the next state,
+            // with the path found for the port rule if any.
+            Ok(Some(path)) => {
+                let mut settings = settings.clone();
+                settings.path = Some(path);
+                Event::PortReady(PortReadyEvent { settings })
+            }
+            Err(e) => give_up(settings, context, e),
+        }
+    }
+}
+
+// SelectPortState =============================================================
+
+#[derive(Debug)]
+pub(crate) struct SelectPortState {
+    /// The selections canceled in a row before this one.
+    pub selections: Attempts,
+}
+impl Runnable for SelectPortState {
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, context: &mut Context) -> Event {
+        info!("=> SelectPort");
+        let selection = match crate::utils::select_port(settings) {
+            Ok(selection) => selection,
+            Err(e) => return give_up(settings, context, e),
+        };
+        match selection {
+            // We have a serial port device path that we now need to update in
+            // the settings and then trigger the transition via the `PortReady`
+            // event. The port selected takes over the port rule, if any.
+            Some(path) => {
+                let mut cloned_settings = settings.clone();
+                cloned_settings.path = Some(path);
+                cloned_settings.port_match = None;
+                Event::PortReady(PortReadyEvent {
+                    settings: cloned_settings,
+                })
+            }
+            // Refresh the list, unless the user canceled too many times.
+            None => {
+                let mut selections = self.selections.clone();
+                match selections.failed() {
+                    Ok(()) => Event::SelectPort(SelectPortEvent {
+                        settings: settings.clone(),
+                        selections,
+                    }),
+                    Err(e) => give_up(settings, context, e),
+                }
+            }
+        }
+    }
+}
+
+/// Give up on finding a port to use, ending `bootcom` with an error.
+fn give_up(settings: &Settings, context: &Context, e: RetriesExhausted) -> Event {
+    println!(
+        "{}",
+        style(
+            settings
+                .messages
+                .text_with("server.gave_up", &[("error", &e)])
+        )
+        .red()
+    );
+    context.health.error();
+    context.stats.lock().unwrap().error(&e);
+    Event::Done(DoneEvent {
+        settings: settings.clone(),
+        with_errors: true,
+    })
+}
+
+// ServiceState ================================================================
+
+#[derive(Debug)]
+pub(crate) struct ServiceState {}
+impl Runnable for ServiceState {
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, context: &mut Context) -> Event {
+        info!("=> Service");
+
+        // With the changes of the configuration file queued so far.
+        let settings = &context.config.settings_for_session(settings);
+        let mut bpsm = bpsm::factory(settings.clone(), context.clone());
+        let outcome = bpsm.run();
+        info!("boot session ended: {}", outcome);
+        match outcome {
+            // The user is done -> so are we.
+            Outcome::UserQuit => Event::Done(DoneEvent {
+                settings: settings.clone(),
+                with_errors: false,
+            }),
+            // A port error inside the boot protocol state machine, or a bug
+            // which cost us the port -> wait for the device to be ready again
+            Outcome::PortError { source } | Outcome::Fault { anomaly: source } => {
+                Event::PortError(PortErrorEvent {
+                    settings: settings.clone(),
+                    source,
+                })
+            }
+            // Another program holds on to the port and the user does not want
+            // to wait for it -> pick another one.
+            Outcome::PortBusy { .. } => Event::SelectPort(SelectPortEvent::new(settings.clone())),
+            // Nothing to boot, or nothing the device can boot, retrying
+            // won't help.
+            Outcome::ImageError { .. } | Outcome::TransferFailed { .. } => Event::Done(DoneEvent {
+                settings: settings.clone(),
+                with_errors: true,
+            }),
+        }
+    }
+}
+
+// Fault State =================================================================
+
+/// Reached when a state returned an event for which no transition exists,
+/// which is a bug in `bootcom` rather than a problem with the device.
+///
+/// Instead of bringing `bootcom` down, the anomaly is reported and the state
+/// machine evolves via the following transitions:
+///
+///  * **`WaitForPortEvent` => `WaitForPortState`** when a device path is known,
+///    to keep long running sessions alive,
+///  * **`DoneEvent` => `DoneState`** with errors otherwise.
+#[derive(Debug)]
+pub(crate) struct FaultState {
+    pub anomaly: String,
+}
+impl Runnable for FaultState {
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, context: &mut Context) -> Event {
+        info!("=> Fault");
+        println!(
+            "{}",
+            style(
+                settings
+                    .messages
+                    .text_with("boot.fault", &[("anomaly", &self.anomaly)])
+            )
+            .red()
+        );
+        context.health.error();
+        context.stats.lock().unwrap().error(&self.anomaly);
+        match settings.path {
+            Some(_) => Event::WaitForPort(WaitForPortEvent {
+                settings: settings.clone(),
+            }),
+            None => Event::Done(DoneEvent {
+                settings: settings.clone(),
+                with_errors: true,
+            }),
+        }
+    }
+}
+
+// Done State ==================================================================
+
+// State B goes and breaks up that String into words.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct DoneState {
+    pub with_error: bool,
+    pub should_exit: bool,
+}
+impl Runnable for DoneState {
+    type Shared = Context;
+    type Event = Event;
+    type Exit = i8;
+
+    fn run(&mut self, settings: &Settings, _context: &mut Context) -> Event {
+        info!(
+            "=> Done with{}errors",
+            if self.with_error { " " } else { " no " }
+        );
+        Event::Exit(ExitEvent {
+            settings: settings.clone(),
+            with_error: self.with_error,
+        })
+    }
+
+    fn exit(&self) -> Option<i8> {
+        self.should_exit.then_some(self.with_error as i8)
+    }
+}
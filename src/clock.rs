@@ -0,0 +1,162 @@
+//! The clock the sessions wait and time out with.
+//!
+//! The waits of the state machines (for the port to show up or to come back
+//! after a reset, for a port to settle, for the device to answer within the
+//! handshake of a transfer...) and the deadlines of the watchdogs following the
+//! console (boot stages, U-Boot handoff, ROM loaders, line checks) go through
+//! the [`Clock`] of the settings. It is the system clock unless another one is
+//! injected, like the [`SimulatedClock`] of the tests: its time only moves
+//! when something sleeps, so that the waits of minutes are over at once, and
+//! always the same way.
+//!
+//! The reads and writes of the port keep the real timeouts of the port, the
+//! device on the other end lives in real time.
+//!
+//! **Example**
+//! ```
+//! use std::time::Duration;
+//!
+//! use bootcom::clock::{Clock, SimulatedClock};
+//!
+//! let clock = SimulatedClock::new();
+//! let start = clock.now();
+//! clock.sleep(Duration::from_secs(3600));
+//! assert_eq!(clock.now() - start, Duration::from_secs(3600));
+//! ```
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::settings::Settings;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Tells the time, and waits.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Wait for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clock of the system.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A clock whose time only moves when something sleeps or when it is
+/// advanced, sleeping returning at once.
+#[derive(Debug)]
+pub struct SimulatedClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+impl SimulatedClock {
+    pub fn new() -> Self {
+        SimulatedClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the time forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How much time went by since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        SimulatedClock::new()
+    }
+}
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+        // Still give the other threads a chance to run.
+        thread::yield_now();
+    }
+}
+
+/// A [`Clock`] shared by the settings and their clones.
+///
+/// Two handles are equal only when they refer to the same clock.
+#[derive(Clone)]
+pub struct ClockHandle(Arc<dyn Clock>);
+impl ClockHandle {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        ClockHandle(clock)
+    }
+}
+impl PartialEq for ClockHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for ClockHandle {}
+impl fmt::Debug for ClockHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClockHandle")
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// The clock of the `settings`, the system one unless another was injected.
+pub(crate) fn clock(settings: &Settings) -> &dyn Clock {
+    match &settings.clock {
+        Some(handle) => &*handle.0,
+        None => &SystemClock,
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn simulated_time() {
+    let clock = Arc::new(SimulatedClock::new());
+    let settings = crate::SettingsBuilder::default()
+        .clock(clock.clone())
+        .finalize();
+    let start = clock.now();
+    let real = Instant::now();
+    self::clock(&settings).sleep(Duration::from_secs(60));
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(
+        self::clock(&settings).now() - start,
+        Duration::from_secs(61)
+    );
+    assert_eq!(clock.elapsed(), Duration::from_secs(61));
+    assert!(real.elapsed() < Duration::from_secs(1));
+
+    let settings = crate::SettingsBuilder::default().finalize();
+    let before = Instant::now();
+    assert!(self::clock(&settings).now() >= before);
+}
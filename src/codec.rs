@@ -0,0 +1,262 @@
+//! Codecs transforming the console streams of the device.
+//!
+//! The data received from the device goes through a chain of codecs before it
+//! is shown and written to the output sinks, and the console input sent to the
+//! device (the lines of the console input script) goes through the same chain
+//! in the other direction. Teams with their own log encoding can decode it with a codec of
+//! their own, without touching the terminal internals; the trigger patterns and
+//! the boot expectations are always matched against the raw data.
+//!
+//! Each boot session gets fresh codecs from the [`CodecFactory`] list of the
+//! settings, so that codecs can keep state across reads (e.g. whether the
+//! next byte starts a line). A few codecs are built in and can be listed in
+//! the configuration file by name:
+//!
+//! * `timestamp` prefixes every received line with the time since the start of
+//!   the session,
+//! * `strip-ansi` removes the ANSI escape sequences (colors, cursor moves...)
+//!   from the received data.
+//!
+//! **Example**
+//! ```
+//! use bootcom::{
+//!     codec::{Codec, CodecFactory},
+//!     SettingsBuilder,
+//! };
+//!
+//! /// Shows the received data in upper case.
+//! struct Shout;
+//! impl Codec for Shout {
+//!     fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+//!         data.to_ascii_uppercase()
+//!     }
+//! }
+//!
+//! let settings = SettingsBuilder::default()
+//!     .codecs(vec![CodecFactory::new("shout", || Box::new(Shout))])
+//!     .finalize();
+//! ```
+
+use std::{fmt, io, sync::Arc, time::Instant};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Transforms the console streams of the device, in both directions.
+pub trait Codec: Send {
+    /// Transform the `data` received from the device, before it is shown.
+    fn decode(&mut self, data: &[u8]) -> Vec<u8>;
+    /// Transform the `data` about to be sent to the device. Sent as is by
+    /// default.
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+}
+
+/// Creates a new [`Codec`] for each boot session, as kept in the [`Settings`].
+///
+/// Two factories are equal only when they have the same name and refer to the
+/// same function.
+///
+/// [`Settings`]: crate::Settings
+#[derive(Clone)]
+pub struct CodecFactory {
+    name: String,
+    make: Arc<dyn Fn() -> Box<dyn Codec> + Send + Sync>,
+}
+impl CodecFactory {
+    pub fn new(name: &str, make: impl Fn() -> Box<dyn Codec> + Send + Sync + 'static) -> Self {
+        CodecFactory {
+            name: name.into(),
+            make: Arc::new(make),
+        }
+    }
+
+    /// The built-in codec with the given `name`, if there is one.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "timestamp" => Some(CodecFactory::new(name, || Box::new(Timestamp::default()))),
+            "strip-ansi" => Some(CodecFactory::new(name, || Box::new(StripAnsi::default()))),
+            _ => None,
+        }
+    }
+
+    /// The name of the codec, as shown in the summary of the settings.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+impl PartialEq for CodecFactory {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && Arc::ptr_eq(&self.make, &other.make)
+    }
+}
+impl Eq for CodecFactory {}
+impl fmt::Debug for CodecFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CodecFactory").field(&self.name).finish()
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// The codecs of a boot session, applied in order to the received data and in
+/// reverse order to the data sent.
+#[derive(Default)]
+pub(crate) struct CodecChain {
+    codecs: Vec<Box<dyn Codec>>,
+}
+impl CodecChain {
+    pub(crate) fn new(factories: &[CodecFactory]) -> Self {
+        CodecChain {
+            codecs: factories.iter().map(|factory| (factory.make)()).collect(),
+        }
+    }
+
+    pub(crate) fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for codec in &mut self.codecs {
+            data = codec.decode(&data);
+        }
+        data
+    }
+
+    pub(crate) fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for codec in self.codecs.iter_mut().rev() {
+            data = codec.encode(&data);
+        }
+        data
+    }
+
+    /// A writer encoding everything written to `device`.
+    pub(crate) fn encoder<'a>(&'a mut self, device: &'a mut dyn io::Write) -> Encoder<'a> {
+        Encoder {
+            chain: self,
+            device,
+        }
+    }
+}
+impl fmt::Debug for CodecChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodecChain")
+            .field("codecs", &self.codecs.len())
+            .finish()
+    }
+}
+
+/// Encodes the data written to a device with a [`CodecChain`].
+pub(crate) struct Encoder<'a> {
+    chain: &'a mut CodecChain,
+    device: &'a mut dyn io::Write,
+}
+impl io::Write for Encoder<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let encoded = self.chain.encode(buf);
+        self.device.write_all(&encoded)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Prefixes every received line with the time since the start of the session.
+struct Timestamp {
+    started: Instant,
+    at_line_start: bool,
+}
+impl Default for Timestamp {
+    fn default() -> Self {
+        Timestamp {
+            started: Instant::now(),
+            at_line_start: true,
+        }
+    }
+}
+impl Codec for Timestamp {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(data.len() + 16);
+        for &byte in data {
+            if self.at_line_start {
+                let stamp = format!("[{:>10.3}] ", self.started.elapsed().as_secs_f64());
+                decoded.extend_from_slice(stamp.as_bytes());
+                self.at_line_start = false;
+            }
+            decoded.push(byte);
+            if byte == b'\n' {
+                self.at_line_start = true;
+            }
+        }
+        decoded
+    }
+}
+
+/// Removes the ANSI escape sequences from the received data, whichever read
+/// they are split across.
+#[derive(Default)]
+struct StripAnsi {
+    state: AnsiState,
+}
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum AnsiState {
+    #[default]
+    Text,
+    /// After `ESC`.
+    Escape,
+    /// In a control sequence (`ESC [`), until its final byte.
+    Csi,
+}
+impl Codec for StripAnsi {
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (AnsiState::Text, 0x1b) => AnsiState::Escape,
+                (AnsiState::Text, _) => {
+                    decoded.push(byte);
+                    AnsiState::Text
+                }
+                (AnsiState::Escape, b'[') => AnsiState::Csi,
+                // Two byte sequences.
+                (AnsiState::Escape, _) => AnsiState::Text,
+                (AnsiState::Csi, 0x40..=0x7e) => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+            };
+        }
+        decoded
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn ansi_sequences_are_stripped_across_reads() {
+    let mut chain = CodecChain::new(&[CodecFactory::builtin("strip-ansi").unwrap()]);
+    assert_eq!(chain.decode(b"\x1b[1;3"), b"");
+    assert_eq!(chain.decode(b"2mOK\x1b[0m\r\n"), b"OK\r\n");
+    // Sent as is.
+    assert_eq!(chain.encode(b"\x1b[A"), b"\x1b[A");
+}
+
+#[test]
+fn lines_are_timestamped() {
+    let mut chain = CodecChain::new(&[CodecFactory::builtin("timestamp").unwrap()]);
+    let decoded = String::from_utf8(chain.decode(b"one\ntw")).unwrap();
+    let lines: Vec<_> = decoded.split('\n').collect();
+    assert!(lines[0].starts_with('[') && lines[0].ends_with("] one"));
+    assert!(lines[1].ends_with("] tw"));
+    // The line goes on without a new timestamp.
+    assert_eq!(chain.decode(b"o\n"), b"o\n");
+    assert_eq!(CodecFactory::builtin("defmt"), None);
+}
@@ -0,0 +1,1475 @@
+//! Configuration file of `bootcom`.
+//!
+//! Settings which are too cumbersome for the command line are read from a TOML
+//! file, by default `bootcom/config.toml` in the user configuration directory
+//! (`$XDG_CONFIG_HOME` or `~/.config` on Unix, `%APPDATA%` on Windows). All
+//! the sections and keys are optional. The file is reloaded when it changes
+//! while `bootcom` runs; the quirks, the straps and the U-Boot script only take
+//! effect at the next connection, and the access rules and the profiles at the
+//! next start:
+//!
+//! ```toml
+//! [progress]
+//! glyphs = "ascii"            # or "unicode", "auto" by default
+//! bar_template = "{bar:40} {bytes}/{total_bytes}"
+//! progress_chars = "#>-"
+//! spinner_template = "{spinner} {msg}"
+//! tick_strings = ["-", "\\", "|", "/", " "]
+//!
+//! # The console output expected after a kernel push, stage by stage. Each
+//! # timeout (in seconds) runs from the end of the previous stage.
+//! [[expect]]
+//! pattern = "Booting"
+//! timeout = 5
+//! [[expect]]
+//! pattern = "login:"
+//! timeout = 60
+//!
+//! # The workarounds for the boot ROM of the board, applied in order:
+//! # "dtr-toggle", "wake-byte=<hex byte>" and "cr-crlf".
+//! quirks = ["dtr-toggle", "wake-byte=0d"]
+//!
+//! # The codecs applied in order to the console output, see the `codec`
+//! # module for the built-in ones.
+//! codecs = ["strip-ansi", "timestamp"]
+//!
+//! # The binary blobs dumped on the console between two markers, saved to
+//! # files in `directory` (the current directory by default). The encoding
+//! # is "base64" or "hex".
+//! [[capture]]
+//! start = "-----BEGIN CRASH-----"
+//! end = "-----END CRASH-----"
+//! encoding = "base64"
+//! directory = "crashes"
+//!
+//! # The tokens of the clients of the console served on the sockets passed by
+//! # systemd, with what they may do: "view" the console, "type" on it, choose
+//! # the image to "push" and change the line "settings". Anyone who can connect
+//! # may do everything when there are none.
+//! [[access]]
+//! token = "team-a-3f9c1e"
+//! allow = ["view", "type", "push"]
+//! [[access]]
+//! token = "watchers-77d2"
+//! allow = ["view"]
+//!
+//! # The boards of the inventory, see the `boards` module.
+//! [[board]]
+//! name = "rpi4-a"
+//! profile = "rpi4"
+//! image = "target/kernel8.img"
+//! [board.port]
+//! path = "/dev/ttyUSB0"
+//! vid = 1027
+//! pid = 24577
+//!
+//! # The connection profiles picked with `--profile`, see the `profiles`
+//! # module.
+//! [profile.rpi4]
+//! baud_rate = 921600
+//! kernel_image = "target/kernel8.img"
+//! reset_command = "uhubctl -l 1-1 -p 2 -a cycle"
+//! [profile.rpi4.port]
+//! vid = 0x0403
+//! pid = 0x6001
+//!
+//! # The rules classifying the console lines by severity ("warning" or
+//! # "error"), applied in order to the lines containing their pattern,
+//! # whatever the case. The built-in ones of the `severity` module when
+//! # there are none.
+//! [[severity]]
+//! level = "warning"
+//! pattern = "WARNING:"
+//! [[severity]]
+//! level = "error"
+//! pattern = "Synchronous Abort"
+//!
+//! # The external capture tools run in sync with the phases of the boot:
+//! # "trigger" (from the kernel image request until the transfer),
+//! # "transfer", and "boot" (until the boot stages are all reached). The
+//! # `command` starts capturing at the start of the `from` phase, to the file
+//! # given as `{file}`, named after the instrument in `directory` (the
+//! # current directory by default). It is stopped at the end of the `until`
+//! # phase (`from` by default) with the `stop` command, or terminated.
+//! [[instrument]]
+//! name = "logic"
+//! command = "sigrok-cli -d fx2lafw -c samplerate=1m --continuous -o {file}"
+//! from = "trigger"
+//! until = "transfer"
+//! directory = "captures"
+//! extension = "sr"
+//! [[instrument]]
+//! name = "power"
+//! command = "./power-meter.py --output {file}"
+//! stop = "kill -INT $BOOTCOM_CAPTURE_PID"
+//! from = "boot"
+//! extension = "csv"
+//!
+//! # The boot mode strapping pins driven before the port is opened, through
+//! # the GPIO lines of USB adapters (Linux only): `pin` is a CBUS pin of the
+//! # FTDI chips ("cbus0" to "cbus3", configured as GPIOs in their EEPROM) or
+//! # "<label of the GPIO chip>:<line>". The pin is held at its `level` for
+//! # `hold` milliseconds, or until the session ends when there is no `hold`.
+//! # The straps with a `profile` only apply to the boards of the inventory with
+//! # that profile.
+//! [[strap]]
+//! profile = "stm32mp1"
+//! pin = "cbus0"
+//! level = "low"
+//! hold = 500
+//!
+//! # The external tools flashing the boards found waiting in a ROM loader
+//! # ("esp" for the Espressif ones, "stm32" for the system bootloader of the
+//! # STM32), run with the port closed, `{port}` and `{image}` standing for
+//! # the port and the kernel image. Guidance is shown for the others.
+//! [[flasher]]
+//! loader = "esp"
+//! command = "esptool.py --port {port} write_flash 0x10000 {image}"
+//!
+//! # The commands typed at the prompt of a stock U-Boot once its banner is
+//! # seen, the autoboot countdown being interrupted. The kernel image is sent
+//! # after the load command ("loadx" for XMODEM, "loady" for YMODEM).
+//! [uboot]
+//! prompt = "=> "              # the default
+//! commands = [
+//!     "setenv bootargs console=ttyS0,115200",
+//!     "loady ${loadaddr}",
+//!     "bootm ${loadaddr}",
+//! ]
+//!
+//! # The pre-shared AES-256-GCM keys encrypting the transfers of the kernel
+//! # image, given in hex as the `key` or in the `key_file`. The keys with a
+//! # `profile` only apply to the boards of the inventory with that profile,
+//! # taking precedence over the one without.
+//! [[encryption]]
+//! key_file = "lab.key"
+//! [[encryption]]
+//! profile = "rpi4"
+//! key = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+//!
+//! # The logical streams the device multiplexes on the console, each run of
+//! # output being tagged with `SOH` (0x01) and the `channel` byte of its stream,
+//! # and the console with `SOH` and 0. The streams are shown in their `color`
+//! # ("black", "red", "green", "yellow", "blue", "magenta", "cyan" or "white"),
+//! # their lines prefixed with their `name`, or appended to their `file`.
+//! [[stream]]
+//! channel = 0x54              # `T`
+//! name = "test"
+//! color = "green"
+//! [[stream]]
+//! channel = 2
+//! name = "trace"
+//! file = "trace.log"
+//!
+//! # The comparison of the console logs by `bootcom diff`: the lines
+//! # containing one of the `ignore` strings are left out, and `context`
+//! # unchanged lines are shown around the changes.
+//! [diff]
+//! ignore = ["random: crng", "Memory:"]
+//! context = 5
+//!
+//! # The usage statistics kept in a local file for `bootcom stats`, never
+//! # sent anywhere, see the `usage` module. Off by default.
+//! [usage]
+//! stats = true
+//!
+//! # The catalog file translating the prompts, see the `messages` module,
+//! # relative to the directory of the configuration file.
+//! [messages]
+//! catalog = "messages-fr.toml"
+//! ```
+//!
+//! **Example**
+//! ```
+//! use bootcom::{config, progress::Glyphs};
+//!
+//! let config = config::parse("[progress]\nglyphs = \"ascii\"").unwrap();
+//! assert_eq!(config.progress.glyphs, Glyphs::Ascii);
+//! ```
+
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::PathBuf, time::Duration};
+
+use toml::{value::Table, Value};
+
+use crate::boards::Board;
+use crate::codec::CodecFactory;
+use crate::diff::DiffOptions;
+use crate::profiles::{PortRule, Profile};
+use crate::progress::{Glyphs, ProgressTheme};
+use crate::resume;
+use crate::settings::{
+    AccessRule, BlobEncoding, CaptureRule, Expectation, Flasher, Instrument, Permission, Phase,
+    Quirk, RomLoader, Strap, Stream, TransferKey, UbootScript,
+};
+use crate::severity::{Severity, SeverityRule};
+use crate::signing;
+use crate::utils::streams::COLORS;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The content of a configuration file.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Config {
+    /// The `[progress]` section.
+    pub progress: ProgressTheme,
+    /// The `[[expect]]` stages, in order.
+    pub expectations: Vec<Expectation>,
+    /// The `quirks`, in order.
+    pub quirks: Vec<Quirk>,
+    /// The `codecs`, in order.
+    pub codecs: Vec<CodecFactory>,
+    /// The `[[capture]]` rules.
+    pub captures: Vec<CaptureRule>,
+    /// The `[[access]]` rules.
+    pub access: Vec<AccessRule>,
+    /// The `[[board]]` inventory.
+    pub boards: Vec<Board>,
+    /// The `[profile.<name>]` connection profiles, by name.
+    pub profiles: BTreeMap<String, Profile>,
+    /// The `[[instrument]]` capture tools.
+    pub instruments: Vec<Instrument>,
+    /// The `[[severity]]` rules, in order.
+    pub severities: Vec<SeverityRule>,
+    /// The `[[strap]]` pins.
+    pub straps: Vec<Strap>,
+    /// The `[[flasher]]` tools.
+    pub flashers: Vec<Flasher>,
+    /// The `[[encryption]]` keys.
+    pub encryption: Vec<TransferKey>,
+    /// The `[[stream]]` channels.
+    pub streams: Vec<Stream>,
+    /// The `[uboot]` section.
+    pub uboot: Option<UbootScript>,
+    /// The `[diff]` section.
+    pub diff: DiffOptions,
+    /// The `usage.stats` key.
+    pub usage_stats: bool,
+    /// The `messages.catalog` key.
+    pub message_catalog: Option<String>,
+}
+
+/// The path of the default configuration file, if the user configuration
+/// directory can be found.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    dir.map(|dir| dir.join("bootcom").join("config.toml"))
+}
+
+/// Read and parse the configuration file at `path`.
+pub fn load(path: &std::path::Path) -> Result<Config, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the `text` of a configuration file.
+pub fn parse(text: &str) -> Result<Config, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut config = Config::default();
+    if let Some(progress) = section(&root, "progress")? {
+        config.progress = progress_theme(progress)?;
+    }
+    config.expectations = expectations(&root)?;
+    config.quirks = quirks(&root)?;
+    config.codecs = codecs(&root)?;
+    config.captures = captures(&root)?;
+    config.access = access(&root)?;
+    config.boards = boards(&root)?;
+    config.profiles = profiles(&root)?;
+    config.instruments = instruments(&root)?;
+    config.severities = severities(&root)?;
+    config.straps = straps(&root)?;
+    config.flashers = flashers(&root)?;
+    config.encryption = encryption(&root)?;
+    config.streams = streams(&root)?;
+    if let Some(uboot) = section(&root, "uboot")? {
+        config.uboot = Some(uboot_script(uboot)?);
+    }
+    if let Some(diff) = section(&root, "diff")? {
+        config.diff = diff_options(diff)?;
+    }
+    if let Some(usage) = section(&root, "usage")? {
+        config.usage_stats = match usage.get("stats") {
+            None => false,
+            Some(Value::Boolean(stats)) => *stats,
+            Some(_) => return Err("`usage.stats` needs to be `true` or `false`".into()),
+        };
+    }
+    if let Some(messages) = section(&root, "messages")? {
+        config.message_catalog = string(messages, "messages", "catalog")?;
+    }
+    Ok(config)
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+fn section<'a>(root: &'a Table, name: &str) -> Result<Option<&'a Table>, String> {
+    match root.get(name) {
+        None => Ok(None),
+        Some(Value::Table(table)) => Ok(Some(table)),
+        Some(_) => Err(format!("`{}` needs to be a section", name)),
+    }
+}
+
+fn string(table: &Table, section: &str, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.clone())),
+        Some(_) => Err(format!("`{}.{}` needs to be a string", section, key)),
+    }
+}
+
+fn progress_theme(table: &Table) -> Result<ProgressTheme, String> {
+    let glyphs = match string(table, "progress", "glyphs")?.as_deref() {
+        None | Some("auto") => Glyphs::Auto,
+        Some("unicode") => Glyphs::Unicode,
+        Some("ascii") => Glyphs::Ascii,
+        Some(other) => {
+            return Err(format!(
+                "`progress.glyphs` can't be `{}`, use `auto`, `unicode` or `ascii`",
+                other
+            ))
+        }
+    };
+    let tick_strings = match table.get("tick_strings") {
+        None => None,
+        Some(Value::Array(ticks)) if ticks.len() >= 2 => Some(
+            ticks
+                .iter()
+                .map(|tick| tick.as_str().map(str::to_owned))
+                .collect::<Option<Vec<_>>>()
+                .ok_or("`progress.tick_strings` needs to contain strings")?,
+        ),
+        Some(_) => return Err("`progress.tick_strings` needs at least two strings".into()),
+    };
+    Ok(ProgressTheme {
+        glyphs,
+        bar_template: string(table, "progress", "bar_template")?,
+        progress_chars: string(table, "progress", "progress_chars")?,
+        spinner_template: string(table, "progress", "spinner_template")?,
+        tick_strings,
+    })
+}
+
+fn expectations(root: &Table) -> Result<Vec<Expectation>, String> {
+    let stages = match root.get("expect") {
+        None => return Ok(vec![]),
+        Some(Value::Array(stages)) => stages,
+        Some(_) => return Err("`expect` needs to be an array of sections".into()),
+    };
+    stages
+        .iter()
+        .map(|stage| {
+            let table = stage
+                .as_table()
+                .ok_or("`expect` needs to be an array of sections")?;
+            let pattern = string(table, "expect", "pattern")?
+                .filter(|pattern| !pattern.is_empty())
+                .ok_or("`expect.pattern` needs to be a non-empty string")?;
+            let timeout = match table.get("timeout") {
+                Some(Value::Integer(seconds)) if *seconds > 0 => *seconds as u64,
+                _ => return Err("`expect.timeout` needs to be a positive number".into()),
+            };
+            Ok(Expectation {
+                pattern,
+                timeout: Duration::from_secs(timeout),
+            })
+        })
+        .collect()
+}
+
+fn quirks(root: &Table) -> Result<Vec<Quirk>, String> {
+    let names = match root.get("quirks") {
+        None => return Ok(vec![]),
+        Some(Value::Array(names)) => names,
+        Some(_) => return Err("`quirks` needs to be an array of strings".into()),
+    };
+    names
+        .iter()
+        .map(|name| match name.as_str() {
+            Some("dtr-toggle") => Ok(Quirk::DtrToggle),
+            Some("cr-crlf") => Ok(Quirk::CrToCrLf),
+            Some(name) if name.starts_with("wake-byte=") => {
+                u8::from_str_radix(&name["wake-byte=".len()..], 16)
+                    .map(Quirk::WakeByte)
+                    .map_err(|_| format!("`{}` needs a hex byte, e.g. `wake-byte=0d`", name))
+            }
+            Some(name) => Err(format!(
+                "unknown quirk `{}`, use `dtr-toggle`, `wake-byte=<hex>` or `cr-crlf`",
+                name
+            )),
+            None => Err("`quirks` needs to be an array of strings".into()),
+        })
+        .collect()
+}
+
+fn codecs(root: &Table) -> Result<Vec<CodecFactory>, String> {
+    let names = match root.get("codecs") {
+        None => return Ok(vec![]),
+        Some(Value::Array(names)) => names,
+        Some(_) => return Err("`codecs` needs to be an array of strings".into()),
+    };
+    names
+        .iter()
+        .map(|name| {
+            let name = name
+                .as_str()
+                .ok_or("`codecs` needs to be an array of strings")?;
+            CodecFactory::builtin(name)
+                .ok_or_else(|| format!("unknown codec `{}`, use `timestamp` or `strip-ansi`", name))
+        })
+        .collect()
+}
+
+fn captures(root: &Table) -> Result<Vec<CaptureRule>, String> {
+    let rules = match root.get("capture") {
+        None => return Ok(vec![]),
+        Some(Value::Array(rules)) => rules,
+        Some(_) => return Err("`capture` needs to be an array of sections".into()),
+    };
+    rules
+        .iter()
+        .map(|rule| {
+            let table = rule
+                .as_table()
+                .ok_or("`capture` needs to be an array of sections")?;
+            let marker = |key| {
+                string(table, "capture", key)?
+                    .filter(|marker| !marker.is_empty())
+                    .ok_or_else(|| format!("`capture.{}` needs to be a non-empty string", key))
+            };
+            let encoding = match string(table, "capture", "encoding")?.as_deref() {
+                None | Some("base64") => BlobEncoding::Base64,
+                Some("hex") => BlobEncoding::Hex,
+                Some(other) => {
+                    return Err(format!(
+                        "`capture.encoding` can't be `{}`, use `base64` or `hex`",
+                        other
+                    ))
+                }
+            };
+            Ok(CaptureRule {
+                start: marker("start")?,
+                end: marker("end")?,
+                encoding,
+                directory: string(table, "capture", "directory")?.unwrap_or_else(|| ".".into()),
+            })
+        })
+        .collect()
+}
+
+fn access(root: &Table) -> Result<Vec<AccessRule>, String> {
+    let rules = match root.get("access") {
+        None => return Ok(vec![]),
+        Some(Value::Array(rules)) => rules,
+        Some(_) => return Err("`access` needs to be an array of sections".into()),
+    };
+    rules
+        .iter()
+        .map(|rule| {
+            let table = rule
+                .as_table()
+                .ok_or("`access` needs to be an array of sections")?;
+            let token = string(table, "access", "token")?
+                .filter(|token| !token.is_empty())
+                .ok_or("`access.token` needs to be a non-empty string")?;
+            let names = match table.get("allow") {
+                Some(Value::Array(names)) => names,
+                _ => return Err("`access.allow` needs to be an array of strings".into()),
+            };
+            let permissions = names
+                .iter()
+                .map(|name| match name.as_str() {
+                    Some("view") => Ok(Permission::View),
+                    Some("type") => Ok(Permission::Type),
+                    Some("push") => Ok(Permission::Push),
+                    Some("settings") => Ok(Permission::Settings),
+                    Some(name) => Err(format!(
+                        "unknown permission `{}`, use `view`, `type`, `push` or `settings`",
+                        name
+                    )),
+                    None => Err("`access.allow` needs to be an array of strings".into()),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(AccessRule { token, permissions })
+        })
+        .collect()
+}
+
+fn boards(root: &Table) -> Result<Vec<Board>, String> {
+    let boards = match root.get("board") {
+        None => return Ok(vec![]),
+        Some(Value::Array(boards)) => boards,
+        Some(_) => return Err("`board` needs to be an array of sections".into()),
+    };
+    let mut inventory: Vec<Board> = vec![];
+    for board in boards {
+        let table = board
+            .as_table()
+            .ok_or("`board` needs to be an array of sections")?;
+        let name = string(table, "board", "name")?
+            .filter(|name| !name.is_empty())
+            .ok_or("`board.name` needs to be a non-empty string")?;
+        // The claims of the boards are files named after them.
+        if name.contains(['/', '\\']) {
+            return Err(format!(
+                "`board.name` can't contain slashes, `{}` does",
+                name
+            ));
+        }
+        if inventory.iter().any(|board| board.name == name) {
+            return Err(format!("there are several boards named `{}`", name));
+        }
+        let port = match table.get("port") {
+            Some(Value::Table(port)) => resume::port_identity(port, "board.port")?,
+            _ => return Err(format!("board `{}` needs a `port` section", name)),
+        };
+        inventory.push(Board {
+            name,
+            port,
+            profile: string(table, "board", "profile")?,
+            image: string(table, "board", "image")?,
+        });
+    }
+    Ok(inventory)
+}
+
+fn profiles(root: &Table) -> Result<BTreeMap<String, Profile>, String> {
+    let profiles = match section(root, "profile")? {
+        None => return Ok(BTreeMap::new()),
+        Some(profiles) => profiles,
+    };
+    profiles
+        .iter()
+        .map(|(name, profile)| {
+            let section = format!("profile.{}", name);
+            let table = profile
+                .as_table()
+                .ok_or_else(|| format!("`{}` needs to be a section", section))?;
+            let port = match table.get("port") {
+                None => None,
+                Some(Value::Table(port)) => Some(port_rule(port, &format!("{}.port", section))?),
+                Some(_) => return Err(format!("`{}.port` needs to be a section", section)),
+            };
+            let baud_rate = match table.get("baud_rate") {
+                None => None,
+                Some(Value::Integer(rate)) if *rate > 0 && *rate <= i64::from(u32::MAX) => {
+                    Some(*rate as u32)
+                }
+                Some(_) => {
+                    return Err(format!(
+                        "`{}.baud_rate` needs to be a positive number",
+                        section
+                    ))
+                }
+            };
+            let profile = Profile {
+                name: name.clone(),
+                port,
+                baud_rate,
+                kernel_image: string(table, &section, "kernel_image")?,
+                reset_command: string(table, &section, "reset_command")?,
+            };
+            Ok((name.clone(), profile))
+        })
+        .collect()
+}
+
+fn port_rule(table: &Table, section: &str) -> Result<PortRule, String> {
+    let id = |key: &str| match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(id)) if (0..=0xffff).contains(id) => Ok(Some(*id as u16)),
+        Some(_) => Err(format!("`{}.{}` needs to be a USB ID", section, key)),
+    };
+    let rule = PortRule {
+        path: string(table, section, "path")?,
+        vid: id("vid")?,
+        pid: id("pid")?,
+        serial_number: string(table, section, "serial_number")?,
+    };
+    if rule == PortRule::default() {
+        return Err(format!(
+            "`{}` needs a `path`, `vid`, `pid` or `serial_number`",
+            section
+        ));
+    }
+    Ok(rule)
+}
+
+fn instruments(root: &Table) -> Result<Vec<Instrument>, String> {
+    let instruments = match root.get("instrument") {
+        None => return Ok(vec![]),
+        Some(Value::Array(instruments)) => instruments,
+        Some(_) => return Err("`instrument` needs to be an array of sections".into()),
+    };
+    let phase = |table: &Table, key: &str| match string(table, "instrument", key)?.as_deref() {
+        None => Ok(None),
+        Some("trigger") => Ok(Some(Phase::Trigger)),
+        Some("transfer") => Ok(Some(Phase::Transfer)),
+        Some("boot") => Ok(Some(Phase::Boot)),
+        Some(other) => Err(format!(
+            "`instrument.{}` can't be `{}`, use `trigger`, `transfer` or `boot`",
+            key, other
+        )),
+    };
+    let mut parsed: Vec<Instrument> = vec![];
+    for instrument in instruments {
+        let table = instrument
+            .as_table()
+            .ok_or("`instrument` needs to be an array of sections")?;
+        let name = string(table, "instrument", "name")?
+            .filter(|name| !name.is_empty())
+            .ok_or("`instrument.name` needs to be a non-empty string")?;
+        // The capture files are named after them.
+        if name.contains(['/', '\\']) {
+            return Err(format!(
+                "`instrument.name` can't contain slashes, `{}` does",
+                name
+            ));
+        }
+        if parsed.iter().any(|instrument| instrument.name == name) {
+            return Err(format!("there are several instruments named `{}`", name));
+        }
+        let command = string(table, "instrument", "command")?
+            .filter(|command| !command.is_empty())
+            .ok_or("`instrument.command` needs to be a non-empty string")?;
+        let from = phase(table, "from")?.unwrap_or(Phase::Trigger);
+        let until = phase(table, "until")?.unwrap_or(from);
+        if until < from {
+            return Err(format!(
+                "instrument `{}` can't stop at the end of `{}`, before `{}` starts",
+                name, until, from
+            ));
+        }
+        parsed.push(Instrument {
+            name,
+            command,
+            stop: string(table, "instrument", "stop")?,
+            from,
+            until,
+            directory: string(table, "instrument", "directory")?.unwrap_or_else(|| ".".into()),
+            extension: string(table, "instrument", "extension")?.unwrap_or_else(|| "dat".into()),
+        });
+    }
+    Ok(parsed)
+}
+
+fn severities(root: &Table) -> Result<Vec<SeverityRule>, String> {
+    let rules = match root.get("severity") {
+        None => return Ok(vec![]),
+        Some(Value::Array(rules)) => rules,
+        Some(_) => return Err("`severity` needs to be an array of sections".into()),
+    };
+    rules
+        .iter()
+        .map(|rule| {
+            let table = rule
+                .as_table()
+                .ok_or("`severity` needs to be an array of sections")?;
+            let severity = match string(table, "severity", "level")?.as_deref() {
+                Some("warning") => Severity::Warning,
+                Some("error") => Severity::Error,
+                Some(other) => {
+                    return Err(format!(
+                        "`severity.level` can't be `{}`, use `warning` or `error`",
+                        other
+                    ))
+                }
+                None => return Err("`severity.level` needs to be a string".into()),
+            };
+            let pattern = string(table, "severity", "pattern")?
+                .filter(|pattern| !pattern.is_empty())
+                .ok_or("`severity.pattern` needs to be a non-empty string")?;
+            Ok(SeverityRule { severity, pattern })
+        })
+        .collect()
+}
+
+fn straps(root: &Table) -> Result<Vec<Strap>, String> {
+    let straps = match root.get("strap") {
+        None => return Ok(vec![]),
+        Some(Value::Array(straps)) => straps,
+        Some(_) => return Err("`strap` needs to be an array of sections".into()),
+    };
+    straps
+        .iter()
+        .map(|strap| {
+            let table = strap
+                .as_table()
+                .ok_or("`strap` needs to be an array of sections")?;
+            let pin = string(table, "strap", "pin")?.ok_or("`strap.pin` needs to be a string")?;
+            let invalid = || {
+                format!(
+                    "`strap.pin` can't be `{}`, use `cbus<n>` or `<GPIO chip label>:<line>`",
+                    pin
+                )
+            };
+            let (chip, line) = match pin.strip_prefix("cbus") {
+                Some(line) => ("ftdi-cbus", line),
+                None => pin.rsplit_once(':').ok_or_else(invalid)?,
+            };
+            let line = line.parse().map_err(|_| invalid())?;
+            if chip.is_empty() {
+                return Err(invalid());
+            }
+            let high = match string(table, "strap", "level")?.as_deref() {
+                Some("low") => false,
+                Some("high") => true,
+                Some(other) => {
+                    return Err(format!(
+                        "`strap.level` can't be `{}`, use `low` or `high`",
+                        other
+                    ))
+                }
+                None => return Err("`strap.level` needs to be a string".into()),
+            };
+            let hold = match table.get("hold") {
+                None => None,
+                Some(Value::Integer(ms)) if *ms > 0 => Some(Duration::from_millis(*ms as u64)),
+                Some(_) => return Err("`strap.hold` needs to be a positive number".into()),
+            };
+            Ok(Strap {
+                profile: string(table, "strap", "profile")?,
+                chip: chip.to_owned(),
+                line,
+                high,
+                hold,
+            })
+        })
+        .collect()
+}
+
+fn flashers(root: &Table) -> Result<Vec<Flasher>, String> {
+    let flashers = match root.get("flasher") {
+        None => return Ok(vec![]),
+        Some(Value::Array(flashers)) => flashers,
+        Some(_) => return Err("`flasher` needs to be an array of sections".into()),
+    };
+    flashers
+        .iter()
+        .map(|flasher| {
+            let table = flasher
+                .as_table()
+                .ok_or("`flasher` needs to be an array of sections")?;
+            let loader = match string(table, "flasher", "loader")?.as_deref() {
+                Some("esp") => RomLoader::Esp,
+                Some("stm32") => RomLoader::Stm32,
+                Some(other) => {
+                    return Err(format!(
+                        "`flasher.loader` can't be `{}`, use `esp` or `stm32`",
+                        other
+                    ))
+                }
+                None => return Err("`flasher.loader` needs to be a string".into()),
+            };
+            let command = string(table, "flasher", "command")?
+                .filter(|command| !command.is_empty())
+                .ok_or("`flasher.command` needs to be a non-empty string")?;
+            Ok(Flasher { loader, command })
+        })
+        .collect()
+}
+
+fn encryption(root: &Table) -> Result<Vec<TransferKey>, String> {
+    let keys = match root.get("encryption") {
+        None => return Ok(vec![]),
+        Some(Value::Array(keys)) => keys,
+        Some(_) => return Err("`encryption` needs to be an array of sections".into()),
+    };
+    let mut parsed: Vec<TransferKey> = vec![];
+    for key in keys {
+        let table = key
+            .as_table()
+            .ok_or("`encryption` needs to be an array of sections")?;
+        let hex = match (
+            string(table, "encryption", "key")?,
+            string(table, "encryption", "key_file")?,
+        ) {
+            (Some(key), None) => key,
+            (None, Some(path)) => fs::read_to_string(&path)
+                .map_err(|e| format!("`encryption.key_file` {}: {}", path, e))?,
+            _ => return Err("`encryption` needs either a `key` or a `key_file`".into()),
+        };
+        let key = signing::from_hex(hex.trim())
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or("`encryption` keys need to be 32 bytes, in hex")?;
+        let profile = string(table, "encryption", "profile")?;
+        if parsed.iter().any(|key| key.profile == profile) {
+            return Err(match profile {
+                Some(profile) => format!("there are several keys for the profile `{}`", profile),
+                None => "there are several keys for all the boards".into(),
+            });
+        }
+        parsed.push(TransferKey { profile, key });
+    }
+    Ok(parsed)
+}
+
+fn streams(root: &Table) -> Result<Vec<Stream>, String> {
+    let streams = match root.get("stream") {
+        None => return Ok(vec![]),
+        Some(Value::Array(streams)) => streams,
+        Some(_) => return Err("`stream` needs to be an array of sections".into()),
+    };
+    let mut parsed: Vec<Stream> = vec![];
+    for stream in streams {
+        let table = stream
+            .as_table()
+            .ok_or("`stream` needs to be an array of sections")?;
+        let channel = match table.get("channel") {
+            Some(Value::Integer(channel)) if (1..=255).contains(channel) => *channel as u8,
+            _ => return Err("`stream.channel` needs to be a number from 1 to 255".into()),
+        };
+        if parsed.iter().any(|stream| stream.channel == channel) {
+            return Err(format!(
+                "there are several streams on the channel {}",
+                channel
+            ));
+        }
+        let name = string(table, "stream", "name")?
+            .filter(|name| !name.is_empty())
+            .ok_or("`stream.name` needs to be a non-empty string")?;
+        let color = string(table, "stream", "color")?;
+        if let Some(color) = color.as_deref().filter(|color| !COLORS.contains(color)) {
+            return Err(format!(
+                "`stream.color` can't be `{}`, use one of {}",
+                color,
+                COLORS.join(", ")
+            ));
+        }
+        parsed.push(Stream {
+            channel,
+            name,
+            color,
+            file: string(table, "stream", "file")?,
+        });
+    }
+    Ok(parsed)
+}
+
+fn uboot_script(table: &Table) -> Result<UbootScript, String> {
+    let prompt = string(table, "uboot", "prompt")?.unwrap_or_else(|| "=> ".into());
+    if prompt.is_empty() {
+        return Err("`uboot.prompt` can't be empty".into());
+    }
+    let commands: Vec<String> = match table.get("commands") {
+        Some(Value::Array(commands)) if !commands.is_empty() => commands
+            .iter()
+            .map(|command| command.as_str().filter(|command| !command.is_empty()))
+            .map(|command| command.map(str::to_owned))
+            .collect::<Option<_>>(),
+        _ => None,
+    }
+    .ok_or("`uboot.commands` needs to be an array of non-empty strings")?;
+    let mut loads = 0;
+    for command in &commands {
+        match command.split_whitespace().next() {
+            Some("loadx") | Some("loady") => loads += 1,
+            Some(load @ "loadb") | Some(load @ "loads") | Some(load @ "loadm") => {
+                return Err(format!(
+                    "`uboot.commands` can't use `{}`, use `loadx` or `loady`",
+                    load
+                ))
+            }
+            _ => (),
+        }
+    }
+    if loads > 1 {
+        return Err("`uboot.commands` can only have one load command".into());
+    }
+    Ok(UbootScript { prompt, commands })
+}
+
+fn diff_options(table: &Table) -> Result<DiffOptions, String> {
+    let mut options = DiffOptions::default();
+    match table.get("ignore") {
+        None => (),
+        Some(Value::Array(noise)) => {
+            options.ignore = noise
+                .iter()
+                .map(|noise| noise.as_str().filter(|noise| !noise.is_empty()))
+                .map(|noise| noise.map(str::to_owned))
+                .collect::<Option<_>>()
+                .ok_or("`diff.ignore` needs to be an array of non-empty strings")?;
+        }
+        Some(_) => return Err("`diff.ignore` needs to be an array of non-empty strings".into()),
+    }
+    match table.get("context") {
+        None => (),
+        Some(Value::Integer(lines)) if *lines >= 0 => options.context = *lines as usize,
+        Some(_) => return Err("`diff.context` needs to be a positive number".into()),
+    }
+    Ok(options)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn progress_section() {
+    let config = parse(
+        r##"
+        [progress]
+        glyphs = "unicode"
+        progress_chars = "#>-"
+        tick_strings = ["-", "|"]
+        "##,
+    )
+    .unwrap();
+    assert_eq!(config.progress.glyphs, Glyphs::Unicode);
+    assert_eq!(config.progress.progress_chars.unwrap(), "#>-");
+    assert_eq!(config.progress.tick_strings.unwrap(), vec!["-", "|"]);
+    assert_eq!(config.progress.bar_template, None);
+    assert_eq!(parse("").unwrap(), Config::default());
+}
+
+#[test]
+fn expect_sections() {
+    let config = parse(
+        r##"
+        [[expect]]
+        pattern = "Booting"
+        timeout = 5
+        [[expect]]
+        pattern = "login:"
+        timeout = 60
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.expectations,
+        vec![
+            Expectation {
+                pattern: "Booting".into(),
+                timeout: Duration::from_secs(5),
+            },
+            Expectation {
+                pattern: "login:".into(),
+                timeout: Duration::from_secs(60),
+            },
+        ]
+    );
+    assert!(parse("[[expect]]\npattern = \"x\"")
+        .unwrap_err()
+        .contains("expect.timeout"));
+    assert!(parse("[[expect]]\ntimeout = 1")
+        .unwrap_err()
+        .contains("expect.pattern"));
+}
+
+#[test]
+fn quirks_list() {
+    let config = parse("quirks = [\"dtr-toggle\", \"wake-byte=0d\", \"cr-crlf\"]").unwrap();
+    assert_eq!(
+        config.quirks,
+        vec![Quirk::DtrToggle, Quirk::WakeByte(0x0d), Quirk::CrToCrLf]
+    );
+    assert!(parse("quirks = [\"wake-byte=zz\"]")
+        .unwrap_err()
+        .contains("hex byte"));
+    assert!(parse("quirks = [\"reboot\"]")
+        .unwrap_err()
+        .contains("unknown quirk"));
+}
+
+#[test]
+fn codecs_list() {
+    let config = parse("codecs = [\"strip-ansi\", \"timestamp\"]").unwrap();
+    let names: Vec<_> = config.codecs.iter().map(CodecFactory::name).collect();
+    assert_eq!(names, vec!["strip-ansi", "timestamp"]);
+    assert!(parse("codecs = [\"defmt\"]")
+        .unwrap_err()
+        .contains("unknown codec"));
+}
+
+#[test]
+fn invalid_values() {
+    assert!(parse("[progress]\nglyphs = \"emoji\"")
+        .unwrap_err()
+        .contains("progress.glyphs"));
+    assert!(parse("[progress]\ntick_strings = [\"-\"]").is_err());
+    assert!(parse("progress = 1").unwrap_err().contains("section"));
+    assert!(parse("[progress").is_err());
+}
+
+#[test]
+fn capture_rules() {
+    let config = parse(
+        r##"
+        [[capture]]
+        start = "<<"
+        end = ">>"
+        [[capture]]
+        start = "BEGIN"
+        end = "END"
+        encoding = "hex"
+        directory = "dumps"
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.captures,
+        vec![
+            CaptureRule {
+                start: "<<".into(),
+                end: ">>".into(),
+                encoding: BlobEncoding::Base64,
+                directory: ".".into(),
+            },
+            CaptureRule {
+                start: "BEGIN".into(),
+                end: "END".into(),
+                encoding: BlobEncoding::Hex,
+                directory: "dumps".into(),
+            },
+        ]
+    );
+    assert!(parse("[[capture]]\nstart = \"<<\"")
+        .unwrap_err()
+        .contains("capture.end"));
+    assert!(
+        parse("[[capture]]\nstart = \"<\"\nend = \">\"\nencoding = \"uu\"")
+            .unwrap_err()
+            .contains("use `base64` or `hex`")
+    );
+}
+
+#[test]
+fn access_rules() {
+    let config = parse(
+        r##"
+        [[access]]
+        token = "team-a"
+        allow = ["view", "type", "push"]
+        [[access]]
+        token = "watchers"
+        allow = ["view"]
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.access,
+        vec![
+            AccessRule {
+                token: "team-a".into(),
+                permissions: vec![Permission::View, Permission::Type, Permission::Push],
+            },
+            AccessRule {
+                token: "watchers".into(),
+                permissions: vec![Permission::View],
+            },
+        ]
+    );
+    assert!(parse("[[access]]\nallow = [\"view\"]")
+        .unwrap_err()
+        .contains("access.token"));
+    assert!(parse("[[access]]\ntoken = \"t\"\nallow = [\"admin\"]")
+        .unwrap_err()
+        .contains("unknown permission"));
+}
+
+#[test]
+fn board_inventory() {
+    let config = parse(
+        r##"
+        [[board]]
+        name = "rpi4-a"
+        profile = "rpi4"
+        [board.port]
+        path = "/dev/ttyUSB0"
+        vid = 1027
+        pid = 24577
+        [[board]]
+        name = "custom"
+        port = { path = "/dev/ttyACM0" }
+        "##,
+    )
+    .unwrap();
+    let names: Vec<_> = config.boards.iter().map(|b| b.name.as_str()).collect();
+    assert_eq!(names, vec!["rpi4-a", "custom"]);
+    assert_eq!(config.boards[0].profile.as_deref(), Some("rpi4"));
+    assert_eq!(config.boards[0].port.usb.as_ref().unwrap().pid, 24577);
+    assert_eq!(config.boards[1].port.path, "/dev/ttyACM0");
+    assert!(parse("[[board]]\nname = \"a\"")
+        .unwrap_err()
+        .contains("`port` section"));
+    assert!(
+        parse("[[board]]\nname = \"a\"\nport = { path = \"x\", vid = 1 }")
+            .unwrap_err()
+            .contains("board.port.vid")
+    );
+    assert!(parse(
+        "[[board]]\nname = \"a\"\nport = { path = \"x\" }\n\
+         [[board]]\nname = \"a\"\nport = { path = \"y\" }"
+    )
+    .unwrap_err()
+    .contains("several boards"));
+    assert!(
+        parse("[[board]]\nname = \"lab/a\"\nport = { path = \"x\" }")
+            .unwrap_err()
+            .contains("slashes")
+    );
+}
+
+#[test]
+fn profile_sections() {
+    let config = parse(
+        r##"
+        [profile.rpi4]
+        baud_rate = 921600
+        kernel_image = "target/kernel8.img"
+        reset_command = "uhubctl -l 1-1 -p 2 -a cycle"
+        [profile.rpi4.port]
+        vid = 0x0403
+        pid = 0x6001
+        [profile.custom]
+        port = { path = "/dev/ttyACM0" }
+        "##,
+    )
+    .unwrap();
+    let names: Vec<_> = config.profiles.keys().map(String::as_str).collect();
+    assert_eq!(names, vec!["custom", "rpi4"]);
+    let rpi4 = &config.profiles["rpi4"];
+    assert_eq!(rpi4.name, "rpi4");
+    assert_eq!(rpi4.baud_rate, Some(921_600));
+    assert_eq!(rpi4.kernel_image.as_deref(), Some("target/kernel8.img"));
+    assert_eq!(
+        rpi4.reset_command.as_deref(),
+        Some("uhubctl -l 1-1 -p 2 -a cycle")
+    );
+    let port = rpi4.port.as_ref().unwrap();
+    assert_eq!((port.vid, port.pid), (Some(0x0403), Some(0x6001)));
+    assert_eq!(
+        config.profiles["custom"]
+            .port
+            .as_ref()
+            .unwrap()
+            .path
+            .as_deref(),
+        Some("/dev/ttyACM0")
+    );
+    assert_eq!(config.profiles["custom"].baud_rate, None);
+
+    assert!(parse(
+        "[profile.a]
+baud_rate = 0"
+    )
+    .unwrap_err()
+    .contains("profile.a.baud_rate"));
+    assert!(parse(
+        "[profile.a]
+port = { vid = 70000 }"
+    )
+    .unwrap_err()
+    .contains("profile.a.port.vid"));
+    assert!(parse("[profile.a.port]")
+        .unwrap_err()
+        .contains("needs a `path`"));
+    assert!(parse("profile = 1").unwrap_err().contains("`profile`"));
+}
+
+#[test]
+fn instrument_sections() {
+    let config = parse(
+        r##"
+        [[instrument]]
+        name = "logic"
+        command = "sigrok-cli --continuous -o {file}"
+        until = "transfer"
+        extension = "sr"
+        [[instrument]]
+        name = "power"
+        command = "./power-meter.py --output {file}"
+        stop = "kill -INT $BOOTCOM_CAPTURE_PID"
+        from = "boot"
+        "##,
+    )
+    .unwrap();
+    let logic = &config.instruments[0];
+    assert_eq!((logic.from, logic.until), (Phase::Trigger, Phase::Transfer));
+    assert_eq!(logic.directory, ".");
+    assert_eq!(logic.extension, "sr");
+    let power = &config.instruments[1];
+    assert_eq!((power.from, power.until), (Phase::Boot, Phase::Boot));
+    assert_eq!(
+        power.stop.as_deref(),
+        Some("kill -INT $BOOTCOM_CAPTURE_PID")
+    );
+    assert!(parse(
+        "[[instrument]]\nname = \"a\"\ncommand = \"x\"\nfrom = \"boot\"\nuntil = \"trigger\""
+    )
+    .unwrap_err()
+    .contains("before `boot` starts"));
+    assert!(parse("[[instrument]]\nname = \"a\"")
+        .unwrap_err()
+        .contains("instrument.command"));
+}
+
+#[test]
+fn severity_sections() {
+    let config = parse(
+        r##"
+        [[severity]]
+        level = "warning"
+        pattern = "WARNING:"
+        [[severity]]
+        level = "error"
+        pattern = "Synchronous Abort"
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.severities,
+        vec![
+            SeverityRule::new(Severity::Warning, "WARNING:"),
+            SeverityRule::new(Severity::Error, "Synchronous Abort"),
+        ]
+    );
+    assert!(parse("[[severity]]\nlevel = \"fatal\"\npattern = \"x\"")
+        .unwrap_err()
+        .contains("`fatal`"));
+    assert!(parse("[[severity]]\nlevel = \"error\"")
+        .unwrap_err()
+        .contains("severity.pattern"));
+}
+
+#[test]
+fn strap_sections() {
+    let config = parse(
+        r##"
+        [[strap]]
+        profile = "stm32mp1"
+        pin = "cbus0"
+        level = "low"
+        hold = 500
+        [[strap]]
+        pin = "cp210x:2"
+        level = "high"
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.straps,
+        vec![
+            Strap {
+                profile: Some("stm32mp1".into()),
+                chip: "ftdi-cbus".into(),
+                line: 0,
+                high: false,
+                hold: Some(Duration::from_millis(500)),
+            },
+            Strap {
+                profile: None,
+                chip: "cp210x".into(),
+                line: 2,
+                high: true,
+                hold: None,
+            },
+        ]
+    );
+    assert!(parse("[[strap]]\npin = \"gpio\"\nlevel = \"low\"")
+        .unwrap_err()
+        .contains("`gpio`"));
+    assert!(parse("[[strap]]\npin = \"cbus1\"")
+        .unwrap_err()
+        .contains("strap.level"));
+}
+
+#[test]
+fn flasher_sections() {
+    let config = parse(
+        r##"
+        [[flasher]]
+        loader = "esp"
+        command = "esptool.py --port {port} write_flash 0x10000 {image}"
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.flashers,
+        vec![Flasher {
+            loader: RomLoader::Esp,
+            command: "esptool.py --port {port} write_flash 0x10000 {image}".into(),
+        }]
+    );
+    assert!(
+        parse("[[flasher]]\nloader = \"avr\"\ncommand = \"avrdude\"")
+            .unwrap_err()
+            .contains("`avr`")
+    );
+    assert!(parse("[[flasher]]\nloader = \"stm32\"")
+        .unwrap_err()
+        .contains("flasher.command"));
+}
+
+#[test]
+fn encryption_sections() {
+    let path = std::env::temp_dir().join(format!("bootcom-{}.key", std::process::id()));
+    fs::write(&path, format!("{}\n", "5a".repeat(32))).unwrap();
+    let config = parse(&format!(
+        r##"
+        [[encryption]]
+        key_file = "{}"
+        [[encryption]]
+        profile = "rpi4"
+        key = "{}"
+        "##,
+        path.display(),
+        "01".repeat(32)
+    ))
+    .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        config.encryption,
+        vec![
+            TransferKey {
+                profile: None,
+                key: [0x5a; 32],
+            },
+            TransferKey {
+                profile: Some("rpi4".into()),
+                key: [1; 32],
+            },
+        ]
+    );
+    assert!(parse("[[encryption]]\nkey = \"0102\"")
+        .unwrap_err()
+        .contains("32 bytes"));
+    assert!(parse("[[encryption]]\nprofile = \"rpi4\"")
+        .unwrap_err()
+        .contains("`key_file`"));
+    let twice = format!(
+        "[[encryption]]\nkey = \"{0}\"\n[[encryption]]\nkey = \"{0}\"",
+        "01".repeat(32)
+    );
+    assert!(parse(&twice).unwrap_err().contains("several keys"));
+}
+
+#[test]
+fn stream_sections() {
+    let config = parse(
+        r##"
+        [[stream]]
+        channel = 0x54
+        name = "test"
+        color = "green"
+        [[stream]]
+        channel = 2
+        name = "trace"
+        file = "trace.log"
+        "##,
+    )
+    .unwrap();
+    assert_eq!(
+        config.streams,
+        vec![
+            Stream {
+                channel: b'T',
+                name: "test".into(),
+                color: Some("green".into()),
+                file: None,
+            },
+            Stream {
+                channel: 2,
+                name: "trace".into(),
+                color: None,
+                file: Some("trace.log".into()),
+            },
+        ]
+    );
+    assert!(parse(
+        "[[stream]]
+channel = 0
+name = \"log\""
+    )
+    .unwrap_err()
+    .contains("1 to 255"));
+    assert!(parse(
+        "[[stream]]
+channel = 1
+name = \"log\"
+color = \"pink\""
+    )
+    .unwrap_err()
+    .contains("`pink`"));
+    assert!(parse(
+        "[[stream]]
+channel = 1
+name = \"a\"
+[[stream]]
+channel = 1
+name = \"b\""
+    )
+    .unwrap_err()
+    .contains("several streams"));
+}
+
+#[test]
+fn uboot_section() {
+    let config = parse(
+        r##"
+        [uboot]
+        commands = ["setenv bootargs console=ttyS0", "loady ${loadaddr}", "bootm"]
+        "##,
+    )
+    .unwrap();
+    let script = config.uboot.unwrap();
+    assert_eq!(script.prompt, "=> ");
+    assert_eq!(script.commands.len(), 3);
+    assert_eq!(parse("").unwrap().uboot, None);
+    assert!(parse("[uboot]\nprompt = \"U-Boot> \"")
+        .unwrap_err()
+        .contains("uboot.commands"));
+    assert!(parse("[uboot]\ncommands = [\"loadb 0x80000000\"]")
+        .unwrap_err()
+        .contains("`loadb`"));
+    assert!(parse("[uboot]\ncommands = [\"loadx\", \"loady\"]")
+        .unwrap_err()
+        .contains("one load command"));
+}
+
+#[test]
+fn diff_section() {
+    let config = parse(
+        r##"
+        [diff]
+        ignore = ["random: crng"]
+        context = 0
+        "##,
+    )
+    .unwrap();
+    assert_eq!(config.diff.ignore, vec!["random: crng"]);
+    assert_eq!(config.diff.context, 0);
+    assert_eq!(parse("[diff]").unwrap().diff, DiffOptions::default());
+    assert!(parse("[diff]\nignore = [\"\"]")
+        .unwrap_err()
+        .contains("diff.ignore"));
+}
+
+#[test]
+fn usage_section() {
+    assert!(parse("[usage]\nstats = true").unwrap().usage_stats);
+    assert!(!parse("[usage]").unwrap().usage_stats);
+    assert!(parse("[usage]\nstats = \"yes\"")
+        .unwrap_err()
+        .contains("usage.stats"));
+}
+
+#[test]
+fn messages_section() {
+    let config = parse("[messages]\ncatalog = \"messages-fr.toml\"").unwrap();
+    assert_eq!(config.message_catalog.as_deref(), Some("messages-fr.toml"));
+    assert!(parse("[messages]\ncatalog = 1")
+        .unwrap_err()
+        .contains("messages.catalog"));
+}
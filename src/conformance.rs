@@ -0,0 +1,559 @@
+//! Protocol conformance checks for bootloader authors.
+//!
+//! This module (available with the `testing` feature) drives a real device
+//! through a scripted boot protocol session and reports which parts of the
+//! protocol the bootloader implements correctly, without having to push a real
+//! kernel by hand with `bootcom`.
+//!
+//! The scripted session goes through the following steps, each reported as a
+//! separate [`Check`]:
+//!
+//!  1. **Trigger** - wait for the bootloader to send the trigger pattern.
+//!  2. **Size acknowledged** - send the size of a test payload (4 bytes, little
+//!     endian) and expect `OK` in return. A bootloader reporting the revision
+//!     of the protocol it speaks first (`OV` and the revision) is told the one
+//!     of `bootcom`, which it must speak.
+//!  3. **Payload accepted** - send the test payload and check that the
+//!     bootloader does not immediately request a new transfer.
+//!  4. **CRC** - optionally, the bootloader echoes back the CRC-32 (IEEE
+//!     802.3) of the received payload as 4 bytes, little endian. Bootloaders
+//!     not implementing this get a [`CheckResult::NotSupported`].
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{conformance, SettingsBuilder};
+//!
+//! let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
+//! let report = conformance::run(&settings, &conformance::Options::default())
+//!     .expect("could not open the serial port");
+//! println!("{}", report);
+//! ```
+//!
+//! The same session can also be repeated many times in a row with [`soak`], to
+//! qualify a new cable, adapter or baud rate before trusting it. The board is
+//! reset between the runs by a hook command, and the failures and the payloads
+//! received corrupted are counted in a [`SoakReport`].
+
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::{
+    settings::Settings,
+    transport::Transport,
+    utils::{
+        kernel::{PROTOCOL_REVISION, REVISION_REPORT},
+        open_and_setup_port, shell, Crc32, HumanSize,
+    },
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Options for the conformance session.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The trigger pattern expected from the bootloader.
+    pub trigger: Vec<u8>,
+    /// Size of the generated test payload in bytes.
+    pub payload_size: u32,
+    /// How long to wait for the bootloader to send the trigger.
+    pub trigger_timeout: Duration,
+    /// How long to wait for each response from the bootloader.
+    pub response_timeout: Duration,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            trigger: vec![3, 3, 3],
+            payload_size: 4096,
+            trigger_timeout: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The individual protocol features checked during the session.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Check {
+    Trigger,
+    SizeAcknowledged,
+    PayloadAccepted,
+    Crc,
+}
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Check::Trigger => "trigger",
+            Check::SizeAcknowledged => "size acknowledged with `OK`",
+            Check::PayloadAccepted => "payload accepted",
+            Check::Crc => "CRC-32 of the payload echoed back",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The outcome of a single check.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CheckResult {
+    Passed,
+    Failed(String),
+    /// The bootloader does not implement this optional feature.
+    NotSupported,
+    /// The check could not be run because a previous one failed.
+    Skipped,
+}
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckResult::Passed => f.write_str("passed"),
+            CheckResult::Failed(reason) => write!(f, "FAILED ({})", reason),
+            CheckResult::NotSupported => f.write_str("not supported"),
+            CheckResult::Skipped => f.write_str("skipped"),
+        }
+    }
+}
+
+/// The results of all checks, in the order they were run.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Report {
+    pub checks: Vec<(Check, CheckResult)>,
+}
+impl Report {
+    /// Returns `true` if no check failed. Optional features which are not
+    /// supported do not count as failures.
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|(_, result)| matches!(result, CheckResult::Passed | CheckResult::NotSupported))
+    }
+
+    /// The result of the given check.
+    pub fn result(&self, check: Check) -> Option<&CheckResult> {
+        self.checks
+            .iter()
+            .find(|(c, _)| *c == check)
+            .map(|(_, result)| result)
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (check, result) in &self.checks {
+            writeln!(f, "{:<36} {}", check.to_string(), result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Options for the soak test, repeating the conformance session.
+#[derive(Debug, Clone)]
+pub struct SoakOptions {
+    /// How many times the session is run.
+    pub runs: u32,
+    /// The shell command resetting the board after each run, so that its
+    /// bootloader requests the next transfer. The number of the next run is
+    /// passed in the `BOOTCOM_RUN` environment variable.
+    pub reset_hook: Option<String>,
+}
+impl Default for SoakOptions {
+    fn default() -> Self {
+        SoakOptions {
+            runs: 10,
+            reset_hook: None,
+        }
+    }
+}
+
+/// The results of all the runs of a soak test.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SoakReport {
+    /// The size of the payload sent on each run.
+    pub payload_size: u32,
+    /// The report of each run, in order. A run where the port could not be
+    /// opened has its trigger check failed.
+    pub runs: Vec<Report>,
+}
+impl SoakReport {
+    /// The number of runs with a failed check.
+    pub fn failures(&self) -> usize {
+        self.runs.iter().filter(|report| !report.passed()).count()
+    }
+
+    /// The number of payloads sent to the bootloader.
+    pub fn payloads_sent(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|report| report.result(Check::SizeAcknowledged) == Some(&CheckResult::Passed))
+            .count()
+    }
+
+    /// The number of payloads the bootloader received corrupted, as told by
+    /// the CRC-32 it echoed back.
+    pub fn corrupted(&self) -> usize {
+        self.runs
+            .iter()
+            .filter(|report| matches!(report.result(Check::Crc), Some(CheckResult::Failed(_))))
+            .count()
+    }
+
+    /// The lower bound of the byte error rate: at least one byte is wrong in
+    /// each corrupted payload.
+    pub fn byte_error_rate(&self) -> f64 {
+        let bytes = self.payloads_sent() as f64 * f64::from(self.payload_size);
+        if bytes > 0.0 {
+            self.corrupted() as f64 / bytes
+        } else {
+            0.0
+        }
+    }
+}
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (number, report) in self.runs.iter().enumerate() {
+            if let Some((check, result)) = report
+                .checks
+                .iter()
+                .find(|(_, result)| matches!(result, CheckResult::Failed(_)))
+            {
+                writeln!(f, "run {:<4} {}: {}", number + 1, check, result)?;
+            }
+        }
+        writeln!(
+            f,
+            "{} run(s), {} failed, {} of {} payload(s) of {} corrupted \
+             (byte error rate >= {:.2e})",
+            self.runs.len(),
+            self.failures(),
+            self.corrupted(),
+            self.payloads_sent(),
+            HumanSize(self.payload_size.into()),
+            self.byte_error_rate()
+        )
+    }
+}
+
+/// Run the conformance session against the device on the port described by
+/// `settings`.
+///
+/// An error is only returned if the port could not be opened; protocol
+/// failures are reported in the returned [`Report`].
+pub fn run(settings: &Settings, options: &Options) -> Result<Report, serialport::Error> {
+    let mut port = open_and_setup_port(settings)?;
+    Ok(run_on_port(&mut port, options))
+}
+
+/// Run the conformance session `soak.runs` times in a row, with the reset hook
+/// run in between. The port is reopened for every run, as a reset may make the
+/// device go away for a moment.
+pub fn soak(settings: &Settings, options: &Options, soak: &SoakOptions) -> SoakReport {
+    let mut runs = Vec::with_capacity(soak.runs as usize);
+    for run in 0..soak.runs {
+        if run > 0 {
+            if let Some(hook) = &soak.reset_hook {
+                reset(hook, run + 1);
+            }
+        }
+        let report = match open_and_setup_port(settings) {
+            Ok(mut port) => run_on_port(&mut port, options),
+            Err(e) => not_run(e.to_string()),
+        };
+        runs.push(report);
+    }
+    SoakReport {
+        payload_size: options.payload_size,
+        runs,
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Run the reset `hook` before the run with the given `number`.
+fn reset(hook: &str, number: u32) {
+    let result = shell(hook).env("BOOTCOM_RUN", number.to_string()).status();
+    match result {
+        Ok(status) if !status.success() => {
+            info!("the reset hook failed before run {}: {}", number, status)
+        }
+        Err(e) => info!("could not run the reset hook: {}", e),
+        Ok(_) => {}
+    }
+}
+
+/// The report of a run which could not even start.
+fn not_run(reason: String) -> Report {
+    Report {
+        checks: vec![
+            (Check::Trigger, CheckResult::Failed(reason)),
+            (Check::SizeAcknowledged, CheckResult::Skipped),
+            (Check::PayloadAccepted, CheckResult::Skipped),
+            (Check::Crc, CheckResult::Skipped),
+        ],
+    }
+}
+
+/// Run the conformance session against the device on the `port`.
+pub(crate) fn run_on_port(port: &mut dyn Transport, options: &Options) -> Report {
+    let payload = test_payload(options.payload_size);
+    // Data received after the payload, carried over to the CRC check.
+    let mut after_payload = vec![];
+
+    let trigger = check_trigger(port, &options.trigger, options.trigger_timeout);
+    let size = after(&trigger, || {
+        check_size(port, options.payload_size, options.response_timeout)
+    });
+    let accepted = after(&size, || {
+        check_payload(
+            port,
+            &payload,
+            &options.trigger,
+            options.response_timeout,
+            &mut after_payload,
+        )
+    });
+    let crc = after(&accepted, || {
+        check_crc(port, &payload, after_payload, options.response_timeout)
+    });
+
+    Report {
+        checks: vec![
+            (Check::Trigger, trigger),
+            (Check::SizeAcknowledged, size),
+            (Check::PayloadAccepted, accepted),
+            (Check::Crc, crc),
+        ],
+    }
+}
+
+/// Run the `check` only if the `previous` one did not fail or was skipped.
+fn after(previous: &CheckResult, check: impl FnOnce() -> CheckResult) -> CheckResult {
+    match previous {
+        CheckResult::Failed(_) | CheckResult::Skipped => CheckResult::Skipped,
+        _ => check(),
+    }
+}
+
+/// A deterministic, non-trivial payload so that transmission errors would
+/// show up in the CRC.
+fn test_payload(size: u32) -> Vec<u8> {
+    let mut state: u32 = 0x1234_5678;
+    (0..size)
+        .map(|_| {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// Read whatever is available from the port until `done` returns `true` on the
+/// accumulated data or the `timeout` expires. Returns the accumulated data.
+fn read_until(
+    port: &mut dyn Transport,
+    timeout: Duration,
+    done: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, std::io::Error> {
+    let started = Instant::now();
+    let mut received = vec![];
+    while started.elapsed() < timeout {
+        let available = port.bytes_to_read()?;
+        if available > 0 {
+            let mut buf = vec![0; available as usize];
+            let n = port.read(&mut buf)?;
+            received.extend_from_slice(&buf[..n]);
+            if done(&received) {
+                break;
+            }
+        } else {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    Ok(received)
+}
+
+fn check_trigger(port: &mut dyn Transport, trigger: &[u8], timeout: Duration) -> CheckResult {
+    match read_until(port, timeout, |data| data.ends_with(trigger)) {
+        Ok(data) if data.ends_with(trigger) => CheckResult::Passed,
+        Ok(_) => CheckResult::Failed("no trigger received in time".into()),
+        Err(e) => CheckResult::Failed(e.to_string()),
+    }
+}
+
+fn check_size(port: &mut dyn Transport, size: u32, timeout: Duration) -> CheckResult {
+    let result = port
+        .clear_input()
+        .and_then(|_| port.write_all(&size.to_le_bytes()))
+        .and_then(|_| read_until(port, timeout, |data| data.len() >= 2));
+    match result {
+        Ok(data) if data.starts_with(b"OK") => CheckResult::Passed,
+        Ok(data) if data.starts_with(REVISION_REPORT) => check_revision(port, data, timeout),
+        Ok(data) if data.is_empty() => CheckResult::Failed("no response to the size".into()),
+        Ok(data) => CheckResult::Failed(format!("unexpected response {:02x?}", data)),
+        Err(e) => CheckResult::Failed(e.to_string()),
+    }
+}
+
+/// Check the revision the bootloader reported at the start of the `data`, and
+/// the confirmation of the size which follows once told the one of `bootcom`.
+fn check_revision(port: &mut dyn Transport, mut data: Vec<u8>, timeout: Duration) -> CheckResult {
+    let reported = REVISION_REPORT.len() + 1;
+    if data.len() < reported {
+        let already = data.len();
+        match read_until(port, timeout, |d| already + d.len() >= reported) {
+            Ok(more) => data.extend_from_slice(&more),
+            Err(e) => return CheckResult::Failed(e.to_string()),
+        }
+    }
+    let revision = match data.get(REVISION_REPORT.len()) {
+        Some(revision) => *revision,
+        None => return CheckResult::Failed("no revision after `OV`".into()),
+    };
+    let confirmation = port
+        .write_all(&[PROTOCOL_REVISION])
+        .and_then(|_| read_until(port, timeout, |d| d.len() >= 2));
+    match confirmation {
+        Ok(_) if revision != PROTOCOL_REVISION => CheckResult::Failed(format!(
+            "the bootloader speaks the revision {} of the protocol, bootcom the revision {}",
+            revision, PROTOCOL_REVISION
+        )),
+        Ok(data) if data.starts_with(b"OK") => CheckResult::Passed,
+        Ok(data) => CheckResult::Failed(format!(
+            "unexpected response {:02x?} after the revisions",
+            data
+        )),
+        Err(e) => CheckResult::Failed(e.to_string()),
+    }
+}
+
+fn check_payload(
+    port: &mut dyn Transport,
+    payload: &[u8],
+    trigger: &[u8],
+    timeout: Duration,
+    after_payload: &mut Vec<u8>,
+) -> CheckResult {
+    if let Err(e) = port.write_all(payload).and_then(|_| port.flush()) {
+        return CheckResult::Failed(e.to_string());
+    }
+    // A bootloader rejecting the payload would request a new transfer.
+    match read_until(port, timeout, |data| data.ends_with(trigger)) {
+        Ok(data) if data.ends_with(trigger) => {
+            CheckResult::Failed("the bootloader requested a new transfer".into())
+        }
+        Ok(data) => {
+            *after_payload = data;
+            CheckResult::Passed
+        }
+        Err(e) => CheckResult::Failed(e.to_string()),
+    }
+}
+
+fn check_crc(
+    port: &mut dyn Transport,
+    payload: &[u8],
+    mut data: Vec<u8>,
+    timeout: Duration,
+) -> CheckResult {
+    if data.len() < 4 {
+        let already = data.len();
+        match read_until(port, timeout, |d| already + d.len() >= 4) {
+            Ok(more) => data.extend_from_slice(&more),
+            Err(e) => return CheckResult::Failed(e.to_string()),
+        }
+    }
+    if data.len() < 4 {
+        return CheckResult::NotSupported;
+    }
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    let expected = crc.finalize();
+    let received = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    if received == expected {
+        CheckResult::Passed
+    } else {
+        CheckResult::Failed(format!(
+            "expected {:#010x}, received {:#010x}",
+            expected, received
+        ))
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn report_passes_with_unsupported_optional_features() {
+    let report = Report {
+        checks: vec![
+            (Check::Trigger, CheckResult::Passed),
+            (Check::SizeAcknowledged, CheckResult::Passed),
+            (Check::PayloadAccepted, CheckResult::Passed),
+            (Check::Crc, CheckResult::NotSupported),
+        ],
+    };
+    assert!(report.passed());
+    assert_eq!(report.result(Check::Crc), Some(&CheckResult::NotSupported));
+}
+
+#[test]
+fn report_fails_on_failed_check() {
+    let report = Report {
+        checks: vec![
+            (Check::Trigger, CheckResult::Passed),
+            (
+                Check::SizeAcknowledged,
+                CheckResult::Failed("no response".into()),
+            ),
+            (Check::PayloadAccepted, CheckResult::Skipped),
+            (Check::Crc, CheckResult::Skipped),
+        ],
+    };
+    assert!(!report.passed());
+}
+
+#[test]
+fn payload_is_deterministic() {
+    assert_eq!(test_payload(64), test_payload(64));
+    assert_eq!(test_payload(64).len(), 64);
+}
+
+#[test]
+fn soak_statistics() {
+    let passed = Report {
+        checks: vec![
+            (Check::Trigger, CheckResult::Passed),
+            (Check::SizeAcknowledged, CheckResult::Passed),
+            (Check::PayloadAccepted, CheckResult::Passed),
+            (Check::Crc, CheckResult::Passed),
+        ],
+    };
+    let mut corrupted = passed.clone();
+    corrupted.checks[3].1 = CheckResult::Failed("expected 0x1, received 0x2".into());
+    let report = SoakReport {
+        payload_size: 1000,
+        runs: vec![
+            passed.clone(),
+            corrupted,
+            not_run("no such device".into()),
+            passed,
+        ],
+    };
+    assert_eq!(report.failures(), 2);
+    assert_eq!(report.payloads_sent(), 3);
+    assert_eq!(report.corrupted(), 1);
+    assert!((report.byte_error_rate() - 1.0 / 3000.0).abs() < f64::EPSILON);
+    let summary = report.to_string();
+    assert!(summary.contains("run 2    CRC-32 of the payload echoed back: FAILED"));
+    assert!(summary.contains("run 3    trigger: FAILED (no such device)"));
+    assert!(summary.ends_with(
+        "4 run(s), 2 failed, 1 of 3 payload(s) of 1000 B corrupted (byte error rate >= 3.33e-4)\n"
+    ));
+}
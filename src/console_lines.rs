@@ -0,0 +1,231 @@
+//! The console output of the device as lines, for library users asserting on
+//! it (test frameworks, CI rigs...).
+//!
+//! Each reader obtained from [`BootServer::console_lines`] receives the lines
+//! printed by the device from that moment on, as rendered on the terminal
+//! (after the codecs) but without the ANSI escape sequences, along with the
+//! time they were completed. The iteration ends when the device manager stops
+//! running, or when no line came within the timeout of the reader, if any.
+//!
+//! With the `stream` feature, a reader is also a [`Stream`] of the lines for
+//! async test harnesses, ending when the device manager stops running. The
+//! timeout of the reader only applies to the iteration, the runtime of the
+//! harness has its own.
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{BootServer, DeviceManager, SettingsBuilder};
+//! use std::{thread, time::Duration};
+//!
+//! let settings = SettingsBuilder::default()
+//!     .path("/dev/ttyUSB0")
+//!     .keyboard(false)
+//!     .finalize();
+//! let server = BootServer::new(settings);
+//! let lines = server.console_lines().with_timeout(Duration::from_secs(60));
+//! let mut running = server.clone();
+//! thread::spawn(move || running.run());
+//!
+//! assert!(lines.map(|line| line.text).any(|text| text.contains("login:")));
+//! ```
+//!
+//! [`BootServer::console_lines`]: crate::BootServer::console_lines
+//! [`Stream`]: https://docs.rs/futures-core/0.3/futures_core/stream/trait.Stream.html
+
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, SystemTime},
+};
+#[cfg(feature = "stream")]
+use std::{
+    pin::Pin,
+    sync::{mpsc::TryRecvError, Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A line printed by the device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConsoleLine {
+    /// The text of the line, without the line ending and the ANSI escape
+    /// sequences.
+    pub text: String,
+    /// When the end of the line was received.
+    pub timestamp: SystemTime,
+}
+
+/// The lines printed by the device, as an [`Iterator`] blocking until the next
+/// line comes.
+#[derive(Debug)]
+pub struct ConsoleLines {
+    receiver: Receiver<ConsoleLine>,
+    timeout: Option<Duration>,
+    /// The task polling the stream, woken by the next line.
+    #[cfg(feature = "stream")]
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+impl ConsoleLines {
+    /// End the iteration when no line comes within the `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+impl Iterator for ConsoleLines {
+    type Item = ConsoleLine;
+
+    fn next(&mut self) -> Option<ConsoleLine> {
+        match self.timeout {
+            Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+            },
+            None => self.receiver.recv().ok(),
+        }
+    }
+}
+#[cfg(feature = "stream")]
+impl futures_core::Stream for ConsoleLines {
+    type Item = ConsoleLine;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ConsoleLine>> {
+        // Registered first, for a line sent in the meantime not to be missed.
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.receiver.try_recv() {
+            Ok(line) => Poll::Ready(Some(line)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Splits the console output into lines for a [`ConsoleLines`] reader.
+#[derive(Debug)]
+pub(crate) struct LineSink {
+    sender: Sender<ConsoleLine>,
+    /// The start of a line not ended yet.
+    partial: Vec<u8>,
+    #[cfg(feature = "stream")]
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+impl LineSink {
+    /// A sink and the reader of its lines.
+    pub(crate) fn new() -> (Self, ConsoleLines) {
+        let (sender, receiver) = mpsc::channel();
+        #[cfg(feature = "stream")]
+        let waker = Arc::new(Mutex::new(None));
+        let sink = LineSink {
+            sender,
+            partial: vec![],
+            #[cfg(feature = "stream")]
+            waker: waker.clone(),
+        };
+        let lines = ConsoleLines {
+            receiver,
+            timeout: None,
+            #[cfg(feature = "stream")]
+            waker,
+        };
+        (sink, lines)
+    }
+
+    /// Wake the task polling the stream of lines, if any.
+    #[cfg(feature = "stream")]
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Send the lines completed by the `data`. Fails with a broken pipe once
+    /// the reader is gone.
+    pub(crate) fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for &byte in data {
+            if byte != b'\n' {
+                self.partial.push(byte);
+                continue;
+            }
+            let line = std::mem::take(&mut self.partial);
+            let text = String::from_utf8_lossy(&line);
+            let line = ConsoleLine {
+                text: console::strip_ansi_codes(text.trim_end_matches('\r')).into_owned(),
+                timestamp: SystemTime::now(),
+            };
+            self.sender
+                .send(line)
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            #[cfg(feature = "stream")]
+            self.wake();
+        }
+        Ok(())
+    }
+}
+// The stream ends once the device manager drops the sink.
+#[cfg(feature = "stream")]
+impl Drop for LineSink {
+    fn drop(&mut self) {
+        self.wake();
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn output_is_split_in_lines() {
+    let (mut sink, lines) = LineSink::new();
+    sink.write(b"U-Boot\r\n\x1b[32mlog").unwrap();
+    sink.write(b"in:\x1b[0m \nprompt").unwrap();
+    drop(sink);
+    let texts: Vec<_> = lines.map(|line| line.text).collect();
+    assert_eq!(texts, vec!["U-Boot", "login: "]);
+
+    let (mut sink, lines) = LineSink::new();
+    drop(lines);
+    assert_eq!(
+        sink.write(b"gone\n").unwrap_err().kind(),
+        io::ErrorKind::BrokenPipe
+    );
+}
+
+#[cfg(feature = "stream")]
+#[test]
+fn lines_streamed() {
+    use futures_core::Stream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let wakes = Arc::new(Wakes::default());
+    let waker = Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    let (mut sink, mut lines) = LineSink::new();
+    let mut lines = Pin::new(&mut lines);
+    assert_eq!(lines.as_mut().poll_next(&mut cx), Poll::Pending);
+    sink.write(b"login: \n").unwrap();
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+    match lines.as_mut().poll_next(&mut cx) {
+        Poll::Ready(Some(line)) => assert_eq!(line.text, "login: "),
+        other => panic!("no line: {:?}", other),
+    }
+    assert_eq!(lines.as_mut().poll_next(&mut cx), Poll::Pending);
+    drop(sink);
+    assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+    assert_eq!(lines.as_mut().poll_next(&mut cx), Poll::Ready(None));
+}
@@ -0,0 +1,96 @@
+//! Context shared by the device manager and all the boot protocol sessions it
+//! runs, for the whole lifetime of `bootcom`.
+//!
+//! Unlike the settings, the context holds live resources (open files, threads)
+//! which must survive the reconnection of the device.
+
+use std::sync::{Arc, Mutex};
+
+use crate::debug_bundle::DebugBundle;
+use crate::fsm::Shared;
+use crate::session_handle::SessionHandle;
+use crate::settings::Settings;
+use crate::stats::SessionStats;
+use crate::utils::{
+    serve_activated_sockets, ConfigReload, Health, History, ImageWatch, Interrupted, Notifier,
+    Outputs, SessionReport, Stopwatch,
+};
+
+/// Cloning the context gives another handle to the same shared resources.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Context {
+    /// Where the console output received from the device goes.
+    pub outputs: Outputs,
+    /// The health status, periodically reported when enabled in the settings.
+    pub health: Health,
+    /// The statistics of all the boot sessions that ended.
+    pub stats: Arc<Mutex<SessionStats>>,
+    /// The events consumed by the state machines, dumped on abnormal exit.
+    pub history: History,
+    /// The kernel image the user chose for the next transfers, instead of the
+    /// one bound to the trigger.
+    pub selected_image: Arc<Mutex<Option<String>>>,
+    /// The last upload of the kernel image, should it have failed partway.
+    pub interrupted: Arc<Mutex<Option<Interrupted>>>,
+    /// The markers of the console log and the stopwatch, kept across the
+    /// reconnections of the device.
+    pub stopwatch: Arc<Mutex<Stopwatch>>,
+    /// The configuration file, watched for modifications.
+    pub config: ConfigReload,
+    /// The kernel image file, watched for rebuilds when enabled in the
+    /// settings.
+    pub image_watch: ImageWatch,
+    /// The data written to the device by library code.
+    pub session: SessionHandle,
+    /// The report of the run, written on exit when enabled in the settings.
+    pub report: SessionReport,
+    /// The debug bundle, saved on abnormal exit when enabled in the settings.
+    pub debug_bundle: DebugBundle,
+}
+impl Context {
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let health = Health::default();
+        if settings.health.is_enabled() {
+            health.start_reporting(settings.health.clone(), settings.messages.clone());
+        }
+        let history = History::new(settings.messages.clone());
+        let debug_bundle = DebugBundle::new(settings, &history);
+        let context = Context {
+            outputs: Outputs::new(settings),
+            health,
+            stats: Arc::default(),
+            history,
+            selected_image: Arc::default(),
+            interrupted: Arc::default(),
+            stopwatch: Arc::default(),
+            config: ConfigReload::new(settings),
+            image_watch: ImageWatch::default(),
+            session: SessionHandle::default(),
+            report: SessionReport::new(settings),
+            debug_bundle,
+        };
+        if settings.systemd {
+            serve_activated_sockets(&context, settings);
+            if let Some(notifier) = Notifier::from_env() {
+                context.health.notify_service_manager(notifier);
+            }
+        }
+        context
+    }
+
+    /// Write the console output received from the device, as rendered, to all
+    /// the output sinks.
+    pub(crate) fn output(&self, data: &[u8]) {
+        self.outputs.write(data);
+        self.report.output(data);
+        self.debug_bundle.output(data);
+        self.health.output();
+    }
+}
+impl Shared for Context {
+    const MACHINE: &'static str = "device";
+
+    fn context(&self) -> &Context {
+        self
+    }
+}
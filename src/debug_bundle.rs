@@ -0,0 +1,358 @@
+//! Bundle of everything needed to investigate a problem with `bootcom` itself,
+//! saved when it exits abnormally, for attaching to a bug report.
+//!
+//! When a bundle file is set in the settings, the end of the console output
+//! and the messages of the log captured by a [`LogCapture`] are kept while
+//! `bootcom` runs. If it then exits with an error or panics, they are saved in
+//! a tar archive along with the history of the state transitions, the
+//! effective settings (the access tokens left out) and the environment:
+//!
+//! ```text
+//! bootcom.log      the messages of the log, the last MiB of them
+//! console.txt      the last 64 KiB of the console output, as rendered
+//! history.txt      the events consumed by the state machines
+//! settings.txt     the effective settings
+//! environment.txt  the version, platform, arguments and the cause of the exit
+//! ```
+//!
+//! Nothing is saved when `bootcom` exits normally.
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{debug_bundle::LogCapture, SettingsBuilder};
+//! use simplelog::{CombinedLogger, Config, LevelFilter, WriteLogger};
+//!
+//! let log = LogCapture::default();
+//! CombinedLogger::init(vec![WriteLogger::new(
+//!     LevelFilter::Info,
+//!     Config::default(),
+//!     log.clone(),
+//! )])
+//! .unwrap();
+//! let settings = SettingsBuilder::default()
+//!     .debug_bundle("bootcom-debug.tar")
+//!     .debug_log(log)
+//!     .finalize();
+//! ```
+
+use std::{
+    collections::VecDeque,
+    env, fmt, fs,
+    io::{self, Write},
+    panic,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use console::style;
+
+use crate::{
+    settings::Settings,
+    stats::SessionStats,
+    utils::{History, HumanDate},
+};
+
+/// How much of the log is kept, the oldest messages being dropped.
+const LOG_TAIL: usize = 1024 * 1024;
+
+/// How much of the console output is kept, the oldest being dropped.
+const CONSOLE_TAIL: usize = 64 * 1024;
+
+/// The size of the blocks of a tar archive.
+const BLOCK: usize = 512;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Keeps the last messages written to it, for a logger to write to. Cloning it
+/// gives another handle to the same messages.
+///
+/// Two handles are equal only when they refer to the same messages.
+#[derive(Clone, Default)]
+pub struct LogCapture(Arc<Mutex<VecDeque<u8>>>);
+impl LogCapture {
+    /// The messages kept so far.
+    fn contents(&self) -> Vec<u8> {
+        let kept = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        kept.iter().copied().collect()
+    }
+}
+impl Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut kept = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        keep_tail(&mut kept, buf, LOG_TAIL);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl PartialEq for LogCapture {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for LogCapture {}
+impl fmt::Debug for LogCapture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LogCapture")
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// What the bundle is made of, collected while `bootcom` runs. Cloning it
+/// gives another handle to the same bundle.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DebugBundle(Option<Arc<Mutex<Collected>>>);
+
+#[derive(Debug)]
+struct Collected {
+    path: String,
+    settings: Settings,
+    history: History,
+    log: Option<LogCapture>,
+    console: VecDeque<u8>,
+}
+
+impl DebugBundle {
+    /// Start collecting the bundle set in the `settings`, if any, the events
+    /// being recorded in the `history`.
+    pub(crate) fn new(settings: &Settings, history: &History) -> Self {
+        DebugBundle(settings.debug_bundle.as_ref().map(|path| {
+            Arc::new(Mutex::new(Collected {
+                path: path.clone(),
+                settings: settings.clone(),
+                history: history.clone(),
+                log: settings.debug_log.clone(),
+                console: VecDeque::new(),
+            }))
+        }))
+    }
+
+    /// Keep the console output `data`, as rendered.
+    pub(crate) fn output(&self, data: &[u8]) {
+        if let Some(collected) = &self.0 {
+            keep_tail(&mut lock(collected).console, data, CONSOLE_TAIL);
+        }
+    }
+
+    /// Save the bundle whenever a panic occurs, after the default panic
+    /// message.
+    pub(crate) fn save_on_panic(&self) {
+        if self.0.is_none() {
+            return;
+        }
+        let bundle = self.clone();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            bundle.save(&format!("panic: {}", info), None);
+        }));
+    }
+
+    /// Save the bundle, `bootcom` exiting abnormally for the given `reason`,
+    /// with the `stats` of the run if known, and tell where.
+    pub(crate) fn save(&self, reason: &str, stats: Option<&SessionStats>) {
+        let collected = match &self.0 {
+            Some(collected) => lock(collected),
+            None => return,
+        };
+        let archive = collected.archive(reason, stats, SystemTime::now());
+        let messages = &collected.settings.messages;
+        match fs::write(&collected.path, archive) {
+            Ok(_) => println!(
+                "{}",
+                messages.text_with("debug_bundle.saved", &[("path", &collected.path)])
+            ),
+            Err(e) => println!(
+                "{}",
+                style(messages.text_with(
+                    "debug_bundle.not_saved",
+                    &[("path", &collected.path), ("error", &e)]
+                ))
+                .red()
+            ),
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+// The bundle is still wanted after a panic while it was locked.
+fn lock(collected: &Mutex<Collected>) -> MutexGuard<'_, Collected> {
+    collected.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Append the `data` to the `kept` bytes, dropping the oldest ones beyond
+/// `limit`.
+fn keep_tail(kept: &mut VecDeque<u8>, data: &[u8], limit: usize) {
+    let data = &data[data.len().saturating_sub(limit)..];
+    let excess = (kept.len() + data.len()).saturating_sub(limit);
+    kept.drain(..excess);
+    kept.extend(data);
+}
+
+impl Collected {
+    /// The tar archive of the bundle, saved at `now`.
+    fn archive(&self, reason: &str, stats: Option<&SessionStats>, now: SystemTime) -> Vec<u8> {
+        let mut settings = self.settings.clone();
+        for rule in &mut settings.access {
+            rule.token = "<redacted>".into();
+        }
+        let log = self.log.as_ref().map(LogCapture::contents);
+        let console: Vec<u8> = self.console.iter().copied().collect();
+        let files = [
+            (
+                "bootcom.log",
+                log.unwrap_or_else(|| b"the log was not captured\n".to_vec()),
+            ),
+            ("console.txt", console),
+            ("history.txt", format!("{}\n", self.history).into_bytes()),
+            ("settings.txt", format!("{:#?}\n", settings).into_bytes()),
+            (
+                "environment.txt",
+                environment(reason, stats, now).into_bytes(),
+            ),
+        ];
+        let mtime = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut archive = vec![];
+        for (name, contents) in &files {
+            archive.extend_from_slice(&tar_header(name, contents.len(), mtime));
+            archive.extend_from_slice(contents);
+            archive.resize(archive.len() + padding(contents.len()), 0);
+        }
+        // The end of the archive.
+        archive.resize(archive.len() + 2 * BLOCK, 0);
+        archive
+    }
+}
+
+/// What `bootcom` ran on and why it exited, for the `reason`, with the `stats`
+/// of the run if known.
+fn environment(reason: &str, stats: Option<&SessionStats>, now: SystemTime) -> String {
+    let mut text = format!(
+        "bootcom {}\nplatform: {} {} ({})\narguments: {:?}\n",
+        env!("CARGO_PKG_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH,
+        env::consts::FAMILY,
+        env::args().collect::<Vec<_>>(),
+    );
+    if let Ok(dir) = env::current_dir() {
+        text.push_str(&format!("working directory: {}\n", dir.display()));
+    }
+    for name in &["TERM", "LANG", "SHELL"] {
+        if let Ok(value) = env::var(name) {
+            text.push_str(&format!("{}={}\n", name, value));
+        }
+    }
+    text.push_str(&format!("saved: {}\nexit: {}\n", HumanDate(now), reason));
+    if let Some(stats) = stats {
+        text.push_str(&format!("\n{}\n", stats));
+    }
+    text
+}
+
+/// The ustar header of a regular file of `size` bytes, modified at `mtime`.
+fn tar_header(name: &str, size: usize, mtime: u64) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    // Spaces while the checksum is computed.
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    field(265, b"bootcom");
+    field(297, b"bootcom");
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// The zeroes padding `size` bytes to a whole number of blocks.
+fn padding(size: usize) -> usize {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn bundle_is_a_tar_archive() {
+    use crate::{AccessRule, SettingsBuilder};
+
+    let mut log = LogCapture::default();
+    log.write_all(b"09:41:00 [INFO] => Service\n").unwrap();
+    let settings = SettingsBuilder::default()
+        .debug_bundle("bootcom-debug.tar")
+        .debug_log(log)
+        .access(vec![AccessRule {
+            token: "s3cr3t".into(),
+            permissions: vec![],
+        }])
+        .finalize();
+    let history = History::default();
+    history.record("device", "Service", "PortError".into());
+    let bundle = DebugBundle::new(&settings, &history);
+    bundle.output(&[b'x'; CONSOLE_TAIL]);
+    bundle.output(b"\r\nKernel panic");
+
+    let collected = lock(bundle.0.as_ref().unwrap());
+    let archive = collected.archive("exit code 1", None, UNIX_EPOCH);
+    assert_eq!(archive.len() % BLOCK, 0);
+    let mut files = vec![];
+    let mut offset = 0;
+    while archive[offset] != 0 {
+        let header = &archive[offset..offset + BLOCK];
+        let checksum: u32 = header[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&header[156..])
+            .map(|&byte| u32::from(byte))
+            .sum();
+        assert_eq!(&header[148..156], format!("{:06o}\0 ", checksum).as_bytes());
+        assert_eq!(&header[257..263], b"ustar\0");
+        let name = String::from_utf8_lossy(&header[..100]);
+        let size = usize::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8);
+        let size = size.unwrap();
+        let contents = &archive[offset + BLOCK..offset + BLOCK + size];
+        files.push((name.trim_end_matches('\0').to_owned(), contents.to_vec()));
+        offset += BLOCK + size + padding(size);
+    }
+    assert_eq!(archive.len(), offset + 2 * BLOCK);
+
+    let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "bootcom.log",
+            "console.txt",
+            "history.txt",
+            "settings.txt",
+            "environment.txt"
+        ]
+    );
+    assert_eq!(files[0].1, b"09:41:00 [INFO] => Service\n");
+    assert_eq!(files[1].1.len(), CONSOLE_TAIL);
+    assert!(files[1].1.ends_with(b"xx\r\nKernel panic"));
+    assert!(String::from_utf8_lossy(&files[2].1).contains("device/Service -> PortError"));
+    let settings = String::from_utf8_lossy(&files[3].1);
+    assert!(settings.contains("<redacted>") && !settings.contains("s3cr3t"));
+    assert!(String::from_utf8_lossy(&files[4].1).contains("exit: exit code 1"));
+}
@@ -0,0 +1,358 @@
+//! Differences between the console logs of two boots.
+//!
+//! The logs are normalized before they are compared, so that only what
+//! changed in the behavior of the board stands out: the ANSI escape sequences
+//! and the line endings are removed, the timestamps (kernel `[   12.345678]`,
+//! `bootcom`'s own `timestamp` codec, times of the day) and the long
+//! hexadecimal numbers (addresses moved by KASLR, canaries...) are masked,
+//! and the lines containing one of the noise filters are dropped. The lines
+//! are then aligned with the smallest set of insertions and deletions.
+//!
+//! `bootcom diff` compares the console logs of boots archived with
+//! `--archive`, by default the last one with the last one of the same board
+//! which reached all its boot stages, listing the errors which are new, as
+//! classified by the [`severity`](crate::severity) rules. The noise filters
+//! are read from the `[diff]` section of the configuration file (see
+//! [`config`](crate::config)).
+//!
+//! **Example**
+//! ```
+//! use bootcom::{
+//!     diff::{diff, new_errors, DiffOptions},
+//!     severity::default_rules,
+//! };
+//!
+//! let good = b"[    0.000000] Booting Linux\r\n[    1.250000] eth0: up\r\n";
+//! let bad = b"[    0.000000] Booting Linux\r\n[    1.310000] eth0: error -110\r\n";
+//! let lines = diff(good, bad, &DiffOptions::default());
+//! assert_eq!(new_errors(&lines, &default_rules()), vec!["[T] eth0: error -110"]);
+//! ```
+
+use std::fmt;
+
+use crate::codec::{CodecChain, CodecFactory};
+use crate::severity::{classify, Severity, SeverityRule};
+
+/// The largest number of differing lines aligned, beyond which the rest of
+/// the logs are shown as replaced.
+const MAX_EDITS: usize = 4000;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Options for comparing console logs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiffOptions {
+    /// The lines containing one of these are dropped before the comparison.
+    pub ignore: Vec<String>,
+    /// The number of unchanged lines shown around the changes.
+    pub context: usize,
+}
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            ignore: vec![],
+            context: 3,
+        }
+    }
+}
+
+/// A line of the difference between two logs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DiffLine {
+    /// In both logs.
+    Same(String),
+    /// Only in the first log.
+    Removed(String),
+    /// Only in the second log.
+    Added(String),
+}
+
+impl fmt::Display for DiffLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffLine::Same(line) => write!(f, "  {}", line),
+            DiffLine::Removed(line) => write!(f, "- {}", line),
+            DiffLine::Added(line) => write!(f, "+ {}", line),
+        }
+    }
+}
+
+/// The normalized lines of the console `log`.
+pub fn normalize(log: &[u8], options: &DiffOptions) -> Vec<String> {
+    let mut strip_ansi = CodecChain::new(&[CodecFactory::builtin("strip-ansi").unwrap()]);
+    String::from_utf8_lossy(&strip_ansi.decode(log))
+        .lines()
+        .map(|line| mask(line.trim_end()))
+        .filter(|line| !options.ignore.iter().any(|noise| line.contains(noise)))
+        .collect()
+}
+
+/// The difference between the console logs `a` and `b`, line by line.
+pub fn diff(a: &[u8], b: &[u8], options: &DiffOptions) -> Vec<DiffLine> {
+    align(&normalize(a, options), &normalize(b, options))
+}
+
+/// The lines of the `diff` to show, with their context: the unchanged lines
+/// further than `context` lines from any change are left out, `None` standing
+/// for each run of them.
+pub fn hunks(diff: &[DiffLine], context: usize) -> Vec<Option<&DiffLine>> {
+    let changed: Vec<usize> = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Same(_)))
+        .map(|(i, _)| i)
+        .collect();
+    let mut shown = vec![];
+    let mut skipped = false;
+    for (i, line) in diff.iter().enumerate() {
+        let near = changed
+            .binary_search_by(|c| {
+                if c + context < i {
+                    std::cmp::Ordering::Less
+                } else if *c > i + context {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok();
+        if near {
+            shown.push(Some(line));
+            skipped = false;
+        } else if !skipped {
+            shown.push(None);
+            skipped = true;
+        }
+    }
+    shown
+}
+
+/// The lines which are only in the second log of the `diff` and are errors
+/// according to the severity `rules`.
+pub fn new_errors<'a>(diff: &'a [DiffLine], rules: &[SeverityRule]) -> Vec<&'a str> {
+    diff.iter()
+        .filter_map(|line| match line {
+            DiffLine::Added(line) if classify(rules, line) == Some(Severity::Error) => {
+                Some(line.as_str())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Mask the timestamps and the long hexadecimal numbers of a `line`.
+fn mask(line: &str) -> String {
+    let mut masked = String::with_capacity(line.len());
+    let mut rest = line;
+    // The kernel and `timestamp` codec prefixes, e.g. `[   12.345678] `.
+    if let Some(end) = timestamp_prefix(rest) {
+        masked.push_str("[T] ");
+        rest = rest[end..].trim_start();
+    }
+    let chars: Vec<char> = rest.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let digits = |from: usize, hex: bool| {
+            chars[from..]
+                .iter()
+                .take_while(|c| {
+                    if hex {
+                        c.is_ascii_hexdigit()
+                    } else {
+                        c.is_ascii_digit()
+                    }
+                })
+                .count()
+        };
+        let boundary = i == 0 || !chars[i - 1].is_ascii_alphanumeric();
+        // `0x` followed by 8 hexadecimal digits or more.
+        if boundary && chars[i] == '0' && chars.get(i + 1) == Some(&'x') && digits(i + 2, true) >= 8
+        {
+            masked.push_str("0x…");
+            i += 2 + digits(i + 2, true);
+            continue;
+        }
+        // Times of the day, e.g. `12:34:56` or `12:34:56.789`.
+        if boundary && digits(i, false) == 2 && chars.get(i + 2) == Some(&':') {
+            let time: String = chars[i..].iter().take(8).collect();
+            let shape: String = time
+                .chars()
+                .map(|c| if c.is_ascii_digit() { 'd' } else { c })
+                .collect();
+            if shape == "dd:dd:dd" {
+                masked.push_str("hh:mm:ss");
+                i += 8;
+                if chars.get(i) == Some(&'.') && digits(i + 1, false) > 0 {
+                    i += 1 + digits(i + 1, false);
+                }
+                continue;
+            }
+        }
+        masked.push(chars[i]);
+        i += 1;
+    }
+    masked
+}
+
+/// The end of the timestamp prefix of a `line`, e.g. `[   12.345678]`, if it
+/// has one.
+fn timestamp_prefix(line: &str) -> Option<usize> {
+    let inside = line.strip_prefix('[')?;
+    let end = inside.find(']')?;
+    let stamp = inside[..end].trim_start();
+    let (seconds, fraction) = stamp.split_once('.')?;
+    let numeric = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if numeric(seconds) && numeric(fraction) {
+        Some(end + 2)
+    } else {
+        None
+    }
+}
+
+/// Align the lines `a` and `b` with the fewest insertions and deletions
+/// (Myers' algorithm).
+fn align(a: &[String], b: &[String]) -> Vec<DiffLine> {
+    // The common ends are set aside, the search only covers the middle.
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (middle_a, middle_b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut lines: Vec<DiffLine> = a[..prefix].iter().cloned().map(DiffLine::Same).collect();
+    lines.extend(align_middle(middle_a, middle_b));
+    lines.extend(a[a.len() - suffix..].iter().cloned().map(DiffLine::Same));
+    lines
+}
+
+fn align_middle(a: &[String], b: &[String]) -> Vec<DiffLine> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let offset = (n + m) as usize;
+    let mut v = vec![0isize; 2 * offset + 2];
+    // The furthest reaching paths of each number of edits, to trace back.
+    let mut trace: Vec<Vec<isize>> = vec![];
+    let mut found = false;
+    for d in 0..=(n + m).min(MAX_EDITS as isize) {
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                found = true;
+                break;
+            }
+            k += 2;
+        }
+        if found {
+            break;
+        }
+    }
+    if !found {
+        // Too different to be worth aligning.
+        return a
+            .iter()
+            .cloned()
+            .map(DiffLine::Removed)
+            .chain(b.iter().cloned().map(DiffLine::Added))
+            .collect();
+    }
+
+    let mut lines = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let index = |k: isize| (k + offset as isize) as usize;
+        let previous_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = if d == 0 { 0 } else { v[index(previous_k)] };
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            lines.push(DiffLine::Same(a[x as usize].clone()));
+        }
+        if d > 0 {
+            if x == previous_x {
+                lines.push(DiffLine::Added(b[previous_y as usize].clone()));
+            } else {
+                lines.push(DiffLine::Removed(a[previous_x as usize].clone()));
+            }
+        }
+        x = previous_x;
+        y = previous_y;
+    }
+    lines.reverse();
+    lines
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn logs_are_normalized() {
+    let options = DiffOptions {
+        ignore: vec!["random:".into()],
+        ..DiffOptions::default()
+    };
+    let log = b"\x1b[32m[    3.141592] \x1b[0mmapped at 0xffff0000deadbeef, 0x1f\r\n\
+                random: crng init done\r\n\
+                [   12.500] up at 23:59:01.125 since 2023-11-14\r\n";
+    assert_eq!(
+        normalize(log, &options),
+        vec![
+            "[T] mapped at 0x…, 0x1f",
+            "[T] up at hh:mm:ss since 2023-11-14"
+        ]
+    );
+}
+
+#[test]
+fn lines_are_aligned() {
+    let a = b"one\ntwo\nthree\nfour\nfive\n";
+    let b = b"one\nthree\nfour\nfour and a half\nfive\n";
+    let lines = diff(a, b, &DiffOptions::default());
+    assert_eq!(
+        lines,
+        vec![
+            DiffLine::Same("one".into()),
+            DiffLine::Removed("two".into()),
+            DiffLine::Same("three".into()),
+            DiffLine::Same("four".into()),
+            DiffLine::Added("four and a half".into()),
+            DiffLine::Same("five".into()),
+        ]
+    );
+    assert_eq!(diff(b"", b"a\nb", &DiffOptions::default()).len(), 2);
+    assert!(new_errors(&lines, &crate::severity::default_rules()).is_empty());
+
+    let shown = hunks(&lines, 0);
+    assert_eq!(shown.len(), 5);
+    assert_eq!(shown[0], None);
+    assert_eq!(shown[1], Some(&DiffLine::Removed("two".into())));
+    assert_eq!(shown[2], None);
+}
@@ -0,0 +1,411 @@
+//! Staging of the kernel image through fastboot, for the boards whose first
+//! stage bootloader speaks it (Android bootloaders, U-Boot with `fastboot`...).
+//!
+//! The image is downloaded to the bootloader and booted right away, as `fastboot
+//! boot` does, and the serial console is then attached as usual. Over the
+//! network, `bootcom` speaks the fastboot protocol itself, framed as the
+//! `fastboot` host tool does over TCP. There is no USB stack in `bootcom`, the
+//! boards on USB are staged with the `fastboot` host tool, which needs to be
+//! installed (it comes with the Android platform tools).
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{fastboot, SettingsBuilder};
+//!
+//! let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
+//! let target = "tcp:192.168.1.42".parse().unwrap();
+//! match fastboot::boot(&settings, &target, "out/boot.img") {
+//!     Ok(report) => println!("{}", report),
+//!     Err(e) => eprintln!("could not stage the image: {}", e),
+//! }
+//! ```
+
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::progress::Progress;
+use crate::settings::Settings;
+use crate::utils::{HumanDuration, HumanRate, HumanSize};
+
+/// The TCP port of the fastboot bootloaders when the target does not give
+/// one.
+pub const DEFAULT_PORT: u16 = 5554;
+
+/// The size of the pieces of the image sent, between two progress reports.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// How long the bootloader is given to answer a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Where the fastboot bootloader is.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FastbootTarget {
+    /// On USB, the device with the serial number, if given, or the only one.
+    Usb(Option<String>),
+    /// On the network, at a `host` or `host:port`.
+    Tcp(String),
+}
+impl FromStr for FastbootTarget {
+    type Err = String;
+
+    /// Parse `usb`, `usb:<serial number>`, or `tcp:<host>[:<port>]`.
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        match target.split_once(':') {
+            None if target == "usb" => Ok(FastbootTarget::Usb(None)),
+            Some(("usb", serial)) if !serial.is_empty() => {
+                Ok(FastbootTarget::Usb(Some(serial.into())))
+            }
+            Some(("tcp", host)) if !host.is_empty() => Ok(FastbootTarget::Tcp(host.into())),
+            _ => Err(format!(
+                "`{}` is not a fastboot target, use `usb`, `usb:<serial>` or `tcp:<host>`",
+                target
+            )),
+        }
+    }
+}
+impl fmt::Display for FastbootTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FastbootTarget::Usb(None) => f.write_str("usb"),
+            FastbootTarget::Usb(Some(serial)) => write!(f, "usb:{}", serial),
+            FastbootTarget::Tcp(host) => write!(f, "tcp:{}", host),
+        }
+    }
+}
+
+/// What was staged, and how it went.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FastbootReport {
+    /// The path of the image booted.
+    pub image: String,
+    /// The size of the image.
+    pub bytes: u64,
+    /// The duration of the download and of the boot command.
+    pub duration: Duration,
+    /// The messages of the bootloader (`INFO` replies) or of the host tool.
+    pub messages: Vec<String>,
+}
+impl fmt::Display for FastbootReport {
+    /// The humanized figures of the staging, e.g. `12.0 MiB staged over
+    /// fastboot in 1.5s (8.0 MiB/s)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64();
+        let rate = if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        } else {
+            0.0
+        };
+        write!(
+            f,
+            "{} staged over fastboot in {} ({})",
+            HumanSize(self.bytes),
+            HumanDuration(self.duration),
+            HumanRate(rate)
+        )
+    }
+}
+
+/// Why the image could not be staged.
+#[derive(Debug)]
+pub enum FastbootError {
+    /// The image could not be read, or the bootloader reached.
+    Io(io::Error),
+    /// The bootloader refused a command, with its reason.
+    Failed(String),
+    /// The bootloader answered something unexpected.
+    Protocol(String),
+    /// The `fastboot` host tool failed, with its last words.
+    Tool(String),
+}
+impl fmt::Display for FastbootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FastbootError::Io(e) => e.fmt(f),
+            FastbootError::Failed(reason) => write!(f, "the bootloader failed: {}", reason),
+            FastbootError::Protocol(reply) => write!(f, "unexpected reply `{}`", reply),
+            FastbootError::Tool(e) => write!(f, "fastboot: {}", e),
+        }
+    }
+}
+impl Error for FastbootError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FastbootError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for FastbootError {
+    fn from(e: io::Error) -> Self {
+        FastbootError::Io(e)
+    }
+}
+
+/// Download the kernel `image` to the fastboot bootloader of the `target` and
+/// boot it, reporting the progress to the observer of the `settings`, if any.
+pub fn boot(
+    settings: &Settings,
+    target: &FastbootTarget,
+    image: &str,
+) -> Result<FastbootReport, FastbootError> {
+    match target {
+        FastbootTarget::Usb(serial) => boot_with_tool(serial.as_deref(), image),
+        FastbootTarget::Tcp(host) => {
+            let data = fs::read(image)?;
+            let total = data.len() as u64;
+            let mut transport = TcpTransport::connect(host)?;
+            let observer = settings.progress_observer.as_ref().map(|h| h.observer());
+            if let Some(observer) = observer {
+                observer.started(total);
+            }
+            let started = Instant::now();
+            let messages = download_and_boot(&mut transport, &data, |bytes| {
+                let progress = Progress {
+                    bytes,
+                    total,
+                    elapsed: started.elapsed(),
+                };
+                match observer {
+                    Some(observer) if bytes == total => observer.finished(&progress),
+                    Some(observer) => observer.progress(&progress),
+                    None => (),
+                }
+            })?;
+            Ok(FastbootReport {
+                image: image.into(),
+                bytes: data.len() as u64,
+                duration: started.elapsed(),
+                messages,
+            })
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// A connection to a fastboot bootloader, carrying whole messages.
+trait Transport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+    fn receive(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/// The framing of fastboot over TCP: a `FB01` handshake both ways, then each
+/// message prefixed with its length on 8 bytes, big endian.
+struct TcpTransport(TcpStream);
+impl TcpTransport {
+    fn connect(host: &str) -> io::Result<Self> {
+        let address = if host.contains(':') {
+            host.to_owned()
+        } else {
+            format!("{}:{}", host, DEFAULT_PORT)
+        };
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, address.clone()))?;
+        let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(5))?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        stream.write_all(b"FB01")?;
+        let mut version = [0; 4];
+        stream.read_exact(&mut version)?;
+        if &version[..2] != b"FB" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a fastboot bootloader",
+            ));
+        }
+        Ok(TcpTransport(stream))
+    }
+}
+impl Transport for TcpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.0.write_all(&(message.len() as u64).to_be_bytes())?;
+        self.0.write_all(message)
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let mut length = [0; 8];
+        self.0.read_exact(&mut length)?;
+        let mut message = vec![0; u64::from_be_bytes(length) as usize];
+        self.0.read_exact(&mut message)?;
+        Ok(message)
+    }
+}
+
+/// Download the `data` and boot it, calling `progress` with the number of
+/// bytes sent so far. Returns the messages of the bootloader.
+fn download_and_boot(
+    transport: &mut impl Transport,
+    data: &[u8],
+    mut progress: impl FnMut(u64),
+) -> Result<Vec<String>, FastbootError> {
+    let mut messages = vec![];
+    let reply = command(
+        transport,
+        &format!("download:{:08x}", data.len()),
+        &mut messages,
+    )?;
+    if !reply.starts_with("DATA") {
+        return Err(FastbootError::Protocol(reply));
+    }
+    let mut sent = 0;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        transport.send(chunk)?;
+        sent += chunk.len() as u64;
+        progress(sent);
+    }
+    expect_okay(transport, &mut messages)?;
+    command(transport, "boot", &mut messages)?;
+    Ok(messages)
+}
+
+/// Send a `command` and return its final reply, `OKAY` or `DATA`, the
+/// messages coming before it added to `messages`.
+fn command(
+    transport: &mut impl Transport,
+    command: &str,
+    messages: &mut Vec<String>,
+) -> Result<String, FastbootError> {
+    transport.send(command.as_bytes())?;
+    expect_okay(transport, messages)
+}
+
+fn expect_okay(
+    transport: &mut impl Transport,
+    messages: &mut Vec<String>,
+) -> Result<String, FastbootError> {
+    loop {
+        let reply = String::from_utf8_lossy(&transport.receive()?).into_owned();
+        let (kind, text) = reply.split_at(reply.len().min(4));
+        match kind {
+            "INFO" | "TEXT" => {
+                info!("fastboot: {}", text);
+                messages.push(text.to_owned());
+            }
+            "FAIL" => return Err(FastbootError::Failed(text.to_owned())),
+            "OKAY" | "DATA" => return Ok(reply),
+            _ => return Err(FastbootError::Protocol(reply)),
+        }
+    }
+}
+
+/// Stage the `image` with the `fastboot` host tool, on the device with the
+/// `serial` number if given.
+fn boot_with_tool(serial: Option<&str>, image: &str) -> Result<FastbootReport, FastbootError> {
+    let bytes = fs::metadata(image)?.len();
+    let mut tool = Command::new("fastboot");
+    if let Some(serial) = serial {
+        tool.args(["-s", serial]);
+    }
+    let started = Instant::now();
+    let output = tool
+        .args(["boot", image])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| FastbootError::Tool(format!("could not run it ({})", e)))?;
+    // It reports on the standard error.
+    let text = String::from_utf8_lossy(&output.stderr);
+    let messages: Vec<String> = text.lines().map(str::to_owned).collect();
+    if !output.status.success() {
+        let last = messages.last().cloned().unwrap_or_default();
+        return Err(FastbootError::Tool(last));
+    }
+    Ok(FastbootReport {
+        image: image.into(),
+        bytes,
+        duration: started.elapsed(),
+        messages,
+    })
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn targets() {
+    assert_eq!("usb".parse(), Ok(FastbootTarget::Usb(None)));
+    assert_eq!(
+        "usb:0123456789ABCDEF".parse(),
+        Ok(FastbootTarget::Usb(Some("0123456789ABCDEF".into())))
+    );
+    let tcp: FastbootTarget = "tcp:10.0.0.2:5555".parse().unwrap();
+    assert_eq!(tcp, FastbootTarget::Tcp("10.0.0.2:5555".into()));
+    assert_eq!(tcp.to_string(), "tcp:10.0.0.2:5555");
+    assert!("serial:/dev/ttyUSB0".parse::<FastbootTarget>().is_err());
+    assert!("tcp:".parse::<FastbootTarget>().is_err());
+}
+
+#[test]
+fn tcp_download_and_boot() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    let bootloader = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = TcpTransport(stream);
+        let mut handshake = [0; 4];
+        stream.0.read_exact(&mut handshake).unwrap();
+        stream.0.write_all(b"FB01").unwrap();
+        let mut received = vec![];
+        received.push(stream.receive().unwrap());
+        stream.send(b"DATA00000005").unwrap();
+        let mut data = vec![];
+        while data.len() < 5 {
+            data.extend(stream.receive().unwrap());
+        }
+        stream.send(b"OKAY").unwrap();
+        received.push(stream.receive().unwrap());
+        stream.send(b"INFObooting").unwrap();
+        stream.send(b"OKAY").unwrap();
+        (handshake, received, data)
+    });
+
+    let image = std::env::temp_dir().join(format!("bootcom-fastboot-{}", std::process::id()));
+    fs::write(&image, b"hello").unwrap();
+    let settings = crate::SettingsBuilder::default().finalize();
+    let target = FastbootTarget::Tcp(host);
+    let report = boot(&settings, &target, image.to_str().unwrap()).unwrap();
+    assert_eq!(report.bytes, 5);
+    assert_eq!(report.messages, vec!["booting"]);
+    let (handshake, received, data) = bootloader.join().unwrap();
+    assert_eq!(&handshake, b"FB01");
+    assert_eq!(
+        received,
+        vec![b"download:00000005".to_vec(), b"boot".to_vec()]
+    );
+    assert_eq!(data, b"hello");
+
+    // A refusal carries its reason.
+    let mut replies = vec![b"FAILunknown command".to_vec()];
+    struct Replay<'a>(&'a mut Vec<Vec<u8>>);
+    impl Transport for Replay<'_> {
+        fn send(&mut self, _message: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+        fn receive(&mut self) -> io::Result<Vec<u8>> {
+            Ok(self.0.remove(0))
+        }
+    }
+    match download_and_boot(&mut Replay(&mut replies), b"hello", |_| ()) {
+        Err(FastbootError::Failed(reason)) => assert_eq!(reason, "unknown command"),
+        other => panic!("{:?}", other),
+    }
+    fs::remove_file(image).unwrap();
+}
@@ -0,0 +1,240 @@
+//! The machinery shared by all the state machines of `bootcom`.
+//!
+//! A state machine is made of:
+//!
+//! * its **states**, implementing [`Runnable`], which do their work when run
+//!   and return the **event** requesting the next transition,
+//! * a [`StateMachine`] holding the current state, the settings and the data
+//!   shared by all the states (implementing [`Shared`]),
+//! * an enum of all the possible state machines, one per state, together with
+//!   the transitions between them, generated by the [`transitions!`] macro and
+//!   run with [`Machine::run_to_exit`].
+//!
+//! Refer to the crate documentation for the design principles of the state
+//! machines.
+
+use std::fmt;
+
+use log::error;
+
+use crate::context::Context;
+use crate::settings::Settings;
+use crate::utils::state_name;
+
+/// The data shared by all the states of a state machine, carried over on every
+/// transition.
+pub(crate) trait Shared: Default + fmt::Debug {
+    /// The name of the state machine, for the health status and the history.
+    const MACHINE: &'static str;
+
+    /// The context shared by all the state machines.
+    fn context(&self) -> &Context;
+}
+
+/// The events of a state machine can be summarized for the history.
+pub(crate) trait Summarize {
+    /// A one line summary of the event and its payload.
+    fn summary(&self) -> String;
+}
+
+/// Trait adding the ability for a state to be `run` after a transition into it.
+pub(crate) trait Runnable: fmt::Debug {
+    /// The data shared by all the states of the state machine.
+    type Shared: Shared;
+    /// The events of the state machine.
+    type Event: Summarize;
+    /// The outcome of the state machine, returned by its event loop.
+    type Exit;
+
+    /// A state implements this method so it can be `run` after the state
+    /// machine transitions into it.
+    ///
+    /// During this call, the state can do any work that needs to be done and
+    /// when finished, requests a transition to a `new state` by returning the
+    /// appropriate `event`. The `state` and the `event` are consumed to create
+    /// the `new state` using the corresponding [`From`] trait implementation
+    /// (provided such implementation exists).
+    fn run(&mut self, settings: &Settings, shared: &mut Self::Shared) -> Self::Event;
+
+    /// When the state machine should stop in this state, the outcome returned
+    /// by its event loop.
+    fn exit(&self) -> Option<Self::Exit> {
+        None
+    }
+}
+
+/// Event made up by the state machine itself when a state returns an event for
+/// which no transition exists. It triggers a transition to the `Fault` state.
+///
+/// The offending event is dropped, closing the serial port it may hold.
+#[derive(Debug)]
+pub(crate) struct FaultEvent {
+    pub settings: Settings,
+    /// What went wrong, for the logs and the user.
+    pub anomaly: String,
+}
+
+/// A state machine in the state `S`.
+///
+/// Note that using a generic type that holds the current state serves two
+/// purposes. It allows for also having shared data by all states that is not
+/// really part of state data (e.g. state machine parameters, statistics,
+/// etc...). Additionally, it's nicer when debugging to see the state machine
+/// and the current state it is holding at any time.
+#[derive(Debug)]
+pub(crate) struct StateMachine<S: Runnable> {
+    pub settings: Settings,
+    /// Shared by all states, carried over on every transition by `step()`.
+    pub shared: S::Shared,
+    pub state: S,
+}
+impl<S: Runnable> StateMachine<S> {
+    /// Run the current state, keeping track of it in the health status and of
+    /// the event it returns in the history.
+    pub(crate) fn run(&mut self) -> S::Event {
+        let machine = <S::Shared as Shared>::MACHINE;
+        let health = &self.shared.context().health;
+        health.set_state(machine, state_name::<S>());
+        let event = self.state.run(&self.settings, &mut self.shared);
+        let history = &self.shared.context().history;
+        history.record(machine, state_name::<S>(), event.summary());
+        event
+    }
+
+    /// Turn an `event` for which no transition exists from the current state
+    /// into a [`FaultEvent`].
+    pub(crate) fn fault(&self, event: S::Event) -> FaultEvent {
+        let anomaly = format!(
+            "illegal event {} at state {}",
+            event.summary(),
+            state_name::<S>()
+        );
+        error!("{}: {:#?}", anomaly, self);
+        FaultEvent {
+            settings: self.settings.clone(),
+            anomaly,
+        }
+    }
+}
+
+/// The enum of all the possible state machines, one per state, as generated by
+/// the [`transitions!`] macro.
+pub(crate) trait Machine: Sized {
+    /// The outcome of the state machine, returned by its event loop.
+    type Exit;
+
+    /// The unit of work in the state machine event loop. It runs the current
+    /// state and decides the next transition from the event it returns.
+    fn step(&mut self) -> Self;
+
+    /// The outcome of the event loop, if it should stop in the current state.
+    fn exit(&self) -> Option<Self::Exit>;
+
+    /// The event loop runs until a state requests to exit and returns the
+    /// outcome of the state machine.
+    fn run_to_exit(&mut self) -> Self::Exit {
+        loop {
+            *self = self.step();
+            if let Some(exit) = self.exit() {
+                return exit;
+            }
+        }
+    }
+}
+
+/// Generate the enum of all the possible state machines, one per state, with
+/// its [`Machine`] implementation and the `From` implementations creating the
+/// states from the events, all from a single transition table.
+///
+/// Every machine has a `Fault` state, reached on any event for which there is
+/// no transition in the table.
+///
+/// The shared data is moved from the current state machine to the next one by
+/// `step()`; it is therefore not part of the events and the `From`
+/// implementations fill it with its default value.
+///
+/// ```ignore
+/// transitions! {
+///     /// The states of the machine.
+///     enum States, shared Context, exit i8;
+///     states {
+///         Init(InitState) {
+///             Start => Running,
+///         },
+///         Running(RunningState) {
+///             Start => Running,
+///             Done => Done,
+///         },
+///         ...
+///     }
+///     from {
+///         |event: StartEvent| RunningState { port: Some(event.port) }
+///         ...
+///     }
+/// }
+/// ```
+macro_rules! transitions {
+    (
+        $(#[$meta:meta])*
+        enum $states:ident, shared $shared:ty, exit $exit:ty;
+        states {
+            $( $variant:ident($state:ty) {
+                $( $event:ident => $target:ident ),* $(,)?
+            } ),* $(,)?
+        }
+        from {
+            $( |$ev:ident: $event_ty:ty| $new_state:ident { $($fields:tt)* } )*
+        }
+    ) => {
+        $(#[$meta])*
+        enum $states {
+            $( $variant($crate::fsm::StateMachine<$state>), )*
+        }
+        impl $crate::fsm::Machine for $states {
+            type Exit = $exit;
+
+            /// An event for which no transition exists leads to the `Fault`
+            /// state. The shared data of the current state machine is moved to
+            /// the new one.
+            fn step(&mut self) -> Self {
+                let mut next = match self {
+                    $( $states::$variant(sm) => {
+                        let event = sm.run();
+                        match event {
+                            $( Event::$event(ev) => $states::$target(ev.into()), )*
+                            #[allow(unreachable_patterns)]
+                            event => $states::Fault(sm.fault(event).into()),
+                        }
+                    } )*
+                };
+                *next.shared_mut() = std::mem::take(self.shared_mut());
+                next
+            }
+
+            fn exit(&self) -> Option<$exit> {
+                match self {
+                    $( $states::$variant(sm) => $crate::fsm::Runnable::exit(&sm.state), )*
+                }
+            }
+        }
+        impl $states {
+            fn shared_mut(&mut self) -> &mut $shared {
+                match self {
+                    $( $states::$variant(sm) => &mut sm.shared, )*
+                }
+            }
+        }
+
+        $(
+            impl From<$event_ty> for $crate::fsm::StateMachine<$new_state> {
+                fn from($ev: $event_ty) -> Self {
+                    $crate::fsm::StateMachine {
+                        settings: $ev.settings,
+                        shared: Default::default(),
+                        state: $new_state { $($fields)* },
+                    }
+                }
+            }
+        )*
+    };
+}
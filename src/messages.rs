@@ -0,0 +1,406 @@
+//! Catalog of the user-facing messages, for the operators who would rather be
+//! prompted in their own language.
+//!
+//! Each message has a key and its English text built in. A catalog file,
+//! given by `catalog` in the `[messages]` section of the configuration file
+//! (see [`config`](crate::config)), replaces the texts of the keys it has; the
+//! others stay in English. The placeholders of a text, such as `{path}`, need
+//! to be kept in its translation. `bootcom messages` prints all the messages,
+//! as a catalog to start a translation from:
+//!
+//! ```toml
+//! [prompt]
+//! note = "Remarque"
+//! busy_retry = "Le fermer et réessayer ?"
+//!
+//! [port]
+//! busy = "[BC] 🔒 {path} est utilisé par un autre programme"
+//! ```
+//!
+//! The messages are printed from the catalog of the
+//! [`Settings`](crate::Settings), given with
+//! [`SettingsBuilder::messages`](crate::SettingsBuilder::messages). The console
+//! output, the reports and the logs stay as they are, the markers and notes
+//! written in the console log included, as do the templates of the progress
+//! bars, set by the progress theme.
+//!
+//! **Example**
+//! ```
+//! use bootcom::messages;
+//!
+//! let catalog = messages::parse("[prompt]\nnote = \"Remarque\"").unwrap();
+//! assert_eq!(catalog.get("prompt.note"), Some("Remarque"));
+//! assert_eq!(catalog.text("prompt.busy_retry"), "Close it and retry?");
+//! assert!(messages::parse("[prompt]\nnothing = \"Rien\"").is_err());
+//! ```
+
+use std::{collections::BTreeMap, fmt, fs, path::Path, sync::Arc};
+
+use toml::{value::Table, Value};
+
+/// The keys of the messages, with their English text.
+const MESSAGES: &[(&str, &str)] = &[
+    ("attach.attached", "[BC] 🔗 Attached to {address} (`{push}` to push again, `{detach}` to detach)"),
+    ("attach.closed", "[BC] 🔌 The daemon closed the connection"),
+    ("attach.detached", "[BC] 👋 Detached"),
+    ("attach.mid_line", "[BC] 🙁 Finish the line being typed to upload the image"),
+    ("attach.no_image", "[BC] 🙁 No kernel image to push, give one with `--image`"),
+    ("attach.not_read", "[BC] 🙁 Could not read `{image}`: {error}"),
+    ("attach.token_in_cleartext", "the token would travel in cleartext over TCP, attach to the Unix socket of the daemon instead (e.g. forwarded with `ssh -L`)"),
+    ("attach.uploading", "[BC] 📤 Uploading {name} ({size})"),
+    ("banner.title", "[BC] ⚙️  Effective settings"),
+    ("boot.archived", "[BC] 🗄️  Archived as #{id}"),
+    ("boot.baud_not_found", "[BC] 🙁 No working baud rate found, rescan disabled for this session"),
+    ("boot.baud_switching", "[BC] 🔧 Switching from {from} to {to} baud"),
+    ("boot.blob_captured", "[BC] 📦 Captured {size} to `{path}`"),
+    ("boot.blob_not_captured", "[BC] 💥 Could not capture a blob: {error}"),
+    ("boot.captured", "[BC] 🔬 Captured {capture}"),
+    ("boot.config_not_reloaded", "[BC] ⚠️  Configuration not reloaded: {error}"),
+    ("boot.config_queued", "[BC] 🔄 Configuration changes queued for the next connection: {changes}"),
+    ("boot.config_reloaded", "[BC] 🔄 Configuration reloaded: {changes}"),
+    ("boot.detail", "[BC]    {line}"),
+    ("boot.dump_aborted", "[BC] 🙁 Memory dump aborted: {error}"),
+    ("boot.dump_bad_crc", "[BC] 🧠 Saved {size} from {address} to `{path}` (memory dump received with a bad CRC)"),
+    ("boot.dump_receiving", "[BC] 🧠 Receiving a memory dump..."),
+    ("boot.dump_saved", "[BC] 🧠 Saved {size} from {address} to `{path}`"),
+    ("boot.failed", "[BC] 💥 Boot failed: {error}"),
+    ("boot.fault", "[BC] 🚧 Recovering from a bug: {anomaly}"),
+    ("boot.flashed", "[BC] 🧭 Flashed"),
+    ("boot.flasher_failed", "[BC] 💥 The flasher failed ({status})"),
+    ("boot.flasher_not_run", "[BC] 💥 Could not run the flasher: {error}"),
+    ("boot.flashing", "[BC] 🧭 Flashing {image} with the `{loader}` flasher"),
+    ("boot.image_changed", "[BC] 🔁 The kernel image changed during the transfer, the new one will be sent on the next request"),
+    ("boot.image_error", "[BC] 💥 Can't send the kernel image: {error}"),
+    ("boot.image_rebuilt", "[BC] 👀 {image} was rebuilt"),
+    ("boot.last_image", "[BC] 📦 Last kernel image: {report}, {retries} retries, CRC-32 {crc}"),
+    ("boot.line_not_changed", "[BC] 🙁 Could not change the line parameters: {error}"),
+    ("boot.line_switched", "[BC] 🔧 Switched to {changes}"),
+    ("boot.modem_lines", "[BC] 🔌 {lines}"),
+    ("boot.next_image", "[BC] 🎯 Next kernel image: {image}"),
+    ("boot.next_image_of_trigger", "[BC] 🎯 Next kernel image: the one of the trigger"),
+    ("boot.noise", "[BC] 📡 {percent}% of the received data looks like garbage, baud rate mismatch?"),
+    ("boot.not_a_serial_port", "[BC] 🙁 Only the line parameters of a serial port can be changed"),
+    ("boot.not_archived", "[BC] 🗄️  Could not archive the push: {error}"),
+    ("boot.not_persisted", "[BC] 💾 {error}"),
+    ("boot.port_error", "[BC] 💥 Unrecoverable error on the serial port: {error}"),
+    ("boot.reconnect", "[BC] 🔌 Disconnect and reconnect the device!"),
+    ("boot.reset_failed", "[BC] ⚠️  Reset command failed: {status}"),
+    ("boot.reset_not_run", "[BC] ⚠️  Reset command not run: {error}"),
+    ("boot.rom_loader", "[BC] 🧭 The board is waiting in the {loader}"),
+    ("boot.rom_loader_silent", "[BC] 🧭 {link} stays silent, the board may be waiting in the {loader}"),
+    ("boot.script_completed", "[BC] 📜 Script completed"),
+    ("boot.script_not_loaded", "[BC] 💥 Could not load script `{path}`: {error}"),
+    ("boot.script_timed_out", "[BC] 🙁 Script stopped: `{pattern}` not received in time"),
+    ("boot.send_failed", "[BC] 💥 Failed to send kernel image!"),
+    ("boot.services_timed_out", "[BC] 🙁 Host service session aborted: request timed out"),
+    ("boot.stage_reached", "[BC] ✅ Boot stage `{stage}` reached after {after}"),
+    ("boot.straps_failed", "[BC] 📌 Could not drive the boot mode straps: {error}"),
+    ("boot.time_not_sent", "[BC] ⚠️  Could not send the host time: {error}"),
+    ("boot.time_sent", "[BC] 🕒 Sent the host time to the device ({now} ms since the epoch)"),
+    ("boot.transfer_failed", "[BC] 💥 Giving up after {attempts} failed attempts to send the kernel image: {error}"),
+    ("boot.uboot_abandoned", "[BC] 🥾 U-Boot handoff abandoned, back to the console"),
+    ("boot.uboot_finished", "[BC] 🥾 U-Boot script completed, back to the console"),
+    ("boot.uboot_started", "[BC] 🥾 U-Boot started, taking over its prompt"),
+    ("boot.uboot_timed_out", "[BC] 🥾 U-Boot handoff abandoned: U-Boot didn't get to the next step in time"),
+    ("boot.waiting_request", "[BC] ⏳ Waiting for the device to request the kernel image again ({attempts})"),
+    ("boot.warning", "[BC] ⚠️  {warning}"),
+    ("chaos.injected", "[BC] 🐒 Injected {corrupted} corrupted byte(s), {dropped} dropped write(s) and {delayed} delayed read(s) (seed {seed})"),
+    ("chaos.started", "[BC] 🐒 Chaos mode: {chaos}"),
+    ("cli.board", "{health} {name} on {path}: {port}"),
+    ("cli.board_image", "   image      {image}"),
+    ("cli.board_last_boot", "   last boot  {boot}"),
+    ("cli.board_profile", "   profile    {profile}"),
+    ("cli.boot_file", "file"),
+    ("cli.boot_log", "log"),
+    ("cli.claimed", "[BC] 🔒 Claimed {board} on {path}"),
+    ("cli.error", "error"),
+    ("cli.hex_address", "`{arg}` needs to be a hex address"),
+    ("cli.hex_bytes", "`{arg}` needs to be a sequence of hex bytes"),
+    ("cli.interrupted", "🛑 received Ctrl+C!"),
+    ("cli.invalid_arg", "invalid `{arg}`: {error}"),
+    ("cli.invalid_catalog", "invalid message catalog"),
+    ("cli.invalid_config", "invalid configuration file"),
+    ("cli.invalid_value", "`{value}` is not a valid value"),
+    ("cli.key_not_created", "could not create the key `{path}`: {error}"),
+    ("cli.key_written", "[BC] 🔑 Key written to `{path}`, its public key to `{path}.pub`"),
+    ("cli.known_profiles", "known profiles: {names}"),
+    ("cli.last_week", "last 7 days"),
+    ("cli.new_errors", "[BC] ❗ {count} new error(s) since {before}:"),
+    ("cli.no_board_image", "no kernel image given, and board `{board}` has none"),
+    ("cli.no_boards", "No boards, declare them in the configuration file with `[[board]]` sections"),
+    ("cli.no_boot_recorded", "none recorded"),
+    ("cli.no_boots", "No boots archived, push with `--archive` to archive them"),
+    ("cli.no_boots_of", "No boots of `{board}` archived"),
+    ("cli.no_idle_board", "no board of the profile `{profile}` was idle within {seconds}s"),
+    ("cli.no_known_good", "no boot of {board} reached all its stages before #{id}"),
+    ("cli.no_new_errors", "[BC] ✅ No new errors since {before}"),
+    ("cli.no_pool_board", "no board of the inventory has the profile `{profile}`"),
+    ("cli.no_profile", "no connection profile named `{name}` in the configuration file"),
+    ("cli.no_state_dir", "the user state directory can't be found"),
+    ("cli.no_usage", "No usage statistics, keep them with `stats = true` in the `[usage]` section of the configuration file"),
+    ("cli.not_archived_boot", "`{id}` is not the number of an archived boot"),
+    ("cli.not_attached", "could not attach to `{address}`: {error}"),
+    ("cli.not_boot_number", "`{id}` is not a boot number"),
+    ("cli.not_claimed", "could not claim a board: {error}"),
+    ("cli.not_created", "could not create `{path}`: {error}"),
+    ("cli.not_pushed", "[BC] 💥 {error}"),
+    ("cli.not_staged", "could not stage the image through fastboot"),
+    ("cli.not_written", "could not write `{path}`: {error}"),
+    ("cli.nothing_archived", "no boots archived, push with `--archive` to archive them"),
+    ("cli.nothing_to_resume", "[BC] ⚠️  Nothing to resume: {error}"),
+    ("cli.numeric", "`{arg}` needs to be a numeric value"),
+    ("cli.public_key", "[BC]    public key: {key}"),
+    ("cli.pushed", "[BC] ✅ {report}"),
+    ("cli.raw_stopped", "raw bridge stopped: {error}"),
+    ("cli.released", "[BC] 🔓 Released {board}"),
+    ("cli.resuming", "[BC] ⏯️  Resuming on {port}"),
+    ("cli.staged", "[BC] 🚀 {report}"),
+    ("cli.staging", "[BC] 📲 Staging {image} through fastboot ({target})"),
+    ("cli.trigger", "`{arg}` needs to be `<hex bytes>:<raspbootin|chunked|xmodem-crc|ymodem>[:<image>]`"),
+    ("cli.usage_since", " since {day}"),
+    ("cli.usb_id", "`{arg}` needs to be a USB ID in hexadecimal"),
+    ("cli.version", "[BC] bootcom v{version}"),
+    ("cli.week_before", "7 days before"),
+    ("debug_bundle.not_saved", "[BC] 💥 Could not save the debug bundle to `{path}`: {error}"),
+    ("debug_bundle.saved", "[BC] 🧰 Debug bundle saved to `{path}`, please attach it to the bug report"),
+    ("health.silent", "[BC] 🔕 No console output for {seconds}s"),
+    ("history.dropped", " ({count} older ones dropped)"),
+    ("history.title", "[BC] 📜 last {count} event(s)"),
+    ("instrument.exited_early", "[BC] 🔬 The `{name}` capture exited early ({status})"),
+    ("instrument.not_started", "[BC] 🔬 Could not start the `{name}` capture: {error}"),
+    ("kernel.not_opened", "[BC] 🙁 could not open `{name}`, try again ({attempts})..."),
+    ("kernel.not_persisted", "[BC] 🙁 Only the chunked protocol can persist the image, booting it as is"),
+    ("kernel.persisted", "💾 Image persisted"),
+    ("kernel.persisting", "💾 Persisting the image..."),
+    ("kernel.persisting_percent", "💾 Persisting the image... {percent}%"),
+    ("kernel.resuming", "[BC] ⏯️  Resuming the upload at {from} of {size}"),
+    ("kernel.revision_mismatch", "[BC] ⚠️  The bootloader speaks the revision {device} of the boot protocol, bootcom the revision {bootcom}"),
+    ("kernel.revision_misread", "[BC]    The size or the image may be read differently (byte order, format) without any error, the kernel then failing to boot"),
+    ("kernel.revision_newer", "[BC]    To fix it, update bootcom to a release speaking the revision {device}, or rebuild the bootloader pinned to the revision {bootcom}"),
+    ("kernel.revision_older", "[BC]    To fix it, update the bootloader to the revision {bootcom} (`bootcom stub` renders a receiver of it), or use the release of bootcom speaking the revision {device}"),
+    ("line_check.cts", "Hardware flow control is on, but CTS has not been asserted for {seconds}s, nothing can be sent to the device (try --flow-control=none)"),
+    ("line_check.data_bits", "Less than 8 data bits can't carry a binary kernel image, most boards use 8N1 (try --data-bits=8)"),
+    ("line_check.parity", "Parity is set to {parity}, but the device does not seem to produce valid frames, most boards use no parity (try --parity=none)"),
+    ("line_check.xonxoff", "XON/XOFF flow control reacts too slowly at {baud_rate} baud to protect small FIFOs, hardware flow control is safer (try --flow-control=hard)"),
+    ("mdns.not_advertised", "[BC] 🙁 Could not advertise the console: {error}"),
+    ("modem_manager.probing", "[BC] ⚠️  ModemManager is probing {path}"),
+    ("modem_manager.reload", "[BC]    then run `sudo udevadm control --reload` and replug the device.\n[BC]    Until then, --settle-delay=5000 leaves it the time to finish."),
+    ("modem_manager.udev_rule", "[BC]    To make it ignore the device, add this udev rule to\n[BC]    /etc/udev/rules.d/99-bootcom.rules:\n[BC]      {rule}"),
+    ("modem_manager.usb_ids", "[BC]    with the vendor and product ids of the device (see `lsusb`),"),
+    ("output.not_logged", "[BC] 💥 Could not log to `{path}`: {error}"),
+    ("output.not_recorded", "[BC] 💥 Could not record to `{path}`: {error}"),
+    ("output.stopped", "[BC] 💥 Output stopped: {error}"),
+    ("port.baud_rate_found", "👍 Device seems to be using {baud_rate} baud"),
+    ("port.baud_rate_not_found", "❌ Could not find a working baud rate"),
+    ("port.busy", "[BC] 🔒 {path} is used by another program"),
+    ("port.gave_up", "❌ Gave up waiting for {path} after {attempts} attempts"),
+    ("port.held_by", "[BC]    held by {name} (pid {pid})"),
+    ("port.no_controller", "❌ No USB serial controller connected after {attempts} attempts"),
+    ("port.ready", "👍 Serial port {path} is ready"),
+    ("port.scan_not_opened", "❌ Could not open {path} for scanning"),
+    ("port.scanning", "🔍 Scanning {path} at {baud_rate} baud..."),
+    ("port.select", "Select a port to be used:"),
+    ("port.selection_canceled", "❌ Selection canceled -> refreshing..."),
+    ("port.settling", "[BC] ⏳ Letting {path} settle for {delay} ms..."),
+    ("port.wait_canceled", "❌ Waiting on port {path} canceled after {waited} seconds"),
+    ("port.waiting", "[{waited}s {ports}] ⏳ Waiting for {path} to be ready (ESC to cancel)..."),
+    ("port.waiting_again", "[{waited}s {ports}] ⏳ Waiting for {path} to be ready ({attempts}, ESC to cancel)..."),
+    ("port.waiting_controller", "[{waited}s {ports}] ⌛ Waiting for USB serial controller to be connected ({attempts})..."),
+    ("prompt.baud_rate", "Baud rate"),
+    ("prompt.baud_rate_zero", "the baud rate can't be 0"),
+    ("prompt.busy_retry", "Close it and retry?"),
+    ("prompt.cancel", "🔙cancel and go back..."),
+    ("prompt.flow_control", "Flow control"),
+    ("prompt.kernel_image", "Select a kernel image file to push (`{key}` to refresh):"),
+    ("prompt.line_parameter", "Change which line parameter?"),
+    ("prompt.note", "Note"),
+    ("prompt.palette", "[BC] ⌨️  Ctrl+A: `n` to type a note, `a` to send Ctrl+A, `q` to quit"),
+    ("prompt.parity", "Parity"),
+    ("prompt.rescan", "Rescan for the baud rate used by the device?"),
+    ("raw.bridging", "[BC] 🔀 Bridging the standard streams to {port}"),
+    ("raw.bridging_again", "[BC] 🔀 Bridging again to {port}"),
+    ("raw.lost", "[BC] 🔌 Lost {path}: {error}"),
+    ("remote.granted", "granted {permissions}"),
+    ("remote.image", "next kernel image: {path}"),
+    ("remote.image_not_allowed", "`{path}` is neither a kernel image of the settings nor in the host directory"),
+    ("remote.invalid_argument", "invalid argument `{argument}` for `~{command}`"),
+    ("remote.invalid_name", "invalid image name `{name}`"),
+    ("remote.line_changed", "line parameters changed in terminal mode"),
+    ("remote.needs", "the `{permission}` permission is needed"),
+    ("remote.no_token", "no token needed"),
+    ("remote.not_saved", "could not save the image: {error}"),
+    ("remote.token_in_cleartext", "tokens are only accepted on the Unix sockets, they would travel in cleartext over TCP"),
+    ("remote.unknown_command", "unknown command `~{command}`, use `~auth`, `~image`, `~upload`, `~baud`, `~parity` or `~flow`"),
+    ("remote.unknown_token", "unknown token"),
+    ("remote.upload_usage", "`~upload` needs the size of the image, up to {size}, and its name"),
+    ("remote.uploaded", "next kernel image: {path} ({size})"),
+    ("report.not_written", "[BC] 💥 Could not write the session report to `{path}`: {error}"),
+    ("report.written", "[BC] 📄 Session report written to `{path}`"),
+    ("server.gave_up", "[BC] 💥 {error}"),
+    ("severity.first_error", "[BC]    first error: {error}"),
+    ("severity.summary", "[BC] 🩺 {errors} error(s) and {warnings} warning(s) in {lines} console lines"),
+    ("stats.last_error", "[BC]    last error: {error}"),
+    ("stats.summary", "[BC] 📊 {sessions} session(s) over {connected}, {received} received, {kernels} kernel(s) sent ({sent} in {transfer}), {errors} error(s)"),
+    ("stream.not_written", "[BC] 💥 Stream `{name}` not written to `{path}`: {error}"),
+];
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The translated texts of the messages, by key. The default catalog has
+/// none, all the messages being in English.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Catalog {
+    texts: Arc<BTreeMap<String, String>>,
+}
+
+impl Catalog {
+    /// The translated text of the message `key`, if the catalog has one.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.texts.get(key).map(String::as_str)
+    }
+
+    /// The text of the message `key`, translated if the catalog has it.
+    pub fn text(&self, key: &str) -> String {
+        match self.get(key) {
+            Some(text) => text.to_string(),
+            None => english(key).unwrap_or(key).to_string(),
+        }
+    }
+
+    /// The text of the message `key`, as [`text`](Catalog::text), with its
+    /// placeholders replaced by the `values`, e.g. `("path", &"/dev/ttyUSB0")`
+    /// for `{path}`.
+    pub fn text_with(&self, key: &str, values: &[(&str, &dyn fmt::Display)]) -> String {
+        values.iter().fold(self.text(key), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), &value.to_string())
+        })
+    }
+}
+
+/// Read and parse the catalog file at `path`.
+pub fn load(path: &Path) -> Result<Catalog, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the `text` of a catalog file.
+pub fn parse(text: &str) -> Result<Catalog, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut texts = BTreeMap::new();
+    for (section, table) in &root {
+        let table = match table {
+            Value::Table(table) => table,
+            _ => return Err(format!("`{}` needs to be a section", section)),
+        };
+        for (name, value) in table {
+            let key = format!("{}.{}", section, name);
+            let english = english(&key).ok_or_else(|| format!("`{}` is not a message", key))?;
+            let text = value
+                .as_str()
+                .ok_or_else(|| format!("`{}` needs to be a string", key))?;
+            if let Some(missing) = placeholders(english).find(|p| !text.contains(p)) {
+                return Err(format!(
+                    "`{}` needs to keep the `{}` placeholder",
+                    key, missing
+                ));
+            }
+            texts.insert(key, text.to_string());
+        }
+    }
+    Ok(Catalog {
+        texts: Arc::new(texts),
+    })
+}
+
+/// All the messages in English, as a catalog file.
+pub fn template() -> String {
+    let mut root = Table::new();
+    for (key, text) in MESSAGES {
+        let (section, name) = key.split_once('.').unwrap();
+        if let Value::Table(table) = root
+            .entry(section.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            table.insert(name.into(), Value::String(text.to_string()));
+        }
+    }
+    format!("# The messages of bootcom.\n{}", Value::Table(root))
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+fn english(key: &str) -> Option<&'static str> {
+    MESSAGES
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, text)| *text)
+}
+
+/// The placeholders of the `text`, braces included.
+fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.match_indices('{').filter_map(move |(start, _)| {
+        let end = start + text[start..].find('}')?;
+        Some(&text[start..=end])
+    })
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn catalog_texts() {
+    let catalog =
+        parse("[port]\nbusy = \"[BC] 🔒 {path} est utilisé par un autre programme\"").unwrap();
+    assert!(parse("[port]\nbusy = \"Occupé\"")
+        .unwrap_err()
+        .contains("{path}"));
+    assert_eq!(parse(&template()).unwrap().texts.len(), MESSAGES.len());
+
+    assert_eq!(
+        Catalog::default().text_with("port.busy", &[("path", &"/dev/ttyUSB0")]),
+        "[BC] 🔒 /dev/ttyUSB0 is used by another program"
+    );
+    assert_eq!(
+        catalog.text_with("port.busy", &[("path", &"/dev/ttyUSB0")]),
+        "[BC] 🔒 /dev/ttyUSB0 est utilisé par un autre programme"
+    );
+    assert_eq!(catalog.text("prompt.note"), "Note");
+}
+
+#[test]
+fn keys_used_are_messages() {
+    fn sources(dir: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                sources(&path, found);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                found.push(fs::read_to_string(&path).unwrap());
+            }
+        }
+    }
+
+    let mut found = vec![];
+    sources(
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src")),
+        &mut found,
+    );
+    // The calls with a literal key, e.g. `text_with(\n    "port.busy", ...`.
+    let calls = ["text", "text_with"].map(|name| format!(".{}(", name));
+    for source in &found {
+        for call in &calls {
+            for (at, _) in source.match_indices(call.as_str()) {
+                let rest = source[at + call.len()..].trim_start();
+                if let Some(rest) = rest.strip_prefix('"') {
+                    let key = &rest[..rest.find('"').unwrap()];
+                    assert!(english(key).is_some(), "`{}` is not a message", key);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,597 @@
+//! Capture of the serial port traffic, for Wireshark.
+//!
+//! When a capture is set in the settings, every byte read from and written to
+//! the port is recorded in a pcapng file, one packet per read or write, with
+//! the time it happened. The packets use the `USER0` link type (147), each
+//! starting with a pseudo-header of 2 bytes:
+//!
+//! ```text
+//! direction (u8): 0 from the device, 1 to the device
+//! traffic (u8):   0 console, 1 raspbootin, 2 chunked, 3 xmodem-crc, 4 ymodem,
+//!                 5 host services, 6 memory dump
+//! ```
+//!
+//! The traffic tells what `bootcom` was doing with the port at the time, so
+//! that the bytes of a transfer are not mistaken for console output. The
+//! direction is also given by the `epb_flags` option of the packets, and each
+//! port the session connects to gets its own interface, named after it.
+//!
+//! [`dissector`] generates the Lua dissector decoding these packets for the
+//! configured triggers: the console text, the trigger patterns, the frames and
+//! control bytes of the transfer protocols, the host services and the memory
+//! dumps.
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{pcap::PcapCapture, SettingsBuilder};
+//!
+//! let capture = PcapCapture::create("session.pcapng").unwrap();
+//! let settings = SettingsBuilder::default().pcap(capture).finalize();
+//! std::fs::write("bootcom.lua", bootcom::pcap::dissector(&settings)).unwrap();
+//! ```
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::settings::{Settings, TransferProtocol};
+use crate::utils::{chunked, dump, host_services, kernel, xmodem, DUMP_TRIGGER, SERVICE_TRIGGER};
+
+const DISSECTOR_TEMPLATE: &str = include_str!("pcap/dissector.lua.tpl");
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The link type of the packets, `LINKTYPE_USER0`.
+pub const LINK_TYPE: u16 = 147;
+
+/// What `bootcom` was doing with the port when the bytes of a packet were
+/// exchanged.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Traffic {
+    Console,
+    Transfer(TransferProtocol),
+    HostServices,
+    Dump,
+}
+impl Traffic {
+    /// All the kinds of traffic, in the order of their codes.
+    pub const ALL: [Traffic; 7] = [
+        Traffic::Console,
+        Traffic::Transfer(TransferProtocol::Raspbootin),
+        Traffic::Transfer(TransferProtocol::Chunked),
+        Traffic::Transfer(TransferProtocol::XmodemCrc),
+        Traffic::Transfer(TransferProtocol::Ymodem),
+        Traffic::HostServices,
+        Traffic::Dump,
+    ];
+
+    /// The code of the traffic in the pseudo-header of the packets.
+    pub fn code(self) -> u8 {
+        Traffic::ALL.iter().position(|t| *t == self).unwrap() as u8
+    }
+}
+impl fmt::Display for Traffic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Traffic::Console => f.write_str("console"),
+            Traffic::Transfer(protocol) => protocol.fmt(f),
+            Traffic::HostServices => f.write_str("host services"),
+            Traffic::Dump => f.write_str("memory dump"),
+        }
+    }
+}
+
+/// Which way the bytes of a packet went.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    FromDevice,
+    ToDevice,
+}
+
+/// A pcapng capture of the traffic on the ports of a session.
+///
+/// Two captures are equal only when they refer to the same file.
+#[derive(Clone)]
+pub struct PcapCapture(Arc<Mutex<Capture>>);
+impl PcapCapture {
+    /// Capture to a new file at `path`, replacing any existing one.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        PcapCapture::new(File::create(path)?)
+    }
+
+    /// Capture to the `writer`, starting with the section header.
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut capture = Capture {
+            writer: Box::new(writer),
+            interfaces: vec![],
+            traffic: Traffic::Console,
+            failed: false,
+        };
+        let application = format!("bootcom v{}", env!("CARGO_PKG_VERSION"));
+        let mut body = vec![];
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // The length of the section is not known in advance.
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut body, SHB_USERAPPL, application.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        capture.write_block(SECTION_HEADER_BLOCK, &body)?;
+        Ok(PcapCapture(Arc::new(Mutex::new(capture))))
+    }
+
+    /// Record the `data` which went in the `direction`, on the port named
+    /// `port`, at `time`.
+    pub fn record(&self, port: &str, direction: Direction, data: &[u8], time: SystemTime) {
+        let mut capture = self.0.lock().unwrap();
+        if capture.failed {
+            return;
+        }
+        if let Err(e) = capture.record(port, direction, data, time) {
+            info!("stopped capturing the traffic: {}", e);
+            capture.failed = true;
+        }
+    }
+
+    /// Tell what the bytes recorded from now on are.
+    pub fn set_traffic(&self, traffic: Traffic) {
+        self.0.lock().unwrap().traffic = traffic;
+    }
+}
+impl PartialEq for PcapCapture {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for PcapCapture {}
+impl fmt::Debug for PcapCapture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PcapCapture")
+    }
+}
+
+/// The Lua dissector of the packets captured with the `settings`.
+pub fn dissector(settings: &Settings) -> String {
+    let lua_table = |entries: Vec<(u8, String)>| -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|(key, value)| format!("[{:#04x}] = \"{}\"", key, value))
+            .collect();
+        entries.join(", ")
+    };
+    let lua_string =
+        |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("\\{}", b)).collect() };
+
+    let mut triggers: Vec<(Vec<u8>, String)> = settings
+        .triggers
+        .iter()
+        .map(|trigger| {
+            (
+                trigger.pattern.clone(),
+                format!("kernel image, {}", trigger.protocol),
+            )
+        })
+        .collect();
+    if settings.host_dir.is_some() {
+        triggers.push((SERVICE_TRIGGER.to_vec(), "host services".into()));
+    }
+    if settings.dump_dir.is_some() {
+        triggers.push((DUMP_TRIGGER.to_vec(), "memory dump".into()));
+    }
+    let trigger_list: Vec<String> = triggers.iter().map(|(_, what)| what.clone()).collect();
+    let triggers: Vec<String> = triggers
+        .iter()
+        .map(|(pattern, what)| format!("{{ \"{}\", \"{}\" }}", lua_string(pattern), what))
+        .collect();
+
+    let traffics = Traffic::ALL
+        .iter()
+        .map(|traffic| (traffic.code(), traffic.to_string()))
+        .collect();
+    let decoders: Vec<String> = Traffic::ALL
+        .iter()
+        .map(|traffic| {
+            let decoder = match traffic {
+                Traffic::Console => "console",
+                Traffic::Transfer(TransferProtocol::Raspbootin) => "raspbootin",
+                Traffic::Transfer(TransferProtocol::Chunked) => "chunked",
+                Traffic::Transfer(_) => "xmodem",
+                Traffic::HostServices => "host_services",
+                Traffic::Dump => "dump",
+            };
+            format!("[{}] = {}", traffic.code(), decoder)
+        })
+        .collect();
+    let controls = vec![
+        (xmodem::SOH, "SOH"),
+        (xmodem::STX, "STX"),
+        (xmodem::EOT, "EOT"),
+        (xmodem::ACK, "ACK"),
+        (xmodem::NAK, "NAK"),
+        (xmodem::CAN, "CAN"),
+        (b'C', "C"),
+        (chunked::PERSIST, "P"),
+        (chunked::SYN, "SYN"),
+    ];
+    let names = |entries: &[(u8, &str)]| -> Vec<(u8, String)> {
+        entries
+            .iter()
+            .map(|(key, name)| (*key, name.to_string()))
+            .collect()
+    };
+
+    DISSECTOR_TEMPLATE
+        .replace("{{TRIGGER_LIST}}", &trigger_list.join(", "))
+        .replace("{{TRIGGERS}}", &triggers.join(", "))
+        .replace("{{TRAFFICS}}", &lua_table(traffics))
+        .replace("{{DECODERS}}", &decoders.join(", "))
+        .replace("{{CONTROLS}}", &lua_table(names(&controls)))
+        .replace(
+            "{{OPCODES}}",
+            &lua_table(names(&host_services::OPCODE_NAMES)),
+        )
+        .replace(
+            "{{STATUSES}}",
+            &lua_table(names(&host_services::STATUS_NAMES)),
+        )
+        .replace("{{STX}}", &format!("{:#04x}", xmodem::STX))
+        .replace("{{ACK}}", &format!("{:#04x}", xmodem::ACK))
+        .replace("{{NAK}}", &format!("{:#04x}", xmodem::NAK))
+        .replace("{{SYN}}", &format!("{:#04x}", chunked::SYN))
+        .replace("{{FRAME_OVERHEAD}}", &chunked::FRAME_OVERHEAD.to_string())
+        .replace("{{PROGRESS}}", &format!("{:#04x}", chunked::PROGRESS))
+        .replace("{{BLOCK_SIZE}}", &xmodem::BLOCK_SIZE.to_string())
+        .replace(
+            "{{YMODEM_BLOCK_SIZE}}",
+            &xmodem::YMODEM_BLOCK_SIZE.to_string(),
+        )
+        .replace(
+            "{{SIZE_CONFIRMATION}}",
+            &String::from_utf8_lossy(kernel::SIZE_CONFIRMATION),
+        )
+        .replace(
+            "{{RESUME_OFFER}}",
+            &String::from_utf8_lossy(kernel::RESUME_OFFER),
+        )
+        .replace(
+            "{{REVISION_REPORT}}",
+            &String::from_utf8_lossy(kernel::REVISION_REPORT),
+        )
+        .replace("{{DUMP_HEADER_LEN}}", &dump::HEADER_LEN.to_string())
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Record the traffic on the `port` in the capture of the `settings`, if any.
+pub(crate) fn wrap(settings: &Settings, port: Box<dyn SerialPort>) -> Box<dyn SerialPort> {
+    match &settings.pcap {
+        Some(capture) => Box::new(CapturedPort {
+            name: port.name().unwrap_or_default(),
+            port,
+            capture: capture.clone(),
+        }),
+        None => port,
+    }
+}
+
+/// Marks the traffic in the capture of the `settings` until it is dropped,
+/// the traffic going back to the console then.
+pub(crate) fn mark(settings: &Settings, traffic: Traffic) -> TrafficMark {
+    if let Some(capture) = &settings.pcap {
+        capture.set_traffic(traffic);
+    }
+    TrafficMark(settings.pcap.clone())
+}
+
+pub(crate) struct TrafficMark(Option<PcapCapture>);
+impl Drop for TrafficMark {
+    fn drop(&mut self) {
+        if let Some(capture) = &self.0 {
+            capture.set_traffic(Traffic::Console);
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+/// The inbound and outbound directions of `epb_flags`.
+const EPB_INBOUND: u32 = 1;
+const EPB_OUTBOUND: u32 = 2;
+
+struct Capture {
+    writer: Box<dyn Write + Send>,
+    /// The names of the ports, in the order of their interface description
+    /// blocks.
+    interfaces: Vec<String>,
+    traffic: Traffic,
+    /// Whether writing to the capture failed, it is not tried again.
+    failed: bool,
+}
+impl Capture {
+    fn record(
+        &mut self,
+        port: &str,
+        direction: Direction,
+        data: &[u8],
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let interface = match self.interfaces.iter().position(|name| name == port) {
+            Some(interface) => interface,
+            None => {
+                let mut body = vec![];
+                body.extend_from_slice(&LINK_TYPE.to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                // No limit on the length of the packets.
+                body.extend_from_slice(&0u32.to_le_bytes());
+                push_option(&mut body, IF_NAME, port.as_bytes());
+                // Microseconds, the default, made explicit.
+                push_option(&mut body, IF_TSRESOL, &[6]);
+                push_option(&mut body, OPT_ENDOFOPT, &[]);
+                self.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)?;
+                self.interfaces.push(port.into());
+                self.interfaces.len() - 1
+            }
+        };
+
+        let micros = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_micros() as u64;
+        let (code, flags) = match direction {
+            Direction::FromDevice => (0, EPB_INBOUND),
+            Direction::ToDevice => (1, EPB_OUTBOUND),
+        };
+        let mut packet = vec![code, self.traffic.code()];
+        packet.extend_from_slice(data);
+
+        let mut body = vec![];
+        body.extend_from_slice(&(interface as u32).to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        pad(&mut body);
+        push_option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    /// Write a block of the given type, its `body` being padded to 32 bits.
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let length = (12 + body.len()) as u32;
+        let mut block = Vec::with_capacity(length as usize);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&length.to_le_bytes());
+        self.writer.write_all(&block)?;
+        self.writer.flush()
+    }
+}
+
+/// Push an option, with its value padded to 32 bits.
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    let padding = (4 - body.len() % 4) % 4;
+    body.resize(body.len() + padding, 0);
+}
+
+/// A port recording what goes through it in a capture.
+struct CapturedPort {
+    port: Box<dyn SerialPort>,
+    /// The name of the port, as its interface in the capture.
+    name: String,
+    capture: PcapCapture,
+}
+impl Read for CapturedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.port.read(buf)?;
+        if read > 0 {
+            let time = SystemTime::now();
+            self.capture
+                .record(&self.name, Direction::FromDevice, &buf[..read], time);
+        }
+        Ok(read)
+    }
+}
+impl Write for CapturedPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.port.write(buf)?;
+        if written > 0 {
+            let time = SystemTime::now();
+            self.capture
+                .record(&self.name, Direction::ToDevice, &buf[..written], time);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+impl SerialPort for CapturedPort {
+    fn name(&self) -> Option<String> {
+        self.port.name()
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        self.port.baud_rate()
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        self.port.data_bits()
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        self.port.flow_control()
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        self.port.parity()
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        self.port.stop_bits()
+    }
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.port.set_baud_rate(baud_rate)
+    }
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.port.set_data_bits(data_bits)
+    }
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.port.set_flow_control(flow_control)
+    }
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.port.set_parity(parity)
+    }
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.port.set_stop_bits(stop_bits)
+    }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.port.write_request_to_send(level)
+    }
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.port.write_data_terminal_ready(level)
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        self.port.read_clear_to_send()
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        self.port.read_data_set_ready()
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        self.port.read_ring_indicator()
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        self.port.read_carrier_detect()
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_read()
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        self.port.bytes_to_write()
+    }
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        self.port.clear(buffer_to_clear)
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(CapturedPort {
+            port: self.port.try_clone()?,
+            name: self.name.clone(),
+            capture: self.capture.clone(),
+        }))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        self.port.set_break()
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.port.clear_break()
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn block_layout() {
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let u32_at = |bytes: &[u8], at: usize| {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    };
+
+    let file = Shared::default();
+    let capture = PcapCapture::new(file.clone()).unwrap();
+    let time = UNIX_EPOCH + Duration::from_micros(0x1_0000_0002);
+    capture.record("/dev/ttyUSB0", Direction::FromDevice, b"\x03\x03\x03", time);
+    let mark = mark(
+        &crate::SettingsBuilder::default()
+            .pcap(capture.clone())
+            .finalize(),
+        Traffic::Transfer(TransferProtocol::Ymodem),
+    );
+    capture.record("/dev/ttyUSB0", Direction::ToDevice, b"\x06", time);
+    drop(mark);
+    capture.record("/dev/ttyUSB1", Direction::FromDevice, b"", time);
+
+    let bytes = file.0.lock().unwrap().clone();
+    let mut blocks = vec![];
+    let mut at = 0;
+    while at < bytes.len() {
+        let length = u32_at(&bytes, at + 4) as usize;
+        assert_eq!(length % 4, 0);
+        assert_eq!(u32_at(&bytes, at + length - 4) as usize, length);
+        blocks.push((u32_at(&bytes, at), &bytes[at + 8..at + length - 4]));
+        at += length;
+    }
+    let types: Vec<u32> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+    assert_eq!(types, vec![SECTION_HEADER_BLOCK, 1, 6, 6, 1, 6]);
+    assert_eq!(u32_at(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+    assert_eq!(&blocks[1].1[..2], &LINK_TYPE.to_le_bytes());
+
+    // Interface, timestamp, lengths and the data with its pseudo-header.
+    let packet = blocks[2].1;
+    assert_eq!(u32_at(packet, 0), 0);
+    assert_eq!((u32_at(packet, 4), u32_at(packet, 8)), (1, 2));
+    assert_eq!((u32_at(packet, 12), u32_at(packet, 16)), (5, 5));
+    assert_eq!(&packet[20..25], b"\x00\x00\x03\x03\x03");
+    assert_eq!(&blocks[3].1[20..23], b"\x01\x04\x06");
+    assert_eq!(u32_at(blocks[5].1, 0), 1);
+    assert_eq!(&blocks[5].1[20..22], b"\x00\x00");
+}
+
+#[test]
+fn dissector_placeholders_are_replaced() {
+    use crate::settings::SettingsBuilder;
+
+    let settings = SettingsBuilder::default().host_dir("host").finalize();
+    let source = dissector(&settings);
+    assert!(!source.contains("{{"), "{}", source);
+    assert!(source.contains("{ \"\\3\\3\\3\", \"kernel image, raspbootin\" }"));
+    assert!(source.contains("\"host services\" }"));
+    assert!(source.contains("[0x04] = \"ymodem\""));
+    assert!(source.contains("encaps.USER0,"));
+}
@@ -0,0 +1,239 @@
+-- Wireshark dissector for the serial port captures of `bootcom` (pcapng, link
+-- type USER0).
+--
+-- Generated by `bootcom protocol dissector` for the triggers: {{TRIGGER_LIST}}.
+-- Copy it to the personal Lua plugins directory of Wireshark (see Help >
+-- About Wireshark > Folders), or load it with `wireshark -X lua_script:<file>`.
+
+local bootcom = Proto("bootcom", "bootcom serial port traffic")
+
+local directions = { [0] = "device -> host", [1] = "host -> device" }
+local traffics = { {{TRAFFICS}} }
+local controls = { {{CONTROLS}} }
+local opcodes = { {{OPCODES}} }
+local statuses = { {{STATUSES}} }
+
+-- The trigger patterns, with what they request.
+local triggers = { {{TRIGGERS}} }
+
+local f_direction = ProtoField.uint8("bootcom.direction", "Direction", base.DEC, directions)
+local f_traffic = ProtoField.uint8("bootcom.traffic", "Traffic", base.DEC, traffics)
+local f_text = ProtoField.string("bootcom.text", "Console text")
+local f_trigger = ProtoField.string("bootcom.trigger", "Trigger")
+local f_control = ProtoField.uint8("bootcom.control", "Control byte", base.HEX, controls)
+local f_size = ProtoField.uint32("bootcom.size", "Image size", base.DEC)
+local f_buffer = ProtoField.uint16("bootcom.buffer", "Receive buffer", base.DEC)
+local f_block = ProtoField.uint8("bootcom.block", "Block number", base.DEC)
+local f_version = ProtoField.uint8("bootcom.version", "Protocol version", base.DEC)
+local f_sequence = ProtoField.uint8("bootcom.sequence", "Sequence number", base.DEC)
+local f_crc16 = ProtoField.uint16("bootcom.crc16", "CRC-16", base.HEX)
+local f_crc32 = ProtoField.uint32("bootcom.crc32", "CRC-32", base.HEX)
+local f_opcode = ProtoField.uint8("bootcom.opcode", "Opcode", base.HEX, opcodes)
+local f_status = ProtoField.uint8("bootcom.status", "Status", base.DEC, statuses)
+local f_length = ProtoField.uint32("bootcom.length", "Length", base.DEC)
+local f_offset = ProtoField.uint32("bootcom.offset", "Resume offset", base.DEC)
+local f_address = ProtoField.uint64("bootcom.address", "Address", base.HEX)
+local f_data = ProtoField.bytes("bootcom.data", "Data")
+
+bootcom.fields = {
+    f_direction, f_traffic, f_text, f_trigger, f_control, f_size, f_buffer, f_block,
+    f_version, f_sequence, f_crc16, f_crc32, f_opcode, f_status, f_length, f_offset,
+    f_address, f_data,
+}
+
+local STX = {{STX}}
+local ACK = {{ACK}}
+local NAK = {{NAK}}
+local SYN = {{SYN}}
+local FRAME_OVERHEAD = {{FRAME_OVERHEAD}}
+local PROGRESS = {{PROGRESS}}
+local BLOCK_SIZE = {{BLOCK_SIZE}}
+local YMODEM_BLOCK_SIZE = {{YMODEM_BLOCK_SIZE}}
+local SIZE_CONFIRMATION = "{{SIZE_CONFIRMATION}}"
+local RESUME_OFFER = "{{RESUME_OFFER}}"
+local REVISION_REPORT = "{{REVISION_REPORT}}"
+local DUMP_HEADER_LEN = {{DUMP_HEADER_LEN}}
+
+-- Single control bytes, e.g. ACK or EOT.
+local function control(data, tree)
+    if data:len() == 1 and controls[data:uint()] then
+        tree:add(f_control, data)
+        return controls[data:uint()]
+    end
+end
+
+local function console(data, tree)
+    local text = data:string()
+    tree:add(f_text, data)
+    for _, trigger in ipairs(triggers) do
+        local pattern, what = trigger[1], trigger[2]
+        if #text >= #pattern and text:sub(-#pattern) == pattern then
+            tree:add(f_trigger, data(data:len() - #pattern), what)
+            return "trigger: " .. what
+        end
+    end
+    return string.format("%d bytes of console", data:len())
+end
+
+-- The size of the image, and its confirmation by the device.
+local function size_handshake(to_device, data, tree)
+    if to_device and data:len() == 4 then
+        tree:add_le(f_size, data)
+        return "image size " .. data:le_uint()
+    end
+    if not to_device and data:len() >= #SIZE_CONFIRMATION
+        and data(0, #SIZE_CONFIRMATION):string() == SIZE_CONFIRMATION then
+        if data:len() == #SIZE_CONFIRMATION + 2 then
+            tree:add_le(f_buffer, data(#SIZE_CONFIRMATION, 2))
+            return "size confirmed, receive buffer " .. data(#SIZE_CONFIRMATION, 2):le_uint()
+        end
+        -- The chunked protocol from the version 2.
+        if data:len() == #SIZE_CONFIRMATION + 5 and data(#SIZE_CONFIRMATION, 2):le_uint() == 0 then
+            tree:add(f_version, data(#SIZE_CONFIRMATION + 2, 1))
+            tree:add_le(f_buffer, data(#SIZE_CONFIRMATION + 3, 2))
+            return string.format("size confirmed, version %d, receive buffer %d",
+                data(#SIZE_CONFIRMATION + 2, 1):uint(), data(#SIZE_CONFIRMATION + 3, 2):le_uint())
+        end
+        return "size confirmed"
+    end
+end
+
+local function raspbootin(to_device, data, tree)
+    local summary = size_handshake(to_device, data, tree)
+    if summary then
+        return summary
+    end
+    if not to_device and data:len() == #REVISION_REPORT + 1
+        and data(0, #REVISION_REPORT):string() == REVISION_REPORT then
+        tree:add(f_version, data(#REVISION_REPORT, 1))
+        return "protocol revision " .. data(#REVISION_REPORT, 1):uint()
+    end
+    if not to_device and data:len() == #RESUME_OFFER + 4
+        and data(0, #RESUME_OFFER):string() == RESUME_OFFER then
+        tree:add_le(f_offset, data(#RESUME_OFFER, 4))
+        return "size confirmed, resume offered at " .. data(#RESUME_OFFER, 4):le_uint()
+    end
+    if not to_device and data:len() == 4 then
+        tree:add_le(f_crc32, data)
+        return "CRC-32 of the image?"
+    end
+    tree:add(f_data, data)
+    return string.format("%d bytes of image", data:len())
+end
+
+local function chunked(to_device, data, tree)
+    local summary = size_handshake(to_device, data, tree) or control(data, tree)
+    if summary then
+        return summary
+    end
+    local first = data(0, 1):uint()
+    if to_device and first == SYN and data:len() == 2 then
+        tree:add(f_control, data(0, 1))
+        tree:add(f_version, data(1, 1))
+        return "version " .. data(1, 1):uint()
+    end
+    if not to_device and (first == ACK or first == NAK) and data:len() == 2 then
+        tree:add(f_control, data(0, 1))
+        tree:add(f_sequence, data(1, 1))
+        return string.format("%s of the frame %d", controls[first], data(1, 1):uint())
+    end
+    if to_device and first == STX and data:len() > FRAME_OVERHEAD
+        and data(2, 2):le_uint() == data:len() - FRAME_OVERHEAD then
+        local len = data:len() - FRAME_OVERHEAD
+        tree:add(f_control, data(0, 1))
+        tree:add(f_sequence, data(1, 1))
+        tree:add_le(f_length, data(2, 2))
+        tree:add(f_data, data(4, len))
+        tree:add_le(f_crc32, data(4 + len, 4))
+        return string.format("frame %d of %d bytes", data(1, 1):uint(), len)
+    end
+    if to_device and first == STX and data:len() > 1 then
+        tree:add(f_control, data(0, 1))
+        tree:add(f_data, data(1))
+        return string.format("chunk of %d bytes", data:len() - 1)
+    end
+    if not to_device and first == PROGRESS and data:len() == 2 then
+        return string.format("persisted %d%%", data(1, 1):uint())
+    end
+    tree:add(f_data, data)
+    return string.format("%d bytes", data:len())
+end
+
+local function xmodem(to_device, data, tree)
+    local summary = control(data, tree)
+    if summary then
+        return summary
+    end
+    local size = data:len() - 5
+    if to_device and (size == BLOCK_SIZE or size == YMODEM_BLOCK_SIZE) then
+        tree:add(f_control, data(0, 1))
+        tree:add(f_block, data(1, 1))
+        tree:add(f_data, data(3, size))
+        tree:add(f_crc16, data(3 + size, 2))
+        return string.format("block %d (%d bytes)", data(1, 1):uint(), size)
+    end
+    tree:add(f_data, data)
+    return string.format("%d bytes", data:len())
+end
+
+local function host_services(to_device, data, tree)
+    if data:len() < 3 then
+        tree:add(f_data, data)
+        return string.format("%d bytes", data:len())
+    end
+    local length = data(1, 2):le_uint()
+    tree:add_le(f_length, data(1, 2))
+    if data:len() > 3 then
+        tree:add(f_data, data(3))
+    end
+    if to_device then
+        tree:add(f_status, data(0, 1))
+        return string.format("response: %s, %d bytes", statuses[data(0, 1):uint()] or "?", length)
+    end
+    tree:add(f_opcode, data(0, 1))
+    return string.format("request: %s, %d bytes", opcodes[data(0, 1):uint()] or "?", length)
+end
+
+local function dump(to_device, data, tree)
+    local summary = to_device and control(data, tree)
+    if summary then
+        return summary
+    end
+    if not to_device and data:len() == DUMP_HEADER_LEN then
+        tree:add_le(f_address, data(0, 8))
+        tree:add_le(f_length, data(8, 4))
+        tree:add_le(f_crc32, data(12, 4))
+        return string.format("dump of %d bytes", data(8, 4):le_uint())
+    end
+    tree:add(f_data, data)
+    return string.format("%d bytes of dump", data:len())
+end
+
+local decoders = { {{DECODERS}} }
+
+function bootcom.dissector(buffer, pinfo, tree)
+    if buffer:len() < 2 then
+        return 0
+    end
+    pinfo.cols.protocol = "bootcom"
+    local subtree = tree:add(bootcom, buffer(), "bootcom")
+    subtree:add(f_direction, buffer(0, 1))
+    subtree:add(f_traffic, buffer(1, 1))
+    local to_device = buffer(0, 1):uint() == 1
+    local traffic = buffer(1, 1):uint()
+    local summary = traffics[traffic] or "?"
+    if buffer:len() > 2 then
+        local data = buffer(2)
+        local decoder = decoders[traffic]
+        if decoder == console then
+            summary = console(data, subtree)
+        elseif decoder then
+            summary = traffics[traffic] .. ": " .. decoder(to_device, data, subtree)
+        end
+    end
+    pinfo.cols.info = (directions[buffer(0, 1):uint()] or "?") .. "  " .. summary
+    return buffer:len()
+end
+
+local encaps = wtap_encaps or wtap
+DissectorTable.get("wtap_encap"):add(encaps.USER0, bootcom)
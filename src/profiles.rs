@@ -0,0 +1,218 @@
+//! Named connection profiles, to switch between boards without remembering
+//! their flags.
+//!
+//! The profiles are declared in the configuration file (see
+//! [`config`](crate::config)), each under its name, and one is picked with
+//! `bootcom --profile <name>`. A profile gives the rule matching the port of
+//! the board, its baud rate, its kernel image and the command resetting it
+//! (see `--reset-command`), all optional. The arguments given on the command
+//! line take precedence over the profile:
+//!
+//! ```toml
+//! [profile.rpi4]
+//! baud_rate = 921600
+//! kernel_image = "target/kernel8.img"
+//! reset_command = "uhubctl -l 1-1 -p 2 -a cycle"
+//! [profile.rpi4.port]
+//! vid = 0x0403
+//! pid = 0x6001
+//!
+//! [profile.custom]
+//! baud_rate = 115200
+//! kernel_image = "build/kernel.bin"
+//! port = { path = "/dev/ttyACM0" }
+//! ```
+//!
+//! The port is the first one enumerated which matches all the keys of the
+//! rule: its `path`, the `vid` and `pid` of its USB serial controller and its
+//! `serial_number`. When none matches yet, the rule is waited for, and looked
+//! for again whenever the port is, the device path of a USB serial controller
+//! changing across replugs. The same rule is given on the command line with
+//! `--vid`, `--pid` and `--serial-number`.
+//!
+//! **Example**
+//! ```
+//! use bootcom::{config, SettingsBuilder};
+//!
+//! let config = config::parse(
+//!     "[profile.custom]\nbaud_rate = 115200\nport = { path = \"/dev/ttyACM0\" }",
+//! )
+//! .unwrap();
+//! let mut settings = SettingsBuilder::default().finalize();
+//! config.profiles["custom"].apply(&mut settings);
+//! assert_eq!(settings.baud_rate, 115_200);
+//! assert_eq!(settings.profile.as_deref(), Some("custom"));
+//! ```
+
+use std::fmt;
+
+use log::debug;
+use serialport::{available_ports, SerialPortInfo, SerialPortType};
+
+use crate::settings::Settings;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A named connection profile.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Profile {
+    /// The name of the profile, unique in the configuration file.
+    pub name: String,
+    /// The rule matching the port of the board, if any.
+    pub port: Option<PortRule>,
+    /// The baud rate of the console of the board, if any.
+    pub baud_rate: Option<u32>,
+    /// The kernel image to send to the board, if any.
+    pub kernel_image: Option<String>,
+    /// The command resetting the board, if any.
+    pub reset_command: Option<String>,
+}
+
+/// A rule matching a serial port, on all the keys which are set.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PortRule {
+    /// The path of the port.
+    pub path: Option<String>,
+    /// The vendor ID of the USB serial controller.
+    pub vid: Option<u16>,
+    /// The product ID of the USB serial controller.
+    pub pid: Option<u16>,
+    /// The serial number of the USB serial controller.
+    pub serial_number: Option<String>,
+}
+
+impl Profile {
+    /// Apply the profile to the `settings`, with the port matching its rule
+    /// now.
+    pub fn apply(&self, settings: &mut Settings) {
+        settings.profile = Some(self.name.clone());
+        if let Some(rule) = &self.port {
+            settings.path = rule.locate();
+            // A rule on the path alone is the path, which may not be
+            // enumerated.
+            if rule.vid.is_some() || rule.pid.is_some() || rule.serial_number.is_some() {
+                settings.port_match = Some(rule.clone());
+            }
+        }
+        if let Some(baud_rate) = self.baud_rate {
+            settings.baud_rate = baud_rate;
+        }
+        if let Some(image) = &self.kernel_image {
+            settings.kernel_image = Some(image.clone());
+        }
+        if let Some(command) = &self.reset_command {
+            settings.reset_command = Some(command.clone());
+        }
+    }
+}
+
+impl PortRule {
+    /// The path of the first port matching the rule, or the path of the rule
+    /// if there is none.
+    pub fn locate(&self) -> Option<String> {
+        self.find().or_else(|| self.path.clone())
+    }
+
+    /// The path of the first port matching the rule, if any.
+    pub fn find(&self) -> Option<String> {
+        let ports = available_ports().unwrap_or_default();
+        let port = ports.iter().find(|port| self.matches(port))?;
+        debug!("{} matches the port rule {:?}", port.port_name, self);
+        Some(port.port_name.clone())
+    }
+
+    /// Whether the `port` matches all the keys of the rule which are set.
+    pub fn matches(&self, port: &SerialPortInfo) -> bool {
+        if matches!(&self.path, Some(path) if *path != port.port_name) {
+            return false;
+        }
+        if self.vid.is_none() && self.pid.is_none() && self.serial_number.is_none() {
+            return true;
+        }
+        match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                (self.vid.is_none() || self.vid == Some(info.vid))
+                    && (self.pid.is_none() || self.pid == Some(info.pid))
+                    && (self.serial_number.is_none() || self.serial_number == info.serial_number)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PortRule {
+    /// The keys of the rule which are set, e.g. `0403:6001 #A10KZP3V`, any
+    /// vendor or product ID standing as `*`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut keys = vec![];
+        if let Some(path) = &self.path {
+            keys.push(path.clone());
+        }
+        if self.vid.is_some() || self.pid.is_some() {
+            let id = |id: Option<u16>| id.map_or("*".into(), |id| format!("{:04x}", id));
+            keys.push(format!("{}:{}", id(self.vid), id(self.pid)));
+        }
+        if let Some(serial_number) = &self.serial_number {
+            keys.push(format!("#{}", serial_number));
+        }
+        write!(f, "{}", keys.join(" "))
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn ports_matching_rules() {
+    use serialport::UsbPortInfo;
+
+    let usb = |name: &str, serial_number: &str| SerialPortInfo {
+        port_name: name.into(),
+        port_type: SerialPortType::UsbPort(UsbPortInfo {
+            vid: 0x0403,
+            pid: 0x6001,
+            serial_number: Some(serial_number.into()),
+            manufacturer: None,
+            product: None,
+        }),
+    };
+    let ftdi = PortRule {
+        vid: Some(0x0403),
+        pid: Some(0x6001),
+        ..PortRule::default()
+    };
+    assert!(ftdi.matches(&usb("/dev/ttyUSB0", "A10KZP3V")));
+    assert_eq!(ftdi.to_string(), "0403:6001");
+    assert!(!ftdi.matches(&SerialPortInfo {
+        port_name: "/dev/ttyS0".into(),
+        port_type: SerialPortType::Unknown,
+    }));
+
+    let board = PortRule {
+        serial_number: Some("A10KZP3V".into()),
+        ..ftdi
+    };
+    assert!(board.matches(&usb("/dev/ttyUSB1", "A10KZP3V")));
+    assert!(!board.matches(&usb("/dev/ttyUSB0", "B20XX")));
+    assert_eq!(
+        PortRule {
+            vid: None,
+            ..board.clone()
+        }
+        .to_string(),
+        "*:6001 #A10KZP3V"
+    );
+
+    let path = PortRule {
+        path: Some("/dev/ttyS0".into()),
+        ..PortRule::default()
+    };
+    assert!(path.matches(&SerialPortInfo {
+        port_name: "/dev/ttyS0".into(),
+        port_type: SerialPortType::Unknown,
+    }));
+    assert!(!path.matches(&usb("/dev/ttyUSB0", "A10KZP3V")));
+}
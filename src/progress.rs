@@ -0,0 +1,629 @@
+//! Progress reporting of the kernel image transfers.
+//!
+//! The progress of a transfer is reported to a [`ProgressObserver`]. By
+//! default, it is the progress bar shown in the terminal, but library users can
+//! provide their own observer in the settings. [`JsonProgress`] is provided for
+//! tools driving `bootcom`, and writes one JSON object per line for each
+//! update, as well as for the effective settings of each session.
+//!
+//! The look of the progress bars and spinners can be changed with a
+//! [`ProgressTheme`]. By default, plain ASCII characters are used when the
+//! terminal can't display Unicode.
+//!
+//! **Example**
+//! ```
+//! use bootcom::{
+//!     progress::{Progress, ProgressObserver},
+//!     SettingsBuilder,
+//! };
+//!
+//! struct Percent;
+//! impl ProgressObserver for Percent {
+//!     fn progress(&self, progress: &Progress) {
+//!         println!("{:.0}%", progress.percent());
+//!     }
+//! }
+//!
+//! let settings = SettingsBuilder::default()
+//!     .progress_observer(Percent)
+//!     .finalize();
+//! ```
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use console::Term;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::messages::Catalog;
+use crate::settings::{Settings, TransferProtocol};
+use crate::severity::SeveritySummary;
+use crate::utils::{
+    banner_json, banner_text, follow_bar, json_escape, HumanDuration, HumanRate, HumanSize,
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A snapshot of the progress of a transfer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Progress {
+    /// The number of bytes of the image sent so far.
+    pub bytes: u64,
+    /// The size of the image.
+    pub total: u64,
+    /// The time since the transfer started.
+    pub elapsed: Duration,
+}
+impl Progress {
+    /// The percentage of the image sent so far.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.bytes as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    /// The average throughput since the start of the transfer, in bytes per
+    /// second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+impl fmt::Display for Progress {
+    /// The humanized figures of the transfer, e.g. `1.5 MiB/4.0 MiB (11.2
+    /// KiB/s)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} ({})",
+            HumanSize(self.bytes),
+            HumanSize(self.total),
+            HumanRate(self.throughput())
+        )
+    }
+}
+
+/// What was sent, and how it went.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TransferReport {
+    /// The path of the kernel image sent.
+    pub image: String,
+    pub protocol: TransferProtocol,
+    /// The size of the image sent.
+    pub bytes: u64,
+    /// The duration of the transfer, from the size handshake to the last
+    /// acknowledgment.
+    pub duration: Duration,
+    /// The number of blocks or chunks sent again after the device rejected
+    /// them. Always `0` with the `raspbootin` protocol, which has none.
+    pub retries: u32,
+    /// The CRC-32 of the image sent.
+    pub crc: u32,
+    /// The offset the upload was resumed from, after an interrupted one. `0`
+    /// when the whole image was sent.
+    pub resumed_from: u64,
+    /// The console output received after the transfer, while verifying it
+    /// with [`push_image`](crate::push_image). Empty otherwise.
+    pub output: Vec<u8>,
+}
+impl TransferReport {
+    /// The average throughput of the transfer, in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            (self.bytes - self.resumed_from) as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+impl fmt::Display for TransferReport {
+    /// The humanized figures of the transfer, e.g. `4.0 MiB pushed in 3.2s
+    /// (1.2 MiB/s)`, and where it was resumed from.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pushed in {} ({})",
+            HumanSize(self.bytes),
+            HumanDuration(self.duration),
+            HumanRate(self.throughput())
+        )?;
+        if self.resumed_from > 0 {
+            write!(f, ", resumed at {}", HumanSize(self.resumed_from))?;
+        }
+        Ok(())
+    }
+}
+
+/// A capture made by an [`Instrument`](crate::Instrument) during a boot.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InstrumentCapture {
+    /// The name of the instrument.
+    pub name: String,
+    /// The path of the capture file.
+    pub file: String,
+    pub started: SystemTime,
+    pub stopped: SystemTime,
+}
+impl fmt::Display for InstrumentCapture {
+    /// The instrument with its capture file and duration, e.g. `logic:
+    /// captures/logic-1700000000.sr (3.2s)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({})",
+            self.name,
+            self.file,
+            HumanDuration(
+                self.stopped
+                    .duration_since(self.started)
+                    .unwrap_or_default()
+            )
+        )
+    }
+}
+
+/// Receives the progress updates of the transfers.
+///
+/// Updates are throttled to a few per second, except for the last one which is
+/// always reported through [`finished`](ProgressObserver::finished).
+pub trait ProgressObserver: Send + Sync {
+    /// A transfer of `total` bytes is starting.
+    fn started(&self, _total: u64) {}
+    /// Some more bytes were sent.
+    fn progress(&self, progress: &Progress);
+    /// The device printed `text` right after the last byte was sent, before
+    /// the transfer is reported as finished.
+    fn device_output(&self, text: &str) {
+        print!("{}", text);
+    }
+    /// The transfer completed, with `progress` being the final state.
+    fn finished(&self, progress: &Progress) {
+        self.progress(progress)
+    }
+    /// The transfer was completed by the device, as summarized by the
+    /// `report`.
+    fn transferred(&self, _report: &TransferReport) {}
+    /// A boot session started, or its settings changed, with the summary of
+    /// the effective settings as `(name, value)` pairs (port, line, image...).
+    fn configuration(&self, entries: &[(&'static str, String)]) {
+        println!("{}", banner_text(&Catalog::default(), entries));
+    }
+    /// An instrument stopped its `capture`.
+    fn instrument(&self, capture: &InstrumentCapture) {
+        println!(
+            "{}",
+            Catalog::default().text_with("boot.captured", &[("capture", capture)])
+        );
+    }
+    /// A boot session ended, with its console lines counted by severity in
+    /// the `summary`.
+    fn console_summary(&self, summary: &SeveritySummary) {
+        println!("{}", summary);
+    }
+}
+
+/// A shared [`ProgressObserver`] as kept in the [`Settings`].
+///
+/// Two handles are equal only when they refer to the same observer.
+#[derive(Clone)]
+pub struct ObserverHandle(Arc<dyn ProgressObserver>);
+impl ObserverHandle {
+    pub fn new(observer: impl ProgressObserver + 'static) -> Self {
+        ObserverHandle(Arc::new(observer))
+    }
+
+    pub(crate) fn observer(&self) -> &dyn ProgressObserver {
+        &*self.0
+    }
+}
+impl PartialEq for ObserverHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for ObserverHandle {}
+impl fmt::Debug for ObserverHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ObserverHandle")
+    }
+}
+
+/// Writes the progress updates on the standard output as JSON objects, one
+/// per line:
+///
+/// ```text
+/// {"event":"started","total":8192}
+/// {"event":"progress","bytes":4096,"total":8192,"percent":50.0,"bytes_per_sec":11520}
+/// {"event":"finished","bytes":8192,"total":8192,"percent":100.0,"bytes_per_sec":11520}
+/// {"event":"transferred","protocol":"raspbootin","bytes":8192,"duration_ms":711,"retries":0,"crc":"0x1c291ca3"}
+/// ```
+///
+/// The effective settings and the console lines of the sessions counted by
+/// severity are written the same way:
+///
+/// ```text
+/// {"event":"settings","port":"/dev/ttyUSB0","line":"230400 8N1, no flow control",...}
+/// {"event":"console","lines":240,"errors":1,"warnings":2,"first_error":"mmc0: error -110"}
+/// ```
+///
+/// As well as the captures of the instruments, with their times in
+/// milliseconds since the Unix epoch, to line them up with the console:
+///
+/// ```text
+/// {"event":"instrument","name":"logic","file":"captures/logic-1700000000.sr","started_ms":1700000000125,"stopped_ms":1700000003342}
+/// ```
+#[derive(Debug, Default)]
+pub struct JsonProgress;
+impl JsonProgress {
+    fn line(event: &str, progress: &Progress) -> String {
+        format!(
+            "{{\"event\":\"{}\",\"bytes\":{},\"total\":{},\"percent\":{:.1},\"bytes_per_sec\":{:.0}}}",
+            event,
+            progress.bytes,
+            progress.total,
+            progress.percent(),
+            progress.throughput()
+        )
+    }
+
+    fn report(report: &TransferReport) -> String {
+        format!(
+            "{{\"event\":\"transferred\",\"protocol\":\"{}\",\"bytes\":{},\"duration_ms\":{},\"retries\":{},\"crc\":\"{:#010x}\"}}",
+            report.protocol,
+            report.bytes,
+            report.duration.as_millis(),
+            report.retries,
+            report.crc
+        )
+    }
+
+    fn capture(capture: &InstrumentCapture) -> String {
+        let millis = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        };
+        format!(
+            "{{\"event\":\"instrument\",\"name\":\"{}\",\"file\":\"{}\",\"started_ms\":{},\"stopped_ms\":{}}}",
+            json_escape(&capture.name),
+            json_escape(&capture.file),
+            millis(capture.started),
+            millis(capture.stopped)
+        )
+    }
+
+    fn summary(summary: &SeveritySummary) -> String {
+        let first_error = match &summary.first_error {
+            Some(error) => format!("\"{}\"", json_escape(error)),
+            None => "null".into(),
+        };
+        format!(
+            "{{\"event\":\"console\",\"lines\":{},\"errors\":{},\"warnings\":{},\"first_error\":{}}}",
+            summary.lines, summary.errors, summary.warnings, first_error
+        )
+    }
+}
+impl ProgressObserver for JsonProgress {
+    fn started(&self, total: u64) {
+        println!("{{\"event\":\"started\",\"total\":{}}}", total);
+    }
+
+    fn progress(&self, progress: &Progress) {
+        println!("{}", JsonProgress::line("progress", progress));
+    }
+
+    fn finished(&self, progress: &Progress) {
+        println!("{}", JsonProgress::line("finished", progress));
+    }
+
+    fn transferred(&self, report: &TransferReport) {
+        println!("{}", JsonProgress::report(report));
+    }
+
+    fn configuration(&self, entries: &[(&'static str, String)]) {
+        println!("{}", banner_json(entries));
+    }
+
+    fn instrument(&self, capture: &InstrumentCapture) {
+        println!("{}", JsonProgress::capture(capture));
+    }
+
+    fn console_summary(&self, summary: &SeveritySummary) {
+        println!("{}", JsonProgress::summary(summary));
+    }
+}
+
+/// The characters used to draw the progress bars and spinners.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Glyphs {
+    /// Unicode if the terminal supports it, ASCII otherwise.
+    Auto,
+    Unicode,
+    Ascii,
+}
+
+/// The look of the progress bars and spinners. Anything not set uses the
+/// default for the selected glyphs.
+///
+/// Templates and tick strings follow the syntax of
+/// [`indicatif`](https://docs.rs/indicatif/0.16.0/indicatif/#templates).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProgressTheme {
+    pub glyphs: Glyphs,
+    /// The template of the transfer progress bars.
+    pub bar_template: Option<String>,
+    /// The characters used to draw the filled, current and empty parts of the
+    /// bars.
+    pub progress_chars: Option<String>,
+    /// The template of the spinners shown while waiting.
+    pub spinner_template: Option<String>,
+    /// The successive frames of the spinners.
+    pub tick_strings: Option<Vec<String>>,
+}
+impl Default for ProgressTheme {
+    fn default() -> Self {
+        ProgressTheme {
+            glyphs: Glyphs::Auto,
+            bar_template: None,
+            progress_chars: None,
+            spinner_template: None,
+            tick_strings: None,
+        }
+    }
+}
+impl ProgressTheme {
+    /// Returns `true` if Unicode characters are used.
+    pub fn is_unicode(&self) -> bool {
+        match self.glyphs {
+            Glyphs::Auto => Term::stdout().features().wants_emoji(),
+            Glyphs::Unicode => true,
+            Glyphs::Ascii => false,
+        }
+    }
+
+    /// A progress bar for a transfer of `total` bytes.
+    pub(crate) fn bar(&self, total: u64) -> ProgressBar {
+        let default_template = if self.is_unicode() {
+            "[BC] ⏩ Pushing [{elapsed_precise}] [{wide_bar:.cyan/blue}] {msg}"
+        } else {
+            "[BC] >> Pushing [{elapsed_precise}] [{wide_bar:.cyan/blue}] {msg}"
+        };
+        let bar = ProgressBar::new(total);
+        // The default bar spans the whole width, and is redrawn as such when
+        // the terminal is resized.
+        if self.bar_template.is_none() {
+            follow_bar(&bar);
+        }
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(self.bar_template.as_deref().unwrap_or(default_template))
+                .progress_chars(self.progress_chars.as_deref().unwrap_or("=>-")),
+        );
+        bar
+    }
+
+    /// A spinner, already ticking.
+    pub(crate) fn spinner(&self) -> ProgressBar {
+        let default_ticks: &[&str] = if self.is_unicode() {
+            // For more spinners check out the cli-spinners project:
+            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
+            &["⠋", "⠙", "⠚", "⠞", "⠖", "⠦", "⠴", "⠲", "⠳", "⠓"]
+        } else {
+            &["-", "\\", "|", "/"]
+        };
+        let ticks = match &self.tick_strings {
+            Some(ticks) => ticks.iter().map(String::as_str).collect(),
+            None => default_ticks.to_vec(),
+        };
+        let spinner = ProgressBar::new_spinner();
+        spinner.enable_steady_tick(120);
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .tick_strings(&ticks)
+                .template(
+                    self.spinner_template
+                        .as_deref()
+                        .unwrap_or("[BC] {spinner:.blue} {msg}"),
+                ),
+        );
+        spinner
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// How often the observer is told about the progress of a transfer.
+const UPDATE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Tracks a transfer and reports its progress to the observer from the
+/// settings, or to a progress bar if there is none.
+pub(crate) struct TransferProgress {
+    observer: Arc<dyn ProgressObserver>,
+    started: Instant,
+    total: u64,
+    last_update: Mutex<Option<Instant>>,
+}
+impl TransferProgress {
+    pub(crate) fn start(settings: &Settings, total: u64) -> Self {
+        let observer = match &settings.progress_observer {
+            Some(handle) => handle.0.clone(),
+            None => Arc::new(BarObserver {
+                bar: settings.progress_theme.bar(total),
+            }),
+        };
+        observer.started(total);
+        TransferProgress {
+            observer,
+            started: Instant::now(),
+            total,
+            last_update: Mutex::new(None),
+        }
+    }
+
+    /// `bytes` of the image were sent so far.
+    pub(crate) fn update(&self, bytes: u64) {
+        let now = Instant::now();
+        let mut last_update = self.last_update.lock().unwrap();
+        if last_update.is_none_or(|last| now - last >= UPDATE_PERIOD) {
+            *last_update = Some(now);
+            self.observer.progress(&self.snapshot(bytes));
+        }
+    }
+
+    /// Show what the device printed at the end of the transfer.
+    pub(crate) fn device_output(&self, data: &[u8]) {
+        if !data.is_empty() {
+            self.observer.device_output(&String::from_utf8_lossy(data));
+        }
+    }
+
+    /// The transfer completed after sending `bytes`.
+    pub(crate) fn finish(&self, bytes: u64) {
+        self.observer.finished(&self.snapshot(bytes));
+    }
+
+    /// Tell the observer from the `settings`, if any, about the transfer
+    /// completed as summarized by the `report`.
+    pub(crate) fn report(settings: &Settings, report: &TransferReport) {
+        if let Some(handle) = &settings.progress_observer {
+            handle.observer().transferred(report);
+        }
+    }
+
+    fn snapshot(&self, bytes: u64) -> Progress {
+        Progress {
+            bytes,
+            total: self.total,
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The default observer, showing a progress bar in the terminal.
+struct BarObserver {
+    bar: ProgressBar,
+}
+impl ProgressObserver for BarObserver {
+    fn progress(&self, progress: &Progress) {
+        self.bar.set_position(progress.bytes);
+        self.bar.set_message(progress.to_string());
+    }
+
+    // Printed above the bar, which would garble it otherwise.
+    fn device_output(&self, text: &str) {
+        self.bar.println(text.trim_end_matches(&['\r', '\n'][..]));
+    }
+
+    fn finished(&self, progress: &Progress) {
+        self.bar.set_position(progress.bytes);
+        self.bar.finish_with_message(format!(
+            "{} in {} ({})",
+            HumanSize(progress.bytes),
+            HumanDuration(progress.elapsed),
+            HumanRate(progress.throughput())
+        ));
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn progress_figures() {
+    let progress = Progress {
+        bytes: 2048,
+        total: 8192,
+        elapsed: Duration::from_secs(2),
+    };
+    assert!((progress.percent() - 25.0).abs() < f64::EPSILON);
+    assert!((progress.throughput() - 1024.0).abs() < f64::EPSILON);
+    assert_eq!(
+        JsonProgress::line("progress", &progress),
+        "{\"event\":\"progress\",\"bytes\":2048,\"total\":8192,\"percent\":25.0,\"bytes_per_sec\":1024}"
+    );
+    assert_eq!(progress.to_string(), "2.0 KiB/8.0 KiB (1.0 KiB/s)");
+
+    let report = TransferReport {
+        image: "kernel8.img".into(),
+        protocol: TransferProtocol::XmodemCrc,
+        bytes: 8192,
+        duration: Duration::from_secs(4),
+        retries: 2,
+        crc: 0x1c29_1ca3,
+        resumed_from: 0,
+        output: vec![],
+    };
+    assert_eq!(
+        JsonProgress::report(&report),
+        "{\"event\":\"transferred\",\"protocol\":\"xmodem-crc\",\"bytes\":8192,\"duration_ms\":4000,\"retries\":2,\"crc\":\"0x1c291ca3\"}"
+    );
+    assert_eq!(report.to_string(), "8.0 KiB pushed in 4.0s (2.0 KiB/s)");
+
+    let capture = InstrumentCapture {
+        name: "logic".into(),
+        file: "captures/logic-1700000000.sr".into(),
+        started: UNIX_EPOCH + Duration::from_millis(1_700_000_000_125),
+        stopped: UNIX_EPOCH + Duration::from_millis(1_700_000_003_342),
+    };
+    assert_eq!(
+        JsonProgress::capture(&capture),
+        "{\"event\":\"instrument\",\"name\":\"logic\",\"file\":\"captures/logic-1700000000.sr\",\"started_ms\":1700000000125,\"stopped_ms\":1700000003342}"
+    );
+    assert_eq!(
+        capture.to_string(),
+        "logic: captures/logic-1700000000.sr (3.2s)"
+    );
+
+    let summary = SeveritySummary {
+        lines: 240,
+        errors: 1,
+        warnings: 2,
+        first_error: Some("mmc0: \"error\" -110".into()),
+    };
+    assert_eq!(
+        JsonProgress::summary(&summary),
+        "{\"event\":\"console\",\"lines\":240,\"errors\":1,\"warnings\":2,\"first_error\":\"mmc0: \\\"error\\\" -110\"}"
+    );
+}
+
+#[test]
+fn updates_are_throttled() {
+    #[derive(Default)]
+    struct Counter(Mutex<Vec<u64>>);
+    impl ProgressObserver for Counter {
+        fn progress(&self, progress: &Progress) {
+            self.0.lock().unwrap().push(progress.bytes);
+        }
+    }
+
+    let counter = Arc::new(Counter::default());
+    let progress = TransferProgress {
+        observer: counter.clone(),
+        started: Instant::now(),
+        total: 300,
+        last_update: Mutex::new(None),
+    };
+    progress.update(100);
+    progress.update(200);
+    progress.finish(300);
+    assert_eq!(*counter.0.lock().unwrap(), vec![100, 300]);
+}
@@ -0,0 +1,915 @@
+//! Property-based checks of the framing of the transfers.
+//!
+//! This module (available with the `testing` feature) checks the frames
+//! `bootcom` sends, built by the very functions the transfers use, against
+//! reference receivers on cases generated by [`proptest`]. The byte streams
+//! are cut at random places, the way a UART driver hands them over, before
+//! being fed to the receivers:
+//!
+//!  * **Reassembly** - any image sent with the `raspbootin`, chunked (either
+//!    version, for any receive buffer, some frames of the version 2 being sent
+//!    twice), XMODEM-CRC or YMODEM protocol, escaped for the software flow
+//!    control or not, comes out of the receiver identical.
+//!  * **Flow control** - the escaped data never holds `XON` or `XOFF`, and
+//!    those received from the device are removed, however the data is cut.
+//!  * **Triggers** - a trigger is recognized exactly when the console output
+//!    ends with it, however the output is cut: none is missed, and nothing else
+//!    is taken for one. The output shown is the console output without the
+//!    triggers.
+//!
+//! A failing case is shrunk to a minimal one, reported in the [`Failure`] for
+//! it to be replayed by calling the property on it while changing a protocol.
+//!
+//! **Example**
+//! ```
+//! use bootcom::properties;
+//!
+//! let failures = properties::check_all(50);
+//! assert!(failures.is_empty(), "{:?}", failures);
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use proptest::{
+    collection::vec,
+    prelude::*,
+    sample::select,
+    test_runner::{Config, TestError, TestRunner},
+};
+use serialport::FlowControl;
+
+use crate::utils::{
+    chunked,
+    crc::{crc16, Crc32},
+    kernel::size_frame,
+    xmodem::{self, block_frame, end_of_batch, header_frame, SUB},
+    xonxoff::{DLE, XOFF, XON},
+    SoftFlow, TriggerMatcher,
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A property, checked on a generated [`Case`], telling what went wrong when
+/// it does not hold.
+pub type Property = fn(&Case) -> Result<(), String>;
+
+/// All the properties, by name.
+pub const PROPERTIES: [(&str, Property); 7] = [
+    ("raspbootin reassembly", raspbootin_reassembly),
+    ("chunked reassembly", chunked_reassembly),
+    ("chunked v2 reassembly", chunked_v2_reassembly),
+    ("xmodem-crc reassembly", xmodem_reassembly),
+    ("ymodem reassembly", ymodem_reassembly),
+    ("flow control", flow_control),
+    ("triggers", triggers),
+];
+
+/// The inputs of the properties, each one using those it needs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Case {
+    /// The image transferred, or the data escaped for the flow control. Its
+    /// bytes are the control bytes of the protocols more often than not.
+    pub image: Vec<u8>,
+    /// Whether the transfer is escaped for the software flow control.
+    pub software_flow: bool,
+    /// What the receive buffer of the device has on top of the smallest one
+    /// the chunked protocol works with.
+    pub buffer: usize,
+    /// Whether the frames of the version 2 of the chunked protocol are sent
+    /// twice, in turn.
+    pub resent: Vec<bool>,
+    /// The name of the YMODEM file.
+    pub name: String,
+    /// The console output holding `XON` and `XOFF`, for the flow control.
+    pub console: Vec<u8>,
+    /// The trigger patterns, duplicates being ignored.
+    pub patterns: Vec<Vec<u8>>,
+    /// The console output around the triggers, ended with the pattern at this
+    /// index (modulo their number) when there is one.
+    pub output: Vec<u8>,
+    pub ending: Option<usize>,
+    /// The lengths of the pieces the streams are cut in, in turn, `0` standing
+    /// for a read timing out.
+    pub cuts: Vec<usize>,
+}
+impl Arbitrary for Case {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Case>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        // A small alphabet, for the output to come close to the patterns often.
+        let trigger_byte = || select(&TRIGGER_BYTES[..]);
+        let name = vec(select(NAME_CHARS), 1..=32)
+            .prop_map(|chars| chars.into_iter().map(char::from).collect());
+        let cut = prop_oneof![1 => Just(0), 4 => 1..=8usize, 3 => 1..=64 * 1024usize];
+        (
+            (
+                image(),
+                any::<bool>(),
+                0..600usize,
+                vec(prop::bool::weighted(0.25), 1..16),
+            ),
+            (name, bytes(256)),
+            (
+                vec(vec(trigger_byte(), 1..=5), 1..=3),
+                vec(trigger_byte(), 0..64),
+                any::<Option<usize>>(),
+            ),
+            vec(cut, 1..32),
+        )
+            .prop_map(
+                |(
+                    (image, software_flow, buffer, resent),
+                    (name, console),
+                    (patterns, output, ending),
+                    cuts,
+                )| Case {
+                    image,
+                    software_flow,
+                    buffer,
+                    resent,
+                    name,
+                    console,
+                    patterns,
+                    output,
+                    ending,
+                    cuts,
+                },
+            )
+            .boxed()
+    }
+}
+
+/// A property that did not hold.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Failure {
+    pub property: String,
+    /// The minimal failing case, to replay it, unless the check was aborted.
+    pub case: Option<Box<Case>>,
+    pub message: String,
+}
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.case {
+            Some(case) => write!(
+                f,
+                "property `{}` failed: {}, for {:?}",
+                self.property, self.message, case
+            ),
+            None => write!(
+                f,
+                "property `{}` could not be checked: {}",
+                self.property, self.message
+            ),
+        }
+    }
+}
+
+/// Check the `property` on `cases` generated cases, shrinking the first
+/// failing one.
+pub fn check(name: &str, property: Property, cases: u32) -> Result<(), Failure> {
+    let mut runner = TestRunner::new(Config {
+        cases,
+        failure_persistence: None,
+        // The vectors shrink one element at a time, the longest images need
+        // as many iterations as they have bytes.
+        max_shrink_iters: 128 * 1024,
+        ..Config::default()
+    });
+    runner
+        .run(&any::<Case>(), |case| {
+            property(&case).map_err(TestCaseError::fail)
+        })
+        .map_err(|error| match error {
+            TestError::Fail(reason, case) => Failure {
+                property: name.into(),
+                case: Some(Box::new(case)),
+                message: reason.to_string(),
+            },
+            TestError::Abort(reason) => Failure {
+                property: name.into(),
+                case: None,
+                message: reason.to_string(),
+            },
+        })
+}
+
+/// Check all the [`PROPERTIES`] on `cases` generated cases each.
+pub fn check_all(cases: u32) -> Vec<Failure> {
+    PROPERTIES
+        .iter()
+        .filter_map(|(name, property)| check(name, *property, cases).err())
+        .collect()
+}
+
+/// An image sent with the `raspbootin` protocol is received identical.
+pub fn raspbootin_reassembly(case: &Case) -> Result<(), String> {
+    let image = &case.image;
+    let flow = flow(case);
+    let mut stream = flow.encode(&size_frame(image.len() as u32)).into_owned();
+    stream.extend_from_slice(&flow.encode(image));
+
+    let mut receiver = Raspbootin::default();
+    receive(case, &stream, &flow, &mut receiver)?;
+    same(image, &receiver.finish()?)
+}
+
+/// An image sent with the chunked protocol is received identical, whatever
+/// the receive buffer of the device.
+pub fn chunked_reassembly(case: &Case) -> Result<(), String> {
+    let image = &case.image;
+    let flow = flow(case);
+    let buffer = 2 + case.buffer;
+    let chunk_size = chunked::chunk_size(buffer, flow.is_enabled())
+        .ok_or(format!("no chunk size for a buffer of {} bytes", buffer))?;
+    let mut stream = flow.encode(&size_frame(image.len() as u32)).into_owned();
+    for chunk in image.chunks(chunk_size) {
+        stream.extend_from_slice(&flow.encode(&chunked::chunk_frame(chunk)));
+    }
+    stream.extend_from_slice(&flow.encode(&[chunked::EOT]));
+
+    let mut receiver = Chunked::new(chunk_size);
+    receive(case, &stream, &flow, &mut receiver)?;
+    same(image, &receiver.finish()?)
+}
+
+/// An image sent in frames of the version 2 of the chunked protocol is
+/// received identical, whatever the receive buffer of the device, the frames
+/// sent again after a lost `ACK` being received once.
+pub fn chunked_v2_reassembly(case: &Case) -> Result<(), String> {
+    let image = &case.image;
+    let flow = flow(case);
+    let buffer = 2 * (chunked::FRAME_OVERHEAD + 1) + case.buffer;
+    let payload = chunked::frame_payload(buffer, flow.is_enabled())
+        .ok_or(format!("no frame payload for a buffer of {} bytes", buffer))?;
+    let mut stream = flow.encode(&size_frame(image.len() as u32)).into_owned();
+    stream.extend_from_slice(&flow.encode(&[chunked::SYN, chunked::VERSION]));
+    for (index, chunk) in image.chunks(payload).enumerate() {
+        let frame = chunked::frame(index as u8, chunk);
+        stream.extend_from_slice(&flow.encode(&frame));
+        if case.resent.get(index % case.resent.len().max(1)) == Some(&true) {
+            stream.extend_from_slice(&flow.encode(&frame));
+        }
+    }
+    stream.extend_from_slice(&flow.encode(&[chunked::EOT]));
+
+    let mut receiver = ChunkedV2::new(payload);
+    receive(case, &stream, &flow, &mut receiver)?;
+    same(image, &receiver.finish()?)
+}
+
+/// An image sent with XMODEM-CRC is received identical, padded with `SUB` to
+/// the end of its last block.
+pub fn xmodem_reassembly(case: &Case) -> Result<(), String> {
+    let image = &case.image;
+    let flow = flow(case);
+    let mut stream = vec![];
+    for (index, block) in image.chunks(xmodem::BLOCK_SIZE).enumerate() {
+        let frame = block_frame((index + 1) as u8, block, xmodem::BLOCK_SIZE);
+        stream.extend_from_slice(&flow.encode(&frame));
+    }
+    stream.extend_from_slice(&flow.encode(&[xmodem::EOT]));
+
+    let mut receiver = Xmodem::new(false);
+    receive(case, &stream, &flow, &mut receiver)?;
+    let (_, received) = receiver.finish()?;
+    let blocks = image.len().div_ceil(xmodem::BLOCK_SIZE);
+    let (data, padding) = received.split_at(image.len().min(received.len()));
+    same(image, data)?;
+    if received.len() != blocks * xmodem::BLOCK_SIZE || padding.iter().any(|b| *b != SUB) {
+        return Err(format!("bad padding {:02x?}", padding));
+    }
+    Ok(())
+}
+
+/// An image sent with YMODEM is received identical, under its name.
+pub fn ymodem_reassembly(case: &Case) -> Result<(), String> {
+    let image = &case.image;
+    let flow = flow(case);
+    let name = &case.name;
+    let mut stream = flow
+        .encode(&header_frame(name, image.len() as u64))
+        .into_owned();
+    for (index, block) in image.chunks(xmodem::YMODEM_BLOCK_SIZE).enumerate() {
+        let frame = block_frame((index + 1) as u8, block, xmodem::YMODEM_BLOCK_SIZE);
+        stream.extend_from_slice(&flow.encode(&frame));
+    }
+    stream.extend_from_slice(&flow.encode(&[xmodem::EOT]));
+    stream.extend_from_slice(&flow.encode(&end_of_batch()));
+
+    let mut receiver = Xmodem::new(true);
+    receive(case, &stream, &flow, &mut receiver)?;
+    let (file, received) = receiver.finish()?;
+    if file.as_ref().map(|(name, _)| name) != Some(name) {
+        return Err(format!("file {:?} received as {:?}", name, file));
+    }
+    same(image, &received)
+}
+
+/// The escaped data never holds `XON` or `XOFF` and is unescaped identical,
+/// and the `XON` and `XOFF` received are removed and followed.
+pub fn flow_control(case: &Case) -> Result<(), String> {
+    let mut flow = SoftFlow::new(FlowControl::Software);
+    let data = &case.image;
+    let escaped = flow.encode(data).into_owned();
+    if escaped.iter().any(|b| *b == XON || *b == XOFF) {
+        return Err(format!("{:02x?} escaped as {:02x?}", data, escaped));
+    }
+    let mut unescape = Unescape::default();
+    let mut unescaped = vec![];
+    for piece in cut(&escaped, &case.cuts) {
+        unescaped.extend_from_slice(&unescape.feed(piece)?);
+    }
+    same(data, &unescaped)?;
+
+    let console = &case.console;
+    let mut received = vec![];
+    for piece in cut(console, &case.cuts) {
+        received.extend_from_slice(&flow.receive(piece));
+    }
+    let expected: Vec<u8> = console
+        .iter()
+        .copied()
+        .filter(|b| *b != XON && *b != XOFF)
+        .collect();
+    same(&expected, &received)?;
+    let paused = console.iter().rev().find(|b| **b == XON || **b == XOFF) == Some(&XOFF);
+    if flow.is_paused() != paused {
+        return Err(format!(
+            "paused: {} after {:02x?}",
+            flow.is_paused(),
+            console
+        ));
+    }
+    Ok(())
+}
+
+/// A trigger is recognized exactly when the console output seen since the
+/// previous one ends with it, the longest one when several do, however the
+/// output is cut. Once the device goes quiet, all the output but the triggers
+/// was shown.
+pub fn triggers(case: &Case) -> Result<(), String> {
+    let mut patterns: Vec<&Vec<u8>> = vec![];
+    for pattern in &case.patterns {
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    let mut output = case.output.clone();
+    if let (Some(index), false) = (case.ending, patterns.is_empty()) {
+        output.extend_from_slice(patterns[index % patterns.len()]);
+    }
+
+    let mut matcher = TriggerMatcher::new(
+        patterns
+            .iter()
+            .map(|pattern| pattern.to_vec())
+            .zip(0..)
+            .collect::<Vec<_>>(),
+    );
+    let mut seen = vec![];
+    let (mut shown, mut expected_shown) = (vec![], vec![]);
+    for piece in cut(&output, &case.cuts) {
+        seen.extend_from_slice(piece);
+        let expected = patterns
+            .iter()
+            .zip(0..)
+            .filter(|(pattern, _)| seen.ends_with(pattern))
+            .max_by_key(|(pattern, _)| pattern.len());
+        let (output, found) = matcher.feed(piece);
+        shown.extend(output);
+        if found != expected.map(|(_, index)| index) {
+            return Err(format!(
+                "{:?} found instead of {:?} at the end of {:02x?}, for the patterns {:02x?}",
+                found, expected, seen, patterns
+            ));
+        }
+        if let Some((pattern, _)) = expected {
+            expected_shown.extend_from_slice(&seen[..seen.len() - pattern.len()]);
+            seen.clear();
+        }
+    }
+    expected_shown.extend(seen);
+    let quiet = Instant::now();
+    shown.extend(matcher.release(quiet));
+    shown.extend(matcher.release(quiet + Duration::from_secs(1)));
+    same(&expected_shown, &shown)
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The bytes meaning something to one protocol or another.
+const CONTROL_BYTES: [u8; 14] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x06, 0x10, 0x11, 0x13, 0x15, 0x18, 0x1a, b'C', 0xff,
+];
+
+/// The bytes of the trigger patterns, and of the console output around them.
+const TRIGGER_BYTES: [u8; 4] = [0x03, 0x16, b'C', b'\n'];
+
+const NAME_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789._-";
+
+/// An image, small most of the times, long enough for the block numbers to wrap
+/// around sometimes.
+fn image() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![15 => bytes(4096), 1 => bytes(48 * 1024)]
+}
+
+/// Up to `max_len` bytes, the control bytes of the protocols being more likely
+/// than the others.
+fn bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    // Drawn from a `u16` rather than a union of strategies, many times faster
+    // for the long images.
+    let byte = any::<u16>().prop_map(|n| match n.to_le_bytes() {
+        [choice, index] if choice % 4 == 0 => CONTROL_BYTES[index as usize % CONTROL_BYTES.len()],
+        [_, byte] => byte,
+    });
+    vec(byte, 0..=max_len)
+}
+
+/// The flow control of the transfer, software or none.
+fn flow(case: &Case) -> SoftFlow {
+    SoftFlow::new(if case.software_flow {
+        FlowControl::Software
+    } else {
+        FlowControl::None
+    })
+}
+
+/// The `data` cut in pieces of the lengths of the `cuts`, in turn. The rest is
+/// taken whole once all the cuts in a row gave empty pieces.
+fn cut<'a>(data: &'a [u8], cuts: &[usize]) -> Vec<&'a [u8]> {
+    let mut pieces = vec![];
+    let mut rest = data;
+    let mut empty = 0;
+    for len in cuts.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let len = if empty == cuts.len() {
+            rest.len()
+        } else {
+            rest.len().min(*len)
+        };
+        empty = if len == 0 { empty + 1 } else { 0 };
+        let (piece, after) = rest.split_at(len);
+        pieces.push(piece);
+        rest = after;
+    }
+    if !rest.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
+fn same(expected: &[u8], received: &[u8]) -> Result<(), String> {
+    if expected == received {
+        return Ok(());
+    }
+    let at = expected
+        .iter()
+        .zip(received)
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| expected.len().min(received.len()));
+    Err(format!(
+        "{} bytes received instead of {}, differing from the offset {}",
+        received.len(),
+        expected.len(),
+        at
+    ))
+}
+
+/// The device side of a transfer, fed with the bytes as they are read.
+trait Receiver {
+    /// Take the `data` read, failing on a malformed stream.
+    fn feed(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+/// Feed the `stream` to the `receiver` in the pieces of the `case`, unescaped
+/// first when the `flow` control is enabled.
+fn receive(
+    case: &Case,
+    stream: &[u8],
+    flow: &SoftFlow,
+    receiver: &mut impl Receiver,
+) -> Result<(), String> {
+    let mut unescape = Unescape::default();
+    for piece in cut(stream, &case.cuts) {
+        if flow.is_enabled() {
+            receiver.feed(&unescape.feed(piece)?)?;
+        } else {
+            receiver.feed(piece)?;
+        }
+    }
+    if unescape.escaping {
+        return Err("the stream ends with a `DLE`".into());
+    }
+    Ok(())
+}
+
+/// Undoes the escaping of the software flow control.
+#[derive(Debug, Default)]
+struct Unescape {
+    /// A `DLE` was received, the next byte is escaped.
+    escaping: bool,
+}
+impl Unescape {
+    fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut unescaped = Vec::with_capacity(data.len());
+        for byte in data {
+            match *byte {
+                byte if self.escaping => {
+                    unescaped.push(byte ^ 0x20);
+                    self.escaping = false;
+                }
+                DLE => self.escaping = true,
+                XON | XOFF => return Err(format!("unescaped {:#04x}", byte)),
+                byte => unescaped.push(byte),
+            }
+        }
+        Ok(unescaped)
+    }
+}
+
+/// Takes the 4 bytes of the size of the image, if they were all received.
+fn take_size(pending: &mut Vec<u8>) -> Option<usize> {
+    if pending.len() < 4 {
+        return None;
+    }
+    let size: Vec<u8> = pending.drain(..4).collect();
+    Some(u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+}
+
+/// The device side of the `raspbootin` protocol.
+#[derive(Debug, Default)]
+struct Raspbootin {
+    pending: Vec<u8>,
+    size: Option<usize>,
+    image: Vec<u8>,
+}
+impl Receiver for Raspbootin {
+    fn feed(&mut self, data: &[u8]) -> Result<(), String> {
+        self.pending.extend_from_slice(data);
+        if self.size.is_none() {
+            self.size = take_size(&mut self.pending);
+        }
+        if let Some(size) = self.size {
+            if self.image.len() + self.pending.len() > size {
+                return Err(format!("more than the {} bytes announced", size));
+            }
+            self.image.append(&mut self.pending);
+        }
+        Ok(())
+    }
+}
+impl Raspbootin {
+    fn finish(self) -> Result<Vec<u8>, String> {
+        match self.size {
+            Some(size) if size == self.image.len() => Ok(self.image),
+            size => Err(format!(
+                "{} bytes received, {:?} announced",
+                self.image.len(),
+                size
+            )),
+        }
+    }
+}
+
+/// The device side of the chunked protocol.
+#[derive(Debug)]
+struct Chunked {
+    chunk_size: usize,
+    pending: Vec<u8>,
+    size: Option<usize>,
+    image: Vec<u8>,
+    ended: bool,
+}
+impl Chunked {
+    fn new(chunk_size: usize) -> Self {
+        Chunked {
+            chunk_size,
+            pending: vec![],
+            size: None,
+            image: vec![],
+            ended: false,
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, String> {
+        if !self.ended {
+            return Err(format!("no `EOT` after {} bytes", self.image.len()));
+        }
+        Ok(self.image)
+    }
+}
+impl Receiver for Chunked {
+    fn feed(&mut self, data: &[u8]) -> Result<(), String> {
+        self.pending.extend_from_slice(data);
+        loop {
+            let size = match self.size {
+                Some(size) => size,
+                None => match take_size(&mut self.pending) {
+                    Some(size) => {
+                        self.size = Some(size);
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+            };
+            let first = match self.pending.first() {
+                Some(first) => *first,
+                None => return Ok(()),
+            };
+            if self.ended {
+                return Err(format!("{:02x?} after the `EOT`", self.pending));
+            } else if self.image.len() == size {
+                if first != chunked::EOT {
+                    return Err(format!("{:#04x} instead of the `EOT`", first));
+                }
+                self.pending.remove(0);
+                self.ended = true;
+            } else {
+                if first != chunked::STX {
+                    return Err(format!("{:#04x} instead of an `STX`", first));
+                }
+                let len = self.chunk_size.min(size - self.image.len());
+                if self.pending.len() < 1 + len {
+                    return Ok(());
+                }
+                self.image.extend(self.pending.drain(..1 + len).skip(1));
+            }
+        }
+    }
+}
+
+/// The device side of the version 2 of the chunked protocol.
+#[derive(Debug)]
+struct ChunkedV2 {
+    payload: usize,
+    pending: Vec<u8>,
+    size: Option<usize>,
+    negotiated: bool,
+    next_sequence: u8,
+    image: Vec<u8>,
+    ended: bool,
+}
+impl ChunkedV2 {
+    fn new(payload: usize) -> Self {
+        ChunkedV2 {
+            payload,
+            pending: vec![],
+            size: None,
+            negotiated: false,
+            next_sequence: 0,
+            image: vec![],
+            ended: false,
+        }
+    }
+
+    fn finish(self) -> Result<Vec<u8>, String> {
+        if !self.ended {
+            return Err(format!("no `EOT` after {} bytes", self.image.len()));
+        }
+        Ok(self.image)
+    }
+}
+impl Receiver for ChunkedV2 {
+    fn feed(&mut self, data: &[u8]) -> Result<(), String> {
+        self.pending.extend_from_slice(data);
+        loop {
+            let size = match self.size {
+                Some(size) => size,
+                None => match take_size(&mut self.pending) {
+                    Some(size) => {
+                        self.size = Some(size);
+                        continue;
+                    }
+                    None => return Ok(()),
+                },
+            };
+            if !self.negotiated {
+                if self.pending.len() < 2 {
+                    return Ok(());
+                }
+                if self.pending[..2] != [chunked::SYN, chunked::VERSION] {
+                    return Err(format!(
+                        "{:02x?} instead of the version",
+                        &self.pending[..2]
+                    ));
+                }
+                self.pending.drain(..2);
+                self.negotiated = true;
+                continue;
+            }
+            let first = match self.pending.first() {
+                Some(first) => *first,
+                None => return Ok(()),
+            };
+            if self.ended {
+                return Err(format!("{:02x?} after the `EOT`", self.pending));
+            }
+            // A frame sent again may come after the last one.
+            if self.image.len() == size && first == chunked::EOT {
+                self.pending.remove(0);
+                self.ended = true;
+                continue;
+            }
+            if first != chunked::STX {
+                return Err(format!("{:#04x} instead of an `STX`", first));
+            }
+            if self.pending.len() < 4 {
+                return Ok(());
+            }
+            let sequence = self.pending[1];
+            let len = u16::from_le_bytes([self.pending[2], self.pending[3]]) as usize;
+            if len == 0 || len > self.payload {
+                return Err(format!("a frame of {} bytes", len));
+            }
+            if self.pending.len() < chunked::FRAME_OVERHEAD + len {
+                return Ok(());
+            }
+            let frame: Vec<u8> = self
+                .pending
+                .drain(..chunked::FRAME_OVERHEAD + len)
+                .collect();
+            let mut crc = Crc32::new();
+            crc.update(&frame[1..4 + len]);
+            if crc.finalize().to_le_bytes() != frame[4 + len..] {
+                return Err(format!("the frame {} has a bad CRC-32", sequence));
+            }
+            if sequence == self.next_sequence {
+                if self.image.len() + len > size {
+                    return Err(format!("more than the {} bytes announced", size));
+                }
+                self.image.extend_from_slice(&frame[4..4 + len]);
+                self.next_sequence = self.next_sequence.wrapping_add(1);
+            } else if sequence != self.next_sequence.wrapping_sub(1) {
+                return Err(format!(
+                    "the frame {} instead of the frame {}",
+                    sequence, self.next_sequence
+                ));
+            }
+        }
+    }
+}
+
+/// The device side of XMODEM-CRC, or YMODEM for a single file.
+#[derive(Debug)]
+struct Xmodem {
+    pending: Vec<u8>,
+    /// The name and size of the YMODEM file, once its header was received.
+    file: Option<(String, usize)>,
+    ymodem: bool,
+    next_block: u8,
+    /// Whether the `EOT` was received.
+    ended: bool,
+    /// Whether the YMODEM batch was ended.
+    batch_ended: bool,
+    data: Vec<u8>,
+}
+impl Xmodem {
+    fn new(ymodem: bool) -> Self {
+        Xmodem {
+            pending: vec![],
+            file: None,
+            ymodem,
+            next_block: if ymodem { 0 } else { 1 },
+            ended: false,
+            batch_ended: false,
+            data: vec![],
+        }
+    }
+
+    /// The YMODEM file and the data received, cut to the size of the file.
+    #[allow(clippy::type_complexity)]
+    fn finish(mut self) -> Result<(Option<(String, usize)>, Vec<u8>), String> {
+        if !self.ended || self.ymodem != self.batch_ended {
+            return Err(format!("unfinished after {} bytes", self.data.len()));
+        }
+        if let Some((_, size)) = &self.file {
+            let padding = self.data.get(*size..).unwrap_or_default();
+            if padding.len() >= xmodem::YMODEM_BLOCK_SIZE || padding.iter().any(|b| *b != SUB) {
+                return Err(format!("{} bytes for a file of {}", self.data.len(), size));
+            }
+            self.data.truncate(*size);
+        }
+        Ok((self.file, self.data))
+    }
+
+    /// The number and data of the block at the start of the pending bytes,
+    /// once it was all received.
+    fn take_block(&mut self) -> Result<Option<(u8, Vec<u8>)>, String> {
+        let size = match self.pending[0] {
+            xmodem::SOH => xmodem::BLOCK_SIZE,
+            xmodem::STX => xmodem::YMODEM_BLOCK_SIZE,
+            other => return Err(format!("{:#04x} instead of a block", other)),
+        };
+        if self.pending.len() < 3 + size + 2 {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = self.pending.drain(..3 + size + 2).collect();
+        let (number, data, crc) = (frame[1], &frame[3..3 + size], &frame[3 + size..]);
+        if frame[2] != 255 - number {
+            return Err(format!("block {} numbered {} too", number, frame[2]));
+        }
+        if crc != crc16(data).to_be_bytes() {
+            return Err(format!("bad CRC for the block {}", number));
+        }
+        Ok(Some((number, data.to_vec())))
+    }
+}
+impl Receiver for Xmodem {
+    fn feed(&mut self, data: &[u8]) -> Result<(), String> {
+        self.pending.extend_from_slice(data);
+        while !self.pending.is_empty() {
+            if self.batch_ended || (self.ended && !self.ymodem) {
+                return Err(format!("{:02x?} after the end", self.pending));
+            }
+            if self.pending[0] == xmodem::EOT && !self.ended && self.file.is_some() == self.ymodem {
+                self.pending.remove(0);
+                self.ended = true;
+                continue;
+            }
+            let (number, data) = match self.take_block()? {
+                Some(block) => block,
+                None => return Ok(()),
+            };
+            if self.ended {
+                if number != 0 || data.iter().any(|b| *b != 0) {
+                    return Err("the batch is not ended by an empty block 0".into());
+                }
+                self.batch_ended = true;
+            } else if number != self.next_block {
+                return Err(format!("block {} instead of {}", number, self.next_block));
+            } else if self.ymodem && self.file.is_none() {
+                let fields: Vec<&[u8]> = data.splitn(3, |b| *b == 0).collect();
+                let name = String::from_utf8_lossy(fields[0]).into_owned();
+                let size = fields
+                    .get(1)
+                    .and_then(|size| std::str::from_utf8(size).ok()?.parse().ok())
+                    .ok_or(format!("bad file header {:02x?}", data))?;
+                self.file = Some((name, size));
+                self.next_block = 1;
+            } else {
+                self.data.extend_from_slice(&data);
+                self.next_block = self.next_block.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn properties_hold() {
+    let failures = check_all(100);
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+#[test]
+fn failures_are_shrunk() {
+    let property: Property = |case| match case.image.len() {
+        0..=3 => Ok(()),
+        len => Err(format!("{} bytes", len)),
+    };
+    let failure = check("long", property, 1000).unwrap_err();
+    let case = failure.case.clone().unwrap();
+    assert_eq!(case.image.len(), 4);
+    assert_eq!(failure.message, "4 bytes");
+    assert_eq!(property(&case), Err("4 bytes".into()));
+    assert!(failure
+        .to_string()
+        .starts_with("property `long` failed: 4 bytes, for Case {"));
+}
+
+#[test]
+fn cuts_keep_all_the_data() {
+    let data = b"0123456789";
+    assert_eq!(
+        cut(data, &[3, 0]),
+        [&b"012"[..], b"", b"345", b"", b"678", b"", b"9"]
+    );
+    assert_eq!(cut(data, &[0, 0]), [&b""[..], b"", b"0123456789"]);
+    assert_eq!(cut(data, &[]), [&data[..]]);
+}
+
+#[test]
+fn broken_framing_is_caught() {
+    // A block with the wrong number, or cut short.
+    let mut receiver = Xmodem::new(false);
+    let frame = block_frame(2, b"data", xmodem::BLOCK_SIZE);
+    assert!(receiver.feed(&frame).is_err());
+    let mut receiver = Raspbootin::default();
+    receiver.feed(&size_frame(8)).unwrap();
+    receiver.feed(b"short").unwrap();
+    assert!(receiver.finish().is_err());
+}
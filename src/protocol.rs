@@ -0,0 +1,258 @@
+//! Description of the wire protocol spoken with the bootloader, as configured.
+//!
+//! Firmware authors implement the device side against `bootcom protocol
+//! describe`, which prints the trigger patterns, the frames of the transfer
+//! protocols, their handshakes, checksums and chunking. The description is
+//! generated by the modules implementing each protocol, from the very
+//! constants they send and expect, so that it can't drift from what `bootcom`
+//! actually does. Only what the settings enable is described: the protocols of
+//! the triggers and of the U-Boot handoff, the streams, the encryption, the signature header
+//! and the trailer of the image, the host services, the memory dumps, the time synchronization
+//! and the software flow control.
+//!
+//! **Example**
+//! ```
+//! use bootcom::{protocol, SettingsBuilder};
+//!
+//! let sections = protocol::describe(&SettingsBuilder::default().finalize());
+//! assert_eq!(sections[1].title, "Triggers");
+//! assert!(sections[1].lines[0].text.starts_with("03 03 03"));
+//! ```
+
+use std::fmt;
+
+use crate::settings::{FlowControl, Settings, TransferProtocol};
+use crate::signing;
+use crate::utils::{
+    chunked, crc, dump, encryption, host_services, kernel, line_format, streams, time_sync,
+    trailer, xmodem, xonxoff, DUMP_TRIGGER, SERVICE_TRIGGER, TIME_TRIGGER,
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Who sends a message.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Sender {
+    Host,
+    Device,
+}
+
+/// A line of the description of a protocol.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Line {
+    /// Who sends the message, `None` for the notes about the protocol.
+    pub sender: Option<Sender>,
+    pub text: String,
+}
+
+/// A part of the wire protocol.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Section {
+    pub title: String,
+    /// The messages in the order they are exchanged, and the notes.
+    pub lines: Vec<Line>,
+}
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        for line in &self.lines {
+            let sender = match line.sender {
+                Some(Sender::Host) => "host",
+                Some(Sender::Device) => "device",
+                None => "",
+            };
+            writeln!(f, "  {:<8}{}", sender, line.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// The wire protocol enabled by the `settings`.
+pub fn describe(settings: &Settings) -> Vec<Section> {
+    let mut sections = vec![section("Line", vec![note(line_format(settings))])];
+
+    let mut triggers: Vec<Line> = settings
+        .triggers
+        .iter()
+        .map(|trigger| {
+            let mut text = format!(
+                "{}  kernel image, {}",
+                hex(&trigger.pattern),
+                trigger.protocol
+            );
+            if let Some(image) = &trigger.image {
+                text.push_str(&format!(" ({})", image));
+            }
+            device(text)
+        })
+        .collect();
+    if settings.host_dir.is_some() {
+        triggers.push(device(format!("{}  host services", hex(&SERVICE_TRIGGER))));
+    }
+    if settings.dump_dir.is_some() {
+        triggers.push(device(format!("{}  memory dump", hex(&DUMP_TRIGGER))));
+    }
+    if settings.time_sync {
+        triggers.push(device(format!("{}  host time", hex(&TIME_TRIGGER))));
+    }
+    triggers.push(note(
+        "recognized at the end of the console output, in terminal mode",
+    ));
+    sections.push(section("Triggers", triggers));
+
+    let mut protocols: Vec<TransferProtocol> = vec![];
+    let uboot = settings.uboot.as_ref().and_then(|script| script.transfer());
+    for protocol in settings
+        .triggers
+        .iter()
+        .map(|trigger| trigger.protocol)
+        .chain(uboot.map(|(_, protocol)| protocol))
+    {
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
+        }
+    }
+    let flow_controlled = settings.flow_control == FlowControl::Software;
+    for protocol in protocols {
+        let lines = match protocol {
+            TransferProtocol::Raspbootin => kernel::wire_format(flow_controlled),
+            TransferProtocol::Chunked => chunked::wire_format(flow_controlled),
+            TransferProtocol::XmodemCrc => xmodem::wire_format(false),
+            TransferProtocol::Ymodem => xmodem::wire_format(true),
+        };
+        sections.push(section(&protocol.to_string(), lines));
+    }
+    if !settings.streams.is_empty() {
+        sections.push(section("Streams", streams::wire_format(&settings.streams)));
+    }
+    if !settings.transfer_keys.is_empty() {
+        sections.push(section("Encryption", encryption::wire_format()));
+    }
+    if settings.signing_key.is_some() {
+        sections.push(section("Signature header", signing::wire_format()));
+    }
+    if settings.trailer.is_some() {
+        sections.push(section("Image trailer", trailer::wire_format()));
+    }
+
+    if settings.host_dir.is_some() {
+        sections.push(section("Host services", host_services::wire_format()));
+    }
+    if settings.dump_dir.is_some() {
+        sections.push(section("Memory dumps", dump::wire_format()));
+    }
+    if settings.time_sync {
+        sections.push(section("Time synchronization", time_sync::wire_format()));
+    }
+    if flow_controlled {
+        sections.push(section("Software flow control", xonxoff::wire_format()));
+    }
+    sections.push(section("Checksums", crc::wire_format()));
+    sections
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// A message sent by the host.
+pub(crate) fn host(text: impl Into<String>) -> Line {
+    Line {
+        sender: Some(Sender::Host),
+        text: text.into(),
+    }
+}
+
+/// A message sent by the device.
+pub(crate) fn device(text: impl Into<String>) -> Line {
+    Line {
+        sender: Some(Sender::Device),
+        text: text.into(),
+    }
+}
+
+/// A note about the protocol.
+pub(crate) fn note(text: impl Into<String>) -> Line {
+    Line {
+        sender: None,
+        text: text.into(),
+    }
+}
+
+/// A control byte of a protocol, e.g. `` `ACK` (0x06) ``.
+pub(crate) fn byte(name: &str, value: u8) -> String {
+    format!("`{}` ({:#04x})", name, value)
+}
+
+/// The `bytes` in hex, e.g. `03 03 03`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(" ")
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+fn section(title: &str, lines: Vec<Line>) -> Section {
+    Section {
+        title: title.into(),
+        lines,
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn configured_parts_only() {
+    use crate::settings::{SettingsBuilder, Trigger};
+
+    let titles = |settings: &Settings| -> Vec<String> {
+        describe(settings).into_iter().map(|s| s.title).collect()
+    };
+    let settings = SettingsBuilder::default().finalize();
+    assert_eq!(
+        titles(&settings),
+        vec!["Line", "Triggers", "raspbootin", "Checksums"]
+    );
+
+    let settings = SettingsBuilder::default()
+        .triggers(vec![
+            Trigger::raspbootin(),
+            Trigger {
+                pattern: vec![b'C'],
+                protocol: TransferProtocol::XmodemCrc,
+                image: None,
+            },
+        ])
+        .flow_control(FlowControl::Software)
+        .time_sync(true)
+        .trailer(Default::default())
+        .finalize();
+    let sections = describe(&settings);
+    assert_eq!(
+        sections
+            .iter()
+            .map(|s| s.title.as_str())
+            .collect::<Vec<_>>(),
+        vec![
+            "Line",
+            "Triggers",
+            "raspbootin",
+            "xmodem-crc",
+            "Image trailer",
+            "Time synchronization",
+            "Software flow control",
+            "Checksums"
+        ]
+    );
+    let triggers = sections[1].to_string();
+    assert!(triggers.contains("  device  43  kernel image, xmodem-crc\n"));
+    assert!(triggers.contains("16 16 16  host time"));
+    // The device is given the chance to pause the transfer more often.
+    assert!(sections[2].to_string().contains("1.0 KiB"));
+}
@@ -0,0 +1,407 @@
+//! Kernel image push in a single call, for build scripts and test rigs which
+//! just want to "flash over serial" without the interactive session.
+//!
+//! [`push_image`] opens the port of the settings, waits for the bootloader to
+//! send one of the triggers of the settings (or sends right away when forced),
+//! sends the image with the protocol of the trigger, and then checks that the
+//! bootloader did not ask for the image again, or that the expected text shows
+//! up on the console. Nothing is read from the keyboard, and the progress is
+//! reported to the observer of the settings, if any. The result is recorded
+//! when the port is the one of a board of the [`boards`](crate::boards)
+//! inventory, the push is archived along with the console output checked
+//! when the settings have an [`archive`](crate::archive), and counted to their
+//! [`usage`](crate::usage) file, if any.
+//!
+//! Host tools needing their own logic between the trigger and the transfer
+//! (resetting another board, picking the image from what the bootloader
+//! printed...) can instead go through the steps one at a time on a port they
+//! keep: [`open_port`], [`wait_for_trigger`] and [`send_image`].
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{push::PushOptions, push_image, SettingsBuilder};
+//!
+//! let settings = SettingsBuilder::default()
+//!     .path("/dev/ttyUSB0")
+//!     .baud_rate(921_600)
+//!     .finalize();
+//! let options = PushOptions {
+//!     expect: Some("Booting".into()),
+//!     ..PushOptions::default()
+//! };
+//! match push_image(&settings, "target/kernel8.img", &options) {
+//!     Ok(report) => println!("{}", report),
+//!     Err(e) => eprintln!("push failed: {}", e),
+//! }
+//! ```
+
+use std::{
+    error::Error,
+    fmt,
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use serialport::SerialPort;
+
+use crate::{
+    archive::Archived,
+    boards::{self, BootResult},
+    settings::{Settings, TransferProtocol, Trigger},
+    usage,
+    utils::{apply_straps, is_transient, open_and_setup_port, send_kernel, TriggerMatcher},
+};
+
+pub use crate::progress::TransferReport;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Options for a kernel image push.
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    /// Send the image right away instead of waiting for a trigger, for
+    /// bootloaders already waiting for it.
+    pub force: bool,
+    /// The protocol used when the transfer is forced. Defaults to the one of
+    /// the first trigger of the settings.
+    pub protocol: Option<TransferProtocol>,
+    /// How long to wait for the bootloader to send a trigger.
+    pub trigger_timeout: Duration,
+    /// The text expected on the console once the image was sent, telling that
+    /// it started.
+    pub expect: Option<String>,
+    /// How long to wait after the transfer for the expected text, or, when
+    /// there is none, for the bootloader to ask for the image again.
+    pub verify_timeout: Duration,
+}
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            force: false,
+            protocol: None,
+            trigger_timeout: Duration::from_secs(30),
+            expect: None,
+            verify_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Why a kernel image push failed.
+#[derive(Debug)]
+pub enum PushError {
+    /// The serial port could not be opened.
+    Port(serialport::Error),
+    /// No trigger was received in time.
+    NoTrigger,
+    /// The image could not be read, or the transfer failed.
+    Transfer(Box<dyn Error + Send + Sync>),
+    /// The bootloader asked for the image again right after the transfer.
+    Rejected,
+    /// The expected text did not show up in time after the transfer.
+    NotVerified {
+        /// The console output received meanwhile.
+        output: Vec<u8>,
+    },
+}
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Port(e) => write!(f, "could not open the serial port: {}", e),
+            PushError::NoTrigger => f.write_str("no trigger received in time"),
+            PushError::Transfer(e) => write!(f, "transfer failed: {}", e),
+            PushError::Rejected => f.write_str("the bootloader asked for the image again"),
+            PushError::NotVerified { .. } => {
+                f.write_str("the expected output did not show up in time")
+            }
+        }
+    }
+}
+impl Error for PushError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PushError::Port(e) => Some(e),
+            PushError::Transfer(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// Push the kernel `image` to the device on the port of the `settings`, and
+/// verify that it was accepted.
+pub fn push_image(
+    settings: &Settings,
+    image: &str,
+    options: &PushOptions,
+) -> Result<TransferReport, PushError> {
+    let mut archived = None;
+    let pushed = push_and_verify(settings, image, options, &mut archived);
+    let result = match &pushed {
+        Ok(_) if options.expect.is_some() => BootResult::Booted,
+        Ok(_) => BootResult::Pushed,
+        Err(e) => BootResult::Failed(e.to_string()),
+    };
+    if let Some(archived) = &mut archived {
+        archived.result(&result);
+    }
+    boards::record(settings, result);
+    usage::record(settings, pushed.as_ref().ok());
+    pushed
+}
+
+/// Open and configure the port of the `settings`, driving their boot mode
+/// straps while it is opened: they are released once their hold time is over,
+/// before the port is returned.
+pub fn open_port(settings: &Settings) -> Result<Box<dyn SerialPort>, PushError> {
+    let straps = apply_straps(settings)
+        .map_err(|e| info!("could not drive the boot mode straps: {}", e))
+        .unwrap_or_default();
+    let port = open_and_setup_port(&non_interactive(settings)).map_err(PushError::Port);
+    straps.release();
+    port
+}
+
+/// Wait up to `timeout` for the bootloader on the `port` to send one of the
+/// `triggers`, and return the one received. What the bootloader printed before
+/// is discarded.
+///
+/// **Example**
+/// ```no_run
+/// use bootcom::{push, SettingsBuilder};
+/// use std::time::Duration;
+///
+/// let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
+/// let mut port = push::open_port(&settings)?;
+/// let trigger = push::wait_for_trigger(&mut port, &settings.triggers, Duration::from_secs(30))?;
+/// // The bootloader waits for the image, the host tool can do its own thing.
+/// let image = trigger.image.as_deref().unwrap_or("kernel8.img");
+/// let report = push::send_image(&mut port, &settings, trigger.protocol, image)?;
+/// println!("{}", report);
+/// # Ok::<(), push::PushError>(())
+/// ```
+pub fn wait_for_trigger(
+    port: &mut Box<dyn SerialPort>,
+    triggers: &[Trigger],
+    timeout: Duration,
+) -> Result<Trigger, PushError> {
+    let mut matcher = TriggerMatcher::new(
+        triggers
+            .iter()
+            .map(|trigger| (trigger.pattern.clone(), trigger.clone()))
+            .collect(),
+    );
+    let mut found = None;
+    read_until(port, timeout, |data| {
+        found = matcher.feed(data).1;
+        found.is_some()
+    })?;
+    found.ok_or(PushError::NoTrigger)
+}
+
+/// Send the kernel `image` on the `port` with the `protocol`, once the
+/// bootloader asked for it. The `output` of the report is left empty.
+pub fn send_image(
+    port: &mut Box<dyn SerialPort>,
+    settings: &Settings,
+    protocol: TransferProtocol,
+    image: &str,
+) -> Result<TransferReport, PushError> {
+    // Each push starts over, the interrupted ones are not kept track of.
+    send_kernel(
+        port,
+        &non_interactive(settings),
+        protocol,
+        Some(image),
+        &mut None,
+    )
+    .map_err(|e| PushError::Transfer(e.to_string().into()))?
+    // Nobody is asked to pick another image without the keyboard.
+    .ok_or_else(|| PushError::Transfer("no kernel image selected".into()))
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Push the `image` and verify it, archiving the push in `archived`.
+fn push_and_verify(
+    settings: &Settings,
+    image: &str,
+    options: &PushOptions,
+    archived: &mut Option<Archived>,
+) -> Result<TransferReport, PushError> {
+    let mut port = open_port(settings)?;
+    let protocol = if options.force {
+        options
+            .protocol
+            .or_else(|| settings.triggers.first().map(|t| t.protocol))
+            .unwrap_or(TransferProtocol::Raspbootin)
+    } else {
+        wait_for_trigger(&mut port, &settings.triggers, options.trigger_timeout)?.protocol
+    };
+    let mut report = send_image(&mut port, settings, protocol, image)?;
+    *archived = Archived::start(settings, &report).unwrap_or_else(|e| {
+        info!("could not archive the push: {}", e);
+        None
+    });
+    let verified = verify(&mut port, settings, options);
+    if let Some(archived) = archived {
+        match &verified {
+            Ok(output) | Err(PushError::NotVerified { output }) => archived.output(output),
+            Err(_) => (),
+        }
+    }
+    report.output = verified?;
+    Ok(report)
+}
+
+/// The `settings` without any interaction: nobody is at the keyboard to pick
+/// another image or port.
+fn non_interactive(settings: &Settings) -> Settings {
+    let mut settings = settings.clone();
+    settings.keyboard = false;
+    settings
+}
+
+/// Check the bootloader does not ask for the image again after the transfer,
+/// and that the expected text, if any, shows up. Returns the console output
+/// received meanwhile.
+fn verify(
+    port: &mut Box<dyn SerialPort>,
+    settings: &Settings,
+    options: &PushOptions,
+) -> Result<Vec<u8>, PushError> {
+    let patterns = settings
+        .triggers
+        .iter()
+        .map(|trigger| (trigger.pattern.clone(), ()))
+        .collect();
+    let mut triggers = TriggerMatcher::new(patterns);
+    let mut output = vec![];
+    let mut rejected = false;
+    read_until(port, options.verify_timeout, |data| {
+        rejected = triggers.feed(data).1.is_some();
+        output.extend_from_slice(data);
+        rejected || expected(&output, options.expect.as_deref())
+    })?;
+    if rejected {
+        Err(PushError::Rejected)
+    } else if options.expect.is_some() && !expected(&output, options.expect.as_deref()) {
+        Err(PushError::NotVerified { output })
+    } else {
+        Ok(output)
+    }
+}
+
+fn expected(output: &[u8], expect: Option<&str>) -> bool {
+    expect.is_some_and(|text| String::from_utf8_lossy(output).contains(text))
+}
+
+/// Feed what is received from the `port` to `done` until it returns `true` or
+/// the `timeout` expires.
+fn read_until(
+    port: &mut Box<dyn SerialPort>,
+    timeout: Duration,
+    mut done: impl FnMut(&[u8]) -> bool,
+) -> Result<(), PushError> {
+    let started = Instant::now();
+    let mut buf = [0u8; 1024];
+    while started.elapsed() < timeout {
+        let available = port.bytes_to_read().map_err(port_error)?;
+        if available == 0 {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        match port.read(&mut buf) {
+            Ok(n) if done(&buf[..n]) => break,
+            Ok(_) => (),
+            Err(ref e) if is_transient(e) => (),
+            Err(e) => return Err(PushError::Transfer(e.into())),
+        }
+    }
+    Ok(())
+}
+
+fn port_error(e: serialport::Error) -> PushError {
+    PushError::Transfer(Box::new(e))
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(unix)]
+#[test]
+fn image_is_pushed_and_verified() {
+    use crate::settings::SettingsBuilder;
+    use serialport::TTYPort;
+    use std::io::Write;
+
+    let image = std::env::temp_dir().join(format!("bootcom-push-{}.img", std::process::id()));
+    std::fs::write(&image, vec![0x5a; 3000]).unwrap();
+    let (mut master, slave) = TTYPort::pair().unwrap();
+    let settings = SettingsBuilder::default()
+        .path(slave.name().unwrap())
+        .finalize();
+
+    // A bootloader sending the trigger, then booting the image received.
+    let bootloader = thread::spawn(move || {
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+        // Until the port is opened in raw mode, the trigger would be taken
+        // for interrupts by the line discipline.
+        thread::sleep(Duration::from_millis(200));
+        master.write_all(b"waiting\x03\x03\x03").unwrap();
+        let mut size = [0u8; 4];
+        master.read_exact(&mut size).unwrap();
+        master.write_all(b"OK").unwrap();
+        let mut image = vec![0u8; u32::from_le_bytes(size) as usize];
+        master.read_exact(&mut image).unwrap();
+        master.write_all(b"Booting...\r\n").unwrap();
+        thread::sleep(Duration::from_millis(500));
+        image
+    });
+
+    let options = PushOptions {
+        expect: Some("Booting".into()),
+        ..PushOptions::default()
+    };
+    let report = push_image(&settings, &image.to_string_lossy(), &options).unwrap();
+    assert_eq!(report.protocol, TransferProtocol::Raspbootin);
+    assert_eq!(report.bytes, 3000);
+    assert_eq!(report.retries, 0);
+    let mut crc = crate::utils::Crc32::new();
+    crc.update(&[0x5a; 3000]);
+    assert_eq!(report.crc, crc.finalize());
+    assert!(String::from_utf8_lossy(&report.output).contains("Booting"));
+    assert_eq!(bootloader.join().unwrap(), vec![0x5a; 3000]);
+    drop(slave);
+    std::fs::remove_file(image).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn trigger_is_waited_for() {
+    use serialport::TTYPort;
+    use std::io::Write;
+
+    let (mut master, slave) = TTYPort::pair().unwrap();
+    let mut port: Box<dyn SerialPort> = Box::new(slave);
+    let triggers = [
+        Trigger::raspbootin(),
+        Trigger {
+            pattern: b"CCC".to_vec(),
+            protocol: TransferProtocol::XmodemCrc,
+            image: Some("debug.img".into()),
+        },
+    ];
+    assert!(matches!(
+        wait_for_trigger(&mut port, &triggers, Duration::from_millis(100)),
+        Err(PushError::NoTrigger)
+    ));
+    master.write_all(b"U-Boot> CCC").unwrap();
+    let trigger = wait_for_trigger(&mut port, &triggers, Duration::from_secs(5)).unwrap();
+    assert_eq!(trigger, triggers[1]);
+}
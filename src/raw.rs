@@ -0,0 +1,227 @@
+//! Transparent bridge between the standard streams and the device, for the
+//! external tools which only need a byte pipe to the board (`socat`, `expect`,
+//! a test runner...) but would rather not pick the port, open it and wait for
+//! it to come back after a reset themselves.
+//!
+//! [`bridge`] selects and opens the port of the settings as the interactive
+//! session does, then copies the standard input to the device and what the
+//! device sends to the standard output, 8-bit clean: no triggers, no quirks,
+//! no codecs, no flow control characters taken out. When the port goes away,
+//! it is reopened once it is back, the data received meanwhile on the standard
+//! input being sent then. The bridge ends when the standard input is closed,
+//! after sending what was read. Only the standard output carries the data, the
+//! messages go to the standard error.
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{raw, SettingsBuilder};
+//!
+//! let settings = SettingsBuilder::default()
+//!     .path("/dev/ttyUSB0")
+//!     .baud_rate(115_200)
+//!     .finalize();
+//! if let Err(e) = raw::bridge(&settings) {
+//!     eprintln!("bridge failed: {}", e);
+//! }
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+use console::style;
+
+use crate::{
+    settings::Settings,
+    transport::Transport,
+    utils::{is_transient, open_and_setup_port, select_port, wait_for_port},
+};
+
+/// How long to wait for the standard input when the device sent nothing.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// Bridge the standard input and output to the device on the port of the
+/// `settings`, selecting it first when there is none, until the standard input
+/// is closed.
+pub fn bridge(settings: &Settings) -> io::Result<()> {
+    let mut settings = settings.clone();
+    // The standard input belongs to the device, not to the prompts.
+    settings.keyboard = false;
+    if settings.path.is_none() && settings.port_match.is_none() {
+        settings.path = select_port(&settings).map_err(io::Error::other)?;
+        if settings.path.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no serial port selected",
+            ));
+        }
+    }
+
+    let input = read_input();
+    let mut output = io::stdout();
+    let mut buf = vec![0u8; settings.max_read_size.max(1)];
+    let mut port = reopen(&mut settings)?;
+    eprintln!(
+        "{}",
+        settings
+            .messages
+            .text_with("raw.bridging", &[("port", &style(port.describe()).cyan())])
+    );
+    loop {
+        match pump(&mut port, &input, &mut output, &mut buf)? {
+            Stop::InputClosed => return Ok(()),
+            Stop::PortLost(e) => {
+                eprintln!(
+                    "{}",
+                    style(settings.messages.text_with(
+                        "raw.lost",
+                        &[
+                            ("path", &settings.path.as_deref().unwrap_or_default()),
+                            ("error", &e)
+                        ]
+                    ))
+                    .yellow()
+                );
+                drop(port);
+                port = reopen(&mut settings)?;
+                eprintln!(
+                    "{}",
+                    settings.messages.text_with(
+                        "raw.bridging_again",
+                        &[("port", &style(port.describe()).cyan())]
+                    )
+                );
+            }
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Why the copy between the streams and the device stopped.
+#[derive(Debug)]
+enum Stop {
+    /// The standard input was closed, and what was read from it sent.
+    InputClosed,
+    /// The port failed.
+    PortLost(io::Error),
+}
+
+/// Read the standard input on a thread of its own, the channel being closed
+/// with it.
+fn read_input() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0u8; 4096];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
+
+/// Wait for the port of the `settings` to be present and open it, the path
+/// found for their port rule, if any, kept in the `settings`.
+fn reopen(settings: &mut Settings) -> io::Result<Box<dyn serialport::SerialPort>> {
+    if let Some(path) = wait_for_port(settings).map_err(io::Error::other)? {
+        settings.path = Some(path);
+    }
+    Ok(open_and_setup_port(settings)?)
+}
+
+/// Copy what the device sends on the `port` to the `output`, and the `input`
+/// to the device, until either the input is closed or the port fails. Only the
+/// failures to write to the `output` are errors.
+fn pump(
+    port: &mut dyn Transport,
+    input: &Receiver<Vec<u8>>,
+    output: &mut dyn Write,
+    buf: &mut [u8],
+) -> io::Result<Stop> {
+    loop {
+        // Only read what is available, for the read to return right away.
+        let available = match port.bytes_to_read() {
+            Ok(available) => available as usize,
+            Err(e) => return Ok(Stop::PortLost(e)),
+        };
+        if available > 0 {
+            let wanted = available.min(buf.len());
+            match port.read(&mut buf[..wanted]) {
+                Ok(n) => {
+                    output.write_all(&buf[..n])?;
+                    output.flush()?;
+                }
+                Err(e) if is_transient(&e) => {}
+                Err(e) => return Ok(Stop::PortLost(e)),
+            }
+        }
+
+        // Send everything read so far, waiting a bit for more when the device
+        // was silent.
+        let mut received = if available > 0 {
+            input
+                .try_recv()
+                .map_err(|e| e == TryRecvError::Disconnected)
+        } else {
+            input
+                .recv_timeout(POLL_INTERVAL)
+                .map_err(|e| e == RecvTimeoutError::Disconnected)
+        };
+        loop {
+            match received {
+                Ok(data) => {
+                    if let Err(e) = port.write_all(&data).and_then(|_| port.flush()) {
+                        return Ok(Stop::PortLost(e));
+                    }
+                }
+                Err(true) => return Ok(Stop::InputClosed),
+                Err(false) => break,
+            }
+            received = input
+                .try_recv()
+                .map_err(|e| e == TryRecvError::Disconnected);
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn bytes_are_copied_untouched() {
+    use crate::transport::MemoryTransport;
+
+    let mut port = MemoryTransport::new(|_| vec![]);
+    port.queue_input(b"\x01T\x03\x03\x03\r\n\xff\x00");
+    let (sender, input) = mpsc::channel();
+    sender.send(b"\x11\x13".to_vec()).unwrap();
+    sender.send(b"\x1b[A\x00\xff\r".to_vec()).unwrap();
+    drop(sender);
+
+    let mut output = vec![];
+    let mut buf = [0u8; 64];
+    let stop = pump(&mut port, &input, &mut output, &mut buf).unwrap();
+    assert!(matches!(stop, Stop::InputClosed));
+    assert_eq!(output, b"\x01T\x03\x03\x03\r\n\xff\x00");
+    assert_eq!(port.written(), b"\x11\x13\x1b[A\x00\xff\r");
+}
@@ -0,0 +1,442 @@
+//! Resuming a previous run of `bootcom`.
+//!
+//! When a resume file is set in the settings, the port of the session, with the
+//! USB identity of its controller, the line parameters and the last kernel
+//! image sent are saved to it as TOML whenever they change. The command line
+//! always saves them, by default to `bootcom/session.toml` in the user state
+//! directory (`$XDG_STATE_HOME` or `~/.local/state` on Unix, `%LOCALAPPDATA%`
+//! on Windows), and `bootcom --resume` reads them back to reconnect to the same
+//! device after a host reboot or an accidental terminal close. A USB device
+//! given another path meanwhile is found by its identity:
+//!
+//! ```toml
+//! kernel_image = "target/kernel8.img"
+//!
+//! [line]
+//! baud_rate = 921600
+//! data_bits = 8
+//! flow_control = "none"
+//! parity = "none"
+//! stop_bits = 1
+//!
+//! [port]
+//! path = "/dev/ttyUSB0"
+//! pid = 24577
+//! serial_number = "A10KZP3V"
+//! vid = 1027
+//! ```
+//!
+//! **Example**
+//! ```
+//! use bootcom::{resume, SettingsBuilder};
+//!
+//! let state = resume::parse("kernel_image = \"kernel8.img\"").unwrap();
+//! let mut settings = SettingsBuilder::default().finalize();
+//! state.apply(&mut settings);
+//! assert_eq!(settings.kernel_image.as_deref(), Some("kernel8.img"));
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info};
+use serialport::{available_ports, SerialPortType};
+use toml::{value::Table, Value};
+
+use crate::settings::{DataBits, FlowControl, Parity, Settings, StopBits};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// What is saved of a session to resume it.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SessionState {
+    /// The port of the session.
+    pub port: Option<PortIdentity>,
+    /// The line parameters of the port.
+    pub line: Option<LineParameters>,
+    /// The last kernel image sent.
+    pub kernel_image: Option<String>,
+}
+
+/// A serial port, as found again in a later run.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PortIdentity {
+    /// The path of the port when it was saved.
+    pub path: String,
+    /// The identity of the USB serial controller, if the port is one.
+    pub usb: Option<UsbIdentity>,
+}
+
+/// The identity of a USB serial controller.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UsbIdentity {
+    /// The vendor ID.
+    pub vid: u16,
+    /// The product ID.
+    pub pid: u16,
+    /// The serial number, telling apart several controllers of the same
+    /// model.
+    pub serial_number: Option<String>,
+}
+
+/// The line parameters of a port.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LineParameters {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl SessionState {
+    /// Apply the saved state to the `settings`, with the port found again by
+    /// its identity.
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(port) = &self.port {
+            settings.path = Some(port.locate());
+        }
+        if let Some(line) = self.line {
+            settings.baud_rate = line.baud_rate;
+            settings.data_bits = line.data_bits;
+            settings.parity = line.parity;
+            settings.stop_bits = line.stop_bits;
+            settings.flow_control = line.flow_control;
+        }
+        if let Some(image) = &self.kernel_image {
+            settings.kernel_image = Some(image.clone());
+        }
+    }
+
+    /// The content of the resume file for this state.
+    pub fn to_toml(&self) -> String {
+        let mut root = Table::new();
+        if let Some(image) = &self.kernel_image {
+            root.insert("kernel_image".into(), Value::String(image.clone()));
+        }
+        if let Some(port) = &self.port {
+            let mut table = Table::new();
+            table.insert("path".into(), Value::String(port.path.clone()));
+            if let Some(usb) = &port.usb {
+                table.insert("vid".into(), Value::Integer(usb.vid.into()));
+                table.insert("pid".into(), Value::Integer(usb.pid.into()));
+                if let Some(serial_number) = &usb.serial_number {
+                    table.insert("serial_number".into(), Value::String(serial_number.clone()));
+                }
+            }
+            root.insert("port".into(), Value::Table(table));
+        }
+        if let Some(line) = &self.line {
+            root.insert("line".into(), Value::Table(line_table(line)));
+        }
+        format!("# Saved by bootcom for `--resume`.\n{}", Value::Table(root))
+    }
+}
+
+impl PortIdentity {
+    /// The path of the port now: the saved one if it is there, or the one of
+    /// the USB serial controller with the same identity otherwise.
+    pub fn locate(&self) -> String {
+        let ports = available_ports().unwrap_or_default();
+        let usb = match &self.usb {
+            Some(usb) if !ports.iter().any(|p| p.port_name == self.path) => usb,
+            _ => return self.path.clone(),
+        };
+        let matching: Vec<_> = ports
+            .iter()
+            .filter(|p| match &p.port_type {
+                SerialPortType::UsbPort(info) => {
+                    info.vid == usb.vid
+                        && info.pid == usb.pid
+                        && info.serial_number == usb.serial_number
+                }
+                _ => false,
+            })
+            .collect();
+        // Without a serial number, only a single controller of the model is
+        // surely the same one.
+        match matching.as_slice() {
+            [port] => {
+                debug!("{} is now {}", self.path, port.port_name);
+                port.port_name.clone()
+            }
+            _ => self.path.clone(),
+        }
+    }
+}
+
+/// The path of the default resume file, if the user state directory can be
+/// found.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+    };
+    dir.map(|dir| dir.join("bootcom").join("session.toml"))
+}
+
+/// Read and parse the resume file at `path`.
+pub fn load(path: &Path) -> Result<SessionState, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the `text` of a resume file.
+pub fn parse(text: &str) -> Result<SessionState, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let kernel_image = match root.get("kernel_image") {
+        None => None,
+        Some(Value::String(image)) => Some(image.clone()),
+        Some(_) => return Err("`kernel_image` needs to be a string".into()),
+    };
+    let port = match root.get("port") {
+        None => None,
+        Some(Value::Table(table)) => Some(port_identity(table, "port")?),
+        Some(_) => return Err("`port` needs to be a section".into()),
+    };
+    let line = match root.get("line") {
+        None => None,
+        Some(Value::Table(table)) => Some(line_parameters(table)?),
+        Some(_) => return Err("`line` needs to be a section".into()),
+    };
+    Ok(SessionState {
+        port,
+        line,
+        kernel_image,
+    })
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Save the port and the line parameters of the `settings` to their resume
+/// file, if any, along with the kernel `image` just sent, or the one saved
+/// before. The image is saved with its absolute path, the next run may start
+/// elsewhere. Failures are only logged, resuming is a convenience.
+pub(crate) fn save(settings: &Settings, image: Option<&str>) {
+    let file = match &settings.resume_file {
+        Some(file) => Path::new(file),
+        None => return,
+    };
+    let kernel_image = match image {
+        Some(image) => Some(
+            fs::canonicalize(image)
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|_| image.to_string()),
+        ),
+        None => load(file).ok().and_then(|state| state.kernel_image),
+    };
+    let state = SessionState {
+        port: settings.path.as_deref().map(identify),
+        line: Some(LineParameters {
+            baud_rate: settings.baud_rate,
+            data_bits: settings.data_bits,
+            parity: settings.parity,
+            stop_bits: settings.stop_bits,
+            flow_control: settings.flow_control,
+        }),
+        kernel_image,
+    };
+    let saved = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| fs::write(file, state.to_toml()));
+    if let Err(e) = saved {
+        info!("could not save the session to {}: {}", file.display(), e);
+    }
+}
+
+/// The identity of the port at `path`, as enumerated now.
+pub(crate) fn identify(path: &str) -> PortIdentity {
+    let usb = available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|p| p.port_name == path)
+        .and_then(|p| match p.port_type {
+            SerialPortType::UsbPort(info) => Some(UsbIdentity {
+                vid: info.vid,
+                pid: info.pid,
+                serial_number: info.serial_number,
+            }),
+            _ => None,
+        });
+    PortIdentity {
+        path: path.to_string(),
+        usb,
+    }
+}
+
+/// Parse the identity of a port from its `table`, the `section` named in the
+/// errors.
+pub(crate) fn port_identity(table: &Table, section: &str) -> Result<PortIdentity, String> {
+    let path = match table.get("path") {
+        Some(Value::String(path)) => path.clone(),
+        _ => return Err(format!("`{}.path` needs to be a string", section)),
+    };
+    let id = |key: &str| match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(id)) if (0..=0xffff).contains(id) => Ok(Some(*id as u16)),
+        Some(_) => Err(format!("`{}.{}` needs to be a USB ID", section, key)),
+    };
+    let usb = match (id("vid")?, id("pid")?) {
+        (Some(vid), Some(pid)) => Some(UsbIdentity {
+            vid,
+            pid,
+            serial_number: match table.get("serial_number") {
+                None => None,
+                Some(Value::String(serial_number)) => Some(serial_number.clone()),
+                Some(_) => return Err(format!("`{}.serial_number` needs to be a string", section)),
+            },
+        }),
+        (None, None) => None,
+        _ => return Err(format!("`{0}.vid` and `{0}.pid` go together", section)),
+    };
+    Ok(PortIdentity { path, usb })
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+fn line_table(line: &LineParameters) -> Table {
+    let mut table = Table::new();
+    table.insert("baud_rate".into(), Value::Integer(line.baud_rate.into()));
+    let data_bits = match line.data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    };
+    table.insert("data_bits".into(), Value::Integer(data_bits));
+    let parity = match line.parity {
+        Parity::None => "none",
+        Parity::Even => "even",
+        Parity::Odd => "odd",
+    };
+    table.insert("parity".into(), Value::String(parity.into()));
+    let stop_bits = match line.stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    };
+    table.insert("stop_bits".into(), Value::Integer(stop_bits));
+    let flow_control = match line.flow_control {
+        FlowControl::None => "none",
+        FlowControl::Software => "soft",
+        FlowControl::Hardware => "hard",
+    };
+    table.insert("flow_control".into(), Value::String(flow_control.into()));
+    table
+}
+
+fn line_parameters(table: &Table) -> Result<LineParameters, String> {
+    let invalid = |key: &str| format!("`line.{}` is missing or invalid", key);
+    let baud_rate = match table.get("baud_rate") {
+        Some(Value::Integer(rate)) if *rate > 0 && *rate <= u32::MAX.into() => *rate as u32,
+        _ => return Err(invalid("baud_rate")),
+    };
+    let data_bits = match table.get("data_bits").and_then(Value::as_integer) {
+        Some(5) => DataBits::Five,
+        Some(6) => DataBits::Six,
+        Some(7) => DataBits::Seven,
+        Some(8) => DataBits::Eight,
+        _ => return Err(invalid("data_bits")),
+    };
+    let parity = match table.get("parity").and_then(Value::as_str) {
+        Some("none") => Parity::None,
+        Some("even") => Parity::Even,
+        Some("odd") => Parity::Odd,
+        _ => return Err(invalid("parity")),
+    };
+    let stop_bits = match table.get("stop_bits").and_then(Value::as_integer) {
+        Some(1) => StopBits::One,
+        Some(2) => StopBits::Two,
+        _ => return Err(invalid("stop_bits")),
+    };
+    let flow_control = match table.get("flow_control").and_then(Value::as_str) {
+        Some("none") => FlowControl::None,
+        Some("soft") => FlowControl::Software,
+        Some("hard") => FlowControl::Hardware,
+        _ => return Err(invalid("flow_control")),
+    };
+    Ok(LineParameters {
+        baud_rate,
+        data_bits,
+        parity,
+        stop_bits,
+        flow_control,
+    })
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn state_round_trip() {
+    let state = SessionState {
+        port: Some(PortIdentity {
+            path: "/dev/ttyUSB0".into(),
+            usb: Some(UsbIdentity {
+                vid: 0x0403,
+                pid: 0x6001,
+                serial_number: Some("A10KZP3V".into()),
+            }),
+        }),
+        line: Some(LineParameters {
+            baud_rate: 921_600,
+            data_bits: DataBits::Eight,
+            parity: Parity::Even,
+            stop_bits: StopBits::Two,
+            flow_control: FlowControl::Hardware,
+        }),
+        kernel_image: Some("target/kernel8.img".into()),
+    };
+    assert_eq!(parse(&state.to_toml()).unwrap(), state);
+    assert_eq!(parse("").unwrap(), SessionState::default());
+    assert_eq!(
+        parse("[line]\nbaud_rate = 115200").unwrap_err(),
+        "`line.data_bits` is missing or invalid"
+    );
+    assert_eq!(
+        parse("[port]\npath = \"COM3\"\nvid = 1027").unwrap_err(),
+        "`port.vid` and `port.pid` go together"
+    );
+}
+
+#[test]
+fn state_is_saved() {
+    use crate::settings::SettingsBuilder;
+
+    let file = std::env::temp_dir().join(format!("bootcom-resume-{}.toml", std::process::id()));
+    let settings = SettingsBuilder::default()
+        .path("/dev/pts/99")
+        .baud_rate(57_600)
+        .resume_file(file.to_string_lossy())
+        .finalize();
+    save(&settings, Some("kernel8.img"));
+    save(&settings, None);
+    let state = load(&file).unwrap();
+    fs::remove_file(&file).unwrap();
+    assert_eq!(state.port.unwrap().path, "/dev/pts/99");
+    assert_eq!(state.line.unwrap().baud_rate, 57_600);
+    assert_eq!(state.kernel_image.as_deref(), Some("kernel8.img"));
+
+    let mut resumed = SettingsBuilder::default().finalize();
+    SessionState {
+        kernel_image: Some("other.img".into()),
+        ..SessionState::default()
+    }
+    .apply(&mut resumed);
+    assert_eq!(resumed.kernel_image.as_deref(), Some("other.img"));
+}
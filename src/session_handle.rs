@@ -0,0 +1,74 @@
+//! Interaction with the device from library code while the boot sessions run,
+//! e.g. host-side test code typing commands into the device shell.
+//!
+//! **Example**
+//! ```no_run
+//! use bootcom::{BootServer, DeviceManager, SettingsBuilder};
+//! use std::thread;
+//!
+//! let settings = SettingsBuilder::default()
+//!     .path("/dev/ttyUSB0")
+//!     .keyboard(false)
+//!     .finalize();
+//! let server = BootServer::new(settings);
+//! let session = server.session_handle();
+//! let mut lines = server.console_lines();
+//! let mut running = server.clone();
+//! thread::spawn(move || running.run());
+//!
+//! lines.find(|line| line.text.ends_with("# ")).unwrap();
+//! session.write(b"uname -a\r");
+//! ```
+
+use std::sync::{Arc, Mutex};
+
+use crate::utils::LineChange;
+
+/// A handle to the boot sessions of a device manager. Cloning it gives
+/// another handle to the same sessions.
+#[derive(Debug, Clone, Default)]
+pub struct SessionHandle {
+    /// The data waiting to be written to the device.
+    input: Arc<Mutex<Vec<u8>>>,
+    /// The changes of the line parameters waiting to be made.
+    line_changes: Arc<Mutex<Vec<LineChange>>>,
+}
+impl SessionHandle {
+    /// Queue `data` to be written to the device, as if it was typed in
+    /// terminal mode: it goes through the codecs and is paced like pasted
+    /// text. It is written as soon as a session is in terminal mode, and the
+    /// device does not ask for a pause.
+    pub fn write(&self, data: &[u8]) {
+        self.input.lock().unwrap().extend_from_slice(data);
+    }
+
+    /// Take the data waiting to be written to the device.
+    pub(crate) fn take_input(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.input.lock().unwrap())
+    }
+
+    /// Queue a `change` of the line parameters, made as soon as a session is
+    /// in terminal mode.
+    pub(crate) fn change_line(&self, change: LineChange) {
+        self.line_changes.lock().unwrap().push(change);
+    }
+
+    /// Take the changes of the line parameters waiting to be made.
+    pub(crate) fn take_line_changes(&self) -> Vec<LineChange> {
+        std::mem::take(&mut *self.line_changes.lock().unwrap())
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn input_is_queued() {
+    let session = SessionHandle::default();
+    let other = session.clone();
+    session.write(b"ls");
+    other.write(b" -l\r");
+    assert_eq!(session.take_input(), b"ls -l\r");
+    assert!(other.take_input().is_empty());
+}
@@ -3,8 +3,23 @@
 //! Use the [builder](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html)
 //! pattern to set the configurable values.
 
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use crate::boards::Board;
+use crate::clock::{Clock, ClockHandle};
+use crate::codec::CodecFactory;
+use crate::debug_bundle::LogCapture;
+use crate::messages::Catalog;
+use crate::pcap::PcapCapture;
+use crate::profiles::PortRule;
+use crate::progress::{ObserverHandle, ProgressObserver, ProgressTheme};
+use crate::severity::SeverityRule;
+
 pub use serialport::{DataBits, FlowControl, Parity, StopBits};
 
+/// The default maximum size of the reads in terminal mode.
+const DEFAULT_MAX_READ_SIZE: usize = 64 * 1024;
+
 // =============================================================================
 // Public Interface
 // =============================================================================
@@ -13,9 +28,14 @@ pub use serialport::{DataBits, FlowControl, Parity, StopBits};
 /// a [builder](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html)
 /// for the settings.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[allow(clippy::manual_non_exhaustive)]
 pub struct Settings {
     /// The port name, usually the device path.
     pub path: Option<String>,
+    /// The rule matching the port by its USB serial controller, looked for
+    /// again whenever the port is waited for, the device path changing across
+    /// replugs. The `path` is then the one last found.
+    pub port_match: Option<PortRule>,
     /// The baud rate in symbols-per-second.
     pub baud_rate: u32,
     /// Number of bits used to represent a character sent on the line.
@@ -33,12 +53,834 @@ pub struct Settings {
     /// current working directory for selection by the user.
     pub kernel_image: Option<String>,
 
+    /// The name of the connection profile applied to the settings (see
+    /// [`profiles`](crate::profiles)), if any.
+    pub profile: Option<String>,
+
+    /// What to do when the terminal suddenly receives mostly garbage, which
+    /// is usually the symptom of a baud rate mismatch after the board switched
+    /// its UART configuration.
+    pub baud_rescan: BaudRescan,
+
+    /// The trigger patterns recognized in the data received from the device,
+    /// each mapped to the transfer protocol to use for pushing the kernel
+    /// image. Defaults to three consecutive `0x03` bytes for the `raspbootin`
+    /// style protocol.
+    pub triggers: Vec<Trigger>,
+
+    /// Directory in which the booted kernel can open, read and write files
+    /// using the host services. When not set, host services are disabled.
+    pub host_dir: Option<String>,
+
+    /// Directory in which the memory dumps streamed by the device are saved.
+    /// When not set, the dump command of the device is not recognized.
+    pub dump_dir: Option<String>,
+
+    /// Whether the host time is sent to the device when the port is opened,
+    /// and whenever the device asks for it, for the boards without a
+    /// real-time clock. Off by default.
+    pub time_sync: bool,
+
+    /// Path to a script of console input lines to be sent to the device after
+    /// connection. See the `script` module for the directives it can contain.
+    pub send_script: Option<String>,
+
+    /// How fast pasted text and script lines are written to the device. No
+    /// pacing by default.
+    pub paste_pacing: PastePacing,
+
+    /// Whether the device is asked to persist the kernel image to its storage
+    /// after the transfer, before booting it. Only supported by the chunked
+    /// protocol. Off by default.
+    pub persist: bool,
+
+    /// How many times the operations repeated until they succeed (waiting for
+    /// a port, selecting a port or an image, sending the image) are attempted
+    /// before `bootcom` gives up.
+    pub retry: RetryPolicy,
+
+    /// The console output expected from the device after a kernel push, in
+    /// order, to verify that it boots. Nothing is checked by default.
+    pub expectations: Vec<Expectation>,
+
+    /// The rules extracting the binary blobs the device dumps, framed between
+    /// markers, on its console (e.g. crash data) and saving them to files.
+    /// None by default.
+    pub captures: Vec<CaptureRule>,
+
+    /// The rules classifying the console lines by severity, for the summary
+    /// shown at the end of each session. The built-in ones of
+    /// [`severity::default_rules`](crate::severity::default_rules) when empty,
+    /// by default.
+    pub severities: Vec<SeverityRule>,
+
+    /// The external capture tools run in sync with the boot phases. None by
+    /// default.
+    pub instruments: Vec<Instrument>,
+
+    /// Path to a file in which the console session is recorded, in the
+    /// asciicast v2 format used by `asciinema`. Not recorded when not set.
+    pub record: Option<String>,
+
+    /// The file the console session is appended to, to keep the boot logs.
+    /// Not logged when not set.
+    pub log_file: Option<SessionLog>,
+
+    /// Whether the kernel image is watched in terminal mode, and sent again
+    /// once rebuilt. Off by default.
+    pub watch: bool,
+
+    /// The command resetting the board before the watched kernel image is
+    /// sent again, run with the shell of the platform. When not set, the image
+    /// is sent right away, to a bootloader still waiting for it.
+    pub reset_command: Option<String>,
+
+    /// Path to a file in which a report of the run (settings, transfers,
+    /// timings, errors, console excerpt and files written) is written on exit,
+    /// in HTML when its name ends with `.html`, in Markdown otherwise. Not
+    /// written when not set.
+    pub report: Option<String>,
+
+    /// Path to a file in which a debug bundle (log, end of the console output,
+    /// state transitions, settings and environment) is saved when `bootcom`
+    /// exits with an error or panics, see
+    /// [`debug_bundle`](crate::debug_bundle). Not saved when not set.
+    pub debug_bundle: Option<String>,
+
+    /// The messages of the log, captured for the debug bundle. Left out of the
+    /// bundle when not set.
+    pub debug_log: Option<LogCapture>,
+
+    /// Periodic reporting of the health status, for unattended sessions.
+    /// Disabled by default.
+    pub health: HealthReporting,
+
+    /// Whether `bootcom` runs as a systemd service: the service manager is
+    /// notified of its readiness, state and liveness (`sd_notify`, with the
+    /// watchdog), and the console is served on the sockets passed by socket
+    /// activation. Off by default.
+    pub systemd: bool,
+
+    /// The tokens clients of the console served on the activated sockets
+    /// authenticate with, each granting its permissions. When there are none,
+    /// anyone who can connect has all the permissions. The tokens are only
+    /// accepted on the Unix sockets, as nothing is encrypted over TCP.
+    pub access: Vec<AccessRule>,
+
+    /// Whether the console served on the activated TCP sockets is advertised
+    /// on the LAN with multicast DNS (`_bootcom._tcp`), along with the port
+    /// and line parameters of the board. Off by default.
+    pub advertise: bool,
+
+    /// Whether Bluetooth virtual serial ports are offered in the interactive
+    /// port selection. Off by default, as they clutter the list and may hang
+    /// when opened.
+    pub bluetooth_ports: bool,
+
+    /// Whether the keyboard of the terminal is read (in raw mode) for the
+    /// shortcuts and the interactive prompts. On by default; a program running
+    /// several device managers in the same process turns it off on all but
+    /// one of them, the prompts then take their default answer.
+    pub keyboard: bool,
+
+    /// How long to wait after a port appears before opening it, letting
+    /// programs probing new serial devices, like ModemManager, finish with it.
+    /// No wait by default.
+    pub settle_delay: Duration,
+
+    /// How long to wait for the device to come back after the connection was
+    /// lost in terminal mode, as it does when a board reset drops DTR or makes
+    /// the USB serial controller re-enumerate. The same device is then reopened
+    /// silently and the session goes on. Disabled (zero) by default.
+    pub reset_grace: Duration,
+
+    /// How long the device must stay quiet after the last byte of the kernel
+    /// image before the transfer is reported as finished. What the bootloader
+    /// prints in the meantime (e.g. its own CRC or status) is shown first
+    /// instead of being interleaved with the transfer summary. Disabled (zero)
+    /// by default.
+    pub flush_window: Duration,
+
+    /// The maximum number of bytes read from the serial port at once in
+    /// terminal mode. High baud rates need larger reads to keep up with bursts
+    /// of output. 64 KiB by default.
+    pub max_read_size: usize,
+
+    /// Whether the state of the modem lines (CTS, DSR, CD, RI, RTS and DTR) is
+    /// shown in terminal mode whenever it changes. Off by default.
+    pub modem_lines: bool,
+
+    /// The workarounds for the boot ROM of the board, applied in order when
+    /// the port is opened and to the console output. None by default.
+    pub quirks: Vec<Quirk>,
+
+    /// The boot mode strapping pins driven through the GPIO lines of USB
+    /// adapters before the port is opened, for the board on the port. None by
+    /// default.
+    pub straps: Vec<Strap>,
+
+    /// The external tools flashing the boards found in a ROM loader which does
+    /// not speak the protocols of `bootcom`, guidance being shown for the
+    /// others. None by default.
+    pub flashers: Vec<Flasher>,
+
+    /// The commands typed at the prompt of a stock U-Boot to load the kernel
+    /// image and boot it, instead of waiting for a trigger. None by default.
+    pub uboot: Option<UbootScript>,
+
+    /// The codecs transforming the console streams, applied in order to the
+    /// data received from the device. None by default.
+    pub codecs: Vec<CodecFactory>,
+
+    /// The logical streams the device tags on the console, shown apart or
+    /// written to files (see [`Stream`]). None by default.
+    pub streams: Vec<Stream>,
+
+    /// The faults injected on purpose during the transfers of the kernel
+    /// image. None by default.
+    pub chaos: Option<Chaos>,
+
+    /// The clock the waits and the watchdogs go by, the one of the system when
+    /// not set. None by default.
+    pub clock: Option<ClockHandle>,
+
+    /// The capture of the traffic on the port in the pcapng format, for
+    /// Wireshark. None by default.
+    pub pcap: Option<PcapCapture>,
+
+    /// The metadata appended to the kernel image when it is sent, for the
+    /// kernel to read at runtime. None by default.
+    pub trailer: Option<Trailer>,
+
+    /// The key signing the kernel images, the signature being sent in a
+    /// header before the image (see [`signing`](crate::signing)). Not signed
+    /// by default.
+    pub signing_key: Option<SigningKey>,
+
+    /// The pre-shared keys encrypting the transfers of the kernel image, for
+    /// the boards of a profile or all of them. Not encrypted when none applies.
+    pub transfer_keys: Vec<TransferKey>,
+
+    /// Receives the progress of the kernel image transfers instead of the
+    /// progress bar, when set.
+    pub progress_observer: Option<ObserverHandle>,
+
+    /// The look of the progress bars and spinners.
+    pub progress_theme: ProgressTheme,
+
+    /// The catalog translating the messages (see
+    /// [`messages`](crate::messages)). All in English by default.
+    pub messages: Catalog,
+
+    /// The configuration file some of the settings were read from, shown in
+    /// the summary of the effective settings. None by default.
+    pub config_file: Option<String>,
+
+    /// Path to the file in which the port, the line parameters and the kernel
+    /// image of the session are saved whenever they change, to resume from
+    /// them in a later run (see [`resume`](crate::resume)). Not saved when not
+    /// set.
+    pub resume_file: Option<String>,
+
+    /// The boards of the inventory (see [`boards`](crate::boards)). The result
+    /// of the boots on the port of one of them is recorded.
+    pub boards: Vec<Board>,
+
+    /// Path to the file in which the result of the last boot of each board of
+    /// the inventory is recorded. Not recorded when not set.
+    pub boards_file: Option<String>,
+
+    /// Path to the file in which the local usage statistics are kept (see
+    /// [`usage`](crate::usage)). Not kept when not set.
+    pub usage_file: Option<String>,
+
+    /// Directory in which each kernel image pushed is archived, with the
+    /// report of the transfer and the console output of the boot (see
+    /// [`archive`](crate::archive)). Not archived when not set.
+    pub archive: Option<String>,
+
     /// Restrict creation of `Settings` instances unless through the
     /// `SettingsBuilder`.
     #[doc(hidden)]
     private_use_builder__: (),
 }
 
+/// Policy for rescanning the baud rate when the terminal detects a storm of
+/// invalid bytes (parity/framing errors, invalid UTF-8, `0xFF` runs...).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BaudRescan {
+    /// Never rescan, only warn once about the suspicious data.
+    Off,
+    /// Ask the user for confirmation before rescanning.
+    Prompt,
+    /// Rescan automatically without asking.
+    Auto,
+}
+
+/// Protocols that can be used to transfer the kernel image to the device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TransferProtocol {
+    /// The `raspbootin` style protocol: the size of the image as 4 bytes
+    /// (little endian), followed by an `OK` from the device and then the
+    /// content of the image.
+    Raspbootin,
+    /// Like `raspbootin`, but the device follows the `OK` with the size of its
+    /// receive buffer (2 bytes, little endian). The image is then sent in
+    /// chunks no larger than that, each preceded by `STX` (`0x02`) and
+    /// acknowledged by the device with an `ACK` (`0x06`), or requested again
+    /// with a `NAK` (`0x15`), for devices with tiny FIFOs and no flow control.
+    /// The transfer ends with `EOT` (`0x04`) to boot the image, or `P` to have
+    /// the device persist it first (see [`Settings::persist`]); a transfer
+    /// given up by `bootcom` ends with `CAN` (`0x18`) instead.
+    Chunked,
+    /// XMODEM with 128 byte blocks and CRC-16 checksums, as expected by
+    /// receivers announcing themselves by sending `C`.
+    XmodemCrc,
+    /// YMODEM with 1024 byte blocks, as expected by the `loady` command of
+    /// U-Boot, which also announces itself by sending `C`.
+    Ymodem,
+}
+
+impl fmt::Display for TransferProtocol {
+    /// The name of the protocol, as given on the command line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransferProtocol::Raspbootin => "raspbootin",
+            TransferProtocol::Chunked => "chunked",
+            TransferProtocol::XmodemCrc => "xmodem-crc",
+            TransferProtocol::Ymodem => "ymodem",
+        })
+    }
+}
+
+/// A sequence of bytes which, when received from the device, requests the
+/// kernel image to be pushed using the associated protocol.
+///
+/// Each trigger can be bound to its own kernel image, letting the bootloader
+/// choose between several of them (e.g. a debug and a release kernel) by
+/// sending a different pattern, or a selector byte after a common prefix.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trigger {
+    /// The bytes to look for at the end of the received data.
+    pub pattern: Vec<u8>,
+    /// The protocol to use when this trigger is received.
+    pub protocol: TransferProtocol,
+    /// The kernel image sent when this trigger is received, instead of the
+    /// one from the settings.
+    pub image: Option<String>,
+}
+impl Trigger {
+    /// The default trigger: three consecutive `0x03` bytes requesting a
+    /// transfer with the `raspbootin` style protocol.
+    pub fn raspbootin() -> Self {
+        Trigger {
+            pattern: vec![3, 3, 3],
+            protocol: TransferProtocol::Raspbootin,
+            image: None,
+        }
+    }
+}
+
+/// A workaround for the boot ROM of a board, so that board specific behaviors
+/// live in the configuration rather than in forks of `bootcom`. Quirks compose:
+/// they are applied in the order they are listed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Quirk {
+    /// Deassert DTR for a moment after opening the port, for boards resetting
+    /// into their boot ROM on a DTR pulse.
+    DtrToggle,
+    /// Send a byte after opening the port, for boot ROMs waiting for some
+    /// input before they start talking.
+    WakeByte(u8),
+    /// Show the carriage returns received from the device as a new line, for
+    /// devices ending their lines with a bare `CR`.
+    CrToCrLf,
+}
+
+/// A boot mode strapping pin (e.g. held low to boot from the UART), driven
+/// through a GPIO line of a USB adapter, such as the CBUS pins of an FTDI
+/// chip, before the port is opened.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Strap {
+    /// The profile of the boards the pin is driven for, all of them when not
+    /// set.
+    pub profile: Option<String>,
+    /// The label of the GPIO chip of the adapter, e.g. `ftdi-cbus`.
+    pub chip: String,
+    /// The line of the GPIO chip, e.g. `0` for CBUS0.
+    pub line: u32,
+    /// Whether the pin is driven high rather than low.
+    pub high: bool,
+    /// How long the pin is driven before it is released, left driven until
+    /// the session ends when not set.
+    pub hold: Option<Duration>,
+}
+impl fmt::Display for Strap {
+    /// The pin and its level, e.g. `ftdi-cbus:0 low for 500ms`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} {}",
+            self.chip,
+            self.line,
+            if self.high { "high" } else { "low" }
+        )?;
+        if let Some(hold) = self.hold {
+            write!(f, " for {}ms", hold.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// A ROM loader of the microcontrollers found in mixed labs, which does not
+/// speak the protocols of `bootcom`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RomLoader {
+    /// The ROM loader of the Espressif chips (ESP8266, ESP32 and its
+    /// variants), spoken by `esptool.py`.
+    Esp,
+    /// The system bootloader of the STM32 chips (AN3155), spoken by
+    /// `stm32flash`.
+    Stm32,
+}
+impl fmt::Display for RomLoader {
+    /// The name of the ROM loader, as given in the configuration file.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RomLoader::Esp => "esp",
+            RomLoader::Stm32 => "stm32",
+        })
+    }
+}
+
+/// An external tool flashing the boards found in a ROM loader, with the port
+/// closed while it runs.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Flasher {
+    pub loader: RomLoader,
+    /// The command run with the shell of the platform, `{port}` and `{image}`
+    /// standing for the path of the port and the kernel image.
+    pub command: String,
+}
+
+/// The commands typed at the prompt of U-Boot to load the kernel image over
+/// the serial port and boot it, e.g. `setenv bootargs ...`, `loady
+/// ${loadaddr}` and `bootm ${loadaddr}`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UbootScript {
+    /// The prompt of U-Boot, `=> ` by default.
+    pub prompt: String,
+    /// The commands, each typed at the next prompt. The transfer of the
+    /// kernel image follows the load command (`loadx` or `loady`).
+    pub commands: Vec<String>,
+}
+impl UbootScript {
+    /// The index of the load command of the script and the protocol of its
+    /// transfer, if it has one.
+    pub fn transfer(&self) -> Option<(usize, TransferProtocol)> {
+        self.commands
+            .iter()
+            .enumerate()
+            .find_map(|(index, command)| match command.split_whitespace().next() {
+                Some("loadx") => Some((index, TransferProtocol::XmodemCrc)),
+                Some("loady") => Some((index, TransferProtocol::Ymodem)),
+                _ => None,
+            })
+    }
+}
+
+/// The faults injected on purpose during the transfers of the kernel image, to
+/// validate the robustness of a bootloader and the retries of `bootcom`. The
+/// rates are in parts per million.
+///
+/// It is given as `corrupt=0.01%,drop=1%,delay=5%`, with optionally the time
+/// the reads are delayed by (`delay-ms=1000` by default) and the seed of the
+/// faults (`seed=42`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Chaos {
+    /// The rate of the bytes sent with some of their bits flipped.
+    pub corrupt: u32,
+    /// The rate of the writes (the chunks, blocks...) dropped instead of
+    /// sent.
+    pub drop: u32,
+    /// The rate of the reads (the acknowledgements...) handed over late.
+    pub delay: u32,
+    /// How late the delayed reads are handed over.
+    pub delay_time: Duration,
+    /// The seed of the faults, for a transfer to be replayed with the same
+    /// ones. A new one is picked for each transfer when not set.
+    pub seed: Option<u64>,
+}
+impl Default for Chaos {
+    fn default() -> Self {
+        Chaos {
+            corrupt: 0,
+            drop: 0,
+            delay: 0,
+            delay_time: Duration::from_millis(1000),
+            seed: None,
+        }
+    }
+}
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = |key: &str, value: &str| -> Result<u32, String> {
+            let invalid = || format!("`{}` needs to be a percentage, e.g. `0.5%`", key);
+            let percent: f64 = value
+                .strip_suffix('%')
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(invalid());
+            }
+            Ok((percent * 10_000.0).round() as u32)
+        };
+        let mut chaos = Chaos::default();
+        for option in s.split(',') {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("`{}` is not a `key=value` option", option))?;
+            match key.trim() {
+                "corrupt" => chaos.corrupt = rate(key, value.trim())?,
+                "drop" => chaos.drop = rate(key, value.trim())?,
+                "delay" => chaos.delay = rate(key, value.trim())?,
+                "delay-ms" => {
+                    let millis = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| "`delay-ms` needs to be a number of milliseconds")?;
+                    chaos.delay_time = Duration::from_millis(millis);
+                }
+                "seed" => {
+                    let seed = value.trim().parse().map_err(|_| "`seed` needs to be a number")?;
+                    chaos.seed = Some(seed);
+                }
+                _ => {
+                    return Err(format!(
+                        "unknown option `{}`, expected `corrupt`, `drop`, `delay`, `delay-ms` or `seed`",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(chaos)
+    }
+}
+impl fmt::Display for Chaos {
+    /// The rates of the faults, e.g. `corrupt 0.01%, drop 1%, delay 5% by
+    /// 1000ms`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |rate: u32| format!("{}%", rate as f64 / 10_000.0);
+        write!(
+            f,
+            "corrupt {}, drop {}, delay {} by {}ms",
+            percent(self.corrupt),
+            percent(self.drop),
+            percent(self.delay),
+            self.delay_time.as_millis()
+        )?;
+        if let Some(seed) = self.seed {
+            write!(f, ", seed {}", seed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The metadata appended to the kernel image when it is sent, along with its
+/// build time and CRC-32.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Trailer {
+    /// The version of the kernel.
+    pub version: String,
+    /// The git hash the kernel was built from, when known.
+    pub git_hash: Option<String>,
+}
+impl fmt::Display for Trailer {
+    /// The version and git hash, e.g. `v1.2 (abc123)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.version)?;
+        if let Some(git_hash) = &self.git_hash {
+            write!(f, " ({})", git_hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the key signing the kernel images comes from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SigningKey {
+    /// A key file made by `bootcom keygen`.
+    File(String),
+    /// The first Ed25519 key of the SSH agent.
+    Agent,
+}
+impl FromStr for SigningKey {
+    type Err = String;
+
+    /// `agent` for the SSH agent, or the path of a key file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("the path of a key file or `agent` is expected".into()),
+            "agent" => Ok(SigningKey::Agent),
+            path => Ok(SigningKey::File(path.into())),
+        }
+    }
+}
+impl fmt::Display for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningKey::File(path) => write!(f, "key `{}`", path),
+            SigningKey::Agent => f.write_str("SSH agent"),
+        }
+    }
+}
+
+/// A pre-shared AES-256-GCM key encrypting the transfers of the kernel image.
+#[derive(Clone, Eq, PartialEq)]
+pub struct TransferKey {
+    /// The profile of the boards the key is used for, all of them when not
+    /// set.
+    pub profile: Option<String>,
+    pub key: [u8; 32],
+}
+impl fmt::Debug for TransferKey {
+    /// Never shows the key.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransferKey")
+            .field("profile", &self.profile)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A logical stream multiplexed by the device on the console: what follows the
+/// `SOH` byte and its `channel`, up to the next tag, is shown in the `color`
+/// of the stream with its `name`, or appended to its `file`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Stream {
+    /// The byte after `SOH` switching to the stream, never `0`, the console.
+    pub channel: u8,
+    /// The name prefixed to the lines of the stream on the terminal.
+    pub name: String,
+    /// The color of the stream on the terminal, e.g. `green`.
+    pub color: Option<String>,
+    /// The file the stream is appended to instead of being shown.
+    pub file: Option<String>,
+}
+
+/// A stage of the boot of the kernel, told by a pattern the device prints on
+/// the console (e.g. `Booting`, `initrd loaded` or a login prompt).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Expectation {
+    /// The text expected on the console.
+    pub pattern: String,
+    /// How long the stage may take, from the end of the previous one (or of
+    /// the kernel push for the first stage).
+    pub timeout: Duration,
+}
+
+/// A rule extracting a binary blob the device dumps on its console, encoded as
+/// text between a start and an end marker, and saving it to a file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CaptureRule {
+    /// The text starting the blob.
+    pub start: String,
+    /// The text ending the blob.
+    pub end: String,
+    /// How the blob is encoded between the markers.
+    pub encoding: BlobEncoding,
+    /// The directory in which the blobs are saved.
+    pub directory: String,
+}
+
+/// An external capture tool (logic analyzer, power meter...) run in sync with
+/// the phases of the boot, so that its traces line up with the console.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Instrument {
+    /// The name of the instrument, naming its capture files.
+    pub name: String,
+    /// The shell command starting the capture, with `{file}` replaced by the
+    /// path of the capture file, also in the `BOOTCOM_CAPTURE_FILE`
+    /// environment variable.
+    pub command: String,
+    /// The shell command stopping the capture, with the process id of the
+    /// capture in the `BOOTCOM_CAPTURE_PID` environment variable. The capture
+    /// is terminated when there is none.
+    pub stop: Option<String>,
+    /// The phase at the start of which the capture starts.
+    pub from: Phase,
+    /// The phase at the end of which the capture stops, `from` or a later one.
+    pub until: Phase,
+    /// The directory in which the capture files are saved.
+    pub directory: String,
+    /// The extension of the capture files.
+    pub extension: String,
+}
+
+/// A phase of the boot of a kernel image, in order.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Phase {
+    /// From the kernel image request of the bootloader until the transfer.
+    Trigger,
+    /// The transfer of the kernel image.
+    Transfer,
+    /// From the end of the transfer until the boot stages are all reached or
+    /// one of them failed, or without boot stages, until the next kernel
+    /// image request.
+    Boot,
+}
+impl fmt::Display for Phase {
+    /// The name of the phase, as given in the configuration file.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Trigger => "trigger",
+            Phase::Transfer => "transfer",
+            Phase::Boot => "boot",
+        })
+    }
+}
+
+/// A token granting permissions to the clients of the console served on the
+/// activated sockets, shared by the members of a team.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccessRule {
+    /// The secret sent by the clients to authenticate.
+    pub token: String,
+    /// What the clients with the token may do.
+    pub permissions: Vec<Permission>,
+}
+
+/// What a client of the console served on the activated sockets may do.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Permission {
+    /// Receive the console output of the device.
+    View,
+    /// Type on the device console.
+    Type,
+    /// Choose the kernel image pushed next.
+    Push,
+    /// Change the line parameters of the port.
+    Settings,
+}
+impl fmt::Display for Permission {
+    /// The name of the permission, as given in the configuration file.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::View => "view",
+            Permission::Type => "type",
+            Permission::Push => "push",
+            Permission::Settings => "settings",
+        })
+    }
+}
+
+/// The text encoding of a captured blob. Whitespace (including new lines) is
+/// ignored in both.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BlobEncoding {
+    Base64,
+    Hex,
+}
+
+/// Logging of the console session to a file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SessionLog {
+    /// The file the session is appended to.
+    pub path: String,
+    /// How the session is written to the file.
+    pub format: LogFormat,
+    /// Whether the data sent to the device is logged too.
+    pub sent: bool,
+}
+
+/// How the console session is written to its log file.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LogFormat {
+    /// The bytes as shown on the terminal.
+    Raw,
+    /// The console lines without their escape sequences, each prefixed with
+    /// the time it started, to be compared between builds.
+    Lines,
+}
+
+/// Pacing of the text pasted to the device, for boards whose UART drops
+/// characters when they arrive too fast. The text is written in small chunks,
+/// with pauses proportional to the size of each chunk.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct PastePacing {
+    /// The pause for each byte written.
+    pub byte_delay: Duration,
+    /// An additional pause after each end of line, giving the device time to
+    /// process the line.
+    pub line_delay: Duration,
+}
+impl PastePacing {
+    /// Returns `true` if the text should be written at full speed.
+    pub fn is_off(&self) -> bool {
+        self.byte_delay == Duration::default() && self.line_delay == Duration::default()
+    }
+}
+
+/// Health reporting for `bootcom` instances supervising long running tests.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HealthReporting {
+    /// File in which the status (state, time of the last output, number of
+    /// boots and errors) is written, in the Prometheus text format.
+    pub status_file: Option<String>,
+    /// How often the status file is written and the console silence checked.
+    pub interval: Duration,
+    /// Console silence duration after which an alert is raised.
+    pub silence_threshold: Option<Duration>,
+    /// Shell command run when the alert is raised, with the silence duration
+    /// in seconds in the `BOOTCOM_SILENT_SECS` environment variable.
+    pub silence_hook: Option<String>,
+}
+impl HealthReporting {
+    /// Returns `true` if there is anything to report.
+    pub fn is_enabled(&self) -> bool {
+        self.status_file.is_some() || self.silence_threshold.is_some()
+    }
+}
+impl Default for HealthReporting {
+    fn default() -> Self {
+        HealthReporting {
+            status_file: None,
+            interval: Duration::from_secs(10),
+            silence_threshold: None,
+            silence_hook: None,
+        }
+    }
+}
+
+/// How many times the operations repeated until they succeed are attempted
+/// before `bootcom` gives up, so that an unattended session does not spin
+/// forever. A limit of zero means no limit.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// How many consecutive kernel transfers may fail, each time the device
+    /// requests the image again, before the session is declared failed. 5 by
+    /// default.
+    pub send_attempts: usize,
+    /// How many times in a row the interactive selection of a port or of a
+    /// kernel image may be canceled, or fail, before `bootcom` gives up. No
+    /// limit by default.
+    pub selection_attempts: usize,
+    /// How many times the serial ports are looked for, every one or two
+    /// seconds, while waiting for a port to appear. No limit by default.
+    pub port_wait_attempts: usize,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            send_attempts: 5,
+            selection_attempts: 0,
+            port_wait_attempts: 0,
+        }
+    }
+}
+
 /// The builder for the `Settings` values.
 ///
 /// All values are optional and have default values that will be used if not
@@ -52,30 +894,91 @@ pub struct Settings {
 pub struct SettingsBuilder {
     settings: Settings,
 }
-impl SettingsBuilder {
+impl Default for SettingsBuilder {
     /// Start building the settings using default values and no path for the
     /// port.
-    pub fn default() -> Self {
+    fn default() -> Self {
         SettingsBuilder {
             settings: Settings {
                 path: None,
+                port_match: None,
                 baud_rate: 230_400,
                 data_bits: DataBits::Eight,
                 flow_control: FlowControl::None,
                 parity: Parity::None,
                 stop_bits: StopBits::One,
                 kernel_image: None,
+                profile: None,
+                baud_rescan: BaudRescan::Prompt,
+                triggers: vec![Trigger::raspbootin()],
+                host_dir: None,
+                dump_dir: None,
+                time_sync: false,
+                send_script: None,
+                paste_pacing: PastePacing::default(),
+                persist: false,
+                retry: RetryPolicy::default(),
+                expectations: vec![],
+                captures: vec![],
+                severities: vec![],
+                instruments: vec![],
+                record: None,
+                log_file: None,
+                watch: false,
+                reset_command: None,
+                report: None,
+                debug_bundle: None,
+                debug_log: None,
+                health: HealthReporting::default(),
+                systemd: false,
+                access: vec![],
+                advertise: false,
+                bluetooth_ports: false,
+                keyboard: true,
+                settle_delay: Duration::from_millis(0),
+                reset_grace: Duration::from_millis(0),
+                flush_window: Duration::from_millis(0),
+                max_read_size: DEFAULT_MAX_READ_SIZE,
+                modem_lines: false,
+                quirks: vec![],
+                straps: vec![],
+                flashers: vec![],
+                uboot: None,
+                codecs: vec![],
+                streams: vec![],
+                chaos: None,
+                clock: None,
+                pcap: None,
+                trailer: None,
+                signing_key: None,
+                transfer_keys: vec![],
+                progress_observer: None,
+                progress_theme: ProgressTheme::default(),
+                messages: Catalog::default(),
+                config_file: None,
+                resume_file: None,
+                boards: vec![],
+                boards_file: None,
+                usage_file: None,
+                archive: None,
                 private_use_builder__: (),
             },
         }
     }
-
+}
+impl SettingsBuilder {
     /// Set the path to the serial port
     pub fn path<'a>(mut self, path: impl Into<std::borrow::Cow<'a, str>>) -> Self {
         self.settings.path = Some(path.into().as_ref().to_owned());
         self
     }
 
+    /// Set the rule matching the port by its USB serial controller
+    pub fn port_match(mut self, port_match: PortRule) -> Self {
+        self.settings.port_match = Some(port_match);
+        self
+    }
+
     /// Set the baud rate in symbols-per-second
     pub fn baud_rate(mut self, baud_rate: u32) -> Self {
         self.settings.baud_rate = baud_rate;
@@ -112,80 +1015,988 @@ impl SettingsBuilder {
         self
     }
 
-    pub fn finalize(self) -> Settings {
-        self.settings
+    /// Set the name of the connection profile applied to the settings
+    pub fn profile<'a>(mut self, profile: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.profile = Some(profile.into().as_ref().to_owned());
+        self
     }
-}
 
-// =============================================================================
-// Unit Tests
-// =============================================================================
+    /// Set the policy for rescanning the baud rate on garbage input
+    pub fn baud_rescan(mut self, baud_rescan: BaudRescan) -> Self {
+        self.settings.baud_rescan = baud_rescan;
+        self
+    }
 
-#[test]
-fn all_default() {
-    let settings = SettingsBuilder::default().finalize();
-    assert_eq!(
-        settings,
-        Settings {
-            path: None,
-            baud_rate: 230_400,
-            data_bits: DataBits::Eight,
-            flow_control: FlowControl::None,
-            parity: Parity::None,
-            stop_bits: StopBits::One,
-            kernel_image: None,
-            private_use_builder__: (),
-        }
-    )
-}
+    /// Set the trigger patterns and their associated transfer protocols,
+    /// replacing the default one
+    pub fn triggers(mut self, triggers: Vec<Trigger>) -> Self {
+        self.settings.triggers = triggers;
+        self
+    }
 
-#[test]
-fn path() {
-    let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
-    assert_eq!(settings.path.unwrap(), "/dev/ttyUSB0");
-}
+    /// Enable the host services, sandboxed in the given directory
+    pub fn host_dir<'a>(mut self, host_dir: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.host_dir = Some(host_dir.into().as_ref().to_owned());
+        self
+    }
 
-#[test]
-fn baud_rate() {
-    let baud_rate = 96_000;
-    let settings = SettingsBuilder::default().baud_rate(baud_rate).finalize();
-    assert_eq!(settings.baud_rate, baud_rate);
-}
+    /// Enable the reception of memory dumps, saved in the given directory
+    pub fn dump_dir<'a>(mut self, dump_dir: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.dump_dir = Some(dump_dir.into().as_ref().to_owned());
+        self
+    }
 
-#[test]
-fn data_bits() {
-    let data_bits = DataBits::Seven;
-    let settings = SettingsBuilder::default().data_bits(data_bits).finalize();
-    assert_eq!(settings.data_bits, data_bits);
-}
+    /// Set whether the host time is sent to the device
+    pub fn time_sync(mut self, time_sync: bool) -> Self {
+        self.settings.time_sync = time_sync;
+        self
+    }
 
-#[test]
-fn flow_control() {
-    let flow_control = FlowControl::Hardware;
-    let settings = SettingsBuilder::default()
-        .flow_control(flow_control)
-        .finalize();
-    assert_eq!(settings.flow_control, flow_control);
-}
+    /// Set the path to the console input script to play after connection
+    pub fn send_script<'a>(mut self, send_script: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.send_script = Some(send_script.into().as_ref().to_owned());
+        self
+    }
 
-#[test]
-fn stop_bits() {
-    let stop_bits = StopBits::Two;
-    let settings = SettingsBuilder::default().stop_bits(stop_bits).finalize();
-    assert_eq!(settings.stop_bits, stop_bits);
-}
+    /// Set the pacing of the text pasted to the device
+    pub fn paste_pacing(mut self, paste_pacing: PastePacing) -> Self {
+        self.settings.paste_pacing = paste_pacing;
+        self
+    }
 
-#[test]
-fn parity() {
-    let parity = Parity::Even;
-    let settings = SettingsBuilder::default().parity(parity).finalize();
-    assert_eq!(settings.parity, parity);
-}
+    /// Set whether the device is asked to persist the kernel image
+    pub fn persist(mut self, persist: bool) -> Self {
+        self.settings.persist = persist;
+        self
+    }
 
-#[test]
-fn kernel_image() {
-    let settings = SettingsBuilder::default()
+    /// Set the limits of the operations repeated until they succeed
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.settings.retry = retry;
+        self
+    }
+
+    /// Set the console output expected after a kernel push
+    pub fn expectations(mut self, expectations: Vec<Expectation>) -> Self {
+        self.settings.expectations = expectations;
+        self
+    }
+
+    /// Set the rules extracting the binary blobs dumped on the console
+    pub fn captures(mut self, captures: Vec<CaptureRule>) -> Self {
+        self.settings.captures = captures;
+        self
+    }
+
+    /// Set the rules classifying the console lines by severity
+    pub fn severities(mut self, severities: Vec<SeverityRule>) -> Self {
+        self.settings.severities = severities;
+        self
+    }
+
+    /// Set the external capture tools run in sync with the boot phases
+    pub fn instruments(mut self, instruments: Vec<Instrument>) -> Self {
+        self.settings.instruments = instruments;
+        self
+    }
+
+    /// Set the path to the file in which the console session is recorded
+    pub fn record<'a>(mut self, record: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.record = Some(record.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the file the console session is appended to
+    pub fn log_file(mut self, log_file: SessionLog) -> Self {
+        self.settings.log_file = Some(log_file);
+        self
+    }
+
+    /// Set whether the kernel image is sent again once rebuilt
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.settings.watch = watch;
+        self
+    }
+
+    /// Set the command resetting the board before the watched kernel image is
+    /// sent again
+    pub fn reset_command<'a>(mut self, command: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.reset_command = Some(command.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the path to the file in which the report of the run is written on
+    /// exit
+    pub fn report<'a>(mut self, report: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.report = Some(report.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the path to the file in which the debug bundle is saved on abnormal
+    /// exit
+    pub fn debug_bundle<'a>(mut self, path: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.debug_bundle = Some(path.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the capture of the log messages for the debug bundle
+    pub fn debug_log(mut self, log: LogCapture) -> Self {
+        self.settings.debug_log = Some(log);
+        self
+    }
+
+    /// Set the path to the file in which the session is saved for resuming it
+    pub fn resume_file<'a>(mut self, resume_file: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.resume_file = Some(resume_file.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the boards of the inventory
+    pub fn boards(mut self, boards: Vec<Board>) -> Self {
+        self.settings.boards = boards;
+        self
+    }
+
+    /// Set the file recording the last boot of the boards of the inventory
+    pub fn boards_file<'a>(mut self, boards_file: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.boards_file = Some(boards_file.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the file in which the local usage statistics are kept
+    pub fn usage_file<'a>(mut self, usage_file: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.usage_file = Some(usage_file.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the directory in which the kernel images pushed are archived
+    pub fn archive<'a>(mut self, archive: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.archive = Some(archive.into().as_ref().to_owned());
+        self
+    }
+
+    /// Set the health reporting options
+    pub fn health(mut self, health: HealthReporting) -> Self {
+        self.settings.health = health;
+        self
+    }
+
+    /// Set whether `bootcom` runs as a systemd service
+    pub fn systemd(mut self, systemd: bool) -> Self {
+        self.settings.systemd = systemd;
+        self
+    }
+
+    /// Set whether Bluetooth serial ports are offered in the port selection
+    pub fn bluetooth_ports(mut self, bluetooth_ports: bool) -> Self {
+        self.settings.bluetooth_ports = bluetooth_ports;
+        self
+    }
+
+    /// Set the tokens of the clients of the console served on the activated
+    /// sockets
+    pub fn access(mut self, access: Vec<AccessRule>) -> Self {
+        self.settings.access = access;
+        self
+    }
+
+    /// Set whether the console served on the network is advertised with
+    /// multicast DNS
+    pub fn advertise(mut self, advertise: bool) -> Self {
+        self.settings.advertise = advertise;
+        self
+    }
+
+    /// Set whether the keyboard of the terminal is read
+    pub fn keyboard(mut self, keyboard: bool) -> Self {
+        self.settings.keyboard = keyboard;
+        self
+    }
+
+    /// Set how long to wait after a port appears before opening it
+    pub fn settle_delay(mut self, settle_delay: Duration) -> Self {
+        self.settings.settle_delay = settle_delay;
+        self
+    }
+
+    /// Set how long to wait for the device to come back after a reset
+    pub fn reset_grace(mut self, reset_grace: Duration) -> Self {
+        self.settings.reset_grace = reset_grace;
+        self
+    }
+
+    /// Set how long the device must stay quiet after a kernel transfer
+    pub fn flush_window(mut self, flush_window: Duration) -> Self {
+        self.settings.flush_window = flush_window;
+        self
+    }
+
+    /// Set the maximum number of bytes read at once in terminal mode
+    pub fn max_read_size(mut self, max_read_size: usize) -> Self {
+        self.settings.max_read_size = max_read_size.max(1);
+        self
+    }
+
+    /// Set whether the state of the modem lines is shown in terminal mode
+    pub fn modem_lines(mut self, modem_lines: bool) -> Self {
+        self.settings.modem_lines = modem_lines;
+        self
+    }
+
+    /// Set the workarounds for the boot ROM of the board
+    pub fn quirks(mut self, quirks: Vec<Quirk>) -> Self {
+        self.settings.quirks = quirks;
+        self
+    }
+
+    /// Set the boot mode strapping pins driven before the port is opened
+    pub fn straps(mut self, straps: Vec<Strap>) -> Self {
+        self.settings.straps = straps;
+        self
+    }
+
+    /// Set the external tools flashing the boards found in a ROM loader
+    pub fn flashers(mut self, flashers: Vec<Flasher>) -> Self {
+        self.settings.flashers = flashers;
+        self
+    }
+
+    /// Set the commands typed at the prompt of U-Boot
+    pub fn uboot(mut self, uboot: UbootScript) -> Self {
+        self.settings.uboot = Some(uboot);
+        self
+    }
+
+    /// Set the codecs transforming the console streams
+    pub fn codecs(mut self, codecs: Vec<CodecFactory>) -> Self {
+        self.settings.codecs = codecs;
+        self
+    }
+
+    /// Set the streams tagged by the device on the console
+    pub fn streams(mut self, streams: Vec<Stream>) -> Self {
+        self.settings.streams = streams;
+        self
+    }
+
+    /// Set the faults injected during the transfers
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.settings.chaos = Some(chaos);
+        self
+    }
+
+    /// Set the clock the waits and the watchdogs go by, e.g. a shared
+    /// [`SimulatedClock`](crate::clock::SimulatedClock)
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.settings.clock = Some(ClockHandle::new(clock));
+        self
+    }
+
+    /// Set the capture of the traffic on the port
+    pub fn pcap(mut self, capture: PcapCapture) -> Self {
+        self.settings.pcap = Some(capture);
+        self
+    }
+
+    /// Set the metadata appended to the kernel image when it is sent
+    pub fn trailer(mut self, trailer: Trailer) -> Self {
+        self.settings.trailer = Some(trailer);
+        self
+    }
+
+    /// Set the key signing the kernel images
+    pub fn signing_key(mut self, signing_key: SigningKey) -> Self {
+        self.settings.signing_key = Some(signing_key);
+        self
+    }
+
+    /// Set the pre-shared keys encrypting the transfers
+    pub fn transfer_keys(mut self, transfer_keys: Vec<TransferKey>) -> Self {
+        self.settings.transfer_keys = transfer_keys;
+        self
+    }
+
+    /// Set the observer receiving the progress of the transfers
+    pub fn progress_observer(mut self, observer: impl ProgressObserver + 'static) -> Self {
+        self.settings.progress_observer = Some(ObserverHandle::new(observer));
+        self
+    }
+
+    /// Set the look of the progress bars and spinners
+    pub fn progress_theme(mut self, progress_theme: ProgressTheme) -> Self {
+        self.settings.progress_theme = progress_theme;
+        self
+    }
+
+    /// Set the catalog translating the messages
+    pub fn messages(mut self, messages: Catalog) -> Self {
+        self.settings.messages = messages;
+        self
+    }
+
+    /// Set the path to the configuration file the settings were read from
+    pub fn config_file<'a>(mut self, config_file: impl Into<std::borrow::Cow<'a, str>>) -> Self {
+        self.settings.config_file = Some(config_file.into().as_ref().to_owned());
+        self
+    }
+
+    pub fn finalize(self) -> Settings {
+        self.settings
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn all_default() {
+    let settings = SettingsBuilder::default().finalize();
+    assert_eq!(
+        settings,
+        Settings {
+            path: None,
+            port_match: None,
+            baud_rate: 230_400,
+            data_bits: DataBits::Eight,
+            flow_control: FlowControl::None,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            kernel_image: None,
+            profile: None,
+            baud_rescan: BaudRescan::Prompt,
+            triggers: vec![Trigger::raspbootin()],
+            host_dir: None,
+            dump_dir: None,
+            time_sync: false,
+            send_script: None,
+            paste_pacing: PastePacing::default(),
+            persist: false,
+            retry: RetryPolicy::default(),
+            expectations: vec![],
+            captures: vec![],
+            severities: vec![],
+            instruments: vec![],
+            record: None,
+            log_file: None,
+            watch: false,
+            reset_command: None,
+            report: None,
+            debug_bundle: None,
+            debug_log: None,
+            health: HealthReporting::default(),
+            systemd: false,
+            access: vec![],
+            advertise: false,
+            bluetooth_ports: false,
+            keyboard: true,
+            settle_delay: Duration::from_millis(0),
+            reset_grace: Duration::from_millis(0),
+            flush_window: Duration::from_millis(0),
+            max_read_size: DEFAULT_MAX_READ_SIZE,
+            modem_lines: false,
+            quirks: vec![],
+            straps: vec![],
+            flashers: vec![],
+            uboot: None,
+            codecs: vec![],
+            streams: vec![],
+            chaos: None,
+            clock: None,
+            pcap: None,
+            trailer: None,
+            signing_key: None,
+            transfer_keys: vec![],
+            progress_observer: None,
+            progress_theme: ProgressTheme::default(),
+            messages: Catalog::default(),
+            config_file: None,
+            resume_file: None,
+            boards: vec![],
+            boards_file: None,
+            usage_file: None,
+            archive: None,
+            private_use_builder__: (),
+        }
+    )
+}
+
+#[test]
+fn path() {
+    let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
+    assert_eq!(settings.path.unwrap(), "/dev/ttyUSB0");
+}
+
+#[test]
+fn port_match() {
+    let rule = PortRule {
+        vid: Some(0x0403),
+        pid: Some(0x6001),
+        ..PortRule::default()
+    };
+    let settings = SettingsBuilder::default()
+        .port_match(rule.clone())
+        .finalize();
+    assert_eq!(settings.port_match, Some(rule));
+}
+
+#[test]
+fn baud_rate() {
+    let baud_rate = 96_000;
+    let settings = SettingsBuilder::default().baud_rate(baud_rate).finalize();
+    assert_eq!(settings.baud_rate, baud_rate);
+}
+
+#[test]
+fn data_bits() {
+    let data_bits = DataBits::Seven;
+    let settings = SettingsBuilder::default().data_bits(data_bits).finalize();
+    assert_eq!(settings.data_bits, data_bits);
+}
+
+#[test]
+fn flow_control() {
+    let flow_control = FlowControl::Hardware;
+    let settings = SettingsBuilder::default()
+        .flow_control(flow_control)
+        .finalize();
+    assert_eq!(settings.flow_control, flow_control);
+}
+
+#[test]
+fn stop_bits() {
+    let stop_bits = StopBits::Two;
+    let settings = SettingsBuilder::default().stop_bits(stop_bits).finalize();
+    assert_eq!(settings.stop_bits, stop_bits);
+}
+
+#[test]
+fn parity() {
+    let parity = Parity::Even;
+    let settings = SettingsBuilder::default().parity(parity).finalize();
+    assert_eq!(settings.parity, parity);
+}
+
+#[test]
+fn kernel_image() {
+    let settings = SettingsBuilder::default()
         .kernel_image("test_kernel8.img")
         .finalize();
     assert_eq!(settings.kernel_image.unwrap(), "test_kernel8.img");
 }
+
+#[test]
+fn profile() {
+    let settings = SettingsBuilder::default().profile("rpi4").finalize();
+    assert_eq!(settings.profile.unwrap(), "rpi4");
+}
+
+#[test]
+fn baud_rescan() {
+    let settings = SettingsBuilder::default()
+        .baud_rescan(BaudRescan::Auto)
+        .finalize();
+    assert_eq!(settings.baud_rescan, BaudRescan::Auto);
+}
+
+#[test]
+fn triggers() {
+    let triggers = vec![
+        Trigger::raspbootin(),
+        Trigger {
+            pattern: b"CCC".to_vec(),
+            protocol: TransferProtocol::XmodemCrc,
+            image: Some("debug.img".into()),
+        },
+    ];
+    let settings = SettingsBuilder::default()
+        .triggers(triggers.clone())
+        .finalize();
+    assert_eq!(settings.triggers, triggers);
+}
+
+#[test]
+fn host_dir() {
+    let settings = SettingsBuilder::default().host_dir("fixtures").finalize();
+    assert_eq!(settings.host_dir.unwrap(), "fixtures");
+}
+
+#[test]
+fn dump_dir() {
+    let settings = SettingsBuilder::default().dump_dir("dumps").finalize();
+    assert_eq!(settings.dump_dir.unwrap(), "dumps");
+}
+
+#[test]
+fn time_sync() {
+    let settings = SettingsBuilder::default().time_sync(true).finalize();
+    assert!(settings.time_sync);
+}
+
+#[test]
+fn send_script() {
+    let settings = SettingsBuilder::default()
+        .send_script("repro.script")
+        .finalize();
+    assert_eq!(settings.send_script.unwrap(), "repro.script");
+}
+
+#[test]
+fn persist() {
+    let settings = SettingsBuilder::default().persist(true).finalize();
+    assert!(settings.persist);
+}
+
+#[test]
+fn retry() {
+    let retry = RetryPolicy {
+        send_attempts: 0,
+        port_wait_attempts: 30,
+        ..RetryPolicy::default()
+    };
+    let settings = SettingsBuilder::default().retry(retry).finalize();
+    assert_eq!(settings.retry, retry);
+}
+
+#[test]
+fn expectations() {
+    let expectations = vec![
+        Expectation {
+            pattern: "Booting".into(),
+            timeout: Duration::from_secs(5),
+        },
+        Expectation {
+            pattern: "login:".into(),
+            timeout: Duration::from_secs(60),
+        },
+    ];
+    let settings = SettingsBuilder::default()
+        .expectations(expectations.clone())
+        .finalize();
+    assert_eq!(settings.expectations, expectations);
+}
+
+#[test]
+fn paste_pacing() {
+    let paste_pacing = PastePacing {
+        byte_delay: Duration::from_micros(100),
+        line_delay: Duration::from_millis(20),
+    };
+    let settings = SettingsBuilder::default()
+        .paste_pacing(paste_pacing)
+        .finalize();
+    assert_eq!(settings.paste_pacing, paste_pacing);
+    assert!(!settings.paste_pacing.is_off());
+}
+
+#[test]
+fn record() {
+    let settings = SettingsBuilder::default().record("boot.cast").finalize();
+    assert_eq!(settings.record.unwrap(), "boot.cast");
+}
+
+#[test]
+fn log_file() {
+    let settings = SettingsBuilder::default()
+        .log_file(SessionLog {
+            path: "boot.log".into(),
+            format: LogFormat::Lines,
+            sent: true,
+        })
+        .finalize();
+    let log_file = settings.log_file.unwrap();
+    assert_eq!(log_file.path, "boot.log");
+    assert_eq!(log_file.format, LogFormat::Lines);
+    assert!(log_file.sent);
+}
+
+#[test]
+fn watch() {
+    let settings = SettingsBuilder::default()
+        .watch(true)
+        .reset_command("relay off 1 && relay on 1")
+        .finalize();
+    assert!(settings.watch);
+    assert_eq!(settings.reset_command.unwrap(), "relay off 1 && relay on 1");
+}
+
+#[test]
+fn debug_bundle() {
+    let log = LogCapture::default();
+    let settings = SettingsBuilder::default()
+        .debug_bundle("bootcom-debug.tar")
+        .debug_log(log.clone())
+        .finalize();
+    assert_eq!(settings.debug_bundle.unwrap(), "bootcom-debug.tar");
+    assert_eq!(settings.debug_log, Some(log));
+}
+
+#[test]
+fn report() {
+    let settings = SettingsBuilder::default().report("report.html").finalize();
+    assert_eq!(settings.report.unwrap(), "report.html");
+}
+
+#[test]
+fn resume_file() {
+    let settings = SettingsBuilder::default()
+        .resume_file("session.toml")
+        .finalize();
+    assert_eq!(settings.resume_file.unwrap(), "session.toml");
+}
+
+#[test]
+fn boards() {
+    let board = Board {
+        name: "rpi4-a".into(),
+        port: crate::resume::PortIdentity {
+            path: "/dev/ttyUSB0".into(),
+            usb: None,
+        },
+        profile: Some("rpi4".into()),
+        image: None,
+    };
+    let settings = SettingsBuilder::default()
+        .boards(vec![board.clone()])
+        .boards_file("boards.toml")
+        .finalize();
+    assert_eq!(settings.boards, vec![board]);
+    assert_eq!(settings.boards_file.unwrap(), "boards.toml");
+}
+
+#[test]
+fn usage_file() {
+    let settings = SettingsBuilder::default()
+        .usage_file("usage.toml")
+        .finalize();
+    assert_eq!(settings.usage_file.unwrap(), "usage.toml");
+}
+
+#[test]
+fn archive() {
+    let settings = SettingsBuilder::default().archive("archive").finalize();
+    assert_eq!(settings.archive.unwrap(), "archive");
+}
+
+#[test]
+fn systemd() {
+    let settings = SettingsBuilder::default().systemd(true).finalize();
+    assert!(settings.systemd);
+}
+
+#[test]
+fn health() {
+    let health = HealthReporting {
+        status_file: Some("bootcom.prom".into()),
+        silence_threshold: Some(Duration::from_secs(300)),
+        ..HealthReporting::default()
+    };
+    let settings = SettingsBuilder::default().health(health.clone()).finalize();
+    assert_eq!(settings.health, health);
+    assert!(settings.health.is_enabled());
+    assert!(!HealthReporting::default().is_enabled());
+}
+
+#[test]
+fn bluetooth_ports() {
+    let settings = SettingsBuilder::default().bluetooth_ports(true).finalize();
+    assert!(settings.bluetooth_ports);
+}
+
+#[test]
+fn access() {
+    let access = vec![AccessRule {
+        token: "s3cr3t".into(),
+        permissions: vec![Permission::View, Permission::Push],
+    }];
+    let settings = SettingsBuilder::default().access(access.clone()).finalize();
+    assert_eq!(settings.access, access);
+}
+
+#[test]
+fn advertise() {
+    let settings = SettingsBuilder::default().advertise(true).finalize();
+    assert!(settings.advertise);
+}
+
+#[test]
+fn keyboard() {
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    assert!(!settings.keyboard);
+}
+
+#[test]
+fn settle_delay() {
+    let settings = SettingsBuilder::default()
+        .settle_delay(Duration::from_millis(1500))
+        .finalize();
+    assert_eq!(settings.settle_delay, Duration::from_millis(1500));
+}
+
+#[test]
+fn reset_grace() {
+    let settings = SettingsBuilder::default()
+        .reset_grace(Duration::from_secs(3))
+        .finalize();
+    assert_eq!(settings.reset_grace, Duration::from_secs(3));
+}
+
+#[test]
+fn flush_window() {
+    let settings = SettingsBuilder::default()
+        .flush_window(Duration::from_millis(300))
+        .finalize();
+    assert_eq!(settings.flush_window, Duration::from_millis(300));
+}
+
+#[test]
+fn max_read_size() {
+    let settings = SettingsBuilder::default().finalize();
+    assert_eq!(settings.max_read_size, 65536);
+    let settings = SettingsBuilder::default().max_read_size(0).finalize();
+    assert_eq!(settings.max_read_size, 1);
+}
+
+#[test]
+fn modem_lines() {
+    let settings = SettingsBuilder::default().modem_lines(true).finalize();
+    assert!(settings.modem_lines);
+}
+
+#[test]
+fn progress_observer() {
+    let settings = SettingsBuilder::default()
+        .progress_observer(crate::progress::JsonProgress)
+        .finalize();
+    assert!(settings.progress_observer.is_some());
+    assert_eq!(settings.clone(), settings);
+}
+
+#[test]
+fn trailer() {
+    let trailer = Trailer {
+        version: "v1.2".into(),
+        git_hash: Some("abc123".into()),
+    };
+    assert_eq!(trailer.to_string(), "v1.2 (abc123)");
+    let settings = SettingsBuilder::default()
+        .trailer(trailer.clone())
+        .finalize();
+    assert_eq!(settings.trailer, Some(trailer));
+}
+
+#[test]
+fn signing_key() {
+    assert_eq!("agent".parse(), Ok(SigningKey::Agent));
+    let key: SigningKey = "keys/board.key".parse().unwrap();
+    assert_eq!(key, SigningKey::File("keys/board.key".into()));
+    assert_eq!(key.to_string(), "key `keys/board.key`");
+    assert!("".parse::<SigningKey>().is_err());
+
+    let settings = SettingsBuilder::default()
+        .signing_key(key.clone())
+        .finalize();
+    assert_eq!(settings.signing_key, Some(key));
+}
+
+#[test]
+fn transfer_keys() {
+    let keys = vec![TransferKey {
+        profile: Some("rpi4".into()),
+        key: [0x5a; 32],
+    }];
+    assert_eq!(
+        format!("{:?}", keys[0]),
+        "TransferKey { profile: Some(\"rpi4\"), .. }"
+    );
+    let settings = SettingsBuilder::default()
+        .transfer_keys(keys.clone())
+        .finalize();
+    assert_eq!(settings.transfer_keys, keys);
+}
+
+#[test]
+fn chaos() {
+    let chaos: Chaos = "corrupt=0.01%, drop=1%,delay=100%,delay-ms=250,seed=42"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        chaos,
+        Chaos {
+            corrupt: 100,
+            drop: 10_000,
+            delay: 1_000_000,
+            delay_time: Duration::from_millis(250),
+            seed: Some(42),
+        }
+    );
+    assert_eq!(
+        chaos.to_string(),
+        "corrupt 0.01%, drop 1%, delay 100% by 250ms, seed 42"
+    );
+    assert!("drop=1".parse::<Chaos>().is_err());
+    assert!("drop=101%".parse::<Chaos>().is_err());
+    assert!("lose=1%".parse::<Chaos>().is_err());
+
+    let settings = SettingsBuilder::default().chaos(chaos).finalize();
+    assert_eq!(settings.chaos, Some(chaos));
+}
+
+#[test]
+fn clock() {
+    let clock: Arc<dyn Clock> = Arc::new(crate::clock::SimulatedClock::new());
+    let settings = SettingsBuilder::default().clock(clock.clone()).finalize();
+    assert_eq!(settings.clock, Some(ClockHandle::new(clock)));
+    assert_eq!(settings.clone(), settings);
+}
+
+#[test]
+fn pcap() {
+    let capture = PcapCapture::new(std::io::sink()).unwrap();
+    let settings = SettingsBuilder::default().pcap(capture.clone()).finalize();
+    assert_eq!(settings.pcap, Some(capture));
+    assert_eq!(settings.clone(), settings);
+}
+
+#[test]
+fn progress_theme() {
+    let theme = ProgressTheme {
+        glyphs: crate::progress::Glyphs::Ascii,
+        ..ProgressTheme::default()
+    };
+    let settings = SettingsBuilder::default()
+        .progress_theme(theme.clone())
+        .finalize();
+    assert_eq!(settings.progress_theme, theme);
+    assert!(!settings.progress_theme.is_unicode());
+}
+
+#[test]
+fn messages() {
+    let catalog = crate::messages::parse("[prompt]\nnote = \"Remarque\"").unwrap();
+    let settings = SettingsBuilder::default()
+        .messages(catalog.clone())
+        .finalize();
+    assert_eq!(settings.messages, catalog);
+    assert_eq!(settings.messages.text("prompt.note"), "Remarque");
+}
+
+#[test]
+fn config_file() {
+    let settings = SettingsBuilder::default()
+        .config_file("bootcom.toml")
+        .finalize();
+    assert_eq!(settings.config_file.unwrap(), "bootcom.toml");
+}
+
+#[test]
+fn quirks() {
+    let quirks = vec![Quirk::DtrToggle, Quirk::WakeByte(b'\r')];
+    let settings = SettingsBuilder::default().quirks(quirks.clone()).finalize();
+    assert_eq!(settings.quirks, quirks);
+}
+
+#[test]
+fn straps() {
+    let straps = vec![Strap {
+        profile: Some("stm32mp1".into()),
+        chip: "ftdi-cbus".into(),
+        line: 0,
+        high: false,
+        hold: Some(Duration::from_millis(500)),
+    }];
+    let settings = SettingsBuilder::default().straps(straps.clone()).finalize();
+    assert_eq!(settings.straps, straps);
+    assert_eq!(straps[0].to_string(), "ftdi-cbus:0 low for 500ms");
+}
+
+#[test]
+fn flashers() {
+    let flashers = vec![Flasher {
+        loader: RomLoader::Esp,
+        command: "esptool.py --port {port} write_flash 0x10000 {image}".into(),
+    }];
+    let settings = SettingsBuilder::default()
+        .flashers(flashers.clone())
+        .finalize();
+    assert_eq!(settings.flashers, flashers);
+    assert_eq!(RomLoader::Stm32.to_string(), "stm32");
+}
+
+#[test]
+fn uboot() {
+    let script = UbootScript {
+        prompt: "=> ".into(),
+        commands: vec![
+            "setenv bootargs console=ttyS0,115200".into(),
+            "loady ${loadaddr}".into(),
+            "bootm ${loadaddr}".into(),
+        ],
+    };
+    let settings = SettingsBuilder::default().uboot(script.clone()).finalize();
+    assert_eq!(settings.uboot, Some(script.clone()));
+    assert_eq!(script.transfer(), Some((1, TransferProtocol::Ymodem)));
+}
+
+#[test]
+fn codecs() {
+    let codecs = vec![CodecFactory::builtin("timestamp").unwrap()];
+    let settings = SettingsBuilder::default().codecs(codecs.clone()).finalize();
+    assert_eq!(settings.codecs, codecs);
+}
+
+#[test]
+fn streams() {
+    let streams = vec![Stream {
+        channel: b'T',
+        name: "test".into(),
+        color: Some("green".into()),
+        file: Some("results.log".into()),
+    }];
+    let settings = SettingsBuilder::default()
+        .streams(streams.clone())
+        .finalize();
+    assert_eq!(settings.streams, streams);
+}
+
+#[test]
+fn captures() {
+    let captures = vec![CaptureRule {
+        start: "-----BEGIN CRASH-----".into(),
+        end: "-----END CRASH-----".into(),
+        encoding: BlobEncoding::Base64,
+        directory: "crashes".into(),
+    }];
+    let settings = SettingsBuilder::default()
+        .captures(captures.clone())
+        .finalize();
+    assert_eq!(settings.captures, captures);
+}
+
+#[test]
+fn instruments() {
+    let instruments = vec![Instrument {
+        name: "logic".into(),
+        command: "sigrok-cli -d fx2lafw --continuous -o {file}".into(),
+        stop: None,
+        from: Phase::Trigger,
+        until: Phase::Transfer,
+        directory: "captures".into(),
+        extension: "sr".into(),
+    }];
+    let settings = SettingsBuilder::default()
+        .instruments(instruments.clone())
+        .finalize();
+    assert_eq!(settings.instruments, instruments);
+}
+
+#[test]
+fn severities() {
+    use crate::severity::Severity;
+
+    let severities = vec![SeverityRule::new(Severity::Error, "Synchronous Abort")];
+    let settings = SettingsBuilder::default()
+        .severities(severities.clone())
+        .finalize();
+    assert_eq!(settings.severities, severities);
+}
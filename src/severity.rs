@@ -0,0 +1,220 @@
+//! Classification of the console lines by severity.
+//!
+//! Each line printed by the device is matched against the severity rules of
+//! the settings, in order, the first one matching giving its severity. A rule
+//! matches the lines containing its pattern, whatever the case. Without rules,
+//! the built-in ones of [`default_rules`] are used, which catch the usual
+//! warnings and errors of the bootloaders and of the Linux kernel.
+//!
+//! The lines of each session are counted by severity, and a summary of them is
+//! shown when the session ends, or written as a JSON object in the JSON output
+//! mode (see [`JsonProgress`](crate::progress::JsonProgress)) for the CI
+//! dashboards. The rules are read from the `[[severity]]` sections of the
+//! configuration file (see [`config`](crate::config)).
+//!
+//! **Example**
+//! ```
+//! use bootcom::severity::{classify, default_rules, Severity};
+//!
+//! let rules = default_rules();
+//! assert_eq!(
+//!     classify(&rules, "Kernel panic - not syncing: VFS"),
+//!     Some(Severity::Error)
+//! );
+//! assert_eq!(classify(&rules, "Freeing unused kernel memory"), None);
+//! ```
+
+use std::fmt;
+
+use crate::messages::Catalog;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// How serious a console line is.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// The lines containing `pattern`, whatever the case, have the `severity`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SeverityRule {
+    pub severity: Severity,
+    pub pattern: String,
+}
+impl SeverityRule {
+    pub fn new(severity: Severity, pattern: impl Into<String>) -> Self {
+        SeverityRule {
+            severity,
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Returns `true` if the rule applies to the `line`.
+    pub fn matches(&self, line: &str) -> bool {
+        line.to_lowercase().contains(&self.pattern.to_lowercase())
+    }
+}
+
+/// The built-in rules. The warnings come first, so that the kernel warnings
+/// reporting failures are not taken for errors.
+pub fn default_rules() -> Vec<SeverityRule> {
+    let warnings = ["warning", "warn:"];
+    let errors = [
+        "error",
+        "fail",
+        "panic",
+        "oops",
+        "fault",
+        "bug:",
+        "timed out",
+    ];
+    warnings
+        .iter()
+        .map(|pattern| SeverityRule::new(Severity::Warning, *pattern))
+        .chain(
+            errors
+                .iter()
+                .map(|pattern| SeverityRule::new(Severity::Error, *pattern)),
+        )
+        .collect()
+}
+
+/// The severity of the `line` according to the `rules`, if any applies.
+pub fn classify(rules: &[SeverityRule], line: &str) -> Option<Severity> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(line))
+        .map(|rule| rule.severity)
+}
+
+/// The console lines of a session counted by severity.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SeveritySummary {
+    /// The number of lines printed by the device.
+    pub lines: u32,
+    pub errors: u32,
+    pub warnings: u32,
+    /// The first line classified as an error.
+    pub first_error: Option<String>,
+}
+
+impl SeveritySummary {
+    /// The summary on one or two lines, e.g. `[BC] 🩺 1 error(s) and 2
+    /// warning(s) in 240 console lines`, in the language of the `messages`.
+    pub fn text(&self, messages: &Catalog) -> String {
+        let mut text = messages.text_with(
+            "severity.summary",
+            &[
+                ("errors", &self.errors),
+                ("warnings", &self.warnings),
+                ("lines", &self.lines),
+            ],
+        );
+        if let Some(error) = &self.first_error {
+            text.push('\n');
+            text.push_str(&messages.text_with("severity.first_error", &[("error", error)]));
+        }
+        text
+    }
+}
+impl fmt::Display for SeveritySummary {
+    /// The summary in English.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text(&Catalog::default()))
+    }
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Splits the console output in lines and counts them by severity.
+#[derive(Debug, Default)]
+pub(crate) struct LineClassifier {
+    rules: Vec<SeverityRule>,
+    /// The start of a line not ended yet.
+    partial: Vec<u8>,
+    summary: SeveritySummary,
+}
+impl LineClassifier {
+    /// Classify with the `rules`, or the built-in ones if there are none.
+    pub(crate) fn new(rules: &[SeverityRule]) -> Self {
+        LineClassifier {
+            rules: if rules.is_empty() {
+                default_rules()
+            } else {
+                rules.to_vec()
+            },
+            ..LineClassifier::default()
+        }
+    }
+
+    /// Count the lines completed by the console `data`.
+    pub(crate) fn output(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte != b'\n' {
+                self.partial.push(byte);
+                continue;
+            }
+            let line = std::mem::take(&mut self.partial);
+            let text = String::from_utf8_lossy(&line);
+            let text = console::strip_ansi_codes(text.trim_end_matches('\r'));
+            let summary = &mut self.summary;
+            summary.lines += 1;
+            match classify(&self.rules, &text) {
+                Some(Severity::Error) => {
+                    summary.errors += 1;
+                    if summary.first_error.is_none() {
+                        summary.first_error = Some(text.trim().to_owned());
+                    }
+                }
+                Some(Severity::Warning) => summary.warnings += 1,
+                None => (),
+            }
+        }
+    }
+
+    pub(crate) fn summary(&self) -> &SeveritySummary {
+        &self.summary
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn lines_are_classified() {
+    let mut classifier = LineClassifier::new(&[]);
+    classifier.output(b"U-Boot 2023.04\r\n\x1b[31mmmc0: error -110 wh");
+    classifier.output(b"ile initializing\x1b[0m\r\n");
+    classifier.output(b"WARNING: CPU: 0 PID: 1 failed\r\nmmc1: ERROR again\r\npartial");
+    assert_eq!(
+        classifier.summary(),
+        &SeveritySummary {
+            lines: 4,
+            errors: 2,
+            warnings: 1,
+            first_error: Some("mmc0: error -110 while initializing".into()),
+        }
+    );
+
+    let rules = [SeverityRule::new(Severity::Error, "Synchronous Abort")];
+    let mut classifier = LineClassifier::new(&rules);
+    classifier.output(b"error\n\"synchronous abort\" handler\n");
+    assert_eq!(classifier.summary().errors, 1);
+    assert_eq!(classifier.summary().warnings, 0);
+}
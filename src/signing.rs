@@ -0,0 +1,334 @@
+//! Ed25519 signatures of the kernel images.
+//!
+//! When a [`SigningKey`] is set, the kernel image is sent after a header of
+//! [`HEADER_LEN`] bytes holding the public key and the Ed25519 signature of
+//! what follows the header (the image, and its trailer when there is one),
+//! whatever the transfer protocol: the size announced includes the header. A
+//! secure bootloader checks the public key against the one it trusts and the
+//! signature, as [`verify`] does, before jumping to the image.
+//!
+//! The key is either read from a file made by [`keygen`] (`bootcom keygen`),
+//! holding the 32 bytes of the secret seed in hex, or left in the SSH agent:
+//! the first Ed25519 key of the agent signs the images.
+//!
+//! **Example**
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use bootcom::signing;
+//!
+//! let public_key = signing::keygen(Path::new("board.key")).expect("could not create the key");
+//! println!("trusted key: {:02x?}", public_key);
+//! ```
+
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH};
+
+use crate::{
+    protocol::{hex, note, Line},
+    settings::SigningKey,
+};
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The length of the header sent before a signed image.
+pub const HEADER_LEN: usize = 128;
+
+/// The magic starting the header.
+pub const MAGIC: &[u8; 4] = b"BCSG";
+
+/// Create a new key, in a key file at `path` and its public key in hex next to
+/// it, with the `.pub` extension added. Fails if the key file already exists.
+///
+/// Returns the public key.
+pub fn keygen(path: &Path) -> io::Result<[u8; PUBLIC_KEY_LENGTH]> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(io::Error::other)?;
+    let key = ed25519_dalek::SigningKey::from_bytes(&seed);
+    let public_key = key.verifying_key().to_bytes();
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    writeln!(options.open(path)?, "{}", to_hex(&seed))?;
+    fs::write(public_path(path), format!("{}\n", to_hex(&public_key)))?;
+    Ok(public_key)
+}
+
+/// Check that the `signed` image, header included, is signed with the
+/// `public_key`, returning what follows the header.
+pub fn verify<'a>(
+    public_key: &[u8; PUBLIC_KEY_LENGTH],
+    signed: &'a [u8],
+) -> Result<&'a [u8], String> {
+    if signed.len() < HEADER_LEN || &signed[..MAGIC.len()] != MAGIC {
+        return Err("no signature header".into());
+    }
+    let (header, image) = signed.split_at(HEADER_LEN);
+    if header[8..8 + PUBLIC_KEY_LENGTH] != public_key[..] {
+        return Err("signed with another key".into());
+    }
+    let key = VerifyingKey::from_bytes(public_key).map_err(|e| e.to_string())?;
+    let mut signature = [0u8; 64];
+    signature.copy_from_slice(&header[8 + PUBLIC_KEY_LENGTH..8 + PUBLIC_KEY_LENGTH + 64]);
+    key.verify(image, &Signature::from_bytes(&signature))
+        .map_err(|_| "bad signature".to_string())?;
+    Ok(image)
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// The header of the `image` signed with the `key`.
+pub(crate) fn sign(key: &SigningKey, image: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (public_key, signature) = match key {
+        SigningKey::File(path) => {
+            let key = read_key(Path::new(path))
+                .map_err(|e| format!("could not read the key `{}`: {}", path, e))?;
+            (key.verifying_key().to_bytes(), key.sign(image).to_bytes())
+        }
+        SigningKey::Agent => {
+            agent::sign(image).map_err(|e| format!("could not sign with the SSH agent: {}", e))?
+        }
+    };
+    Ok(header(&public_key, &signature))
+}
+
+/// The layout of the header.
+pub(crate) fn wire_format() -> Vec<Line> {
+    vec![
+        note(format!(
+            "{} bytes sent before the kernel image, in the size announced",
+            HEADER_LEN
+        )),
+        note(format!(
+            "{} (`{}`) | format {} (u8) | 3 bytes of zero padding",
+            hex(MAGIC),
+            String::from_utf8_lossy(MAGIC),
+            FORMAT
+        )),
+        note("Ed25519 public key (32 bytes) | Ed25519 signature (64 bytes)"),
+        note("zero padding to the end of the header"),
+        note("the signature is of the rest of the transfer: the image, and its trailer"),
+    ]
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The version of the layout of the header.
+const FORMAT: u8 = 1;
+
+fn header(public_key: &[u8; PUBLIC_KEY_LENGTH], signature: &[u8; 64]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&[FORMAT, 0, 0, 0]);
+    header.extend_from_slice(public_key);
+    header.extend_from_slice(signature);
+    header.resize(HEADER_LEN, 0);
+    header
+}
+
+fn public_path(path: &Path) -> PathBuf {
+    let mut public = path.as_os_str().to_owned();
+    public.push(".pub");
+    public.into()
+}
+
+fn read_key(path: &Path) -> Result<ed25519_dalek::SigningKey, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let seed = from_hex(text.trim())
+        .filter(|seed| seed.len() == 32)
+        .ok_or("expected the 32 bytes of a seed, in hex")?;
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&seed);
+    Ok(ed25519_dalek::SigningKey::from_bytes(&bytes))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The bytes written in `hex`, if valid.
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A client of the SSH agent (draft-miller-ssh-agent), signing with its first
+/// Ed25519 key.
+#[cfg(unix)]
+mod agent {
+    use std::{
+        env,
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+
+    const FAILURE: u8 = 5;
+    const REQUEST_IDENTITIES: u8 = 11;
+    const IDENTITIES_ANSWER: u8 = 12;
+    const SIGN_REQUEST: u8 = 13;
+    const SIGN_RESPONSE: u8 = 14;
+
+    const KEY_TYPE: &[u8] = b"ssh-ed25519";
+
+    /// The public key and the signature of the `data`.
+    pub(super) fn sign(data: &[u8]) -> Result<([u8; PUBLIC_KEY_LENGTH], [u8; 64]), String> {
+        let socket = env::var("SSH_AUTH_SOCK").map_err(|_| "`SSH_AUTH_SOCK` is not set")?;
+        let mut agent = UnixStream::connect(&socket).map_err(|e| e.to_string())?;
+
+        let answer = request(&mut agent, REQUEST_IDENTITIES, &[])?;
+        let mut reader = Reader(&answer);
+        if reader.byte()? != IDENTITIES_ANSWER {
+            return Err("the identities could not be listed".into());
+        }
+        let mut found = None;
+        for _ in 0..reader.u32()? {
+            let blob = reader.string()?;
+            // The comment.
+            reader.string()?;
+            if let Some(public_key) = ed25519_key(blob)? {
+                found = Some((blob, public_key));
+                break;
+            }
+        }
+        let (blob, public_key) = found.ok_or("no Ed25519 key in the agent")?;
+
+        let mut message = vec![];
+        put_string(&mut message, blob);
+        put_string(&mut message, data);
+        message.extend_from_slice(&0u32.to_be_bytes());
+        let response = request(&mut agent, SIGN_REQUEST, &message)?;
+        let mut reader = Reader(&response);
+        match reader.byte()? {
+            SIGN_RESPONSE => (),
+            FAILURE => return Err("the agent refused to sign".into()),
+            other => return Err(format!("unexpected answer {}", other)),
+        }
+        let mut signature = Reader(reader.string()?);
+        if signature.string()? != KEY_TYPE {
+            return Err("not an Ed25519 signature".into());
+        }
+        let mut bytes = [0u8; 64];
+        let signature = signature.string()?;
+        if signature.len() != bytes.len() {
+            return Err("not an Ed25519 signature".into());
+        }
+        bytes.copy_from_slice(signature);
+        Ok((public_key, bytes))
+    }
+
+    /// The public key in the key `blob`, if it is an Ed25519 one.
+    fn ed25519_key(blob: &[u8]) -> Result<Option<[u8; PUBLIC_KEY_LENGTH]>, String> {
+        let mut reader = Reader(blob);
+        if reader.string()? != KEY_TYPE {
+            return Ok(None);
+        }
+        let key = reader.string()?;
+        if key.len() != PUBLIC_KEY_LENGTH {
+            return Err("bad Ed25519 key".into());
+        }
+        let mut public_key = [0u8; PUBLIC_KEY_LENGTH];
+        public_key.copy_from_slice(key);
+        Ok(Some(public_key))
+    }
+
+    fn request(agent: &mut UnixStream, kind: u8, contents: &[u8]) -> Result<Vec<u8>, String> {
+        let mut message = ((contents.len() + 1) as u32).to_be_bytes().to_vec();
+        message.push(kind);
+        message.extend_from_slice(contents);
+        agent.write_all(&message).map_err(|e| e.to_string())?;
+        let mut len = [0u8; 4];
+        agent.read_exact(&mut len).map_err(|e| e.to_string())?;
+        let mut answer = vec![0u8; u32::from_be_bytes(len) as usize];
+        agent.read_exact(&mut answer).map_err(|e| e.to_string())?;
+        Ok(answer)
+    }
+
+    fn put_string(message: &mut Vec<u8>, string: &[u8]) {
+        message.extend_from_slice(&(string.len() as u32).to_be_bytes());
+        message.extend_from_slice(string);
+    }
+
+    /// Reads the fields of a message.
+    struct Reader<'a>(&'a [u8]);
+    impl<'a> Reader<'a> {
+        fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+            if self.0.len() < len {
+                return Err("truncated answer".into());
+            }
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            Ok(taken)
+        }
+
+        fn byte(&mut self) -> Result<u8, String> {
+            Ok(self.take(1)?[0])
+        }
+
+        fn u32(&mut self) -> Result<u32, String> {
+            let bytes = self.take(4)?;
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+
+        fn string(&mut self) -> Result<&'a [u8], String> {
+            let len = self.u32()? as usize;
+            self.take(len)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod agent {
+    use ed25519_dalek::PUBLIC_KEY_LENGTH;
+
+    pub(super) fn sign(_data: &[u8]) -> Result<([u8; PUBLIC_KEY_LENGTH], [u8; 64]), String> {
+        Err("the SSH agent is only supported on Unix".into())
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn signed_with_a_generated_key() {
+    let path = std::env::temp_dir().join(format!("bootcom-signing-{}.key", std::process::id()));
+    let public_key = keygen(&path).unwrap();
+    assert!(keygen(&path).is_err());
+    let public = fs::read_to_string(public_path(&path)).unwrap();
+    assert_eq!(from_hex(public.trim()).unwrap(), public_key);
+
+    let header = sign(&SigningKey::File(path.to_string_lossy().into()), b"kernel").unwrap();
+    assert_eq!(header.len(), HEADER_LEN);
+    assert_eq!(&header[..8], b"BCSG\x01\0\0\0");
+    let mut signed = header.clone();
+    signed.extend_from_slice(b"kernel");
+    assert_eq!(verify(&public_key, &signed), Ok(&b"kernel"[..]));
+
+    signed[HEADER_LEN] ^= 1;
+    assert_eq!(verify(&public_key, &signed), Err("bad signature".into()));
+    assert!(verify(&[0; 32], &signed).is_err());
+    assert!(verify(&public_key, b"kernel").is_err());
+
+    fs::remove_file(public_path(&path)).unwrap();
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,155 @@
+//! Statistics of the boot sessions.
+//!
+//! Each boot protocol session, from the connection to the device until its
+//! disconnection, keeps its own [`SessionStats`] in the data shared by all the
+//! states of the state machine. When the session ends, they are added to the
+//! totals kept by the device manager for the whole run, which are available
+//! through [`DeviceManager::stats`](crate::DeviceManager::stats) and shown in
+//! the summary at the end of the run.
+
+use std::{fmt, time::Duration};
+
+use crate::messages::Catalog;
+use crate::progress::TransferReport;
+use crate::utils::{HumanDuration, HumanSize};
+
+/// Counters and timings of one or more boot sessions.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SessionStats {
+    /// The number of boot sessions, one per connection to the device.
+    pub sessions: u32,
+    /// How long the device was connected.
+    pub connected_time: Duration,
+    /// The number of bytes received in terminal mode.
+    pub bytes_received: u64,
+    /// The number of kernel images successfully sent.
+    pub kernels_sent: u32,
+    /// The number of bytes of the kernel images successfully sent.
+    pub kernel_bytes_sent: u64,
+    /// The time spent sending the kernel images.
+    pub transfer_time: Duration,
+    /// The number of blocks or chunks of the kernel images sent again.
+    pub transfer_retries: u32,
+    /// The report of the last kernel image successfully sent.
+    pub last_transfer: Option<TransferReport>,
+    /// The number of host service sessions served.
+    pub host_service_sessions: u32,
+    /// The number of memory dumps received.
+    pub dumps_received: u32,
+    /// The number of baud rate rescans.
+    pub baud_rescans: u32,
+    /// The number of errors, recovered from or not.
+    pub errors: u32,
+    /// The description of the last error.
+    pub last_error: Option<String>,
+    /// The distinct errors, with the number of times each occurred, in the
+    /// order they first did.
+    pub error_counts: Vec<(String, u32)>,
+}
+impl SessionStats {
+    /// Count a kernel image successfully sent, as summarized by the `report`.
+    pub(crate) fn transfer(&mut self, report: TransferReport) {
+        self.kernels_sent += 1;
+        self.kernel_bytes_sent += report.bytes;
+        self.transfer_time += report.duration;
+        self.transfer_retries += report.retries;
+        self.last_transfer = Some(report);
+    }
+
+    /// Count an error, remembering its `description`.
+    pub(crate) fn error(&mut self, description: impl fmt::Display) {
+        let description = description.to_string();
+        self.errors += 1;
+        self.count_error(&description, 1);
+        self.last_error = Some(description);
+    }
+
+    fn count_error(&mut self, description: &str, count: u32) {
+        match self.error_counts.iter_mut().find(|(d, _)| d == description) {
+            Some((_, total)) => *total += count,
+            None => self.error_counts.push((description.into(), count)),
+        }
+    }
+
+    /// Add the statistics of another session to these ones.
+    pub(crate) fn add(&mut self, other: &SessionStats) {
+        self.sessions += other.sessions;
+        self.connected_time += other.connected_time;
+        self.bytes_received += other.bytes_received;
+        self.kernels_sent += other.kernels_sent;
+        self.kernel_bytes_sent += other.kernel_bytes_sent;
+        self.transfer_time += other.transfer_time;
+        self.transfer_retries += other.transfer_retries;
+        if other.last_transfer.is_some() {
+            self.last_transfer = other.last_transfer.clone();
+        }
+        self.host_service_sessions += other.host_service_sessions;
+        self.dumps_received += other.dumps_received;
+        self.baud_rescans += other.baud_rescans;
+        self.errors += other.errors;
+        if other.last_error.is_some() {
+            self.last_error = other.last_error.clone();
+        }
+        for (description, count) in &other.error_counts {
+            self.count_error(description, *count);
+        }
+    }
+}
+impl SessionStats {
+    /// The end of run summary, on one or two lines, in the language of the
+    /// `messages`.
+    pub fn text(&self, messages: &Catalog) -> String {
+        let mut text = messages.text_with(
+            "stats.summary",
+            &[
+                ("sessions", &self.sessions),
+                ("connected", &HumanDuration(self.connected_time)),
+                ("received", &HumanSize(self.bytes_received)),
+                ("kernels", &self.kernels_sent),
+                ("sent", &HumanSize(self.kernel_bytes_sent)),
+                ("transfer", &HumanDuration(self.transfer_time)),
+                ("errors", &self.errors),
+            ],
+        );
+        if let Some(error) = &self.last_error {
+            text.push('\n');
+            text.push_str(&messages.text_with("stats.last_error", &[("error", error)]));
+        }
+        text
+    }
+}
+impl fmt::Display for SessionStats {
+    /// The end of run summary, in English.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text(&Catalog::default()))
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn sessions_add_up() {
+    let mut total = SessionStats::default();
+    let mut session = SessionStats {
+        sessions: 1,
+        bytes_received: 100,
+        kernels_sent: 1,
+        kernel_bytes_sent: 2048,
+        transfer_time: Duration::from_millis(1500),
+        ..SessionStats::default()
+    };
+    total.add(&session);
+    session.error("port closed");
+    total.add(&session);
+    assert_eq!(total.sessions, 2);
+    assert_eq!(total.bytes_received, 200);
+    assert_eq!(total.errors, 1);
+    assert_eq!(total.error_counts, vec![("port closed".to_string(), 1)]);
+    assert_eq!(
+        total.to_string(),
+        "[BC] 📊 2 session(s) over 0ms, 200 B received, 2 kernel(s) sent \
+         (4.0 KiB in 3.0s), 1 error(s)\n[BC]    last error: port closed"
+    );
+}
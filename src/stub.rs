@@ -0,0 +1,538 @@
+//! Generation of bootloader receiver stubs matching the host implementation.
+//!
+//! Firmware authors can start from a minimal receiver, rendered in Rust or C
+//! from the templates embedded in `bootcom`, with the configured trigger and
+//! protocol options. The generated code only needs two UART primitives
+//! (`uart_getc` and `uart_putc`) from the platform.
+//!
+//! The stubs speak the `raspbootin` protocol, reporting their revision (`OV`)
+//! and offering to resume an interrupted upload (`OR`), or the chunked one in
+//! its version 1 or 2, without the software flow control. The Rust stub ends
+//! with the logic of the protocols verbatim, which the tests run against the
+//! sending side of `bootcom` and the [`conformance`](crate::conformance)
+//! session; the C stub follows it line by line.
+//!
+//! **Example**
+//! ```
+//! use bootcom::stub::{self, Language, StubOptions, Target};
+//!
+//! let options = StubOptions::new(Target::Aarch64, Language::C);
+//! let source = stub::render(&options);
+//! assert!(source.contains("receive_and_boot"));
+//! ```
+
+use std::{fmt, str::FromStr};
+
+use crate::utils::{chunked::FRAME_OVERHEAD, kernel::PROTOCOL_REVISION};
+
+const RUST_TEMPLATE: &str = include_str!("stub/receiver.rs.tpl");
+/// The logic of the protocols, appended to the Rust template.
+const RUST_PROTOCOL: &str = include_str!("stub/protocol.rs");
+const C_TEMPLATE: &str = include_str!("stub/receiver.c.tpl");
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The architecture the stub is generated for. It determines the default
+/// address at which the kernel image is loaded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Target {
+    /// 64-bit ARM, loading at `0x80000` like the Raspberry Pi firmware.
+    Aarch64,
+    /// 32-bit ARM, loading at `0x8000` like the Raspberry Pi firmware.
+    Arm,
+    /// 64-bit RISC-V, loading at `0x80200000` like OpenSBI payloads.
+    Riscv64,
+}
+impl Target {
+    /// The default load address of the kernel image on this target.
+    pub fn load_address(self) -> u64 {
+        match self {
+            Target::Aarch64 => 0x8_0000,
+            Target::Arm => 0x8000,
+            Target::Riscv64 => 0x8020_0000,
+        }
+    }
+}
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aarch64" => Ok(Target::Aarch64),
+            "arm" => Ok(Target::Arm),
+            "riscv64" => Ok(Target::Riscv64),
+            _ => Err(format!("unsupported target `{}`", s)),
+        }
+    }
+}
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Target::Aarch64 => "aarch64",
+            Target::Arm => "arm",
+            Target::Riscv64 => "riscv64",
+        })
+    }
+}
+
+/// The language of the generated stub.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Language {
+    Rust,
+    C,
+}
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rust" => Ok(Language::Rust),
+            "c" => Ok(Language::C),
+            _ => Err(format!("unsupported language `{}`", s)),
+        }
+    }
+}
+
+/// The protocol the stub receives the image with.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Protocol {
+    Raspbootin,
+    /// The version 1 of the chunked protocol.
+    Chunked,
+    /// The version 2 of the chunked protocol, with framed chunks.
+    ChunkedV2,
+}
+impl Protocol {
+    /// The smallest receive buffer the protocol works with.
+    pub fn smallest_buffer(self) -> u16 {
+        match self {
+            Protocol::Raspbootin => 0,
+            Protocol::Chunked => 1,
+            Protocol::ChunkedV2 => FRAME_OVERHEAD as u16 + 1,
+        }
+    }
+
+    /// The version of the chunked protocol, `0` for the `raspbootin` one.
+    fn chunked_version(self) -> u8 {
+        match self {
+            Protocol::Raspbootin => 0,
+            Protocol::Chunked => 1,
+            Protocol::ChunkedV2 => 2,
+        }
+    }
+}
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raspbootin" => Ok(Protocol::Raspbootin),
+            "chunked" => Ok(Protocol::Chunked),
+            "chunked-v2" => Ok(Protocol::ChunkedV2),
+            _ => Err(format!("unsupported protocol `{}`", s)),
+        }
+    }
+}
+
+/// Options used to render the stub.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StubOptions {
+    pub target: Target,
+    pub language: Language,
+    /// The trigger pattern the stub sends to request the kernel image.
+    pub trigger: Vec<u8>,
+    /// Whether the stub sends back the CRC-32 of the received image.
+    pub crc: bool,
+    /// Overrides the default load address of the target.
+    pub load_address: Option<u64>,
+    pub protocol: Protocol,
+    /// The receive buffer the stub advertises with the chunked protocol, at
+    /// least the [`Protocol::smallest_buffer`].
+    pub receive_buffer: u16,
+}
+impl StubOptions {
+    /// Options for the given target and language, with the default trigger,
+    /// CRC echo enabled, the default load address of the target and the
+    /// `raspbootin` protocol.
+    pub fn new(target: Target, language: Language) -> Self {
+        StubOptions {
+            target,
+            language,
+            trigger: vec![3, 3, 3],
+            crc: true,
+            load_address: None,
+            protocol: Protocol::Raspbootin,
+            receive_buffer: 256,
+        }
+    }
+}
+
+/// Render the receiver stub source code for the given options.
+pub fn render(options: &StubOptions) -> String {
+    let template = match options.language {
+        Language::Rust => format!("{}{}", RUST_TEMPLATE, RUST_PROTOCOL),
+        Language::C => C_TEMPLATE.into(),
+    };
+    let trigger_bytes = options
+        .trigger
+        .iter()
+        .map(|b| format!("{:#04x}", b))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let trigger_hex: String = options
+        .trigger
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let load_address = options
+        .load_address
+        .unwrap_or_else(|| options.target.load_address());
+    let protocol = match options.protocol {
+        Protocol::Raspbootin => format!("raspbootin revision {}", PROTOCOL_REVISION),
+        chunked => format!(
+            "chunked version {}, {} B receive buffer",
+            chunked.chunked_version(),
+            options.receive_buffer
+        ),
+    };
+
+    template
+        .replace("{{TARGET}}", &options.target.to_string())
+        .replace("{{PROTOCOL}}", &protocol)
+        .replace("{{PROTOCOL_REVISION}}", &PROTOCOL_REVISION.to_string())
+        .replace(
+            "{{CHUNKED_VERSION}}",
+            &options.protocol.chunked_version().to_string(),
+        )
+        .replace("{{RECEIVE_BUFFER}}", &options.receive_buffer.to_string())
+        .replace("{{LOAD_ADDRESS}}", &format!("{:#x}", load_address))
+        .replace("{{TRIGGER_LEN}}", &options.trigger.len().to_string())
+        .replace("{{TRIGGER_BYTES}}", &trigger_bytes)
+        .replace("{{TRIGGER_HEX}}", &trigger_hex)
+        .replace("{{CRC}}", if options.crc { "true" } else { "false" })
+        .replace("{{CRC_C}}", if options.crc { "1" } else { "0" })
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(test)]
+mod protocol {
+    include!("stub/protocol.rs");
+}
+
+/// What goes through the UART of a stub, which runs in its own thread.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Wire {
+    to_stub: std::collections::VecDeque<u8>,
+    from_stub: std::collections::VecDeque<u8>,
+    /// Whether the stub waits for bytes, having read all the others.
+    waiting: bool,
+    /// Whether the stub booted the image or gave up.
+    done: bool,
+}
+
+/// The side of `bootcom` of the UART of a stub, a write returning once the
+/// stub took all the bytes and answered them.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+struct Link(std::sync::Arc<(std::sync::Mutex<Wire>, std::sync::Condvar)>);
+#[cfg(test)]
+impl Link {
+    /// Run the stub with the `config` for up to 3 attempts, the device going
+    /// quiet for the `silence` ending one, and return the size of the image it
+    /// boots with its memory. The stub sent its trigger once this returns.
+    fn spawn_stub(
+        &self,
+        config: protocol::Config,
+        silence: std::time::Duration,
+    ) -> std::thread::JoinHandle<(Option<usize>, Vec<u8>)> {
+        let mut board = StubBoard {
+            link: self.clone(),
+            silence,
+            memory: vec![],
+        };
+        let stub = std::thread::spawn(move || {
+            let mut receiver = protocol::Receiver::new(config);
+            let size = (0..3).find_map(|_| receiver.receive(&mut board));
+            let (wire, changed) = &*board.link.0;
+            wire.lock().unwrap().done = true;
+            changed.notify_all();
+            (size, board.memory)
+        });
+        let (wire, changed) = &*self.0;
+        let mut wire = wire.lock().unwrap();
+        while !wire.waiting && !wire.done {
+            wire = changed.wait(wire).unwrap();
+        }
+        stub
+    }
+}
+#[cfg(test)]
+impl std::io::Read for Link {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut wire = self.0 .0.lock().unwrap();
+        let len = buf.len().min(wire.from_stub.len());
+        for (byte, received) in buf.iter_mut().zip(wire.from_stub.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+}
+#[cfg(test)]
+impl std::io::Write for Link {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (wire, changed) = &*self.0;
+        let mut wire = wire.lock().unwrap();
+        wire.to_stub.extend(buf);
+        wire.waiting = false;
+        changed.notify_all();
+        while !(wire.done || wire.waiting && wire.to_stub.is_empty()) {
+            wire = changed.wait(wire).unwrap();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+#[cfg(test)]
+impl crate::transport::Transport for Link {
+    fn bytes_to_read(&self) -> std::io::Result<u32> {
+        Ok(self.0 .0.lock().unwrap().from_stub.len() as u32)
+    }
+
+    fn clear_input(&mut self) -> std::io::Result<()> {
+        self.0 .0.lock().unwrap().from_stub.clear();
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        "stub".into()
+    }
+}
+
+/// The UART and the memory of the stub.
+#[cfg(test)]
+struct StubBoard {
+    link: Link,
+    silence: std::time::Duration,
+    memory: Vec<u8>,
+}
+#[cfg(test)]
+impl protocol::Board for StubBoard {
+    fn getc(&mut self) -> Option<u8> {
+        let (wire, changed) = &*self.link.0;
+        let mut wire = wire.lock().unwrap();
+        loop {
+            if let Some(byte) = wire.to_stub.pop_front() {
+                return Some(byte);
+            }
+            wire.waiting = true;
+            changed.notify_all();
+            let (guard, waited) = changed.wait_timeout(wire, self.silence).unwrap();
+            wire = guard;
+            if waited.timed_out() && wire.to_stub.is_empty() {
+                wire.waiting = false;
+                return None;
+            }
+        }
+    }
+
+    fn putc(&mut self, byte: u8) {
+        self.link.0 .0.lock().unwrap().from_stub.push_back(byte);
+    }
+
+    fn memory(&mut self, size: usize) -> &mut [u8] {
+        if self.memory.len() < size {
+            self.memory.resize(size, 0);
+        }
+        &mut self.memory[..size]
+    }
+}
+
+/// The configuration the stub is rendered with for the `options`.
+#[cfg(test)]
+fn config(options: &StubOptions) -> protocol::Config {
+    protocol::Config {
+        trigger: Box::leak(options.trigger.clone().into_boxed_slice()),
+        protocol_revision: PROTOCOL_REVISION,
+        chunked_version: options.protocol.chunked_version(),
+        receive_buffer: options.receive_buffer,
+        send_crc: options.crc,
+    }
+}
+
+#[test]
+fn all_placeholders_are_replaced() {
+    for language in [Language::Rust, Language::C].iter() {
+        let source = render(&StubOptions::new(Target::Aarch64, *language));
+        assert!(!source.contains("{{"), "{}", source);
+        assert!(source.contains("0x80000"));
+        assert!(source.contains("0x03, 0x03, 0x03"));
+        assert!(source.contains(&format!("raspbootin revision {},", PROTOCOL_REVISION)));
+        assert!(source.contains("trigger: 030303,"));
+    }
+}
+
+#[test]
+fn custom_trigger_and_load_address() {
+    let mut options = StubOptions::new(Target::Riscv64, Language::C);
+    options.trigger = b"CC".to_vec();
+    options.crc = false;
+    options.load_address = Some(0x4000_0000);
+    let source = render(&options);
+    assert!(source.contains("trigger[2] = {0x43, 0x43}"));
+    assert!(source.contains("#define SEND_CRC 0"));
+    assert!(source.contains("0x40000000"));
+}
+
+#[test]
+fn rust_stub_ends_with_the_tested_protocols() {
+    let mut options = StubOptions::new(Target::Aarch64, Language::Rust);
+    options.protocol = Protocol::ChunkedV2;
+    options.receive_buffer = 64;
+    let source = render(&options);
+    assert!(source.ends_with(RUST_PROTOCOL));
+    assert!(source.contains("chunked_version: 2,\n    receive_buffer: 64,"));
+    assert!(source.contains("protocol: chunked version 2, 64 B receive buffer"));
+
+    options.language = Language::C;
+    let source = render(&options);
+    assert!(source.contains("#define CHUNKED_VERSION 2\n"));
+    assert!(source.contains("#define RECEIVE_BUFFER 64\n"));
+}
+
+#[test]
+fn stub_receives_what_bootcom_sends() {
+    use crate::settings::{SettingsBuilder, TransferProtocol};
+    use crate::utils::send_kernel;
+    use std::time::Duration;
+
+    let image: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("bootcom-stub-{}.img", std::process::id()));
+    std::fs::write(&path, &image).unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    for protocol in [Protocol::Raspbootin, Protocol::Chunked, Protocol::ChunkedV2].iter() {
+        let mut options = StubOptions::new(Target::Aarch64, Language::Rust);
+        options.protocol = *protocol;
+        options.receive_buffer = 64;
+        let transfer = match protocol {
+            Protocol::Raspbootin => TransferProtocol::Raspbootin,
+            _ => TransferProtocol::Chunked,
+        };
+        let mut link = Link::default();
+        let stub = link.spawn_stub(config(&options), Duration::from_millis(500));
+        let report = send_kernel(&mut link, &settings, transfer, path.to_str(), &mut None);
+        let (size, memory) = stub.join().unwrap();
+        assert!(report.unwrap().is_some(), "{:?}", protocol);
+        assert_eq!(size, Some(image.len()), "{:?}", protocol);
+        assert!(memory == image, "{:?}", protocol);
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn stub_resumes_an_interrupted_upload() {
+    use crate::settings::{SettingsBuilder, TransferProtocol};
+    use crate::utils::{kernel::size_frame, send_kernel, Crc32, Interrupted};
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    let path = std::env::temp_dir().join(format!("bootcom-stub-resume-{}.img", std::process::id()));
+    std::fs::write(&path, b"kernel").unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    let options = StubOptions::new(Target::Aarch64, Language::Rust);
+    let mut link = Link::default();
+    let stub = link.spawn_stub(config(&options), Duration::from_millis(500));
+
+    // The first upload stops after 3 bytes.
+    link.write_all(&size_frame(6)).unwrap();
+    link.write_all(&[PROTOCOL_REVISION]).unwrap();
+    link.write_all(b"ker").unwrap();
+    let mut received = [0; 8];
+    link.read_exact(&mut received).unwrap();
+    assert_eq!(&received, b"\x03\x03\x03OV\x02OK");
+    // Until the stub gives up and requests the image again.
+    let mut trigger = vec![];
+    while trigger.len() < 3 {
+        std::thread::sleep(Duration::from_millis(10));
+        link.read_to_end(&mut trigger).unwrap();
+    }
+
+    let mut crc = Crc32::new();
+    crc.update(b"kernel");
+    let mut interrupted = Some(Interrupted {
+        crc: crc.finalize(),
+        len: 6,
+        written: 4,
+    });
+    let report = send_kernel(
+        &mut link,
+        &settings,
+        TransferProtocol::Raspbootin,
+        path.to_str(),
+        &mut interrupted,
+    );
+    let (size, memory) = stub.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(report.unwrap().unwrap().resumed_from, 3);
+    assert_eq!(size, Some(6));
+    assert_eq!(memory, b"kernel");
+}
+
+#[test]
+fn stub_passes_the_conformance_session() {
+    use crate::conformance::{self, Check, CheckResult};
+    use std::time::Duration;
+
+    let options = StubOptions::new(Target::Aarch64, Language::Rust);
+    let mut link = Link::default();
+    let stub = link.spawn_stub(config(&options), Duration::from_secs(1));
+    let report = conformance::run_on_port(
+        &mut link,
+        &conformance::Options {
+            trigger_timeout: Duration::from_secs(1),
+            response_timeout: Duration::from_millis(200),
+            ..conformance::Options::default()
+        },
+    );
+    assert_eq!(stub.join().unwrap().0, Some(4096));
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.result(Check::Crc), Some(&CheckResult::Passed));
+}
+
+#[test]
+fn stub_acknowledges_the_frames_again() {
+    use crate::utils::{chunked, kernel::size_frame};
+    use std::time::Duration;
+
+    let mut options = StubOptions::new(Target::Aarch64, Language::Rust);
+    options.protocol = Protocol::ChunkedV2;
+    options.receive_buffer = 16;
+    let mut link = Link::default();
+    let stub = link.spawn_stub(config(&options), Duration::from_millis(500));
+    let mut corrupted = chunked::frame(1, b"image");
+    corrupted[5] ^= 1;
+    let mut stream = size_frame(13).to_vec();
+    stream.extend_from_slice(&[chunked::SYN, 2]);
+    // The first frame sent twice, the second one corrupted once.
+    stream.extend(chunked::frame(0, b"a kernel"));
+    stream.extend(chunked::frame(0, b"a kernel"));
+    stream.extend(corrupted);
+    stream.extend(chunked::frame(1, b"image"));
+    stream.push(chunked::EOT);
+    std::io::Write::write_all(&mut link, &stream).unwrap();
+    let (size, memory) = stub.join().unwrap();
+    assert_eq!(size, Some(13));
+    assert_eq!(memory, b"a kernelimage");
+    let mut answers = vec![];
+    std::io::Read::read_to_end(&mut link, &mut answers).unwrap();
+    assert_eq!(&answers[3..16], b"OK\0\0\x02\x10\0\x06\0\x06\0\x15\x01");
+    assert_eq!(&answers[16..18], b"\x06\x01");
+}
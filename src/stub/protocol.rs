@@ -0,0 +1,237 @@
+// =============================================================================
+// The boot protocols, the same on every platform
+// =============================================================================
+
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const SYN: u8 = 0x16;
+const CAN: u8 = 0x18;
+const PERSIST: u8 = b'P';
+
+/// The bytes of a frame of the version 2 of the chunked protocol around its
+/// chunk: `STX`, the sequence number, the length and the CRC-32.
+const FRAME_OVERHEAD: usize = 8;
+
+/// How the stub receives the image.
+pub struct Config {
+    /// Sent to `bootcom` to request the image.
+    pub trigger: &'static [u8],
+    /// The revision of the `raspbootin` protocol, reported to `bootcom`.
+    pub protocol_revision: u8,
+    /// The highest version of the chunked protocol spoken, `0` to speak the
+    /// `raspbootin` protocol instead.
+    pub chunked_version: u8,
+    /// The receive buffer advertised with the chunked protocol, which the
+    /// chunks (or the frames of the version 2) fit in.
+    pub receive_buffer: u16,
+    /// Whether the CRC-32 of the image is sent back once it is received.
+    pub send_crc: bool,
+}
+
+/// What the stub needs from the platform.
+pub trait Board {
+    /// The next byte received, `None` once nothing was received for a while.
+    fn getc(&mut self) -> Option<u8>;
+
+    fn putc(&mut self, byte: u8);
+
+    /// The memory the image is received in, from its start to its `size`.
+    fn memory(&mut self, size: usize) -> &mut [u8];
+}
+
+/// Receives the image, resuming the `raspbootin` uploads interrupted by a
+/// timeout.
+pub struct Receiver {
+    config: Config,
+    /// How much of the image an interrupted upload left in memory.
+    held: usize,
+}
+impl Receiver {
+    pub const fn new(config: Config) -> Self {
+        Receiver { config, held: 0 }
+    }
+
+    /// Request the image and receive it, returning its size once it is to be
+    /// booted, or `None` when the transfer was given up and the image is to
+    /// be requested again.
+    pub fn receive(&mut self, board: &mut impl Board) -> Option<usize> {
+        put(board, self.config.trigger);
+        let size = get_u32(board)? as usize;
+        if self.config.chunked_version == 0 {
+            self.receive_raspbootin(board, size)?;
+        } else {
+            receive_chunked(board, &self.config, size)?;
+        }
+        if self.config.send_crc {
+            let crc = board
+                .memory(size)
+                .iter()
+                .fold(0xffff_ffff, |crc, byte| crc32_update(crc, *byte));
+            put(board, &(!crc).to_le_bytes());
+        }
+        Some(size)
+    }
+
+    /// The `raspbootin` protocol: the revisions, `bootcom` warning when they
+    /// differ, then the image from where an interrupted upload stopped.
+    fn receive_raspbootin(&mut self, board: &mut impl Board, size: usize) -> Option<()> {
+        put(board, b"OV");
+        board.putc(self.config.protocol_revision);
+        board.getc()?;
+        let mut from = 0;
+        if self.held > 0 {
+            put(board, b"OR");
+            put(board, &(self.held as u32).to_le_bytes());
+            // `bootcom` sends another image from the start.
+            from = get_u32(board)? as usize;
+            if from > size {
+                return None;
+            }
+        } else {
+            put(board, b"OK");
+        }
+        self.held = from;
+        while self.held < size {
+            let byte = board.getc()?;
+            board.memory(size)[self.held] = byte;
+            self.held += 1;
+        }
+        self.held = 0;
+        Some(())
+    }
+}
+
+/// The chunked protocol, in the version `bootcom` picks.
+fn receive_chunked(board: &mut impl Board, config: &Config, size: usize) -> Option<()> {
+    put(board, b"OK");
+    if config.chunked_version >= 2 {
+        put(board, &[0, 0, config.chunked_version]);
+    }
+    put(board, &config.receive_buffer.to_le_bytes());
+    let mut version = 1;
+    if config.chunked_version >= 2 {
+        if board.getc()? != SYN {
+            return None;
+        }
+        version = board.getc()?;
+    }
+    let buffer = config.receive_buffer as usize;
+    if version == 1 {
+        receive_chunks(board, buffer, size)
+    } else {
+        receive_frames(board, buffer.saturating_sub(FRAME_OVERHEAD), size)
+    }
+}
+
+/// The version 1 of the chunked protocol: the chunks of `buffer` bytes, each
+/// one after an `STX` and acknowledged once received.
+fn receive_chunks(board: &mut impl Board, buffer: usize, size: usize) -> Option<()> {
+    let mut offset = 0;
+    while offset < size {
+        match board.getc()? {
+            STX => {}
+            CAN => return None,
+            _ => continue,
+        }
+        let end = size.min(offset + buffer);
+        while offset < end {
+            let byte = board.getc()?;
+            board.memory(size)[offset] = byte;
+            offset += 1;
+        }
+        board.putc(ACK);
+    }
+    loop {
+        match board.getc()? {
+            command @ EOT | command @ PERSIST => return finish(board, command),
+            CAN => return None,
+            _ => {}
+        }
+    }
+}
+
+/// The version 2 of the chunked protocol: the frames of up to `payload` bytes,
+/// acknowledged or asked for again by their sequence number, those received
+/// twice being acknowledged again.
+fn receive_frames(board: &mut impl Board, payload: usize, size: usize) -> Option<()> {
+    let mut offset = 0;
+    let mut next: u8 = 0;
+    loop {
+        match board.getc()? {
+            command @ EOT | command @ PERSIST if offset == size => return finish(board, command),
+            STX => {}
+            CAN => return None,
+            // What is left of a frame cut short.
+            _ => continue,
+        }
+        let sequence = board.getc()?;
+        let length = [board.getc()?, board.getc()?];
+        let len = u16::from_le_bytes(length) as usize;
+        if len == 0 || len > payload {
+            put(board, &[NAK, sequence]);
+            continue;
+        }
+        let mut crc = crc32_update(0xffff_ffff, sequence);
+        crc = crc32_update(crc32_update(crc, length[0]), length[1]);
+        // The next frame is received in place, the others only checked.
+        let fresh = sequence == next && offset + len <= size;
+        for i in offset..offset + len {
+            let byte = board.getc()?;
+            crc = crc32_update(crc, byte);
+            if fresh {
+                board.memory(size)[i] = byte;
+            }
+        }
+        if !crc != get_u32(board)? {
+            put(board, &[NAK, sequence]);
+        } else if fresh {
+            offset += len;
+            next = next.wrapping_add(1);
+            put(board, &[ACK, sequence]);
+        } else if sequence == next.wrapping_sub(1) {
+            put(board, &[ACK, sequence]);
+        }
+    }
+}
+
+/// Follow the `command` ending a chunked transfer: boot the image, or tell
+/// persisting it is not supported.
+fn finish(board: &mut impl Board, command: u8) -> Option<()> {
+    if command == EOT {
+        return Some(());
+    }
+    board.putc(NAK);
+    put(board, b"persisting the image is not supported by the stub\n");
+    None
+}
+
+fn put(board: &mut impl Board, data: &[u8]) {
+    for byte in data {
+        board.putc(*byte);
+    }
+}
+
+/// 4 bytes, in little endian.
+fn get_u32(board: &mut impl Board) -> Option<u32> {
+    Some(u32::from_le_bytes([
+        board.getc()?,
+        board.getc()?,
+        board.getc()?,
+        board.getc()?,
+    ]))
+}
+
+/// The CRC-32 (IEEE 802.3) `crc` updated with the `byte`.
+fn crc32_update(crc: u32, byte: u8) -> u32 {
+    let mut crc = crc ^ byte as u32;
+    for _ in 0..8 {
+        crc = if crc & 1 != 0 {
+            (crc >> 1) ^ 0xedb8_8320
+        } else {
+            crc >> 1
+        };
+    }
+    crc
+}
@@ -0,0 +1,304 @@
+/*
+ * Minimal `bootcom` receiver stub for {{TARGET}}.
+ *
+ * Generated by `bootcom stub` (protocol: {{PROTOCOL}},
+ * trigger: {{TRIGGER_HEX}}, CRC-32 echo: {{CRC}}). It only depends on two UART
+ * primitives which must be provided by the platform code:
+ *
+ *     int uart_getc(void);
+ *     void uart_putc(unsigned char c);
+ *
+ * uart_getc returns the next byte received, or a negative value once nothing
+ * was received for a while (a second or so): the image is then requested
+ * again, and an interrupted `raspbootin` upload resumed. It may also wait for
+ * the next byte forever.
+ */
+#include <stdint.h>
+
+/* Address at which the kernel image is loaded and started. */
+#define LOAD_ADDRESS ((uintptr_t){{LOAD_ADDRESS}})
+
+/* Whether the CRC-32 of the received image is sent back to `bootcom`. */
+#define SEND_CRC {{CRC_C}}
+
+/* The revision of the `raspbootin` protocol, reported to `bootcom`. */
+#define PROTOCOL_REVISION {{PROTOCOL_REVISION}}
+
+/*
+ * The highest version of the chunked protocol spoken, 0 to speak the
+ * `raspbootin` protocol instead.
+ */
+#define CHUNKED_VERSION {{CHUNKED_VERSION}}
+
+/*
+ * The receive buffer advertised with the chunked protocol, which the chunks
+ * (or the frames of the version 2) fit in.
+ */
+#define RECEIVE_BUFFER {{RECEIVE_BUFFER}}
+
+/* Bytes sent to `bootcom` to request the kernel image. */
+static const uint8_t trigger[{{TRIGGER_LEN}}] = {{{TRIGGER_BYTES}}};
+
+extern int uart_getc(void);
+extern void uart_putc(unsigned char c);
+
+/* ========================================================================== */
+/* The boot protocols, the same on every platform                            */
+/* ========================================================================== */
+
+#define STX 0x02
+#define EOT 0x04
+#define ACK 0x06
+#define NAK 0x15
+#define SYN 0x16
+#define CAN 0x18
+#define PERSIST 'P'
+
+/*
+ * The bytes of a frame of the version 2 of the chunked protocol around its
+ * chunk: STX, the sequence number, the length and the CRC-32.
+ */
+#define FRAME_OVERHEAD 8
+
+static volatile uint8_t *const kernel = (volatile uint8_t *)LOAD_ADDRESS;
+
+/* 4 bytes, in little endian, -1 once nothing was received for a while. */
+static int get_u32(uint32_t *value)
+{
+    int i, c;
+
+    *value = 0;
+    for (i = 0; i < 4; i++) {
+        if ((c = uart_getc()) < 0)
+            return -1;
+        *value |= (uint32_t)c << (8 * i);
+    }
+    return 0;
+}
+
+static inline void put_u32(uint32_t value)
+{
+    int i;
+
+    for (i = 0; i < 4; i++)
+        uart_putc((value >> (8 * i)) & 0xff);
+}
+
+/* The CRC-32 (IEEE 802.3) `crc` updated with the `byte`. */
+static inline uint32_t crc32_update(uint32_t crc, uint8_t byte)
+{
+    int bit;
+
+    crc ^= byte;
+    for (bit = 0; bit < 8; bit++)
+        crc = (crc & 1) ? (crc >> 1) ^ 0xedb88320 : crc >> 1;
+    return crc;
+}
+
+#if CHUNKED_VERSION == 0
+/* How much of the image an interrupted upload left in memory. */
+static uint32_t held;
+
+/*
+ * The `raspbootin` protocol: the revisions, `bootcom` warning when they
+ * differ, then the image from where an interrupted upload stopped.
+ */
+static int receive_image(uint32_t size)
+{
+    uint32_t from = 0;
+    int c;
+
+    uart_putc('O');
+    uart_putc('V');
+    uart_putc(PROTOCOL_REVISION);
+    if (uart_getc() < 0)
+        return -1;
+    if (held > 0) {
+        uart_putc('O');
+        uart_putc('R');
+        put_u32(held);
+        /* `bootcom` sends another image from the start. */
+        if (get_u32(&from) < 0 || from > size)
+            return -1;
+    } else {
+        uart_putc('O');
+        uart_putc('K');
+    }
+    for (held = from; held < size; held++) {
+        if ((c = uart_getc()) < 0)
+            return -1;
+        kernel[held] = c;
+    }
+    held = 0;
+    return 0;
+}
+#else
+/*
+ * Follow the `command` ending a chunked transfer: boot the image, or tell
+ * persisting it is not supported.
+ */
+static int finish(int command)
+{
+    const char *reason = "persisting the image is not supported by the stub\n";
+
+    if (command == EOT)
+        return 0;
+    uart_putc(NAK);
+    while (*reason)
+        uart_putc(*reason++);
+    return -1;
+}
+
+/*
+ * The version 1 of the chunked protocol: the chunks of `buffer` bytes, each
+ * one after an STX and acknowledged once received.
+ */
+static int receive_chunks(uint32_t buffer, uint32_t size)
+{
+    uint32_t offset = 0, end;
+    int c;
+
+    while (offset < size) {
+        if ((c = uart_getc()) < 0 || c == CAN)
+            return -1;
+        if (c != STX)
+            continue;
+        end = size - offset < buffer ? size : offset + buffer;
+        for (; offset < end; offset++) {
+            if ((c = uart_getc()) < 0)
+                return -1;
+            kernel[offset] = c;
+        }
+        uart_putc(ACK);
+    }
+    for (;;) {
+        if ((c = uart_getc()) < 0 || c == CAN)
+            return -1;
+        if (c == EOT || c == PERSIST)
+            return finish(c);
+    }
+}
+
+/*
+ * The version 2 of the chunked protocol: the frames of up to `payload` bytes,
+ * acknowledged or asked for again by their sequence number, those received
+ * twice being acknowledged again.
+ */
+static int receive_frames(uint32_t payload, uint32_t size)
+{
+    uint32_t offset = 0, len, i, crc, expected;
+    uint8_t next = 0, sequence, length[2];
+    int c, fresh;
+
+    for (;;) {
+        if ((c = uart_getc()) < 0 || c == CAN)
+            return -1;
+        if ((c == EOT || c == PERSIST) && offset == size)
+            return finish(c);
+        /* Otherwise, what is left of a frame cut short. */
+        if (c != STX)
+            continue;
+        if ((c = uart_getc()) < 0)
+            return -1;
+        sequence = c;
+        for (i = 0; i < 2; i++) {
+            if ((c = uart_getc()) < 0)
+                return -1;
+            length[i] = c;
+        }
+        len = length[0] | (uint32_t)length[1] << 8;
+        if (len == 0 || len > payload) {
+            uart_putc(NAK);
+            uart_putc(sequence);
+            continue;
+        }
+        crc = crc32_update(0xffffffff, sequence);
+        crc = crc32_update(crc32_update(crc, length[0]), length[1]);
+        /* The next frame is received in place, the others only checked. */
+        fresh = sequence == next && len <= size - offset;
+        for (i = 0; i < len; i++) {
+            if ((c = uart_getc()) < 0)
+                return -1;
+            crc = crc32_update(crc, c);
+            if (fresh)
+                kernel[offset + i] = c;
+        }
+        if (get_u32(&expected) < 0)
+            return -1;
+        if (~crc != expected) {
+            uart_putc(NAK);
+            uart_putc(sequence);
+        } else if (fresh) {
+            offset += len;
+            next++;
+            uart_putc(ACK);
+            uart_putc(sequence);
+        } else if (sequence == (uint8_t)(next - 1)) {
+            uart_putc(ACK);
+            uart_putc(sequence);
+        }
+    }
+}
+
+/* The chunked protocol, in the version `bootcom` picks. */
+static int receive_image(uint32_t size)
+{
+    int version = 1;
+
+    uart_putc('O');
+    uart_putc('K');
+#if CHUNKED_VERSION >= 2
+    uart_putc(0);
+    uart_putc(0);
+    uart_putc(CHUNKED_VERSION);
+#endif
+    uart_putc(RECEIVE_BUFFER & 0xff);
+    uart_putc(RECEIVE_BUFFER >> 8);
+#if CHUNKED_VERSION >= 2
+    if (uart_getc() != SYN || (version = uart_getc()) < 0)
+        return -1;
+#endif
+    if (version == 1)
+        return receive_chunks(RECEIVE_BUFFER, size);
+    return receive_frames(RECEIVE_BUFFER - FRAME_OVERHEAD, size);
+}
+#endif
+
+/*
+ * Request the image and receive it, with its `size`, returning -1 when the
+ * transfer was given up and the image is to be requested again.
+ */
+static int receive(uint32_t *size)
+{
+    uint32_t i;
+
+    for (i = 0; i < sizeof(trigger); i++)
+        uart_putc(trigger[i]);
+    if (get_u32(size) < 0 || receive_image(*size) < 0)
+        return -1;
+#if SEND_CRC
+    {
+        uint32_t crc = 0xffffffff;
+
+        for (i = 0; i < *size; i++)
+            crc = crc32_update(crc, kernel[i]);
+        put_u32(~crc);
+    }
+#endif
+    return 0;
+}
+
+/*
+ * Request the kernel image from `bootcom`, receive it at LOAD_ADDRESS and jump
+ * to it. The memory starting at LOAD_ADDRESS must not overlap with the stub.
+ */
+void __attribute__((noreturn)) receive_and_boot(void)
+{
+    uint32_t size;
+
+    while (receive(&size) < 0)
+        ;
+    ((void (*)(void))LOAD_ADDRESS)();
+    for (;;)
+        ;
+}
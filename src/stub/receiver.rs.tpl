@@ -0,0 +1,67 @@
+//! Minimal `bootcom` receiver stub for {{TARGET}}.
+//!
+//! Generated by `bootcom stub` (protocol: {{PROTOCOL}},
+//! trigger: {{TRIGGER_HEX}}, CRC-32 echo: {{CRC}}). It only depends on two UART
+//! primitives which must be provided by the platform code:
+//!
+//! ```ignore
+//! #[no_mangle] extern "C" fn uart_getc() -> i32;
+//! #[no_mangle] extern "C" fn uart_putc(c: u8);
+//! ```
+//!
+//! `uart_getc` returns the next byte received, or a negative value once nothing
+//! was received for a while (a second or so): the image is then requested
+//! again, and an interrupted `raspbootin` upload resumed. It may also wait for
+//! the next byte forever.
+#![allow(dead_code)]
+
+/// Address at which the kernel image is loaded and started.
+pub const LOAD_ADDRESS: usize = {{LOAD_ADDRESS}};
+
+const CONFIG: Config = Config {
+    trigger: &[{{TRIGGER_BYTES}}],
+    protocol_revision: {{PROTOCOL_REVISION}},
+    chunked_version: {{CHUNKED_VERSION}},
+    receive_buffer: {{RECEIVE_BUFFER}},
+    send_crc: {{CRC}},
+};
+
+extern "C" {
+    fn uart_getc() -> i32;
+    fn uart_putc(c: u8);
+}
+
+/// The UART, and the memory at [`LOAD_ADDRESS`].
+struct Platform;
+impl Board for Platform {
+    fn getc(&mut self) -> Option<u8> {
+        match unsafe { uart_getc() } {
+            c if c < 0 => None,
+            c => Some(c as u8),
+        }
+    }
+
+    fn putc(&mut self, byte: u8) {
+        unsafe { uart_putc(byte) }
+    }
+
+    fn memory(&mut self, size: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(LOAD_ADDRESS as *mut u8, size) }
+    }
+}
+
+/// Request the kernel image from `bootcom`, receive it at [`LOAD_ADDRESS`] and
+/// jump to it.
+///
+/// # Safety
+///
+/// Overwrites the memory starting at [`LOAD_ADDRESS`], which must not overlap
+/// with the running stub.
+pub unsafe fn receive_and_boot() -> ! {
+    let mut receiver = Receiver::new(CONFIG);
+    while receiver.receive(&mut Platform).is_none() {}
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+    let entry: extern "C" fn() -> ! = core::mem::transmute(LOAD_ADDRESS);
+    entry()
+}
+
@@ -0,0 +1,161 @@
+//! The link to the device, over which the boot protocol is spoken.
+//!
+//! The terminal mode and the transfers of the kernel image only read and write
+//! bytes, so they work over any [`Transport`], not just the serial port opened
+//! for the settings. What only a serial port has, the modem lines and the line
+//! parameters, is reached through [`Transport::serial_port`] and left alone
+//! on the other transports: a TCP connection to a serial server, or an
+//! in-memory device for the tests.
+//!
+//! **Example**
+//! ```
+//! use std::io;
+//! use bootcom::transport::Transport;
+//!
+//! /// Announce the `size` of an image, whatever the link.
+//! fn announce(link: &mut impl Transport, size: u32) -> io::Result<()> {
+//!     link.clear_input()?;
+//!     link.write_all(&size.to_le_bytes())
+//! }
+//! ```
+
+use std::io::{self, Read, Write};
+
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::settings::Settings;
+use crate::utils::open_and_setup_port;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// A link to the device, reading and writing bytes.
+pub trait Transport: Read + Write + Send {
+    /// The number of bytes received and not read yet.
+    fn bytes_to_read(&self) -> io::Result<u32>;
+
+    /// Discard the bytes received and not read yet.
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// What the link is, e.g. `/dev/ttyUSB0 @ 115200`, for the logs and the
+    /// history.
+    fn describe(&self) -> String;
+
+    /// The serial port carrying the link, for the modem lines and the line
+    /// parameters. There is none by default.
+    fn serial_port(&mut self) -> Option<&mut Box<dyn SerialPort>> {
+        None
+    }
+
+    /// Open the link to the device of the `settings`, e.g. again after the
+    /// connection was lost. Not supported by default.
+    fn open(_settings: &Settings) -> io::Result<Self>
+    where
+        Self: Sized,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the link to the device can't be opened again",
+        ))
+    }
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(SerialPort::bytes_to_read(self.as_ref())?)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} @ {}",
+            self.name().unwrap_or_else(|| "-".into()),
+            self.baud_rate().unwrap_or_default()
+        )
+    }
+
+    fn serial_port(&mut self) -> Option<&mut Box<dyn SerialPort>> {
+        Some(self)
+    }
+
+    fn open(settings: &Settings) -> io::Result<Self> {
+        Ok(open_and_setup_port(settings)?)
+    }
+}
+
+/// The device behind a [`MemoryTransport`], given every write and returning
+/// the bytes it sends back.
+#[cfg(any(test, feature = "testing"))]
+type Device = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+/// An in-memory device, answering what is written to it.
+#[cfg(any(test, feature = "testing"))]
+pub struct MemoryTransport {
+    device: Device,
+    input: std::collections::VecDeque<u8>,
+    output: Vec<u8>,
+}
+#[cfg(any(test, feature = "testing"))]
+impl MemoryTransport {
+    /// A transport to the `device`, given every write and returning the bytes
+    /// it sends back.
+    pub fn new(device: impl FnMut(&[u8]) -> Vec<u8> + Send + 'static) -> Self {
+        MemoryTransport {
+            device: Box::new(device),
+            input: Default::default(),
+            output: vec![],
+        }
+    }
+
+    /// Have the device send the `data`, on top of its answers.
+    pub fn queue_input(&mut self, data: &[u8]) {
+        self.input.extend(data);
+    }
+
+    /// Everything written to the device so far.
+    pub fn written(&self) -> &[u8] {
+        &self.output
+    }
+}
+#[cfg(any(test, feature = "testing"))]
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.input.len());
+        for (byte, received) in buf.iter_mut().zip(self.input.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+}
+#[cfg(any(test, feature = "testing"))]
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        let answer = (self.device)(buf);
+        self.input.extend(answer);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+#[cfg(any(test, feature = "testing"))]
+impl Transport for MemoryTransport {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(self.input.len() as u32)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.input.clear();
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        "memory".into()
+    }
+}
@@ -0,0 +1,350 @@
+//! Local usage statistics, kept for `bootcom stats` once enabled in the
+//! configuration file (`stats = true` in the `[usage]` section, see
+//! [`config`](crate::config)).
+//!
+//! Each push of a kernel image, successful or not, is counted to the usage
+//! file, by default `bootcom/usage.toml` next to the resume file in the user
+//! state directory: in total, by board and by day, with the bytes sent and the
+//! time spent sending them. Nothing ever leaves the file. The board of a push
+//! is the one of the inventory on its port (see [`boards`](crate::boards)), or
+//! else its connection profile, or else its port. The days older than a year
+//! are dropped.
+//!
+//! **Example**
+//! ```
+//! use bootcom::usage;
+//!
+//! let usage = usage::parse(
+//!     "[total]\nboots = 2\nfailures = 1\nbytes = 8192\ntransfer_ms = 3000",
+//! )
+//! .unwrap();
+//! assert_eq!(usage.total.failures, 1);
+//! assert_eq!(
+//!     usage.total.average_transfer_time().unwrap().as_millis(),
+//!     1500
+//! );
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use toml::{value::Table, Value};
+
+use crate::progress::TransferReport;
+use crate::settings::Settings;
+use crate::utils::{HumanDate, HumanDuration, HumanSize};
+use crate::{boards, resume};
+
+/// How many days are kept in the usage file.
+const KEPT_DAYS: u64 = 366;
+
+// =============================================================================
+// Public Interface
+// =============================================================================
+
+/// The usage statistics of the usage file.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Usage {
+    /// When the first push was counted, if any was.
+    pub since: Option<SystemTime>,
+    /// All the pushes.
+    pub total: Tally,
+    /// The pushes by board.
+    pub boards: BTreeMap<String, Tally>,
+    /// The pushes by day, `YYYY-MM-DD` in UTC.
+    pub days: BTreeMap<String, Tally>,
+}
+
+/// The pushes of kernel images counted.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Tally {
+    /// The number of kernel images pushed.
+    pub boots: u64,
+    /// The number of pushes which failed.
+    pub failures: u64,
+    /// The size of the kernel images pushed.
+    pub bytes: u64,
+    /// The time spent sending the kernel images pushed.
+    pub transfer_time: Duration,
+}
+
+impl Tally {
+    /// The average time spent sending a kernel image, if any was pushed.
+    pub fn average_transfer_time(&self) -> Option<Duration> {
+        let millis = self.transfer_time.as_millis() as u64;
+        (self.boots > 0).then(|| Duration::from_millis(millis / self.boots))
+    }
+
+    /// Add the pushes of `other` to these ones.
+    pub fn add(&mut self, other: &Tally) {
+        self.boots += other.boots;
+        self.failures += other.failures;
+        self.bytes += other.bytes;
+        self.transfer_time += other.transfer_time;
+    }
+
+    /// Count a push, summarized by its `report` when it succeeded.
+    fn count(&mut self, report: Option<&TransferReport>) {
+        match report {
+            Some(report) => self.add(&Tally {
+                boots: 1,
+                failures: 0,
+                bytes: report.bytes,
+                transfer_time: report.duration,
+            }),
+            None => self.failures += 1,
+        }
+    }
+}
+
+impl fmt::Display for Tally {
+    /// The pushes summed up, e.g. `12 pushed, 1 failed, 35.2 MiB sent, 1.4s
+    /// per transfer`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.boots == 0 && self.failures == 0 {
+            return write!(f, "none");
+        }
+        write!(f, "{} pushed", self.boots)?;
+        if self.failures > 0 {
+            write!(f, ", {} failed", self.failures)?;
+        }
+        if let Some(average) = self.average_transfer_time() {
+            write!(
+                f,
+                ", {} sent, {} per transfer",
+                HumanSize(self.bytes),
+                HumanDuration(average)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Usage {
+    /// The day the first push was counted, `YYYY-MM-DD` in UTC, if any was.
+    pub fn first_day(&self) -> Option<String> {
+        self.since.map(day_of)
+    }
+
+    /// The pushes of the `days` days up to the one of `now`, oldest first, the
+    /// days without any included.
+    pub fn last_days(&self, now: SystemTime, days: u64) -> Vec<(String, Tally)> {
+        (0..days)
+            .rev()
+            .map(|ago| {
+                let day = day_of(now - Duration::from_secs(ago * 86_400));
+                let tally = self.days.get(&day).copied().unwrap_or_default();
+                (day, tally)
+            })
+            .collect()
+    }
+
+    /// The usage statistics, as saved to the usage file.
+    pub fn to_toml(&self) -> String {
+        let mut root = Table::new();
+        if let Some(since) = self.since {
+            let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+            root.insert("since".into(), Value::Integer(since.as_secs() as i64));
+        }
+        root.insert("total".into(), Value::Table(tally_table(&self.total)));
+        for (name, tallies) in [("boards", &self.boards), ("days", &self.days)] {
+            let tables = tallies
+                .iter()
+                .map(|(key, tally)| (key.clone(), Value::Table(tally_table(tally))))
+                .collect();
+            root.insert(name.into(), Value::Table(tables));
+        }
+        format!(
+            "# Saved by bootcom for `bootcom stats`, never sent anywhere.\n{}",
+            Value::Table(root)
+        )
+    }
+
+    /// Count a push to the `board` at `now`, summarized by its `report` when
+    /// it succeeded, dropping the days which are not kept anymore.
+    fn count(&mut self, board: &str, report: Option<&TransferReport>, now: SystemTime) {
+        self.since.get_or_insert(now);
+        self.total.count(report);
+        self.boards.entry(board.into()).or_default().count(report);
+        self.days.entry(day_of(now)).or_default().count(report);
+        let oldest = day_of(now - Duration::from_secs((KEPT_DAYS - 1) * 86_400));
+        self.days = self.days.split_off(&oldest);
+    }
+}
+
+/// The path of the default usage file, if the user state directory can be
+/// found.
+pub fn default_path() -> Option<PathBuf> {
+    resume::default_path().map(|path| path.with_file_name("usage.toml"))
+}
+
+/// Read and parse the usage file at `path`.
+pub fn load(path: &Path) -> Result<Usage, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Parse the `text` of a usage file.
+pub fn parse(text: &str) -> Result<Usage, String> {
+    let root: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let since = match root.get("since") {
+        None => None,
+        Some(Value::Integer(at)) if *at >= 0 => Some(UNIX_EPOCH + Duration::from_secs(*at as u64)),
+        Some(_) => return Err("`since` needs to be a time in seconds since the epoch".into()),
+    };
+    let total = match root.get("total") {
+        None => Tally::default(),
+        Some(Value::Table(table)) => tally(table, "total")?,
+        Some(_) => return Err("`total` needs to be a section".into()),
+    };
+    let tallies = |name: &str| match root.get(name) {
+        None => Ok(BTreeMap::new()),
+        Some(Value::Table(tables)) => tables
+            .iter()
+            .map(|(key, value)| {
+                let section = format!("{}.{}", name, key);
+                match value {
+                    Value::Table(table) => Ok((key.clone(), tally(table, &section)?)),
+                    _ => Err(format!("`{}` needs to be a section", section)),
+                }
+            })
+            .collect(),
+        Some(_) => Err(format!("`{}` needs to be a section", name)),
+    };
+    Ok(Usage {
+        since,
+        total,
+        boards: tallies("boards")?,
+        days: tallies("days")?,
+    })
+}
+
+// =============================================================================
+// Crate-Public Interface
+// =============================================================================
+
+/// Count a push to the usage file of the `settings`, if any, summarized by its
+/// `report` when it succeeded. Failures are only logged, the statistics are
+/// informative.
+pub(crate) fn record(settings: &Settings, report: Option<&TransferReport>) {
+    let file = match &settings.usage_file {
+        Some(file) => Path::new(file),
+        None => return,
+    };
+    let board = boards::board_of(settings)
+        .map(|board| board.name.clone())
+        .or_else(|| settings.profile.clone())
+        .or_else(|| settings.path.clone())
+        .unwrap_or_else(|| "-".into());
+    let mut usage = load(file).unwrap_or_default();
+    usage.count(&board, report, SystemTime::now());
+    let saved = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| fs::write(file, usage.to_toml()));
+    if let Err(e) = saved {
+        info!("could not count the push to {}: {}", file.display(), e);
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The day of the `time`, `YYYY-MM-DD` in UTC.
+fn day_of(time: SystemTime) -> String {
+    HumanDate(time).to_string()[..10].into()
+}
+
+fn tally(table: &Table, section: &str) -> Result<Tally, String> {
+    let count = |key: &str| match table.get(key) {
+        None => Ok(0),
+        Some(Value::Integer(count)) => u64::try_from(*count)
+            .map_err(|_| format!("`{}.{}` needs to be a positive number", section, key)),
+        Some(_) => Err(format!("`{}.{}` needs to be a number", section, key)),
+    };
+    Ok(Tally {
+        boots: count("boots")?,
+        failures: count("failures")?,
+        bytes: count("bytes")?,
+        transfer_time: Duration::from_millis(count("transfer_ms")?),
+    })
+}
+
+fn tally_table(tally: &Tally) -> Table {
+    let mut table = Table::new();
+    let mut insert = |key: &str, count: u64| {
+        table.insert(key.into(), Value::Integer(count as i64));
+    };
+    insert("boots", tally.boots);
+    insert("failures", tally.failures);
+    insert("bytes", tally.bytes);
+    insert("transfer_ms", tally.transfer_time.as_millis() as u64);
+    table
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn pushes_counted() {
+    use crate::settings::TransferProtocol;
+
+    let report = TransferReport {
+        image: "kernel8.img".into(),
+        protocol: TransferProtocol::Raspbootin,
+        bytes: 4096,
+        duration: Duration::from_millis(900),
+        retries: 0,
+        crc: 0,
+        resumed_from: 0,
+        output: vec![],
+    };
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut usage = Usage::default();
+    usage.count(
+        "rpi4-a",
+        Some(&report),
+        now - Duration::from_secs(400 * 86_400),
+    );
+    usage.count("rpi4-a", Some(&report), now - Duration::from_secs(86_400));
+    usage.count("rpi4-a", None, now);
+    usage.count("/dev/ttyUSB0", Some(&report), now);
+
+    assert_eq!(usage.total.boots, 3);
+    assert_eq!(usage.total.failures, 1);
+    assert_eq!(
+        usage.total.average_transfer_time(),
+        Some(Duration::from_millis(900))
+    );
+    assert_eq!(usage.boards["rpi4-a"].boots, 2);
+    assert_eq!(
+        usage.boards["rpi4-a"].to_string(),
+        "2 pushed, 1 failed, 8.0 KiB sent, 900ms per transfer"
+    );
+    assert_eq!(usage.boards["/dev/ttyUSB0"].bytes, 4096);
+    // The day over a year ago was dropped.
+    assert_eq!(usage.days.len(), 2);
+    assert_eq!(usage.first_day().unwrap(), "2022-10-10");
+    assert_eq!(
+        usage
+            .last_days(now, 3)
+            .iter()
+            .map(|(day, tally)| (day.as_str(), tally.boots))
+            .collect::<Vec<_>>(),
+        vec![("2023-11-12", 0), ("2023-11-13", 1), ("2023-11-14", 1)]
+    );
+
+    assert_eq!(parse(&usage.to_toml()).unwrap(), usage);
+    assert!(parse("[total]\nboots = -1")
+        .unwrap_err()
+        .contains("total.boots"));
+}
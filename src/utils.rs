@@ -1,9 +1,104 @@
 //! Helper functions to deal with serial ports.
 
-mod kernel;
+mod asciicast;
+mod attempts;
+mod banner;
+mod boot_check;
+mod busy;
+mod capture;
+mod chaos;
+pub(crate) mod chunked;
+mod config_reload;
+pub(crate) mod crc;
+pub(crate) mod dump;
+pub(crate) mod encryption;
+mod health;
+mod history;
+pub(crate) mod host_services;
+mod human;
+mod image;
+mod image_watch;
+mod instruments;
+mod io_errors;
+pub(crate) mod kernel;
 mod keyboard;
+mod line_check;
+mod line_settings;
+#[cfg(unix)]
+mod mdns;
+mod modem_lines;
+pub(crate) mod modem_manager;
+mod noise;
+mod notes;
+mod outputs;
+mod paste;
 mod ports;
+mod quirks;
+#[cfg(unix)]
+mod remote;
+pub(crate) mod render;
+pub(crate) mod rom_loaders;
+mod script;
+mod session_log;
+mod session_report;
+mod stopwatch;
+mod strapping;
+pub(crate) mod streams;
+mod systemd;
+mod terminal;
+pub(crate) mod time_sync;
+pub(crate) mod trailer;
+mod triggers;
+mod uboot;
+#[cfg(windows)]
+mod windows_ports;
+pub(crate) mod xmodem;
+pub(crate) mod xonxoff;
 
-pub(crate) use kernel::send_kernel;
+pub(crate) use asciicast::{json_escape, AsciicastRecorder};
+pub(crate) use attempts::{Attempts, RetriesExhausted};
+pub(crate) use banner::{banner_entries, banner_json, banner_text, line_format, show_banner};
+pub(crate) use boot_check::{BootCheck, Stage};
+pub(crate) use busy::{is_port_busy, port_holders, prompt_busy_retry};
+pub(crate) use capture::BlobCapture;
+#[cfg(test)]
+pub(crate) use chaos::SplitMix64;
+pub(crate) use config_reload::{apply_config, ConfigReload};
+pub(crate) use crc::Crc32;
+pub(crate) use dump::{receive_dump, DUMP_TRIGGER};
+pub(crate) use health::{shell, state_name, Health};
+pub(crate) use history::History;
+pub(crate) use host_services::{HostServices, SERVICE_TRIGGER};
+pub(crate) use human::{HumanDate, HumanDuration, HumanRate, HumanSize};
+pub(crate) use image::{ImageChanged, KernelImage};
+pub(crate) use image_watch::ImageWatch;
+pub(crate) use instruments::Instruments;
+pub(crate) use io_errors::is_transient;
+pub(crate) use kernel::{send_kernel, Interrupted, SendError};
 pub(crate) use keyboard::*;
-pub(crate) use ports::{open_and_setup_port, select_port, wait_for_port};
+pub(crate) use line_check::{noise_hint, static_warnings, LineCheck};
+pub(crate) use line_settings::{describe_changes, prompt_line_settings, LineChange};
+pub(crate) use modem_lines::ModemLines;
+pub(crate) use noise::NoiseDetector;
+pub(crate) use notes::{note_line, prompt_note};
+pub(crate) use outputs::Outputs;
+pub(crate) use paste::write_paced;
+pub(crate) use ports::{
+    configure_port, is_port_present, open_and_setup_port, scan_baud_rate, select_port,
+    wait_for_port,
+};
+pub(crate) use quirks::map_output;
+pub(crate) use script::{Playback, ScriptPlayer};
+pub(crate) use session_log::SessionLogger;
+pub(crate) use session_report::SessionReport;
+pub(crate) use stopwatch::Stopwatch;
+pub(crate) use strapping::{apply_straps, Straps};
+pub(crate) use streams::StreamDemux;
+pub(crate) use systemd::{serve_activated_sockets, Notifier};
+pub(crate) use terminal::{
+    bracketed_paste, follow_bar, hide_cursor, prepare_terminal, raw_mode, resized, set_status,
+};
+pub(crate) use time_sync::{send_time, TIME_TRIGGER};
+pub(crate) use triggers::TriggerMatcher;
+pub(crate) use uboot::{Handoff, UbootHandoff};
+pub(crate) use xonxoff::SoftFlow;
@@ -0,0 +1,175 @@
+//! Recording of the console session in the
+//! [asciicast v2](https://docs.asciinema.org/manual/asciicast/v2/) format, so
+//! that boot logs and demos can be replayed with `asciinema play` or shared.
+//!
+//! The recording starts with a header line describing the terminal, followed by
+//! one line per output event: `[<seconds since start>, "o", "<data>"]`, and
+//! the notes typed during the session as markers: `[<seconds since start>,
+//! "m", "<note>"]`.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::outputs::OutputSink;
+
+/// Writes the console output as asciicast v2 events.
+pub(crate) struct AsciicastRecorder<W: Write + Send> {
+    writer: W,
+    started: Instant,
+    /// Trailing bytes of an incomplete UTF-8 sequence, waiting for the rest of
+    /// the sequence in the next write.
+    pending: Vec<u8>,
+    /// Whether the last byte written was a carriage return.
+    after_cr: bool,
+}
+impl AsciicastRecorder<BufWriter<File>> {
+    /// Create the recording file at `path`, with the size of the current
+    /// terminal in the header.
+    pub(crate) fn create(path: &str) -> io::Result<Self> {
+        let (width, height) = super::terminal::size();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        AsciicastRecorder::new(
+            BufWriter::new(File::create(path)?),
+            width,
+            height,
+            timestamp,
+        )
+    }
+}
+impl<W: Write + Send> AsciicastRecorder<W> {
+    pub(crate) fn new(mut writer: W, width: u16, height: u16, timestamp: u64) -> io::Result<Self> {
+        writeln!(
+            writer,
+            "{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}, \"title\": \"bootcom\"}}",
+            width, height, timestamp
+        )?;
+        writer.flush()?;
+        Ok(AsciicastRecorder {
+            writer,
+            started: Instant::now(),
+            pending: vec![],
+            after_cr: false,
+        })
+    }
+
+    /// Turn `data` into the text of an output event. Like a terminal would,
+    /// lone line feeds are rendered as carriage return + line feed and invalid
+    /// UTF-8 is replaced.
+    fn text(&mut self, data: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.pending);
+        for &b in data {
+            if b == b'\n' && !self.after_cr {
+                bytes.push(b'\r');
+            }
+            self.after_cr = b == b'\r';
+            bytes.push(b);
+        }
+
+        let mut text = String::new();
+        let mut rest = &bytes[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        None => {
+                            // Incomplete sequence at the end, wait for more.
+                            self.pending = after.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+impl<W: Write + Send> OutputSink for AsciicastRecorder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let text = self.text(data);
+        if text.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            self.writer,
+            "[{:.6}, \"o\", \"{}\"]",
+            self.started.elapsed().as_secs_f64(),
+            json_escape(&text)
+        )?;
+        self.writer.flush()
+    }
+
+    fn mark(&mut self, label: &str) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "[{:.6}, \"m\", \"{}\"]",
+            self.started.elapsed().as_secs_f64(),
+            json_escape(label)
+        )?;
+        self.writer.flush()
+    }
+}
+
+/// Escape `text` to be used in a JSON string.
+pub(crate) fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn header_and_events() {
+    let mut recorder = AsciicastRecorder::new(vec![], 100, 30, 1_600_000_000).unwrap();
+    recorder.write(b"Booting \"kernel\"\n").unwrap();
+    recorder.write(b"\x1b[32mOK\x1b[0m\r\n").unwrap();
+    recorder.mark("no \"login\"").unwrap();
+
+    let recording = String::from_utf8(recorder.writer).unwrap();
+    let lines: Vec<&str> = recording.lines().collect();
+    assert_eq!(
+        lines[0],
+        "{\"version\": 2, \"width\": 100, \"height\": 30, \"timestamp\": 1600000000, \"title\": \"bootcom\"}"
+    );
+    assert!(lines[1].ends_with(", \"o\", \"Booting \\\"kernel\\\"\\r\\n\"]"));
+    assert!(lines[2].ends_with(", \"o\", \"\\u001b[32mOK\\u001b[0m\\r\\n\"]"));
+    assert!(lines[3].ends_with(", \"m\", \"no \\\"login\\\"\"]"));
+}
+
+#[test]
+fn utf8_split_across_writes() {
+    let mut recorder = AsciicastRecorder::new(vec![], 80, 24, 0).unwrap();
+    let degrees = "°".as_bytes();
+    assert_eq!(recorder.text(&[b'4', b'2', degrees[0]]), "42");
+    assert_eq!(recorder.text(&[degrees[1], b'C', 0xff]), "°C\u{fffd}");
+}
@@ -0,0 +1,99 @@
+//! Bounded retries of the operations `bootcom` repeats until they succeed:
+//! waiting for a serial port, selecting a port or a kernel image, sending the
+//! kernel image...
+//!
+//! Each of these loops counts its failed attempts with [`Attempts`], up to the
+//! limit set for it in the [`RetryPolicy`](crate::settings::RetryPolicy), and
+//! ends with a [`RetriesExhausted`] error once the limit is reached, so that an
+//! unattended `bootcom` eventually gives up instead of spinning forever.
+
+use std::{error::Error, fmt};
+
+/// The failed attempts of an operation, up to a limit (zero for no limit).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Attempts {
+    /// What is being attempted, for the messages.
+    operation: &'static str,
+    limit: usize,
+    failed: usize,
+}
+impl Attempts {
+    pub(crate) fn new(operation: &'static str, limit: usize) -> Self {
+        Attempts {
+            operation,
+            limit,
+            failed: 0,
+        }
+    }
+
+    /// Record a failed attempt, giving up when it was the last one allowed.
+    pub(crate) fn failed(&mut self) -> Result<(), RetriesExhausted> {
+        self.failed += 1;
+        if self.failed == self.limit {
+            Err(RetriesExhausted {
+                operation: self.operation,
+                attempts: self.failed,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start counting again, after a success.
+    pub(crate) fn reset(&mut self) {
+        self.failed = 0;
+    }
+}
+impl fmt::Display for Attempts {
+    /// The count of failed attempts, with the limit if there is one.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            0 => write!(f, "attempt {}", self.failed),
+            limit => write!(f, "attempt {} of {}", self.failed, limit),
+        }
+    }
+}
+
+/// The error ending a bounded retry loop which did not succeed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct RetriesExhausted {
+    /// What was being attempted.
+    pub operation: &'static str,
+    /// How many attempts failed.
+    pub attempts: usize,
+}
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gave up {} after {} failed attempts",
+            self.operation, self.attempts
+        )
+    }
+}
+impl Error for RetriesExhausted {}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn retries_are_bounded() {
+    let mut attempts = Attempts::new("selecting an image", 3);
+    assert!(attempts.failed().is_ok());
+    assert!(attempts.failed().is_ok());
+    assert_eq!(attempts.to_string(), "attempt 2 of 3");
+    assert_eq!(
+        attempts.failed().unwrap_err().to_string(),
+        "gave up selecting an image after 3 failed attempts"
+    );
+
+    attempts.reset();
+    assert!(attempts.failed().is_ok());
+
+    let mut attempts = Attempts::new("selecting an image", 0);
+    for _ in 0..100 {
+        assert!(attempts.failed().is_ok());
+    }
+    assert_eq!(attempts.to_string(), "attempt 100");
+}
@@ -0,0 +1,272 @@
+//! Summary of the effective configuration, shown when a boot session starts and
+//! whenever the settings change during the session (e.g. after a baud rate
+//! rescan), so that the settings `bootcom` was actually using can always be
+//! told from its output.
+//!
+//! The summary goes to the progress observer when there is one, which writes
+//! it as a JSON object in the JSON output mode, and is printed as a short
+//! block of text otherwise.
+
+use std::fmt::Write;
+
+use crate::messages::Catalog;
+use crate::settings::{DataBits, FlowControl, Parity, Quirk, Settings, StopBits, Trigger};
+
+use super::{encryption, json_escape};
+
+/// Show the summary of the effective `settings`.
+pub(crate) fn show_banner(settings: &Settings) {
+    let entries = banner_entries(settings);
+    match &settings.progress_observer {
+        Some(handle) => handle.observer().configuration(&entries),
+        None => println!("{}", banner_text(&settings.messages, &entries)),
+    }
+}
+
+/// The summary of the effective `settings`, as `(name, value)` pairs.
+pub(crate) fn banner_entries(settings: &Settings) -> Vec<(&'static str, String)> {
+    let mut transfer = vec![];
+    if settings.persist {
+        transfer.push("persist".to_string());
+    }
+    if settings.flush_window.as_millis() > 0 {
+        transfer.push(format!("flush window {:?}", settings.flush_window));
+    }
+    if !settings.expectations.is_empty() {
+        transfer.push(format!("{} boot stage(s)", settings.expectations.len()));
+    }
+    if let Some(trailer) = &settings.trailer {
+        transfer.push(format!("trailer {}", trailer));
+    }
+    if let Some(key) = &settings.signing_key {
+        transfer.push(format!("signed with the {}", key));
+    }
+    if let Some(key) = encryption::key_for(settings) {
+        match &key.profile {
+            Some(profile) => transfer.push(format!("encrypted with the key of `{}`", profile)),
+            None => transfer.push("encrypted".into()),
+        }
+    }
+    if let Some(chaos) = &settings.chaos {
+        transfer.push(format!("chaos: {}", chaos));
+    }
+    match settings.retry.send_attempts {
+        0 => transfer.push("unlimited attempts".into()),
+        attempts => transfer.push(format!("{} attempt(s)", attempts)),
+    }
+
+    let mut image = settings
+        .kernel_image
+        .clone()
+        .unwrap_or_else(|| "kernel8.img".into());
+    if settings.watch {
+        image.push_str(", watched");
+        if let Some(command) = &settings.reset_command {
+            let _ = write!(image, ", reset with `{}`", command);
+        }
+    }
+
+    let port = match (&settings.path, &settings.port_match) {
+        (path, Some(rule)) => format!(
+            "{} (matching {})",
+            path.as_deref().unwrap_or("(waited for)"),
+            rule
+        ),
+        (Some(path), None) => path.clone(),
+        (None, None) => "(selected)".into(),
+    };
+
+    let mut entries = vec![
+        ("port", port),
+        ("line", line_format(settings)),
+        ("image", image),
+        (
+            "triggers",
+            settings
+                .triggers
+                .iter()
+                .map(trigger)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        ("transfer", transfer.join(", ")),
+        (
+            "config",
+            settings
+                .config_file
+                .clone()
+                .unwrap_or_else(|| "(none)".into()),
+        ),
+    ];
+    if let Some(profile) = &settings.profile {
+        entries.push(("profile", profile.clone()));
+    }
+    if !settings.codecs.is_empty() {
+        let codecs = settings.codecs.iter().map(|codec| codec.name());
+        entries.push(("codecs", codecs.collect::<Vec<_>>().join(", ")));
+    }
+    if !settings.streams.is_empty() {
+        let streams = settings.streams.iter().map(|stream| match &stream.file {
+            Some(file) => format!("{} ({})", stream.name, file),
+            None => stream.name.clone(),
+        });
+        entries.push(("streams", streams.collect::<Vec<_>>().join(", ")));
+    }
+    if !settings.quirks.is_empty() {
+        let quirks = settings.quirks.iter().map(quirk).collect::<Vec<_>>();
+        entries.push(("quirks", quirks.join(", ")));
+    }
+    if !settings.straps.is_empty() {
+        let straps = settings.straps.iter().map(ToString::to_string);
+        entries.push(("straps", straps.collect::<Vec<_>>().join(", ")));
+    }
+    if !settings.flashers.is_empty() {
+        let flashers = settings
+            .flashers
+            .iter()
+            .map(|flasher| flasher.loader.to_string());
+        entries.push(("flashers", flashers.collect::<Vec<_>>().join(", ")));
+    }
+    if let Some(script) = &settings.uboot {
+        let load = match script.transfer() {
+            Some((_, protocol)) => protocol.to_string(),
+            None => "no load".into(),
+        };
+        entries.push((
+            "uboot",
+            format!(
+                "{} commands at `{}` ({})",
+                script.commands.len(),
+                script.prompt.trim_end(),
+                load
+            ),
+        ));
+    }
+    if !settings.captures.is_empty() {
+        let captures = settings
+            .captures
+            .iter()
+            .map(|rule| format!("{}..{} to {}", rule.start, rule.end, rule.directory));
+        entries.push(("captures", captures.collect::<Vec<_>>().join(", ")));
+    }
+    if !settings.instruments.is_empty() {
+        let instruments = settings.instruments.iter().map(|instrument| {
+            if instrument.from == instrument.until {
+                format!("{} ({})", instrument.name, instrument.from)
+            } else {
+                format!(
+                    "{} ({}..{})",
+                    instrument.name, instrument.from, instrument.until
+                )
+            }
+        });
+        entries.push(("traces", instruments.collect::<Vec<_>>().join(", ")));
+    }
+    // The tokens themselves are secrets.
+    if !settings.access.is_empty() {
+        entries.push(("access", format!("{} token(s)", settings.access.len())));
+    }
+    entries
+}
+
+/// The summary as text, one line per entry, under a title in the language of
+/// the `messages`.
+pub(crate) fn banner_text(messages: &Catalog, entries: &[(&'static str, String)]) -> String {
+    let mut text = messages.text("banner.title");
+    for (name, value) in entries {
+        let _ = write!(text, "\n[BC]    {:<9}{}", name, value);
+    }
+    text
+}
+
+/// The summary as a JSON object, on a single line.
+pub(crate) fn banner_json(entries: &[(&'static str, String)]) -> String {
+    let mut json = String::from("{\"event\":\"settings\"");
+    for (name, value) in entries {
+        let _ = write!(json, ",\"{}\":\"{}\"", name, json_escape(value));
+    }
+    json.push('}');
+    json
+}
+
+/// The baud rate, framing (e.g. `8N1`) and flow control.
+pub(crate) fn line_format(settings: &Settings) -> String {
+    let data_bits = match settings.data_bits {
+        DataBits::Five => 5,
+        DataBits::Six => 6,
+        DataBits::Seven => 7,
+        DataBits::Eight => 8,
+    };
+    let parity = match settings.parity {
+        Parity::None => 'N',
+        Parity::Odd => 'O',
+        Parity::Even => 'E',
+    };
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    };
+    let flow_control = match settings.flow_control {
+        FlowControl::None => "no flow control",
+        FlowControl::Software => "XON/XOFF",
+        FlowControl::Hardware => "RTS/CTS",
+    };
+    format!(
+        "{} {}{}{}, {}",
+        settings.baud_rate, data_bits, parity, stop_bits, flow_control
+    )
+}
+
+/// A quirk as given in the configuration file.
+fn quirk(quirk: &Quirk) -> String {
+    match quirk {
+        Quirk::DtrToggle => "dtr-toggle".into(),
+        Quirk::WakeByte(byte) => format!("wake-byte={:02x}", byte),
+        Quirk::CrToCrLf => "cr-crlf".into(),
+    }
+}
+
+/// A trigger as given on the command line, `<hex>:<protocol>[:<image>]`.
+fn trigger(trigger: &Trigger) -> String {
+    let mut text: String = trigger
+        .pattern
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    text.push(':');
+    text.push_str(&trigger.protocol.to_string());
+    if let Some(image) = &trigger.image {
+        text.push(':');
+        text.push_str(image);
+    }
+    text
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn banner_of_the_default_settings() {
+    use crate::settings::SettingsBuilder;
+
+    let settings = SettingsBuilder::default().path("/dev/ttyUSB0").finalize();
+    let entries = banner_entries(&settings);
+    assert_eq!(
+        banner_text(&Catalog::default(), &entries),
+        "[BC] ⚙️  Effective settings\n\
+         [BC]    port     /dev/ttyUSB0\n\
+         [BC]    line     230400 8N1, no flow control\n\
+         [BC]    image    kernel8.img\n\
+         [BC]    triggers 030303:raspbootin\n\
+         [BC]    transfer 5 attempt(s)\n\
+         [BC]    config   (none)"
+    );
+    assert_eq!(
+        banner_json(&entries),
+        "{\"event\":\"settings\",\"port\":\"/dev/ttyUSB0\",\
+         \"line\":\"230400 8N1, no flow control\",\"image\":\"kernel8.img\",\
+         \"triggers\":\"030303:raspbootin\",\"transfer\":\"5 attempt(s)\",\
+         \"config\":\"(none)\"}"
+    );
+}
@@ -0,0 +1,152 @@
+//! Verification of the boot of the kernel from the console output.
+//!
+//! After a kernel push, the device is expected to print the patterns of the
+//! [`Expectation`]s of the settings in order, each within its timeout from the
+//! previous one. The check reports each stage reached and, if the boot goes
+//! wrong, the stage which failed.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::settings::Expectation;
+
+/// What happened to a boot stage.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Stage {
+    /// The pattern was received after the given time since the push.
+    Reached(String, Duration),
+    /// The pattern was not received in time.
+    TimedOut(String, Duration),
+}
+
+/// The progress of the boot verification after a kernel push.
+#[derive(Debug)]
+pub(crate) struct BootCheck {
+    /// The stages not reached yet.
+    stages: VecDeque<Expectation>,
+    /// When the kernel push completed.
+    pushed: Instant,
+    /// When the current stage started.
+    stage_started: Instant,
+    /// The end of the output received so far, long enough to complete a
+    /// pattern split across reads.
+    tail: Vec<u8>,
+}
+impl BootCheck {
+    /// Start checking the `expectations` after a push completed at `now`.
+    /// Returns `None` if there is nothing to check.
+    pub(crate) fn start(expectations: &[Expectation], now: Instant) -> Option<Self> {
+        if expectations.is_empty() {
+            return None;
+        }
+        Some(BootCheck {
+            stages: expectations.iter().cloned().collect(),
+            pushed: now,
+            stage_started: now,
+            tail: vec![],
+        })
+    }
+
+    /// Returns `true` once all the stages were reached, or one failed.
+    pub(crate) fn is_over(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// Look for the expected patterns in the `data` received from the device
+    /// (which may be empty) at `now`, returning the stages which were reached
+    /// or failed.
+    ///
+    /// After a failure, the remaining stages are abandoned.
+    pub(crate) fn advance(&mut self, data: &[u8], now: Instant) -> Vec<Stage> {
+        let mut window = std::mem::take(&mut self.tail);
+        window.extend_from_slice(data);
+        let mut stages = vec![];
+        while let Some(stage) = self.stages.front() {
+            let pattern = stage.pattern.as_bytes();
+            if let Some(at) = window.windows(pattern.len()).position(|w| w == pattern) {
+                window.drain(..at + pattern.len());
+                stages.push(Stage::Reached(stage.pattern.clone(), now - self.pushed));
+                self.stage_started = now;
+                self.stages.pop_front();
+            } else if now - self.stage_started > stage.timeout {
+                stages.push(Stage::TimedOut(stage.pattern.clone(), stage.timeout));
+                self.stages.clear();
+            } else {
+                let keep = std::cmp::min(window.len(), pattern.len() - 1);
+                self.tail = window.split_off(window.len() - keep);
+                break;
+            }
+        }
+        stages
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn stages_are_reached_in_order() {
+    let expectations = vec![
+        Expectation {
+            pattern: "Booting".into(),
+            timeout: Duration::from_secs(5),
+        },
+        Expectation {
+            pattern: "login:".into(),
+            timeout: Duration::from_secs(60),
+        },
+    ];
+    let pushed = Instant::now();
+    let mut check = BootCheck::start(&expectations, pushed).unwrap();
+
+    // The second pattern does not count before the first one.
+    assert!(check.advance(b"login: Boo", pushed).is_empty());
+    let one = pushed + Duration::from_secs(1);
+    assert_eq!(
+        check.advance(b"ting Linux\nlog", one),
+        vec![Stage::Reached("Booting".into(), Duration::from_secs(1))]
+    );
+    let two = pushed + Duration::from_secs(2);
+    assert_eq!(
+        check.advance(b"in: ", two),
+        vec![Stage::Reached("login:".into(), Duration::from_secs(2))]
+    );
+    assert!(check.is_over());
+
+    assert!(BootCheck::start(&[], pushed).is_none());
+}
+
+#[test]
+fn the_failed_stage_is_reported() {
+    let expectations = vec![
+        Expectation {
+            pattern: "Booting".into(),
+            timeout: Duration::from_secs(5),
+        },
+        Expectation {
+            pattern: "initrd loaded".into(),
+            timeout: Duration::from_secs(10),
+        },
+        Expectation {
+            pattern: "login:".into(),
+            timeout: Duration::from_secs(60),
+        },
+    ];
+    let pushed = Instant::now();
+    let mut check = BootCheck::start(&expectations, pushed).unwrap();
+    check.advance(b"Booting", pushed + Duration::from_secs(3));
+    assert!(check
+        .advance(b"", pushed + Duration::from_secs(12))
+        .is_empty());
+    assert_eq!(
+        check.advance(b"", pushed + Duration::from_secs(14)),
+        vec![Stage::TimedOut(
+            "initrd loaded".into(),
+            Duration::from_secs(10)
+        )]
+    );
+    assert!(check.is_over());
+}
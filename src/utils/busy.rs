@@ -0,0 +1,126 @@
+//! Diagnosis of serial ports held by another program.
+//!
+//! A port already opened in exclusive mode by another program (a terminal
+//! emulator, ModemManager probing a freshly plugged device...) can't be opened
+//! by `bootcom`. Retrying blindly doesn't help, so instead the user is told
+//! which process holds the port (when the system allows finding out) and can
+//! choose to retry once it has been closed.
+
+use console::{style, Term};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+
+use crate::Settings;
+
+/// Returns `true` if the `error` returned when opening a port means that the
+/// port is held by another program.
+pub(crate) fn is_port_busy(error: &serialport::Error) -> bool {
+    // `serialport` does not have a specific error kind for this, it reports
+    // `EBUSY` on Unix and `ERROR_ACCESS_DENIED` on Windows.
+    let description = error.description.to_lowercase();
+    description.contains("busy") || description.contains("access is denied")
+}
+
+/// The processes (pid and command name) having the file at `path` open.
+#[cfg(target_os = "linux")]
+pub(crate) fn port_holders(path: &str) -> Vec<(u32, String)> {
+    use std::fs;
+
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return vec![],
+    };
+    let mut holders = vec![];
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return holders,
+    };
+    for process in processes.flatten() {
+        let pid = match process.file_name().to_str().and_then(|p| p.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // File descriptors of processes of other users are not readable
+        // without privileges, just skip them.
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link == target));
+        if holds {
+            let name = fs::read_to_string(process.path().join("comm"))
+                .map(|comm| comm.trim().to_owned())
+                .unwrap_or_else(|_| "?".into());
+            holders.push((pid, name));
+        }
+    }
+    holders
+}
+
+/// Finding the processes holding a port is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn port_holders(_path: &str) -> Vec<(u32, String)> {
+    vec![]
+}
+
+/// Tell the user that the port at `path` is busy, and by whom if possible, then
+/// ask whether opening it should be retried.
+pub(crate) fn prompt_busy_retry(settings: &Settings, path: &str) -> bool {
+    let messages = &settings.messages;
+    let _paused = super::render::pause();
+    let _keyboard = super::suspend();
+    println!(
+        "{}",
+        style(messages.text_with("port.busy", &[("path", &path)])).yellow()
+    );
+    let holders = port_holders(path);
+    for (pid, name) in &holders {
+        println!(
+            "{}",
+            messages.text_with(
+                "port.held_by",
+                &[("name", &style(name).cyan()), ("pid", pid)]
+            )
+        );
+    }
+    if holders.iter().any(|(_, name)| name == "ModemManager") {
+        // It lets go of the port after a few seconds, but will do it again the
+        // next time the device is plugged in.
+        super::modem_manager::warn(settings, path);
+    }
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(messages.text("prompt.busy_retry"))
+        .default(true)
+        .interact_on_opt(&Term::stdout())
+        .unwrap_or(None)
+        .unwrap_or(false)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn busy_errors() {
+    let busy = serialport::Error::new(serialport::ErrorKind::Unknown, "Device or resource busy");
+    assert!(is_port_busy(&busy));
+    let denied = serialport::Error::new(serialport::ErrorKind::NoDevice, "Access is denied.");
+    assert!(is_port_busy(&denied));
+    let missing = serialport::Error::new(
+        serialport::ErrorKind::Io(std::io::ErrorKind::NotFound),
+        "No such file or directory",
+    );
+    assert!(!is_port_busy(&missing));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn own_process_holds_open_file() {
+    let path = std::env::temp_dir().join(format!("bootcom-busy-{}", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    let holders = port_holders(path.to_str().unwrap());
+    assert!(holders.iter().any(|(pid, _)| *pid == std::process::id()));
+    drop(file);
+    std::fs::remove_file(path).unwrap();
+}
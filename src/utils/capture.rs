@@ -0,0 +1,240 @@
+//! Extraction of the binary blobs the device dumps on its console.
+//!
+//! Kernels often dump crash data (register files, memory, traces) as base64 or
+//! hex text between two markers, as that is all a console can carry. The
+//! capture rules of the settings tell those markers and the encoding, and the
+//! blobs are decoded and saved to files as soon as their end marker is
+//! received, wherever the reads split them.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::settings::{BlobEncoding, CaptureRule};
+
+/// The largest blob text kept, a runaway capture (lost end marker) is dropped
+/// beyond that.
+const MAX_BLOB_TEXT: usize = 16 * 1024 * 1024;
+
+/// A blob extracted from the console, or why it could not be.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Blob {
+    /// The index of the rule which captured it.
+    pub rule: usize,
+    pub data: Result<Vec<u8>, String>,
+}
+
+/// Scans the console output for the blobs framed by the capture rules.
+#[derive(Debug, Default)]
+pub(crate) struct BlobCapture {
+    rules: Vec<CaptureRule>,
+    /// The rule whose blob is being captured, if any.
+    capturing: Option<usize>,
+    /// The text of the blob being captured, or the end of the output which
+    /// may hold the beginning of a start marker.
+    text: Vec<u8>,
+    /// The number of blobs saved so far, keeping their file names unique.
+    saved: usize,
+}
+impl BlobCapture {
+    pub(crate) fn new(rules: &[CaptureRule]) -> Self {
+        BlobCapture {
+            rules: rules.to_vec(),
+            capturing: None,
+            text: vec![],
+            saved: 0,
+        }
+    }
+
+    /// Scan the `data` received from the device, returning the blobs it
+    /// completed.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Vec<Blob> {
+        let mut blobs = vec![];
+        if self.rules.is_empty() {
+            return blobs;
+        }
+        self.text.extend_from_slice(data);
+        loop {
+            match self.capturing {
+                None => match self.find_start() {
+                    Some((rule, after)) => {
+                        self.text.drain(..after);
+                        self.capturing = Some(rule);
+                    }
+                    None => {
+                        let longest = self.rules.iter().map(|r| r.start.len()).max();
+                        let keep = std::cmp::min(self.text.len(), longest.unwrap_or(1) - 1);
+                        self.text.drain(..self.text.len() - keep);
+                        return blobs;
+                    }
+                },
+                Some(rule) => {
+                    let end = self.rules[rule].end.as_bytes();
+                    match find(&self.text, end) {
+                        Some(at) => {
+                            let data = decode(&self.text[..at], self.rules[rule].encoding);
+                            blobs.push(Blob { rule, data });
+                            self.text.drain(..at + end.len());
+                            self.capturing = None;
+                        }
+                        None => {
+                            if self.text.len() > MAX_BLOB_TEXT {
+                                blobs.push(Blob {
+                                    rule,
+                                    data: Err("no end marker, the blob is too large".into()),
+                                });
+                                self.text.clear();
+                                self.capturing = None;
+                            }
+                            return blobs;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Save the `data` of a blob captured by the `rule` to a new file in the
+    /// directory of the rule, returning its path.
+    pub(crate) fn save(&mut self, rule: usize, data: &[u8]) -> io::Result<PathBuf> {
+        let directory = Path::new(&self.rules[rule].directory);
+        fs::create_dir_all(directory)?;
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.saved += 1;
+        let path = directory.join(format!("blob-{}-{}.bin", stamp, self.saved));
+        fs::write(&path, data)?;
+        Ok(path)
+    }
+
+    /// The earliest start marker in the text, with the rule it belongs to and
+    /// the offset right after it.
+    fn find_start(&self) -> Option<(usize, usize)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(i, rule)| {
+                find(&self.text, rule.start.as_bytes()).map(|at| (at, i, at + rule.start.len()))
+            })
+            .min()
+            .map(|(_, rule, after)| (rule, after))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Decode the `text` of a blob, ignoring whitespace.
+fn decode(text: &[u8], encoding: BlobEncoding) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    match encoding {
+        BlobEncoding::Hex => decode_hex(&digits),
+        BlobEncoding::Base64 => decode_base64(&digits),
+    }
+}
+
+fn decode_hex(digits: &[u8]) -> Result<Vec<u8>, String> {
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| "invalid hex digit".to_string())
+        })
+        .collect()
+}
+
+fn decode_base64(digits: &[u8]) -> Result<Vec<u8>, String> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(format!("invalid base64 character {:#04x}", c)),
+    };
+    let digits = digits
+        .iter()
+        .rposition(|&c| c != b'=')
+        .map_or(&digits[..0], |last| &digits[..=last]);
+    if digits.len() % 4 == 1 {
+        return Err("truncated base64 data".into());
+    }
+    let mut data = Vec::with_capacity(digits.len() * 3 / 4);
+    for group in digits.chunks(4) {
+        let mut bits: u32 = 0;
+        for (i, &c) in group.iter().enumerate() {
+            bits |= (value(c)? as u32) << (18 - 6 * i);
+        }
+        let bytes = bits.to_be_bytes();
+        data.extend_from_slice(&bytes[1..group.len()]);
+    }
+    Ok(data)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn blobs_are_extracted_across_reads() {
+    let rules = [
+        CaptureRule {
+            start: "<<CRASH".into(),
+            end: "CRASH>>".into(),
+            encoding: BlobEncoding::Base64,
+            directory: ".".into(),
+        },
+        CaptureRule {
+            start: "<<HEX".into(),
+            end: "HEX>>".into(),
+            encoding: BlobEncoding::Hex,
+            directory: ".".into(),
+        },
+    ];
+    let mut capture = BlobCapture::new(&rules);
+    assert_eq!(capture.feed(b"panic!\r\n<<CR"), vec![]);
+    assert_eq!(capture.feed(b"ASH\r\naGVsbG8=\r\nCRA"), vec![]);
+    assert_eq!(
+        capture.feed(b"SH>> and <<HEX 01 ff HEX>> <<HEX z HEX>>"),
+        vec![
+            Blob {
+                rule: 0,
+                data: Ok(b"hello".to_vec())
+            },
+            Blob {
+                rule: 1,
+                data: Ok(vec![0x01, 0xff])
+            },
+            Blob {
+                rule: 1,
+                data: Err("odd number of hex digits".into())
+            },
+        ]
+    );
+}
+
+#[test]
+fn base64_padding() {
+    assert_eq!(decode_base64(b"aGk=").unwrap(), b"hi");
+    assert_eq!(decode_base64(b"aGk").unwrap(), b"hi");
+    assert_eq!(decode_base64(b"aGVsbG8gd29ybGQ=").unwrap(), b"hello world");
+    assert!(decode_base64(b"a").is_err());
+    assert!(decode_base64(b"a?==").is_err());
+}
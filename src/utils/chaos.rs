@@ -0,0 +1,212 @@
+//! Injection of faults in the transfers of the kernel image.
+//!
+//! In chaos mode, the port is wrapped for the time of a transfer so that the
+//! bytes sent are corrupted, the writes dropped and the reads handed over late
+//! at the rates of the [`Chaos`] settings. A bootloader has to reject the
+//! corrupted images and recover from the lost chunks, and `bootcom` has to
+//! retry them. The faults follow from a seed, shown with the faults injected
+//! once the transfer is over, for a failure to be reproduced.
+
+use std::{
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{messages::Catalog, settings::Chaos, transport::Transport};
+
+/// The rates of the faults are in parts per million.
+const MILLION: u64 = 1_000_000;
+
+/// Run the `transfer` on the `port` with the faults of the `chaos` settings
+/// injected, telling the user about them in the words of the `messages`.
+pub(crate) fn inject<T>(
+    port: &mut dyn Transport,
+    chaos: &Chaos,
+    messages: &Catalog,
+    transfer: impl FnOnce(&mut dyn Transport) -> T,
+) -> T {
+    let seed = chaos.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    });
+    let chaos = Chaos {
+        seed: Some(seed),
+        ..*chaos
+    };
+    println!(
+        "{}",
+        messages.text_with("chaos.started", &[("chaos", &chaos)])
+    );
+    let mut chaotic = ChaosPort {
+        port,
+        faults: Faults::new(&chaos, seed),
+    };
+    let result = transfer(&mut chaotic);
+    let faults = &chaotic.faults;
+    println!(
+        "{}",
+        messages.text_with(
+            "chaos.injected",
+            &[
+                ("corrupted", &faults.corrupted),
+                ("dropped", &faults.dropped),
+                ("delayed", &faults.delayed),
+                ("seed", &seed),
+            ]
+        )
+    );
+    result
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// Decides the faults, and counts them.
+#[derive(Debug)]
+struct Faults {
+    chaos: Chaos,
+    rng: SplitMix64,
+    corrupted: u64,
+    dropped: u64,
+    delayed: u64,
+}
+impl Faults {
+    fn new(chaos: &Chaos, seed: u64) -> Self {
+        Faults {
+            chaos: *chaos,
+            rng: SplitMix64(seed),
+            corrupted: 0,
+            dropped: 0,
+            delayed: 0,
+        }
+    }
+
+    /// Whether the next write is dropped.
+    fn drops(&mut self) -> bool {
+        let dropped = self.roll(self.chaos.drop);
+        self.dropped += dropped as u64;
+        dropped
+    }
+
+    /// Flip some of the bits of some of the `data`.
+    fn corrupt(&mut self, data: &mut [u8]) {
+        if self.chaos.corrupt == 0 {
+            return;
+        }
+        for byte in data {
+            if self.roll(self.chaos.corrupt) {
+                // Never zero, the byte has to change.
+                *byte ^= (self.rng.next() % 255 + 1) as u8;
+                self.corrupted += 1;
+            }
+        }
+    }
+
+    /// How late the next read with some data is handed over, if at all.
+    fn delay(&mut self) -> Option<Duration> {
+        if self.roll(self.chaos.delay) {
+            self.delayed += 1;
+            Some(self.chaos.delay_time)
+        } else {
+            None
+        }
+    }
+
+    fn roll(&mut self, rate: u32) -> bool {
+        rate > 0 && self.rng.next() % MILLION < u64::from(rate)
+    }
+}
+
+/// The SplitMix64 generator, good enough for faults and any seed will do.
+#[derive(Debug)]
+pub(crate) struct SplitMix64(pub(crate) u64);
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// A link injecting faults in what goes through it.
+struct ChaosPort<'a> {
+    port: &'a mut dyn Transport,
+    faults: Faults,
+}
+impl Read for ChaosPort<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.port.read(buf)?;
+        // The polls with nothing to read are not worth delaying.
+        if read > 0 {
+            if let Some(delay) = self.faults.delay() {
+                thread::sleep(delay);
+            }
+        }
+        Ok(read)
+    }
+}
+impl Write for ChaosPort<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.faults.drops() {
+            return Ok(buf.len());
+        }
+        let mut data = buf.to_vec();
+        self.faults.corrupt(&mut data);
+        self.port.write(&data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+impl Transport for ChaosPort<'_> {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        self.port.bytes_to_read()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.port.clear_input()
+    }
+
+    fn describe(&self) -> String {
+        self.port.describe()
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn faults_at_their_rates() {
+    let chaos = Chaos {
+        corrupt: 10_000,
+        drop: 100_000,
+        delay: 1_000_000,
+        ..Chaos::default()
+    };
+    let mut faults = Faults::new(&chaos, 42);
+    let mut data = vec![0x55; 100_000];
+    faults.corrupt(&mut data);
+    let corrupted = data.iter().filter(|b| **b != 0x55).count() as u64;
+    assert_eq!(corrupted, faults.corrupted);
+    assert!((800..1200).contains(&corrupted), "{}", corrupted);
+    let dropped = (0..10_000).filter(|_| faults.drops()).count();
+    assert!((800..1200).contains(&dropped), "{}", dropped);
+    assert_eq!(faults.delay(), Some(chaos.delay_time));
+
+    // The same seed, the same faults.
+    let mut again = vec![0x55; 100_000];
+    Faults::new(&chaos, 42).corrupt(&mut again);
+    assert_eq!(again, data);
+
+    let mut faults = Faults::new(&Chaos::default(), 42);
+    assert!(!faults.drops());
+    assert_eq!(faults.delay(), None);
+}
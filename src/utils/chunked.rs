@@ -0,0 +1,713 @@
+//! Sender side of the chunked transfer protocol, for devices with a small
+//! receive buffer and no flow control.
+//!
+//! The protocol starts like the `raspbootin` one: the size of the image is sent
+//! as 4 bytes (little endian) and the device confirms it with `OK`, followed by
+//! the size of its receive buffer as 2 bytes (little endian). The image is then
+//! sent in chunks no larger than that buffer, each of them preceded by an `STX`
+//! (`0x02`). The device acknowledges every chunk with an `ACK` (`0x06`) once it
+//! is ready for the next one, or asks for it again with a `NAK` (`0x15`), e.g.
+//! after a receive overrun. A chunk is sent at most 3 more times.
+//!
+//! When `bootcom` gives up mid-stream (the chunk is not acknowledged, the image
+//! changed, ...), it sends a `CAN` (`0x18`) where the device expects the next
+//! `STX`, so that the device can tell a truncated transfer from a complete one
+//! instead of waiting for the rest of the image. The device then requests the
+//! image again, by sending its trigger, whenever it is ready for a new attempt.
+//!
+//! Once the last chunk is acknowledged, `bootcom` ends the transfer with a
+//! command byte: `EOT` (`0x04`) to boot the image, or `P` to first persist it
+//! to the storage of the device (eMMC, SD card...). While persisting, the
+//! device reports its progress with `%` followed by a percentage byte, and
+//! finally an `ACK` on success or a `NAK` followed by a line of text telling
+//! what went wrong.
+//!
+//! Rather than polling for the `ACK` at a fixed rate, the wait is paced from
+//! the round-trip time measured on the previous chunks: fast links are polled
+//! tightly, slow ones are left alone until the `ACK` is due.
+//!
+//! The above is the version 1 of the protocol. A device speaking a later one
+//! tells so by advertising a receive buffer of `0`, followed by the highest
+//! version it speaks (u8) and the actual size of its receive buffer, and
+//! `bootcom` answers with a `SYN` (`0x16`) and the version picked, the lowest
+//! of the two. In the version 2, made for the fast links where a byte gets lost
+//! now and then, every chunk is sent in a frame holding its sequence number, its
+//! length and its CRC-32, and the device tells which frame it acknowledges (or
+//! asks for again). A frame is sent again when it is not acknowledged in time,
+//! instead of giving up, and the device acknowledges again the frame it already
+//! received when its `ACK` got lost on the way.
+
+use std::{
+    error::Error,
+    fmt, io, thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+
+use super::{encryption, is_transient, kernel, set_status, Crc32, KernelImage, SoftFlow};
+use crate::{
+    progress::TransferProgress,
+    protocol::{byte, device, host, note, Line},
+    settings::Settings,
+    transport::Transport,
+};
+
+pub(crate) const STX: u8 = 0x02;
+pub(crate) const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+pub(crate) const SYN: u8 = 0x16;
+pub(crate) const PERSIST: u8 = b'P';
+pub(crate) const PROGRESS: u8 = b'%';
+
+/// How long to wait for the device to acknowledge a chunk.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a chunk is sent again when the device asks for it.
+const MAX_RESENDS: usize = 3;
+
+/// The highest version of the protocol spoken by `bootcom`.
+pub(crate) const VERSION: u8 = 2;
+
+/// The receive buffer advertised by the devices telling their version next.
+const VERSIONED: usize = 0;
+
+/// The bytes of a frame of the version 2 around its chunk: `STX`, the
+/// sequence number, the length and the CRC-32.
+pub(crate) const FRAME_OVERHEAD: usize = 8;
+
+/// How many times a frame of the version 2 is sent again, when it is rejected
+/// or not acknowledged in time.
+const MAX_RETRANSMISSIONS: usize = 10;
+
+/// The bounds of the time a frame of the version 2 waits for its `ACK` before
+/// being sent again, and that time before the round-trip time is known.
+const MIN_RTO: Duration = Duration::from_millis(50);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+/// How long the device may stay silent while persisting the image.
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The bounds of the interval between two checks for the `ACK`.
+const MIN_POLL: Duration = Duration::from_micros(200);
+const MAX_POLL: Duration = Duration::from_millis(20);
+
+/// Send the kernel `image` of the given `size` with the chunked protocol.
+///
+/// Returns the number of chunks sent again after being rejected.
+pub(crate) fn send(
+    port: &mut dyn Transport,
+    settings: &Settings,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    size: u32,
+) -> Result<u32, Box<dyn Error>> {
+    let mut response = [0u8; kernel::SIZE_CONFIRMATION.len() + 2];
+    kernel::write_kernel_size(settings, port, flow, size, &mut response)?;
+    let confirmed = kernel::SIZE_CONFIRMATION.len();
+    encryption::confirm(settings, &response[..confirmed])?;
+    let mut buffer = u16::from_le_bytes([response[confirmed], response[confirmed + 1]]) as usize;
+    let mut version = 1;
+    if buffer == VERSIONED {
+        let (picked, versioned_buffer) = negotiate(port, flow)?;
+        version = picked;
+        buffer = versioned_buffer;
+    }
+    debug!(
+        "chunked protocol version {}, device receive buffer: {} bytes",
+        version, buffer
+    );
+    let chunk_size = match version {
+        1 => chunk_size(buffer, flow.is_enabled()),
+        _ => frame_payload(buffer, flow.is_enabled()),
+    }
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the device advertised a receive buffer too small for a chunk",
+        )
+    })?;
+
+    // The image may be larger than its `size`, once encrypted.
+    let progress = TransferProgress::start(settings, image.len() as u64);
+    let sending = match version {
+        1 => send_chunks(port, flow, image, chunk_size, &progress),
+        _ => send_frames(port, flow, image, chunk_size, &progress),
+    };
+    let (sent, resends) = match sending {
+        Ok(sent) => sent,
+        Err(e) => {
+            abort(port, flow);
+            return Err(e);
+        }
+    };
+    progress.finish(sent as u64);
+
+    if settings.persist {
+        kernel::write_chunk(port, flow, &[PERSIST])?;
+        persist(port, settings, flow)?;
+    } else {
+        kernel::write_chunk(port, flow, &[EOT])?;
+    }
+    let output = kernel::drain_output(port, flow, settings.flush_window);
+    progress.device_output(&output);
+    Ok(resends)
+}
+
+/// The messages of the chunked protocol, the chunks being escaped when
+/// `flow_controlled`.
+pub(crate) fn wire_format(flow_controlled: bool) -> Vec<Line> {
+    let chunk = if flow_controlled {
+        "half of the receive buffer, as the escaping may double it"
+    } else {
+        "the size of the receive buffer"
+    };
+    vec![
+        host("size of the image (u32 LE)"),
+        device(format!(
+            "`{}` and the size of its receive buffer (u16 LE)",
+            String::from_utf8_lossy(kernel::SIZE_CONFIRMATION)
+        )),
+        device(format!(
+            "or, from the version 2, `{}` | 00 00 | its highest version (u8) | the size of its \
+             receive buffer (u16 LE)",
+            String::from_utf8_lossy(kernel::SIZE_CONFIRMATION)
+        )),
+        host(format!(
+            "then {} | the version picked (u8), the lowest of the two, up to {}",
+            byte("SYN", SYN),
+            VERSION
+        )),
+        host(format!(
+            "version 1: {} | a chunk of the image, up to {}",
+            byte("STX", STX),
+            chunk
+        )),
+        device(format!(
+            "version 1: {} once ready for the next chunk, or {} to get it again, within {} s",
+            byte("ACK", ACK),
+            byte("NAK", NAK),
+            ACK_TIMEOUT.as_secs()
+        )),
+        note(format!(
+            "version 1: a chunk is sent {} more times at most",
+            MAX_RESENDS
+        )),
+        host(format!(
+            "version 2: {} | sequence number (u8, from 0, wrapping) | length (u16 LE) | a \
+             chunk of the image | CRC-32 of the sequence number, length and chunk (u32 LE), \
+             the frame fitting in {}",
+            byte("STX", STX),
+            chunk
+        )),
+        device(format!(
+            "version 2: {} | sequence number once ready for the next frame, or {} | sequence \
+             number to get it again, the frames received twice being acknowledged again",
+            byte("ACK", ACK),
+            byte("NAK", NAK),
+        )),
+        note(format!(
+            "version 2: a frame is sent again when rejected or not acknowledged in time \
+             (between {} ms and {} s, from the round-trip time), {} times at most",
+            MIN_RTO.as_millis(),
+            ACK_TIMEOUT.as_secs(),
+            MAX_RETRANSMISSIONS
+        )),
+        host(format!(
+            "after the last chunk, {} to boot the image, or {} to persist it first",
+            byte("EOT", EOT),
+            byte("P", PERSIST)
+        )),
+        device(format!(
+            "when persisting, {} | percentage (u8) as it goes, at most {} s apart",
+            byte("%", PROGRESS),
+            PERSIST_TIMEOUT.as_secs()
+        )),
+        device(format!(
+            "when persisting, {} once done, or {} and a line of text telling why",
+            byte("ACK", ACK),
+            byte("NAK", NAK)
+        )),
+        host(format!(
+            "{} in place of the next `STX` when the transfer is given up",
+            byte("CAN", CAN)
+        )),
+    ]
+}
+
+/// Send the `image` in chunks of `chunk_size` bytes, each acknowledged by the
+/// device, and return the number of bytes sent and of chunks sent again.
+fn send_chunks(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    chunk_size: usize,
+    progress: &TransferProgress,
+) -> Result<(usize, u32), Box<dyn Error>> {
+    let mut pacer = AckPacer::default();
+    let mut sent = 0;
+    let mut total_resends = 0;
+    for chunk in image.as_bytes().chunks(chunk_size) {
+        let mut resends = 0;
+        loop {
+            let written = Instant::now();
+            kernel::write_chunk(port, flow, &chunk_frame(chunk))?;
+            if wait_for_ack(port, flow, &pacer, written)? {
+                pacer.sample(written.elapsed());
+                break;
+            }
+            if resends == MAX_RESENDS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the device rejected the chunk at offset {}", sent),
+                )
+                .into());
+            }
+            resends += 1;
+            total_resends += 1;
+            debug!("the device asked for the chunk at offset {} again", sent);
+        }
+        image.check_unchanged()?;
+        sent += chunk.len();
+        progress.update(sent as u64);
+    }
+    if let Some(srtt) = pacer.srtt {
+        debug!("ACK round-trip time: {:?}", srtt);
+    }
+    Ok((sent, total_resends))
+}
+
+/// Pick the version of the protocol with a device which advertised a
+/// receive buffer of `0`, and return it with the actual receive buffer.
+fn negotiate(port: &mut dyn Transport, flow: &mut SoftFlow) -> Result<(u8, usize), Box<dyn Error>> {
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    let mut versioned = [0u8; 3];
+    for byte in &mut versioned {
+        *byte = read_byte(port, flow, deadline, MAX_POLL)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the device did not tell its version of the protocol in time",
+            )
+        })?;
+    }
+    if versioned[0] == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the device advertised the version 0 of the protocol",
+        )
+        .into());
+    }
+    let version = versioned[0].min(VERSION);
+    kernel::write_chunk(port, flow, &[SYN, version])?;
+    Ok((
+        version,
+        u16::from_le_bytes([versioned[1], versioned[2]]) as usize,
+    ))
+}
+
+/// Send the `image` in frames of the version 2, holding up to `payload_size`
+/// bytes each, and return the number of bytes sent and of frames sent again.
+fn send_frames(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    payload_size: usize,
+    progress: &TransferProgress,
+) -> Result<(usize, u32), Box<dyn Error>> {
+    let mut pacer = AckPacer::default();
+    let mut sent = 0;
+    let mut total_retransmissions = 0;
+    for (index, chunk) in image.as_bytes().chunks(payload_size).enumerate() {
+        let sequence = index as u8;
+        let frame = frame(sequence, chunk);
+        let mut retransmissions = 0;
+        loop {
+            let written = Instant::now();
+            kernel::write_chunk(port, flow, &frame)?;
+            // The timeout backs off as the frame is sent again.
+            let timeout =
+                (pacer.retransmission_timeout() * (1 << retransmissions)).min(ACK_TIMEOUT);
+            match wait_for_frame_ack(port, flow, &pacer, written, sequence, timeout)? {
+                Some(true) => {
+                    // Only the frames sent once tell the round-trip time
+                    // (Karn's algorithm).
+                    if retransmissions == 0 {
+                        pacer.sample(written.elapsed());
+                    }
+                    break;
+                }
+                Some(false) => debug!("the device asked for the frame {} again", sequence),
+                None => debug!("the frame {} was not acknowledged in time", sequence),
+            }
+            if retransmissions == MAX_RETRANSMISSIONS {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "the frame at offset {} was not acknowledged after {} attempts",
+                        sent,
+                        MAX_RETRANSMISSIONS + 1
+                    ),
+                )
+                .into());
+            }
+            retransmissions += 1;
+            total_retransmissions += 1;
+        }
+        image.check_unchanged()?;
+        sent += chunk.len();
+        progress.update(sent as u64);
+    }
+    if let Some(srtt) = pacer.srtt {
+        debug!("ACK round-trip time: {:?}", srtt);
+    }
+    Ok((sent, total_retransmissions))
+}
+
+/// Tell the device the transfer is aborted, so that it does not wait for the
+/// rest of the image. This is best effort: the port may well be the reason of
+/// the abort.
+fn abort(port: &mut dyn Transport, flow: &mut SoftFlow) {
+    if let Err(e) = kernel::write_chunk(port, flow, &[CAN]) {
+        debug!("could not abort the transfer: {}", e);
+    }
+}
+
+/// Relay the progress of the device persisting the image until it is done.
+fn persist(
+    port: &mut dyn Transport,
+    settings: &Settings,
+    flow: &mut SoftFlow,
+) -> Result<(), Box<dyn Error>> {
+    let spinner = settings.progress_theme.spinner();
+    set_status(&spinner, settings.messages.text("kernel.persisting"));
+    let result = (|| loop {
+        match next_status_byte(port, flow, PERSIST_TIMEOUT)? {
+            ACK => return Ok(()),
+            PROGRESS => {
+                let percent = next_status_byte(port, flow, PERSIST_TIMEOUT)?;
+                set_status(
+                    &spinner,
+                    settings
+                        .messages
+                        .text_with("kernel.persisting_percent", &[("percent", &percent)]),
+                );
+            }
+            NAK => {
+                let mut reason = vec![];
+                loop {
+                    match next_status_byte(port, flow, ACK_TIMEOUT)? {
+                        b'\n' => break,
+                        byte => reason.push(byte),
+                    }
+                }
+                return Err(PersistError(String::from_utf8_lossy(&reason).trim().into()).into());
+            }
+            other => trace!("ignored byte {:#04x} while persisting", other),
+        }
+    })();
+    match &result {
+        Ok(_) => spinner.finish_with_message(settings.messages.text("kernel.persisted")),
+        Err(_) => spinner.finish_and_clear(),
+    }
+    result
+}
+
+/// The frame of a `chunk` of the image.
+pub(crate) fn chunk_frame(chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(chunk.len() + 1);
+    frame.push(STX);
+    frame.extend_from_slice(chunk);
+    frame
+}
+
+/// The frame of the version 2 of the `chunk` of the image with the `sequence`
+/// number.
+pub(crate) fn frame(sequence: u8, chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(chunk.len() + FRAME_OVERHEAD);
+    frame.push(STX);
+    frame.push(sequence);
+    frame.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+    frame.extend_from_slice(chunk);
+    let mut crc = Crc32::new();
+    crc.update(&frame[1..]);
+    frame.extend_from_slice(&crc.finalize().to_le_bytes());
+    frame
+}
+
+/// The device could not persist the image, for the given reason.
+#[derive(Debug)]
+pub(crate) struct PersistError(String);
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the device could not persist the image: {}", self.0)
+    }
+}
+impl Error for PersistError {}
+
+/// The size of the chunks fitting in the receive `buffer` of the device, once
+/// escaped for the software flow control if `escaped`, which may double them.
+pub(crate) fn chunk_size(buffer: usize, escaped: bool) -> Option<usize> {
+    let size = if escaped { buffer / 2 } else { buffer };
+    if size > 0 {
+        Some(size)
+    } else {
+        None
+    }
+}
+
+/// The size of the chunks whose frames of the version 2 fit in the receive
+/// `buffer` of the device, once escaped if `escaped`.
+pub(crate) fn frame_payload(buffer: usize, escaped: bool) -> Option<usize> {
+    chunk_size(buffer, escaped)?
+        .checked_sub(FRAME_OVERHEAD)
+        .filter(|size| *size > 0)
+}
+
+/// Estimates the round-trip time of the chunks, from their write to their
+/// `ACK`, the way TCP does (RFC 6298), to pace the waits for the `ACK`.
+#[derive(Debug, Default)]
+struct AckPacer {
+    /// The smoothed round-trip time, once measured.
+    srtt: Option<Duration>,
+    /// The variation of the round-trip time.
+    rttvar: Duration,
+}
+impl AckPacer {
+    /// Account for the round-trip time `rtt` of the last chunk.
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = rtt.abs_diff(srtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// How long after the write of a chunk its `ACK` can't be there yet.
+    fn quiet_time(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) if srtt > self.rttvar * 2 => srtt - self.rttvar * 2,
+            _ => Duration::from_millis(0),
+        }
+    }
+
+    /// How long to wait for the `ACK` of a frame before sending it again, the
+    /// way TCP does.
+    fn retransmission_timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(MIN_RTO, ACK_TIMEOUT),
+            None => INITIAL_RTO,
+        }
+    }
+
+    /// The interval between two checks for the `ACK` after the quiet time.
+    fn poll_interval(&self) -> Duration {
+        let interval = match self.srtt {
+            Some(srtt) => srtt / 16,
+            None => Duration::from_millis(1),
+        };
+        interval.clamp(MIN_POLL, MAX_POLL)
+    }
+}
+
+/// Read the next byte from the device which is not a flow control character,
+/// checking for it every `interval` until the `deadline`.
+///
+/// Returns `None` if nothing came in time.
+fn read_byte(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    deadline: Instant,
+    interval: Duration,
+) -> io::Result<Option<u8>> {
+    while Instant::now() < deadline {
+        if port.bytes_to_read()? > 0 {
+            let mut byte = [0u8; 1];
+            match port.read(&mut byte) {
+                Ok(1) => {}
+                Ok(_) => continue,
+                Err(ref e) if is_transient(e) => continue,
+                Err(e) => return Err(e),
+            }
+            trace!("response byte {:#04x}", byte[0]);
+            if let Some(&byte) = flow.receive(&byte).first() {
+                return Ok(Some(byte));
+            }
+        } else {
+            thread::sleep(interval);
+        }
+    }
+    Ok(None)
+}
+
+/// Read the next byte while the device persists the image, which it may take
+/// a while to send.
+fn next_status_byte(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    timeout: Duration,
+) -> io::Result<u8> {
+    read_byte(port, flow, Instant::now() + timeout, MAX_POLL)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the device stopped responding while persisting the image",
+        )
+    })
+}
+
+/// Wait for the device to acknowledge the chunk `written` at the given time,
+/// keeping track of the flow control characters. Returns `false` when the
+/// device asks for the chunk again.
+fn wait_for_ack(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    pacer: &AckPacer,
+    written: Instant,
+) -> io::Result<bool> {
+    let quiet_time = pacer.quiet_time();
+    let elapsed = written.elapsed();
+    if elapsed < quiet_time {
+        thread::sleep(quiet_time - elapsed);
+    }
+    match read_byte(port, flow, written + ACK_TIMEOUT, pacer.poll_interval())? {
+        Some(ACK) => Ok(true),
+        Some(NAK) => Ok(false),
+        Some(other) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected ACK or NAK, received {:#04x}", other),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the device did not acknowledge the chunk in time",
+        )),
+    }
+}
+
+/// Wait for the device to acknowledge the frame with the `sequence` number
+/// `written` at the given time, for up to `timeout`. The acknowledgements of
+/// other frames and the noise are skipped. Returns `false` when the device
+/// asks for the frame again, and `None` when nothing came in time.
+fn wait_for_frame_ack(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    pacer: &AckPacer,
+    written: Instant,
+    sequence: u8,
+    timeout: Duration,
+) -> io::Result<Option<bool>> {
+    let quiet_time = pacer.quiet_time().min(timeout);
+    let elapsed = written.elapsed();
+    if elapsed < quiet_time {
+        thread::sleep(quiet_time - elapsed);
+    }
+    let deadline = written + timeout;
+    loop {
+        let reply = match read_byte(port, flow, deadline, pacer.poll_interval())? {
+            Some(reply @ ACK) | Some(reply @ NAK) => reply,
+            Some(other) => {
+                trace!("ignored byte {:#04x} while waiting for the ACK", other);
+                continue;
+            }
+            None => return Ok(None),
+        };
+        match read_byte(port, flow, deadline, pacer.poll_interval())? {
+            Some(acknowledged) if acknowledged == sequence => return Ok(Some(reply == ACK)),
+            Some(other) => trace!("ignored the reply to the frame {}", other),
+            None => return Ok(None),
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn chunks_fit_in_the_buffer() {
+    assert_eq!(chunk_size(64, false), Some(64));
+    assert_eq!(chunk_size(64, true), Some(32));
+    assert_eq!(chunk_size(1, true), None);
+    assert_eq!(chunk_size(0, false), None);
+}
+
+#[test]
+fn pacing_follows_the_round_trip_time() {
+    let mut pacer = AckPacer::default();
+    assert_eq!(pacer.quiet_time(), Duration::from_millis(0));
+    assert_eq!(pacer.poll_interval(), Duration::from_millis(1));
+
+    // A slow and steady link is left alone until the ACK is due.
+    for _ in 0..32 {
+        pacer.sample(Duration::from_millis(100));
+    }
+    assert!(pacer.quiet_time() > Duration::from_millis(90));
+    assert_eq!(pacer.poll_interval(), Duration::from_micros(6250));
+
+    // A fast link is polled tightly.
+    let mut pacer = AckPacer::default();
+    pacer.sample(Duration::from_micros(500));
+    assert_eq!(pacer.quiet_time(), Duration::from_millis(0));
+    assert_eq!(pacer.poll_interval(), MIN_POLL);
+}
+
+#[test]
+fn lost_frames_are_sent_again() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::settings::{SettingsBuilder, TransferProtocol};
+    use crate::transport::MemoryTransport;
+
+    let path = std::env::temp_dir().join(format!("bootcom-v2-{}.img", std::process::id()));
+    let image: Vec<u8> = (0..40).collect();
+    std::fs::write(&path, &image).unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+
+    // A device of the version 2 with a receive buffer of 24 bytes, whose
+    // first ACK of the second frame gets lost, and which gets the third frame
+    // corrupted the first time.
+    let received = Arc::new(Mutex::new(vec![]));
+    let into = Arc::clone(&received);
+    let mut expected = 0;
+    let mut corrupted = false;
+    let mut device = MemoryTransport::new(move |written: &[u8]| match written {
+        [40, 0, 0, 0] => b"OK\0\0\x02\x18\0".to_vec(),
+        [STX, sequence, len, 0, rest @ ..] => {
+            let (sequence, len) = (*sequence, *len as usize);
+            assert_eq!(written, &frame(sequence, &rest[..len])[..]);
+            if sequence == 2 && !corrupted {
+                corrupted = true;
+                return vec![b'?', NAK, sequence];
+            }
+            if sequence == expected {
+                into.lock().unwrap().extend_from_slice(&rest[..len]);
+                expected += 1;
+                if sequence == 1 {
+                    return vec![];
+                }
+            }
+            vec![ACK, sequence]
+        }
+        _ => vec![],
+    });
+    let report = super::send_kernel(
+        &mut device,
+        &settings,
+        TransferProtocol::Chunked,
+        path.to_str(),
+        &mut None,
+    );
+    std::fs::remove_file(&path).unwrap();
+    let report = report.unwrap().unwrap();
+    assert_eq!(report.bytes, 40);
+    assert_eq!(report.retries, 2);
+    assert_eq!(*received.lock().unwrap(), image);
+    assert!(device.written().ends_with(&[EOT]));
+    assert_eq!(&device.written()[4..6], &[SYN, 2]);
+}
@@ -0,0 +1,218 @@
+//! Reload of the configuration file while `bootcom` runs, so that a long
+//! running lab session can be tuned without restarting it.
+//!
+//! The file is checked for modifications every second in terminal mode. The
+//! changes which are safe to make in the middle of a session (the progress
+//! theme, the boot stages, the codecs, the capture rules, the flashers, the
+//! encryption keys and the streams) are applied right away; the others (the quirks and the straps, which act when the port is
+//! opened, the U-Boot script, which may be running, and the instruments, which
+//! may be capturing) are queued and only applied when the next session starts.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::debug;
+
+use crate::codec::CodecFactory;
+use crate::config::{self, Config};
+use crate::settings::Settings;
+
+/// How often the configuration file is checked for modifications.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Watches the configuration file of the settings, if any. Cloning it gives
+/// another handle to the same watcher.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConfigReload {
+    inner: Arc<Mutex<Watched>>,
+}
+
+#[derive(Debug, Default)]
+struct Watched {
+    path: Option<PathBuf>,
+    /// When the file was last modified, as of the last check.
+    modified: Option<SystemTime>,
+    checked: Option<Instant>,
+    /// The configuration last reloaded, if the file changed since `bootcom`
+    /// started.
+    latest: Option<Config>,
+}
+
+/// The settings resulting from a reloaded configuration.
+#[derive(Debug)]
+pub(crate) struct Reloaded {
+    pub settings: Settings,
+    /// The names of the changes applied to the `settings`.
+    pub applied: Vec<&'static str>,
+    /// The names of the changes left for the next session.
+    pub queued: Vec<&'static str>,
+}
+
+impl ConfigReload {
+    /// Watch the configuration file of the `settings`.
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let path = settings.config_file.as_ref().map(PathBuf::from);
+        let modified = path.as_ref().and_then(|path| modified(path));
+        ConfigReload {
+            inner: Arc::new(Mutex::new(Watched {
+                path,
+                modified,
+                checked: None,
+                latest: None,
+            })),
+        }
+    }
+
+    /// Check the configuration file from time to time, returning its content
+    /// when it was modified since the last check, or why it can't be loaded.
+    pub(crate) fn poll(&self) -> Option<Result<Config, String>> {
+        let mut watched = self.inner.lock().unwrap();
+        let path = watched.path.clone()?;
+        let now = Instant::now();
+        if matches!(watched.checked, Some(checked) if now - checked < CHECK_INTERVAL) {
+            return None;
+        }
+        watched.checked = Some(now);
+        let modified = modified(&path);
+        if modified.is_none() || modified == watched.modified {
+            return None;
+        }
+        watched.modified = modified;
+        debug!("reloading the configuration from {}", path.display());
+        let config = config::load(&path);
+        if let Ok(config) = &config {
+            watched.latest = Some(config.clone());
+        }
+        Some(config)
+    }
+
+    /// Apply the configuration last reloaded, if any, to the `settings` of a
+    /// new session, including the changes queued so far.
+    pub(crate) fn settings_for_session(&self, settings: &Settings) -> Settings {
+        match &self.inner.lock().unwrap().latest {
+            Some(config) => apply_config(settings, config, false).settings,
+            None => settings.clone(),
+        }
+    }
+}
+
+/// Apply a reloaded `config` to the `settings`, only making the changes safe
+/// in the middle of a session when it is `live`.
+pub(crate) fn apply_config(settings: &Settings, config: &Config, live: bool) -> Reloaded {
+    let mut reloaded = Reloaded {
+        settings: settings.clone(),
+        applied: vec![],
+        queued: vec![],
+    };
+    let new = &mut reloaded.settings;
+    if new.progress_theme != config.progress {
+        new.progress_theme = config.progress.clone();
+        reloaded.applied.push("progress");
+    }
+    if new.expectations != config.expectations {
+        new.expectations = config.expectations.clone();
+        reloaded.applied.push("expect");
+    }
+    // Codecs are told apart by name, the factories of a reload are new ones.
+    if names(&new.codecs) != names(&config.codecs) {
+        new.codecs = config.codecs.clone();
+        reloaded.applied.push("codecs");
+    }
+    if new.boards != config.boards {
+        new.boards = config.boards.clone();
+        reloaded.applied.push("board");
+    }
+    if new.captures != config.captures {
+        new.captures = config.captures.clone();
+        reloaded.applied.push("capture");
+    }
+    if new.severities != config.severities {
+        new.severities = config.severities.clone();
+        reloaded.applied.push("severity");
+    }
+    if new.flashers != config.flashers {
+        new.flashers = config.flashers.clone();
+        reloaded.applied.push("flasher");
+    }
+    if new.transfer_keys != config.encryption {
+        new.transfer_keys = config.encryption.clone();
+        reloaded.applied.push("encryption");
+    }
+    if new.streams != config.streams {
+        new.streams = config.streams.clone();
+        reloaded.applied.push("stream");
+    }
+    if new.instruments != config.instruments {
+        if live {
+            reloaded.queued.push("instrument");
+        } else {
+            new.instruments = config.instruments.clone();
+            reloaded.applied.push("instrument");
+        }
+    }
+    if new.quirks != config.quirks {
+        if live {
+            reloaded.queued.push("quirks");
+        } else {
+            new.quirks = config.quirks.clone();
+            reloaded.applied.push("quirks");
+        }
+    }
+    if new.straps != config.straps {
+        if live {
+            reloaded.queued.push("strap");
+        } else {
+            new.straps = config.straps.clone();
+            reloaded.applied.push("strap");
+        }
+    }
+    if new.uboot != config.uboot {
+        if live {
+            reloaded.queued.push("uboot");
+        } else {
+            new.uboot = config.uboot.clone();
+            reloaded.applied.push("uboot");
+        }
+    }
+    reloaded
+}
+
+fn names(codecs: &[CodecFactory]) -> Vec<&str> {
+    codecs.iter().map(CodecFactory::name).collect()
+}
+
+fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn live_changes_are_applied_or_queued() {
+    use crate::settings::SettingsBuilder;
+
+    let settings = SettingsBuilder::default()
+        .codecs(vec![CodecFactory::builtin("timestamp").unwrap()])
+        .finalize();
+    let config = config::parse(
+        "codecs = [\"timestamp\"]\nquirks = [\"cr-crlf\"]\n\
+         [[expect]]\npattern = \"login:\"\ntimeout = 30",
+    )
+    .unwrap();
+
+    let reloaded = apply_config(&settings, &config, true);
+    assert_eq!(reloaded.applied, vec!["expect"]);
+    assert_eq!(reloaded.queued, vec!["quirks"]);
+    assert_eq!(reloaded.settings.expectations, config.expectations);
+    assert!(reloaded.settings.quirks.is_empty());
+
+    let reloaded = apply_config(&settings, &config, false);
+    assert_eq!(reloaded.applied, vec!["expect", "quirks"]);
+    assert_eq!(reloaded.settings.quirks, config.quirks);
+}
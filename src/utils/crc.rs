@@ -0,0 +1,92 @@
+//! Checksums used by the transfer protocols.
+
+use crate::protocol::{note, Line};
+
+/// The polynomial of the CRC-16/XMODEM.
+const CRC16_POLY: u16 = 0x1021;
+
+/// The polynomial of the CRC-32, reflected.
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+/// The parameters of the checksums, with their check values (of the ASCII
+/// `123456789`).
+pub(crate) fn wire_format() -> Vec<Line> {
+    let mut crc32 = Crc32::new();
+    crc32.update(b"123456789");
+    vec![
+        note(format!(
+            "CRC-16/XMODEM: polynomial {:#06x}, initial value 0, not reflected, check {:#06x}",
+            CRC16_POLY,
+            crc16(b"123456789")
+        )),
+        note(format!(
+            "CRC-32 (IEEE 802.3, zlib): polynomial {:#010x} reflected, initial value and final \
+             XOR 0xffffffff, check {:#010x}",
+            CRC32_POLY,
+            crc32.finalize()
+        )),
+    ]
+}
+
+/// Compute the CRC-16/XMODEM (polynomial `0x1021`, initial value `0`) of
+/// `data`.
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ CRC16_POLY
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Incremental computation of the CRC-32 (IEEE 802.3, as used by zlib) of a
+/// stream of data.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Crc32 {
+    value: u32,
+}
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        Crc32 { value: 0xffff_ffff }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.value ^= *byte as u32;
+            for _ in 0..8 {
+                self.value = if self.value & 1 != 0 {
+                    (self.value >> 1) ^ CRC32_POLY
+                } else {
+                    self.value >> 1
+                };
+            }
+        }
+    }
+
+    pub(crate) fn finalize(self) -> u32 {
+        !self.value
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn crc16_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+}
+
+#[test]
+fn crc32_check_value() {
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finalize(), 0xcbf4_3926);
+}
@@ -0,0 +1,187 @@
+//! Reception of the memory dumps streamed by the device.
+//!
+//! When a dump directory is set, the device (usually a crashed kernel or a
+//! debug monitor) can stream a memory region to the host by sending the dump
+//! trigger (**`0x04`** **three(3)** times) followed by a header and the
+//! content of the region:
+//!
+//! ```text
+//! header:   address (u64 LE) | length (u32 LE) | CRC-32 of the data (u32 LE)
+//! data:     length bytes
+//! response: ACK (0x06) when the CRC matches, NAK (0x15) otherwise
+//! ```
+//!
+//! The region is saved to `dump-<address>-<unix time>.bin` in the dump
+//! directory, along with a `.json` file of the same name holding its metadata
+//! (address, length, CRCs and the port it came from). It is saved even when
+//! the CRC does not match, as a damaged dump often still tells a lot.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use serialport::SerialPort;
+
+use super::host_services::read_exact_timeout;
+use super::{json_escape, Crc32, HumanSize};
+use crate::protocol::{byte, device, host, Line};
+
+/// The pattern sent by the device to start streaming a memory dump.
+pub(crate) const DUMP_TRIGGER: [u8; 3] = [4, 4, 4];
+
+/// The length of the header of a memory dump.
+pub(crate) const HEADER_LEN: usize = 16;
+
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+
+/// The largest memory region accepted, anything larger is most likely a
+/// corrupted header.
+const MAX_DUMP_LEN: u32 = 256 * 1024 * 1024;
+
+/// The messages of the memory dumps.
+pub(crate) fn wire_format() -> Vec<Line> {
+    vec![
+        device("address (u64 LE) | length (u32 LE) | CRC-32 of the data (u32 LE)"),
+        device(format!(
+            "length bytes, up to {}",
+            HumanSize(MAX_DUMP_LEN.into())
+        )),
+        host(format!(
+            "{} when the CRC matches, {} otherwise",
+            byte("ACK", ACK),
+            byte("NAK", NAK)
+        )),
+    ]
+}
+
+/// The header of a memory dump.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct DumpHeader {
+    pub address: u64,
+    pub length: u32,
+    pub crc: u32,
+}
+impl DumpHeader {
+    fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Self {
+        let mut address = [0; 8];
+        address.copy_from_slice(&bytes[..8]);
+        DumpHeader {
+            address: u64::from_le_bytes(address),
+            length: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            crc: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+        }
+    }
+}
+
+/// A memory dump received and saved.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Dump {
+    pub header: DumpHeader,
+    /// Whether the CRC of the data received matches the one of the header.
+    pub intact: bool,
+    /// Where the memory region was saved.
+    pub path: PathBuf,
+}
+
+/// Receive a memory dump from the `port`, once its trigger was received, and
+/// save it in the `directory`.
+pub(crate) fn receive_dump(port: &mut Box<dyn SerialPort>, directory: &str) -> io::Result<Dump> {
+    let mut header = [0u8; HEADER_LEN];
+    read_exact_timeout(port, &mut header)?;
+    let header = DumpHeader::from_bytes(&header);
+    debug!("memory dump {:x?}", header);
+    if header.length > MAX_DUMP_LEN {
+        port.write_all(&[NAK])?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "memory dump of {} is too large",
+                HumanSize(header.length.into())
+            ),
+        ));
+    }
+
+    let mut data = vec![0; header.length as usize];
+    read_exact_timeout(port, &mut data)?;
+    let mut crc = Crc32::new();
+    crc.update(&data);
+    let crc = crc.finalize();
+    port.write_all(&[if crc == header.crc { ACK } else { NAK }])?;
+
+    let source = port.name().unwrap_or_default();
+    let path = save_dump(Path::new(directory), &header, crc, &source, &data)?;
+    Ok(Dump {
+        header,
+        intact: crc == header.crc,
+        path,
+    })
+}
+
+/// Save the `data` of a memory dump and its metadata in the `directory`,
+/// returning the path of the data file.
+fn save_dump(
+    directory: &Path,
+    header: &DumpHeader,
+    crc: u32,
+    source: &str,
+    data: &[u8],
+) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let received = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = format!("dump-{:x}-{}", header.address, received.as_secs());
+    let path = directory.join(format!("{}.bin", name));
+    fs::write(&path, data)?;
+    let metadata = format!(
+        "{{\"address\":{},\"length\":{},\"crc32\":{},\"received_crc32\":{},\
+         \"intact\":{},\"port\":\"{}\",\"time\":{}}}\n",
+        header.address,
+        header.length,
+        header.crc,
+        crc,
+        crc == header.crc,
+        json_escape(source),
+        received.as_millis()
+    );
+    fs::write(directory.join(format!("{}.json", name)), metadata)?;
+    Ok(path)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn dump_is_saved_with_metadata() {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&0x8_0000u64.to_le_bytes());
+    bytes[8..12].copy_from_slice(&4u32.to_le_bytes());
+    bytes[12..].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+    let header = DumpHeader::from_bytes(&bytes);
+    assert_eq!(
+        header,
+        DumpHeader {
+            address: 0x8_0000,
+            length: 4,
+            crc: 0xdead_beef,
+        }
+    );
+
+    let dir = std::env::temp_dir().join(format!("bootcom-dump-{}", std::process::id()));
+    let path = save_dump(&dir, &header, 0x1234, "/dev/ttyUSB0", b"\x01\x02\x03\x04").unwrap();
+    assert!(path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("dump-80000-"));
+    assert_eq!(fs::read(&path).unwrap(), b"\x01\x02\x03\x04");
+    let metadata = fs::read_to_string(path.with_extension("json")).unwrap();
+    assert!(metadata.starts_with("{\"address\":524288,\"length\":4,\"crc32\":3735928559,"));
+    assert!(metadata.contains("\"intact\":false,\"port\":\"/dev/ttyUSB0\""));
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,188 @@
+//! Encrypted transfers of the kernel image.
+//!
+//! Boards in shared labs are often reached through networked serial servers,
+//! where anyone on the path could read a kernel image sent in clear, along with
+//! the secrets it embeds. When a [`TransferKey`] of the settings applies to the
+//! board (the one of its profile, or else one for all the boards), the image is
+//! encrypted with AES-256-GCM under that pre-shared key.
+//!
+//! The encryption is negotiated in the handshake of the `raspbootin` and
+//! chunked protocols: the size of the image is sent as usual, and the device
+//! confirms it with `OE` instead of `OK` to tell that it will decrypt the image.
+//! The transfer is aborted when the device and `bootcom` do not agree, rather
+//! than sending the image in clear to a device expecting it encrypted, or the
+//! other way around. The image is then replaced by a random nonce, the
+//! encrypted image and the authentication tag, the size of the image being the
+//! additional authenticated data.
+//!
+//! An encrypted upload can't be resumed: each one is sealed with a new nonce,
+//! so the part the device holds from the interrupted one is of no use. The
+//! device offering to resume it (`OR`) aborts the transfer.
+
+use std::{error::Error, fmt};
+
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+
+use super::kernel::{size_frame, RESUME_OFFER, SIZE_CONFIRMATION};
+use crate::{
+    boards,
+    protocol::{device, note, Line},
+    settings::{Settings, TransferKey, TransferProtocol},
+};
+
+/// The confirmation of the size of an image sent encrypted.
+pub(crate) const ENCRYPTED_CONFIRMATION: &[u8; 2] = b"OE";
+
+/// The length of the nonce sent before the encrypted image.
+pub(crate) const NONCE_LEN: usize = 12;
+
+/// The length of the authentication tag sent after the encrypted image.
+pub(crate) const TAG_LEN: usize = 16;
+
+/// The device and `bootcom` do not agree on the encryption of the image.
+#[derive(Debug)]
+pub(crate) struct EncryptionMismatch(&'static str);
+impl fmt::Display for EncryptionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+impl Error for EncryptionMismatch {}
+
+/// The key encrypting the transfers to the board on the port of the
+/// `settings`, the one of its profile, or else the one of all the boards.
+pub(crate) fn key_for(settings: &Settings) -> Option<&TransferKey> {
+    let profile = boards::board_of(settings).and_then(|board| board.profile.as_deref());
+    let keys = &settings.transfer_keys;
+    keys.iter()
+        .find(|key| key.profile.is_some() && key.profile.as_deref() == profile)
+        .or_else(|| keys.iter().find(|key| key.profile.is_none()))
+}
+
+/// The `image` of `size` bytes encrypted with the `key`, for the `protocol`:
+/// the nonce, the encrypted image and the tag.
+pub(crate) fn seal(
+    key: &TransferKey,
+    protocol: TransferProtocol,
+    size: u32,
+    image: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !matches!(
+        protocol,
+        TransferProtocol::Raspbootin | TransferProtocol::Chunked
+    ) {
+        return Err(format!(
+            "the image can't be encrypted with {}, only with raspbootin or chunked",
+            protocol
+        )
+        .into());
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    let cipher = Aes256Gcm::new(&key.key.into());
+    let payload = Payload {
+        msg: image,
+        aad: &size_frame(size),
+    };
+    let encrypted = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| "the image could not be encrypted")?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&encrypted);
+    Ok(sealed)
+}
+
+/// Check that the `confirmation` of the size by the device agrees with the
+/// encryption of the transfer.
+pub(crate) fn confirm(settings: &Settings, confirmation: &[u8]) -> Result<(), EncryptionMismatch> {
+    let encrypted = confirmation == ENCRYPTED_CONFIRMATION;
+    match (key_for(settings).is_some(), encrypted) {
+        (true, false) if confirmation == RESUME_OFFER => Err(EncryptionMismatch(
+            "the device offers to resume the upload, which is not supported with encryption",
+        )),
+        (true, false) => Err(EncryptionMismatch(
+            "the device did not accept the encrypted image, aborted not to send it in clear",
+        )),
+        (false, true) => Err(EncryptionMismatch(
+            "the device expects an encrypted image, but no key applies to the board",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The changes to the `raspbootin` and chunked protocols.
+pub(crate) fn wire_format() -> Vec<Line> {
+    vec![
+        device(format!(
+            "`{}` instead of `{}` to confirm the size, the image being encrypted",
+            String::from_utf8_lossy(ENCRYPTED_CONFIRMATION),
+            String::from_utf8_lossy(SIZE_CONFIRMATION)
+        )),
+        note(format!(
+            "nonce ({} bytes) | image encrypted with AES-256-GCM | tag ({} bytes), in place of \
+             the image",
+            NONCE_LEN, TAG_LEN
+        )),
+        note("the additional authenticated data is the size of the image (u32 LE)"),
+        note("the key is pre-shared, for all the boards or for the ones of a profile"),
+        note(format!(
+            "no `{}` to resume an encrypted upload, each image being sealed with a new nonce: \
+             the transfer is aborted",
+            String::from_utf8_lossy(RESUME_OFFER)
+        )),
+    ]
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn sealed_with_the_key_of_the_profile() {
+    use crate::{boards::Board, resume::PortIdentity, settings::SettingsBuilder};
+
+    let key = |profile: Option<&str>, byte: u8| TransferKey {
+        profile: profile.map(Into::into),
+        key: [byte; 32],
+    };
+    let board = Board {
+        name: "rpi4-a".into(),
+        port: PortIdentity {
+            path: "/dev/ttyUSB0".into(),
+            usb: None,
+        },
+        profile: Some("rpi4".into()),
+        image: None,
+    };
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyUSB0")
+        .boards(vec![board])
+        .transfer_keys(vec![key(None, 1), key(Some("rpi4"), 2)])
+        .finalize();
+    let transfer_key = key_for(&settings).unwrap();
+    assert_eq!(transfer_key.key, [2; 32]);
+    assert!(confirm(&settings, b"OE").is_ok());
+    assert!(confirm(&settings, b"OK").is_err());
+    let resume = confirm(&settings, b"OR").unwrap_err().to_string();
+    assert!(resume.contains("not supported with encryption"));
+
+    let sealed = seal(transfer_key, TransferProtocol::Chunked, 6, b"kernel").unwrap();
+    assert_eq!(sealed.len(), NONCE_LEN + 6 + TAG_LEN);
+    let cipher = Aes256Gcm::new(&[2; 32].into());
+    let (nonce, encrypted) = sealed.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: encrypted,
+        aad: &6u32.to_le_bytes(),
+    };
+    let image = cipher.decrypt(Nonce::from_slice(nonce), payload).unwrap();
+    assert_eq!(image, b"kernel");
+    assert!(seal(transfer_key, TransferProtocol::Ymodem, 6, b"kernel").is_err());
+
+    let settings = SettingsBuilder::default().path("/dev/ttyS0").finalize();
+    assert!(key_for(&settings).is_none());
+    assert!(confirm(&settings, b"OK").is_ok());
+    assert!(confirm(&settings, b"OE").is_err());
+}
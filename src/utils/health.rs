@@ -0,0 +1,259 @@
+//! Health reporting for `bootcom` instances supervising long running tests.
+//!
+//! The status of `bootcom` (current state, time of the last console output,
+//! number of boots and errors) is periodically written to a file, in the
+//! Prometheus text exposition format so that it can be scraped as is (e.g. by
+//! the node exporter textfile collector). An alert can also be raised, running
+//! a hook command, when the console has been silent for too long.
+//!
+//! When `bootcom` runs as a systemd service, the service manager is also told
+//! about the state changes, and its watchdog is fed.
+
+use std::{
+    fs,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use console::style;
+use log::info;
+
+use super::{render, systemd::watchdog_interval, Notifier};
+use crate::{messages::Catalog, settings::HealthReporting};
+
+#[derive(Debug)]
+struct Status {
+    machine: &'static str,
+    state: &'static str,
+    started: SystemTime,
+    last_output: Option<SystemTime>,
+    boots: u64,
+    errors: u64,
+    /// The service manager to keep informed, if any.
+    notifier: Option<Arc<Notifier>>,
+}
+
+/// The health status of `bootcom`, updated by the state machines. Cloning it
+/// gives another handle to the same status.
+#[derive(Debug, Clone)]
+pub(crate) struct Health {
+    status: Arc<Mutex<Status>>,
+}
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            status: Arc::new(Mutex::new(Status {
+                machine: "device",
+                state: "Init",
+                started: SystemTime::now(),
+                last_output: None,
+                boots: 0,
+                errors: 0,
+                notifier: None,
+            })),
+        }
+    }
+}
+impl Health {
+    /// Record the current `state` of the given state `machine`.
+    pub(crate) fn set_state(&self, machine: &'static str, state: &'static str) {
+        let mut status = self.status.lock().unwrap();
+        status.machine = machine;
+        status.state = state;
+        if let Some(notifier) = &status.notifier {
+            notifier.notify(&format!("STATUS={}/{}", machine, state));
+        }
+    }
+
+    /// Record that console output was just received.
+    pub(crate) fn output(&self) {
+        self.status.lock().unwrap().last_output = Some(SystemTime::now());
+    }
+
+    /// Record that a kernel image was sent to the device.
+    pub(crate) fn boot(&self) {
+        self.status.lock().unwrap().boots += 1;
+    }
+
+    /// Record an error.
+    pub(crate) fn error(&self) {
+        self.status.lock().unwrap().errors += 1;
+    }
+
+    /// Tell the service manager through the `notifier` that `bootcom` is
+    /// ready, and then about the state changes. Its watchdog, if enabled, is
+    /// fed from a thread at half its interval, as long as the status is not
+    /// held by a stuck state machine.
+    pub(crate) fn notify_service_manager(&self, notifier: Notifier) {
+        let notifier = Arc::new(notifier);
+        notifier.notify("READY=1");
+        self.status.lock().unwrap().notifier = Some(notifier.clone());
+        if let Some(interval) = watchdog_interval() {
+            let health = self.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval / 2);
+                drop(health.status.lock().unwrap());
+                notifier.notify("WATCHDOG=1");
+            });
+        }
+    }
+
+    /// Tell the service manager, if any, that `bootcom` is stopping.
+    pub(crate) fn stopping(&self) {
+        if let Some(notifier) = &self.status.lock().unwrap().notifier {
+            notifier.notify("STOPPING=1");
+        }
+    }
+
+    /// Start a thread writing the status file and checking the console silence
+    /// at the configured interval, the alerts being worded from the `messages`.
+    pub(crate) fn start_reporting(&self, reporting: HealthReporting, messages: Catalog) {
+        let health = self.clone();
+        thread::spawn(move || {
+            let mut alerted = false;
+            loop {
+                thread::sleep(reporting.interval);
+                let now = SystemTime::now();
+                if let Some(path) = &reporting.status_file {
+                    // Write then rename so that scrapers never see half a file.
+                    let tmp = format!("{}.tmp", path);
+                    let result =
+                        fs::write(&tmp, health.render(now)).and_then(|_| fs::rename(&tmp, path));
+                    if let Err(e) = result {
+                        info!("could not write the status file: {}", e);
+                    }
+                }
+                if let Some(threshold) = reporting.silence_threshold {
+                    let silent = health.silent_for(now);
+                    if silent < threshold {
+                        alerted = false;
+                    } else if !alerted {
+                        alerted = true;
+                        health.alert(&messages, silent, reporting.silence_hook.as_deref());
+                    }
+                }
+            }
+        });
+    }
+
+    /// How long the console has been silent, since the last output or since
+    /// `bootcom` started if nothing was ever received.
+    fn silent_for(&self, now: SystemTime) -> Duration {
+        let status = self.status.lock().unwrap();
+        let since = status.last_output.unwrap_or(status.started);
+        now.duration_since(since).unwrap_or_default()
+    }
+
+    fn alert(&self, messages: &Catalog, silent: Duration, hook: Option<&str>) {
+        // Reported from the background, not in the middle of a prompt.
+        render::message(
+            &style(messages.text_with("health.silent", &[("seconds", &silent.as_secs())]))
+                .yellow()
+                .to_string(),
+        );
+        if let Some(hook) = hook {
+            let state = self.status.lock().unwrap().state;
+            let result = shell(hook)
+                .env("BOOTCOM_SILENT_SECS", silent.as_secs().to_string())
+                .env("BOOTCOM_STATE", state)
+                .spawn();
+            if let Err(e) = result {
+                info!("could not run the silence hook: {}", e);
+            }
+        }
+    }
+
+    /// Render the status in the Prometheus text exposition format.
+    fn render(&self, now: SystemTime) -> String {
+        let silent = self.silent_for(now);
+        let status = self.status.lock().unwrap();
+        let last_output = status
+            .last_output
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|t| t.as_secs_f64())
+            .unwrap_or(0.0);
+        format!(
+            "# HELP bootcom_state Current state of bootcom.\n\
+             # TYPE bootcom_state gauge\n\
+             bootcom_state{{machine=\"{}\",state=\"{}\"}} 1\n\
+             # HELP bootcom_last_output_timestamp_seconds Time of the last console output, 0 if none.\n\
+             # TYPE bootcom_last_output_timestamp_seconds gauge\n\
+             bootcom_last_output_timestamp_seconds {:.3}\n\
+             # HELP bootcom_console_silent_seconds Time since the last console output.\n\
+             # TYPE bootcom_console_silent_seconds gauge\n\
+             bootcom_console_silent_seconds {}\n\
+             # HELP bootcom_boots_total Kernel images sent to the device.\n\
+             # TYPE bootcom_boots_total counter\n\
+             bootcom_boots_total {}\n\
+             # HELP bootcom_errors_total Errors on the serial port and during transfers.\n\
+             # TYPE bootcom_errors_total counter\n\
+             bootcom_errors_total {}\n",
+            status.machine,
+            status.state,
+            last_output,
+            silent.as_secs(),
+            status.boots,
+            status.errors
+        )
+    }
+}
+
+/// The short name of a state type, e.g. `TerminalMode` for
+/// `TerminalModeState<Box<dyn SerialPort>>`, to be used with
+/// [`Health::set_state`].
+pub(crate) fn state_name<S>() -> &'static str {
+    let name = std::any::type_name::<S>();
+    let name = name.split('<').next().unwrap_or(name);
+    let name = name.rsplit("::").next().unwrap_or(name);
+    name.strip_suffix("State").unwrap_or(name)
+}
+
+/// A command running `command` with the shell of the platform.
+#[cfg(not(windows))]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+pub(crate) fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn status_in_prometheus_format() {
+    let health = Health::default();
+    health.set_state("protocol", "TerminalMode");
+    health.boot();
+    health.boot();
+    health.error();
+    let report = health.render(SystemTime::now());
+    assert!(report.contains("bootcom_state{machine=\"protocol\",state=\"TerminalMode\"} 1\n"));
+    assert!(report.contains("bootcom_last_output_timestamp_seconds 0.000\n"));
+    assert!(report.contains("bootcom_boots_total 2\n"));
+    assert!(report.contains("bootcom_errors_total 1\n"));
+}
+
+#[test]
+fn silence_since_last_output() {
+    let health = Health::default();
+    let later = SystemTime::now() + Duration::from_secs(60);
+    assert!(health.silent_for(later) >= Duration::from_secs(60));
+    health.output();
+    assert!(health.silent_for(SystemTime::now()) < Duration::from_secs(1));
+}
+
+#[test]
+fn short_state_names() {
+    struct TerminalModeState<T = Box<dyn std::io::Read>>(T);
+    assert_eq!(state_name::<TerminalModeState>(), "TerminalMode");
+}
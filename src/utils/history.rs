@@ -0,0 +1,152 @@
+//! History of the events consumed by the state machines, for post-mortems.
+//!
+//! Every event returned by a state of the device manager or of the boot
+//! protocol state machine is recorded, with a summary of its payload, in a
+//! ring buffer holding the most recent ones. The history is dumped to the
+//! standard error when `bootcom` terminates abnormally (panic or exit with an
+//! error), so that a report of `bootcom` getting stuck or confused comes with
+//! the sequence of transitions that led there.
+
+use std::{
+    collections::VecDeque,
+    fmt, panic,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::messages::Catalog;
+
+/// How many events are kept in the history.
+const CAPACITY: usize = 256;
+
+#[derive(Debug)]
+struct Record {
+    /// When the event was consumed, relative to the start of the history.
+    at: Duration,
+    machine: &'static str,
+    /// The state which returned the event.
+    state: &'static str,
+    event: String,
+}
+
+#[derive(Debug)]
+struct Records {
+    started: Instant,
+    records: VecDeque<Record>,
+    /// The number of records which did not fit in the ring buffer.
+    dropped: u64,
+}
+
+/// The history of the events consumed by the state machines. Cloning it gives
+/// another handle to the same history.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    inner: Arc<Mutex<Records>>,
+    /// The catalog the history is dumped with.
+    messages: Catalog,
+}
+impl Default for History {
+    fn default() -> Self {
+        History::new(Catalog::default())
+    }
+}
+impl History {
+    /// An empty history, dumped in the language of the `messages`.
+    pub(crate) fn new(messages: Catalog) -> Self {
+        History {
+            inner: Arc::new(Mutex::new(Records {
+                started: Instant::now(),
+                records: VecDeque::with_capacity(CAPACITY),
+                dropped: 0,
+            })),
+            messages,
+        }
+    }
+
+    /// Record the `event` returned by `state` in the given state `machine`,
+    /// dropping the oldest record if the history is full.
+    pub(crate) fn record(&self, machine: &'static str, state: &'static str, event: String) {
+        let mut inner = self.lock();
+        let at = inner.started.elapsed();
+        if inner.records.len() == CAPACITY {
+            inner.records.pop_front();
+            inner.dropped += 1;
+        }
+        inner.records.push_back(Record {
+            at,
+            machine,
+            state,
+            event,
+        });
+    }
+
+    /// Dump the history to the standard error whenever a panic occurs, after
+    /// the default panic message.
+    pub(crate) fn dump_on_panic(&self) {
+        let history = self.clone();
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            eprintln!("{}", history);
+        }));
+    }
+
+    // The history is still wanted after a panic while it was locked.
+    fn lock(&self) -> MutexGuard<'_, Records> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl fmt::Display for History {
+    /// The recorded events, oldest first, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.lock();
+        let count = inner.records.len();
+        f.write_str(
+            &self
+                .messages
+                .text_with("history.title", &[("count", &count)]),
+        )?;
+        if inner.dropped > 0 {
+            let dropped = &inner.dropped;
+            f.write_str(
+                &self
+                    .messages
+                    .text_with("history.dropped", &[("count", dropped)]),
+            )?;
+        }
+        for record in &inner.records {
+            write!(
+                f,
+                "\n[BC]    {:>9.3}s {}/{} -> {}",
+                record.at.as_secs_f64(),
+                record.machine,
+                record.state,
+                record.event
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn oldest_records_are_dropped() {
+    let history = History::default();
+    for i in 0..CAPACITY + 2 {
+        history.record("device", "SelectPort", format!("SelectPort #{}", i));
+    }
+    let dump = history.to_string();
+    let mut lines = dump.lines();
+    assert_eq!(
+        lines.next(),
+        Some("[BC] 📜 last 256 event(s) (2 older ones dropped)")
+    );
+    assert!(lines
+        .next()
+        .unwrap()
+        .ends_with("device/SelectPort -> SelectPort #2"));
+    assert!(lines.last().unwrap().ends_with("SelectPort #257"));
+}
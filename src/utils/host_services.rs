@@ -0,0 +1,448 @@
+//! Semihosting-style services offered to the booted kernel over the serial
+//! port.
+//!
+//! Early kernel test harnesses often need to read fixtures from the host and
+//! write results back. When host services are enabled (by giving `bootcom` a
+//! host directory), the kernel can request them by sending the service trigger
+//! (**`0x05`** **three(3)** times) followed by a sequence of request frames:
+//!
+//! ```text
+//! request:  opcode (u8) | length (u16 LE) | payload
+//! response: status (u8) | length (u16 LE) | payload
+//! ```
+//!
+//! | opcode | request payload                 | response payload          |
+//! |--------|---------------------------------|---------------------------|
+//! | `0x01` | open: mode (u8), relative path  | handle (u8)               |
+//! | `0x02` | read: handle (u8), max (u16 LE) | data read (empty at EOF)  |
+//! | `0x03` | write: handle (u8), data        | bytes written (u16 LE)    |
+//! | `0x04` | close: handle (u8)              | -                         |
+//! | `0x05` | time                            | unix time in ms (u64 LE)  |
+//! | `0x06` | end of the service session      | -                         |
+//!
+//! Open modes are `0` for reading, `1` for writing (create/truncate) and `2`
+//! for appending. All paths are relative to the host directory; absolute paths
+//! and `..` components are rejected, and the links are resolved before the
+//! path is known to be in the directory, so the kernel can't escape the
+//! sandbox.
+//! Files opened during a service session are closed when the session ends.
+//!
+//! A zero status means success; otherwise it is one of the `STATUS_*` codes
+//! and the payload is empty.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace};
+use serialport::SerialPort;
+
+use super::is_transient;
+use crate::protocol::{device, host, note, Line};
+
+/// The pattern sent by the kernel to start a host service session.
+pub(crate) const SERVICE_TRIGGER: [u8; 3] = [5, 5, 5];
+
+const OP_OPEN: u8 = 0x01;
+const OP_READ: u8 = 0x02;
+const OP_WRITE: u8 = 0x03;
+const OP_CLOSE: u8 = 0x04;
+const OP_TIME: u8 = 0x05;
+const OP_END: u8 = 0x06;
+
+const STATUS_OK: u8 = 0;
+const STATUS_BAD_REQUEST: u8 = 1;
+const STATUS_NOT_FOUND: u8 = 2;
+const STATUS_DENIED: u8 = 3;
+const STATUS_BAD_HANDLE: u8 = 4;
+const STATUS_IO_ERROR: u8 = 5;
+
+/// The names of the opcodes of the requests.
+pub(crate) const OPCODE_NAMES: [(u8, &str); 6] = [
+    (OP_OPEN, "open"),
+    (OP_READ, "read"),
+    (OP_WRITE, "write"),
+    (OP_CLOSE, "close"),
+    (OP_TIME, "time"),
+    (OP_END, "end"),
+];
+
+/// The names of the statuses of the responses.
+pub(crate) const STATUS_NAMES: [(u8, &str); 6] = [
+    (STATUS_OK, "ok"),
+    (STATUS_BAD_REQUEST, "bad request"),
+    (STATUS_NOT_FOUND, "not found"),
+    (STATUS_DENIED, "denied"),
+    (STATUS_BAD_HANDLE, "bad handle"),
+    (STATUS_IO_ERROR, "I/O error"),
+];
+
+/// How long to wait for the next byte of a frame before giving up on it (and
+/// on the service session).
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The messages of the host services.
+pub(crate) fn wire_format() -> Vec<Line> {
+    let opcodes = [
+        (OP_OPEN, "open: mode (u8), relative path -> handle (u8)"),
+        (
+            OP_READ,
+            "read: handle (u8), max (u16 LE) -> data read (empty at EOF)",
+        ),
+        (
+            OP_WRITE,
+            "write: handle (u8), data -> bytes written (u16 LE)",
+        ),
+        (OP_CLOSE, "close: handle (u8)"),
+        (OP_TIME, "time -> unix time in ms (u64 LE)"),
+        (OP_END, "end of the service session"),
+    ];
+    let mut lines = vec![
+        device("opcode (u8) | length (u16 LE) | payload"),
+        host("status (u8) | length (u16 LE) | payload, empty unless the status is ok"),
+    ];
+    lines.extend(
+        opcodes
+            .iter()
+            .map(|(opcode, payload)| note(format!("opcode {:#04x} {}", opcode, payload))),
+    );
+    lines.push(note(
+        "open modes: 0 to read, 1 to write (create/truncate), 2 to append",
+    ));
+    let statuses: Vec<String> = STATUS_NAMES
+        .iter()
+        .map(|(status, name)| format!("{} {}", status, name))
+        .collect();
+    lines.push(note(format!("statuses: {}", statuses.join(", "))));
+    lines.push(note(format!(
+        "a frame is given up after {} s without data",
+        REQUEST_TIMEOUT.as_secs()
+    )));
+    lines
+}
+
+/// A request frame received from the kernel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Request {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A response frame to be sent back to the kernel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Response {
+    pub status: u8,
+    pub payload: Vec<u8>,
+}
+impl Response {
+    fn ok(payload: Vec<u8>) -> Self {
+        Response {
+            status: STATUS_OK,
+            payload,
+        }
+    }
+
+    fn error(status: u8) -> Self {
+        Response {
+            status,
+            payload: vec![],
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 3);
+        bytes.push(self.status);
+        bytes.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+/// The state of a host service session: the sandbox directory and the files
+/// opened by the kernel.
+#[derive(Debug)]
+pub(crate) struct HostServices {
+    root: PathBuf,
+    files: HashMap<u8, File>,
+    next_handle: u8,
+}
+impl HostServices {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        HostServices {
+            root: root.into(),
+            files: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    /// Serve requests from the kernel on the `port` until it ends the session.
+    pub(crate) fn serve(&mut self, port: &mut Box<dyn SerialPort>) -> io::Result<()> {
+        loop {
+            let request = read_request(port)?;
+            trace!("host service request {:02x?}", request);
+            if request.opcode == OP_END {
+                port.write_all(&Response::ok(vec![]).to_bytes())?;
+                return Ok(());
+            }
+            let response = self.handle(&request);
+            port.write_all(&response.to_bytes())?;
+        }
+    }
+
+    /// Handle a single request and produce the response for it.
+    pub(crate) fn handle(&mut self, request: &Request) -> Response {
+        let payload = &request.payload;
+        match request.opcode {
+            OP_OPEN if !payload.is_empty() => self.open(payload[0], &payload[1..]),
+            OP_READ if payload.len() == 3 => {
+                let max = u16::from_le_bytes([payload[1], payload[2]]);
+                self.read(payload[0], max)
+            }
+            OP_WRITE if !payload.is_empty() => self.write(payload[0], &payload[1..]),
+            OP_CLOSE if payload.len() == 1 => match self.files.remove(&payload[0]) {
+                Some(_) => Response::ok(vec![]),
+                None => Response::error(STATUS_BAD_HANDLE),
+            },
+            OP_TIME => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                Response::ok(now.to_le_bytes().to_vec())
+            }
+            _ => Response::error(STATUS_BAD_REQUEST),
+        }
+    }
+
+    fn open(&mut self, mode: u8, path: &[u8]) -> Response {
+        let path = match std::str::from_utf8(path)
+            .ok()
+            .and_then(|p| self.sandboxed(p))
+        {
+            Some(path) => path,
+            None => return Response::error(STATUS_DENIED),
+        };
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            _ => return Response::error(STATUS_BAD_REQUEST),
+        };
+        match options.open(&path) {
+            Ok(file) => {
+                // Find a free handle, 0 is never used.
+                let handle = (0..255)
+                    .map(|i| self.next_handle.wrapping_add(i))
+                    .find(|h| *h != 0 && !self.files.contains_key(h));
+                match handle {
+                    Some(handle) => {
+                        debug!("host service opened {:?} as {}", path, handle);
+                        self.files.insert(handle, file);
+                        self.next_handle = handle.wrapping_add(1);
+                        Response::ok(vec![handle])
+                    }
+                    None => Response::error(STATUS_IO_ERROR),
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Response::error(STATUS_NOT_FOUND),
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Response::error(STATUS_DENIED)
+            }
+            Err(_) => Response::error(STATUS_IO_ERROR),
+        }
+    }
+
+    fn read(&mut self, handle: u8, max: u16) -> Response {
+        match self.files.get_mut(&handle) {
+            Some(file) => {
+                let mut data = vec![0; max as usize];
+                match file.read(&mut data) {
+                    Ok(n) => {
+                        data.truncate(n);
+                        Response::ok(data)
+                    }
+                    Err(_) => Response::error(STATUS_IO_ERROR),
+                }
+            }
+            None => Response::error(STATUS_BAD_HANDLE),
+        }
+    }
+
+    fn write(&mut self, handle: u8, data: &[u8]) -> Response {
+        match self.files.get_mut(&handle) {
+            Some(file) => match file.write_all(data) {
+                Ok(_) => Response::ok((data.len() as u16).to_le_bytes().to_vec()),
+                Err(_) => Response::error(STATUS_IO_ERROR),
+            },
+            None => Response::error(STATUS_BAD_HANDLE),
+        }
+    }
+
+    /// Resolve `path` inside the sandbox directory, refusing anything that
+    /// could escape it, through a link included.
+    fn sandboxed(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path);
+        let safe = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if path.is_empty() || !safe {
+            return None;
+        }
+        let root = fs::canonicalize(&self.root).ok()?;
+        let joined = root.join(relative);
+        let resolved = match fs::canonicalize(&joined) {
+            Ok(resolved) => resolved,
+            // A dangling link, which would be followed to create its target.
+            Err(_) if fs::symlink_metadata(&joined).is_ok() => return None,
+            // Not there yet, created in a directory which must be.
+            Err(_) => fs::canonicalize(joined.parent()?)
+                .ok()?
+                .join(joined.file_name()?),
+        };
+        Some(resolved).filter(|resolved| resolved.starts_with(&root))
+    }
+}
+
+/// Read one request frame from the port.
+fn read_request(port: &mut Box<dyn SerialPort>) -> io::Result<Request> {
+    let mut header = [0u8; 3];
+    read_exact_timeout(port, &mut header)?;
+    let length = u16::from_le_bytes([header[1], header[2]]) as usize;
+    let mut payload = vec![0; length];
+    read_exact_timeout(port, &mut payload)?;
+    Ok(Request {
+        opcode: header[0],
+        payload,
+    })
+}
+
+/// Fill `buf` from the port, failing if no data arrives for too long.
+pub(crate) fn read_exact_timeout(port: &mut Box<dyn SerialPort>, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    let mut last_data = Instant::now();
+    while filled < buf.len() {
+        let available = port.bytes_to_read()? as usize;
+        if available > 0 {
+            let wanted = std::cmp::min(available, buf.len() - filled);
+            match port.read(&mut buf[filled..filled + wanted]) {
+                Ok(read) => {
+                    filled += read;
+                    last_data = Instant::now();
+                }
+                Err(ref e) if is_transient(e) => {}
+                Err(e) => return Err(e),
+            }
+        } else if last_data.elapsed() > REQUEST_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "incomplete frame from the device",
+            ));
+        } else {
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bootcom-hs-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn write_then_read_back() {
+    let dir = test_dir("rw");
+    let mut services = HostServices::new(&dir);
+
+    let mut open = vec![1];
+    open.extend_from_slice(b"result.txt");
+    let response = services.handle(&Request {
+        opcode: OP_OPEN,
+        payload: open,
+    });
+    assert_eq!(response.status, STATUS_OK);
+    let handle = response.payload[0];
+
+    let mut write = vec![handle];
+    write.extend_from_slice(b"pass");
+    let response = services.handle(&Request {
+        opcode: OP_WRITE,
+        payload: write,
+    });
+    assert_eq!(response, Response::ok(4u16.to_le_bytes().to_vec()));
+    services.handle(&Request {
+        opcode: OP_CLOSE,
+        payload: vec![handle],
+    });
+
+    let mut open = vec![0];
+    open.extend_from_slice(b"result.txt");
+    let handle = services
+        .handle(&Request {
+            opcode: OP_OPEN,
+            payload: open,
+        })
+        .payload[0];
+    let response = services.handle(&Request {
+        opcode: OP_READ,
+        payload: vec![handle, 64, 0],
+    });
+    assert_eq!(response, Response::ok(b"pass".to_vec()));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn escaping_the_sandbox_is_denied() {
+    let dir = test_dir("sandbox");
+    std::fs::create_dir_all(dir.join("fixtures")).unwrap();
+    let services = HostServices::new(&dir);
+    assert!(services.sandboxed("../etc/passwd").is_none());
+    assert!(services.sandboxed("/etc/passwd").is_none());
+    assert!(services.sandboxed("fixtures/../../x").is_none());
+    assert!(services.sandboxed("missing/input.bin").is_none());
+    assert!(services.sandboxed("fixtures/input.bin").is_some());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::symlink;
+
+        symlink("/etc", dir.join("etc")).unwrap();
+        symlink("/nonexistent/x", dir.join("dangling")).unwrap();
+        symlink("fixtures", dir.join("inside")).unwrap();
+        assert!(services.sandboxed("etc/passwd").is_none());
+        assert!(services.sandboxed("etc/new").is_none());
+        assert!(services.sandboxed("dangling").is_none());
+        assert!(services.sandboxed("inside/input.bin").is_some());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn bad_requests() {
+    let dir = test_dir("bad");
+    let mut services = HostServices::new(&dir);
+    let response = services.handle(&Request {
+        opcode: 0x7f,
+        payload: vec![],
+    });
+    assert_eq!(response.status, STATUS_BAD_REQUEST);
+    let response = services.handle(&Request {
+        opcode: OP_READ,
+        payload: vec![42, 1, 0],
+    });
+    assert_eq!(response.status, STATUS_BAD_HANDLE);
+    assert_eq!(response.to_bytes(), vec![STATUS_BAD_HANDLE, 0, 0]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
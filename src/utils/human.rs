@@ -0,0 +1,130 @@
+//! Human readable sizes, rates, durations and dates, shared by the transfers,
+//! the conformance runs and the summaries so that they all read the same.
+//!
+//! Sizes use binary units (`KiB`, `MiB`...) and are 64 bits wide, images and
+//! dumps larger than 4 GiB included.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+/// A size in bytes, e.g. `512 B` or `1.5 MiB`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct HumanSize(pub u64);
+impl fmt::Display for HumanSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// A throughput in bytes per second, e.g. `11.2 KiB/s`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct HumanRate(pub f64);
+impl fmt::Display for HumanRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", HumanSize(self.0.max(0.0).round() as u64))
+    }
+}
+
+/// A duration, as precise as useful for its magnitude, e.g. `850ms`, `3.2s`,
+/// `2m05s` or `1h02m`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct HumanDuration(pub Duration);
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.0.as_secs();
+        match seconds {
+            0 => write!(f, "{}ms", self.0.as_millis()),
+            1..=59 => write!(f, "{:.1}s", self.0.as_secs_f64()),
+            60..=3599 => write!(f, "{}m{:02}s", seconds / 60, seconds % 60),
+            _ => write!(f, "{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+        }
+    }
+}
+
+/// A date, in UTC as the local time zone is not known, e.g. `2023-11-14
+/// 22:13:20 UTC`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct HumanDate(pub SystemTime);
+impl fmt::Display for HumanDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self
+            .0
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // The civil date of the days since the epoch, in the proleptic
+        // Gregorian calendar with years starting in March.
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            seconds % 86_400 / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn humanized_figures() {
+    assert_eq!(HumanSize(0).to_string(), "0 B");
+    assert_eq!(HumanSize(1023).to_string(), "1023 B");
+    assert_eq!(HumanSize(1536).to_string(), "1.5 KiB");
+    assert_eq!(HumanSize(6 * 1024 * 1024 * 1024).to_string(), "6.0 GiB");
+    assert_eq!(HumanSize(u64::MAX).to_string(), "16.0 EiB");
+
+    assert_eq!(HumanRate(11_520.0).to_string(), "11.2 KiB/s");
+    assert_eq!(HumanRate(0.0).to_string(), "0 B/s");
+
+    assert_eq!(
+        HumanDuration(Duration::from_millis(850)).to_string(),
+        "850ms"
+    );
+    assert_eq!(
+        HumanDuration(Duration::from_millis(3210)).to_string(),
+        "3.2s"
+    );
+    assert_eq!(HumanDuration(Duration::from_secs(125)).to_string(), "2m05s");
+    assert_eq!(
+        HumanDuration(Duration::from_secs(3720)).to_string(),
+        "1h02m"
+    );
+
+    assert_eq!(
+        HumanDate(UNIX_EPOCH + Duration::from_secs(1_700_000_000)).to_string(),
+        "2023-11-14 22:13:20 UTC"
+    );
+    assert_eq!(
+        HumanDate(UNIX_EPOCH + Duration::from_secs(951_782_400)).to_string(),
+        "2000-02-29 00:00:00 UTC"
+    );
+}
@@ -0,0 +1,170 @@
+//! The kernel image, read in memory for the transfer.
+//!
+//! The image is not read chunk by chunk in between the writes to the serial
+//! port. It is read at once before the transfer instead, so that an image on a
+//! slow (e.g. network) filesystem does not stall the serial pipeline while the
+//! next chunk is fetched. It is not mapped: the build system truncating the
+//! file in the meantime would fault the process.
+//!
+//! The build system may also rewrite the image while it is being read, or
+//! before the transfer ends, and the board would then receive a torn or an
+//! outdated image. The size and the modification time of the file are
+//! therefore checked once it is read and along the transfer, which is aborted
+//! with an [`ImageChanged`] error as soon as they differ from when it started.
+
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, Read},
+    time::SystemTime,
+};
+
+/// The kernel image file was modified during the transfer.
+#[derive(Debug)]
+pub(crate) struct ImageChanged;
+impl fmt::Display for ImageChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the kernel image file changed during the transfer")
+    }
+}
+impl Error for ImageChanged {}
+
+/// What tells that the content of the file changed.
+#[derive(Debug, PartialEq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+impl Stamp {
+    fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// A kernel image read in memory.
+#[derive(Debug)]
+pub(crate) struct KernelImage {
+    data: Vec<u8>,
+    file: File,
+    stamp: Stamp,
+}
+impl KernelImage {
+    /// Read the whole content of `file`, failing with [`ImageChanged`] if it
+    /// was modified while it was read.
+    pub(crate) fn read(mut file: File) -> Result<Self, Box<dyn Error>> {
+        let stamp = Stamp::of(&file)?;
+        let mut data = Vec::with_capacity(stamp.len as usize);
+        file.read_to_end(&mut data)?;
+        let image = KernelImage { data, file, stamp };
+        image.check_unchanged()?;
+        Ok(image)
+    }
+
+    /// The image followed by the `trailer`.
+    pub(crate) fn append(self, trailer: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(self.len() + trailer.len());
+        data.extend_from_slice(self.as_bytes());
+        data.extend_from_slice(trailer);
+        KernelImage { data, ..self }
+    }
+
+    /// The `header` followed by the image.
+    pub(crate) fn prepend(self, header: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(header.len() + self.len());
+        data.extend_from_slice(header);
+        data.extend_from_slice(self.as_bytes());
+        KernelImage { data, ..self }
+    }
+
+    /// The image replaced by `data`, e.g. once encrypted.
+    pub(crate) fn replace(self, data: Vec<u8>) -> Self {
+        KernelImage { data, ..self }
+    }
+
+    /// When the file was last modified, if known.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        self.stamp.modified
+    }
+
+    /// Fail with [`ImageChanged`] if the file was modified since it was read,
+    /// meaning that what is sent is no longer the image of the file.
+    pub(crate) fn check_unchanged(&self) -> Result<(), Box<dyn Error>> {
+        if Stamp::of(&self.file)? != self.stamp {
+            return Err(ImageChanged.into());
+        }
+        Ok(())
+    }
+
+    /// The size of the image, in bytes.
+    pub(crate) fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    /// The content of the image.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn reads_the_whole_file() {
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("bootcom-image-{}", std::process::id()));
+    let data: Vec<u8> = (0..3 * 1024 * 1024 / 2).map(|i| i as u8).collect();
+    File::create(&path).unwrap().write_all(&data).unwrap();
+
+    let image = KernelImage::read(File::open(&path).unwrap()).unwrap();
+    assert_eq!(image.len(), data.len());
+    assert_eq!(image.as_bytes(), &data[..]);
+    assert!(image.check_unchanged().is_ok());
+    assert!(image.modified().is_some());
+
+    // Rewritten in place, as `cp` does.
+    let mut rewrite = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    rewrite.write_all(b"more").unwrap();
+    let error = image.check_unchanged().unwrap_err();
+    assert!(error.is::<ImageChanged>());
+
+    let image = image.append(b"trailer");
+    assert_eq!(image.len(), data.len() + 7);
+    assert!(image.as_bytes().ends_with(b"\xfftrailer"));
+    let image = image.prepend(b"header");
+    assert!(image.as_bytes().starts_with(b"header\x00\x01"));
+    drop(image);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn survives_the_file_being_truncated() {
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!("bootcom-truncated-{}", std::process::id()));
+    File::create(&path)
+        .unwrap()
+        .write_all(&[0x5a; 64 * 1024])
+        .unwrap();
+
+    let image = KernelImage::read(File::open(&path).unwrap()).unwrap();
+    File::create(&path).unwrap().set_len(0).unwrap();
+    // Still there, where reading a mapped file would fault.
+    assert!(image.as_bytes().iter().all(|byte| *byte == 0x5a));
+    assert_eq!(image.len(), 64 * 1024);
+    let error = image.check_unchanged().unwrap_err();
+    assert!(error.is::<ImageChanged>());
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,105 @@
+//! Watch of the kernel image file, to send it again once rebuilt and close the
+//! edit-build-boot loop.
+//!
+//! With the `watch` of the settings, the image is checked for modifications
+//! every half second in terminal mode. A rebuild writes the file in several
+//! steps, so a change is only reported once the file stayed the same for a
+//! second. The board is then reset with the reset command of the settings, if
+//! any, and the image sent when the bootloader asks for it; without a reset
+//! command, the image is sent right away, to a bootloader still waiting for it.
+
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often the image file is checked for modifications.
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the image file has to stay the same after a modification.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Watches the kernel image file. Cloning it gives another handle to the same
+/// watcher.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ImageWatch {
+    inner: Arc<Mutex<Watched>>,
+}
+
+#[derive(Debug, Default)]
+struct Watched {
+    path: Option<String>,
+    /// When the file was last modified and its size, as of the last check,
+    /// `None` if it was missing.
+    stamp: Option<(SystemTime, u64)>,
+    checked: Option<Instant>,
+    /// When the file was seen changing, if it was since the last rebuild
+    /// reported.
+    changed: Option<Instant>,
+}
+
+impl ImageWatch {
+    /// Check the image file at `path` from time to time, returning `true` once
+    /// it was rebuilt. A change of the path is not a rebuild.
+    pub(crate) fn poll(&self, path: &str, now: Instant) -> bool {
+        let mut watched = self.inner.lock().unwrap();
+        if matches!(watched.checked, Some(checked) if now - checked < CHECK_INTERVAL) {
+            return false;
+        }
+        watched.checked = Some(now);
+        let stamp = fs::metadata(path)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        if watched.path.as_deref() != Some(path) {
+            watched.path = Some(path.into());
+            watched.stamp = stamp;
+            watched.changed = None;
+            return false;
+        }
+        if stamp != watched.stamp {
+            watched.stamp = stamp;
+            watched.changed = Some(now);
+            return false;
+        }
+        match watched.changed {
+            Some(changed) if stamp.is_some() && now - changed >= SETTLE_TIME => {
+                watched.changed = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn rebuilds_reported_once_settled() {
+    let path = std::env::temp_dir().join(format!("bootcom-watch-{}.img", std::process::id()));
+    let path = path.to_str().unwrap();
+    fs::write(path, b"kernel").unwrap();
+    let watch = ImageWatch::default();
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+
+    assert!(!watch.poll(path, at(0)));
+    // Not checked again that soon.
+    fs::write(path, b"kernel v2").unwrap();
+    assert!(!watch.poll(path, at(100)));
+    assert!(!watch.poll(path, at(600)));
+    assert!(!watch.poll(path, at(1200)));
+    assert!(watch.poll(path, at(1700)));
+    assert!(!watch.poll(path, at(2300)));
+
+    // Still being written.
+    fs::remove_file(path).unwrap();
+    assert!(!watch.poll(path, at(2900)));
+    assert!(!watch.poll(path, at(4000)));
+    fs::write(path, b"kernel v3").unwrap();
+    assert!(!watch.poll(path, at(4600)));
+    assert!(watch.poll(path, at(5600)));
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,246 @@
+//! External capture tools run in sync with the phases of the boot.
+//!
+//! Each [`Instrument`] of the settings is started, with the shell of the
+//! platform, at the start of its `from` phase, and stopped at the end of its
+//! `until` phase, with its `stop` command or by terminating it. All of them
+//! are stopped when a transfer fails or when the session ends. The captures
+//! are saved to `<name>-<unix time>.<extension>` in the directory of the
+//! instrument, and reported with the times they started and stopped so that
+//! the hardware traces can be lined up with the console.
+
+use std::{
+    fs,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use console::style;
+use log::info;
+
+use super::shell;
+use crate::messages::Catalog;
+use crate::progress::InstrumentCapture;
+use crate::settings::{Instrument, Phase};
+
+/// How long a capture is given to exit once told to stop, before it is
+/// killed.
+const STOP_GRACE: Duration = Duration::from_secs(5);
+
+/// The instruments of a session, and their captures in progress.
+#[derive(Debug, Default)]
+pub(crate) struct Instruments {
+    instruments: Vec<Instrument>,
+    running: Vec<Running>,
+    messages: Catalog,
+}
+
+#[derive(Debug)]
+struct Running {
+    instrument: Instrument,
+    child: Child,
+    file: String,
+    started: SystemTime,
+}
+
+impl Instruments {
+    pub(crate) fn new(instruments: &[Instrument], messages: &Catalog) -> Self {
+        Instruments {
+            instruments: instruments.to_vec(),
+            running: vec![],
+            messages: messages.clone(),
+        }
+    }
+
+    /// Start the captures of the instruments starting with the `phase`, which
+    /// are not running already.
+    pub(crate) fn phase_started(&mut self, phase: Phase) {
+        for instrument in &self.instruments {
+            let running = self
+                .running
+                .iter()
+                .any(|running| running.instrument.name == instrument.name);
+            if instrument.from != phase || running {
+                continue;
+            }
+            match start(instrument) {
+                Ok(running) => {
+                    info!("{} capturing to {}", instrument.name, running.file);
+                    self.running.push(running);
+                }
+                Err(e) => println!(
+                    "{}",
+                    style(self.messages.text_with(
+                        "instrument.not_started",
+                        &[("name", &instrument.name), ("error", &e)]
+                    ))
+                    .yellow()
+                ),
+            }
+        }
+    }
+
+    /// Stop the captures of the instruments stopping with the `phase`.
+    pub(crate) fn phase_ended(&mut self, phase: Phase) -> Vec<InstrumentCapture> {
+        self.stop_where(|instrument| instrument.until == phase)
+    }
+
+    /// Stop all the captures.
+    pub(crate) fn stop_all(&mut self) -> Vec<InstrumentCapture> {
+        self.stop_where(|_| true)
+    }
+
+    fn stop_where(&mut self, stopping: impl Fn(&Instrument) -> bool) -> Vec<InstrumentCapture> {
+        let (stopped, running) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|running| stopping(&running.instrument));
+        self.running = running;
+        stopped
+            .into_iter()
+            .map(|running| stop(running, &self.messages))
+            .collect()
+    }
+}
+impl Drop for Instruments {
+    fn drop(&mut self) {
+        self.stop_all();
+    }
+}
+
+fn start(instrument: &Instrument) -> std::io::Result<Running> {
+    let directory = Path::new(&instrument.directory);
+    fs::create_dir_all(directory)?;
+    let started = SystemTime::now();
+    let stamp = started.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let file = directory
+        .join(format!(
+            "{}-{}.{}",
+            instrument.name,
+            stamp.as_secs(),
+            instrument.extension
+        ))
+        .display()
+        .to_string();
+    let command = instrument.command.replace("{file}", &file);
+    // Run in place of the shell, so that terminating it stops the capture.
+    let command = if cfg!(windows) {
+        command
+    } else {
+        format!("exec {}", command)
+    };
+    // The output of the tool would garble the console.
+    let child = shell(&command)
+        .env("BOOTCOM_CAPTURE_FILE", &file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(Running {
+        instrument: instrument.clone(),
+        child,
+        file,
+        started,
+    })
+}
+
+/// Stop the `running` capture, waiting for it to exit from the background.
+fn stop(mut running: Running, messages: &Catalog) -> InstrumentCapture {
+    let capture = InstrumentCapture {
+        name: running.instrument.name.clone(),
+        file: running.file.clone(),
+        started: running.started,
+        stopped: SystemTime::now(),
+    };
+    if let Ok(Some(status)) = running.child.try_wait() {
+        if !status.success() {
+            println!(
+                "{}",
+                style(messages.text_with(
+                    "instrument.exited_early",
+                    &[("name", &capture.name), ("status", &status)]
+                ))
+                .yellow()
+            );
+        }
+        return capture;
+    }
+    let pid = running.child.id().to_string();
+    let stopper = match &running.instrument.stop {
+        Some(command) => Some(
+            shell(command)
+                .env("BOOTCOM_CAPTURE_PID", &pid)
+                .env("BOOTCOM_CAPTURE_FILE", &running.file)
+                .stdin(Stdio::null())
+                .spawn(),
+        ),
+        // There are no signals to ask for it politely.
+        None if cfg!(windows) => None,
+        None => Some(Command::new("kill").args(["-TERM", &pid]).spawn()),
+    };
+    let mut stopper = stopper.and_then(|stopper| {
+        stopper
+            .map_err(|e| info!("could not stop the {} capture: {}", capture.name, e))
+            .ok()
+    });
+    thread::spawn(move || {
+        if let Some(stopper) = &mut stopper {
+            let _ = stopper.wait();
+        }
+        let deadline = Instant::now() + STOP_GRACE;
+        while Instant::now() < deadline {
+            match running.child.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(50)),
+                _ => return,
+            }
+        }
+        let _ = running.child.kill();
+        let _ = running.child.wait();
+    });
+    capture
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(unix)]
+#[test]
+fn captures_follow_the_phases() {
+    let dir = std::env::temp_dir().join(format!("bootcom-instruments-{}", std::process::id()));
+    let instrument = |name: &str, from, until| Instrument {
+        name: name.into(),
+        command: "sh -c 'echo started > {file}; exec sleep 30'".into(),
+        stop: None,
+        from,
+        until,
+        directory: dir.display().to_string(),
+        extension: "txt".into(),
+    };
+    let mut instruments = Instruments::new(
+        &[
+            instrument("logic", Phase::Trigger, Phase::Transfer),
+            instrument("power", Phase::Transfer, Phase::Boot),
+        ],
+        &Catalog::default(),
+    );
+
+    instruments.phase_started(Phase::Trigger);
+    instruments.phase_started(Phase::Trigger);
+    assert_eq!(instruments.running.len(), 1);
+    assert!(instruments.phase_ended(Phase::Trigger).is_empty());
+    instruments.phase_started(Phase::Transfer);
+    thread::sleep(Duration::from_millis(200));
+    let captures = instruments.phase_ended(Phase::Transfer);
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].name, "logic");
+    assert!(captures[0].file.ends_with(".txt"));
+    assert_eq!(fs::read_to_string(&captures[0].file).unwrap(), "started\n");
+    assert!(captures[0].stopped >= captures[0].started);
+
+    let captures = instruments.stop_all();
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].name, "power");
+    assert!(instruments.running.is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
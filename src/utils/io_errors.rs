@@ -0,0 +1,41 @@
+//! Classification of the I/O errors reported by the serial port.
+//!
+//! Depending on the platform and on the driver, a read or a write on a serial
+//! port can fail without anything being wrong with the port: the operation
+//! timed out before any data was available, it was interrupted by a signal, or
+//! it would have blocked. Such errors are not worth tearing down the session;
+//! the operation is simply tried again later.
+
+use std::io;
+
+/// Whether `e` only means that the operation did not complete this time and
+/// can be tried again, as opposed to a real failure of the port.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn transient_errors() {
+    for kind in &[
+        io::ErrorKind::TimedOut,
+        io::ErrorKind::Interrupted,
+        io::ErrorKind::WouldBlock,
+    ] {
+        assert!(is_transient(&io::Error::from(*kind)), "{:?}", kind);
+    }
+    for kind in &[
+        io::ErrorKind::BrokenPipe,
+        io::ErrorKind::NotFound,
+        io::ErrorKind::PermissionDenied,
+        io::ErrorKind::Other,
+    ] {
+        assert!(!is_transient(&io::Error::from(*kind)), "{:?}", kind);
+    }
+}
@@ -1,221 +1,912 @@
-//! Helper functions to send the kernel data over the serial port.
-
-use std::fs;
-use std::{convert::TryInto, io::prelude::*};
-use std::{error::Error, fs::File};
-
-use console::{style, Term};
-use dialoguer::{theme::ColorfulTheme, Select};
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, error, info, log_enabled, trace, Level::Debug};
-use serialport::{ClearBuffer, SerialPort};
-
-use hexplay::HexViewBuilder;
-use std::io::Write;
-
-use crate::Settings;
-
-pub(crate) fn send_kernel(
-    port: &mut Box<dyn SerialPort>,
-    settings: &Settings,
-) -> Result<usize, Box<dyn Error>> {
-    let image_path = match &settings.kernel_image {
-        Some(value) => value.clone(),
-        None => "kernel8.img".into(),
-    };
-
-    let mut open_result = File::open(&image_path);
-    if let Err(e) = open_result {
-        debug!("`{}` error: {}", &image_path, e);
-        debug!("Looking for an image file in current directory");
-
-        loop {
-            match select_image_file_interactive() {
-                Some(ref name) => {
-                    if name.ends_with("cancel and go back...") {
-                        return Ok(0);
-                    }
-                    open_result = File::open(name);
-                    if let Err(ref e) = open_result {
-                        debug!("`{}` error: {}", name, e);
-                        println!(
-                            "{}",
-                            style(format!("[BC] 🙁 could not open `{}`, try again...", name))
-                                .yellow()
-                        );
-                    } else {
-                        break;
-                    }
-                }
-                None => {
-                    debug!("No kernel image file was selected!");
-                    // Try again with arefreshed list of files
-                }
-            }
-        }
-    }
-
-    let mut file = open_result?;
-
-    let size = file.metadata()?.len();
-    if size > 0xffffffff {
-        // The kernel file is too big for the current bootloader protocol which
-        // only allows for 4 bytes to be sent for the kernel size.
-        return Err(serialport::Error {
-            kind: serialport::ErrorKind::InvalidInput,
-            description: "kernel file is too big".into(),
-        }
-        .into());
-    }
-
-    write_kernel_size(port, size as u32)?;
-
-    write_kernel_image(port, &mut file, size as u32)?;
-
-    Ok(0)
-}
-
-fn write_kernel_size(port: &mut Box<dyn SerialPort>, size: u32) -> Result<(), Box<dyn Error>> {
-    use retry::{delay, retry};
-
-    // Clear the port input buffer
-    port.clear(ClearBuffer::Input)?;
-
-    // Write the 4 bytes for the size in little endian
-    let bytes = size.to_le_bytes();
-    port.write_all(&bytes)?;
-
-    // Expect a response with 'O''K' coming back from the bootloader
-    let mut ok: Vec<u8> = vec![0; 2];
-    let result = retry(
-        delay::Fixed::from_millis(1000).take(9),
-        || -> Result<usize, Box<dyn Error>> {
-            let available = port.bytes_to_read()?;
-            trace!("Bytes available to read: {}", available);
-
-            if available >= 2 {
-                port.read_exact(ok.as_mut_slice()).unwrap();
-                return Ok(2);
-            }
-
-            Err(serialport::Error {
-                kind: serialport::ErrorKind::Unknown,
-                description: "did not receive OK in time".into(),
-            }
-            .into())
-        },
-    );
-
-    match result {
-        Ok(2) => {
-            // Dump the received data in a hex table for
-            // debugging
-            if log_enabled!(Debug) {
-                let view = HexViewBuilder::new(&ok)
-                    .address_offset(0)
-                    .row_width(16)
-                    .finish();
-                println!("{}", view);
-            }
-            Ok(())
-        }
-        _ => {
-            info!("error: {:?}", result.unwrap_err());
-            Err(serialport::Error {
-                kind: serialport::ErrorKind::InvalidInput,
-                description: "kernel size was not confirmed with `OK`".into(),
-            }
-            .into())
-        }
-    }
-}
-
-fn write_kernel_image(
-    port: &mut Box<dyn SerialPort>,
-    file: &mut File,
-    size: u32,
-) -> Result<(), serialport::Error> {
-    let mut written: usize = 0;
-    let mut chunk: Vec<u8> = vec![0; 1024];
-
-    let pb = ProgressBar::new(size.into());
-    pb.set_style(ProgressStyle::default_bar()
-        .template("[BC] ⏩ Pushing [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .progress_chars("=>-"));
-
-    while (written as u32) < size {
-        let bytes_in = file.read(&mut chunk)?;
-        trace!("{} bytes read from input file", { bytes_in });
-        loop {
-            match port.write(&chunk[..bytes_in]) {
-                Ok(bytes_out) => {
-                    trace!("{} bytes written to serial port", { bytes_out });
-                    assert_eq!(bytes_in, bytes_out);
-
-                    written += bytes_in;
-                    pb.set_position(written.try_into().unwrap());
-                    break;
-                }
-                Err(err) => {
-                    if err.kind() == std::io::ErrorKind::TimedOut {
-                    } else {
-                        error!("{}", err);
-                        return Err(err.into());
-                    }
-                }
-            }
-            std::thread::sleep(std::time::Duration::from_millis(50));
-        }
-    }
-    pb.finish_with_message("[BC] Kernel uploaded");
-
-    Ok(())
-}
-
-fn select_image_file_interactive() -> Option<String> {
-    // List files ending with ".img" in the current working directory and
-    // ask the user to select one out of them.
-    match fs::read_dir(".") {
-        Ok(files) => {
-            let mut items: Vec<String> = Vec::new();
-            files
-                .filter_map(Result::ok)
-                .filter(|f| f.path().extension().unwrap_or_default() == "img")
-                .for_each(|f| {
-                    let name = f.file_name();
-                    items.push(name.to_str().unwrap().into());
-                });
-
-            if items.is_empty() {
-                debug!("There are no image files in the current directory");
-            }
-
-            items.push("🔙cancel and go back...".into());
-
-            let selection = Select::with_theme(&ColorfulTheme::default())
-                .items(&items)
-                .with_prompt(format!(
-                    "Select a kernel image file to push (`{}` to refresh):",
-                    style("Esc").cyan()
-                ))
-                .default(0)
-                .interact_on_opt(&Term::stdout());
-
-            match selection {
-                Ok(Some(index)) => Some(items[index].clone()),
-                Ok(None) => {
-                    debug!("user did not select any kernel image file");
-                    None
-                }
-                Err(ref e) => {
-                    info!("error: {}", e.to_string());
-                    None
-                }
-            }
-        }
-        Err(ref e) => {
-            info!("error: {}", e.to_string());
-            None
-        }
-    }
-}
+//! Helper functions to send the kernel data over the link to the device.
+//!
+//! An upload with the `raspbootin` protocol that fails partway (a timeout, the
+//! board unplugged) is remembered as [`Interrupted`], and the device can offer
+//! to resume it on the next one: it confirms the size with `OR` and the number
+//! of bytes it already holds, and the image is sent from there when it is the
+//! same one and it went that far, from the start otherwise.
+//!
+//! The wire format of the `raspbootin` protocol has a revision,
+//! [`PROTOCOL_REVISION`]. A device reporting its own one sends `OV` and its
+//! revision before confirming the size, and is told the revision of `bootcom`
+//! in return. The two not agreeing is warned about loudly, as the size and the
+//! image may then be read differently (byte order, format) without any error.
+//! The devices which don't report it are taken for the revision 1.
+
+use std::convert::TryInto;
+use std::time::{Duration, Instant, SystemTime};
+use std::{error::Error, fs::File};
+use std::{fmt, fs, io, path::Path, thread};
+
+use console::{style, Term};
+use dialoguer::{theme::ColorfulTheme, Select};
+use log::{debug, error, info, log_enabled, trace, Level::Debug};
+
+use hexplay::HexViewBuilder;
+
+use super::{
+    chaos, chunked, encryption, is_transient, suspend, trailer, xmodem, Attempts, Crc32, HumanSize,
+    ImageChanged, KernelImage, SoftFlow,
+};
+use crate::{
+    clock::clock,
+    messages::Catalog,
+    pcap::{self, Traffic},
+    progress::{TransferProgress, TransferReport},
+    protocol::{device, host, Line},
+    settings::{Settings, TransferProtocol},
+    signing,
+    transport::Transport,
+};
+
+/// The size of the chunks of the kernel image written at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// The size of the chunks with software flow control, small enough for the
+/// device to pause the transfer before its input buffer overflows.
+const FLOW_CONTROLLED_CHUNK_SIZE: usize = 1024;
+
+/// What the device sends back to confirm the size of the image.
+pub(crate) const SIZE_CONFIRMATION: &[u8; 2] = b"OK";
+
+/// What the device sends back instead of [`SIZE_CONFIRMATION`], followed by the
+/// number of bytes it holds, to offer resuming an interrupted upload.
+pub(crate) const RESUME_OFFER: &[u8; 2] = b"OR";
+
+/// What the device sends back before confirming the size of the image,
+/// followed by the revision of the protocol it speaks, to have it checked.
+pub(crate) const REVISION_REPORT: &[u8; 2] = b"OV";
+
+/// The revision of the wire format of the `raspbootin` protocol spoken by
+/// `bootcom`, bumped with every change a device has to follow: the revision 2
+/// added the report of the revisions.
+pub(crate) const PROTOCOL_REVISION: u8 = 2;
+
+/// How long the device has to answer in the middle of the handshake: with the
+/// size confirmation after its revision, or where to resume the upload from.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the device has to confirm the size of the image, and how often
+/// the confirmation is looked for meanwhile.
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(9);
+const CONFIRMATION_POLL: Duration = Duration::from_secs(1);
+
+/// The longest the output of the device is drained after a transfer, should it
+/// never stay quiet.
+const MAX_FLUSH: Duration = Duration::from_secs(5);
+
+/// Why sending the kernel image failed.
+#[derive(Debug)]
+pub(crate) enum SendError {
+    /// The kernel image can't be read, or can't be sent with the protocol.
+    Image(Box<dyn Error>),
+    /// The communication with the device failed.
+    Port(Box<dyn Error>),
+    /// The kernel image file was rewritten during the transfer, which was
+    /// aborted.
+    ImageChanged,
+    /// The image was transferred, but the device could not persist it.
+    Persist(Box<dyn Error>),
+}
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Image(e) => write!(f, "kernel image error: {}", e),
+            SendError::Port(e) => write!(f, "{}", e),
+            SendError::ImageChanged => write!(f, "{}", ImageChanged),
+            SendError::Persist(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for SendError {}
+
+/// An upload of the kernel image that failed partway, which the device may
+/// offer to resume.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Interrupted {
+    /// The CRC-32 of the whole image as sent, telling it is the same one.
+    pub crc: u32,
+    /// The size of the image as sent.
+    pub len: usize,
+    /// The number of bytes written before the failure, some of which may
+    /// never have reached the device.
+    pub written: usize,
+}
+impl Interrupted {
+    /// Where to resume the upload of the `image` from, given the `offset` the
+    /// device holds the beginning of it up to: there when this upload was the
+    /// one of the image and went that far, from the start otherwise.
+    fn resume_from(interrupted: Option<&Self>, image: &[u8], offset: usize) -> usize {
+        match interrupted {
+            Some(interrupted)
+                if offset <= interrupted.written
+                    && interrupted.len == image.len()
+                    && interrupted.crc == crc_of(image) =>
+            {
+                offset
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Send the kernel `image`, or the one from the `settings` if not given, with
+/// the given `protocol`.
+///
+/// Returns the report of the transfer, also given to the progress observer of
+/// the `settings`, or `None` if the user canceled the image selection. The
+/// upload is resumed when the device offers it and it was the `interrupted`
+/// one, which is updated with this upload should it fail partway.
+pub(crate) fn send_kernel<T: Transport>(
+    port: &mut T,
+    settings: &Settings,
+    protocol: TransferProtocol,
+    image: Option<&str>,
+    interrupted: &mut Option<Interrupted>,
+) -> Result<Option<TransferReport>, SendError> {
+    let (file, path) = match open_kernel_image(settings, image).map_err(SendError::Image)? {
+        Some(opened) => opened,
+        // The user canceled the image selection
+        None => return Ok(None),
+    };
+
+    let mut image = KernelImage::read(file).map_err(SendError::Image)?;
+    if let Some(metadata) = &settings.trailer {
+        let built = image.modified().unwrap_or_else(SystemTime::now);
+        let bytes = trailer::build(metadata, image.as_bytes(), built);
+        debug!("{} bytes of trailer appended to the image", bytes.len());
+        image = image.append(&bytes);
+    }
+    if let Some(key) = &settings.signing_key {
+        let header = signing::sign(key, image.as_bytes()).map_err(SendError::Image)?;
+        image = image.prepend(&header);
+    }
+    let _traffic = pcap::mark(settings, Traffic::Transfer(protocol));
+    let size = image.len() as u64;
+    if let Some(key) = encryption::key_for(settings) {
+        let sealed = encryption::seal(key, protocol, size_field(size)?, image.as_bytes())
+            .map_err(SendError::Image)?;
+        image = image.replace(sealed);
+    }
+    let mut flow = SoftFlow::new(settings.flow_control);
+    if settings.persist && protocol != TransferProtocol::Chunked {
+        println!(
+            "{}",
+            style(settings.messages.text("kernel.not_persisted")).yellow()
+        );
+    }
+    let started = Instant::now();
+    let mut resumed_from = 0;
+    let mut written = 0;
+    let previous = interrupted.take();
+    let mut transfer = |port: &mut dyn Transport| match protocol {
+        TransferProtocol::Raspbootin => {
+            let mut response = [0; SIZE_CONFIRMATION.len()];
+            write_kernel_size(settings, port, &mut flow, size_field(size)?, &mut response)
+                .map_err(SendError::Port)?;
+            if &response == REVISION_REPORT {
+                response =
+                    exchange_revisions(settings, port, &mut flow).map_err(SendError::Port)?;
+            }
+            encryption::confirm(settings, &response).map_err(|e| SendError::Image(e.into()))?;
+            if &response == RESUME_OFFER {
+                resumed_from = resume(
+                    settings,
+                    port,
+                    &mut flow,
+                    previous.as_ref(),
+                    image.as_bytes(),
+                )
+                .map_err(SendError::Port)?;
+            }
+
+            written = resumed_from;
+            write_kernel_image(port, settings, &mut flow, &image, &mut written)
+                .map_err(transfer_error)?;
+            Ok(0)
+        }
+        TransferProtocol::Chunked => {
+            chunked::send(port, settings, &mut flow, &image, size_field(size)?)
+                .map_err(transfer_error)
+        }
+        TransferProtocol::XmodemCrc => {
+            xmodem::send(port, settings, &mut flow, &image).map_err(transfer_error)
+        }
+        TransferProtocol::Ymodem => {
+            let name = Path::new(&path).file_name().unwrap_or_default();
+            xmodem::send_ymodem(port, settings, &mut flow, &image, &name.to_string_lossy())
+                .map_err(transfer_error)
+        }
+    };
+    let result = match &settings.chaos {
+        Some(chaos) => chaos::inject(port, chaos, &settings.messages, transfer),
+        None => transfer(port),
+    };
+    // Only the communication failures leave the device with the beginning of
+    // the image the next upload is for.
+    if let Err(SendError::Port(_)) = result {
+        if written > 0 {
+            info!("upload interrupted after {} bytes", written);
+            *interrupted = Some(Interrupted {
+                crc: crc_of(image.as_bytes()),
+                len: image.len(),
+                written,
+            });
+        }
+    }
+    let retries = result?;
+
+    let report = TransferReport {
+        image: path,
+        protocol,
+        bytes: size,
+        duration: started.elapsed(),
+        retries,
+        crc: crc_of(image.as_bytes()),
+        resumed_from: resumed_from as u64,
+        output: vec![],
+    };
+    info!("kernel image CRC-32: {:#010x}", report.crc);
+    TransferProgress::report(settings, &report);
+    Ok(Some(report))
+}
+
+/// The messages of the `raspbootin` protocol, the image being written in
+/// smaller chunks when `flow_controlled`.
+pub(crate) fn wire_format(flow_controlled: bool) -> Vec<Line> {
+    vec![
+        host("size of the image (u32 LE)"),
+        device(format!(
+            "optionally, `{}` | the revision of the protocol it speaks (u8), answered with the \
+             one of bootcom (u8), {}, before the following",
+            String::from_utf8_lossy(REVISION_REPORT),
+            PROTOCOL_REVISION
+        )),
+        device(format!(
+            "`{}`, or `{}` | the number of bytes of the image it holds from an interrupted \
+             upload (u32 LE)",
+            String::from_utf8_lossy(SIZE_CONFIRMATION),
+            String::from_utf8_lossy(RESUME_OFFER)
+        )),
+        host(format!(
+            "after `{}`, the offset the image is sent from (u32 LE): the same one when the \
+             interrupted upload was the one of this image and went that far, `0` otherwise",
+            String::from_utf8_lossy(RESUME_OFFER)
+        )),
+        host(format!(
+            "the image from that offset, written {} at a time",
+            HumanSize(chunk_size(flow_controlled) as u64)
+        )),
+        device(
+            "optionally, the CRC-32 of the image (u32 LE), to compare with the one of the \
+             transfer report",
+        ),
+    ]
+}
+
+/// The kernel size as sent to the bootloader, which only allows for 4 bytes.
+fn size_field(size: u64) -> Result<u32, SendError> {
+    size.try_into().map_err(|_| {
+        SendError::Image(
+            format!(
+                "kernel file of {} is too big, the bootloader takes up to {}",
+                HumanSize(size),
+                HumanSize(u32::MAX.into())
+            )
+            .into(),
+        )
+    })
+}
+
+/// The CRC-32 of the `image`.
+fn crc_of(image: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(image);
+    crc.finalize()
+}
+
+/// Read the `N` bytes the device answers within the handshake, failing with
+/// the `missing` error when they don't come in time.
+fn read_reply<const N: usize>(
+    settings: &Settings,
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    missing: &str,
+) -> Result<[u8; N], Box<dyn Error>> {
+    let clock = clock(settings);
+    let mut reply = vec![];
+    let started = clock.now();
+    while reply.len() < N {
+        if clock.now() - started > REPLY_TIMEOUT {
+            return Err(
+                serialport::Error::new(serialport::ErrorKind::InvalidInput, missing).into(),
+            );
+        }
+        if port.bytes_to_read()? == 0 {
+            clock.sleep(Duration::from_millis(1));
+            continue;
+        }
+        let mut bytes = [0u8; N];
+        let wanted = N - reply.len();
+        match port.read(&mut bytes[..wanted]) {
+            Ok(read) => reply.extend_from_slice(&flow.receive(&bytes[..read])),
+            Err(ref e) if is_transient(e) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(&reply);
+    Ok(bytes)
+}
+
+/// Read the revision of the protocol the device reported, answer the one of
+/// `bootcom`, warning when they differ, and return the confirmation of the size
+/// which follows.
+fn exchange_revisions(
+    settings: &Settings,
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+) -> Result<[u8; SIZE_CONFIRMATION.len()], Box<dyn Error>> {
+    let [revision] = read_reply(
+        settings,
+        port,
+        flow,
+        "the device did not tell its revision of the protocol",
+    )?;
+    write_chunk(port, flow, &[PROTOCOL_REVISION])?;
+    if revision == PROTOCOL_REVISION {
+        debug!(
+            "the device speaks the revision {} of the protocol",
+            revision
+        );
+    } else {
+        for line in revision_mismatch(&settings.messages, revision) {
+            println!("{}", style(line).red().bold());
+        }
+    }
+    read_reply(
+        settings,
+        port,
+        flow,
+        "the device did not confirm the size with `OK`",
+    )
+}
+
+/// The loud warning about the device speaking the `revision` of the protocol,
+/// other than the one of `bootcom`, with what to do about it.
+fn revision_mismatch(messages: &Catalog, revision: u8) -> Vec<String> {
+    let revisions: &[(&str, &dyn fmt::Display)] =
+        &[("device", &revision), ("bootcom", &PROTOCOL_REVISION)];
+    let guidance = if revision < PROTOCOL_REVISION {
+        "kernel.revision_older"
+    } else {
+        "kernel.revision_newer"
+    };
+    vec![
+        messages.text_with("kernel.revision_mismatch", revisions),
+        messages.text("kernel.revision_misread"),
+        messages.text_with(guidance, revisions),
+    ]
+}
+
+/// Read the offset the device offers to resume the upload of the `image`
+/// from, and answer the one it is sent from, given the `interrupted` upload.
+fn resume(
+    settings: &Settings,
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    interrupted: Option<&Interrupted>,
+    image: &[u8],
+) -> Result<usize, Box<dyn Error>> {
+    let offset = read_reply(
+        settings,
+        port,
+        flow,
+        "the device did not tell where to resume the upload from",
+    )?;
+    let offered = u32::from_le_bytes(offset) as usize;
+    let from = Interrupted::resume_from(interrupted, image, offered);
+    write_chunk(port, flow, &(from as u32).to_le_bytes())?;
+    if from > 0 {
+        println!(
+            "{}",
+            settings.messages.text_with(
+                "kernel.resuming",
+                &[
+                    ("from", &style(HumanSize(from as u64)).cyan()),
+                    ("size", &HumanSize(image.len() as u64))
+                ]
+            )
+        );
+    } else {
+        debug!(
+            "the device holds {} bytes of another upload, sent from the start",
+            offered
+        );
+    }
+    Ok(from)
+}
+
+/// The image changing during the transfer is not a communication failure.
+fn transfer_error(e: Box<dyn Error>) -> SendError {
+    if e.is::<ImageChanged>() {
+        SendError::ImageChanged
+    } else if e.is::<encryption::EncryptionMismatch>() {
+        SendError::Image(e)
+    } else if e.is::<chunked::PersistError>() {
+        SendError::Persist(e)
+    } else {
+        SendError::Port(e)
+    }
+}
+
+/// Open the kernel `image`, or the one from the settings (or `kernel8.img` by
+/// default),
+/// falling back to an interactive selection of the image files in the current
+/// directory if it can't be opened and the keyboard is read.
+///
+/// Returns the file opened along with its path, `None` if the user canceled the
+/// selection, or an error when no image could be opened within the attempts
+/// allowed by the retry policy.
+fn open_kernel_image(
+    settings: &Settings,
+    image: Option<&str>,
+) -> Result<Option<(File, String)>, Box<dyn Error>> {
+    let mut image_path = match image.or(settings.kernel_image.as_deref()) {
+        Some(value) => value.to_string(),
+        None => "kernel8.img".into(),
+    };
+
+    let mut open_result = File::open(&image_path);
+    // Nobody to ask for another image.
+    if !settings.keyboard {
+        return Ok(Some((open_result?, image_path)));
+    }
+    if let Err(e) = open_result {
+        debug!("`{}` error: {}", &image_path, e);
+        debug!("Looking for an image file in current directory");
+
+        let mut selections = Attempts::new(
+            "selecting a kernel image",
+            settings.retry.selection_attempts,
+        );
+        loop {
+            match select_image_file_interactive(settings) {
+                Some(ref name) => {
+                    if *name == settings.messages.text("prompt.cancel") {
+                        return Ok(None);
+                    }
+                    open_result = File::open(name);
+                    if let Err(ref e) = open_result {
+                        debug!("`{}` error: {}", name, e);
+                        selections.failed()?;
+                        println!(
+                            "{}",
+                            style(settings.messages.text_with(
+                                "kernel.not_opened",
+                                &[("name", name), ("attempts", &selections)]
+                            ))
+                            .yellow()
+                        );
+                    } else {
+                        image_path = name.clone();
+                        break;
+                    }
+                }
+                None => {
+                    debug!("No kernel image file was selected!");
+                    // Try again with arefreshed list of files
+                    selections.failed()?;
+                }
+            }
+        }
+    }
+
+    Ok(Some((open_result?, image_path)))
+}
+
+/// The 4 bytes announcing the `size` of the image, in little endian.
+pub(crate) fn size_frame(size: u32) -> [u8; 4] {
+    size.to_le_bytes()
+}
+
+/// Send the `size` of the image and wait for the device to confirm it with the
+/// `response`, filled with what it sent back (`OK` followed by anything the
+/// protocol expects).
+pub(super) fn write_kernel_size(
+    settings: &Settings,
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    size: u32,
+    response: &mut [u8],
+) -> Result<(), Box<dyn Error>> {
+    // Clear the port input buffer, but not before the device had a chance to
+    // ask for a pause.
+    flow.wait_until_resumed(port)?;
+    port.clear_input()?;
+
+    port.write_all(&flow.encode(&size_frame(size)))?;
+
+    // Expect a response with 'O''K' coming back from the bootloader
+    let expected = response.len();
+    let mut confirm = || -> Result<bool, Box<dyn Error>> {
+        let available = port.bytes_to_read()?;
+        trace!("Bytes available to read: {}", available);
+        if (available as usize) < expected {
+            return Ok(false);
+        }
+        port.read_exact(response)?;
+        Ok(true)
+    };
+    let clock = clock(settings);
+    let started = clock.now();
+    loop {
+        match confirm() {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => info!("error: {:?}", e),
+        }
+        if clock.now() - started >= CONFIRMATION_TIMEOUT {
+            info!("error: did not receive OK in time");
+            return Err(serialport::Error {
+                kind: serialport::ErrorKind::InvalidInput,
+                description: "kernel size was not confirmed with `OK`".into(),
+            }
+            .into());
+        }
+        clock.sleep(CONFIRMATION_POLL);
+    }
+
+    // Dump the received data in a hex table for debugging
+    if log_enabled!(Debug) {
+        let view = HexViewBuilder::new(response)
+            .address_offset(0)
+            .row_width(16)
+            .finish();
+        println!("{}", view);
+    }
+    Ok(())
+}
+
+/// Write the kernel image to the port, chunk by chunk, from the offset already
+/// `written`, which is kept up to date.
+///
+/// Each chunk is written straight from the image in memory, as is unless it
+/// needs escaping for the software flow control. Writes may be partial at high
+/// baud rates, the rest of the chunk is written on the next ones.
+///
+/// The transfer is aborted as soon as a change of the image file is noticed;
+/// there is no way to tell the bootloader, which is left waiting for the rest.
+fn write_kernel_image(
+    port: &mut dyn Transport,
+    settings: &Settings,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    written: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    let size = image.len();
+    let chunk_size = chunk_size(flow.is_enabled());
+    let progress = TransferProgress::start(settings, size as u64);
+    progress.update(*written as u64);
+
+    for chunk in image.as_bytes()[*written..].chunks(chunk_size) {
+        write_chunk(port, flow, chunk)?;
+        image.check_unchanged()?;
+        *written += chunk.len();
+        progress.update((*written).try_into().unwrap());
+    }
+    progress.device_output(&drain_output(port, flow, settings.flush_window));
+    progress.finish((*written).try_into().unwrap());
+
+    Ok(())
+}
+
+/// The size of the chunks of the image written at once.
+fn chunk_size(flow_controlled: bool) -> usize {
+    // Large chunks keep the serial driver busy, but the device pausing the
+    // transfer is only checked between two chunks.
+    if flow_controlled {
+        FLOW_CONTROLLED_CHUNK_SIZE
+    } else {
+        CHUNK_SIZE
+    }
+}
+
+/// Write a `chunk` of the image, escaped for the software flow control, and
+/// retrying the partial writes and the transient errors.
+pub(super) fn write_chunk(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    chunk: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let data = flow.encode(chunk);
+    let mut sent = 0;
+    while sent < data.len() {
+        flow.wait_until_resumed(port)?;
+        match port.write(&data[sent..]) {
+            Ok(0) => {
+                return Err(serialport::Error::new(
+                    serialport::ErrorKind::Io(io::ErrorKind::WriteZero),
+                    "the serial port did not accept any more data",
+                )
+                .into());
+            }
+            Ok(bytes_out) => {
+                trace!("{} bytes written to serial port", { bytes_out });
+                sent += bytes_out;
+            }
+            Err(ref err) if is_transient(err) => {
+                trace!("transient write error: {}", err);
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => {
+                error!("{}", err);
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read what the device prints after a transfer, until it stays quiet for the
+/// `window` (or [`MAX_FLUSH`] at most).
+pub(super) fn drain_output(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    window: Duration,
+) -> Vec<u8> {
+    let mut output = Vec::new();
+    let started = Instant::now();
+    let mut last_data = started;
+    while last_data.elapsed() < window && started.elapsed() < MAX_FLUSH {
+        match port.bytes_to_read() {
+            Ok(0) => thread::sleep(Duration::from_millis(5)),
+            Ok(available) => {
+                let mut data = vec![0; available as usize];
+                match port.read(&mut data) {
+                    Ok(read) => {
+                        output.extend_from_slice(&flow.receive(&data[..read]));
+                        last_data = Instant::now();
+                    }
+                    Err(ref e) if is_transient(e) => {}
+                    // Terminal mode deals with the port errors.
+                    Err(_) => break,
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    output
+}
+
+fn select_image_file_interactive(settings: &Settings) -> Option<String> {
+    // List files ending with ".img" in the current working directory and
+    // ask the user to select one out of them.
+    match fs::read_dir(".") {
+        Ok(files) => {
+            let mut items: Vec<String> = Vec::new();
+            files
+                .filter_map(Result::ok)
+                .filter(|f| f.path().extension().unwrap_or_default() == "img")
+                .for_each(|f| {
+                    let name = f.file_name();
+                    items.push(name.to_str().unwrap().into());
+                });
+
+            if items.is_empty() {
+                debug!("There are no image files in the current directory");
+            }
+
+            items.push(settings.messages.text("prompt.cancel"));
+
+            let _keyboard = suspend();
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .items(&items)
+                .with_prompt(
+                    settings
+                        .messages
+                        .text_with("prompt.kernel_image", &[("key", &style("Esc").cyan())]),
+                )
+                .default(0)
+                .interact_on_opt(&Term::stdout());
+
+            match selection {
+                Ok(Some(index)) => Some(items[index].clone()),
+                Ok(None) => {
+                    debug!("user did not select any kernel image file");
+                    None
+                }
+                Err(ref e) => {
+                    info!("error: {}", e.to_string());
+                    None
+                }
+            }
+        }
+        Err(ref e) => {
+            info!("error: {}", e.to_string());
+            None
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn sent_over_a_memory_transport() {
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+
+    let path = std::env::temp_dir().join(format!("bootcom-{}.img", std::process::id()));
+    fs::write(&path, b"kernel").unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    // The size is confirmed as soon as it is written.
+    let mut device = MemoryTransport::new(|written: &[u8]| match written {
+        [6, 0, 0, 0] => SIZE_CONFIRMATION.to_vec(),
+        _ => vec![],
+    });
+    let image = path.to_str().unwrap();
+    let report = send_kernel(
+        &mut device,
+        &settings,
+        TransferProtocol::Raspbootin,
+        Some(image),
+        &mut None,
+    );
+    fs::remove_file(&path).unwrap();
+    assert_eq!(report.unwrap().unwrap().bytes, 6);
+    assert_eq!(device.written(), b"\x06\0\0\0kernel");
+}
+
+#[test]
+fn interrupted_upload_resumed() {
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+
+    let path = std::env::temp_dir().join(format!("bootcom-resume-{}.img", std::process::id()));
+    fs::write(&path, b"kernel").unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    let send = |interrupted: &mut Option<Interrupted>| {
+        // The device holds the first 3 bytes.
+        let mut device = MemoryTransport::new(|written: &[u8]| match written {
+            [6, 0, 0, 0] => b"OR\x03\0\0\0".to_vec(),
+            _ => vec![],
+        });
+        let report = send_kernel(
+            &mut device,
+            &settings,
+            TransferProtocol::Raspbootin,
+            path.to_str(),
+            interrupted,
+        )
+        .unwrap()
+        .unwrap();
+        (report.resumed_from, device.written().to_vec())
+    };
+
+    let mut interrupted = Some(Interrupted {
+        crc: crc_of(b"kernel"),
+        len: 6,
+        written: 4,
+    });
+    assert_eq!(
+        send(&mut interrupted),
+        (3, b"\x06\0\0\0\x03\0\0\0nel".to_vec())
+    );
+    assert_eq!(interrupted, None);
+
+    // Another image, or not that far.
+    for crc in [crc_of(b"kernal"), crc_of(b"kernel")] {
+        let mut interrupted = Some(Interrupted {
+            crc,
+            len: 6,
+            written: 2,
+        });
+        assert_eq!(
+            send(&mut interrupted),
+            (0, b"\x06\0\0\0\0\0\0\0kernel".to_vec())
+        );
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn revisions_exchanged() {
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+
+    let path = std::env::temp_dir().join(format!("bootcom-revision-{}.img", std::process::id()));
+    fs::write(&path, b"kernel").unwrap();
+    let settings = SettingsBuilder::default().keyboard(false).finalize();
+    // The revision 1 being reported, then the size confirmed once told the one
+    // of bootcom.
+    let mut device = MemoryTransport::new(|written: &[u8]| match written {
+        [6, 0, 0, 0] => b"OV\x01".to_vec(),
+        [PROTOCOL_REVISION] => b"OK".to_vec(),
+        _ => vec![],
+    });
+    send_kernel(
+        &mut device,
+        &settings,
+        TransferProtocol::Raspbootin,
+        path.to_str(),
+        &mut None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(device.written(), b"\x06\0\0\0\x02kernel");
+    fs::remove_file(&path).unwrap();
+
+    let messages = Catalog::default();
+    assert!(revision_mismatch(&messages, 1)[2].contains("update the bootloader to the revision 2"));
+    assert!(revision_mismatch(&messages, 3)[2].contains("update bootcom"));
+}
+
+#[test]
+fn handshake_timeouts_follow_the_clock() {
+    use crate::clock::SimulatedClock;
+    use crate::settings::SettingsBuilder;
+    use crate::transport::MemoryTransport;
+    use std::sync::Arc;
+
+    let clock = Arc::new(SimulatedClock::new());
+    let settings = SettingsBuilder::default()
+        .keyboard(false)
+        .clock(clock.clone())
+        .finalize();
+    let mut flow = SoftFlow::new(settings.flow_control);
+    // A device never confirming the size, then one not telling its revision.
+    let mut device = MemoryTransport::new(|_: &[u8]| vec![]);
+    let mut response = [0; SIZE_CONFIRMATION.len()];
+    assert!(write_kernel_size(&settings, &mut device, &mut flow, 6, &mut response).is_err());
+    assert_eq!(clock.elapsed(), CONFIRMATION_TIMEOUT);
+
+    let before = clock.elapsed();
+    assert!(exchange_revisions(&settings, &mut device, &mut flow).is_err());
+    let waited = clock.elapsed() - before;
+    assert!(waited > REPLY_TIMEOUT && waited < 2 * REPLY_TIMEOUT);
+}
+
+/// The throughput of `write_kernel_image` over a pseudo terminal, the median of
+/// 5 transfers of a 32 MiB image, with and without the software flow control.
+/// Run with `cargo test --release write_throughput -- --ignored --nocapture`.
+#[cfg(unix)]
+#[test]
+#[ignore]
+fn write_throughput() {
+    use crate::settings::{FlowControl, SettingsBuilder};
+    use serialport::{SerialPort, TTYPort};
+    use std::io::Read;
+
+    const SIZE: usize = 32 << 20;
+    let path = std::env::temp_dir().join(format!("bootcom-bench-{}.img", std::process::id()));
+    // Random data, with escapes for the software flow control.
+    let mut random = super::SplitMix64(1);
+    let data: Vec<u8> = (0..SIZE).map(|_| random.next() as u8).collect();
+    fs::write(&path, &data).unwrap();
+    for flow_control in [FlowControl::None, FlowControl::Software] {
+        let settings = SettingsBuilder::default()
+            .keyboard(false)
+            .flow_control(flow_control)
+            .finalize();
+        let mut rates = vec![];
+        for _ in 0..5 {
+            let (mut device, slave) = TTYPort::pair().unwrap();
+            let reader = thread::spawn(move || {
+                let mut buf = vec![0; 1 << 16];
+                while device.read(&mut buf).is_ok_and(|read| read > 0) {}
+            });
+            let mut port: Box<dyn SerialPort> = Box::new(slave);
+            let image = KernelImage::read(File::open(&path).unwrap()).unwrap();
+            let mut flow = SoftFlow::new(settings.flow_control);
+            let started = Instant::now();
+            write_kernel_image(&mut port, &settings, &mut flow, &image, &mut 0).unwrap();
+            rates.push(SIZE as f64 / started.elapsed().as_secs_f64() / 1e6);
+            drop(port);
+            reader.join().unwrap();
+        }
+        rates.sort_by(f64::total_cmp);
+        println!("{:?}: {:.1} MB/s", flow_control, rates[2]);
+    }
+    fs::remove_file(&path).unwrap();
+}
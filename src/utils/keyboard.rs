@@ -1,44 +1,383 @@
-use std::io::stdout;
-use std::{process, time::Duration};
-
-use crossterm::{
-    cursor::{Hide, MoveToColumn, Show},
-    event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode},
-    Result,
+//! Keyboard input of the terminal, routed to the parts of `bootcom` waiting for
+//! key presses.
+//!
+//! A single thread reads the keyboard, and it alone switches the terminal to
+//! raw mode: once when the first subscriber arrives, until the last one leaves.
+//! Meanwhile the keyboard is read continuously, and each key pressed is sent to
+//! all the subscribers, which take the keys at their own pace. The text pasted
+//! in the terminal comes as a whole, to the subscribers typing for the device.
+//!
+//! The interactive prompts read the keyboard themselves: while one is shown, it
+//! holds a [`KeyboardSuspension`], and the keyboard thread leaves the terminal
+//! in its normal mode. The resizes of the terminal are read along with the
+//! keys, and handled by the keyboard thread.
+//!
+//! `Ctrl+C` exits `bootcom` as it would out of raw mode, unless a subscriber
+//! types for the device, which then gets it like any other key. The keys typed
+//! for the device are turned back into the bytes a terminal sends for them by
+//! [`key_bytes`].
+
+use std::{
+    process,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Condvar, Mutex, MutexGuard, Once,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-pub(crate) fn poll_escape() -> Result<bool> {
-    enable_raw_mode()?;
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+
+use super::{bracketed_paste, raw_mode, resized};
+
+/// How long the keyboard is polled at once, and so how long the keyboard thread
+/// takes to leave the terminal alone once asked to.
+const POLL: Duration = Duration::from_millis(20);
+
+/// What is typed on the keyboard.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Input {
+    /// A key pressed.
+    Key(KeyEvent),
+    /// Text pasted in the terminal.
+    Paste(String),
+}
+
+struct Subscriber {
+    id: usize,
+    sender: Sender<Input>,
+    /// Whether the subscriber types for the device, getting the pasted text
+    /// and `Ctrl+C`.
+    typing: bool,
+}
+
+struct Router {
+    list: Vec<Subscriber>,
+    next_id: usize,
+    /// The number of prompts reading the keyboard themselves.
+    suspensions: usize,
+    /// Whether the keyboard thread reads the keyboard, in raw mode.
+    reading: bool,
+}
+impl Router {
+    /// Whether the keyboard is to be read by the keyboard thread.
+    fn active(&self) -> bool {
+        !self.list.is_empty() && self.suspensions == 0
+    }
+}
+
+static ROUTER: Mutex<Router> = Mutex::new(Router {
+    list: Vec::new(),
+    next_id: 0,
+    suspensions: 0,
+    reading: false,
+});
+/// Notified whenever the router changes.
+static CHANGED: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+/// A subscription to the key presses, unsubscribed when dropped.
+pub(crate) struct Keys {
+    id: usize,
+    receiver: Receiver<Input>,
+}
+impl Keys {
+    /// Wait up to `timeout` for a key to be pressed, without echoing it, the
+    /// keys pressed since the last call coming first and the pasted text being
+    /// skipped. Returns `None` when no key was pressed or when there is no
+    /// terminal to read from, in which case the whole `timeout` is still
+    /// waited.
+    pub(crate) fn next(&self, timeout: Duration) -> Option<KeyEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(left).ok()? {
+                Input::Key(key) => return Some(key),
+                Input::Paste(_) => (),
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a key to be pressed or for text to be pasted,
+    /// as [`next`](Keys::next) does for the keys.
+    pub(crate) fn next_input(&self, timeout: Duration) -> Option<Input> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
 
-    let mut esc_pressed = false;
+    /// Wait up to `timeout` for the `Esc` key, ignoring the others. Returns
+    /// `true` if it was pressed.
+    pub(crate) fn escape(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut now = Instant::now();
+        while now < deadline {
+            if let Some(key) = self.next(deadline - now) {
+                if key.code == KeyCode::Esc {
+                    return true;
+                }
+            }
+            now = Instant::now();
+        }
+        false
+    }
+}
+impl Drop for Keys {
+    fn drop(&mut self) {
+        let mut router = lock();
+        router.list.retain(|s| s.id != self.id);
+        CHANGED.notify_all();
+        // The terminal is back in its normal mode once the last one is gone.
+        if router.list.is_empty() {
+            wait_until_idle(router);
+        }
+    }
+}
 
-    enable_raw_mode()?;
-    execute!(stdout(), Hide)?;
-    let result = poll(Duration::from_millis(500))?;
-    execute!(stdout(), MoveToColumn(0), Show)?;
-    disable_raw_mode()?;
+/// Keeps the keyboard thread off the terminal for as long as it lives, for a
+/// prompt to read the keyboard.
+#[must_use = "the keyboard thread reads the keyboard again as soon as the suspension is dropped"]
+pub(crate) struct KeyboardSuspension {
+    // Only created by `suspend`.
+    _private: (),
+}
+impl Drop for KeyboardSuspension {
+    fn drop(&mut self) {
+        lock().suspensions -= 1;
+        CHANGED.notify_all();
+    }
+}
 
-    if result {
-        // It's guaranteed that read() wont block if `poll` returns `Ok(true)`
-        let event = read()?;
+/// Stop the keyboard thread from reading the keyboard until the returned guard
+/// is dropped, the terminal back in its normal mode. Suspensions can be
+/// nested.
+pub(crate) fn suspend() -> KeyboardSuspension {
+    let mut router = lock();
+    router.suspensions += 1;
+    CHANGED.notify_all();
+    wait_until_idle(router);
+    KeyboardSuspension { _private: () }
+}
 
-        if event == Event::Key(KeyCode::Esc.into()) {
-            esc_pressed = true;
-        } else if event
-            == Event::Key(KeyEvent {
-                modifiers: KeyModifiers::CONTROL,
-                code: KeyCode::Char('c'),
-            })
+/// The bytes a terminal sends to the device for the `key`, if it sends any: the
+/// UTF-8 of the characters, the control characters of `Ctrl` with a letter or
+/// one of `@[\]^_`, prefixed with `Esc` with `Alt`, and the VT100 sequences of
+/// the editing and cursor keys. The function keys are kept for `bootcom`.
+pub(crate) fn key_bytes(key: &KeyEvent) -> Option<Vec<u8>> {
+    let sequence = |sequence: &str| Some(sequence.as_bytes().to_vec());
+    match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            let control = match c.to_ascii_uppercase() {
+                c @ '@'..='_' => c as u8 - b'@',
+                _ => return None,
+            };
+            match key.modifiers.contains(KeyModifiers::ALT) {
+                true => Some(vec![0x1b, control]),
+                false => Some(vec![control]),
+            }
+        }
+        KeyCode::Char(c) => {
+            let mut bytes = vec![];
+            if key.modifiers.contains(KeyModifiers::ALT) {
+                bytes.push(0x1b);
+            }
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            Some(bytes)
+        }
+        KeyCode::Enter => sequence("\r"),
+        KeyCode::Tab => sequence("\t"),
+        KeyCode::BackTab => sequence("\x1b[Z"),
+        KeyCode::Backspace => sequence("\x7f"),
+        KeyCode::Esc => sequence("\x1b"),
+        KeyCode::Up => sequence("\x1b[A"),
+        KeyCode::Down => sequence("\x1b[B"),
+        KeyCode::Right => sequence("\x1b[C"),
+        KeyCode::Left => sequence("\x1b[D"),
+        KeyCode::Home => sequence("\x1b[H"),
+        KeyCode::End => sequence("\x1b[F"),
+        KeyCode::Insert => sequence("\x1b[2~"),
+        KeyCode::Delete => sequence("\x1b[3~"),
+        KeyCode::PageUp => sequence("\x1b[5~"),
+        KeyCode::PageDown => sequence("\x1b[6~"),
+        _ => None,
+    }
+}
+
+/// Subscribe to the key presses, starting the keyboard thread if needed.
+pub(crate) fn subscribe() -> Keys {
+    start();
+    register(false)
+}
+
+/// Subscribe to the keys typed for the device, `Ctrl+C` included, and to the
+/// text pasted, starting the keyboard thread if needed.
+pub(crate) fn subscribe_typing() -> Keys {
+    start();
+    register(true)
+}
+
+fn start() {
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("keyboard".into())
+            .spawn(route_keys)
+            .expect("could not start the keyboard thread");
+    });
+}
+
+fn register(typing: bool) -> Keys {
+    let (sender, receiver) = mpsc::channel();
+    let mut router = lock();
+    let id = router.next_id;
+    router.next_id += 1;
+    router.list.push(Subscriber { id, sender, typing });
+    CHANGED.notify_all();
+    Keys { id, receiver }
+}
+
+// The keyboard thread may have panicked with the router locked.
+fn lock() -> MutexGuard<'static, Router> {
+    ROUTER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Wait for the keyboard thread to leave the terminal alone.
+fn wait_until_idle(mut router: MutexGuard<'static, Router>) {
+    while router.reading {
+        router = CHANGED.wait(router).unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+/// Mark the keyboard thread as reading or not.
+fn set_reading(reading: bool) {
+    lock().reading = reading;
+    CHANGED.notify_all();
+}
+
+/// The keyboard thread, reading the keyboard in raw mode while there are
+/// subscribers and no prompt.
+fn route_keys() {
+    loop {
         {
-            // As we are in raw mode, Ctrl+C will be captured here as a key
-            // event. Catch it and exit the process if that happens
+            let mut router = lock();
+            while !router.active() {
+                router = CHANGED.wait(router).unwrap_or_else(|e| e.into_inner());
+            }
+            router.reading = true;
+        }
+
+        let raw_mode = match raw_mode() {
+            Ok(raw_mode) => raw_mode,
+            // There is no terminal to read from.
+            Err(_) => {
+                set_reading(false);
+                thread::sleep(POLL);
+                continue;
+            }
+        };
+        let paste = bracketed_paste().ok();
+        let interrupted = read_keys();
+        drop(paste);
+        drop(raw_mode);
+        set_reading(false);
+        if interrupted {
+            // As we are in raw mode, Ctrl+C is captured as a key event.
+            // Exit the process as it would have done otherwise.
             process::exit(0);
         }
-    } else {
-        // Timeout expired with no event
     }
+}
+
+/// Read the keyboard and dispatch what is typed for as long as the keyboard
+/// thread is active. Returns `true` if `Ctrl+C` was pressed while nobody typed
+/// for the device.
+fn read_keys() -> bool {
+    while lock().active() {
+        let event = match poll(POLL) {
+            Ok(true) => read().ok(),
+            _ => None,
+        };
+        match event {
+            Some(Event::Key(key)) if key.kind != KeyEventKind::Release => {
+                let interrupt =
+                    key.code == KeyCode::Char('c') && key.modifiers == KeyModifiers::CONTROL;
+                if interrupt && !lock().list.iter().any(|s| s.typing) {
+                    return true;
+                }
+                dispatch(Input::Key(key));
+            }
+            Some(Event::Paste(text)) => dispatch(Input::Paste(text)),
+            Some(Event::Resize(columns, rows)) => resized(columns, rows),
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Send the `input` to the subscribers, the pasted text only to the ones
+/// typing for the device.
+fn dispatch(input: Input) {
+    let router = lock();
+    let paste = matches!(input, Input::Paste(_));
+    for subscriber in router.list.iter().filter(|s| s.typing || !paste) {
+        let _ = subscriber.sender.send(input.clone());
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn input_goes_to_the_subscribers() {
+    // Registered without the keyboard thread, the input is dispatched by hand.
+    let typing = register(true);
+    let other = register(false);
+    let escape = KeyEvent::from(KeyCode::Esc);
+    dispatch(Input::Key(escape));
+    dispatch(Input::Paste("ls\r".into()));
+    assert_eq!(typing.next_input(Duration::ZERO), Some(Input::Key(escape)));
+    assert_eq!(
+        typing.next_input(Duration::ZERO),
+        Some(Input::Paste("ls\r".into()))
+    );
+    assert_eq!(other.next(Duration::ZERO), Some(escape));
+    assert_eq!(other.next(Duration::ZERO), None);
+
+    // Not reading, the keyboard thread is idle right away.
+    let suspension = suspend();
+    assert!(!lock().active());
+    drop(suspension);
+
+    drop(typing);
+    let router = lock();
+    assert!(router.list.iter().all(|s| s.id == other.id));
+}
 
-    Ok(esc_pressed)
+#[test]
+fn keys_typed_for_the_device() {
+    let key = |code, modifiers| key_bytes(&KeyEvent::new(code, modifiers));
+    assert_eq!(
+        key(KeyCode::Char('é'), KeyModifiers::NONE).unwrap(),
+        "é".as_bytes()
+    );
+    assert_eq!(key(KeyCode::Char('D'), KeyModifiers::SHIFT).unwrap(), b"D");
+    assert_eq!(
+        key(KeyCode::Char('d'), KeyModifiers::CONTROL).unwrap(),
+        b"\x04"
+    );
+    assert_eq!(
+        key(KeyCode::Char('c'), KeyModifiers::CONTROL).unwrap(),
+        b"\x03"
+    );
+    assert_eq!(
+        key(KeyCode::Char('['), KeyModifiers::CONTROL).unwrap(),
+        b"\x1b"
+    );
+    assert_eq!(key(KeyCode::Char('1'), KeyModifiers::CONTROL), None);
+    assert_eq!(
+        key(KeyCode::Char('b'), KeyModifiers::ALT).unwrap(),
+        b"\x1bb"
+    );
+    assert_eq!(key(KeyCode::Enter, KeyModifiers::NONE).unwrap(), b"\r");
+    assert_eq!(key(KeyCode::Up, KeyModifiers::NONE).unwrap(), b"\x1b[A");
+    assert_eq!(key(KeyCode::F(1), KeyModifiers::NONE), None);
 }
@@ -0,0 +1,160 @@
+//! Heuristics warning about suspicious serial parameters, nudging the user
+//! toward the right settings before they waste an hour on a board which
+//! "just doesn't boot".
+//!
+//! Some combinations can be told wrong from the settings alone (a binary
+//! kernel image over 7 data bits), others only from the behavior of the line:
+//! hardware flow control enabled while the device never asserts CTS, or parity
+//! enabled while the device only produces garbage.
+
+use std::time::{Duration, Instant};
+
+use serialport::SerialPort;
+
+use crate::{
+    messages::Catalog,
+    settings::{DataBits, FlowControl, Parity, Settings},
+};
+
+/// How long CTS may stay deasserted, with hardware flow control, before the
+/// user is warned.
+const CTS_GRACE: Duration = Duration::from_secs(3);
+
+/// How often CTS is read.
+const CTS_POLL: Duration = Duration::from_secs(1);
+
+/// Warnings about the settings alone, shown when a session starts.
+pub(crate) fn static_warnings(settings: &Settings) -> Vec<String> {
+    let mut warnings = vec![];
+    if settings.data_bits != DataBits::Eight {
+        warnings.push(settings.messages.text("line_check.data_bits"));
+    }
+    if settings.flow_control == FlowControl::Software && settings.baud_rate >= 1_000_000 {
+        warnings.push(
+            settings
+                .messages
+                .text_with("line_check.xonxoff", &[("baud_rate", &settings.baud_rate)]),
+        );
+    }
+    warnings
+}
+
+/// What the noise storm may tell about the settings, besides a baud rate
+/// mismatch.
+pub(crate) fn noise_hint(settings: &Settings) -> Option<String> {
+    let parity = match settings.parity {
+        Parity::None => return None,
+        Parity::Odd => "odd",
+        Parity::Even => "even",
+    };
+    Some(
+        settings
+            .messages
+            .text_with("line_check.parity", &[("parity", &parity)]),
+    )
+}
+
+/// Tracks the behavior of the line in terminal mode to tell settings which
+/// do not match the device.
+#[derive(Debug)]
+pub(crate) struct LineCheck {
+    /// When the port was opened.
+    opened: Instant,
+    /// When CTS was last read.
+    cts_polled: Option<Instant>,
+    cts_seen: bool,
+    cts_warned: bool,
+}
+impl LineCheck {
+    /// Start tracking a port opened `now`.
+    pub(crate) fn new(now: Instant) -> Self {
+        LineCheck {
+            opened: now,
+            cts_polled: None,
+            cts_seen: false,
+            cts_warned: false,
+        }
+    }
+
+    /// Read CTS from the `port` from time to time when hardware flow control
+    /// is enabled, returning a warning the first time it stayed deasserted for
+    /// too long.
+    pub(crate) fn poll_cts(
+        &mut self,
+        settings: &Settings,
+        port: &mut Box<dyn SerialPort>,
+    ) -> Option<String> {
+        if settings.flow_control != FlowControl::Hardware || self.cts_seen || self.cts_warned {
+            return None;
+        }
+        let now = Instant::now();
+        if matches!(self.cts_polled, Some(polled) if now - polled < CTS_POLL) {
+            return None;
+        }
+        self.cts_polled = Some(now);
+        let asserted = port.read_clear_to_send().ok()?;
+        self.observe_cts(&settings.messages, asserted, now)
+    }
+
+    /// Account for the state of CTS read at `now`.
+    fn observe_cts(&mut self, messages: &Catalog, asserted: bool, now: Instant) -> Option<String> {
+        if asserted {
+            self.cts_seen = true;
+            return None;
+        }
+        if self.cts_warned || now - self.opened < CTS_GRACE {
+            return None;
+        }
+        self.cts_warned = true;
+        Some(messages.text_with(
+            "line_check.cts",
+            &[("seconds", &(now - self.opened).as_secs())],
+        ))
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn cts_never_asserted() {
+    let messages = Catalog::default();
+    let opened = Instant::now();
+    let mut check = LineCheck::new(opened);
+    assert_eq!(
+        check.observe_cts(&messages, false, opened + Duration::from_secs(1)),
+        None
+    );
+    assert!(check
+        .observe_cts(&messages, false, opened + Duration::from_secs(4))
+        .unwrap()
+        .contains("CTS has not been asserted for 4s"));
+    // Only once.
+    assert_eq!(
+        check.observe_cts(&messages, false, opened + Duration::from_secs(8)),
+        None
+    );
+
+    let mut check = LineCheck::new(opened);
+    assert_eq!(
+        check.observe_cts(&messages, true, opened + Duration::from_secs(1)),
+        None
+    );
+    assert!(check.cts_seen);
+}
+
+#[test]
+fn suspicious_settings() {
+    use crate::settings::SettingsBuilder;
+
+    assert!(static_warnings(&SettingsBuilder::default().finalize()).is_empty());
+    assert_eq!(noise_hint(&SettingsBuilder::default().finalize()), None);
+
+    let settings = SettingsBuilder::default()
+        .data_bits(DataBits::Seven)
+        .parity(Parity::Even)
+        .finalize();
+    assert_eq!(static_warnings(&settings).len(), 1);
+    assert!(noise_hint(&settings).unwrap().contains("set to even"));
+}
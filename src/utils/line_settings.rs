@@ -0,0 +1,181 @@
+//! Changes of the line parameters during a session, for the devices which
+//! switch their UART configuration (e.g. a boot loader at 115200 baud handing
+//! over to a kernel at 1500000 baud) without a restart of `bootcom`.
+
+use console::Term;
+use dialoguer::{theme::ColorfulTheme, Input, Select};
+
+use crate::settings::{FlowControl, Parity, Settings};
+
+use super::{render, suspend};
+
+const PARITIES: [(Parity, &str); 3] = [
+    (Parity::None, "none"),
+    (Parity::Odd, "odd"),
+    (Parity::Even, "even"),
+];
+
+const FLOW_CONTROLS: [(FlowControl, &str); 3] = [
+    (FlowControl::None, "none"),
+    (FlowControl::Software, "software (XON/XOFF)"),
+    (FlowControl::Hardware, "hardware (RTS/CTS)"),
+];
+
+/// Ask the user which line parameter to change and its new value, returning
+/// the new settings, or `None` if nothing was changed.
+pub(crate) fn prompt_line_settings(settings: &Settings) -> Option<Settings> {
+    let messages = &settings.messages;
+    let _paused = render::pause();
+    let _keyboard = suspend();
+    let term = Term::stdout();
+    let theme = ColorfulTheme::default();
+    let mut new_settings = settings.clone();
+
+    let items = [
+        format!(
+            "{} ({})",
+            messages.text("prompt.baud_rate"),
+            settings.baud_rate
+        ),
+        format!(
+            "{} ({})",
+            messages.text("prompt.parity"),
+            parity_name(settings.parity)
+        ),
+        format!(
+            "{} ({})",
+            messages.text("prompt.flow_control"),
+            flow_control_name(settings.flow_control)
+        ),
+    ];
+    let selection = Select::with_theme(&theme)
+        .with_prompt(messages.text("prompt.line_parameter"))
+        .items(&items)
+        .default(0)
+        .interact_on_opt(&term)
+        .ok()??;
+    match selection {
+        0 => {
+            new_settings.baud_rate = Input::<u32>::with_theme(&theme)
+                .with_prompt(messages.text("prompt.baud_rate"))
+                .default(settings.baud_rate)
+                .validate_with(|baud_rate: &u32| match baud_rate {
+                    0 => Err(messages.text("prompt.baud_rate_zero")),
+                    _ => Ok(()),
+                })
+                .interact_on(&term)
+                .ok()?;
+        }
+        1 => {
+            let names: Vec<_> = PARITIES.iter().map(|(_, name)| *name).collect();
+            let current = PARITIES.iter().position(|(p, _)| *p == settings.parity);
+            let index = Select::with_theme(&theme)
+                .with_prompt(messages.text("prompt.parity"))
+                .items(&names)
+                .default(current.unwrap_or(0))
+                .interact_on_opt(&term)
+                .ok()??;
+            new_settings.parity = PARITIES[index].0;
+        }
+        _ => {
+            let names: Vec<_> = FLOW_CONTROLS.iter().map(|(_, name)| *name).collect();
+            let current = FLOW_CONTROLS
+                .iter()
+                .position(|(f, _)| *f == settings.flow_control);
+            let index = Select::with_theme(&theme)
+                .with_prompt(messages.text("prompt.flow_control"))
+                .items(&names)
+                .default(current.unwrap_or(0))
+                .interact_on_opt(&term)
+                .ok()??;
+            new_settings.flow_control = FLOW_CONTROLS[index].0;
+        }
+    }
+    if describe_changes(settings, &new_settings).is_empty() {
+        None
+    } else {
+        Some(new_settings)
+    }
+}
+
+/// A change of a line parameter asked for by a client of the console served on
+/// the activated sockets, made when terminal mode gets to it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum LineChange {
+    BaudRate(u32),
+    Parity(Parity),
+    FlowControl(FlowControl),
+}
+impl LineChange {
+    /// The `settings` with the change made.
+    pub(crate) fn apply(self, settings: &Settings) -> Settings {
+        let mut new_settings = settings.clone();
+        match self {
+            LineChange::BaudRate(baud_rate) => new_settings.baud_rate = baud_rate,
+            LineChange::Parity(parity) => new_settings.parity = parity,
+            LineChange::FlowControl(flow_control) => new_settings.flow_control = flow_control,
+        }
+        new_settings
+    }
+}
+
+/// The changes of the line parameters from the `old` settings to the `new`
+/// ones, e.g. `115200 → 1500000 baud`.
+pub(crate) fn describe_changes(old: &Settings, new: &Settings) -> Vec<String> {
+    let mut changes = vec![];
+    if old.baud_rate != new.baud_rate {
+        changes.push(format!("{} → {} baud", old.baud_rate, new.baud_rate));
+    }
+    if old.parity != new.parity {
+        changes.push(format!(
+            "parity {} → {}",
+            parity_name(old.parity),
+            parity_name(new.parity)
+        ));
+    }
+    if old.flow_control != new.flow_control {
+        changes.push(format!(
+            "flow control {} → {}",
+            flow_control_name(old.flow_control),
+            flow_control_name(new.flow_control)
+        ));
+    }
+    changes
+}
+
+fn parity_name(parity: Parity) -> &'static str {
+    PARITIES
+        .iter()
+        .find(|(p, _)| *p == parity)
+        .map_or("?", |(_, name)| name)
+}
+
+fn flow_control_name(flow_control: FlowControl) -> &'static str {
+    FLOW_CONTROLS
+        .iter()
+        .find(|(f, _)| *f == flow_control)
+        .map_or("?", |(_, name)| name)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn changes_are_described() {
+    use crate::settings::SettingsBuilder;
+
+    let old = SettingsBuilder::default().baud_rate(115_200).finalize();
+    assert!(describe_changes(&old, &old).is_empty());
+    let new = SettingsBuilder::default()
+        .baud_rate(1_500_000)
+        .flow_control(FlowControl::Hardware)
+        .finalize();
+    assert_eq!(
+        describe_changes(&old, &new),
+        vec![
+            "115200 → 1500000 baud",
+            "flow control none → hardware (RTS/CTS)"
+        ]
+    );
+}
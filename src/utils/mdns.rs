@@ -0,0 +1,463 @@
+//! Advertisement of the console served on the network with multicast DNS
+//! (DNS-SD), so that the clients on the LAN discover the boards available:
+//!
+//! ```text
+//! $ avahi-browse --resolve _bootcom._tcp
+//! = eth0 IPv4 labhost ttyUSB0    _bootcom._tcp    local
+//!    hostname = [labhost.local]
+//!    address = [192.168.1.20]
+//!    port = [7000]
+//!    txt = ["auth=token" "line=115200 8N1" "image=kernel8.img" "usb=0403:6001"
+//!           "serial=A10KZP3V" "tty=/dev/ttyUSB0" "version=0.1.0"]
+//! ```
+//!
+//! A minimal responder, sharing the mDNS port with the one of the system (e.g.
+//! Avahi) if any, announces the service when `bootcom` starts and answers the
+//! questions about it. The metadata are the ones of the settings `bootcom`
+//! started with.
+
+use std::{
+    env, fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use console::style;
+use log::{debug, info};
+
+use crate::{resume, settings::Settings};
+
+/// The mDNS multicast group and port (RFC 6762).
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// The service type, and the pseudo service listing all the service types.
+const SERVICE: [&str; 3] = ["_bootcom", "_tcp", "local"];
+const SERVICE_TYPES: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set in the class of the records only this host has.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// How long the records are cached: the ones of the host for 2 minutes, the
+/// other ones for 75 minutes.
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+/// A `_bootcom._tcp` service instance.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Advertisement {
+    /// The name of the instance, e.g. `labhost ttyUSB0`.
+    instance: String,
+    /// The name of the host, without `.local`.
+    host: String,
+    /// The TCP port the console is served on.
+    port: u16,
+    /// The metadata of the board, as `key=value` strings.
+    txt: Vec<String>,
+}
+impl Advertisement {
+    /// The advertisement of the console of the device of the `settings`,
+    /// served on the TCP `port`.
+    pub(crate) fn new(settings: &Settings, port: u16) -> Self {
+        let host = host_name();
+        let auth = if settings.access.is_empty() {
+            "none"
+        } else {
+            "token"
+        };
+        let mut txt = vec![
+            format!("auth={}", auth),
+            format!("line={} {}", settings.baud_rate, line_format(settings)),
+        ];
+        if let Some(image) = &settings.kernel_image {
+            let name = Path::new(image).file_name().unwrap_or_default();
+            txt.push(format!("image={}", name.to_string_lossy()));
+        }
+        let mut instance = host.clone();
+        if let Some(path) = &settings.path {
+            if let Some(usb) = resume::identify(path).usb {
+                txt.push(format!("usb={:04x}:{:04x}", usb.vid, usb.pid));
+                if let Some(serial_number) = usb.serial_number {
+                    txt.push(format!("serial={}", serial_number));
+                }
+            }
+            txt.push(format!("tty={}", path));
+            let name = Path::new(path).file_name().unwrap_or_default();
+            instance = format!("{} {}", host, name.to_string_lossy());
+        }
+        txt.push(format!("version={}", env!("CARGO_PKG_VERSION")));
+        Advertisement {
+            instance: truncate_label(instance),
+            host: truncate_label(host),
+            port,
+            txt,
+        }
+    }
+
+    fn instance_name(&self) -> Vec<&str> {
+        let mut name = vec![self.instance.as_str()];
+        name.extend_from_slice(&SERVICE);
+        name
+    }
+
+    fn host_name(&self) -> Vec<&str> {
+        vec![self.host.as_str(), "local"]
+    }
+
+    /// Returns `true` if the mDNS `packet` is a query asking about the
+    /// service.
+    fn is_asked(&self, packet: &[u8]) -> bool {
+        questions(packet).0.iter().any(|(name, kind)| {
+            let is = |expected: &[&str]| {
+                name.len() == expected.len()
+                    && name
+                        .iter()
+                        .zip(expected)
+                        .all(|(label, expected)| *label == expected.to_lowercase())
+            };
+            match *kind {
+                TYPE_PTR => is(&SERVICE) || is(&SERVICE_TYPES),
+                TYPE_SRV | TYPE_TXT => is(&self.instance_name()),
+                TYPE_A => is(&self.host_name()),
+                TYPE_ANY => {
+                    is(&SERVICE)
+                        || is(&SERVICE_TYPES)
+                        || is(&self.instance_name())
+                        || is(&self.host_name())
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// The response with all the records of the service, the host having the
+    /// `address`, repeating the ID and the questions of the `legacy` query it
+    /// answers, if any.
+    fn response(&self, legacy: Option<&[u8]>, address: Ipv4Addr) -> Vec<u8> {
+        let (id, (questions, end)) = match legacy {
+            Some(query) => (&query[..2], questions(query)),
+            None => (&[0, 0][..], (vec![], 12)),
+        };
+        let mut packet = id.to_vec();
+        // An authoritative answer.
+        packet.extend_from_slice(&[0x84, 0x00]);
+        packet.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 5, 0, 0, 0, 0]);
+        if let Some(query) = legacy {
+            packet.extend_from_slice(&query[12..end]);
+        }
+
+        let mut data = vec![];
+        encode_name(&mut data, &SERVICE);
+        record(&mut packet, &SERVICE_TYPES, TYPE_PTR, CLASS_IN, &data);
+
+        data.clear();
+        encode_name(&mut data, &self.instance_name());
+        record(&mut packet, &SERVICE, TYPE_PTR, CLASS_IN, &data);
+
+        data.clear();
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&self.port.to_be_bytes());
+        encode_name(&mut data, &self.host_name());
+        let class = CLASS_IN | CACHE_FLUSH;
+        record(&mut packet, &self.instance_name(), TYPE_SRV, class, &data);
+
+        data.clear();
+        for entry in &self.txt {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry);
+        }
+        record(&mut packet, &self.instance_name(), TYPE_TXT, class, &data);
+
+        record(
+            &mut packet,
+            &self.host_name(),
+            TYPE_A,
+            class,
+            &address.octets(),
+        );
+        packet
+    }
+}
+
+/// Advertise the service in the background, if the mDNS port can be shared.
+pub(crate) fn advertise(settings: &Settings, advertisement: Advertisement) {
+    let socket = match mdns_socket() {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!(
+                "{}",
+                style(
+                    settings
+                        .messages
+                        .text_with("mdns.not_advertised", &[("error", &e)])
+                )
+                .yellow()
+            );
+            return;
+        }
+    };
+    info!(
+        "advertising `{}` on port {}",
+        advertisement.instance, advertisement.port
+    );
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+    if let Ok(announcer) = socket.try_clone() {
+        let advertisement = advertisement.clone();
+        thread::spawn(move || {
+            // Announced twice, a second apart, as the first one may be lost.
+            for _ in 0..2 {
+                let response = advertisement.response(None, local_address());
+                if let Err(e) = announcer.send_to(&response, group) {
+                    debug!("mDNS announcement not sent: {}", e);
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+    thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    info!("mDNS responder stopped: {}", e);
+                    break;
+                }
+            };
+            if !advertisement.is_asked(&buf[..len]) {
+                continue;
+            }
+            // Legacy resolvers, not sending from the mDNS port, only get
+            // unicast answers for their query.
+            let response = if from.port() == MDNS_PORT {
+                advertisement.response(None, local_address())
+            } else {
+                advertisement.response(Some(&buf[..len]), local_address())
+            };
+            let to = if from.port() == MDNS_PORT {
+                group
+            } else {
+                from
+            };
+            if let Err(e) = socket.send_to(&response, to) {
+                debug!("mDNS response not sent: {}", e);
+            }
+        }
+    });
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// A socket receiving the mDNS queries, sharing the port with the responder of
+/// the system, if any.
+fn mdns_socket() -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        MDNS_PORT,
+    )))?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into_udp_socket())
+}
+
+/// The address of the host on the LAN, the one multicast goes out from.
+fn local_address() -> Ipv4Addr {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((MDNS_GROUP, MDNS_PORT))?;
+            socket.local_addr()
+        })
+        .ok()
+        .and_then(|address| match address {
+            SocketAddr::V4(address) => Some(*address.ip()),
+            SocketAddr::V6(_) => None,
+        })
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+/// The short name of the host.
+fn host_name() -> String {
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .and_then(|name| name.trim().split('.').next().map(str::to_owned))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "bootcom".into())
+}
+
+/// The data bits, parity and stop bits of the line, e.g. `8N1`.
+fn line_format(settings: &Settings) -> String {
+    use crate::settings::{DataBits, Parity, StopBits};
+
+    let data_bits = match settings.data_bits {
+        DataBits::Five => '5',
+        DataBits::Six => '6',
+        DataBits::Seven => '7',
+        DataBits::Eight => '8',
+    };
+    let parity = match settings.parity {
+        Parity::None => 'N',
+        Parity::Odd => 'O',
+        Parity::Even => 'E',
+    };
+    let stop_bits = match settings.stop_bits {
+        StopBits::One => '1',
+        StopBits::Two => '2',
+    };
+    format!("{}{}{}", data_bits, parity, stop_bits)
+}
+
+/// The `label` cut to the 63 bytes a DNS label can have at most.
+fn truncate_label(mut label: String) -> String {
+    let mut end = label.len().min(63);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label.truncate(end);
+    label
+}
+
+fn encode_name(packet: &mut Vec<u8>, name: &[&str]) {
+    for label in name {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn record(packet: &mut Vec<u8>, name: &[&str], kind: u16, class: u16, data: &[u8]) {
+    let ttl = if kind == TYPE_A || kind == TYPE_SRV {
+        HOST_TTL
+    } else {
+        OTHER_TTL
+    };
+    encode_name(packet, name);
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&ttl.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+/// The questions of the mDNS `packet`, with their name in lower case, if it is
+/// a query, and the offset right after them.
+fn questions(packet: &[u8]) -> (Vec<(Vec<String>, u16)>, usize) {
+    let mut questions = vec![];
+    let mut offset = 12;
+    if packet.len() < offset || packet[2] & 0x80 != 0 {
+        return (questions, offset);
+    }
+    let count = u16::from_be_bytes([packet[4], packet[5]]);
+    for _ in 0..count {
+        let (name, end) = match read_name(packet, offset) {
+            Some(read) => read,
+            None => break,
+        };
+        let kind = match packet.get(end..end + 4) {
+            Some(fields) => u16::from_be_bytes([fields[0], fields[1]]),
+            None => break,
+        };
+        questions.push((name, kind));
+        offset = end + 4;
+    }
+    (questions, offset)
+}
+
+/// The name at the `offset` of the `packet`, following the compression
+/// pointers, and the offset right after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Bounded, pointers may loop in a malformed packet.
+    for _ in 0..128 {
+        let len = *packet.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let pointer = (len & 0x3f) << 8 | *packet.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+        } else if len == 0 {
+            return Some((labels, end.unwrap_or(offset + 1)));
+        } else {
+            let label = packet.get(offset + 1..offset + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            offset += 1 + len;
+        }
+    }
+    None
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn questions_about_the_service_are_answered() {
+    let advertisement = Advertisement {
+        instance: "labhost ttyUSB0".into(),
+        host: "labhost".into(),
+        port: 7000,
+        txt: vec!["auth=none".into()],
+    };
+    let query = |name: &[&str], kind: u16| {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(&mut packet, name);
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    };
+    assert!(advertisement.is_asked(&query(&["_BOOTCOM", "_tcp", "local"], TYPE_PTR)));
+    assert!(advertisement.is_asked(&query(
+        &["labhost ttyUSB0", "_bootcom", "_tcp", "local"],
+        TYPE_SRV
+    )));
+    assert!(!advertisement.is_asked(&query(&["_http", "_tcp", "local"], TYPE_PTR)));
+    assert!(!advertisement.is_asked(&query(&["labhost", "local"], TYPE_TXT)));
+
+    // A second question pointing to the name of the first one.
+    let mut packet = query(&["x", "local"], TYPE_A);
+    packet[5] = 2;
+    packet.extend_from_slice(&[8]);
+    packet.extend_from_slice(b"_bootcom");
+    packet.extend_from_slice(&[4]);
+    packet.extend_from_slice(b"_tcp");
+    packet.extend_from_slice(&[0xc0, 14, 0, 12, 0, 1]);
+    assert!(advertisement.is_asked(&packet));
+
+    let response = advertisement.response(None, Ipv4Addr::new(192, 168, 1, 20));
+    assert_eq!(&response[..4], &[0, 0, 0x84, 0]);
+    assert!(questions(&response).0.is_empty());
+    let srv = [
+        &[0u8, 0, 0, 0][..],
+        &7000u16.to_be_bytes(),
+        &[7],
+        b"labhost",
+    ]
+    .concat();
+    assert!(response.windows(srv.len()).any(|w| w == srv.as_slice()));
+    assert!(response.ends_with(&[192, 168, 1, 20]));
+
+    let mut legacy = query(&["_bootcom", "_tcp", "local"], TYPE_PTR);
+    legacy[..2].copy_from_slice(&[0x12, 0x34]);
+    let response = advertisement.response(Some(&legacy), Ipv4Addr::LOCALHOST);
+    assert_eq!(&response[..6], &[0x12, 0x34, 0x84, 0, 0, 1]);
+    assert!(response[12..].starts_with(&legacy[12..]));
+}
@@ -0,0 +1,90 @@
+//! Modem control lines of the serial port.
+//!
+//! Some boards wire the modem lines to their reset or bootstrap pins (DTR is a
+//! common choice to select the boot mode), so `bootcom` can show the state of
+//! the input lines (CTS, DSR, CD and RI) and drive the output ones (RTS and
+//! DTR) without needing another tool.
+
+use std::fmt;
+
+use serialport::SerialPort;
+
+/// The state of the modem control lines of a port.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct ModemLines {
+    pub cts: bool,
+    pub dsr: bool,
+    pub cd: bool,
+    pub ri: bool,
+    /// Output lines can't be read back, this is the last state written.
+    pub rts: bool,
+    /// Output lines can't be read back, this is the last state written.
+    pub dtr: bool,
+}
+impl ModemLines {
+    /// Start tracking the lines of a port just opened, on which the output
+    /// lines are asserted by the driver.
+    pub(crate) fn new() -> Self {
+        ModemLines {
+            cts: false,
+            dsr: false,
+            cd: false,
+            ri: false,
+            rts: true,
+            dtr: true,
+        }
+    }
+
+    /// Read the current state of the input lines from the `port`.
+    pub(crate) fn refresh(&mut self, port: &mut Box<dyn SerialPort>) -> serialport::Result<()> {
+        self.cts = port.read_clear_to_send()?;
+        self.dsr = port.read_data_set_ready()?;
+        self.cd = port.read_carrier_detect()?;
+        self.ri = port.read_ring_indicator()?;
+        Ok(())
+    }
+
+    /// Invert the state of the RTS line.
+    pub(crate) fn toggle_rts(&mut self, port: &mut Box<dyn SerialPort>) -> serialport::Result<()> {
+        port.write_request_to_send(!self.rts)?;
+        self.rts = !self.rts;
+        Ok(())
+    }
+
+    /// Invert the state of the DTR line.
+    pub(crate) fn toggle_dtr(&mut self, port: &mut Box<dyn SerialPort>) -> serialport::Result<()> {
+        port.write_data_terminal_ready(!self.dtr)?;
+        self.dtr = !self.dtr;
+        Ok(())
+    }
+}
+impl fmt::Display for ModemLines {
+    /// A compact status line, with `●` for an asserted line and `○` otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = |on: bool| if on { '●' } else { '○' };
+        write!(
+            f,
+            "CTS {} DSR {} CD {} RI {} | RTS {} DTR {}",
+            mark(self.cts),
+            mark(self.dsr),
+            mark(self.cd),
+            mark(self.ri),
+            mark(self.rts),
+            mark(self.dtr)
+        )
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn status_line() {
+    let lines = ModemLines {
+        cts: true,
+        dtr: false,
+        ..ModemLines::new()
+    };
+    assert_eq!(lines.to_string(), "CTS ● DSR ○ CD ○ RI ○ | RTS ● DTR ○");
+}
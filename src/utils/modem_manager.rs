@@ -0,0 +1,134 @@
+//! Mitigation of ModemManager interference on Linux.
+//!
+//! ModemManager probes every new serial device in case it is a modem: right
+//! after the board is plugged in, it holds the port for a few seconds and
+//! writes AT commands to it. This shows as a transient "busy" port, or as
+//! garbage echoed back by the bootloader. The port can be left alone for a
+//! while with a settle delay, but the real fix is a udev rule telling
+//! ModemManager to ignore the device, which is suggested to the user when the
+//! interference is detected.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use console::style;
+use serialport::{available_ports, SerialPortType};
+
+use crate::Settings;
+
+/// Commands sent by ModemManager when probing a port, as they appear when the
+/// device echoes them back.
+const PROBE_COMMANDS: [&[u8]; 5] = [b"ATE0", b"AT+GCAP", b"AT+CGMI", b"AT+CGMM", b"AT+CPIN?"];
+
+/// The warning is only worth showing once.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if the `data` received from the device contains ModemManager
+/// probe commands.
+pub(crate) fn looks_like_probe(data: &[u8]) -> bool {
+    PROBE_COMMANDS
+        .iter()
+        .any(|command| data.windows(command.len()).any(|w| w == *command))
+}
+
+/// Returns `true` if ModemManager is running.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_running() -> bool {
+    std::fs::read_dir("/proc")
+        .map(|processes| {
+            processes.flatten().any(|process| {
+                std::fs::read_to_string(process.path().join("comm"))
+                    .is_ok_and(|comm| comm.trim() == "ModemManager")
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// ModemManager only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_running() -> bool {
+    false
+}
+
+/// Returns `true` if udev tells ModemManager to ignore the device at `path`.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_ignored(path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let rdev = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.rdev(),
+        Err(_) => return false,
+    };
+    // Same encoding as the `major` and `minor` macros of glibc.
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    std::fs::read_to_string(format!("/run/udev/data/c{}:{}", major, minor))
+        .is_ok_and(|data| data.lines().any(|l| l == "E:ID_MM_DEVICE_IGNORE=1"))
+}
+
+/// ModemManager only exists on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn is_ignored(_path: &str) -> bool {
+    true
+}
+
+/// The udev rule making ModemManager ignore the USB device with the given
+/// vendor and product ids.
+pub(crate) fn udev_rule(vid: u16, pid: u16) -> String {
+    format!(
+        "ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", ENV{{ID_MM_DEVICE_IGNORE}}=\"1\"",
+        vid, pid
+    )
+}
+
+/// Warn the user, once, that ModemManager interferes with the port at `path`
+/// and explain how to prevent it.
+pub(crate) fn warn(settings: &Settings, path: &str) {
+    if WARNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let usb_ids = available_ports().ok().and_then(|ports| {
+        ports
+            .into_iter()
+            .find(|port| port.port_name == path)
+            .and_then(|port| match port.port_type {
+                SerialPortType::UsbPort(info) => Some((info.vid, info.pid)),
+                _ => None,
+            })
+    });
+    let (vid, pid) = usb_ids.unwrap_or((0xffff, 0xffff));
+    let messages = &settings.messages;
+    println!(
+        "{}",
+        style(messages.text_with("modem_manager.probing", &[("path", &path)])).yellow()
+    );
+    println!(
+        "{}",
+        messages.text_with(
+            "modem_manager.udev_rule",
+            &[("rule", &style(udev_rule(vid, pid)).cyan())]
+        )
+    );
+    if usb_ids.is_none() {
+        println!("{}", messages.text("modem_manager.usb_ids"));
+    }
+    println!("{}", messages.text("modem_manager.reload"));
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn probe_detection() {
+    assert!(looks_like_probe(b"\r\nAT+GCAP\r\n"));
+    assert!(looks_like_probe(b"xxATE0"));
+    assert!(!looks_like_probe(b"Raspberry Pi bootloader\r\n"));
+}
+
+#[test]
+fn rule_for_usb_ids() {
+    assert_eq!(
+        udev_rule(0x0403, 0x6001),
+        "ATTRS{idVendor}==\"0403\", ATTRS{idProduct}==\"6001\", ENV{ID_MM_DEVICE_IGNORE}=\"1\""
+    );
+}
@@ -0,0 +1,129 @@
+//! Detection of line noise in the data received from the serial port.
+//!
+//! When the board switches its UART configuration (typically the baud rate)
+//! after `bootcom` opened the port, the terminal suddenly receives nothing but
+//! garbage: invalid UTF-8 sequences, runs of `0xFF` or `0x00` resulting from
+//! framing errors, and random control characters. This module provides a
+//! simple heuristic to detect such a "storm" so that a baud rate rescan can be
+//! offered.
+
+use std::collections::VecDeque;
+
+/// Number of most recent bytes over which the noise ratio is computed.
+const WINDOW_SIZE: usize = 256;
+
+/// Minimum number of bytes to have in the window before the heuristic is
+/// applied. Avoids false positives on a few stray bytes at power up.
+const MIN_SAMPLES: usize = 64;
+
+/// Ratio (in percent) of bad bytes above which we consider the line to be in a
+/// noise storm.
+const STORM_THRESHOLD_PERCENT: usize = 50;
+
+/// Keeps track of the recently received bytes and whether they look like
+/// legitimate console output or line noise.
+#[derive(Debug)]
+pub(crate) struct NoiseDetector {
+    /// One entry per recent byte, `true` if the byte was deemed bad.
+    window: VecDeque<bool>,
+    /// Number of `true` entries in `window`.
+    bad: usize,
+}
+impl NoiseDetector {
+    pub(crate) fn new() -> Self {
+        NoiseDetector {
+            window: VecDeque::with_capacity(WINDOW_SIZE),
+            bad: 0,
+        }
+    }
+
+    /// Account for newly received `data` and return `true` if the recent
+    /// traffic looks like a noise storm.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> bool {
+        for chunk in data.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                let bad = is_suspicious_char(c);
+                // A multi-byte character still counts as one sample.
+                self.push(bad);
+            }
+            for _ in chunk.invalid() {
+                self.push(true);
+            }
+        }
+        self.is_storm()
+    }
+
+    /// Returns `true` if the recent traffic looks like a noise storm.
+    pub(crate) fn is_storm(&self) -> bool {
+        self.window.len() >= MIN_SAMPLES
+            && self.bad * 100 > self.window.len() * STORM_THRESHOLD_PERCENT
+    }
+
+    /// Ratio of bad bytes in the current window, in percent.
+    pub(crate) fn noise_percent(&self) -> usize {
+        if self.window.is_empty() {
+            0
+        } else {
+            self.bad * 100 / self.window.len()
+        }
+    }
+
+    fn push(&mut self, bad: bool) {
+        if self.window.len() == WINDOW_SIZE {
+            if let Some(true) = self.window.pop_front() {
+                self.bad -= 1;
+            }
+        }
+        self.window.push_back(bad);
+        if bad {
+            self.bad += 1;
+        }
+    }
+}
+
+/// Control characters other than the usual whitespace, backspace, bell and
+/// escape (for ANSI sequences) are not expected in console output.
+fn is_suspicious_char(c: char) -> bool {
+    match c {
+        '\n' | '\r' | '\t' | '\x07' | '\x08' | '\x1b' => false,
+        // The replacement character is what a previous decoder outputs for
+        // garbage, and `ÿ` is `0xFF` decoded as latin-1 by some devices.
+        '\u{fffd}' | 'ÿ' => true,
+        c => c.is_control(),
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn clean_text_is_not_a_storm() {
+    let mut detector = NoiseDetector::new();
+    let text = "[    0.000000] Booting Linux on physical CPU 0x0\r\n".repeat(8);
+    assert!(!detector.feed(text.as_bytes()));
+    assert_eq!(detector.noise_percent(), 0);
+}
+
+#[test]
+fn ff_runs_are_a_storm() {
+    let mut detector = NoiseDetector::new();
+    assert!(detector.feed(&[0xff; 128]));
+}
+
+#[test]
+fn few_bad_bytes_are_tolerated() {
+    let mut detector = NoiseDetector::new();
+    assert!(!detector.feed(&[0xff; 16]));
+    let text = "login: ".repeat(20);
+    assert!(!detector.feed(text.as_bytes()));
+}
+
+#[test]
+fn storm_clears_with_clean_data() {
+    let mut detector = NoiseDetector::new();
+    assert!(detector.feed(&[0x00, 0x80, 0xfe, 0x13].repeat(32)));
+    let text = "# ".repeat(128);
+    assert!(!detector.feed(text.as_bytes()));
+    assert_eq!(detector.noise_percent(), 0);
+}
@@ -0,0 +1,47 @@
+//! Notes typed during a session, keeping the observations made while
+//! debugging along with the console output they are about.
+//!
+//! In terminal mode, `Ctrl+A` followed by `n` prompts for a line of text. The
+//! note is shown with the time of day amid the console output, written to the
+//! recordings (as a marker in the asciicast ones) and to the log, and saved in
+//! the record of the push being archived, if any.
+
+use console::Term;
+use dialoguer::{theme::ColorfulTheme, Input};
+use std::time::SystemTime;
+
+use super::{render, stopwatch::time_of_day, suspend};
+use crate::Settings;
+
+/// Ask the user for a note, returning `None` when it was left empty.
+pub(crate) fn prompt_note(settings: &Settings) -> Option<String> {
+    let _paused = render::pause();
+    let _keyboard = suspend();
+    let text: String = Input::with_theme(&ColorfulTheme::default())
+        .with_prompt(settings.messages.text("prompt.note"))
+        .allow_empty(true)
+        .interact_on(&Term::stdout())
+        .ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// The line showing the note `text` typed at `time`.
+pub(crate) fn note_line(time: SystemTime, text: &str) -> String {
+    format!("[BC] 📝 Note at {}: {}", time_of_day(time), text)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn note_lines() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_125);
+    assert_eq!(
+        note_line(time, "stuck after the reset"),
+        "[BC] 📝 Note at 22:13:20.125 UTC: stuck after the reset"
+    );
+}
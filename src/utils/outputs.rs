@@ -0,0 +1,203 @@
+//! Destinations of the console output received from the device.
+//!
+//! The data received in terminal mode is rendered on the terminal and can also
+//! be written to additional sinks, such as a session recording. All sinks are
+//! grouped in [`Outputs`], which lives as long as `bootcom` runs so that the
+//! sinks survive the reconnection of the device, along with the readers of the
+//! console lines of library users.
+
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use console::style;
+
+use super::{render, AsciicastRecorder, SessionLogger};
+use crate::console_lines::{ConsoleLines, LineSink};
+use crate::messages::Catalog;
+use crate::settings::Settings;
+
+/// A destination for the console output, as rendered on the terminal.
+pub(crate) trait OutputSink: Send {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Mark the output written so far with the `label`, for the sinks which
+    /// can. Nothing to do by default.
+    fn mark(&mut self, _label: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Take note of the `data` sent to the device, for the sinks which log
+    /// it. Nothing to do by default.
+    fn sent(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The terminal on which `bootcom` runs, where the output is held while
+/// prompts and progress bars are shown.
+struct TerminalSink {}
+impl OutputSink for TerminalSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        render::write(data)
+    }
+}
+
+/// The output sinks configured in the settings. Cloning it gives another
+/// handle to the same sinks.
+#[derive(Clone, Default)]
+pub(crate) struct Outputs {
+    sinks: Arc<Mutex<Vec<Box<dyn OutputSink>>>>,
+    lines: Arc<Mutex<Vec<LineSink>>>,
+    /// Whether the output written last ended in the middle of a line.
+    mid_line: Arc<AtomicBool>,
+    messages: Catalog,
+}
+impl Outputs {
+    /// Create the terminal sink and the additional sinks enabled in the
+    /// `settings`. Sinks that can't be created are reported and skipped.
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let mut sinks: Vec<Box<dyn OutputSink>> = vec![Box::new(TerminalSink {})];
+        if let Some(path) = &settings.record {
+            match AsciicastRecorder::create(path) {
+                Ok(recorder) => sinks.push(Box::new(recorder)),
+                Err(e) => println!(
+                    "{}",
+                    style(
+                        settings
+                            .messages
+                            .text_with("output.not_recorded", &[("path", path), ("error", &e)])
+                    )
+                    .red()
+                ),
+            }
+        }
+        if let Some(log) = &settings.log_file {
+            match SessionLogger::open(log) {
+                Ok(logger) => sinks.push(Box::new(logger)),
+                Err(e) => println!(
+                    "{}",
+                    style(
+                        settings
+                            .messages
+                            .text_with("output.not_logged", &[("path", &log.path), ("error", &e)])
+                    )
+                    .red()
+                ),
+            }
+        }
+        Outputs {
+            sinks: Arc::new(Mutex::new(sinks)),
+            lines: Arc::default(),
+            mid_line: Arc::default(),
+            messages: settings.messages.clone(),
+        }
+    }
+
+    /// A reader of the console lines written from now on.
+    pub(crate) fn subscribe_lines(&self) -> ConsoleLines {
+        let (sink, lines) = LineSink::new();
+        self.lines.lock().unwrap().push(sink);
+        lines
+    }
+
+    /// End the iteration of all the readers of the console lines.
+    pub(crate) fn close_lines(&self) {
+        self.lines.lock().unwrap().clear();
+    }
+
+    /// Write the rendered `data` to all sinks. A sink failing to write is
+    /// reported and removed.
+    pub(crate) fn write(&self, data: &[u8]) {
+        if let Some(&last) = data.last() {
+            self.mid_line.store(last != b'\n', Ordering::Relaxed);
+        }
+        let mut sinks = self.sinks.lock().unwrap();
+        sinks.retain_mut(|sink| match sink.write(data) {
+            Ok(_) => true,
+            Err(e) => {
+                self.stopped(&e);
+                false
+            }
+        });
+        // The readers which went away are forgotten silently.
+        let mut lines = self.lines.lock().unwrap();
+        lines.retain_mut(|sink| sink.write(data).is_ok());
+    }
+
+    /// Write a `line` of `bootcom` to all sinks, on a line of its own amid the
+    /// console output.
+    pub(crate) fn write_line(&self, line: &str) {
+        let newline = if self.mid_line.load(Ordering::Relaxed) {
+            "\r\n"
+        } else {
+            ""
+        };
+        self.write(format!("{}{}\r\n", newline, line).as_bytes());
+    }
+
+    /// Report a sink which stopped with the error `e`.
+    fn stopped(&self, e: &io::Error) {
+        let stopped = self.messages.text_with("output.stopped", &[("error", e)]);
+        println!("{}", style(stopped).red());
+    }
+
+    /// A writer to the `device`, handing everything written to the sinks which
+    /// log the data sent.
+    pub(crate) fn sending<'a>(&'a self, device: &'a mut dyn Write) -> Sending<'a> {
+        Sending {
+            outputs: self,
+            device,
+        }
+    }
+
+    /// Write the `line` of a note to all sinks, the ones which can mark their
+    /// output marking it with the `text` of the note. A sink failing to write
+    /// is reported and removed.
+    pub(crate) fn note(&self, line: &str, text: &str) {
+        self.write_line(line);
+        let mut sinks = self.sinks.lock().unwrap();
+        sinks.retain_mut(|sink| match sink.mark(text) {
+            Ok(_) => true,
+            Err(e) => {
+                self.stopped(&e);
+                false
+            }
+        });
+    }
+}
+
+/// Writes to a device, handing the data sent to the output sinks.
+pub(crate) struct Sending<'a> {
+    outputs: &'a Outputs,
+    device: &'a mut dyn Write,
+}
+impl Write for Sending<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.device.write(buf)?;
+        let mut sinks = self.outputs.sinks.lock().unwrap();
+        sinks.retain_mut(|sink| match sink.sent(&buf[..written]) {
+            Ok(_) => true,
+            Err(e) => {
+                self.outputs.stopped(&e);
+                false
+            }
+        });
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.flush()
+    }
+}
+impl std::fmt::Debug for Outputs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Outputs")
+            .field("sinks", &self.sinks.lock().unwrap().len())
+            .finish()
+    }
+}
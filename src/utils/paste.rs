@@ -0,0 +1,88 @@
+//! Paced writing of pasted text to the device.
+//!
+//! The UART FIFO of some boards is only a few bytes deep and drops characters
+//! when a large blob of text is pasted at full speed. Pasted data is therefore
+//! written in small chunks, pausing after each chunk and after each end of line
+//! according to the [`PastePacing`] in the settings.
+
+use std::{io, thread};
+
+use crate::settings::PastePacing;
+
+/// Number of bytes written at once, the depth of the common 16550 style UART
+/// FIFOs.
+const CHUNK_SIZE: usize = 16;
+
+/// Write `data` to the `device`, paced according to `pacing`.
+///
+/// When no pacing is configured, the data is written all at once.
+pub(crate) fn write_paced(
+    device: &mut dyn io::Write,
+    data: &[u8],
+    pacing: &PastePacing,
+) -> io::Result<()> {
+    if pacing.is_off() {
+        device.write_all(data)?;
+        return device.flush();
+    }
+
+    for line in data.split_inclusive(|b| *b == b'\r' || *b == b'\n') {
+        for chunk in line.chunks(CHUNK_SIZE) {
+            device.write_all(chunk)?;
+            device.flush()?;
+            thread::sleep(pacing.byte_delay * chunk.len() as u32);
+        }
+        if line.ends_with(b"\r") || line.ends_with(b"\n") {
+            thread::sleep(pacing.line_delay);
+        }
+    }
+    Ok(())
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingDevice {
+    writes: Vec<Vec<u8>>,
+}
+#[cfg(test)]
+impl io::Write for RecordingDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn unpaced_write_at_once() {
+    let mut device = RecordingDevice::default();
+    let data = [b'x'; 100];
+    write_paced(&mut device, &data, &PastePacing::default()).unwrap();
+    assert_eq!(device.writes, vec![data.to_vec()]);
+}
+
+#[test]
+fn paced_write_in_chunks_and_lines() {
+    let mut device = RecordingDevice::default();
+    let pacing = PastePacing {
+        byte_delay: std::time::Duration::from_micros(1),
+        line_delay: std::time::Duration::from_millis(1),
+    };
+    let data = b"0123456789abcdefXY\rls\r";
+    write_paced(&mut device, data, &pacing).unwrap();
+    assert_eq!(
+        device.writes,
+        vec![
+            b"0123456789abcdef".to_vec(),
+            b"XY\r".to_vec(),
+            b"ls\r".to_vec()
+        ]
+    );
+}
@@ -1,23 +1,27 @@
 //! Serial port device manipulation.
 
 use console::{style, Term};
-use indicatif::{ProgressBar, ProgressStyle};
 use log::{debug, info};
-use serialport::{available_ports, SerialPort, SerialPortType};
+use serialport::{available_ports, FlowControl, SerialPort, SerialPortType};
 
-use std::{
-    sync::mpsc::{self, RecvTimeoutError},
-    thread,
-    time::Duration,
-};
+use std::{path::Path, time::Duration};
 
-use crate::{utils::poll_escape, Settings};
+use super::{
+    busy::is_port_busy, hide_cursor, is_transient, modem_manager, quirks, set_status, Attempts,
+    RetriesExhausted,
+};
+use crate::{clock::clock, pcap, profiles::PortRule, utils::subscribe, Settings};
 
 //==============================================================================
 // Public Interface
 //==============================================================================
 
-pub(crate) fn select_port() -> Option<String> {
+/// Let the user select the serial port to be used, waiting for USB serial
+/// controllers to be connected if there are none.
+///
+/// Returns `None` if the user canceled the selection, or an error when no
+/// controller showed up within the attempts allowed by the retry policy.
+pub(crate) fn select_port(settings: &Settings) -> Result<Option<String>, RetriesExhausted> {
     // If no specific device was requested, we'll present the list of connected
     // devices to the user to interactively select one. The user may cancel the
     // selection to request for another refresh of connected devices, probably
@@ -28,39 +32,48 @@ pub(crate) fn select_port() -> Option<String> {
     let mut found_ports;
     let mut attempt: usize = 1;
     let waiting_period: usize = 1;
-
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(120);
-    pb.set_style(
-        ProgressStyle::default_spinner()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["⠋", "⠙", "⠚", "⠞", "⠖", "⠦", "⠴", "⠲", "⠳", "⠓"])
-            .template("[BC] {spinner:.blue} {msg}"),
+    let mut waits = Attempts::new(
+        "waiting for a USB serial controller",
+        settings.retry.port_wait_attempts,
     );
 
+    let messages = &settings.messages;
+    let pb = settings.progress_theme.spinner();
+
     // Avoid cursor flicker during the waiting
-    Term::stdout().hide_cursor().unwrap();
+    let cursor = hide_cursor().ok();
     // Enumerate connected USB serial devices until we have some.
     loop {
-        found_ports = enumerate_usb_serial_ports();
+        found_ports = enumerate_usb_serial_ports(settings.bluetooth_ports, None);
         let num_ports = found_ports.len();
         if num_ports > 0 {
-            pb.finish_with_message("Select a port to be used:");
+            pb.finish_with_message(messages.text("port.select"));
             break;
         } else {
+            if let Err(e) = waits.failed() {
+                pb.finish_with_message(
+                    messages.text_with("port.no_controller", &[("attempts", &e.attempts)]),
+                );
+                return Err(e);
+            }
             let waited = attempt * waiting_period;
-            pb.set_message(format!(
-                "[{:03}s {}] ⌛ Waiting for USB serial controller to be connected...",
-                style(waited).dim(),
-                num_ports
-            ));
+            set_status(
+                &pb,
+                messages.text_with(
+                    "port.waiting_controller",
+                    &[
+                        ("waited", &style(format!("{:03}", waited)).dim()),
+                        ("ports", &num_ports),
+                        ("attempts", &style(&waits).dim()),
+                    ],
+                ),
+            );
             attempt += 1;
         }
 
-        thread::sleep(Duration::from_secs(waiting_period as u64));
+        clock(settings).sleep(Duration::from_secs(waiting_period as u64));
     }
-    Term::stdout().show_cursor().unwrap();
+    drop(cursor);
 
     // Ask the user to confirm the port selection. If a port is confirmed, it is
     // then returned as the selected port for use; otherwise, we loop again
@@ -70,154 +83,154 @@ pub(crate) fn select_port() -> Option<String> {
     let selection = select_port_interactive(&found_ports);
     match &selection {
         Some(path) => {
-            pb.finish_with_message(format!("👍 Serial port {} is ready", style(path).green()));
+            pb.finish_with_message(
+                messages.text_with("port.ready", &[("path", &style(path).green())]),
+            );
         }
         None => {
-            pb.finish_with_message("❌ Selection canceled -> refreshing...");
+            pb.finish_with_message(messages.text("port.selection_canceled"));
         }
     }
-    selection
+    Ok(selection)
 }
 
-/// Check for a device with the given path in the system. If not immediately
+/// Check for a device with the given path in the system, or for the first one
+/// matching the port rule when the `settings` have one. If not immediately
 /// found, enter into a waiting loop, checking every period of time whether the
 /// device has been created or not. While waiting, the user can interactively
 /// cancel waiting by pressing the `ESC` key.
 ///
-/// The function will return `true` when the wait was cancelled by the user
-/// hitting `Esc`.
-pub(crate) fn wait_for_port(path: &str) -> bool {
-    let pb = ProgressBar::new_spinner();
-    pb.enable_steady_tick(120);
-    pb.set_style(
-        ProgressStyle::default_spinner()
-            // For more spinners check out the cli-spinners project:
-            // https://github.com/sindresorhus/cli-spinners/blob/master/spinners.json
-            .tick_strings(&["⠋", "⠙", "⠚", "⠞", "⠖", "⠦", "⠴", "⠲", "⠳", "⠓"])
-            .template("[BC] {spinner:.blue} {msg}"),
-    );
+/// When the device only appears while waiting, it is given the settle delay
+/// from the `settings` to settle before returning.
+///
+/// The function will return the path of the device once ready, `None` when the
+/// wait was cancelled by the user hitting `Esc`, or an error when the device
+/// did not show up within the attempts allowed by the retry policy.
+pub(crate) fn wait_for_port(settings: &Settings) -> Result<Option<String>, RetriesExhausted> {
+    let rule = settings.port_match.as_ref();
+    let wanted = match rule {
+        Some(rule) => rule.to_string(),
+        None => settings.path.clone().unwrap(),
+    };
+    let path = wanted.as_str();
+    let messages = &settings.messages;
+    let pb = settings.progress_theme.spinner();
 
     let mut found_ports: Vec<String> = [].into();
     let mut attempt: usize = 1;
     let waiting_period = 2;
+    let mut waits = Attempts::new(
+        "waiting for the serial port",
+        settings.retry.port_wait_attempts,
+    );
+    let mut exhausted = None;
+
+    set_status(
+        &pb,
+        messages.text_with(
+            "port.waiting",
+            &[
+                ("waited", &style(format!("{:03}", waiting_period)).dim()),
+                ("ports", &found_ports.len()),
+                ("path", &style(path).cyan()),
+            ],
+        ),
+    );
 
-    pb.set_message(format!(
-        "[{:03}s {}] ⏳ Waiting for {} to be ready (ESC to cancel)...",
-        style(waiting_period).dim(),
-        found_ports.len(),
-        style(path).cyan()
-    ));
-
-    // We'll be using the main thread and one additional one listening on the
-    // `ESC` key to cancel the waiting. Both threads needs to coordinate their
-    // termination:
-    //
-    //  - When the device is ready on the main thread, the cancelation thread
-    //    should terminate.
-    //  - When the `ESC` key is pressed, the cancelation thread will naturally
-    //    terminate and the main thread should stop waiting and exit the waiting
-    //    loop.
-    //
-    // In order to achieve this coordinated termination, two channels are used:
-    // one channel for the cancelation condition, and another channel for device
-    // readiness condition.
-
-    // Cancellation channel, on which the cancellation thread will be the sender
-    // and the main thread the receiver.
-    let (cancel_tx, cancel_rx) = mpsc::channel();
-
-    // The device ready channel, on which the main thread will be the sender and
-    // the cancellation thread the receiver.
-    let (done_tx, done_rx) = mpsc::channel();
-
-    // Start the cancellation thread to check for the `ESC` key and listen for
-    // the completion from the main thread.
-    let cancelation_thread = thread::spawn(move || loop {
-        // Check if we need to terminate because the serial device is ready.
-        if done_rx.try_recv().is_ok() {
-            // Terminate
-            break;
-        }
-        // Poll for the Esc key, non blocking
-        if let Ok(esc) = poll_escape() {
-            if esc {
-                cancel_tx
-                    .send(1)
-                    .expect("an unrecoverable error while sending over cancel_tx");
-                break;
-            }
-        }
-    });
+    // Listen to the `ESC` key while waiting, unless the keyboard belongs to
+    // someone else.
+    let keys = settings.keyboard.then(subscribe);
+    let period = Duration::from_secs(waiting_period as u64);
 
-    let mut cancelled = false;
+    let mut ready = None;
     loop {
-        found_ports = enumerate_usb_serial_ports();
+        // The requested port is always looked for, even if it is a Bluetooth
+        // one.
+        found_ports = enumerate_usb_serial_ports(true, rule);
 
         // If we are waiting specifically for a certain port, loop until
-        // it is part of the detected ports.
-        let found = check_requested_port(&found_ports, path);
-        if found {
-            // Notify the cancellation thread
-            done_tx
-                .send(1)
-                .expect("an unrecoverable error while sending over done_tx");
-
-            pb.finish_with_message(format!("👍 Serial port {} is ready", style(path).green()));
+        // it is part of the detected ports, or until one matches the rule.
+        // Ports which are not enumerated (pseudo terminals of simulators,
+        // `socat`...) are found by their path.
+        let found = match rule {
+            Some(_) => found_ports.first().map(|port| port_path(port).to_owned()),
+            None => (check_requested_port(&found_ports, path)
+                || (cfg!(unix) && is_port_present(path)))
+            .then(|| path.to_owned()),
+        };
+        if let Some(found) = found {
+            pb.finish_with_message(
+                messages.text_with("port.ready", &[("path", &style(&found).green())]),
+            );
+            if attempt > 1 {
+                settle(&found, settings);
+            }
+            ready = Some(found);
+            break;
+        }
+
+        // Give up when out of attempts.
+        if let Err(e) = waits.failed() {
+            pb.finish_with_message(messages.text_with(
+                "port.gave_up",
+                &[("path", &style(path).cyan()), ("attempts", &e.attempts)],
+            ));
+            exhausted = Some(e);
             break;
         }
 
-        // Update the progress message and wait for some time (receiving until
-        // timeout from the cancellation channel) before enumerating serial
-        // devices again.
+        // Update the progress message and wait for some time (or for the `ESC`
+        // key) before enumerating serial devices again.
         let num_ports = found_ports.len();
         let waited = attempt * waiting_period;
-        pb.set_message(format!(
-            "[{:03}s {}] ⏳ Waiting for {} to be ready (ESC to cancel)...",
-            style(waited).dim(),
-            num_ports,
-            style(path).cyan()
-        ));
-
-        match cancel_rx.recv_timeout(Duration::from_secs(waiting_period as u64)) {
-            Ok(_) => {
-                // we got cancelled
-                pb.finish_with_message(format!(
-                    "❌ Waiting on port {} canceled after {} seconds",
-                    style(path).cyan(),
-                    style(waited).dim()
-                ));
-                cancelled = true;
-                break;
-            }
-            Err(RecvTimeoutError::Timeout) => {
-                // try again after a timeout
-            }
-            Err(RecvTimeoutError::Disconnected) => {
-                // no point in waiting anymore :'(
-                cancelled = true;
-                break;
+        set_status(
+            &pb,
+            messages.text_with(
+                "port.waiting_again",
+                &[
+                    ("waited", &style(format!("{:03}", waited)).dim()),
+                    ("ports", &num_ports),
+                    ("path", &style(path).cyan()),
+                    ("attempts", &style(&waits).dim()),
+                ],
+            ),
+        );
+
+        let escaped = match &keys {
+            Some(keys) => keys.escape(period),
+            None => {
+                clock(settings).sleep(period);
+                false
             }
+        };
+        if escaped {
+            pb.finish_with_message(messages.text_with(
+                "port.wait_canceled",
+                &[
+                    ("path", &style(path).cyan()),
+                    ("waited", &style(waited).dim()),
+                ],
+            ));
+            break;
         }
 
         attempt += 1;
     }
 
-    // Join the cancellation thread
-    cancelation_thread
-        .join()
-        .expect("an unrecoverable error while joining the cancellation thread");
-
-    cancelled
+    match exhausted {
+        Some(e) => Err(e),
+        None => Ok(ready),
+    }
 }
 
 pub(crate) fn open_and_setup_port(
     settings: &Settings,
 ) -> Result<Box<dyn SerialPort>, serialport::Error> {
-    use retry::{delay, retry_with_index};
+    use retry::{delay, retry_with_index, OperationResult};
 
     let result = retry_with_index(
         delay::Fixed::from_millis(1000).take(4),
-        |index| -> Result<Box<dyn SerialPort>, serialport::Error> {
+        |index| -> OperationResult<Box<dyn SerialPort>, serialport::Error> {
             debug!("Trying to connect {}", index);
             // Open the port
             let path = settings.path.clone().unwrap();
@@ -225,8 +238,14 @@ pub(crate) fn open_and_setup_port(
                 .data_bits(settings.data_bits)
                 .stop_bits(settings.stop_bits)
                 .parity(settings.parity)
-                .flow_control(settings.flow_control);
-            builder.open()
+                .flow_control(driver_flow_control(settings));
+            match builder.open() {
+                Ok(port) => OperationResult::Ok(port),
+                // No point in retrying while another program holds the port,
+                // the caller can tell the user about it instead.
+                Err(e) if is_port_busy(&e) => OperationResult::Err(e),
+                Err(e) => OperationResult::Retry(e),
+            }
         },
     );
     match result {
@@ -234,11 +253,7 @@ pub(crate) fn open_and_setup_port(
             // Configure the port with the values in `settings`. TODO: This is
             // probably temporary until `serialport` configures the port after
             // `open` by itself.
-            port.set_baud_rate(settings.baud_rate)?;
-            port.set_data_bits(settings.data_bits)?;
-            port.set_stop_bits(settings.stop_bits)?;
-            port.set_parity(settings.parity)?;
-            port.set_flow_control(settings.flow_control)?;
+            configure_port(&mut port, settings)?;
 
             info!(
                 "Connected to {} at {} baud",
@@ -265,7 +280,8 @@ pub(crate) fn open_and_setup_port(
             assert_eq!(settings.stop_bits, port.stop_bits().unwrap());
             assert_eq!(settings.parity, port.parity().unwrap());
 
-            Ok(port)
+            quirks::apply_on_open(&mut port, &settings.quirks)?;
+            Ok(pcap::wrap(settings, port))
         }
         Err(err) => match err {
             retry::Error::Operation {
@@ -290,6 +306,155 @@ pub(crate) fn open_and_setup_port(
     }
 }
 
+/// Baud rates tried, in order, when rescanning for the baud rate used by the
+/// device.
+const STANDARD_BAUD_RATES: [u32; 10] = [
+    115_200, 230_400, 460_800, 921_600, 57_600, 38_400, 19_200, 9_600, 500_000, 1_000_000,
+];
+
+/// Listen on the port at each of the standard baud rates but the current one
+/// and return the first one for which the received data looks like legitimate
+/// console output.
+///
+/// The device needs to be actively sending data for the scan to succeed, which
+/// is usually the case when the baud rate mismatch was detected in the first
+/// place. Returns `None` if no suitable baud rate was found.
+pub(crate) fn scan_baud_rate(settings: &Settings) -> Option<u32> {
+    use super::noise::NoiseDetector;
+    use std::io::Read;
+
+    let path = settings.path.clone()?;
+    let messages = &settings.messages;
+
+    let pb = settings.progress_theme.spinner();
+
+    // Skip the current baud rate, we already know it's not working.
+    let candidates = STANDARD_BAUD_RATES
+        .iter()
+        .filter(|rate| **rate != settings.baud_rate);
+    for baud_rate in candidates {
+        set_status(
+            &pb,
+            messages.text_with(
+                "port.scanning",
+                &[
+                    ("path", &style(&path).cyan()),
+                    ("baud_rate", &style(baud_rate).cyan()),
+                ],
+            ),
+        );
+        let port = serialport::new(&path, *baud_rate)
+            .data_bits(settings.data_bits)
+            .stop_bits(settings.stop_bits)
+            .parity(settings.parity)
+            .flow_control(driver_flow_control(settings))
+            .timeout(Duration::from_millis(100))
+            .open();
+        let mut port = match port {
+            Ok(port) => port,
+            Err(ref e) => {
+                info!("error: {}", e.to_string());
+                pb.finish_with_message(
+                    messages.text_with("port.scan_not_opened", &[("path", &path)]),
+                );
+                return None;
+            }
+        };
+        let _ = port.clear(serialport::ClearBuffer::Input);
+
+        // Listen for a while and judge the quality of what we receive.
+        let mut detector = NoiseDetector::new();
+        let mut received = 0;
+        let mut buf = [0u8; 256];
+        let started = std::time::Instant::now();
+        while started.elapsed() < Duration::from_millis(1500) && received < 512 {
+            match port.read(&mut buf) {
+                Ok(n) => {
+                    detector.feed(&buf[..n]);
+                    received += n;
+                }
+                Err(ref e) if is_transient(e) => {}
+                Err(ref e) => {
+                    info!("error: {}", e.to_string());
+                    break;
+                }
+            }
+        }
+        debug!(
+            "baud rate {}: {} bytes, {}% noise",
+            baud_rate,
+            received,
+            detector.noise_percent()
+        );
+        if received >= 32 && detector.noise_percent() < 10 {
+            pb.finish_with_message(messages.text_with(
+                "port.baud_rate_found",
+                &[("baud_rate", &style(baud_rate).green())],
+            ));
+            return Some(*baud_rate);
+        }
+    }
+
+    pb.finish_with_message(messages.text("port.baud_rate_not_found"));
+    None
+}
+
+/// Check that the device behind the port at `path` is still there, without
+/// touching the port itself.
+///
+/// A yanked USB cable is otherwise only noticed on the next read or write,
+/// which can take a long time on an idle console. On Unix systems the device
+/// node disappears with the device; elsewhere the port must still be
+/// enumerated by the system.
+pub(crate) fn is_port_present(path: &str) -> bool {
+    if cfg!(unix) {
+        Path::new(path).exists()
+    } else {
+        available_ports().map_or(true, |ports| ports.iter().any(|p| p.port_name == path))
+    }
+}
+
+/// Configure the line parameters (baud rate, framing and flow control) of an
+/// open `port` with the values in `settings`.
+pub(crate) fn configure_port(
+    port: &mut Box<dyn SerialPort>,
+    settings: &Settings,
+) -> serialport::Result<()> {
+    port.set_baud_rate(settings.baud_rate)?;
+    port.set_data_bits(settings.data_bits)?;
+    port.set_stop_bits(settings.stop_bits)?;
+    port.set_parity(settings.parity)?;
+    port.set_flow_control(driver_flow_control(settings))
+}
+
+/// The flow control to be configured in the serial driver. Software flow
+/// control is handled by `bootcom` itself, see [`SoftFlow`](super::SoftFlow).
+fn driver_flow_control(settings: &Settings) -> FlowControl {
+    match settings.flow_control {
+        FlowControl::Software => FlowControl::None,
+        other => other,
+    }
+}
+
+/// Leave a port that just appeared alone for the settle delay of the
+/// `settings`, warning about ModemManager if it is likely to be probing it.
+fn settle(path: &str, settings: &Settings) {
+    let delay = settings.settle_delay;
+    if modem_manager::is_running() && !modem_manager::is_ignored(path) {
+        modem_manager::warn(settings, path);
+    }
+    if delay > Duration::from_millis(0) {
+        println!(
+            "{}",
+            settings.messages.text_with(
+                "port.settling",
+                &[("path", &style(path).cyan()), ("delay", &delay.as_millis())]
+            )
+        );
+        clock(settings).sleep(delay);
+    }
+}
+
 //==============================================================================
 // Private stuff
 //==============================================================================
@@ -303,12 +468,46 @@ fn check_requested_port(ports: &[String], path: &str) -> bool {
     false
 }
 
-/// Enumerates serial devices of type USB on the system
-fn enumerate_usb_serial_ports() -> Vec<String> {
+/// The path of a port listed by [`enumerate_usb_serial_ports`].
+fn port_path(port: &str) -> &str {
+    port.split(':').next().unwrap()
+}
+
+/// Enumerates serial devices on the system, USB ones with more details about
+/// the connected serial controller.
+///
+/// Bluetooth virtual serial ports are only listed when `include_bluetooth` is
+/// set: they clutter the list and opening them may hang while the system tries
+/// to reach the remote device. Only the ports matching the `rule` are listed
+/// when there is one.
+fn enumerate_usb_serial_ports(include_bluetooth: bool, rule: Option<&PortRule>) -> Vec<String> {
+    #[cfg(windows)]
+    let details = super::windows_ports::port_details();
+
     let mut usb_ports = vec![];
     match available_ports() {
         Ok(ports) => {
             for p in ports {
+                #[cfg(windows)]
+                let (friendly_name, bluetooth) = match details.get(&p.port_name) {
+                    Some(d) => (d.friendly_name.clone(), d.bluetooth),
+                    None => (None, false),
+                };
+                #[cfg(not(windows))]
+                let (friendly_name, bluetooth): (Option<String>, bool) = (None, false);
+
+                let bluetooth = bluetooth
+                    || p.port_type == SerialPortType::BluetoothPort
+                    || is_bluetooth_name(&p.port_name);
+                if bluetooth && !include_bluetooth {
+                    debug!("skipping bluetooth port {}", p.port_name);
+                    continue;
+                }
+                if rule.is_some_and(|rule| !rule.matches(&p)) {
+                    debug!("skipping port {} not matching the rule", p.port_name);
+                    continue;
+                }
+
                 match p.port_type {
                     // USB ports give us more info about the connected serial
                     // controller
@@ -322,10 +521,12 @@ fn enumerate_usb_serial_ports() -> Vec<String> {
                         usb_ports.push(extended_name);
                     }
                     // We're also interested in the other devices, such as
-                    // virtual ports for testing
-                    _ => {
-                        usb_ports.push(p.port_name);
-                    }
+                    // virtual ports for testing, described with their
+                    // friendly name when the system has one
+                    _ => match friendly_name {
+                        Some(name) => usb_ports.push(format!("{}: ({})", p.port_name, name)),
+                        None => usb_ports.push(p.port_name),
+                    },
                 }
             }
         }
@@ -336,9 +537,19 @@ fn enumerate_usb_serial_ports() -> Vec<String> {
     usb_ports
 }
 
+/// Recognize the names given to Bluetooth serial ports on Linux (`rfcomm`) and
+/// macOS, for which `serialport` does not always report the port type.
+fn is_bluetooth_name(name: &str) -> bool {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    base.starts_with("rfcomm") || base.contains("Bluetooth")
+}
+
 fn select_port_interactive(ports: &[String]) -> Option<String> {
     use dialoguer::{theme::ColorfulTheme, Select};
 
+    let _paused = super::render::pause();
+    let _keyboard = super::suspend();
+
     // If we are waiting specifically for a certain port (name in
     // `requested_port`, check if it is part of the detected ports; otherwise
     // present the list of detected ports to the user to optionally select one
@@ -354,5 +565,46 @@ fn select_port_interactive(ports: &[String]) -> Option<String> {
     }
 
     let selection = select.default(0).interact_on_opt(&term).unwrap();
-    selection.map(|x| String::from(ports.get(x).unwrap().split(':').next().unwrap()))
+    selection.map(|x| String::from(port_path(ports.get(x).unwrap())))
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn bluetooth_port_names() {
+    assert!(is_bluetooth_name("/dev/rfcomm0"));
+    assert!(is_bluetooth_name("/dev/tty.Bluetooth-Incoming-Port"));
+    assert!(!is_bluetooth_name("/dev/ttyUSB0"));
+    assert!(!is_bluetooth_name("COM3"));
+}
+
+#[cfg(unix)]
+#[test]
+fn port_presence() {
+    assert!(is_port_present("/dev/null"));
+    assert!(!is_port_present("/dev/ttyBOOTCOM-unplugged"));
+}
+
+#[test]
+fn wait_for_port_in_simulated_time() {
+    use crate::clock::SimulatedClock;
+    use crate::settings::{RetryPolicy, SettingsBuilder};
+    use std::sync::Arc;
+
+    let clock = Arc::new(SimulatedClock::new());
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyBOOTCOM-unplugged")
+        .keyboard(false)
+        .retry(RetryPolicy {
+            port_wait_attempts: 30,
+            ..RetryPolicy::default()
+        })
+        .clock(clock.clone())
+        .finalize();
+    let error = wait_for_port(&settings).unwrap_err();
+    assert_eq!(error.attempts, 30);
+    // Every attempt but the last is followed by a wait of 2 seconds.
+    assert_eq!(clock.elapsed(), Duration::from_secs(58));
 }
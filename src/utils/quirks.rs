@@ -0,0 +1,72 @@
+//! Workarounds for the boot ROMs of some boards, as listed in the settings.
+//!
+//! The quirks acting on the serial line are applied, in order, right after the
+//! port is opened (see [`apply_on_open`]), the ones acting on the console are
+//! applied to the output received from the device (see [`map_output`]).
+
+use std::{borrow::Cow, thread, time::Duration};
+
+use log::debug;
+use serialport::SerialPort;
+
+use crate::settings::Quirk;
+
+/// How long DTR is deasserted by the [`Quirk::DtrToggle`].
+const DTR_PULSE: Duration = Duration::from_millis(100);
+
+/// Apply the `quirks` acting on the serial line to a `port` just opened.
+pub(crate) fn apply_on_open(
+    port: &mut Box<dyn SerialPort>,
+    quirks: &[Quirk],
+) -> serialport::Result<()> {
+    for quirk in quirks {
+        debug!("applying quirk {:?}", quirk);
+        match quirk {
+            Quirk::DtrToggle => {
+                port.write_data_terminal_ready(false)?;
+                thread::sleep(DTR_PULSE);
+                port.write_data_terminal_ready(true)?;
+            }
+            Quirk::WakeByte(byte) => port.write_all(&[*byte])?,
+            Quirk::CrToCrLf => {}
+        }
+    }
+    Ok(())
+}
+
+/// Apply the `quirks` acting on the console to the `data` received from the
+/// device.
+pub(crate) fn map_output<'a>(quirks: &[Quirk], data: &'a [u8]) -> Cow<'a, [u8]> {
+    let mut data = Cow::Borrowed(data);
+    for quirk in quirks {
+        if let Quirk::CrToCrLf = quirk {
+            if data.contains(&b'\r') {
+                let mut mapped = Vec::with_capacity(data.len() + 16);
+                for &byte in data.iter() {
+                    mapped.push(byte);
+                    if byte == b'\r' {
+                        mapped.push(b'\n');
+                    }
+                }
+                data = Cow::Owned(mapped);
+            }
+        }
+    }
+    data
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn carriage_returns_become_new_lines() {
+    assert_eq!(
+        map_output(&[Quirk::CrToCrLf], b"U-Boot\rlogin:").as_ref(),
+        b"U-Boot\r\nlogin:"
+    );
+    assert!(matches!(
+        map_output(&[Quirk::DtrToggle], b"U-Boot\r"),
+        Cow::Borrowed(_)
+    ));
+}
@@ -0,0 +1,688 @@
+//! Control of the device by the clients of the console served on the activated
+//! sockets, on lab hosts shared among teams.
+//!
+//! What a client writes is typed on the device console, except for the lines
+//! starting with `~`, which are commands for `bootcom` (`~~` types a `~`):
+//!
+//! - `~auth <token>` authenticates with one of the tokens of the settings,
+//!   granting its permissions. As the tokens would travel in cleartext, they
+//!   are only accepted on the Unix sockets: the clients on other hosts reach
+//!   them through an SSH tunnel (e.g. `ssh -L`),
+//! - `~image <path>` chooses the kernel image pushed next (`push`), among the
+//!   images of the settings or in the host directory (a relative path being
+//!   taken from there),
+//! - `~upload <size> <name>`, followed right after the end of the line by the
+//!   `size` bytes of a kernel image, chooses the uploaded image for the next
+//!   push (`push`), e.g. one built on the host of the client. The image is
+//!   written as it is received to a directory only the user of `bootcom` can
+//!   enter, and discarded right away without the permission,
+//! - `~baud <rate>`, `~parity none|odd|even` and `~flow none|soft|hard` change
+//!   the line parameters of the port (`settings`).
+//!
+//! Without access rules in the settings, every client has all the permissions.
+//! Otherwise a client has none until it authenticates: it neither receives the
+//! console output (`view`) nor types on it (`type`). `bootcom` answers each
+//! command with a line starting with `[BC] ok:` or `[BC] error:`, followed by
+//! a text in the language of the message catalog of the settings.
+
+use std::{
+    collections::hash_map::RandomState,
+    env,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+use log::info;
+
+use super::{HumanSize, LineChange};
+use crate::context::Context;
+use crate::messages::Catalog;
+use crate::settings::{AccessRule, FlowControl, Parity, Permission, Settings};
+
+/// How long a client waits for the answer to a wrong token, so that guessing
+/// one takes forever.
+const FAILED_AUTH_DELAY: Duration = Duration::from_millis(500);
+
+/// The size of the largest image which can be uploaded.
+const MAX_UPLOAD: usize = 256 * 1024 * 1024;
+
+/// The kind of socket a client is connected to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Socket {
+    /// A Unix socket, only reachable from the host.
+    Unix,
+    /// A TCP socket, over which nothing is encrypted.
+    Tcp,
+}
+
+/// A client of the console, with the permissions it was granted.
+#[derive(Debug)]
+pub(crate) struct Client {
+    rules: Vec<AccessRule>,
+    socket: Socket,
+    /// The kernel images of the settings, which can be chosen from anywhere.
+    images: Vec<String>,
+    /// The directory of the host services, in which any image can be chosen.
+    host_dir: Option<PathBuf>,
+    permissions: Vec<Permission>,
+    input: ClientInput,
+    /// Whether the client was told it can't type, which it is only once.
+    denied_typing: bool,
+    /// The catalog the replies are written with.
+    messages: Catalog,
+    /// The image being uploaded, or why it can't be saved. `None` when the
+    /// data of an upload is discarded.
+    upload: Option<Result<Receiving, String>>,
+}
+impl Client {
+    /// A new client connected to a `socket`, with all the permissions unless
+    /// there are access rules in the `settings`.
+    pub(crate) fn new(settings: &Settings, socket: Socket) -> Self {
+        let rules = &settings.access;
+        let permissions = if rules.is_empty() {
+            vec![
+                Permission::View,
+                Permission::Type,
+                Permission::Push,
+                Permission::Settings,
+            ]
+        } else {
+            vec![]
+        };
+        let images = settings
+            .triggers
+            .iter()
+            .filter_map(|t| t.image.clone())
+            .chain(settings.kernel_image.clone())
+            .collect();
+        Client {
+            rules: rules.to_vec(),
+            socket,
+            images,
+            host_dir: settings.host_dir.as_ref().map(PathBuf::from),
+            permissions,
+            input: ClientInput::default(),
+            denied_typing: false,
+            messages: settings.messages.clone(),
+            upload: None,
+        }
+    }
+
+    /// Returns `true` if the client has the `permission`.
+    pub(crate) fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Handle the `data` written by the client, typing it on the device
+    /// console or running its commands, and return the lines to answer with.
+    pub(crate) fn feed(&mut self, data: &[u8], context: &Context) -> Vec<String> {
+        let mut replies = vec![];
+        for item in self.input.feed(data) {
+            match item {
+                Item::Input(data) if self.allows(Permission::Type) => context.session.write(&data),
+                Item::Input(_) if !self.denied_typing => {
+                    self.denied_typing = true;
+                    let needs = needs(&self.messages, Permission::Type);
+                    replies.push(reply(Err(needs)));
+                }
+                Item::Input(_) => (),
+                Item::Command(command) => replies.push(reply(self.run(&command, context))),
+                Item::UploadStart { name, size } => match self.start_upload(&name, size) {
+                    Ok(receiving) => self.upload = Some(Ok(receiving)),
+                    Err(e) => replies.push(reply(Err(e))),
+                },
+                Item::UploadData(data) => self.receive(&data),
+                Item::UploadEnd => {
+                    if let Some(received) = self.upload.take() {
+                        let uploaded = received.map(|receiving| self.uploaded(receiving, context));
+                        replies.push(reply(uploaded));
+                    }
+                }
+            }
+        }
+        replies
+    }
+
+    fn run(&mut self, command: &str, context: &Context) -> Result<String, String> {
+        match parse_command(&self.messages, command)? {
+            Request::Auth(token) => self.authenticate(&token),
+            Request::Image(path) => {
+                self.require(Permission::Push)?;
+                let path = self.allowed_image(&path)?;
+                *context.selected_image.lock().unwrap() = Some(path.clone());
+                Ok(self.messages.text_with("remote.image", &[("path", &path)]))
+            }
+            Request::Line(change) => {
+                self.require(Permission::Settings)?;
+                context.session.change_line(change);
+                Ok(self.messages.text("remote.line_changed"))
+            }
+        }
+    }
+
+    /// The image at `path` if it is one of the settings, or its canonical path
+    /// if it is in the host directory.
+    fn allowed_image(&self, path: &str) -> Result<String, String> {
+        if self.images.iter().any(|image| image == path) {
+            return Ok(path.into());
+        }
+        let not_allowed = || {
+            self.messages
+                .text_with("remote.image_not_allowed", &[("path", &path)])
+        };
+        let root = self.host_dir.as_ref().ok_or_else(not_allowed)?;
+        let root = fs::canonicalize(root).map_err(|_| not_allowed())?;
+        // Links and `..` are resolved before the image is known to be there.
+        match fs::canonicalize(root.join(path)) {
+            Ok(image) if image.starts_with(&root) => Ok(image.display().to_string()),
+            _ => Err(not_allowed()),
+        }
+    }
+
+    /// Create the file the image `name` of `size` bytes is uploaded to, before
+    /// any of its data is received.
+    fn start_upload(&self, name: &str, size: usize) -> Result<Receiving, String> {
+        self.require(Permission::Push)?;
+        // Only the name is kept, the image can't be written anywhere else.
+        let name = Path::new(name).file_name().ok_or_else(|| {
+            self.messages
+                .text_with("remote.invalid_name", &[("name", &name)])
+        })?;
+        let not_saved = |e: io::Error| {
+            self.messages
+                .text_with("remote.not_saved", &[("error", &e)])
+        };
+        let path = upload_dir().map_err(not_saved)?.join(name);
+        // The image uploaded before under the same name is replaced.
+        let _ = fs::remove_file(&path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(not_saved)?;
+        Ok(Receiving { path, file, size })
+    }
+
+    /// Write the `data` of the image being uploaded, if any.
+    fn receive(&mut self, data: &[u8]) {
+        if let Some(Ok(receiving)) = &mut self.upload {
+            if let Err(e) = receiving.file.write_all(data) {
+                let _ = fs::remove_file(&receiving.path);
+                let not_saved = self
+                    .messages
+                    .text_with("remote.not_saved", &[("error", &e)]);
+                self.upload = Some(Err(not_saved));
+            }
+        }
+    }
+
+    /// Choose the image received in full for the next push.
+    fn uploaded(&self, receiving: Receiving, context: &Context) -> String {
+        let path = receiving.path.display().to_string();
+        info!("console client uploaded {}", path);
+        *context.selected_image.lock().unwrap() = Some(path.clone());
+        self.messages.text_with(
+            "remote.uploaded",
+            &[("path", &path), ("size", &HumanSize(receiving.size as u64))],
+        )
+    }
+
+    fn authenticate(&mut self, token: &str) -> Result<String, String> {
+        if self.rules.is_empty() {
+            return Ok(self.messages.text("remote.no_token"));
+        }
+        if self.socket == Socket::Tcp {
+            info!("console client denied, token sent over TCP");
+            return Err(self.messages.text("remote.token_in_cleartext"));
+        }
+        match self
+            .rules
+            .iter()
+            .find(|rule| same_token(&rule.token, token))
+        {
+            Some(rule) => {
+                self.permissions = rule.permissions.clone();
+                self.denied_typing = false;
+                let names: Vec<_> = self.permissions.iter().map(|p| p.to_string()).collect();
+                info!("console client granted {}", names.join(", "));
+                let granted = names.join(", ");
+                Ok(self
+                    .messages
+                    .text_with("remote.granted", &[("permissions", &granted)]))
+            }
+            None => {
+                info!("console client denied, unknown token");
+                thread::sleep(FAILED_AUTH_DELAY);
+                Err(self.messages.text("remote.unknown_token"))
+            }
+        }
+    }
+
+    fn require(&self, permission: Permission) -> Result<(), String> {
+        if self.allows(permission) {
+            Ok(())
+        } else {
+            Err(needs(&self.messages, permission))
+        }
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// A command of a client.
+#[derive(Debug, Eq, PartialEq)]
+enum Request {
+    Auth(String),
+    Image(String),
+    Line(LineChange),
+}
+
+fn parse_command(messages: &Catalog, command: &str) -> Result<Request, String> {
+    let (name, argument) = match command.trim().split_once(' ') {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command.trim(), ""),
+    };
+    let invalid = || {
+        messages.text_with(
+            "remote.invalid_argument",
+            &[("argument", &argument), ("command", &name)],
+        )
+    };
+    match name {
+        "auth" => Ok(Request::Auth(argument.into())),
+        "image" if !argument.is_empty() => Ok(Request::Image(argument.into())),
+        "image" => Err(invalid()),
+        "upload" => Err(messages.text_with(
+            "remote.upload_usage",
+            &[("size", &HumanSize(MAX_UPLOAD as u64))],
+        )),
+        "baud" => match argument.parse() {
+            Ok(baud_rate) if baud_rate > 0 => Ok(Request::Line(LineChange::BaudRate(baud_rate))),
+            _ => Err(invalid()),
+        },
+        "parity" => match argument {
+            "none" => Ok(Request::Line(LineChange::Parity(Parity::None))),
+            "odd" => Ok(Request::Line(LineChange::Parity(Parity::Odd))),
+            "even" => Ok(Request::Line(LineChange::Parity(Parity::Even))),
+            _ => Err(invalid()),
+        },
+        "flow" => match argument {
+            "none" => Ok(Request::Line(LineChange::FlowControl(FlowControl::None))),
+            "soft" => Ok(Request::Line(LineChange::FlowControl(
+                FlowControl::Software,
+            ))),
+            "hard" => Ok(Request::Line(LineChange::FlowControl(
+                FlowControl::Hardware,
+            ))),
+            _ => Err(invalid()),
+        },
+        _ => Err(messages.text_with("remote.unknown_command", &[("command", &name)])),
+    }
+}
+
+fn reply(result: Result<String, String>) -> String {
+    match result {
+        Ok(reply) => format!("[BC] ok: {}", reply),
+        Err(e) => format!("[BC] error: {}", e),
+    }
+}
+
+fn needs(messages: &Catalog, permission: Permission) -> String {
+    messages.text_with("remote.needs", &[("permission", &permission)])
+}
+
+/// Compare the tokens in a time which does not tell how much of them matched.
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// What a client wrote, told apart.
+#[derive(Debug, Eq, PartialEq)]
+enum Item {
+    /// To be typed on the device console.
+    Input(Vec<u8>),
+    /// A command line, without the `~`.
+    Command(String),
+    /// A kernel image announced with `~upload`, its data coming next.
+    UploadStart { name: String, size: usize },
+    /// Some data of the image being uploaded, as it is received.
+    UploadData(Vec<u8>),
+    /// The image being uploaded was received in full.
+    UploadEnd,
+}
+
+/// An image being uploaded to a file.
+#[derive(Debug)]
+struct Receiving {
+    path: PathBuf,
+    file: File,
+    size: usize,
+}
+
+/// The private directory the uploaded images are written to, created with a
+/// random name on the first upload, so that only the user of `bootcom` can
+/// enter it.
+fn upload_dir() -> io::Result<PathBuf> {
+    static DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    let mut dir = DIR.lock().unwrap();
+    if let Some(dir) = &*dir {
+        return Ok(dir.clone());
+    }
+    loop {
+        let random = RandomState::new().build_hasher().finish();
+        let path = env::temp_dir().join(format!("bootcom-{}-{:016x}", process::id(), random));
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        // Not created if anything is there already, a link included.
+        match builder.create(&path) {
+            Ok(()) => {
+                *dir = Some(path.clone());
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The name and the size of the image announced by the `command`, if it is a
+/// valid `~upload`.
+fn upload(command: &str) -> Option<(String, usize)> {
+    let mut words = command.trim().splitn(3, ' ');
+    if words.next() != Some("upload") {
+        return None;
+    }
+    let size = words
+        .next()?
+        .parse()
+        .ok()
+        .filter(|size| *size > 0 && *size <= MAX_UPLOAD)?;
+    let name = words
+        .next()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())?;
+    Some((name.into(), size))
+}
+
+/// Splits what a client writes into console input, commands and uploads.
+#[derive(Debug)]
+struct ClientInput {
+    at_line_start: bool,
+    /// The command being received, if any.
+    command: Option<Vec<u8>>,
+    /// Whether a line feed right after the end of a command is part of it.
+    skip_lf: bool,
+    /// The number of bytes of the image being uploaded yet to come, if any.
+    upload: Option<usize>,
+}
+impl Default for ClientInput {
+    fn default() -> Self {
+        ClientInput {
+            at_line_start: true,
+            command: None,
+            skip_lf: false,
+            upload: None,
+        }
+    }
+}
+impl ClientInput {
+    fn feed(&mut self, data: &[u8]) -> Vec<Item> {
+        let mut items = vec![];
+        let mut input = vec![];
+        let mut data = data;
+        while let Some((&byte, rest)) = data.split_first() {
+            // The data of an upload is passed on as is.
+            if let Some(left) = &mut self.upload {
+                let (chunk, rest) = data.split_at(data.len().min(*left));
+                *left -= chunk.len();
+                items.push(Item::UploadData(chunk.to_vec()));
+                data = rest;
+                if *left == 0 {
+                    self.upload = None;
+                    items.push(Item::UploadEnd);
+                }
+                continue;
+            }
+            data = rest;
+            if std::mem::take(&mut self.skip_lf) && byte == b'\n' {
+                continue;
+            }
+            if let Some(command) = &mut self.command {
+                match byte {
+                    b'\r' | b'\n' => {
+                        if !input.is_empty() {
+                            items.push(Item::Input(std::mem::take(&mut input)));
+                        }
+                        let command = String::from_utf8_lossy(command).into_owned();
+                        self.command = None;
+                        // The image follows its command line right away.
+                        match upload(&command) {
+                            Some((name, size)) => {
+                                items.push(Item::UploadStart { name, size });
+                                self.upload = Some(size);
+                            }
+                            None => {
+                                items.push(Item::Command(command));
+                                self.skip_lf = byte == b'\r';
+                            }
+                        }
+                    }
+                    b'~' if command.is_empty() => {
+                        self.command = None;
+                        self.at_line_start = false;
+                        input.push(byte);
+                    }
+                    _ => command.push(byte),
+                }
+            } else if self.at_line_start && byte == b'~' {
+                self.command = Some(vec![]);
+            } else {
+                self.at_line_start = byte == b'\r' || byte == b'\n';
+                input.push(byte);
+            }
+        }
+        if !input.is_empty() {
+            items.push(Item::Input(input));
+        }
+        items
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn commands_are_told_apart() {
+    let mut input = ClientInput::default();
+    assert_eq!(
+        input.feed(b"ls\r~image kernel8.img\r\ncd ~\n~~/x\n~ba"),
+        vec![
+            Item::Input(b"ls\r".to_vec()),
+            Item::Command("image kernel8.img".into()),
+            Item::Input(b"cd ~\n~/x\n".to_vec()),
+        ]
+    );
+    assert_eq!(
+        input.feed(b"ud 115200\n"),
+        vec![Item::Command("baud 115200".into())]
+    );
+    assert_eq!(
+        input.feed(b"~upload 3 k.img\n\r\n"),
+        vec![
+            Item::UploadStart {
+                name: "k.img".into(),
+                size: 3
+            },
+            Item::UploadData(b"\r\n".to_vec()),
+        ]
+    );
+    assert_eq!(
+        input.feed(b"~ls\n"),
+        vec![
+            Item::UploadData(b"~".to_vec()),
+            Item::UploadEnd,
+            Item::Input(b"ls\n".to_vec()),
+        ]
+    );
+    assert_eq!(
+        input.feed(b"~upload 0 k.img\n"),
+        vec![Item::Command("upload 0 k.img".into())]
+    );
+    let messages = Catalog::default();
+    assert_eq!(
+        parse_command(&messages, "flow soft"),
+        Ok(Request::Line(LineChange::FlowControl(
+            FlowControl::Software
+        )))
+    );
+    assert!(parse_command(&messages, "baud fast").is_err());
+    assert!(parse_command(&messages, "reboot")
+        .unwrap_err()
+        .contains("unknown"));
+}
+
+#[test]
+fn permissions_are_granted_by_token() {
+    use crate::settings::SettingsBuilder;
+
+    let context = Context::default();
+    let rules = vec![AccessRule {
+        token: "team-a".into(),
+        permissions: vec![Permission::View, Permission::Push],
+    }];
+    let settings = SettingsBuilder::default()
+        .access(rules)
+        .kernel_image("a.img")
+        .finalize();
+    let mut client = Client::new(&settings, Socket::Unix);
+    assert!(!client.allows(Permission::View));
+    assert_eq!(
+        client.feed(b"ls\n~image a.img\n", &context),
+        vec![
+            "[BC] error: the `type` permission is needed",
+            "[BC] error: the `push` permission is needed",
+        ]
+    );
+    assert_eq!(
+        client.feed(b"~auth team-b\n", &context),
+        vec!["[BC] error: unknown token"]
+    );
+    assert_eq!(
+        client.feed(b"~auth team-a\n~image a.img\n~baud 9600\n", &context),
+        vec![
+            "[BC] ok: granted view, push",
+            "[BC] ok: next kernel image: a.img",
+            "[BC] error: the `settings` permission is needed",
+        ]
+    );
+    assert_eq!(
+        context.selected_image.lock().unwrap().as_deref(),
+        Some("a.img")
+    );
+    assert!(context.session.take_input().is_empty());
+
+    let mut anyone = Client::new(&SettingsBuilder::default().finalize(), Socket::Tcp);
+    anyone.feed(b"ls\n~parity even\n", &context);
+    assert_eq!(context.session.take_input(), b"ls\n");
+    assert_eq!(
+        context.session.take_line_changes(),
+        vec![LineChange::Parity(Parity::Even)]
+    );
+
+    let replies = anyone.feed(b"~upload 2 ../../etc/k8.img\nOK", &context);
+    let image = context.selected_image.lock().unwrap().clone().unwrap();
+    assert_eq!(fs::read(&image).unwrap(), b"OK");
+    fs::remove_file(&image).unwrap();
+    assert!(image.ends_with("k8.img") && !image.contains(".."));
+    assert_eq!(
+        replies,
+        vec![format!("[BC] ok: next kernel image: {} (2 B)", image)]
+    );
+}
+
+#[test]
+fn uploads_without_permission_are_discarded() {
+    use crate::settings::SettingsBuilder;
+
+    let context = Context::default();
+    let rules = vec![AccessRule {
+        token: "team-a".into(),
+        permissions: vec![Permission::Push],
+    }];
+    let mut client = Client::new(
+        &SettingsBuilder::default().access(rules).finalize(),
+        Socket::Unix,
+    );
+    assert_eq!(
+        client.feed(b"~upload 268435456 k.img\n", &context),
+        vec!["[BC] error: the `push` permission is needed"]
+    );
+    assert!(client.feed(&[0; 4096], &context).is_empty());
+    assert!(client.upload.is_none());
+    assert!(context.selected_image.lock().unwrap().is_none());
+    assert!(context.session.take_input().is_empty());
+}
+
+#[test]
+fn images_are_chosen_from_the_settings_or_the_host_directory() {
+    use crate::settings::SettingsBuilder;
+
+    let root = env::temp_dir().join(format!("bootcom-images-{}", process::id()));
+    fs::create_dir_all(root.join("build")).unwrap();
+    fs::write(root.join("build/k.img"), b"k").unwrap();
+    let settings = SettingsBuilder::default()
+        .kernel_image("/srv/a.img")
+        .host_dir(root.to_str().unwrap())
+        .finalize();
+    let context = Context::default();
+    let mut client = Client::new(&settings, Socket::Tcp);
+    let replies = client.feed(
+        b"~image /srv/a.img\n~image /etc/shadow\n~image build/../../k.img\n~image build/k.img\n",
+        &context,
+    );
+    let image = context.selected_image.lock().unwrap().clone().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+    assert!(Path::new(&image).ends_with("build/k.img"));
+    assert_eq!(
+        replies,
+        vec![
+            "[BC] ok: next kernel image: /srv/a.img".to_string(),
+            "[BC] error: `/etc/shadow` is neither a kernel image of the settings nor in the host directory".into(),
+            "[BC] error: `build/../../k.img` is neither a kernel image of the settings nor in the host directory".into(),
+            format!("[BC] ok: next kernel image: {}", image),
+        ]
+    );
+}
+
+#[test]
+fn tokens_are_refused_over_tcp() {
+    use crate::settings::SettingsBuilder;
+
+    let rules = vec![AccessRule {
+        token: "team-a".into(),
+        permissions: vec![Permission::View],
+    }];
+    let settings = SettingsBuilder::default().access(rules).finalize();
+    let mut client = Client::new(&settings, Socket::Tcp);
+    assert_eq!(
+        client.feed(b"~auth team-a\n", &Context::default()),
+        vec!["[BC] error: tokens are only accepted on the Unix sockets, they would travel in cleartext over TCP"]
+    );
+    assert!(!client.allows(Permission::View));
+}
@@ -0,0 +1,108 @@
+//! Coordination of the rendering on the terminal.
+//!
+//! The console output received from the device, the messages of background
+//! tasks, the progress bars and the interactive prompts all share the same
+//! terminal, and garble each other when they interleave. While a prompt or a
+//! progress bar is active, it holds a [`RenderPause`]: the console output and
+//! the messages are then kept aside, and rendered when the last pause ends.
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use super::HumanSize;
+
+/// How much held output is kept before dropping the excess.
+const MAX_HELD: usize = 1024 * 1024;
+
+struct RenderState {
+    /// The number of active pauses.
+    pauses: usize,
+    /// The output held during the pauses.
+    held: Vec<u8>,
+    /// The number of bytes dropped because too much output was held.
+    dropped: usize,
+}
+
+static STATE: Mutex<RenderState> = Mutex::new(RenderState {
+    pauses: 0,
+    held: Vec::new(),
+    dropped: 0,
+});
+
+/// Keeps the rendering paused for as long as it lives.
+#[must_use = "rendering resumes as soon as the pause is dropped"]
+pub(crate) struct RenderPause {
+    // Only created by `pause`.
+    _private: (),
+}
+impl Drop for RenderPause {
+    fn drop(&mut self) {
+        let mut state = STATE.lock().unwrap();
+        state.pauses -= 1;
+        if state.pauses == 0 {
+            let held = std::mem::take(&mut state.held);
+            let dropped = std::mem::replace(&mut state.dropped, 0);
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(&held);
+            if dropped > 0 {
+                let _ = writeln!(
+                    stdout,
+                    "[BC] ✂️  {} of output dropped",
+                    HumanSize(dropped as u64)
+                );
+            }
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// Pause the rendering of the console output and of the messages until the
+/// returned guard is dropped. Pauses can be nested.
+pub(crate) fn pause() -> RenderPause {
+    STATE.lock().unwrap().pauses += 1;
+    RenderPause { _private: () }
+}
+
+/// Render `data` on the terminal, or hold it if the rendering is paused.
+pub(crate) fn write(data: &[u8]) -> io::Result<()> {
+    let mut state = STATE.lock().unwrap();
+    if state.pauses > 0 {
+        hold(&mut state, data);
+        return Ok(());
+    }
+    let mut stdout = io::stdout();
+    stdout.write_all(data)?;
+    stdout.flush()
+}
+
+/// Render a message line from a background task, held like the console output
+/// if the rendering is paused.
+pub(crate) fn message(line: &str) {
+    let _ = write(format!("{}\n", line).as_bytes());
+}
+
+fn hold(state: &mut RenderState, data: &[u8]) {
+    let room = MAX_HELD.saturating_sub(state.held.len());
+    let kept = std::cmp::min(room, data.len());
+    state.held.extend_from_slice(&data[..kept]);
+    state.dropped += data.len() - kept;
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn held_output_is_capped() {
+    let mut state = RenderState {
+        pauses: 1,
+        held: vec![0; MAX_HELD - 2],
+        dropped: 0,
+    };
+    hold(&mut state, b"abcd");
+    assert_eq!(state.held.len(), MAX_HELD);
+    assert_eq!(&state.held[MAX_HELD - 2..], b"ab");
+    assert_eq!(state.dropped, 2);
+}
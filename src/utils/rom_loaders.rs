@@ -0,0 +1,220 @@
+//! Detection of the ROM loaders of the microcontrollers found in mixed labs.
+//!
+//! A board waiting in the ROM loader of an ESP32 or an STM32 never sends the
+//! trigger of a kernel image request, and the session would otherwise sit in
+//! terminal mode forever. The Espressif ROM loader tells it is waiting for a
+//! download on the console, at any time. The STM32 system bootloader is
+//! silent, so the boards on a USB serial controller known to belong to these
+//! chips (the ST-LINK of the Nucleo and Discovery boards, the USB peripheral
+//! of the Espressif chips) are only suspected when the console stays silent
+//! for a while after the connection.
+//!
+//! Once per session, guidance on how to flash the board is shown, or the
+//! flasher configured for the ROM loader is run when it announced itself.
+
+use std::{
+    io,
+    process::{Output, Stdio},
+    time::{Duration, Instant},
+};
+
+use serialport::{available_ports, SerialPortType};
+
+use super::shell;
+use crate::settings::{Flasher, RomLoader};
+
+/// How long the console of a suspected board stays silent before guidance is
+/// shown.
+const SILENCE: Duration = Duration::from_secs(5);
+
+/// What the ROM loaders print when they wait for a download.
+const BANNERS: [(&[u8], RomLoader); 3] = [
+    // All the ESP32 variants.
+    (b"waiting for download", RomLoader::Esp),
+    (b"DOWNLOAD_BOOT", RomLoader::Esp),
+    // The ESP8266, at 74880 baud.
+    (b"boot mode:(1,", RomLoader::Esp),
+];
+
+/// The USB serial controllers of the boards of the ROM loaders.
+const USB_IDS: [((u16, u16), RomLoader); 8] = [
+    // USB-Serial/JTAG of the ESP32-C3, -S3, -C6 and -H2.
+    ((0x303a, 0x1001), RomLoader::Esp),
+    // USB-OTG of the ESP32-S2 in download mode.
+    ((0x303a, 0x0002), RomLoader::Esp),
+    // The ST-LINK/V2-1 and V3 of the Nucleo and Discovery boards.
+    ((0x0483, 0x374b), RomLoader::Stm32),
+    ((0x0483, 0x374e), RomLoader::Stm32),
+    ((0x0483, 0x374f), RomLoader::Stm32),
+    ((0x0483, 0x3752), RomLoader::Stm32),
+    ((0x0483, 0x3753), RomLoader::Stm32),
+    ((0x0483, 0x3754), RomLoader::Stm32),
+];
+
+/// How a ROM loader was detected.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Detection {
+    /// It printed its banner.
+    Banner(RomLoader),
+    /// The board is on one of its USB serial controllers and stayed silent.
+    Silent(RomLoader),
+}
+
+/// Looks for the ROM loaders in the console output of a session.
+#[derive(Debug, Default)]
+pub(crate) struct RomLoaderCheck {
+    /// The ROM loader of the USB serial controller of the port, if it is a
+    /// known one.
+    suspected: Option<RomLoader>,
+    /// When the port was opened.
+    connected: Option<Instant>,
+    /// The end of the last output, for the banners split across reads.
+    tail: Vec<u8>,
+    /// Whether the device sent anything.
+    talked: bool,
+    /// Only the first detection of the session matters.
+    detected: bool,
+}
+impl RomLoaderCheck {
+    /// Check the console of the port at `path`.
+    pub(crate) fn new(path: Option<&str>) -> Self {
+        let usb_ids = path.and_then(|path| {
+            available_ports()
+                .ok()?
+                .into_iter()
+                .find_map(|port| match port.port_type {
+                    SerialPortType::UsbPort(info) if port.port_name == path => {
+                        Some((info.vid, info.pid))
+                    }
+                    _ => None,
+                })
+        });
+        RomLoaderCheck::with_usb_ids(usb_ids)
+    }
+
+    fn with_usb_ids(usb_ids: Option<(u16, u16)>) -> Self {
+        let suspected = USB_IDS
+            .iter()
+            .find(|(ids, _)| Some(*ids) == usb_ids)
+            .map(|(_, loader)| *loader);
+        RomLoaderCheck {
+            suspected,
+            ..RomLoaderCheck::default()
+        }
+    }
+
+    /// The port was (re)opened at `now`.
+    pub(crate) fn connected(&mut self, now: Instant) {
+        self.connected = Some(now);
+    }
+
+    /// Look for a banner in the console `data`.
+    pub(crate) fn output(&mut self, data: &[u8]) -> Option<Detection> {
+        if data.is_empty() || self.detected {
+            return None;
+        }
+        self.talked = true;
+        let longest = BANNERS.iter().map(|(banner, _)| banner.len()).max();
+        self.tail.extend_from_slice(data);
+        let found = BANNERS.iter().find(|(banner, _)| {
+            self.tail
+                .windows(banner.len())
+                .any(|window| window == *banner)
+        });
+        let keep = self.tail.len().saturating_sub(longest.unwrap_or(0));
+        self.tail.drain(..keep);
+        let (_, loader) = found?;
+        self.detected = true;
+        Some(Detection::Banner(*loader))
+    }
+
+    /// Suspect the ROM loader of the USB serial controller when the device
+    /// never sent anything since the port was opened, at `now`.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<Detection> {
+        let loader = self.suspected?;
+        let connected = self.connected?;
+        if self.detected || self.talked || now.duration_since(connected) < SILENCE {
+            return None;
+        }
+        self.detected = true;
+        Some(Detection::Silent(loader))
+    }
+}
+
+/// The name of the `loader` for the user.
+pub(crate) fn title(loader: RomLoader) -> &'static str {
+    match loader {
+        RomLoader::Esp => "Espressif ROM loader",
+        RomLoader::Stm32 => "STM32 system bootloader",
+    }
+}
+
+/// Run the `flasher` on the port at `path` with the kernel `image`, while the
+/// port is closed. Its output is collected, it would garble the console.
+pub(crate) fn run_flasher(flasher: &Flasher, path: &str, image: &str) -> io::Result<Output> {
+    let command = flasher
+        .command
+        .replace("{port}", path)
+        .replace("{image}", image);
+    shell(&command)
+        .env("BOOTCOM_PORT", path)
+        .env("BOOTCOM_IMAGE", image)
+        .stdin(Stdio::null())
+        .output()
+}
+
+/// How to deal with a board in the `loader`, line by line.
+pub(crate) fn guidance(loader: RomLoader, path: &str) -> Vec<String> {
+    match loader {
+        RomLoader::Esp => vec![
+            "It speaks the esptool protocol, not the ones of bootcom. Flash it with".into(),
+            format!("  esptool.py --port {} write_flash <offset> <image>", path),
+            "or reset it with GPIO0 high to run the application.".into(),
+        ],
+        RomLoader::Stm32 => vec![
+            "Its system bootloader is silent and speaks the AN3155 protocol, not the".into(),
+            "ones of bootcom. Flash it through the ST-LINK or the UART with".into(),
+            "  st-flash write <image> 0x8000000".into(),
+            format!("  stm32flash -w <image> -v -g 0x0 {}", path),
+            "or reset it with BOOT0 low to run the application.".into(),
+        ],
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn banners_and_silent_boards() {
+    let mut check = RomLoaderCheck::with_usb_ids(None);
+    assert_eq!(
+        check.output(b"rst:0x1 (POWERON_RESET),boot:0x3 (DOWNLOAD_BOO"),
+        None
+    );
+    assert_eq!(
+        check.output(b"T(UART0/UART1/SDIO_REI_REO_V2))\r\nwaiting for download\r\n"),
+        Some(Detection::Banner(RomLoader::Esp))
+    );
+    // Once per session.
+    assert_eq!(check.output(b"waiting for download\r\n"), None);
+
+    let start = Instant::now();
+    let mut check = RomLoaderCheck::with_usb_ids(Some((0x0483, 0x374b)));
+    check.connected(start);
+    assert_eq!(check.poll(start + Duration::from_secs(1)), None);
+    assert_eq!(
+        check.poll(start + SILENCE),
+        Some(Detection::Silent(RomLoader::Stm32))
+    );
+    assert_eq!(check.poll(start + SILENCE), None);
+
+    let mut check = RomLoaderCheck::with_usb_ids(Some((0x0483, 0x374b)));
+    check.connected(start);
+    check.output(b"Hello from the Nucleo\r\n");
+    assert_eq!(check.poll(Instant::now() + SILENCE), None);
+
+    let mut check = RomLoaderCheck::with_usb_ids(Some((0x0403, 0x6001)));
+    check.connected(start);
+    assert_eq!(check.poll(start + SILENCE), None);
+}
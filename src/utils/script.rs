@@ -0,0 +1,266 @@
+//! Playback of console input scripts.
+//!
+//! A script is a text file whose lines are sent to the device one after the
+//! other, as if typed on the console, which is handy to reproduce a bug
+//! scenario needing a fixed sequence of commands. Lines starting with `@` are
+//! directives controlling the playback:
+//!
+//! ```text
+//! # Comments and empty lines are ignored.
+//! @wait login:        wait until `login:` is received from the device
+//! root                sent to the device, followed by a carriage return
+//! @delay 500          pause for 500 ms
+//! @line-delay 100     pause for 100 ms after each line sent from now on
+//! @timeout 60         give up on the following `@wait`s after 60 s (30 s by default)
+//! @@text              sends `@text`
+//! ```
+//!
+//! When a `@wait` times out, the rest of the script is abandoned.
+
+use std::{
+    collections::VecDeque,
+    fs, io,
+    time::{Duration, Instant},
+};
+
+use super::write_paced;
+use crate::settings::PastePacing;
+
+/// Default timeout of the `@wait` directive.
+const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A single step of a script.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Step {
+    /// Send the bytes to the device.
+    Send(Vec<u8>),
+    /// Pause the playback.
+    Delay(Duration),
+    /// Pause the playback until the pattern is received from the device.
+    Wait { pattern: Vec<u8>, timeout: Duration },
+}
+
+/// Parse the `text` of a script into its steps. Errors mention the offending
+/// line number.
+pub(crate) fn parse(text: &str) -> Result<Vec<Step>, String> {
+    let mut steps = vec![];
+    let mut line_delay = Duration::from_millis(0);
+    let mut timeout = DEFAULT_WAIT_TIMEOUT;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let text = match line.strip_prefix('@') {
+            // A doubled `@` escapes a line to be sent as is.
+            Some(literal) if literal.starts_with('@') => literal,
+            Some(directive) => {
+                let mut parts = directive.splitn(2, ' ');
+                let name = parts.next().unwrap_or_default();
+                let argument = parts.next().unwrap_or_default().trim();
+                let number_argument = || {
+                    argument
+                        .parse::<u64>()
+                        .map_err(|_| format!("line {}: `@{}` needs a number", number, name))
+                };
+                match name {
+                    "delay" => steps.push(Step::Delay(Duration::from_millis(number_argument()?))),
+                    "line-delay" => line_delay = Duration::from_millis(number_argument()?),
+                    "timeout" => timeout = Duration::from_secs(number_argument()?),
+                    "wait" if !argument.is_empty() => steps.push(Step::Wait {
+                        pattern: argument.as_bytes().to_vec(),
+                        timeout,
+                    }),
+                    "wait" => return Err(format!("line {}: `@wait` needs a pattern", number)),
+                    _ => return Err(format!("line {}: unknown directive `@{}`", number, name)),
+                }
+                continue;
+            }
+            None => line,
+        };
+        steps.push(Step::Send(format!("{}\r", text).into_bytes()));
+        if line_delay > Duration::from_millis(0) {
+            steps.push(Step::Delay(line_delay));
+        }
+    }
+    Ok(steps)
+}
+
+/// The outcome of advancing the playback of a script.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Playback {
+    /// Some steps remain to be played.
+    Running,
+    /// All the steps were played.
+    Finished,
+    /// The given pattern was not received in time, the playback stopped.
+    TimedOut(String),
+}
+
+/// Plays a script back, step by step, as the console output is received.
+///
+/// The player never blocks, except for the pacing of the lines it writes: it
+/// is polled regularly with [`poll`] and fed with the data received from the
+/// device with [`output`].
+///
+/// [`poll`]: ScriptPlayer::poll
+/// [`output`]: ScriptPlayer::output
+#[derive(Debug)]
+pub(crate) struct ScriptPlayer {
+    steps: VecDeque<Step>,
+    /// When the current `Delay` step ends.
+    resume_at: Option<Instant>,
+    /// When the current `Wait` step times out.
+    deadline: Option<Instant>,
+    /// Data received while waiting for a pattern, trimmed to what could still
+    /// be part of a match.
+    received: Vec<u8>,
+}
+impl ScriptPlayer {
+    pub(crate) fn new(steps: Vec<Step>) -> Self {
+        ScriptPlayer {
+            steps: steps.into(),
+            resume_at: None,
+            deadline: None,
+            received: vec![],
+        }
+    }
+
+    /// Load and parse the script at `path`.
+    pub(crate) fn load(path: &str) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let steps = parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(ScriptPlayer::new(steps))
+    }
+
+    /// Feed the data received from the device, to be matched by `@wait`.
+    pub(crate) fn output(&mut self, data: &[u8]) {
+        if let Some(Step::Wait { pattern, .. }) = self.steps.front() {
+            self.received.extend_from_slice(data);
+            if contains(&self.received, pattern) {
+                self.steps.pop_front();
+                self.deadline = None;
+                self.received.clear();
+            } else {
+                let keep = std::cmp::min(self.received.len(), pattern.len() - 1);
+                self.received.drain(..self.received.len() - keep);
+            }
+        }
+    }
+
+    /// Play all the steps that are due at time `now`, writing the lines to
+    /// `device` paced like pasted text.
+    pub(crate) fn poll(
+        &mut self,
+        now: Instant,
+        device: &mut dyn io::Write,
+        pacing: &PastePacing,
+    ) -> io::Result<Playback> {
+        while let Some(step) = self.steps.front() {
+            match step {
+                Step::Send(line) => write_paced(device, line, pacing)?,
+                Step::Delay(delay) => {
+                    let resume_at = *self.resume_at.get_or_insert(now + *delay);
+                    if now < resume_at {
+                        return Ok(Playback::Running);
+                    }
+                    self.resume_at = None;
+                }
+                Step::Wait { pattern, timeout } => {
+                    let deadline = *self.deadline.get_or_insert(now + *timeout);
+                    if now < deadline {
+                        return Ok(Playback::Running);
+                    }
+                    let pattern = String::from_utf8_lossy(pattern).into_owned();
+                    self.steps.clear();
+                    return Ok(Playback::TimedOut(pattern));
+                }
+            }
+            self.steps.pop_front();
+        }
+        Ok(Playback::Finished)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn parse_directives() {
+    let steps = parse("# boot\n@wait login:\nroot\n@line-delay 10\n@@home\n@delay 5\n").unwrap();
+    assert_eq!(
+        steps,
+        vec![
+            Step::Wait {
+                pattern: b"login:".to_vec(),
+                timeout: DEFAULT_WAIT_TIMEOUT
+            },
+            Step::Send(b"root\r".to_vec()),
+            Step::Send(b"@home\r".to_vec()),
+            Step::Delay(Duration::from_millis(10)),
+            Step::Delay(Duration::from_millis(5)),
+        ]
+    );
+    assert!(parse("@sleep 10").unwrap_err().starts_with("line 1"));
+    assert!(parse("\n@delay soon").unwrap_err().starts_with("line 2"));
+}
+
+#[test]
+fn playback_waits_for_output_and_delays() {
+    let mut player = ScriptPlayer::new(parse("@wait login:\nroot\n@delay 100\nls").unwrap());
+    let mut device = vec![];
+    let start = Instant::now();
+
+    assert_eq!(
+        player
+            .poll(start, &mut device, &PastePacing::default())
+            .unwrap(),
+        Playback::Running
+    );
+    player.output(b"board lo");
+    player.output(b"gin: ");
+    assert_eq!(
+        player
+            .poll(start, &mut device, &PastePacing::default())
+            .unwrap(),
+        Playback::Running
+    );
+    assert_eq!(device, b"root\r");
+
+    let later = start + Duration::from_millis(100);
+    assert_eq!(
+        player
+            .poll(later, &mut device, &PastePacing::default())
+            .unwrap(),
+        Playback::Finished
+    );
+    assert_eq!(device, b"root\rls\r");
+}
+
+#[test]
+fn playback_stops_on_wait_timeout() {
+    let mut player = ScriptPlayer::new(parse("@timeout 1\n@wait $\nreboot").unwrap());
+    let mut device = vec![];
+    let start = Instant::now();
+    assert_eq!(
+        player
+            .poll(start, &mut device, &PastePacing::default())
+            .unwrap(),
+        Playback::Running
+    );
+    let later = start + Duration::from_secs(1);
+    assert_eq!(
+        player
+            .poll(later, &mut device, &PastePacing::default())
+            .unwrap(),
+        Playback::TimedOut("$".into())
+    );
+    assert!(device.is_empty());
+}
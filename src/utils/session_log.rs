@@ -0,0 +1,164 @@
+//! Logging of the console session to a file, to keep the boot logs and compare
+//! them between kernel builds.
+//!
+//! The log file is appended to, so that the sessions of several runs follow
+//! each other. In the `raw` format the bytes received are logged as is. In the
+//! `lines` format each console line is prefixed with the time it started, its
+//! escape sequences stripped, and each session starts with a header line:
+//!
+//! ```text
+//! # bootcom session of 2023-11-14 22:13:20 UTC
+//! [22:13:20.125 UTC] Booting Linux on physical CPU 0x0
+//! [22:13:21.480 UTC] > reboot
+//! ```
+//!
+//! The data sent to the device is logged too when enabled, the lines typed
+//! starting with `> ` in the `lines` format.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use super::{outputs::OutputSink, stopwatch::time_of_day, HumanDate};
+use crate::codec::{CodecChain, CodecFactory};
+use crate::settings::{LogFormat, SessionLog};
+
+/// Writes the console session to a log file.
+pub(crate) struct SessionLogger<W: Write + Send> {
+    writer: W,
+    format: LogFormat,
+    /// Whether the data sent to the device is logged.
+    sent: bool,
+    /// Strips the escape sequences of the lines logged.
+    strip_ansi: CodecChain,
+    /// Whether the data received last ended a line.
+    line_start: bool,
+    /// The data sent since the last end of line, in the `lines` format.
+    typed: Vec<u8>,
+}
+impl SessionLogger<File> {
+    /// Open the log file of the `log` for appending, creating it if needed.
+    pub(crate) fn open(log: &SessionLog) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log.path)?;
+        SessionLogger::new(file, log.format, log.sent, SystemTime::now())
+    }
+}
+impl<W: Write + Send> SessionLogger<W> {
+    /// Start logging the session started at `time` to the `writer`.
+    pub(crate) fn new(
+        mut writer: W,
+        format: LogFormat,
+        sent: bool,
+        time: SystemTime,
+    ) -> io::Result<Self> {
+        if format == LogFormat::Lines {
+            writeln!(writer, "# bootcom session of {}", HumanDate(time))?;
+        }
+        Ok(SessionLogger {
+            writer,
+            format,
+            sent,
+            strip_ansi: CodecChain::new(&[CodecFactory::builtin("strip-ansi").unwrap()]),
+            line_start: true,
+            typed: vec![],
+        })
+    }
+
+    /// Log the `data` received at `time`.
+    fn received(&mut self, data: &[u8], time: SystemTime) -> io::Result<()> {
+        if self.format == LogFormat::Raw {
+            return self.writer.write_all(data);
+        }
+        let mut text = vec![];
+        for b in self.strip_ansi.decode(data) {
+            if b == b'\r' {
+                continue;
+            }
+            if self.line_start {
+                text.extend_from_slice(format!("[{}] ", time_of_day(time)).as_bytes());
+            }
+            text.push(b);
+            self.line_start = b == b'\n';
+        }
+        self.writer.write_all(&text)
+    }
+
+    /// Log the `data` sent at `time`, if enabled. In the `lines` format, the
+    /// data is logged once a line is typed.
+    fn sent_at(&mut self, data: &[u8], time: SystemTime) -> io::Result<()> {
+        if !self.sent {
+            return Ok(());
+        }
+        if self.format == LogFormat::Raw {
+            return self.writer.write_all(data);
+        }
+        for &b in data {
+            if b != b'\r' && b != b'\n' {
+                self.typed.push(b);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&std::mem::take(&mut self.typed))
+                .chars()
+                .map(|c| match c {
+                    c if c.is_control() => c.escape_default().to_string(),
+                    c => c.to_string(),
+                })
+                .collect::<String>();
+            if !self.line_start {
+                writeln!(self.writer)?;
+                self.line_start = true;
+            }
+            writeln!(self.writer, "[{}] > {}", time_of_day(time), line)?;
+        }
+        Ok(())
+    }
+}
+impl<W: Write + Send> OutputSink for SessionLogger<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.received(data, SystemTime::now())?;
+        self.writer.flush()
+    }
+
+    fn sent(&mut self, data: &[u8]) -> io::Result<()> {
+        self.sent_at(data, SystemTime::now())?;
+        self.writer.flush()
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn lines_logged() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_125);
+    let mut logger = SessionLogger::new(vec![], LogFormat::Lines, true, time).unwrap();
+    logger
+        .received(b"Booting \x1b[32mLinux\x1b[0m\r\n=> ", time)
+        .unwrap();
+    logger.sent_at(b"res", time).unwrap();
+    logger.sent_at(b"et\r", time).unwrap();
+    logger.received(b"reset\r\nresetting ...", time).unwrap();
+
+    assert_eq!(
+        String::from_utf8(logger.writer).unwrap(),
+        "# bootcom session of 2023-11-14 22:13:20 UTC\n\
+         [22:13:20.125 UTC] Booting Linux\n\
+         [22:13:20.125 UTC] => \n\
+         [22:13:20.125 UTC] > reset\n\
+         [22:13:20.125 UTC] reset\n\
+         [22:13:20.125 UTC] resetting ..."
+    );
+
+    let mut logger = SessionLogger::new(vec![], LogFormat::Raw, false, time).unwrap();
+    logger.received(b"\x1b[32mOK\x1b[0m\r\n", time).unwrap();
+    logger.sent_at(b"reset\r", time).unwrap();
+    assert_eq!(logger.writer, b"\x1b[32mOK\x1b[0m\r\n");
+}
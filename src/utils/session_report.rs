@@ -0,0 +1,505 @@
+//! Report of the whole run of `bootcom`, written on exit for attaching to an
+//! issue.
+//!
+//! When a report file is set in the settings, the transfers, the files written
+//! (recording, captures, archived console logs) and the console output are
+//! collected while `bootcom` runs. On exit, they are rendered along with the
+//! effective settings, the timings and the errors of the [`SessionStats`]:
+//! as HTML when the name of the file ends with `.html`, as Markdown otherwise.
+//! Only the first and last lines of the console output are kept.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use console::style;
+
+use super::{banner_entries, HumanDate, HumanDuration, HumanRate, HumanSize};
+use crate::{messages::Catalog, progress::TransferReport, settings::Settings, stats::SessionStats};
+
+/// How many lines are kept from each end of the console output.
+const EXCERPT_LINES: usize = 20;
+
+/// The longest console line kept, longer ones being cut.
+const MAX_LINE: usize = 200;
+
+/// What the report is made of, collected while `bootcom` runs. Cloning it
+/// gives another handle to the same report.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionReport(Option<Arc<Mutex<Collected>>>);
+
+#[derive(Debug)]
+struct Collected {
+    path: String,
+    started: SystemTime,
+    configuration: Vec<(&'static str, String)>,
+    /// The transfers, when they ended, with the id of their archived push.
+    transfers: Vec<(SystemTime, TransferReport, Option<u64>)>,
+    /// The files written, what they are and their path.
+    files: Vec<(String, String)>,
+    first_lines: Vec<String>,
+    last_lines: VecDeque<String>,
+    /// The number of lines received.
+    lines: usize,
+    /// The end of the output, not a full line yet.
+    partial: Vec<u8>,
+    messages: Catalog,
+}
+
+impl SessionReport {
+    /// Start collecting the report set in the `settings`, if any.
+    pub(crate) fn new(settings: &Settings) -> Self {
+        SessionReport(settings.report.as_ref().map(|path| {
+            let mut files = vec![];
+            if let Some(record) = &settings.record {
+                files.push(("recording".into(), record.clone()));
+            }
+            Arc::new(Mutex::new(Collected {
+                path: path.clone(),
+                started: SystemTime::now(),
+                configuration: banner_entries(settings),
+                transfers: vec![],
+                files,
+                first_lines: vec![],
+                last_lines: VecDeque::new(),
+                lines: 0,
+                partial: vec![],
+                messages: settings.messages.clone(),
+            }))
+        }))
+    }
+
+    /// Keep the console output `data`, as rendered, for the excerpt.
+    pub(crate) fn output(&self, data: &[u8]) {
+        if let Some(collected) = &self.0 {
+            let mut collected = collected.lock().unwrap();
+            collected.partial.extend_from_slice(data);
+            while let Some(end) = collected.partial.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = collected.partial.drain(..=end).collect();
+                collected.line(plain_text(&line));
+            }
+        }
+    }
+
+    /// Add the transfer of the `report`, archived as the push `archived`.
+    pub(crate) fn transfer(&self, report: &TransferReport, archived: Option<u64>) {
+        if let Some(collected) = &self.0 {
+            let mut collected = collected.lock().unwrap();
+            collected
+                .transfers
+                .push((SystemTime::now(), report.clone(), archived));
+        }
+    }
+
+    /// Add a file written, `what` it is and its `path`.
+    pub(crate) fn file(&self, what: impl Into<String>, path: impl AsRef<Path>) {
+        if let Some(collected) = &self.0 {
+            let path = path.as_ref().display().to_string();
+            collected.lock().unwrap().files.push((what.into(), path));
+        }
+    }
+
+    /// Write the report, with the `stats` of the run, and tell where.
+    pub(crate) fn write(&self, stats: &SessionStats) {
+        let collected = match &self.0 {
+            Some(collected) => collected.lock().unwrap(),
+            None => return,
+        };
+        let html = collected.path.ends_with(".html");
+        let sections = collected.sections(stats, SystemTime::now());
+        let text = if html {
+            render_html(&sections)
+        } else {
+            render_markdown(&sections)
+        };
+        let messages = &collected.messages;
+        match fs::write(&collected.path, text) {
+            Ok(_) => println!(
+                "{}",
+                messages.text_with("report.written", &[("path", &collected.path)])
+            ),
+            Err(e) => println!(
+                "{}",
+                style(messages.text_with(
+                    "report.not_written",
+                    &[("path", &collected.path), ("error", &e)]
+                ))
+                .red()
+            ),
+        }
+    }
+}
+
+impl Collected {
+    fn line(&mut self, line: String) {
+        self.lines += 1;
+        if self.first_lines.len() < EXCERPT_LINES {
+            self.first_lines.push(line);
+            return;
+        }
+        if self.last_lines.len() == EXCERPT_LINES {
+            self.last_lines.pop_front();
+        }
+        self.last_lines.push_back(line);
+    }
+
+    /// The sections of the report, ended at `now`.
+    fn sections(&self, stats: &SessionStats, now: SystemTime) -> Vec<Section> {
+        let duration = now.duration_since(self.started).unwrap_or_default();
+        let mut sections = vec![Section {
+            title: "bootcom session report",
+            blocks: vec![Block::Text(format!(
+                "From {} to {} ({}).",
+                HumanDate(self.started),
+                HumanDate(now),
+                HumanDuration(duration)
+            ))],
+        }];
+
+        sections.push(Section {
+            title: "Configuration",
+            blocks: vec![Block::Table(
+                vec!["Setting", "Value"],
+                self.configuration
+                    .iter()
+                    .map(|(name, value)| vec![name.to_string(), value.clone()])
+                    .collect(),
+            )],
+        });
+
+        let timings = [
+            ("Sessions", stats.sessions.to_string()),
+            ("Connected", HumanDuration(stats.connected_time).to_string()),
+            ("Received", HumanSize(stats.bytes_received).to_string()),
+            ("Kernels sent", stats.kernels_sent.to_string()),
+            (
+                "Kernel bytes sent",
+                HumanSize(stats.kernel_bytes_sent).to_string(),
+            ),
+            (
+                "Transfer time",
+                HumanDuration(stats.transfer_time).to_string(),
+            ),
+            ("Transfer retries", stats.transfer_retries.to_string()),
+            (
+                "Host service sessions",
+                stats.host_service_sessions.to_string(),
+            ),
+            ("Dumps received", stats.dumps_received.to_string()),
+            ("Baud rate rescans", stats.baud_rescans.to_string()),
+        ];
+        sections.push(Section {
+            title: "Timings",
+            blocks: vec![Block::Table(
+                vec!["", "Total"],
+                timings
+                    .iter()
+                    .map(|(name, value)| vec![name.to_string(), value.clone()])
+                    .collect(),
+            )],
+        });
+
+        let transfers = match self.transfers.as_slice() {
+            [] => Block::Text("No kernel image was sent.".into()),
+            transfers => Block::Table(
+                vec![
+                    "Ended", "Image", "Protocol", "Size", "Duration", "Rate", "Retries", "CRC-32",
+                    "Archive",
+                ],
+                transfers
+                    .iter()
+                    .map(|(at, report, archived)| {
+                        vec![
+                            HumanDate(*at).to_string(),
+                            report.image.clone(),
+                            report.protocol.to_string(),
+                            HumanSize(report.bytes).to_string(),
+                            HumanDuration(report.duration).to_string(),
+                            HumanRate(report.throughput()).to_string(),
+                            report.retries.to_string(),
+                            format!("{:08x}", report.crc),
+                            archived.map_or_else(|| "-".into(), |id| format!("#{}", id)),
+                        ]
+                    })
+                    .collect(),
+            ),
+        };
+        sections.push(Section {
+            title: "Transfers",
+            blocks: vec![transfers],
+        });
+
+        let errors = match stats.error_counts.as_slice() {
+            [] => vec![Block::Text("No errors.".into())],
+            counts => vec![
+                Block::Text(format!("{} error(s).", stats.errors)),
+                Block::Table(
+                    vec!["Error", "Count"],
+                    counts
+                        .iter()
+                        .map(|(error, count)| vec![error.clone(), count.to_string()])
+                        .collect(),
+                ),
+            ],
+        };
+        sections.push(Section {
+            title: "Errors",
+            blocks: errors,
+        });
+
+        let mut console = vec![Block::Text(format!("{} line(s) received.", self.lines))];
+        if !self.first_lines.is_empty() {
+            console.push(Block::Code(self.first_lines.clone()));
+        }
+        if !self.last_lines.is_empty() {
+            let skipped = self.lines - self.first_lines.len() - self.last_lines.len();
+            if skipped > 0 {
+                console.push(Block::Text(format!("{} line(s) skipped.", skipped)));
+            }
+            console.push(Block::Code(self.last_lines.iter().cloned().collect()));
+        }
+        sections.push(Section {
+            title: "Console",
+            blocks: console,
+        });
+
+        let files = match self.files.as_slice() {
+            [] => Block::Text("No files written.".into()),
+            files => Block::Links(files.to_vec()),
+        };
+        sections.push(Section {
+            title: "Files",
+            blocks: vec![files],
+        });
+        sections
+    }
+}
+
+/// A section of the report, rendered as Markdown or HTML.
+struct Section {
+    title: &'static str,
+    blocks: Vec<Block>,
+}
+
+enum Block {
+    Text(String),
+    /// The headers of the columns, and the rows.
+    Table(Vec<&'static str>, Vec<Vec<String>>),
+    /// Preformatted lines.
+    Code(Vec<String>),
+    /// The labels of the links, and their targets.
+    Links(Vec<(String, String)>),
+}
+
+/// The `line` of console output without its end and the escape sequences,
+/// and cut if too long.
+fn plain_text(line: &[u8]) -> String {
+    let text = String::from_utf8_lossy(line);
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // The control sequences end with a byte from `@` to `~`.
+            '\x1b' => match chars.next() {
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                _ => continue,
+            },
+            '\r' | '\n' => (),
+            c if c.is_control() && c != '\t' => (),
+            c => plain.push(c),
+        }
+    }
+    if plain.chars().count() > MAX_LINE {
+        plain = plain.chars().take(MAX_LINE).collect();
+        plain.push('…');
+    }
+    plain
+}
+
+fn render_markdown(sections: &[Section]) -> String {
+    let mut text = String::new();
+    for (i, section) in sections.iter().enumerate() {
+        let level = if i == 0 { "#" } else { "##" };
+        let _ = writeln!(text, "{} {}\n", level, section.title);
+        for block in &section.blocks {
+            match block {
+                Block::Text(paragraph) => {
+                    let _ = writeln!(text, "{}\n", paragraph);
+                }
+                Block::Table(headers, rows) => {
+                    let _ = writeln!(text, "| {} |", headers.join(" | "));
+                    let _ = writeln!(text, "|{}", " --- |".repeat(headers.len()));
+                    for row in rows {
+                        let cells: Vec<String> =
+                            row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+                        let _ = writeln!(text, "| {} |", cells.join(" | "));
+                    }
+                    text.push('\n');
+                }
+                Block::Code(lines) => {
+                    // Longer than any run of backticks in the lines.
+                    let fence = "`".repeat(
+                        lines
+                            .iter()
+                            .flat_map(|line| line.split(|c| c != '`'))
+                            .map(str::len)
+                            .max()
+                            .unwrap_or(0)
+                            .max(2)
+                            + 1,
+                    );
+                    let _ = writeln!(text, "{}text\n{}\n{}\n", fence, lines.join("\n"), fence);
+                }
+                Block::Links(links) => {
+                    for (label, target) in links {
+                        let _ = writeln!(text, "- {}: [`{}`](<{}>)", label, target, target);
+                    }
+                    text.push('\n');
+                }
+            }
+        }
+    }
+    text
+}
+
+fn render_html(sections: &[Section]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>bootcom session report</title>\n<style>\n\
+         body { font-family: sans-serif; }\n\
+         table { border-collapse: collapse; }\n\
+         td, th { border: 1px solid #ccc; padding: 2px 8px; text-align: left; }\n\
+         pre { background: #f4f4f4; padding: 8px; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    for (i, section) in sections.iter().enumerate() {
+        let tag = if i == 0 { "h1" } else { "h2" };
+        let _ = writeln!(html, "<{}>{}</{}>", tag, escape(section.title), tag);
+        for block in &section.blocks {
+            match block {
+                Block::Text(paragraph) => {
+                    let _ = writeln!(html, "<p>{}</p>", escape(paragraph));
+                }
+                Block::Table(headers, rows) => {
+                    html.push_str("<table>\n<tr>");
+                    for header in headers {
+                        let _ = write!(html, "<th>{}</th>", escape(header));
+                    }
+                    html.push_str("</tr>\n");
+                    for row in rows {
+                        html.push_str("<tr>");
+                        for cell in row {
+                            let _ = write!(html, "<td>{}</td>", escape(cell));
+                        }
+                        html.push_str("</tr>\n");
+                    }
+                    html.push_str("</table>\n");
+                }
+                Block::Code(lines) => {
+                    let _ = writeln!(html, "<pre>{}</pre>", escape(&lines.join("\n")));
+                }
+                Block::Links(links) => {
+                    html.push_str("<ul>\n");
+                    for (label, target) in links {
+                        let _ = writeln!(
+                            html,
+                            "<li>{}: <a href=\"{}\"><code>{}</code></a></li>",
+                            escape(label),
+                            escape(target),
+                            escape(target)
+                        );
+                    }
+                    html.push_str("</ul>\n");
+                }
+            }
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Escape the `text` for HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn rendered_as_markdown_and_html() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::settings::{SettingsBuilder, TransferProtocol};
+
+    let settings = SettingsBuilder::default()
+        .path("/dev/ttyUSB0")
+        .record("boot.cast")
+        .report("report.md")
+        .finalize();
+    let report = SessionReport::new(&settings);
+    for i in 0..50 {
+        report.output(format!("\x1b[32mline {}\x1b[0m\r\n", i).as_bytes());
+    }
+    report.output(b"login: ");
+    report.file("capture of `power`", "captures/power.csv");
+
+    let mut collected = report.0.as_ref().unwrap().lock().unwrap();
+    let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    collected.started = started;
+    let transfer = TransferReport {
+        image: "kernel8.img".into(),
+        protocol: TransferProtocol::Raspbootin,
+        bytes: 8192,
+        duration: Duration::from_secs(4),
+        retries: 0,
+        crc: 0x1c29_1ca3,
+        resumed_from: 0,
+        output: vec![],
+    };
+    collected
+        .transfers
+        .push((started + Duration::from_secs(5), transfer, Some(42)));
+    assert_eq!(collected.lines, 50);
+    assert_eq!(collected.first_lines[0], "line 0");
+    assert_eq!(collected.last_lines.back().unwrap(), "line 49");
+
+    let mut stats = SessionStats::default();
+    stats.error("port | closed");
+    stats.error("port | closed");
+    let sections = collected.sections(&stats, started + Duration::from_secs(60));
+    let markdown = render_markdown(&sections);
+    assert!(markdown.starts_with(
+        "# bootcom session report\n\nFrom 2023-11-14 22:13:20 UTC to 2023-11-14 22:14:20 UTC \
+         (1m00s).\n"
+    ));
+    assert!(markdown.contains("| port | /dev/ttyUSB0 |\n"));
+    assert!(markdown.contains(
+        "| 2023-11-14 22:13:25 UTC | kernel8.img | raspbootin | 8.0 KiB | 4.0s | 2.0 KiB/s | 0 \
+         | 1c291ca3 | #42 |\n"
+    ));
+    assert!(markdown
+        .contains("2 error(s).\n\n| Error | Count |\n| --- | --- |\n| port \\| closed | 2 |\n"));
+    assert!(markdown.contains("10 line(s) skipped.\n\n```text\nline 30\n"));
+    assert!(markdown.contains("- recording: [`boot.cast`](<boot.cast>)\n"));
+
+    let html = render_html(&sections);
+    assert!(html.contains("<td>port | closed</td><td>2</td>"));
+    assert!(html.contains("<a href=\"captures/power.csv\"><code>captures/power.csv</code></a>"));
+    assert!(html.ends_with("</body>\n</html>\n"));
+}
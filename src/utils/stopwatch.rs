@@ -0,0 +1,111 @@
+//! Markers and stopwatch in the console log, to measure the boot phases by eye
+//! during bring-up.
+//!
+//! In terminal mode, `F4` inserts a marker line in the console output, with the
+//! time of day and the time since the previous marker, and `F5` starts or stops
+//! a stopwatch, its elapsed time printed when it stops. The lines go to all the
+//! outputs, the recordings included, along with the console output around
+//! them. The stopwatch keeps running when the device reconnects, a board reset
+//! often being the phase measured.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The markers inserted so far and the stopwatch.
+#[derive(Debug, Default)]
+pub(crate) struct Stopwatch {
+    /// The number of markers inserted so far.
+    markers: u32,
+    /// When the last marker was inserted.
+    marked: Option<Instant>,
+    /// When the stopwatch was started, if it runs.
+    started: Option<Instant>,
+}
+impl Stopwatch {
+    /// Insert a marker at `now`, the wall clock telling the `time`, and return
+    /// its line.
+    pub(crate) fn mark(&mut self, now: Instant, time: SystemTime) -> String {
+        self.markers += 1;
+        let mut line = format!("[BC] 📍 Marker {} at {}", self.markers, time_of_day(time));
+        if let Some(marked) = self.marked.replace(now) {
+            line.push_str(&format!(
+                ", +{} since the previous one",
+                seconds(now - marked)
+            ));
+        }
+        if let Some(started) = self.started {
+            line.push_str(&format!(", stopwatch at {}", seconds(now - started)));
+        }
+        line
+    }
+
+    /// Start the stopwatch at `now`, the wall clock telling the `time`, or
+    /// stop it if it runs, and return the line telling so.
+    pub(crate) fn toggle(&mut self, now: Instant, time: SystemTime) -> String {
+        match self.started.take() {
+            Some(started) => format!(
+                "[BC] ⏱️  Stopwatch stopped after {}",
+                seconds(now - started)
+            ),
+            None => {
+                self.started = Some(now);
+                format!("[BC] ⏱️  Stopwatch started at {}", time_of_day(time))
+            }
+        }
+    }
+}
+
+/// A `duration` to the millisecond, e.g. `3.207s`.
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+/// The time of day of the `time` to the millisecond, in UTC as the local time
+/// zone is not known, e.g. `22:13:20.125 UTC`.
+pub(super) fn time_of_day(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        % 86_400_000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03} UTC",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn markers_and_stopwatch() {
+    let mut stopwatch = Stopwatch::default();
+    let now = Instant::now();
+    let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_125);
+    let later = |millis| now + Duration::from_millis(millis);
+    let wall = |millis| time + Duration::from_millis(millis);
+
+    assert_eq!(
+        stopwatch.mark(now, time),
+        "[BC] 📍 Marker 1 at 22:13:20.125 UTC"
+    );
+    assert_eq!(
+        stopwatch.toggle(later(500), wall(500)),
+        "[BC] ⏱️  Stopwatch started at 22:13:20.625 UTC"
+    );
+    assert_eq!(
+        stopwatch.mark(later(3707), wall(3707)),
+        "[BC] 📍 Marker 2 at 22:13:23.832 UTC, +3.707s since the previous one, stopwatch at \
+         3.207s"
+    );
+    assert_eq!(
+        stopwatch.toggle(later(62_500), wall(62_500)),
+        "[BC] ⏱️  Stopwatch stopped after 62.000s"
+    );
+    assert!(stopwatch
+        .toggle(later(70_000), wall(70_000))
+        .contains("started"));
+}
@@ -0,0 +1,310 @@
+//! Boot mode strapping through the GPIO lines of USB adapters.
+//!
+//! Some boards only enter their UART boot mode when a pin is held at some
+//! level while they reset. When the pin is wired to a GPIO line of a USB
+//! adapter, typically a CBUS pin of the FTDI chip of the console itself, the
+//! [`Strap`]s of the settings drive it before the port is opened, which is
+//! when the boards wired for it (and the [`DtrToggle`] quirk) reset them.
+//!
+//! The lines are driven through the GPIO character devices of Linux
+//! (`/dev/gpiochip<N>`), where the kernel drivers of the adapters register
+//! their GPIO chips (`ftdi-cbus` for the CBUS pins of the FTDI chips
+//! configured as GPIOs in their EEPROM, `cp210x` for the Silicon Labs
+//! ones...). Unlike bitbanging them with libftdi, this leaves the serial port
+//! of the adapter to its driver. When several adapters have a chip with the
+//! label of a strap, the one of the adapter of the port is used.
+//!
+//! The lines stay requested by `bootcom` while they are driven: the
+//! [`Straps`] release them once their hold time is over, and the others when
+//! they are dropped, leaving them as inputs. Should `bootcom` be killed, the
+//! kernel releases them when it closes the character devices.
+//!
+//! [`DtrToggle`]: crate::Quirk::DtrToggle
+
+use std::io;
+#[cfg(target_os = "linux")]
+use std::{
+    fs,
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+#[cfg(target_os = "linux")]
+use gpio_cdev::{Line, LineHandle, LineRequestFlags};
+#[cfg(target_os = "linux")]
+use log::{debug, info};
+
+use crate::boards;
+use crate::settings::{Settings, Strap};
+
+/// The strapping pins driven, released when dropped.
+#[derive(Debug, Default)]
+pub(crate) struct Straps {
+    /// The lines without a hold time, driven until the straps are dropped.
+    #[cfg(target_os = "linux")]
+    kept: Vec<Driven>,
+    /// The thread releasing the other lines once their hold time is over,
+    /// and the channel telling it to release them right away by hanging up.
+    #[cfg(target_os = "linux")]
+    timer: Option<(Sender<()>, JoinHandle<()>)>,
+}
+impl Straps {
+    /// Wait for the hold time of the straps to be over and release them all,
+    /// including those without a hold time.
+    pub(crate) fn release(self) {
+        #[cfg(target_os = "linux")]
+        {
+            let mut straps = self;
+            if let Some((_keep_waiting, timer)) = straps.timer.take() {
+                let _ = timer.join();
+            }
+        }
+    }
+}
+#[cfg(target_os = "linux")]
+impl Drop for Straps {
+    fn drop(&mut self) {
+        if let Some((stop, timer)) = self.timer.take() {
+            drop(stop);
+            let _ = timer.join();
+        }
+        for driven in self.kept.drain(..) {
+            driven.release();
+        }
+    }
+}
+
+/// Drive the strapping pins of the board on the port of the `settings`, until
+/// their hold time is over or the returned [`Straps`] are dropped.
+#[cfg(target_os = "linux")]
+pub(crate) fn apply_straps(settings: &Settings) -> io::Result<Straps> {
+    let port = settings.path.as_deref().unwrap_or_default();
+    let mut straps = Straps::default();
+    let mut held = vec![];
+    for strap in applicable(settings) {
+        let driven = drive(Path::new("/sys"), strap, port)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", strap, e)))?;
+        match strap.hold {
+            Some(hold) => held.push((Instant::now() + hold, driven)),
+            None => straps.kept.push(driven),
+        }
+    }
+    if !held.is_empty() {
+        let (stop, stopped) = mpsc::channel();
+        let timer = thread::Builder::new()
+            .name("straps".into())
+            .spawn(move || release_after_holds(held, stopped))?;
+        straps.timer = Some((stop, timer));
+    }
+    Ok(straps)
+}
+
+/// The GPIO character devices are only available on Linux.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn apply_straps(settings: &Settings) -> io::Result<Straps> {
+    match applicable(settings).next() {
+        None => Ok(Straps::default()),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the boot mode straps can only be driven on Linux",
+        )),
+    }
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The consumer of the lines, as listed by `gpioinfo`.
+#[cfg(target_os = "linux")]
+const CONSUMER: &str = "bootcom";
+
+/// The straps of the `settings` applying to the board on their port.
+fn applicable(settings: &Settings) -> impl Iterator<Item = &Strap> {
+    let profile = boards::board_of(settings).and_then(|board| board.profile.as_deref());
+    settings
+        .straps
+        .iter()
+        .filter(move |strap| strap.profile.is_none() || strap.profile.as_deref() == profile)
+}
+
+/// A GPIO line driven by `bootcom`.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Driven {
+    strap: Strap,
+    line: Line,
+    /// Drives the line as long as it is open.
+    handle: LineHandle,
+}
+#[cfg(target_os = "linux")]
+impl Driven {
+    /// Stop driving the line, leaving it as an input.
+    fn release(self) {
+        let Driven {
+            strap,
+            line,
+            handle,
+        } = self;
+        drop(handle);
+        match line.request(LineRequestFlags::INPUT, 0, CONSUMER) {
+            Ok(_) => debug!("released {}", strap),
+            Err(e) => info!("could not release {}: {}", strap, e),
+        }
+    }
+}
+
+/// Release the `held` lines once the instant paired with each of them is
+/// reached, or all of them right away once the other end of `stop` hangs up.
+#[cfg(target_os = "linux")]
+fn release_after_holds(mut held: Vec<(Instant, Driven)>, stop: Receiver<()>) {
+    held.sort_by_key(|(until, _)| *until);
+    for (until, driven) in held {
+        let _ = stop.recv_timeout(until.saturating_duration_since(Instant::now()));
+        driven.release();
+    }
+}
+
+/// Drive the line of the `strap` as an output, at its level.
+#[cfg(target_os = "linux")]
+fn drive(sysfs: &Path, strap: &Strap, port: &str) -> io::Result<Driven> {
+    let mut chips = vec![];
+    let mut unopened = None;
+    for chip in gpio_cdev::chips().map_err(io::Error::other)? {
+        match chip {
+            Ok(chip) => chips.push(chip),
+            Err(e) => unopened = Some(e),
+        }
+    }
+    let infos: Vec<_> = chips
+        .iter()
+        .map(|chip| (chip.name(), chip.label(), chip.num_lines()))
+        .collect();
+    let index = match (find_chip(sysfs, strap, port, &infos), unopened) {
+        (Ok(index), _) => index,
+        (Err(e), Some(unopened)) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::other(format!(
+                "the GPIO chips can't all be opened ({})",
+                unopened
+            )))
+        }
+        (Err(e), _) => return Err(e),
+    };
+    let mut chip = chips.swap_remove(index);
+    debug!("driving {} ({})", strap, chip.path().display());
+    let line = chip.get_line(strap.line).map_err(io::Error::other)?;
+    // Requesting the output with its level avoids a glitch to the default one.
+    let handle = line
+        .request(LineRequestFlags::OUTPUT, strap.high as u8, CONSUMER)
+        .map_err(io::Error::other)?;
+    Ok(Driven {
+        strap: strap.clone(),
+        line,
+        handle,
+    })
+}
+
+/// The index of the chip of the `strap` among the `chips` (their name, label
+/// and number of lines), preferring the one of the adapter of the `port` when
+/// several chips have its label.
+#[cfg(target_os = "linux")]
+fn find_chip(
+    sysfs: &Path,
+    strap: &Strap,
+    port: &str,
+    chips: &[(&str, &str, u32)],
+) -> io::Result<usize> {
+    let not_found = |message: String| io::Error::new(io::ErrorKind::NotFound, message);
+    let mut candidates = vec![];
+    for (index, (name, label, lines)) in chips.iter().enumerate() {
+        if *label != strap.chip {
+            continue;
+        }
+        if strap.line >= *lines {
+            return Err(not_found(format!(
+                "`{}` only has {} lines, there is no line {}",
+                strap.chip, lines, strap.line
+            )));
+        }
+        // The chips are registered under the device of their adapter.
+        let device = fs::canonicalize(sysfs.join("bus/gpio/devices").join(name))
+            .ok()
+            .and_then(|chip| chip.parent().map(Path::to_path_buf));
+        candidates.push((device, index));
+    }
+
+    let tty = Path::new(port)
+        .file_name()
+        .map(|name| sysfs.join("class/tty").join(name).join("device"))
+        .and_then(|device| fs::canonicalize(device).ok());
+    let of_port = candidates.iter().find(|(device, _)| match (device, &tty) {
+        (Some(device), Some(tty)) => tty.starts_with(device),
+        _ => false,
+    });
+    match (of_port, candidates.as_slice()) {
+        (Some((_, index)), _) | (None, [(_, index)]) => Ok(*index),
+        (None, []) => Err(not_found(format!(
+            "no `{}` GPIO chip, is the adapter plugged in and its pins configured as GPIOs?",
+            strap.chip
+        ))),
+        (None, _) => Err(not_found(format!(
+            "several `{}` GPIO chips and none of them is on the adapter of {}",
+            strap.chip, port
+        ))),
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(target_os = "linux")]
+#[test]
+fn chips_of_the_adapter_of_the_port() {
+    use std::os::unix::fs::symlink;
+
+    let sysfs = std::env::temp_dir().join(format!("bootcom-sysfs-{}", std::process::id()));
+    let gpio = sysfs.join("bus/gpio/devices");
+    let tty = sysfs.join("class/tty/ttyUSB1");
+    fs::create_dir_all(&gpio).unwrap();
+    fs::create_dir_all(&tty).unwrap();
+    for (chip, usb) in &[("gpiochip1", "1-1:1.0"), ("gpiochip2", "1-2:1.0")] {
+        let device = sysfs.join("devices").join(usb);
+        fs::create_dir_all(device.join("ttyUSB")).unwrap();
+        fs::create_dir_all(device.join(chip)).unwrap();
+        symlink(device.join(chip), gpio.join(chip)).unwrap();
+    }
+    symlink(sysfs.join("devices/1-2:1.0/ttyUSB"), tty.join("device")).unwrap();
+    let chips = [
+        ("gpiochip0", "pinctrl-bcm2711", 58),
+        ("gpiochip1", "ftdi-cbus", 4),
+        ("gpiochip2", "ftdi-cbus", 4),
+    ];
+
+    let mut strap = Strap {
+        profile: None,
+        chip: "ftdi-cbus".into(),
+        line: 2,
+        high: false,
+        hold: None,
+    };
+    assert_eq!(
+        find_chip(&sysfs, &strap, "/dev/ttyUSB1", &chips).unwrap(),
+        2
+    );
+    assert_eq!(
+        find_chip(&sysfs, &strap, "/dev/ttyUSB1", &chips[..2]).unwrap(),
+        1
+    );
+    assert!(find_chip(&sysfs, &strap, "/dev/ttyUSB0", &chips)
+        .unwrap_err()
+        .to_string()
+        .contains("several"));
+    strap.line = 4;
+    assert!(find_chip(&sysfs, &strap, "/dev/ttyUSB1", &chips).is_err());
+    strap.chip = "cp210x".into();
+    assert!(find_chip(&sysfs, &strap, "/dev/ttyUSB1", &chips).is_err());
+    fs::remove_dir_all(sysfs).unwrap();
+}
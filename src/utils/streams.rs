@@ -0,0 +1,214 @@
+//! Demultiplexing of the logical streams the device tags on its console.
+//!
+//! A device with a single UART can still keep its logs, test results and
+//! traces apart by tagging them in band: an `SOH` (`0x01`) followed by a
+//! channel byte switches the output to the stream of that channel, until the
+//! next tag, and the channel `0` switches back to the console. When the
+//! [`Stream`]s of the settings are set, the tags are stripped from the output,
+//! and what follows them is shown on the terminal in the color of the stream,
+//! its lines prefixed with its name, or appended to its file. The console and
+//! the unknown channels are shown as usual.
+//!
+//! The tags are only looked for when streams are set, the console output is
+//! left untouched otherwise.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+};
+
+use console::{style, Style};
+
+use crate::{
+    messages::Catalog,
+    protocol::{device, hex, note, Line},
+    settings::Stream,
+};
+
+/// The byte starting the tags, `SOH`.
+pub(crate) const TAG: u8 = 0x01;
+
+/// The channel of the console.
+pub(crate) const CONSOLE: u8 = 0;
+
+/// The colors the streams can be shown in.
+pub(crate) const COLORS: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// Splits the console output into the streams of the settings, and writes
+/// them to their sinks.
+#[derive(Debug, Default)]
+pub(crate) struct StreamDemux {
+    streams: Vec<Stream>,
+    /// The index of the stream the output currently goes to, the console if
+    /// none.
+    current: Option<usize>,
+    /// Whether the last read ended with the `SOH` of a tag.
+    tagged: bool,
+    /// Whether the next output of each stream starts a line.
+    line_starts: Vec<bool>,
+    /// The files of the streams, opened on their first output.
+    files: Vec<Option<File>>,
+    messages: Catalog,
+}
+impl StreamDemux {
+    pub(crate) fn new(streams: &[Stream], messages: &Catalog) -> Self {
+        StreamDemux {
+            streams: streams.to_vec(),
+            current: None,
+            tagged: false,
+            line_starts: vec![true; streams.len()],
+            files: streams.iter().map(|_| None).collect(),
+            messages: messages.clone(),
+        }
+    }
+
+    /// Split the `data` received from the device, wherever the reads split
+    /// the tags, into the runs of output of each stream (`None` for the
+    /// console), in order.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Vec<(Option<usize>, Vec<u8>)> {
+        if self.streams.is_empty() {
+            return vec![(None, data.to_vec())];
+        }
+        let mut runs: Vec<(Option<usize>, Vec<u8>)> = vec![];
+        for &byte in data {
+            if self.tagged {
+                self.tagged = false;
+                self.current = match byte {
+                    CONSOLE => None,
+                    channel => self.streams.iter().position(|s| s.channel == channel),
+                };
+                continue;
+            }
+            if byte == TAG {
+                self.tagged = true;
+                continue;
+            }
+            match runs.last_mut() {
+                Some((stream, run)) if *stream == self.current => run.push(byte),
+                _ => runs.push((self.current, vec![byte])),
+            }
+        }
+        runs
+    }
+
+    /// Write the `data` of the `stream` to its file, or return it as shown
+    /// on the terminal. A file that can't be written to is reported, and the
+    /// stream is shown on the terminal instead.
+    pub(crate) fn write(&mut self, stream: usize, data: &[u8]) -> Option<Vec<u8>> {
+        if let Some(path) = self.streams[stream].file.clone() {
+            match self.write_file(stream, &path, data) {
+                Ok(_) => return None,
+                Err(e) => {
+                    println!(
+                        "{}",
+                        style(self.messages.text_with(
+                            "stream.not_written",
+                            &[
+                                ("name", &self.streams[stream].name),
+                                ("path", &path),
+                                ("error", &e)
+                            ]
+                        ))
+                        .red()
+                    );
+                    self.streams[stream].file = None;
+                }
+            }
+        }
+        Some(self.render(stream, data))
+    }
+
+    fn write_file(&mut self, stream: usize, path: &str, data: &[u8]) -> io::Result<()> {
+        let file = match &mut self.files[stream] {
+            Some(file) => file,
+            empty => empty.insert(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        file.write_all(data)
+    }
+
+    /// The `data` of the `stream`, its lines prefixed with its name, all in
+    /// its color.
+    fn render(&mut self, stream: usize, data: &[u8]) -> Vec<u8> {
+        let mut text = vec![];
+        let prefix = format!("[{}] ", self.streams[stream].name);
+        for &byte in data {
+            if self.line_starts[stream] && byte != b'\r' && byte != b'\n' {
+                text.extend_from_slice(prefix.as_bytes());
+            }
+            self.line_starts[stream] = byte == b'\n';
+            text.push(byte);
+        }
+        match &self.streams[stream].color {
+            Some(color) => {
+                let style = Style::from_dotted_str(color);
+                let text = String::from_utf8_lossy(&text);
+                style.apply_to(text).to_string().into_bytes()
+            }
+            None => text,
+        }
+    }
+}
+
+/// The tags of the `streams`.
+pub(crate) fn wire_format(streams: &[Stream]) -> Vec<Line> {
+    let mut lines: Vec<Line> = streams
+        .iter()
+        .map(|stream| {
+            device(format!(
+                "{}  switch to the `{}` stream",
+                hex(&[TAG, stream.channel]),
+                stream.name
+            ))
+        })
+        .collect();
+    lines.push(device(format!(
+        "{}  switch back to the console",
+        hex(&[TAG, CONSOLE])
+    )));
+    lines.push(note(
+        "the output goes to the stream until the next tag, the unknown channels to the console",
+    ));
+    lines
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn streams_are_split_across_reads() {
+    let streams = [
+        Stream {
+            channel: b'T',
+            name: "test".into(),
+            color: Some("green".into()),
+            file: None,
+        },
+        Stream {
+            channel: 2,
+            name: "trace".into(),
+            color: None,
+            file: None,
+        },
+    ];
+    let mut demux = StreamDemux::new(&streams, &Catalog::default());
+    assert_eq!(
+        demux.feed(b"boot\r\n\x01Tok 1\n\x01"),
+        vec![(None, b"boot\r\n".to_vec()), (Some(0), b"ok 1\n".to_vec())]
+    );
+    assert_eq!(
+        demux.feed(b"\x02fn a\x01\x00$ \x01\x09?"),
+        vec![(Some(1), b"fn a".to_vec()), (None, b"$ ?".to_vec())]
+    );
+    assert_eq!(
+        demux.write(1, b"x\ny"),
+        Some(b"[trace] x\n[trace] y".to_vec())
+    );
+    assert_eq!(demux.write(1, b"z\n"), Some(b"z\n".to_vec()));
+
+    // Left alone without streams.
+    let mut demux = StreamDemux::new(&[], &Catalog::default());
+    assert_eq!(demux.feed(b"\x01T"), vec![(None, b"\x01T".to_vec())]);
+}
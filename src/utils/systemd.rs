@@ -0,0 +1,275 @@
+//! Integration with systemd, for `bootcom` running as a service supervising a
+//! board.
+//!
+//! The service manager is told through its notification socket (`sd_notify`)
+//! when `bootcom` is ready, which state it is in, and that it is still alive
+//! when the watchdog is enabled for the service. The listening sockets passed
+//! by socket activation serve the console of the device: each client receives
+//! the lines printed by the device, and what it writes is typed on the device
+//! console, provided the access rules of the settings allow it (see the
+//! `remote` module for the commands of the clients). Nothing is encrypted on
+//! the TCP sockets, so the clients only authenticate on the Unix sockets. The
+//! console served on TCP sockets is advertised on the LAN when enabled in the
+//! settings (see the `mdns` module).
+//!
+//! ```ini
+//! # bootcom@.socket
+//! [Socket]
+//! ListenStream=/run/bootcom/%i.sock
+//! # To `bootcom attach` from other hosts, on the port it uses by default.
+//! ListenStream=7000
+//!
+//! # bootcom@.service
+//! [Service]
+//! Type=notify
+//! WatchdogSec=30
+//! ExecStart=/usr/local/bin/bootcom --daemon --tty=/dev/%i
+//! ```
+
+use std::{env, time::Duration};
+
+use log::info;
+
+#[cfg(unix)]
+use super::remote::Socket;
+use crate::context::Context;
+use crate::settings::Settings;
+
+/// The service manager notification socket.
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(unix)]
+    address: std::os::unix::net::SocketAddr,
+}
+impl Notifier {
+    /// The notification socket of the service manager, if `bootcom` runs under
+    /// one.
+    pub(crate) fn from_env() -> Option<Notifier> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::net::UnixDatagram;
+
+            let address = notify_address(&env::var_os("NOTIFY_SOCKET")?)?;
+            let socket = UnixDatagram::unbound().ok()?;
+            Some(Notifier { socket, address })
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Send the `state` (e.g. `READY=1`) to the service manager.
+    pub(crate) fn notify(&self, state: &str) {
+        #[cfg(unix)]
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            info!("could not notify the service manager: {}", e);
+        }
+        #[cfg(not(unix))]
+        info!("no service manager to notify of {}", state);
+    }
+}
+
+/// How often the watchdog of the service manager expects to be fed, if it is
+/// enabled for `bootcom`.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Serve the console of the device of the `context`, to the clients allowed by
+/// the access rules of the `settings`, on the listening sockets passed by
+/// socket activation, if any.
+pub(crate) fn serve_activated_sockets(context: &Context, settings: &Settings) {
+    #[cfg(unix)]
+    for fd in activated_fds() {
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        // Passed by the service manager for `bootcom` to own.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        if let Ok(address) = listener.local_addr() {
+            info!("serving the console on {}", address);
+            if settings.advertise {
+                super::mdns::advertise(
+                    settings,
+                    super::mdns::Advertisement::new(settings, address.port()),
+                );
+            }
+            serve(
+                listener,
+                TcpListener::accept,
+                TcpStream::try_clone,
+                Socket::Tcp,
+                context,
+                settings,
+            );
+        } else {
+            let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+            info!("serving the console on {:?}", listener.local_addr());
+            serve(
+                listener,
+                UnixListener::accept,
+                UnixStream::try_clone,
+                Socket::Unix,
+                context,
+                settings,
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (context, settings);
+}
+
+// =============================================================================
+// Private stuff
+// =============================================================================
+
+/// The first file descriptor passed by socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The address of the notification socket, a path or an abstract name
+/// starting with `@` on Linux.
+#[cfg(unix)]
+fn notify_address(socket: &std::ffi::OsStr) -> Option<std::os::unix::net::SocketAddr> {
+    use std::os::unix::{ffi::OsStrExt, net::SocketAddr};
+
+    match socket.as_bytes() {
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name).ok()
+        }
+        [b'/', ..] => SocketAddr::from_pathname(socket).ok(),
+        _ => None,
+    }
+}
+
+/// The file descriptors passed by socket activation, for `bootcom` only: the
+/// variables describing them are removed so that the hooks do not take them
+/// for theirs.
+#[cfg(unix)]
+fn activated_fds() -> Vec<i32> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count: i32 = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    if pid != Some(std::process::id()) {
+        return vec![];
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// Accept the console clients of a `listener` in the background, each served
+/// from its own threads.
+#[cfg(unix)]
+fn serve<L, S, A>(
+    listener: L,
+    accept: fn(&L) -> std::io::Result<(S, A)>,
+    try_clone: fn(&S) -> std::io::Result<S>,
+    socket: Socket,
+    context: &Context,
+    settings: &Settings,
+) where
+    L: Send + 'static,
+    A: 'static,
+    S: std::io::Read + std::io::Write + Send + 'static,
+{
+    let context = context.clone();
+    let settings = settings.clone();
+    std::thread::spawn(move || loop {
+        let client = accept(&listener).and_then(|(stream, _)| Ok((try_clone(&stream)?, stream)));
+        match client {
+            Ok((input, output)) => serve_client(input, output, socket, &context, &settings),
+            Err(e) => info!("could not accept a console client: {}", e),
+        }
+    });
+}
+
+/// Send the console lines to the `output` of a client, and handle its `input`,
+/// until it goes away.
+#[cfg(unix)]
+fn serve_client<S>(mut input: S, output: S, socket: Socket, context: &Context, settings: &Settings)
+where
+    S: std::io::Read + std::io::Write + Send + 'static,
+{
+    use std::sync::{Arc, Mutex};
+
+    use super::remote::Client;
+    use crate::settings::Permission;
+
+    let client = Arc::new(Mutex::new(Client::new(settings, socket)));
+    let output = Arc::new(Mutex::new(output));
+    let lines = context.outputs.subscribe_lines();
+    {
+        let client = client.clone();
+        let output = output.clone();
+        std::thread::spawn(move || {
+            for line in lines {
+                if !client.lock().unwrap().allows(Permission::View) {
+                    continue;
+                }
+                if writeln!(output.lock().unwrap(), "{}", line.text).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let context = context.clone();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            let read = match input.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let replies = client.lock().unwrap().feed(&buf[..read], &context);
+            let mut output = output.lock().unwrap();
+            if replies
+                .iter()
+                .any(|reply| writeln!(output, "{}", reply).is_err())
+            {
+                break;
+            }
+        }
+    });
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(target_os = "linux")]
+#[test]
+fn service_manager_is_notified() {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = env::temp_dir().join(format!("bootcom-notify-{}.sock", std::process::id()));
+    let manager = UnixDatagram::bind(&path).unwrap();
+    let notifier = Notifier {
+        socket: UnixDatagram::unbound().unwrap(),
+        address: notify_address(path.as_os_str()).unwrap(),
+    };
+    notifier.notify("READY=1");
+    let mut buf = [0u8; 64];
+    let received = manager.recv(&mut buf).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&buf[..received], b"READY=1");
+    assert!(notify_address("@bootcom".as_ref()).is_some());
+    assert!(notify_address("relative".as_ref()).is_none());
+}
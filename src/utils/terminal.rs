@@ -0,0 +1,333 @@
+//! Restoration of the state of the user's terminal.
+//!
+//! Raw mode, bracketed paste and the hidden cursor are only ever set through a
+//! [`TerminalGuard`], which restores them when dropped, on an early return as
+//! well as while unwinding. As a panic message printed in raw mode is garbled
+//! (and the process may abort before unwinding), a panic hook also restores the
+//! terminal before the message is printed. The output processing is kept in raw
+//! mode, for the lines printed while the keyboard is read to start at the
+//! beginning of the line.
+//!
+//! The status line (the spinner or progress bar on the last line) is kept
+//! within the width of the terminal, and redrawn when the terminal is resized:
+//! terminals like Windows Terminal reflow the lines already drawn, which then
+//! span several rows and are only partly cleared by the next draw.
+
+use std::{
+    io, panic,
+    sync::{Mutex, MutexGuard, Once, TryLockError},
+};
+
+use console::{measure_text_width, truncate_str, Term};
+use crossterm::{
+    event::{DisableBracketedPaste, EnableBracketedPaste},
+    execute,
+    terminal::{self, disable_raw_mode, enable_raw_mode},
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, WeakProgressBar};
+
+/// The width of the default spinner template before the message, `[BC] ⠋ `.
+const STATUS_PREFIX: usize = 7;
+
+/// The number of guards alive, for each change of the terminal state.
+struct Changes {
+    raw_mode: usize,
+    bracketed_paste: usize,
+    hidden_cursor: usize,
+}
+
+static CHANGES: Mutex<Changes> = Mutex::new(Changes {
+    raw_mode: 0,
+    bracketed_paste: 0,
+    hidden_cursor: 0,
+});
+static HOOK: Once = Once::new();
+
+/// The size of the terminal, as of the last resize.
+static SIZE: Mutex<Option<(u16, u16)>> = Mutex::new(None);
+
+/// The status line being shown.
+struct Status {
+    bar: WeakProgressBar,
+    /// The message of a spinner, `None` for a progress bar spanning the whole
+    /// width.
+    message: Option<String>,
+}
+
+static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+enum Change {
+    RawMode,
+    BracketedPaste,
+    HiddenCursor,
+}
+
+/// Keeps a change of the terminal state for as long as it lives. Guards can be
+/// nested, the terminal is restored when the last one of a kind is dropped.
+#[must_use = "the terminal is restored as soon as the guard is dropped"]
+pub(crate) struct TerminalGuard {
+    change: Change,
+}
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut changes = lock();
+        match self.change {
+            Change::RawMode => {
+                changes.raw_mode -= 1;
+                if changes.raw_mode == 0 {
+                    let _ = disable_raw_mode();
+                }
+            }
+            Change::BracketedPaste => {
+                changes.bracketed_paste -= 1;
+                if changes.bracketed_paste == 0 {
+                    let _ = execute!(io::stdout(), DisableBracketedPaste);
+                }
+            }
+            Change::HiddenCursor => {
+                changes.hidden_cursor -= 1;
+                if changes.hidden_cursor == 0 {
+                    let _ = Term::stdout().show_cursor();
+                }
+            }
+        }
+    }
+}
+
+/// Switch the terminal to raw mode until the returned guard is dropped.
+pub(crate) fn raw_mode() -> io::Result<TerminalGuard> {
+    guard(Change::RawMode, || {
+        enable_raw_mode().map_err(io::Error::other)?;
+        // The lines printed meanwhile would otherwise staircase.
+        #[cfg(unix)]
+        let _ = keep_output_processing();
+        Ok(())
+    })
+}
+
+/// Have the text pasted in the terminal reported as a whole until the returned
+/// guard is dropped.
+pub(crate) fn bracketed_paste() -> io::Result<TerminalGuard> {
+    guard(Change::BracketedPaste, || {
+        execute!(io::stdout(), EnableBracketedPaste)
+    })
+}
+
+/// Hide the cursor until the returned guard is dropped.
+pub(crate) fn hide_cursor() -> io::Result<TerminalGuard> {
+    guard(Change::HiddenCursor, || Term::stdout().hide_cursor())
+}
+
+fn guard(change: Change, apply: impl FnOnce() -> io::Result<()>) -> io::Result<TerminalGuard> {
+    HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore();
+            default_hook(info);
+        }));
+    });
+    let mut changes = lock();
+    let count = match change {
+        Change::RawMode => &mut changes.raw_mode,
+        Change::BracketedPaste => &mut changes.bracketed_paste,
+        Change::HiddenCursor => &mut changes.hidden_cursor,
+    };
+    if *count == 0 {
+        apply()?;
+    }
+    *count += 1;
+    Ok(TerminalGuard { change })
+}
+
+/// Prepare the terminal for the output of `bootcom`: on Windows consoles, the
+/// processing of the ANSI escape sequences (found in the console output of the
+/// devices as well as in the progress bars) and the resize events are enabled.
+pub(crate) fn prepare_terminal() {
+    #[cfg(windows)]
+    enable_console_modes();
+    let mut size = SIZE.lock().unwrap();
+    if size.is_none() {
+        *size = terminal::size().ok();
+    }
+}
+
+/// The size of the terminal, in columns and rows. Pseudo terminals nobody
+/// gave a size report an empty one, taken as unknown.
+pub(crate) fn size() -> (u16, u16) {
+    let cached = *SIZE.lock().unwrap();
+    match cached.or_else(|| terminal::size().ok()) {
+        Some((columns, rows)) if columns > 0 && rows > 0 => (columns, rows),
+        _ => (80, 24),
+    }
+}
+
+/// Show `message` in the status line `bar` (a spinner), truncated to fit the
+/// width of the terminal.
+pub(crate) fn set_status(bar: &ProgressBar, message: impl Into<String>) {
+    let message = message.into();
+    bar.set_message(fit(&message, size().0));
+    *STATUS.lock().unwrap() = Some(Status {
+        bar: bar.downgrade(),
+        message: Some(message),
+    });
+}
+
+/// Follow the progress `bar`, spanning the whole width, for resizes.
+pub(crate) fn follow_bar(bar: &ProgressBar) {
+    *STATUS.lock().unwrap() = Some(Status {
+        bar: bar.downgrade(),
+        message: None,
+    });
+}
+
+/// Take a resize of the terminal into account, clearing the rows spanned by
+/// the status line after a reflow before drawing it again.
+pub(crate) fn resized(columns: u16, rows: u16) {
+    let old = SIZE.lock().unwrap().replace((columns, rows));
+    let old_columns = match old {
+        Some((old_columns, _)) if old_columns != columns => old_columns,
+        _ => return,
+    };
+    let status = STATUS.lock().unwrap();
+    let status = match status.as_ref() {
+        Some(status) => status,
+        None => return,
+    };
+    let bar = match status.bar.upgrade() {
+        Some(bar) if !bar.is_finished() => bar,
+        _ => return,
+    };
+    let width = match &status.message {
+        Some(message) => STATUS_PREFIX + measure_text_width(&fit(message, old_columns)),
+        None => old_columns as usize,
+    };
+    // Nothing is drawn while the rows are cleared, and the new draw target
+    // starts from the cleared rows.
+    bar.set_draw_target(ProgressDrawTarget::hidden());
+    let _ = Term::stderr().clear_last_lines(spanned_rows(width, columns));
+    bar.set_draw_target(ProgressDrawTarget::stderr());
+    match &status.message {
+        Some(message) => bar.set_message(fit(message, columns)),
+        None => bar.tick(),
+    }
+}
+
+/// The message fitting in a status line of the terminal `columns`, keeping
+/// the last column free. At least the ellipsis is left of it on the narrowest
+/// terminals.
+fn fit(message: &str, columns: u16) -> String {
+    let width = (columns as usize).saturating_sub(STATUS_PREFIX + 1).max(1);
+    truncate_str(message, width, "…").into_owned()
+}
+
+/// The rows spanned by a line of `width` columns on a terminal of `columns`.
+fn spanned_rows(width: usize, columns: u16) -> usize {
+    let columns = (columns as usize).max(1);
+    width.div_ceil(columns).max(1)
+}
+
+/// Turn the output processing of the terminal back on after raw mode turned it
+/// off, for the line feeds to return the cursor to the start of the line.
+#[cfg(unix)]
+fn keep_output_processing() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let tty = std::fs::File::open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        termios.c_oflag |= libc::OPOST | libc::ONLCR;
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn enable_console_modes() {
+    use winapi::um::{
+        consoleapi::{GetConsoleMode, SetConsoleMode},
+        processenv::GetStdHandle,
+        winbase::{STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE},
+        wincon::{ENABLE_VIRTUAL_TERMINAL_PROCESSING, ENABLE_WINDOW_INPUT},
+    };
+
+    let modes = [
+        (STD_OUTPUT_HANDLE, ENABLE_VIRTUAL_TERMINAL_PROCESSING),
+        (STD_ERROR_HANDLE, ENABLE_VIRTUAL_TERMINAL_PROCESSING),
+        (STD_INPUT_HANDLE, ENABLE_WINDOW_INPUT),
+    ];
+    for &(handle, flag) in &modes {
+        // Redirected handles and terminals which are not consoles (like
+        // mintty) have no console mode, and need none.
+        unsafe {
+            let handle = GetStdHandle(handle);
+            let mut mode = 0;
+            if GetConsoleMode(handle, &mut mode) != 0 {
+                SetConsoleMode(handle, mode | flag);
+            }
+        }
+    }
+}
+
+/// Restore the terminal, whatever the guards alive.
+fn restore() {
+    // Don't deadlock on a panic while the changes were locked by this thread.
+    let changes = match CHANGES.try_lock() {
+        Ok(changes) => changes,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    if changes.raw_mode > 0 {
+        let _ = disable_raw_mode();
+    }
+    if changes.bracketed_paste > 0 {
+        let _ = execute!(io::stdout(), DisableBracketedPaste);
+    }
+    if changes.hidden_cursor > 0 {
+        let _ = Term::stdout().show_cursor();
+    }
+}
+
+// The terminal still has to be restored after a panic while it was locked.
+fn lock() -> MutexGuard<'static, Changes> {
+    CHANGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn guards_are_counted() {
+    let count = || lock().hidden_cursor;
+    let outer = hide_cursor().unwrap();
+    let inner = hide_cursor().unwrap();
+    assert_eq!(count(), 2);
+    drop(inner);
+    assert_eq!(count(), 1);
+    drop(outer);
+    assert_eq!(count(), 0);
+}
+
+#[test]
+fn status_fits_the_width() {
+    let message = format!(
+        "⏳ Waiting for {} to be ready",
+        console::style("/dev/ttyUSB0").cyan()
+    );
+    assert_eq!(fit(&message, 80), message);
+    let fitted = fit(&message, 20);
+    assert_eq!(measure_text_width(&fitted), 12);
+    assert!(fitted.ends_with('…'));
+    assert_eq!(fit(&message, 0), "…");
+
+    assert_eq!(spanned_rows(72, 80), 1);
+    assert_eq!(spanned_rows(72, 40), 2);
+    assert_eq!(spanned_rows(0, 40), 1);
+}
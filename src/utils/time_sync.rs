@@ -0,0 +1,67 @@
+//! Time synchronization of the boards without a real-time clock.
+//!
+//! When time synchronization is enabled, the host time is sent to the device
+//! as soon as the port is opened, and the device can ask for it again at any
+//! time in terminal mode by sending the time trigger (**`0x16`** **three(3)**
+//! times), so that its early boot logs carry timestamps aligned with the ones
+//! of `bootcom`. Both ways, the time goes in the same frame:
+//!
+//! ```text
+//! frame: 0x16 0x16 0x16 | unix time in ms (u64 LE)
+//! ```
+
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+
+use crate::protocol::{device, hex, host, Line};
+use crate::transport::Transport;
+
+/// The pattern sent by the device to ask for the host time, which also starts
+/// the frames holding the time.
+pub(crate) const TIME_TRIGGER: [u8; 3] = [0x16, 0x16, 0x16];
+
+/// The messages of the time synchronization.
+pub(crate) fn wire_format() -> Vec<Line> {
+    let frame = format!("{} | unix time in ms (u64 LE)", hex(&TIME_TRIGGER));
+    vec![
+        host(format!("{}, when the port is opened", frame)),
+        device(format!("{}, to ask for the time again", hex(&TIME_TRIGGER))),
+        host(frame),
+    ]
+}
+
+/// Send the current host time to the device on the `port`, returning it in
+/// milliseconds since the epoch.
+pub(crate) fn send_time(port: &mut dyn Transport) -> io::Result<u64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    debug!("sending the host time {}", now);
+    port.write_all(&time_frame(now))?;
+    port.flush()?;
+    Ok(now)
+}
+
+/// The frame holding the time `now`, in milliseconds since the epoch.
+fn time_frame(now: u64) -> Vec<u8> {
+    let mut frame = TIME_TRIGGER.to_vec();
+    frame.extend_from_slice(&now.to_le_bytes());
+    frame
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn frame_layout() {
+    assert_eq!(
+        time_frame(0x0102_0304_0506),
+        vec![0x16, 0x16, 0x16, 6, 5, 4, 3, 2, 1, 0, 0]
+    );
+}
@@ -0,0 +1,90 @@
+//! Metadata trailer appended to the kernel image.
+//!
+//! When the [`Trailer`] of the settings is set, a small block of metadata is
+//! appended to the kernel image as it is sent, whatever the transfer protocol,
+//! so that the binary running on the board can tell what it is: its version,
+//! the git hash it was built from, when it was built and the CRC-32 of the
+//! image. It ends with its own length and a magic, for the kernel to find it
+//! from the end of what it received, once loaded.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Crc32;
+use crate::{
+    protocol::{hex, note, Line},
+    settings::Trailer,
+};
+
+/// The magic at both ends of the trailer.
+pub(crate) const MAGIC: &[u8; 4] = b"BCMD";
+
+/// The version of the layout of the trailer.
+pub(crate) const FORMAT: u8 = 1;
+
+/// The trailer of the `payload` of the image built at `built`.
+pub(crate) fn build(trailer: &Trailer, payload: &[u8], built: SystemTime) -> Vec<u8> {
+    let mut crc = Crc32::new();
+    crc.update(payload);
+    let built = built
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(FORMAT);
+    bytes.extend_from_slice(&built.to_le_bytes());
+    bytes.extend_from_slice(&crc.finalize().to_le_bytes());
+    for field in [&trailer.version, trailer.git_hash.as_deref().unwrap_or("")] {
+        bytes.extend(field.bytes().filter(|b| *b != 0));
+        bytes.push(0);
+    }
+    let len = (bytes.len() + 4 + MAGIC.len()) as u32;
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(MAGIC);
+    bytes
+}
+
+/// The layout of the trailer.
+pub(crate) fn wire_format() -> Vec<Line> {
+    vec![
+        note("appended to the kernel image and sent along, in the size announced"),
+        note(format!(
+            "{} (`{}`) | format {} (u8)",
+            hex(MAGIC),
+            String::from_utf8_lossy(MAGIC),
+            FORMAT
+        )),
+        note("build time, seconds since the Unix epoch (u64 LE) | CRC-32 of the image (u32 LE)"),
+        note("version, `NUL` terminated | git hash, `NUL` terminated, empty when unknown"),
+        note(format!(
+            "length of the trailer, magics included (u32 LE) | {}",
+            hex(MAGIC)
+        )),
+        note("the build time is the modification time of the image file"),
+    ]
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn trailer_layout() {
+    use std::time::Duration;
+
+    let trailer = Trailer {
+        version: "v1.2".into(),
+        git_hash: Some("abc123".into()),
+    };
+    let built = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let bytes = build(&trailer, b"123456789", built);
+    assert_eq!(&bytes[..5], b"BCMD\x01");
+    assert_eq!(&bytes[5..13], &1_700_000_000u64.to_le_bytes());
+    assert_eq!(&bytes[13..17], &0xcbf4_3926u32.to_le_bytes());
+    assert_eq!(&bytes[17..29], b"v1.2\0abc123\0");
+    assert_eq!(&bytes[29..33], &(bytes.len() as u32).to_le_bytes());
+    assert_eq!(&bytes[33..], MAGIC);
+
+    let bytes = build(&Trailer::default(), b"", built);
+    assert_eq!(bytes.len(), 27);
+    assert_eq!(&bytes[17..19], b"\0\0");
+}
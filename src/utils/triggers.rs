@@ -0,0 +1,166 @@
+//! Detection of the trigger patterns sent by the booting device to issue
+//! commands to `bootcom`.
+//!
+//! Several triggers can be registered at the same time, each mapped to its own
+//! command (e.g. sending the kernel with a given transfer protocol), so that a
+//! single `bootcom` instance can serve boards with different bootloaders. A
+//! trigger is only recognized at the end of the received data, as the device
+//! is expected to wait for a response after sending it. The pattern may however
+//! be split across several reads from the serial port: the received data
+//! ending with the start of a pattern is held back, and only shown once the
+//! rest turns out not to complete it, or the device went quiet.
+
+use std::time::{Duration, Instant};
+
+/// How long the start of a pattern is held back on a quiet console.
+const HOLD_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Matches the data received from the device against the registered trigger
+/// patterns, each associated with a value of type `T`.
+#[derive(Debug)]
+pub(crate) struct TriggerMatcher<T> {
+    triggers: Vec<(Vec<u8>, T)>,
+    /// The last bytes received, the start of a pattern, not shown yet.
+    held: Vec<u8>,
+    /// Since when the console is quiet with bytes held back, if it is.
+    quiet_since: Option<Instant>,
+}
+impl<T: Clone> TriggerMatcher<T> {
+    pub(crate) fn new(triggers: Vec<(Vec<u8>, T)>) -> Self {
+        TriggerMatcher {
+            triggers: triggers
+                .into_iter()
+                .filter(|(pattern, _)| !pattern.is_empty())
+                .collect(),
+            held: Vec::new(),
+            quiet_since: None,
+        }
+    }
+
+    /// Check whether the newly received `data`, together with what was held
+    /// back before, ends with one of the registered triggers.
+    ///
+    /// Returns the data to show, without the matched trigger nor the start of
+    /// a pattern it may end with, and the value associated with the matched
+    /// trigger, if any.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> (Vec<u8>, Option<T>) {
+        let mut window = std::mem::take(&mut self.held);
+        window.extend_from_slice(data);
+        self.quiet_since = None;
+
+        // Prefer the longest pattern when several match.
+        let found = self
+            .triggers
+            .iter()
+            .filter(|(pattern, _)| window.ends_with(pattern))
+            .max_by_key(|(pattern, _)| pattern.len());
+        if let Some((pattern, value)) = found {
+            window.truncate(window.len() - pattern.len());
+            return (window, Some(value.clone()));
+        }
+
+        let start = (1..self.longest().min(window.len() + 1))
+            .rev()
+            .find(|len| {
+                let end = &window[window.len() - len..];
+                self.triggers
+                    .iter()
+                    .any(|(pattern, _)| pattern.len() > *len && pattern.starts_with(end))
+            })
+            .unwrap_or(0);
+        self.held = window.split_off(window.len() - start);
+        (window, None)
+    }
+
+    /// Returns `true` if the start of a pattern is held back.
+    pub(crate) fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// The bytes held back, to be shown now that the console has been quiet
+    /// for a while after them, at `now`.
+    pub(crate) fn release(&mut self, now: Instant) -> Vec<u8> {
+        match self.quiet_since {
+            Some(since) if now.duration_since(since) >= HOLD_TIMEOUT => {
+                self.quiet_since = None;
+                std::mem::take(&mut self.held)
+            }
+            Some(_) => vec![],
+            None => {
+                self.quiet_since = Some(now);
+                vec![]
+            }
+        }
+    }
+
+    /// Length of the longest registered pattern.
+    fn longest(&self) -> usize {
+        self.triggers
+            .iter()
+            .map(|(p, _)| p.len())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[cfg(test)]
+fn test_matcher() -> TriggerMatcher<&'static str> {
+    TriggerMatcher::new(vec![
+        (vec![3, 3, 3], "raspbootin"),
+        (b"CCC".to_vec(), "xmodem"),
+        (vec![3, 3, 3, 3], "longer"),
+    ])
+}
+
+#[test]
+fn trigger_at_the_end() {
+    let mut matcher = test_matcher();
+    assert_eq!(
+        matcher.feed(b"waiting for kernel\x03\x03\x03"),
+        (b"waiting for kernel".to_vec(), Some("raspbootin"))
+    );
+}
+
+#[test]
+fn trigger_not_at_the_end() {
+    let mut matcher = test_matcher();
+    assert_eq!(
+        matcher.feed(b"\x03\x03\x03 and more"),
+        (b"\x03\x03\x03 and more".to_vec(), None)
+    );
+}
+
+#[test]
+fn trigger_split_across_reads() {
+    let mut matcher = test_matcher();
+    assert_eq!(matcher.feed(b"ready C"), (b"ready ".to_vec(), None));
+    assert!(matcher.is_holding());
+    assert_eq!(matcher.feed(b"C"), (vec![], None));
+    assert_eq!(matcher.feed(b"C"), (vec![], Some("xmodem")));
+    assert!(!matcher.is_holding());
+
+    // Shown along with the rest when it is not a trigger after all.
+    assert_eq!(matcher.feed(b"CC"), (vec![], None));
+    assert_eq!(matcher.feed(b"D\n"), (b"CCD\n".to_vec(), None));
+}
+
+#[test]
+fn start_of_a_trigger_released_when_quiet() {
+    let mut matcher = test_matcher();
+    let now = Instant::now();
+    assert_eq!(matcher.feed(b"ABC"), (b"AB".to_vec(), None));
+    assert_eq!(matcher.release(now), b"");
+    assert_eq!(matcher.release(now + HOLD_TIMEOUT / 2), b"");
+    assert_eq!(matcher.release(now + HOLD_TIMEOUT), b"C");
+    assert!(!matcher.is_holding());
+}
+
+#[test]
+fn longest_trigger_wins() {
+    let mut matcher = test_matcher();
+    assert_eq!(matcher.feed(b"\x03\x03\x03\x03"), (vec![], Some("longer")));
+}
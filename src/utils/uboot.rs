@@ -0,0 +1,289 @@
+//! Scripted handoff to a stock U-Boot.
+//!
+//! Boards running an unmodified U-Boot never send the trigger of a kernel
+//! image request, but U-Boot can load one over its console itself. When the
+//! [`UbootScript`] of the settings is set, its banner is watched for on the
+//! console: the autoboot countdown is interrupted, and the commands of the
+//! script are typed at its prompt one after the other. After the load command
+//! (`loadx` or `loady`), the kernel image is sent with the matching protocol
+//! as soon as the receiver asks for it, and the remaining commands (typically
+//! `bootm`) follow at the next prompt. The console is then left alone until
+//! the next banner.
+
+use std::time::{Duration, Instant};
+
+use crate::settings::{TransferProtocol, UbootScript};
+
+/// How long U-Boot is given to show its prompt, or to be ready to receive the
+/// kernel image, before the handoff is abandoned.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The start of the banner of U-Boot and its SPL.
+const BANNER: &[u8] = b"U-Boot ";
+
+/// The end of the autoboot countdowns, interrupted by any key.
+const AUTOBOOT: [&[u8]; 2] = [b"to stop autoboot", b"to abort autoboot"];
+
+/// The start of the line of `loadx` and `loady` telling they are ready.
+const READY: &[u8] = b"Ready for binary";
+
+/// What to do for the handoff.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Handoff {
+    /// U-Boot started, its prompt is awaited.
+    Started,
+    /// Type the bytes on the console.
+    Type(Vec<u8>),
+    /// Send the kernel image with the protocol, the receiver asked for it.
+    Transfer(TransferProtocol),
+    /// All the commands were typed.
+    Finished,
+    /// U-Boot didn't get to the next step in time.
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum State {
+    Idle,
+    /// The command at the index is typed at the next prompt.
+    Typing(usize),
+    /// The load command was typed, `READY` is awaited before the command at
+    /// the index.
+    Loading(usize),
+    /// `READY` was received, the end of its line and then the request of the
+    /// receiver are awaited.
+    Ready {
+        next: usize,
+        line_ended: bool,
+    },
+    /// The kernel image is being sent.
+    Transferring(usize),
+}
+
+/// Follows the console of U-Boot to run the script of a handoff.
+#[derive(Debug)]
+pub(crate) struct UbootHandoff {
+    script: Option<UbootScript>,
+    /// The index and protocol of the load command of the script.
+    transfer: Option<(usize, TransferProtocol)>,
+    state: State,
+    /// When the current state was entered.
+    since: Instant,
+    /// Whether the autoboot countdown was interrupted since the banner.
+    interrupted: bool,
+    /// The output received in the current state.
+    received: Vec<u8>,
+}
+impl UbootHandoff {
+    pub(crate) fn new(script: Option<&UbootScript>) -> Self {
+        UbootHandoff {
+            transfer: script.and_then(UbootScript::transfer),
+            script: script.cloned(),
+            state: State::Idle,
+            since: Instant::now(),
+            interrupted: false,
+            received: vec![],
+        }
+    }
+
+    /// Follow the console `data` received at `now`, returning what to do.
+    pub(crate) fn output(&mut self, data: &[u8], now: Instant) -> Vec<Handoff> {
+        let mut steps = vec![];
+        let script = match &self.script {
+            Some(script) if !matches!(self.state, State::Transferring(_)) => script.clone(),
+            _ => return steps,
+        };
+        self.received.extend_from_slice(data);
+
+        // The board (re)started, whatever was going on.
+        if let Some(end) = find(&self.received, BANNER) {
+            let rest = self.received.split_off(end);
+            self.enter(State::Typing(0), now);
+            self.received = rest;
+            self.interrupted = false;
+            steps.push(Handoff::Started);
+        }
+
+        match self.state {
+            State::Idle => self.received.clear(),
+            State::Typing(index) => {
+                if !self.interrupted {
+                    if let Some(end) = AUTOBOOT.iter().find_map(|a| find(&self.received, a)) {
+                        self.received.drain(..end);
+                        self.interrupted = true;
+                        steps.push(Handoff::Type(b" ".to_vec()));
+                    }
+                }
+                if self.received.ends_with(script.prompt.as_bytes()) {
+                    let mut typed = script.commands[index].clone().into_bytes();
+                    typed.push(b'\r');
+                    steps.push(Handoff::Type(typed));
+                    let next = index + 1;
+                    if self.transfer.map(|(load, _)| load) == Some(index) {
+                        self.enter(State::Loading(next), now);
+                    } else if next < script.commands.len() {
+                        self.enter(State::Typing(next), now);
+                    } else {
+                        self.enter(State::Idle, now);
+                        steps.push(Handoff::Finished);
+                    }
+                }
+            }
+            State::Loading(next) => {
+                if let Some(end) = find(&self.received, READY) {
+                    self.received.drain(..end);
+                    self.state = State::Ready {
+                        next,
+                        line_ended: false,
+                    };
+                    steps.extend(self.output(&[], now));
+                }
+            }
+            State::Ready { next, line_ended } => {
+                // The line may hold a `C` of its own, in the address.
+                let start = match (line_ended, self.received.iter().position(|b| *b == b'\n')) {
+                    (true, _) => Some(0),
+                    (false, Some(newline)) => Some(newline + 1),
+                    (false, None) => None,
+                };
+                if let Some(start) = start {
+                    self.received.drain(..start);
+                    self.state = State::Ready {
+                        next,
+                        line_ended: true,
+                    };
+                    if self.received.contains(&b'C') {
+                        let (_, protocol) = self.transfer.unwrap();
+                        self.enter(State::Transferring(next), now);
+                        steps.push(Handoff::Transfer(protocol));
+                    }
+                }
+            }
+            State::Transferring(_) => (),
+        }
+
+        // Only the end of the output matters for the prompt.
+        let longest = AUTOBOOT
+            .iter()
+            .map(|a| a.len())
+            .chain([BANNER.len(), READY.len(), script.prompt.len()])
+            .max()
+            .unwrap_or(0);
+        let keep = self.received.len().saturating_sub(longest);
+        self.received.drain(..keep);
+        steps
+    }
+
+    /// Give up on U-Boot when it didn't get to the next step in time, at
+    /// `now`.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<Handoff> {
+        match self.state {
+            State::Idle | State::Transferring(_) => None,
+            _ if now.duration_since(self.since) < TIMEOUT => None,
+            _ => {
+                self.enter(State::Idle, now);
+                Some(Handoff::TimedOut)
+            }
+        }
+    }
+
+    /// The transfer of the kernel image ended at `now`, successfully if `ok`.
+    /// The script goes on at the next prompt, or is abandoned.
+    pub(crate) fn transferred(&mut self, ok: bool, now: Instant) -> Option<Handoff> {
+        let next = match self.state {
+            State::Transferring(next) => next,
+            _ => return None,
+        };
+        let commands = self.script.as_ref().map_or(0, |s| s.commands.len());
+        if ok && next < commands {
+            self.enter(State::Typing(next), now);
+            None
+        } else {
+            self.enter(State::Idle, now);
+            Some(Handoff::Finished)
+        }
+    }
+
+    fn enter(&mut self, state: State, now: Instant) {
+        self.state = state;
+        self.since = now;
+        self.received.clear();
+    }
+}
+
+impl Default for UbootHandoff {
+    fn default() -> Self {
+        UbootHandoff::new(None)
+    }
+}
+
+/// The end of the first occurrence of `pattern` in `data`.
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .position(|window| window == pattern)
+        .map(|start| start + pattern.len())
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn scripted_handoff() {
+    let script = UbootScript {
+        prompt: "=> ".into(),
+        commands: vec![
+            "setenv bootargs console=ttyS0".into(),
+            "loady ${loadaddr}".into(),
+            "bootm ${loadaddr}".into(),
+        ],
+    };
+    let now = Instant::now();
+    let mut handoff = UbootHandoff::new(Some(&script));
+    assert!(handoff.output(b"Hello\r\n=> ", now).is_empty());
+
+    assert_eq!(
+        handoff.output(b"\r\nU-Boot 2023.04 (Apr 03 2023)\r\nDRAM: 1 GiB\r\n", now),
+        vec![Handoff::Started]
+    );
+    assert_eq!(
+        handoff.output(b"Hit any key to stop autoboot:  3 ", now),
+        vec![Handoff::Type(b" ".to_vec())]
+    );
+    assert_eq!(handoff.output(b"\x08\x08\x08 0 \r\n=", now), vec![]);
+    assert_eq!(
+        handoff.output(b"> ", now),
+        vec![Handoff::Type(b"setenv bootargs console=ttyS0\r".to_vec())]
+    );
+    assert_eq!(
+        handoff.output(b"setenv bootargs console=ttyS0\r\n=> ", now),
+        vec![Handoff::Type(b"loady ${loadaddr}\r".to_vec())]
+    );
+    // The `C` of the address is not a request.
+    assert!(handoff
+        .output(b"## Ready for binary (ymodem) download to 0x8C000000", now)
+        .is_empty());
+    assert_eq!(
+        handoff.output(b" at 115200 bps...\r\nC", now),
+        vec![Handoff::Transfer(TransferProtocol::Ymodem)]
+    );
+    assert!(handoff.output(b"CC", now).is_empty());
+    assert_eq!(handoff.transferred(true, now), None);
+    assert_eq!(
+        handoff.output(b"## Total Size = 0x00123456\r\n=> ", now),
+        vec![
+            Handoff::Type(b"bootm ${loadaddr}\r".to_vec()),
+            Handoff::Finished
+        ]
+    );
+    assert!(handoff.output(b"=> ", now).is_empty());
+
+    // U-Boot gets stuck.
+    handoff.output(b"U-Boot SPL 2023.04\r\n", now);
+    assert_eq!(handoff.poll(now + Duration::from_secs(1)), None);
+    assert_eq!(handoff.poll(now + TIMEOUT), Some(Handoff::TimedOut));
+    assert!(handoff.output(b"=> ", now).is_empty());
+
+    let mut handoff = UbootHandoff::new(None);
+    assert!(handoff.output(b"U-Boot 2023.04\r\n=> ", now).is_empty());
+}
@@ -0,0 +1,141 @@
+//! Details about the serial ports on Windows, from the SetupAPI.
+//!
+//! `serialport` only gives the friendly name of USB ports and can't tell
+//! Bluetooth virtual COM ports apart. Both are found here from the device
+//! information of the `Ports` device class.
+
+use std::{collections::HashMap, ffi::OsString, os::windows::ffi::OsStringExt, ptr};
+
+use winapi::{
+    shared::{
+        devguid::GUID_DEVCLASS_PORTS,
+        minwindef::{DWORD, FALSE},
+        winerror::ERROR_SUCCESS,
+    },
+    um::{
+        handleapi::INVALID_HANDLE_VALUE,
+        setupapi::{
+            SetupDiDestroyDeviceInfoList, SetupDiEnumDeviceInfo, SetupDiGetClassDevsW,
+            SetupDiGetDeviceInstanceIdW, SetupDiGetDeviceRegistryPropertyW, SetupDiOpenDevRegKey,
+            DICS_FLAG_GLOBAL, DIGCF_PRESENT, DIREG_DEV, HDEVINFO, SPDRP_FRIENDLYNAME,
+            SP_DEVINFO_DATA,
+        },
+        winnt::KEY_READ,
+        winreg::{RegCloseKey, RegQueryValueExW, HKEY},
+    },
+};
+
+/// What Windows knows about a serial port.
+#[derive(Debug, Clone)]
+pub(crate) struct PortDetails {
+    /// The name shown in the device manager, e.g. `Standard Serial over
+    /// Bluetooth link (COM5)`.
+    pub friendly_name: Option<String>,
+    /// The port is a Bluetooth virtual COM port.
+    pub bluetooth: bool,
+}
+
+/// The details of all present serial ports, by port name (e.g. `COM5`).
+pub(crate) fn port_details() -> HashMap<String, PortDetails> {
+    let mut details = HashMap::new();
+    unsafe {
+        let hdi: HDEVINFO = SetupDiGetClassDevsW(
+            &GUID_DEVCLASS_PORTS,
+            ptr::null(),
+            ptr::null_mut(),
+            DIGCF_PRESENT,
+        );
+        if hdi == INVALID_HANDLE_VALUE as HDEVINFO {
+            return details;
+        }
+        let mut index = 0;
+        loop {
+            let mut devinfo: SP_DEVINFO_DATA = std::mem::zeroed();
+            devinfo.cbSize = std::mem::size_of::<SP_DEVINFO_DATA>() as DWORD;
+            if SetupDiEnumDeviceInfo(hdi, index, &mut devinfo) == FALSE {
+                break;
+            }
+            index += 1;
+
+            let name = match port_name(hdi, &mut devinfo) {
+                Some(name) => name,
+                None => continue,
+            };
+            // Bluetooth serial ports are enumerated by the `BTHENUM` bus.
+            let bluetooth = instance_id(hdi, &mut devinfo)
+                .map_or(false, |id| id.to_uppercase().starts_with("BTHENUM\\"));
+            details.insert(
+                name,
+                PortDetails {
+                    friendly_name: friendly_name(hdi, &mut devinfo),
+                    bluetooth,
+                },
+            );
+        }
+        SetupDiDestroyDeviceInfoList(hdi);
+    }
+    details
+}
+
+/// Convert a nul terminated wide string buffer.
+fn from_wide(buffer: &[u16]) -> String {
+    let len = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    OsString::from_wide(&buffer[..len])
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe fn instance_id(hdi: HDEVINFO, devinfo: &mut SP_DEVINFO_DATA) -> Option<String> {
+    let mut buffer = [0u16; 512];
+    let ok = SetupDiGetDeviceInstanceIdW(
+        hdi,
+        devinfo,
+        buffer.as_mut_ptr(),
+        buffer.len() as DWORD,
+        ptr::null_mut(),
+    );
+    if ok == FALSE {
+        return None;
+    }
+    Some(from_wide(&buffer))
+}
+
+unsafe fn friendly_name(hdi: HDEVINFO, devinfo: &mut SP_DEVINFO_DATA) -> Option<String> {
+    let mut buffer = [0u16; 512];
+    let ok = SetupDiGetDeviceRegistryPropertyW(
+        hdi,
+        devinfo,
+        SPDRP_FRIENDLYNAME,
+        ptr::null_mut(),
+        buffer.as_mut_ptr() as *mut u8,
+        (buffer.len() * 2) as DWORD,
+        ptr::null_mut(),
+    );
+    if ok == FALSE {
+        return None;
+    }
+    Some(from_wide(&buffer)).filter(|name| !name.is_empty())
+}
+
+unsafe fn port_name(hdi: HDEVINFO, devinfo: &mut SP_DEVINFO_DATA) -> Option<String> {
+    let hkey: HKEY = SetupDiOpenDevRegKey(hdi, devinfo, DICS_FLAG_GLOBAL, 0, DIREG_DEV, KEY_READ);
+    if hkey == INVALID_HANDLE_VALUE as HKEY {
+        return None;
+    }
+    let value_name: Vec<u16> = "PortName\0".encode_utf16().collect();
+    let mut buffer = [0u16; 256];
+    let mut size = (buffer.len() * 2) as DWORD;
+    let result = RegQueryValueExW(
+        hkey,
+        value_name.as_ptr(),
+        ptr::null_mut(),
+        ptr::null_mut(),
+        buffer.as_mut_ptr() as *mut u8,
+        &mut size,
+    );
+    RegCloseKey(hkey);
+    if result != ERROR_SUCCESS as i32 {
+        return None;
+    }
+    Some(from_wide(&buffer)).filter(|name| !name.is_empty())
+}
@@ -0,0 +1,322 @@
+//! Sender side of the XMODEM-CRC and YMODEM file transfer protocols.
+//!
+//! The receiver initiates the transfer by sending `C` (which is what the
+//! trigger pattern matches on). The file is then sent in 128 byte blocks, each
+//! framed as:
+//!
+//! ```text
+//! SOH | block number | 255 - block number | 128 data bytes | CRC-16 (BE)
+//! ```
+//!
+//! Every block is acknowledged by the receiver with `ACK` or rejected with
+//! `NAK`, in which case it is sent again. The transfer completes with `EOT`,
+//! which also needs to be acknowledged. The last block is padded with `SUB`
+//! (`0x1A`) bytes.
+//!
+//! YMODEM, as spoken by the `loady` command of U-Boot, sends 1024 byte blocks
+//! framed with `STX` instead, preceded by a block `0` giving the name and the
+//! size of the file (so that the padding is dropped), and followed by an empty
+//! block `0` once the receiver asks for the next file, ending the batch.
+
+use std::{
+    error::Error,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+
+use super::{crc::crc16, is_transient, kernel, KernelImage, SoftFlow};
+use crate::{
+    progress::TransferProgress,
+    protocol::{byte, device, host, note, Line},
+    settings::Settings,
+    transport::Transport,
+};
+
+pub(crate) const SOH: u8 = 0x01;
+pub(crate) const STX: u8 = 0x02;
+pub(crate) const EOT: u8 = 0x04;
+pub(crate) const ACK: u8 = 0x06;
+pub(crate) const NAK: u8 = 0x15;
+pub(crate) const CAN: u8 = 0x18;
+pub(crate) const SUB: u8 = 0x1a;
+
+pub(crate) const BLOCK_SIZE: usize = 128;
+pub(crate) const YMODEM_BLOCK_SIZE: usize = 1024;
+
+/// How often, in bytes sent, the image file is checked for changes.
+const CHECK_EVERY: u64 = 64 * 1024;
+
+/// Number of times a block is sent before giving up.
+const MAX_RETRIES: u32 = 10;
+
+/// How long to wait for the receiver to acknowledge a block.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send the kernel `image` over the `port` using XMODEM-CRC. The receiver
+/// is expected to have already requested the transfer by sending `C`.
+///
+/// Returns the number of blocks sent again after being rejected.
+pub(crate) fn send(
+    port: &mut dyn Transport,
+    settings: &Settings,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+) -> Result<u32, Box<dyn Error>> {
+    let progress = TransferProgress::start(settings, image.len() as u64);
+    let mut retries = send_blocks(port, flow, image, BLOCK_SIZE, &progress)?;
+    check_unchanged(port, image)?;
+    retries += send_with_retries(port, flow, &[EOT])?;
+    progress.device_output(&kernel::drain_output(port, flow, settings.flush_window));
+    progress.finish(image.len() as u64);
+    Ok(retries)
+}
+
+/// Send the kernel `image` over the `port` using YMODEM, as the file `name`.
+/// The receiver is expected to have already requested the transfer by sending
+/// `C`.
+///
+/// Returns the number of blocks sent again after being rejected.
+pub(crate) fn send_ymodem(
+    port: &mut dyn Transport,
+    settings: &Settings,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    name: &str,
+) -> Result<u32, Box<dyn Error>> {
+    let progress = TransferProgress::start(settings, image.len() as u64);
+    let header = header_frame(name, image.len() as u64);
+    let mut retries = send_with_retries(port, flow, &header)?;
+    retries += send_blocks(port, flow, image, YMODEM_BLOCK_SIZE, &progress)?;
+    check_unchanged(port, image)?;
+    retries += send_with_retries(port, flow, &[EOT])?;
+    // No more files.
+    retries += send_with_retries(port, flow, &end_of_batch())?;
+    progress.device_output(&kernel::drain_output(port, flow, settings.flush_window));
+    progress.finish(image.len() as u64);
+    Ok(retries)
+}
+
+/// The messages of XMODEM-CRC, or of YMODEM if `ymodem`.
+pub(crate) fn wire_format(ymodem: bool) -> Vec<Line> {
+    let (start, block_size) = if ymodem {
+        (STX, YMODEM_BLOCK_SIZE)
+    } else {
+        (SOH, BLOCK_SIZE)
+    };
+    let block_zero = |content: &str| {
+        format!(
+            "{} | 0x00 | 0xff | {} | CRC-16 (u16 BE)",
+            byte("SOH", SOH),
+            content
+        )
+    };
+    let mut lines = vec![device(format!("{} to start the transfer", byte("C", b'C')))];
+    if ymodem {
+        lines.push(host(block_zero(&format!(
+            "name of the file, `NUL`, size in decimal, padded with `NUL` to {} bytes",
+            BLOCK_SIZE
+        ))));
+        lines.push(device(format!(
+            "{}, then {}",
+            byte("ACK", ACK),
+            byte("C", b'C')
+        )));
+    }
+    lines.extend(vec![
+        host(format!(
+            "{} | block number (from 1, wrapping) | 255 - block number | {} bytes of the \
+             image, the last block padded with {} | CRC-16 (u16 BE)",
+            byte(if ymodem { "STX" } else { "SOH" }, start),
+            block_size,
+            byte("SUB", SUB)
+        )),
+        device(format!(
+            "{}, {} to get the block again, or {} to cancel, within {} s",
+            byte("ACK", ACK),
+            byte("NAK", NAK),
+            byte("CAN", CAN),
+            ACK_TIMEOUT.as_secs()
+        )),
+        note(format!("a block is sent {} times at most", MAX_RETRIES)),
+        host(format!(
+            "{} after the last block, sent again until acknowledged",
+            byte("EOT", EOT)
+        )),
+    ]);
+    if ymodem {
+        lines.push(host(format!(
+            "{}, ending the batch",
+            block_zero(&format!("{} `NUL`", BLOCK_SIZE))
+        )));
+    }
+    lines
+}
+
+/// Send the `image` in blocks of `block_size` bytes, numbered from `1`.
+/// Returns the number of blocks sent again after being rejected.
+fn send_blocks(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    image: &KernelImage,
+    block_size: usize,
+    progress: &TransferProgress,
+) -> Result<u32, Box<dyn Error>> {
+    let mut block_number: u8 = 1;
+    let mut sent: u64 = 0;
+    let mut retries = 0;
+    for block in image.as_bytes().chunks(block_size) {
+        if sent.is_multiple_of(CHECK_EVERY) {
+            check_unchanged(port, image)?;
+        }
+        let frame = block_frame(block_number, block, block_size);
+        retries += send_with_retries(port, flow, &frame)?;
+
+        sent += block.len() as u64;
+        progress.update(sent);
+        block_number = block_number.wrapping_add(1);
+    }
+    Ok(retries)
+}
+
+/// The frame of the block `block_number` of `block_size` bytes, holding the
+/// `block` of the image, padded with `SUB` when it is the last one.
+pub(crate) fn block_frame(block_number: u8, block: &[u8], block_size: usize) -> Vec<u8> {
+    let mut data = block.to_vec();
+    data.resize(block_size, SUB);
+    make_frame(block_number, &data)
+}
+
+/// The frame of the YMODEM block `0` announcing the file `name` of `size`
+/// bytes.
+pub(crate) fn header_frame(name: &str, size: u64) -> Vec<u8> {
+    make_frame(0, &file_header(name, size))
+}
+
+/// The frame of the empty YMODEM block `0` ending the batch.
+pub(crate) fn end_of_batch() -> Vec<u8> {
+    make_frame(0, &[0; BLOCK_SIZE])
+}
+
+/// The data of the YMODEM block `0`: the name of the file and its size in
+/// decimal, each ended with a `NUL`.
+fn file_header(name: &str, size: u64) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+    let fields = format!("{}\0{}", name, size);
+    // Overlong names are cut, the size matters more.
+    let fields = fields.as_bytes();
+    let start = fields.len().saturating_sub(BLOCK_SIZE - 1);
+    header[..fields.len() - start].copy_from_slice(&fields[start..]);
+    header
+}
+
+/// Build the frame for one block of data, of 128 or 1024 bytes.
+fn make_frame(block_number: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 5);
+    frame.push(if data.len() == BLOCK_SIZE { SOH } else { STX });
+    frame.push(block_number);
+    frame.push(255 - block_number);
+    frame.extend_from_slice(data);
+    frame.extend_from_slice(&crc16(data).to_be_bytes());
+    frame
+}
+
+/// Cancel the transfer if the image file changed since it started, the receiver
+/// then requests it again.
+fn check_unchanged(port: &mut dyn Transport, image: &KernelImage) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = image.check_unchanged() {
+        debug!("canceling the transfer: {}", e);
+        // Best effort, the transfer is failing anyway.
+        let _ = port.write_all(&[CAN, CAN]);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Write `frame` and wait for the receiver to acknowledge it, sending it again
+/// when it is rejected. Returns the number of times it was sent again.
+fn send_with_retries(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+    frame: &[u8],
+) -> Result<u32, Box<dyn Error>> {
+    let frame = flow.encode(frame);
+    for attempt in 1..=MAX_RETRIES {
+        flow.wait_until_resumed(port)?;
+        port.write_all(&frame)?;
+        port.flush()?;
+        match wait_for_response(port, flow)? {
+            Some(ACK) => return Ok(attempt - 1),
+            Some(CAN) => {
+                return Err(serialport::Error::new(
+                    serialport::ErrorKind::Unknown,
+                    "transfer canceled by the receiver",
+                )
+                .into())
+            }
+            Some(other) => debug!("block rejected with {:#04x} (attempt {})", other, attempt),
+            None => debug!("no response to block (attempt {})", attempt),
+        }
+    }
+    Err(serialport::Error::new(
+        serialport::ErrorKind::Unknown,
+        "too many retries while sending block",
+    )
+    .into())
+}
+
+/// Wait for a single response byte from the receiver, skipping the `C` bytes
+/// the receiver may still be sending to request the transfer start, and
+/// keeping track of the flow control characters.
+fn wait_for_response(
+    port: &mut dyn Transport,
+    flow: &mut SoftFlow,
+) -> Result<Option<u8>, Box<dyn Error>> {
+    let started = Instant::now();
+    while started.elapsed() < ACK_TIMEOUT {
+        if port.bytes_to_read()? > 0 {
+            let mut byte = [0u8; 1];
+            match port.read_exact(&mut byte) {
+                Ok(()) => {}
+                Err(ref e) if is_transient(e) => {
+                    thread::sleep(Duration::from_millis(5));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            trace!("response byte {:#04x}", byte[0]);
+            if flow.receive(&byte).is_empty() {
+                continue;
+            }
+            match byte[0] {
+                ACK | NAK | CAN => return Ok(Some(byte[0])),
+                _ => continue,
+            }
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    Ok(None)
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn frame_layout() {
+    let mut data = [SUB; BLOCK_SIZE];
+    data[0] = b'A';
+    let frame = make_frame(1, &data);
+    assert_eq!(frame.len(), 133);
+    assert_eq!(&frame[..3], &[SOH, 1, 254]);
+    assert_eq!(frame[3], b'A');
+    assert_eq!(&frame[131..], &crc16(&data).to_be_bytes());
+
+    let frame = make_frame(2, &[0; YMODEM_BLOCK_SIZE]);
+    assert_eq!(frame.len(), 1029);
+    assert_eq!(&frame[..3], &[STX, 2, 253]);
+    let header = file_header("kernel8.img", 1_234_567);
+    assert_eq!(&header[..20], b"kernel8.img\x001234567\0");
+    assert!(header[20..].iter().all(|b| *b == 0));
+}
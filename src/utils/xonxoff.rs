@@ -0,0 +1,180 @@
+//! Software (XON/XOFF) flow control.
+//!
+//! When software flow control is selected, `bootcom` handles it itself instead
+//! of leaving it to the serial driver, which behaves differently on each
+//! platform and can't tell flow control characters from binary data. The port
+//! is opened without flow control and:
+//!
+//! * `XON` (`0x11`) and `XOFF` (`0x13`) received from the device are removed
+//!   from the data and pause or resume the writing to the device,
+//! * binary data sent to the device (the kernel image and the XMODEM frames)
+//!   is escaped, so it never contains `XON` or `XOFF`: these bytes, and the
+//!   escape byte `DLE` (`0x10`) itself, are sent as `DLE` followed by the byte
+//!   XOR `0x20`. The bootloader is expected to undo the escaping.
+
+use std::{
+    borrow::Cow,
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use log::trace;
+use serialport::FlowControl;
+
+use super::is_transient;
+use crate::protocol::{byte, device, host, note, Line};
+use crate::transport::Transport;
+
+pub(crate) const XON: u8 = 0x11;
+pub(crate) const XOFF: u8 = 0x13;
+pub(crate) const DLE: u8 = 0x10;
+
+/// The messages of the software flow control.
+pub(crate) fn wire_format() -> Vec<Line> {
+    vec![
+        device(format!(
+            "{} to pause the writing, {} to resume it, at any time",
+            byte("XOFF", XOFF),
+            byte("XON", XON)
+        )),
+        host(format!(
+            "{}, {} and {} in the binary data (kernel image, frames) as {} | byte XOR 0x20",
+            byte("XON", XON),
+            byte("XOFF", XOFF),
+            byte("DLE", DLE),
+            byte("DLE", DLE)
+        )),
+        note(format!(
+            "the device may keep the writing paused for {} s at most",
+            RESUME_TIMEOUT.as_secs()
+        )),
+    ]
+}
+
+/// How long the device may keep the writing paused during a binary transfer.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The flow control state of the connection to the device.
+#[derive(Debug)]
+pub(crate) struct SoftFlow {
+    enabled: bool,
+    /// The device sent `XOFF` and did not send `XON` since.
+    paused: bool,
+}
+impl SoftFlow {
+    /// Only does anything when `flow_control` is the software one.
+    pub(crate) fn new(flow_control: FlowControl) -> Self {
+        SoftFlow {
+            enabled: flow_control == FlowControl::Software,
+            paused: false,
+        }
+    }
+
+    /// Returns `true` if the software flow control is in use.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns `true` if the device asked to pause the writing.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Remove the flow control characters from the `data` received from the
+    /// device, pausing or resuming the writing accordingly.
+    pub(crate) fn receive<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.enabled || !data.iter().any(|b| *b == XON || *b == XOFF) {
+            return Cow::Borrowed(data);
+        }
+        let mut kept = Vec::with_capacity(data.len());
+        for byte in data {
+            match *byte {
+                XON => self.paused = false,
+                XOFF => self.paused = true,
+                other => kept.push(other),
+            }
+        }
+        trace!("flow control paused: {}", self.paused);
+        Cow::Owned(kept)
+    }
+
+    /// Escape the binary `data` to be sent to the device.
+    pub(crate) fn encode<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.enabled {
+            return Cow::Borrowed(data);
+        }
+        let mut escaped = Vec::with_capacity(data.len() + data.len() / 64);
+        for byte in data {
+            match *byte {
+                XON | XOFF | DLE => escaped.extend_from_slice(&[DLE, byte ^ 0x20]),
+                other => escaped.push(other),
+            }
+        }
+        Cow::Owned(escaped)
+    }
+
+    /// Process the flow control characters waiting on the `port`, and block
+    /// while the device keeps the writing paused.
+    ///
+    /// Only meant to be used during binary transfers, when the device is not
+    /// expected to send anything else: any other data is discarded.
+    pub(crate) fn wait_until_resumed(&mut self, port: &mut dyn Transport) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let started = Instant::now();
+        loop {
+            let available = port.bytes_to_read()? as usize;
+            if available > 0 {
+                let mut data = vec![0; available];
+                let read = match port.read(&mut data) {
+                    Ok(read) => read,
+                    Err(ref e) if is_transient(e) => 0,
+                    Err(e) => return Err(e),
+                };
+                let discarded = self.receive(&data[..read]);
+                if !discarded.is_empty() {
+                    trace!("discarded during transfer: {:02x?}", discarded);
+                }
+            }
+            if !self.paused {
+                return Ok(());
+            }
+            if started.elapsed() > RESUME_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the device did not resume the transfer (XON) in time",
+                ));
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+// =============================================================================
+// Unit Tests
+// =============================================================================
+
+#[test]
+fn received_flow_control_is_stripped() {
+    let mut flow = SoftFlow::new(FlowControl::Software);
+    assert_eq!(flow.receive(b"ab\x13c").as_ref(), b"abc");
+    assert!(flow.is_paused());
+    assert_eq!(flow.receive(b"\x11d").as_ref(), b"d");
+    assert!(!flow.is_paused());
+
+    let mut none = SoftFlow::new(FlowControl::None);
+    assert_eq!(none.receive(b"a\x13").as_ref(), b"a\x13");
+    assert!(!none.is_paused());
+}
+
+#[test]
+fn binary_data_is_escaped() {
+    let flow = SoftFlow::new(FlowControl::Software);
+    assert_eq!(
+        flow.encode(&[0x00, XON, 0x42, XOFF, DLE]).as_ref(),
+        &[0x00, DLE, 0x31, 0x42, DLE, 0x33, DLE, 0x30]
+    );
+    let none = SoftFlow::new(FlowControl::Hardware);
+    assert_eq!(none.encode(&[XON]).as_ref(), &[XON]);
+}